@@ -1,11 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use tokio;
 
 use owldb::db::Database;
 
 fn database_insert_one_benchmark(c: &mut Criterion) {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let mut db = rt
+    let db = rt
         .block_on(Database::init("data_bench".to_string()))
         .unwrap();
 
@@ -0,0 +1,109 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    grpc::generate();
+}
+
+/// Generates the gRPC service stubs described by `proto/owldb.proto`. The
+/// messages are written by hand in `src/server/grpc.rs`, so building doesn't
+/// need `protoc`.
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    fn method(name: &str, route: &str, input: &str, output: &str) -> Method {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("super::{}", input))
+            .output_type(format!("super::{}", output))
+            .codec_path("tonic_prost::ProstCodec")
+            .build()
+    }
+
+    pub(super) fn generate() {
+        let find_stream = Method::builder()
+            .name("find_stream")
+            .route_name("FindStream")
+            .input_type("super::FindRequest")
+            .output_type("super::Document")
+            .codec_path("tonic_prost::ProstCodec")
+            .server_streaming()
+            .build();
+        let watch = Method::builder()
+            .name("watch")
+            .route_name("Watch")
+            .input_type("super::WatchRequest")
+            .output_type("super::ChangeEvent")
+            .codec_path("tonic_prost::ProstCodec")
+            .client_streaming()
+            .server_streaming()
+            .build();
+
+        let tail = Method::builder()
+            .name("tail")
+            .route_name("Tail")
+            .input_type("super::TailRequest")
+            .output_type("super::OplogEntry")
+            .codec_path("tonic_prost::ProstCodec")
+            .server_streaming()
+            .build();
+        let snapshot = Method::builder()
+            .name("snapshot")
+            .route_name("Snapshot")
+            .input_type("super::SnapshotRequest")
+            .output_type("super::SnapshotDocument")
+            .codec_path("tonic_prost::ProstCodec")
+            .server_streaming()
+            .build();
+        let replicate = Method::builder()
+            .name("replicate")
+            .route_name("Replicate")
+            .input_type("super::ReplicateRequest")
+            .output_type("super::OplogBatch")
+            .codec_path("tonic_prost::ProstCodec")
+            .client_streaming()
+            .server_streaming()
+            .build();
+
+        let service = Service::builder()
+            .name("OwlDb")
+            .package("owldb")
+            .method(method(
+                "insert",
+                "Insert",
+                "InsertRequest",
+                "InsertResponse",
+            ))
+            .method(method("get", "Get", "GetRequest", "GetResponse"))
+            .method(method("find", "Find", "FindRequest", "FindResponse"))
+            .method(find_stream)
+            .method(method(
+                "update",
+                "Update",
+                "UpdateRequest",
+                "UpdateResponse",
+            ))
+            .method(method(
+                "delete",
+                "Delete",
+                "DeleteRequest",
+                "DeleteResponse",
+            ))
+            .method(watch)
+            .method(method("login", "Login", "LoginRequest", "LoginResponse"))
+            .method(method(
+                "commit",
+                "Commit",
+                "CommitRequest",
+                "CommitResponse",
+            ))
+            .method(tail)
+            .method(snapshot)
+            .method(replicate)
+            .build();
+
+        Builder::new().compile(&[service]);
+    }
+}
@@ -0,0 +1,170 @@
+//! `#[derive(OwlDocument)]`, implementing `owldb::db::OwlDocument` for a
+//! struct with named fields, along with a `by_<field>` filter for each
+//! field taking a value of that field's type.
+//!
+//! - `#[owldb(collection = "users")]` on the struct names its collection;
+//!   by default it's the struct's name in snake case.
+//! - `#[owldb(id)]` on a `String` or `Option<String>` field makes it hold
+//!   the document's ID, kept out of the stored document.
+//! - `#[owldb(index)]` on a field indexes it.
+//! - `#[owldb(crate = "path")]` on the struct says where the `owldb` crate
+//!   is, if not at `::owldb`.
+//!
+//! Fields are stored under their names as written.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr, Path, Type};
+
+#[proc_macro_derive(OwlDocument, attributes(owldb))]
+pub fn derive_owl_document(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(name, "OwlDocument needs a struct"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            name,
+            "OwlDocument needs a struct with named fields",
+        ));
+    };
+
+    let mut collection = snake_case(&name.to_string());
+    let mut krate: Path = syn::parse_quote!(::owldb);
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("owldb"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("collection") {
+                collection = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("crate") {
+                krate = meta.value()?.parse::<LitStr>()?.parse()?;
+            } else {
+                return Err(meta.error("unknown owldb attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut id = None;
+    let mut indexes = Vec::new();
+    let mut filters = Vec::new();
+    for field in &fields.named {
+        let Some(ident) = &field.ident else {
+            continue;
+        };
+        let key = ident.to_string().trim_start_matches("r#").to_string();
+        let (mut is_id, mut is_index) = (false, false);
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("owldb"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    is_id = true;
+                } else if meta.path.is_ident("index") {
+                    is_index = true;
+                } else {
+                    return Err(meta.error("unknown owldb attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        if is_id {
+            if id.is_some() {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "only one field can be the id",
+                ));
+            }
+            id = Some((ident, key, is_option(&field.ty)));
+            continue;
+        }
+        if is_index {
+            indexes.push(key.clone());
+        }
+        let filter = format_ident!("by_{}", key);
+        let ty = &field.ty;
+        let doc = format!("A filter matching documents whose `{}` is `value`.", key);
+        filters.push(quote! {
+            #[doc = #doc]
+            #[allow(clippy::ptr_arg)]
+            pub fn #filter(value: &#ty) -> Result<::bson::Document, #krate::db::DatabaseError> {
+                #krate::db::field_filter(#key, value)
+            }
+        });
+    }
+
+    let (id_field, get_id, set_id) = match id {
+        Some((ident, key, true)) => (
+            quote!(Some(#key)),
+            quote!(self.#ident.as_deref()),
+            quote!(self.#ident = Some(id);),
+        ),
+        Some((ident, key, false)) => (
+            quote!(Some(#key)),
+            quote!(Some(self.#ident.as_str()).filter(|id| !id.is_empty())),
+            quote!(self.#ident = id;),
+        ),
+        None => (quote!(None), quote!(None), quote!(let _ = id;)),
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::db::OwlDocument for #name #ty_generics #where_clause {
+            const COLLECTION: &'static str = #collection;
+            const ID_FIELD: Option<&'static str> = #id_field;
+            const INDEXES: &'static [&'static str] = &[#(#indexes),*];
+
+            fn id(&self) -> Option<&str> {
+                #get_id
+            }
+
+            fn set_id(&mut self, id: String) {
+                #set_id
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#filters)*
+        }
+    })
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+/// `UserProfile` as `user_profile`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
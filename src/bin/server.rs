@@ -0,0 +1,133 @@
+use env_logger::Builder;
+use log::LevelFilter;
+use owldb::server::config::{DatabaseConfig, ServerConfig};
+use owldb::server::tenants::{is_valid_name, DEFAULT_TENANT};
+
+fn usage() -> ! {
+    let mut usage =
+        "usage: owldb-server [--config <file>] [--data <folder>] [--listen <address>] [--auth]"
+            .to_string();
+    usage.push_str(" [--database <name>=<folder>]... [--quota <name>=<bytes>]...");
+    usage.push_str(" [--audit-file <path> | --audit-collection]");
+    usage.push_str(" [--max-connections <n>] [--max-in-flight <n>] [--max-request-size <bytes>]");
+    usage.push_str(" [--rate-limit <requests/s> [--rate-burst <n>]]");
+    if cfg!(feature = "grpc") {
+        usage.push_str(" [--grpc <address>]");
+    }
+    if cfg!(feature = "mongo") {
+        usage.push_str(" [--mongo <address>]");
+    }
+    if cfg!(feature = "resp") {
+        usage.push_str(" [--resp <address>]");
+    }
+    if cfg!(feature = "client") {
+        usage.push_str(" [--leader <uri>]");
+    }
+    if cfg!(feature = "tls") {
+        usage.push_str(" [--tls-cert <pem> --tls-key <pem> [--tls-client-ca <pem>]]");
+    }
+    eprintln!("{}", usage);
+    std::process::exit(2);
+}
+
+/// Splits `name=value`, as `--database` and `--quota` take.
+fn parse_named(value: &str) -> (String, String) {
+    match value.split_once('=') {
+        Some((name, value)) if is_valid_name(name) && !value.is_empty() => {
+            (name.to_string(), value.to_string())
+        }
+        _ => usage(),
+    }
+}
+
+fn parse_limit(value: &str) -> usize {
+    match value.parse() {
+        Ok(limit) if limit > 0 => limit,
+        _ => usage(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    Builder::new().filter(None, LevelFilter::Info).init();
+
+    // The file first, then the environment, then the flags.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Some(args.get(i + 1).cloned().unwrap_or_else(|| usage())),
+        None => std::env::var("OWLDB_CONFIG").ok(),
+    };
+    let mut config = match path {
+        Some(path) => ServerConfig::load(&path)
+            .await
+            .map_err(|e| format!("Failed to load config {}: {:?}", path, e))?,
+        None => ServerConfig::default(),
+    };
+    config
+        .apply_env(std::env::vars())
+        .map_err(|e| format!("Invalid environment: {:?}", e))?;
+
+    let mut quotas: Vec<(String, String)> = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--auth" {
+            config.auth.enabled = true;
+            continue;
+        }
+        if arg == "--audit-collection" {
+            config.audit.collection = true;
+            continue;
+        }
+
+        let limits = &mut config.limits;
+        match (arg.as_str(), args.next()) {
+            ("--config", Some(_)) => {}
+            ("--data", Some(value)) => config.data = value,
+            ("--listen", Some(value)) => config.listen.http = value,
+            ("--audit-file", Some(value)) => config.audit.file = Some(value),
+            ("--database", Some(value)) => {
+                let (name, data) = parse_named(&value);
+                config.databases.push(DatabaseConfig {
+                    name,
+                    data,
+                    quota: None,
+                });
+            }
+            ("--quota", Some(value)) => quotas.push(parse_named(&value)),
+            ("--max-connections", Some(value)) => limits.max_connections = parse_limit(&value),
+            ("--max-in-flight", Some(value)) => limits.max_in_flight = parse_limit(&value),
+            ("--max-request-size", Some(value)) => limits.max_request_size = parse_limit(&value),
+            ("--rate-limit", Some(value)) => match value.parse::<f64>() {
+                Ok(rate) => limits.rate_limit = Some(rate),
+                _ => usage(),
+            },
+            ("--rate-burst", Some(value)) => limits.rate_burst = Some(parse_limit(&value) as u32),
+            #[cfg(feature = "grpc")]
+            ("--grpc", Some(value)) => config.listen.grpc = Some(value),
+            #[cfg(feature = "mongo")]
+            ("--mongo", Some(value)) => config.listen.mongo = Some(value),
+            #[cfg(feature = "resp")]
+            ("--resp", Some(value)) => config.listen.resp = Some(value),
+            #[cfg(feature = "client")]
+            ("--leader", Some(value)) => config.replication.leader = Some(value),
+            #[cfg(feature = "tls")]
+            ("--tls-cert", Some(value)) => config.tls.cert = Some(value),
+            #[cfg(feature = "tls")]
+            ("--tls-key", Some(value)) => config.tls.key = Some(value),
+            #[cfg(feature = "tls")]
+            ("--tls-client-ca", Some(value)) => config.tls.client_ca = Some(value),
+            _ => usage(),
+        }
+    }
+
+    // Every quota must be for a database being served.
+    for (name, bytes) in quotas {
+        let quota = Some(parse_limit(&bytes) as u64);
+        match config.databases.iter_mut().find(|d| d.name == name) {
+            Some(database) => database.quota = quota,
+            None if name == DEFAULT_TENANT => config.quota = quota,
+            None => usage(),
+        }
+    }
+    owldb::server::standalone::run(config).await
+}
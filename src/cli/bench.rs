@@ -0,0 +1,320 @@
+//! `owldb bench`: times inserts, finds and updates against a scratch
+//! database, so storage options can be compared on the disk they'd run on.
+//! Each workload runs for a fixed time with a number of concurrent
+//! workers; throughput and latency percentiles are printed for each.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::{Args, ValueEnum};
+use owldb::db::{
+    Codec, CollectionOptions, Database, DatabaseError, DatabaseOptions, WriteBufferOptions,
+};
+use tokio::task::JoinSet;
+
+use super::table;
+
+const COLLECTION: &str = "bench";
+
+/// Documents written before finds and updates, for them to pick from.
+const PRELOAD: usize = 1000;
+
+const CODECS: &[Codec] = &[
+    Codec::Bson,
+    #[cfg(feature = "cbor")]
+    Codec::Cbor,
+    #[cfg(feature = "msgpack")]
+    Codec::MessagePack,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Workload {
+    Insert,
+    Find,
+    Update,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct BenchArgs {
+    /// Workloads to run, one after the other.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Workload::Insert, Workload::Find, Workload::Update]
+    )]
+    workload: Vec<Workload>,
+    /// Bytes of payload in each document.
+    #[arg(long, default_value_t = 256)]
+    doc_size: usize,
+    /// Workers issuing operations at once.
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+    /// Seconds each workload runs for.
+    #[arg(long, default_value_t = 10.0, value_parser = parse_duration)]
+    duration: f64,
+    /// Where to make the scratch database, removed afterwards; the
+    /// system's temporary directory if not given.
+    #[arg(long)]
+    dir: Option<String>,
+    /// How documents are stored: bson, or cbor or msgpack where built in.
+    #[arg(long, default_value = "bson", value_parser = parse_codec)]
+    codec: Codec,
+    /// Buffer inserts through the write-ahead log.
+    #[arg(long)]
+    write_buffer: bool,
+    /// Leave out the document cache, so finds read files.
+    #[arg(long)]
+    no_cache: bool,
+}
+
+/// What the workers of one workload measured.
+struct Measurement {
+    workload: Workload,
+    elapsed: Duration,
+    /// Of every operation, sorted.
+    latencies: Vec<Duration>,
+}
+
+fn parse_codec(name: &str) -> Result<Codec, String> {
+    CODECS
+        .iter()
+        .copied()
+        .find(|codec| codec.extension() == name)
+        .ok_or_else(|| format!("'{}' isn't a codec this build has", name))
+}
+
+fn parse_duration(seconds: &str) -> Result<f64, String> {
+    seconds
+        .parse()
+        .ok()
+        .filter(|seconds| Duration::try_from_secs_f64(*seconds).is_ok())
+        .ok_or_else(|| format!("'{}' isn't a number of seconds", seconds))
+}
+
+pub(crate) async fn run(args: BenchArgs) -> Result<String, DatabaseError> {
+    let dir = args
+        .dir
+        .clone()
+        .map(Into::into)
+        .unwrap_or_else(std::env::temp_dir);
+    let folder = dir
+        .join(format!("owldb-bench-{}", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    let _ = tokio::fs::remove_dir_all(&folder).await;
+
+    let options = DatabaseOptions {
+        write_buffer: args.write_buffer.then(WriteBufferOptions::default),
+        cache_capacity: match args.no_cache {
+            true => 0,
+            false => DatabaseOptions::default().cache_capacity,
+        },
+        ..DatabaseOptions::default()
+    };
+    let database = Database::init_with_options(folder.clone(), options).await?;
+    let measured = measure(&database, &args).await;
+    database.close().await?;
+    let _ = tokio::fs::remove_dir_all(&folder).await;
+
+    let rows: Vec<_> = measured?
+        .iter()
+        .map(|measurement| {
+            let ops = measurement.latencies.len();
+            vec![
+                format!("{:?}", measurement.workload).to_lowercase(),
+                ops.to_string(),
+                format!("{:.0}", ops as f64 / measurement.elapsed.as_secs_f64()),
+                format_latency(percentile(&measurement.latencies, 50.0)),
+                format_latency(percentile(&measurement.latencies, 90.0)),
+                format_latency(percentile(&measurement.latencies, 99.0)),
+                format_latency(percentile(&measurement.latencies, 100.0)),
+            ]
+        })
+        .collect();
+    let mut output = format!(
+        "{}-byte documents stored as {}, {} workers, {}s per workload, write buffer {}, cache {}, in {}\n\n",
+        args.doc_size,
+        args.codec.extension(),
+        args.concurrency,
+        args.duration,
+        on_off(args.write_buffer),
+        on_off(!args.no_cache),
+        dir.display()
+    );
+    output.push_str(&table(
+        &["WORKLOAD", "OPS", "OPS/S", "P50", "P90", "P99", "MAX"],
+        &rows,
+    ));
+    Ok(output)
+}
+
+async fn measure(database: &Database, args: &BenchArgs) -> Result<Vec<Measurement>, DatabaseError> {
+    database
+        .create_collection(COLLECTION, CollectionOptions { codec: args.codec })
+        .await?;
+    let payload = Arc::new("x".repeat(args.doc_size));
+    let mut ids = Vec::new();
+    if args.workload.iter().any(|w| *w != Workload::Insert) {
+        for _ in 0..PRELOAD {
+            ids.push(
+                database
+                    .insert_one(COLLECTION.to_string(), document(&payload))
+                    .await?,
+            );
+        }
+    }
+    let ids = Arc::new(ids);
+    let duration = Duration::from_secs_f64(args.duration);
+
+    let mut measured = Vec::new();
+    for &workload in &args.workload {
+        let started = Instant::now();
+        let deadline = started + duration;
+        let mut workers = JoinSet::new();
+        for seed in 0..args.concurrency.max(1) {
+            workers.spawn(work(
+                database.clone(),
+                workload,
+                ids.clone(),
+                payload.clone(),
+                deadline,
+                seed as u64 + 1,
+            ));
+        }
+
+        let mut latencies = Vec::new();
+        while let Some(worker) = workers.join_next().await {
+            match worker
+                .map_err(|e| DatabaseError::IoError(std::io::Error::other(e.to_string())))
+                .and_then(|worker_latencies| worker_latencies)
+            {
+                Ok(worker_latencies) => latencies.extend(worker_latencies),
+                Err(e) => {
+                    // Leave no worker running against the database the
+                    // caller is about to close.
+                    workers.shutdown().await;
+                    return Err(e);
+                }
+            }
+        }
+        latencies.sort();
+        measured.push(Measurement {
+            workload,
+            elapsed: started.elapsed(),
+            latencies,
+        });
+    }
+    Ok(measured)
+}
+
+/// Runs `workload` until `deadline`, answering how long each operation took.
+async fn work(
+    database: Database,
+    workload: Workload,
+    ids: Arc<Vec<String>>,
+    payload: Arc<String>,
+    deadline: Instant,
+    mut seed: u64,
+) -> Result<Vec<Duration>, DatabaseError> {
+    let mut latencies = Vec::new();
+    let mut pick = || {
+        // xorshift64: cheap, and good enough to spread reads around.
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        ids[(seed % ids.len().max(1) as u64) as usize].clone()
+    };
+
+    while Instant::now() < deadline {
+        let started = Instant::now();
+        match workload {
+            Workload::Insert => {
+                database
+                    .insert_one(COLLECTION.to_string(), document(&payload))
+                    .await?;
+            }
+            Workload::Find => {
+                database.find_one(COLLECTION.to_string(), pick()).await?;
+            }
+            Workload::Update => {
+                database
+                    .update_one(
+                        COLLECTION.to_string(),
+                        pick(),
+                        bson::doc! { "$inc": { "updates": 1 } },
+                    )
+                    .await?;
+            }
+        }
+        latencies.push(started.elapsed());
+    }
+    Ok(latencies)
+}
+
+fn document(payload: &str) -> bson::Document {
+    bson::doc! { "payload": payload, "updates": 0 }
+}
+
+/// Of `sorted` latencies, the one `percent` of them are at or below.
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        len => sorted[((len - 1) as f64 * percent / 100.0).round() as usize],
+    }
+}
+
+fn format_latency(latency: Duration) -> String {
+    match latency.as_micros() {
+        micros if micros < 1000 => format!("{} µs", micros),
+        _ => format!("{:.2} ms", latency.as_secs_f64() * 1000.0),
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    match on {
+        true => "on",
+        false => "off",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(51));
+        assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+        assert_eq!(format_latency(Duration::from_micros(250)), "250 µs");
+        assert_eq!(format_latency(Duration::from_micros(1500)), "1.50 ms");
+    }
+
+    #[tokio::test]
+    async fn test_bench() {
+        let args = BenchArgs {
+            workload: vec![Workload::Insert, Workload::Find, Workload::Update],
+            doc_size: 64,
+            concurrency: 2,
+            duration: 0.1,
+            dir: Some("data_tests/test_cli_bench".to_string()),
+            codec: parse_codec("bson").unwrap(),
+            write_buffer: false,
+            no_cache: true,
+        };
+        let output = run(args).await.unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[0].starts_with("64-byte documents stored as bson, 2 workers"));
+        assert!(lines[2].starts_with("WORKLOAD  OPS"));
+        assert!(lines[3].starts_with("insert"));
+        assert!(lines[5].starts_with("update"));
+        assert!(parse_codec("yaml").is_err());
+        assert_eq!(parse_duration("0.5"), Ok(0.5));
+        for seconds in ["inf", "NaN", "-1", "ten"] {
+            assert!(parse_duration(seconds).is_err());
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! `owldb compact`: rewrites collection directories bloated by documents
+//! since deleted, as the server's background defragmenter does when idle.
+//! `--dry-run` only estimates what that would reclaim.
+
+use indicatif::{ProgressBar, ProgressStyle};
+use owldb::db::{Database, DatabaseError, DefragEstimate};
+
+use super::{human_bytes, table};
+
+/// Compacts `collections`, or every collection if none are named. Those
+/// with nothing to reclaim are left alone.
+pub(crate) async fn run(
+    database: &Database,
+    collections: Vec<String>,
+    dry_run: bool,
+) -> Result<String, DatabaseError> {
+    let collections = match collections.is_empty() {
+        true => database.collections().await?,
+        false => collections,
+    };
+    let mut estimates = Vec::with_capacity(collections.len());
+    for collection in collections {
+        estimates.push(database.defragment_estimate(collection).await?);
+    }
+
+    if dry_run {
+        let rows: Vec<_> = estimates
+            .iter()
+            .map(|estimate| {
+                vec![
+                    estimate.collection.clone(),
+                    estimate.entries.to_string(),
+                    human_bytes(estimate.directory_bytes),
+                    human_bytes(estimate.reclaimable_bytes),
+                ]
+            })
+            .collect();
+        let mut output = table(
+            &["COLLECTION", "ENTRIES", "DIRECTORY", "RECLAIMABLE"],
+            &rows,
+        );
+        output.push_str(&format!(
+            "\nEstimated reclaimable: {}\n",
+            human_bytes(reclaimable(&estimates))
+        ));
+        return Ok(output);
+    }
+
+    let mut rows = Vec::new();
+    let mut reclaimed = 0;
+    for before in estimates {
+        if before.reclaimable_bytes == 0 {
+            continue;
+        }
+        let bar = progress_bar(&before.collection);
+        database
+            .defragment_collection_with_progress(before.collection.clone(), |progress| {
+                bar.set_length(progress.entries);
+                bar.set_position(progress.linked);
+            })
+            .await?;
+        bar.finish_and_clear();
+
+        let after = database
+            .defragment_estimate(before.collection.clone())
+            .await?;
+        reclaimed += before.directory_bytes.saturating_sub(after.directory_bytes);
+        rows.push(vec![
+            before.collection,
+            after.entries.to_string(),
+            human_bytes(before.directory_bytes),
+            human_bytes(after.directory_bytes),
+        ]);
+    }
+
+    if rows.is_empty() {
+        return Ok("Nothing to reclaim.\n".to_string());
+    }
+    let mut output = table(&["COLLECTION", "ENTRIES", "BEFORE", "AFTER"], &rows);
+    output.push_str(&format!("\nReclaimed: {}\n", human_bytes(reclaimed)));
+    Ok(output)
+}
+
+fn reclaimable(estimates: &[DefragEstimate]) -> u64 {
+    estimates
+        .iter()
+        .map(|estimate| estimate.reclaimable_bytes)
+        .sum()
+}
+
+/// Drawn on stderr, and not at all when that isn't a terminal.
+fn progress_bar(collection: &str) -> ProgressBar {
+    let bar = ProgressBar::new(0).with_message(collection.to_string());
+    if let Ok(style) = ProgressStyle::with_template("{msg:16} [{bar:40}] {pos}/{len} ({eta})") {
+        bar.set_style(style.progress_chars("=> "));
+    }
+    bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_compact() {
+        let _ = tokio::fs::remove_dir_all("data_tests/test_cli_compact").await;
+        let db = Database::init("data_tests/test_cli_compact".to_string())
+            .await
+            .unwrap();
+        db.put(
+            "users".to_string(),
+            "ada".to_string(),
+            bson::doc! { "name": "Ada" },
+        )
+        .await
+        .unwrap();
+
+        let dry_run = run(&db, Vec::new(), true).await.unwrap();
+        assert!(dry_run.starts_with("COLLECTION  ENTRIES  DIRECTORY  RECLAIMABLE\nusers"));
+        assert!(dry_run.contains("Estimated reclaimable: "));
+        // A directory that never grew has nothing to give back.
+        assert_eq!(
+            run(&db, vec!["users".to_string()], false).await.unwrap(),
+            "Nothing to reclaim.\n"
+        );
+        assert!(run(&db, vec!["missing".to_string()], true).await.is_err());
+        db.close().await.unwrap();
+    }
+}
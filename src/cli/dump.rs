@@ -0,0 +1,256 @@
+//! `owldb dump` and `owldb restore`: collections to and from a directory
+//! holding one Extended JSON file per collection, `<collection>.json`, or
+//! `<collection>.json.gz` gzipped, as `mongoexport` writes them. With
+//! `--archive`, the whole database to and from a backup archive instead.
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use clap::Args;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use owldb::db::{Database, DatabaseError, JsonExportOptions};
+
+use super::parse;
+
+const EXTENSION: &str = ".json";
+const GZIP_EXTENSION: &str = ".json.gz";
+
+#[derive(Debug, Default, Args)]
+pub(crate) struct DumpArgs {
+    /// Directory the collection files are written to.
+    #[arg(long, default_value = "dump", conflicts_with = "archive")]
+    out: String,
+    /// Only this collection; may be given more than once.
+    #[arg(long)]
+    collection: Vec<String>,
+    /// Only documents matching the filter.
+    #[arg(long, requires = "collection")]
+    query: Option<String>,
+    /// Gzip each collection file.
+    #[arg(long)]
+    gzip: bool,
+    /// Write every collection to a backup archive at this path instead, as
+    /// `Database::backup` does; it is always compressed.
+    #[arg(long, conflicts_with_all = ["collection", "query", "gzip"])]
+    archive: Option<String>,
+}
+
+#[derive(Debug, Default, Args)]
+pub(crate) struct RestoreArgs {
+    /// Directory `owldb dump` wrote, gzipped files included.
+    #[arg(default_value = "dump", conflicts_with = "archive")]
+    from: String,
+    /// Only this collection; may be given more than once.
+    #[arg(long)]
+    collection: Vec<String>,
+    /// Replace the whole database with the backup archive at this path.
+    #[arg(long, conflicts_with = "collection")]
+    archive: Option<String>,
+}
+
+/// Prints how many documents went where.
+pub(crate) async fn dump(database: &Database, args: DumpArgs) -> Result<String, DatabaseError> {
+    if let Some(archive) = args.archive {
+        let info = database.backup(&archive).await?;
+        return Ok(format!(
+            "Dumped every collection to {} as of {}\n",
+            archive,
+            info.finished_at.try_to_rfc3339_string().unwrap_or_default()
+        ));
+    }
+
+    let collections = match args.collection.is_empty() {
+        true => database.collections().await?,
+        false => args.collection,
+    };
+    let filter = match &args.query {
+        Some(query) => parse(query)?,
+        None => bson::Document::new(),
+    };
+    tokio::fs::create_dir_all(&args.out)
+        .await
+        .map_err(DatabaseError::IoError)?;
+
+    let mut output = String::new();
+    for collection in collections {
+        let mut buffer = Vec::new();
+        let options = JsonExportOptions {
+            filter: filter.clone(),
+            ..JsonExportOptions::default()
+        };
+        let count = database
+            .export_json(collection.clone(), &mut buffer, options)
+            .await?;
+
+        let extension = match args.gzip {
+            true => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&buffer).map_err(DatabaseError::IoError)?;
+                buffer = encoder.finish().map_err(DatabaseError::IoError)?;
+                GZIP_EXTENSION
+            }
+            false => EXTENSION,
+        };
+        let path = Path::new(&args.out).join(format!("{}{}", collection, extension));
+        tokio::fs::write(&path, buffer)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        output.push_str(&format!(
+            "{}: {} documents to {}\n",
+            collection,
+            count,
+            path.display()
+        ));
+    }
+    Ok(output)
+}
+
+/// Documents with the `_id` of one already there replace it.
+pub(crate) async fn restore(
+    database: &Database,
+    args: RestoreArgs,
+) -> Result<String, DatabaseError> {
+    if let Some(archive) = args.archive {
+        let count = database.restore(&archive).await?;
+        return Ok(format!("Restored {} documents from {}\n", count, archive));
+    }
+
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(&args.from)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let collection = match name.strip_suffix(GZIP_EXTENSION) {
+            Some(collection) => (collection.to_string(), true),
+            None => match name.strip_suffix(EXTENSION) {
+                Some(collection) => (collection.to_string(), false),
+                None => continue,
+            },
+        };
+        if args.collection.is_empty() || args.collection.contains(&collection.0) {
+            files.push((collection, entry.path()));
+        }
+    }
+    files.sort();
+
+    let mut output = String::new();
+    for ((collection, gzip), path) in files {
+        let mut contents = tokio::fs::read(&path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        if gzip {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(contents.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(DatabaseError::IoError)?;
+            contents = decompressed;
+        }
+        let count = database
+            .import_json(collection.clone(), contents.as_slice())
+            .await?;
+        output.push_str(&format!(
+            "{}: {} documents from {}\n",
+            collection,
+            count,
+            path.display()
+        ));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dump_and_restore() {
+        let folder = "data_tests/test_cli_dump";
+        let _ = tokio::fs::remove_dir_all(folder).await;
+        let source = Database::init(format!("{}/source", folder)).await.unwrap();
+        for (id, age) in [("ada", 36), ("bob", 30)] {
+            source
+                .put(
+                    "users".to_string(),
+                    id.to_string(),
+                    bson::doc! { "age": age },
+                )
+                .await
+                .unwrap();
+        }
+        source
+            .put(
+                "orders".to_string(),
+                "o1".to_string(),
+                bson::doc! { "total": 5 },
+            )
+            .await
+            .unwrap();
+
+        let out = format!("{}/dump", folder);
+        let args = DumpArgs {
+            out: out.clone(),
+            collection: vec!["users".to_string()],
+            query: Some(r#"{"age": 30}"#.to_string()),
+            gzip: true,
+            ..DumpArgs::default()
+        };
+        assert_eq!(
+            dump(&source, args).await.unwrap(),
+            format!("users: 1 documents to {}/users.json.gz\n", out)
+        );
+        let args = DumpArgs {
+            out: out.clone(),
+            collection: vec!["orders".to_string()],
+            ..DumpArgs::default()
+        };
+        dump(&source, args).await.unwrap();
+
+        let target = Database::init(format!("{}/target", folder)).await.unwrap();
+        let args = RestoreArgs {
+            from: out,
+            ..RestoreArgs::default()
+        };
+        restore(&target, args).await.unwrap();
+        assert_eq!(
+            target
+                .find_one("users".to_string(), "bob".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "age": 30 })
+        );
+        assert!(target
+            .find_one("users".to_string(), "ada".to_string())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(target.collections().await.unwrap(), ["orders", "users"]);
+
+        let archive = format!("{}/all.owlbak", folder);
+        let args = DumpArgs {
+            archive: Some(archive.clone()),
+            ..DumpArgs::default()
+        };
+        dump(&source, args).await.unwrap();
+        let args = RestoreArgs {
+            archive: Some(archive),
+            ..RestoreArgs::default()
+        };
+        assert_eq!(
+            restore(&target, args).await.unwrap(),
+            format!("Restored 3 documents from {}/all.owlbak\n", folder)
+        );
+        assert_eq!(
+            target
+                .find("users".to_string(), bson::Document::new())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        source.close().await.unwrap();
+        target.close().await.unwrap();
+    }
+}
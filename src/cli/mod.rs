@@ -0,0 +1,284 @@
+//! What the `owldb` subcommands share: reading documents given as
+//! Extended JSON and printing the ones found, as JSON, CSV or a table.
+
+pub(crate) mod bench;
+pub(crate) mod compact;
+pub(crate) mod dump;
+#[cfg(feature = "http")]
+pub(crate) mod serve;
+pub(crate) mod shell;
+pub(crate) mod stats;
+pub(crate) mod verify;
+
+use bson::{Bson, Document};
+use clap::ValueEnum;
+use owldb::db::{csv_row, format_csv, Database, DatabaseError, Projection};
+
+/// What a command prints, and the status `owldb` exits with.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Output {
+    pub(crate) text: String,
+    pub(crate) status: i32,
+}
+
+impl From<String> for Output {
+    fn from(text: String) -> Self {
+        Output { text, status: 0 }
+    }
+}
+
+/// A document given as Extended JSON.
+pub(crate) fn parse(json: &str) -> Result<Document, DatabaseError> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| invalid_input(e.to_string()))?;
+    to_document(value)
+}
+
+/// A document from an already parsed Extended JSON value.
+pub(crate) fn to_document(value: serde_json::Value) -> Result<Document, DatabaseError> {
+    match Bson::try_from(value).map_err(|e| invalid_input(e.to_string()))? {
+        Bson::Document(doc) => Ok(doc),
+        other => Err(invalid_input(format!("expected an object, got {}", other))),
+    }
+}
+
+fn invalid_input(reason: String) -> DatabaseError {
+    DatabaseError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid JSON document: {}", reason),
+    ))
+}
+
+/// Inserts `doc` under its `_id`, a string or an ObjectId, failing if a
+/// document has that ID already; under a new ID without one. Answers the
+/// ID.
+pub(crate) async fn insert(
+    database: &Database,
+    collection: String,
+    mut doc: Document,
+) -> Result<String, DatabaseError> {
+    match doc.remove("_id") {
+        Some(id) => {
+            let id = match id {
+                Bson::String(id) => id,
+                Bson::ObjectId(id) => id.to_hex(),
+                other => {
+                    return Err(invalid_input(format!(
+                        "_id must be a string or an ObjectId, got {}",
+                        other
+                    )))
+                }
+            };
+            database
+                .insert_one_with_id(collection, id.clone(), doc)
+                .await?;
+            Ok(id)
+        }
+        None => database.insert_one(collection, doc).await,
+    }
+}
+
+pub(crate) fn not_found(id: &str) -> DatabaseError {
+    DatabaseError::IoError(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("no document with ID '{}'", id),
+    ))
+}
+
+/// A document as relaxed Extended JSON, its ID first under `_id`.
+pub(crate) fn to_json(id: String, doc: Document) -> serde_json::Value {
+    let mut with_id = bson::doc! { "_id": id };
+    with_id.extend(doc);
+    Bson::Document(with_id).into_relaxed_extjson()
+}
+
+/// How `get` and `find` print documents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Format {
+    /// Aligned columns, embedded fields under dotted paths.
+    Table,
+    /// Pretty JSON, an array for `find`.
+    #[default]
+    Json,
+    /// One document per line, for `jq` and the like.
+    Ndjson,
+    /// A header, then a record per document, as `export_csv` writes them.
+    Csv,
+}
+
+/// Longest a table cell gets before it is cut short.
+const MAX_CELL: usize = 40;
+
+/// `documents` in `format`, with only `fields` if any are given. JSON
+/// keeps the ID whatever the fields; as a single object unless `many`.
+pub(crate) fn format_documents(
+    documents: Vec<(String, Document)>,
+    format: Format,
+    fields: &[String],
+    many: bool,
+) -> String {
+    let projection = match fields.is_empty() {
+        true => Projection::All,
+        false => Projection::Include(fields.to_vec()),
+    };
+    let documents = documents
+        .into_iter()
+        .map(|(id, doc)| (id, projection.apply(doc)));
+
+    match format {
+        Format::Json | Format::Ndjson => {
+            let values: Vec<serde_json::Value> =
+                documents.map(|(id, doc)| to_json(id, doc)).collect();
+            if format == Format::Ndjson {
+                return values.iter().map(|value| format!("{}\n", value)).collect();
+            }
+            let pretty = match many {
+                true => serde_json::to_string_pretty(&values),
+                false => serde_json::to_string_pretty(&values[0]),
+            };
+            format!("{}\n", pretty.unwrap_or_default())
+        }
+        Format::Table | Format::Csv => {
+            let rows: Vec<_> = documents.map(|(id, doc)| csv_row(id, doc)).collect();
+            let columns = columns(&rows, fields);
+            if format == Format::Csv {
+                return format_csv(&columns, &rows, ',');
+            }
+            let cells: Vec<Vec<String>> = rows
+                .iter()
+                .map(|row| {
+                    columns
+                        .iter()
+                        .map(|column| {
+                            let cell = row
+                                .iter()
+                                .find(|(path, _)| path == column)
+                                .map_or("", |(_, text)| text.as_str());
+                            truncate(cell)
+                        })
+                        .collect()
+                })
+                .collect();
+            let header: Vec<&str> = columns.iter().map(String::as_str).collect();
+            table(&header, &cells)
+        }
+    }
+}
+
+/// Every path in `rows`, in the order first seen; with `fields`, only
+/// those they cover, in their order.
+fn columns(rows: &[Vec<(String, String)>], fields: &[String]) -> Vec<String> {
+    let covering = |path: &str| {
+        fields.iter().position(|field| {
+            path.strip_prefix(field.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    };
+
+    let mut columns: Vec<String> = Vec::new();
+    for (path, _) in rows.iter().flatten() {
+        if !columns.contains(path) {
+            columns.push(path.clone());
+        }
+    }
+    if !fields.is_empty() {
+        columns.retain(|column| covering(column).is_some());
+        columns.sort_by_key(|column| covering(column));
+    }
+    match (columns.is_empty(), fields.is_empty()) {
+        (false, _) => columns,
+        (true, false) => fields.to_vec(),
+        (true, true) => vec!["_id".to_string()],
+    }
+}
+
+fn truncate(cell: &str) -> String {
+    match cell.char_indices().nth(MAX_CELL) {
+        Some((end, _)) => format!("{}…", &cell[..end]),
+        None => cell.to_string(),
+    }
+}
+
+/// `rows` under `header`, in columns as wide as their widest cell.
+pub(crate) fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|cell| cell.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: &mut dyn Iterator<Item = &str>| {
+        let padded: Vec<String> = cells
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+    let mut output = line(&mut header.iter().copied());
+    for row in rows {
+        output.push_str(&line(&mut row.iter().map(String::as_str)));
+    }
+    output
+}
+
+/// `bytes` in the largest binary unit it makes at least one of.
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_documents() {
+        let documents = vec![
+            (
+                "ada".to_string(),
+                bson::doc! { "name": "Ada", "address": { "city": "London" }, "age": 36 },
+            ),
+            (
+                "bob".to_string(),
+                bson::doc! { "name": "Bob, Jr.", "age": 30 },
+            ),
+        ];
+        let fields = ["name".to_string(), "address".to_string()];
+
+        assert_eq!(
+            format_documents(documents.clone(), Format::Csv, &[], true),
+            "_id,name,address.city,age\r\nada,Ada,London,36\r\nbob,\"Bob, Jr.\",,30\r\n"
+        );
+        assert_eq!(
+            format_documents(documents.clone(), Format::Table, &fields, true),
+            "name      address.city\nAda       London\nBob, Jr.\n"
+        );
+        assert_eq!(
+            format_documents(documents.clone(), Format::Ndjson, &fields, true),
+            "{\"_id\":\"ada\",\"name\":\"Ada\",\"address\":{\"city\":\"London\"}}\n\
+             {\"_id\":\"bob\",\"name\":\"Bob, Jr.\"}\n"
+        );
+        assert_eq!(
+            format_documents(Vec::new(), Format::Table, &[], true),
+            "_id\n"
+        );
+        assert_eq!(truncate(&"é".repeat(50)), format!("{}…", "é".repeat(40)));
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
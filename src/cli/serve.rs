@@ -0,0 +1,98 @@
+//! `owldb serve`: the server `owldb-server` runs, on the folder `--path`
+//! names, with its most common settings as flags. A configuration file,
+//! then the `OWLDB_*` environment, then the flags decide the rest.
+
+use clap::Args;
+use owldb::server::config::ServerConfig;
+use owldb::server::standalone;
+
+#[derive(Debug, Default, Args)]
+pub(crate) struct ServeArgs {
+    /// A TOML configuration file, as `owldb-server --config` reads.
+    #[arg(long, env = "OWLDB_CONFIG")]
+    config: Option<String>,
+    /// Address the HTTP front end binds to.
+    #[arg(long)]
+    listen: Option<String>,
+    /// Address for the gRPC front end, with the `grpc` feature.
+    #[arg(long)]
+    grpc: Option<String>,
+    /// Address for the MongoDB wire protocol, with the `mongo` feature.
+    #[arg(long)]
+    mongo: Option<String>,
+    /// Address for the Redis protocol, with the `resp` feature.
+    #[arg(long)]
+    resp: Option<String>,
+    /// Require clients to authenticate.
+    #[arg(long)]
+    auth: bool,
+    /// PEM certificate to serve TLS with, with the `tls` feature.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// PEM CA bundle client certificates must chain to.
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<String>,
+}
+
+/// Serves until ctrl-c or SIGTERM.
+pub(crate) async fn run(path: Option<String>, args: ServeArgs) -> Result<(), String> {
+    let mut config = match &args.config {
+        Some(file) => ServerConfig::load(file)
+            .await
+            .map_err(|e| format!("Failed to load config {}: {:?}", file, e))?,
+        None => ServerConfig::default(),
+    };
+    config
+        .apply_env(std::env::vars())
+        .map_err(|e| format!("Invalid environment: {:?}", e))?;
+    configure(&mut config, path, args);
+
+    standalone::run(config).await.map_err(|e| e.to_string())
+}
+
+/// Lets the flags given override `config`.
+fn configure(config: &mut ServerConfig, path: Option<String>, args: ServeArgs) {
+    let listen = &mut config.listen;
+    if let Some(path) = path {
+        config.data = path;
+    }
+    if let Some(http) = args.listen {
+        listen.http = http;
+    }
+    listen.grpc = args.grpc.or(listen.grpc.take());
+    listen.mongo = args.mongo.or(listen.mongo.take());
+    listen.resp = args.resp.or(listen.resp.take());
+    config.auth.enabled |= args.auth;
+    let tls = &mut config.tls;
+    tls.cert = args.tls_cert.or(tls.cert.take());
+    tls.key = args.tls_key.or(tls.key.take());
+    tls.client_ca = args.tls_client_ca.or(tls.client_ca.take());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_override_config() {
+        let mut config = ServerConfig::default();
+        config.listen.grpc = Some("127.0.0.1:50051".to_string());
+        config.listen.resp = Some("127.0.0.1:6379".to_string());
+        let args = ServeArgs {
+            listen: Some("0.0.0.0:9000".to_string()),
+            resp: Some("0.0.0.0:6380".to_string()),
+            auth: true,
+            ..ServeArgs::default()
+        };
+
+        configure(&mut config, Some("/srv/owldb".to_string()), args);
+        assert_eq!(config.data, "/srv/owldb");
+        assert_eq!(config.listen.http, "0.0.0.0:9000");
+        assert_eq!(config.listen.grpc.as_deref(), Some("127.0.0.1:50051"));
+        assert_eq!(config.listen.resp.as_deref(), Some("0.0.0.0:6380"));
+        assert!(config.auth.enabled);
+        assert_eq!(config.tls.cert, None);
+    }
+}
@@ -0,0 +1,401 @@
+//! `owldb shell`: an interactive prompt taking mongo shell statements,
+//! `db.<collection>.<method>(<arguments>)`, with the arguments written as
+//! JSON. Tab completes statements, collection names and methods; history
+//! is kept in `~/.owldb_history`.
+
+use std::path::Path;
+
+use bson::Document;
+use owldb::db::{Database, DatabaseError};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use serde_json::{json, Value};
+
+use super::{format_documents, insert, to_document, Format};
+
+const HISTORY_FILE: &str = ".owldb_history";
+
+const STATEMENTS: &[&str] = &["db.", "exit", "help", "show collections"];
+
+const METHODS: &[&str] = &[
+    "countDocuments",
+    "deleteMany",
+    "deleteOne",
+    "find",
+    "findOne",
+    "insertMany",
+    "insertOne",
+    "updateOne",
+];
+
+const HELP: &str = "\
+db.<collection>.find(<filter>)                 documents matching the filter
+db.<collection>.findOne(<filter>)              the first of them
+db.<collection>.countDocuments(<filter>)       how many there are
+db.<collection>.insertOne(<document>)
+db.<collection>.insertMany([<document>, ...])
+db.<collection>.updateOne(<filter>, <update>)
+db.<collection>.deleteOne(<filter>)
+db.<collection>.deleteMany(<filter>)
+show collections
+exit
+
+Arguments are JSON; a filter matches documents whose fields equal its own.
+";
+
+#[derive(Debug, PartialEq)]
+enum Statement {
+    Help,
+    ShowCollections,
+    Exit,
+    Call {
+        collection: String,
+        method: String,
+        args: Vec<Value>,
+    },
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper {
+    collections: Vec<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(candidates(&line[..pos], &self.collections))
+    }
+}
+
+/// Reads and runs statements until `exit` or the end of input.
+pub(crate) async fn run(database: &Database) -> Result<(), String> {
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().map_err(|e| e.to_string())?;
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // There's none before the first session.
+        let _ = editor.load_history(history);
+    }
+    println!("Type 'help' for the statements, 'exit' to leave.");
+
+    loop {
+        // Collections come and go, so completion asks again every time.
+        let collections = database.collections().await.unwrap_or_default();
+        editor.set_helper(Some(ShellHelper { collections }));
+
+        let line = match tokio::task::block_in_place(|| editor.readline("owldb> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        let result = match parse_statement(&line) {
+            Ok(Statement::Exit) => break,
+            Ok(statement) => execute(database, statement).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(output) => print!("{}", output),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn parse_statement(line: &str) -> Result<Statement, String> {
+    let line = line.trim().trim_end_matches(';').trim_end();
+    match line {
+        "help" => return Ok(Statement::Help),
+        "show collections" => return Ok(Statement::ShowCollections),
+        "exit" | "quit" => return Ok(Statement::Exit),
+        _ => {}
+    }
+
+    let expected = || format!("expected db.<collection>.<method>(...), got '{}'", line);
+    let call = line.strip_prefix("db.").ok_or_else(expected)?;
+    let (target, args) = call.split_once('(').ok_or_else(expected)?;
+    let args = args.strip_suffix(')').ok_or_else(expected)?;
+    let (collection, method) = target.trim().rsplit_once('.').ok_or_else(expected)?;
+    let args = serde_json::from_str(&format!("[{}]", args))
+        .map_err(|e| format!("invalid arguments: {}", e))?;
+
+    Ok(Statement::Call {
+        collection: collection.to_string(),
+        method: method.to_string(),
+        args,
+    })
+}
+
+/// Where the word ending `line` starts, and what it could be completed to.
+fn candidates(line: &str, collections: &[String]) -> (usize, Vec<String>) {
+    let start = line
+        .rfind(|c: char| c.is_whitespace() || "(),{}[]:".contains(c))
+        .map_or(0, |i| i + 1);
+    let word = &line[start..];
+    let starting_with = |names: &mut dyn Iterator<Item = &str>, prefix: &str, suffix: &str| {
+        names
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| format!("{}{}", name, suffix))
+            .collect()
+    };
+
+    let Some(path) = word.strip_prefix("db.") else {
+        return match start {
+            0 => (0, starting_with(&mut STATEMENTS.iter().copied(), word, "")),
+            _ => (start, Vec::new()),
+        };
+    };
+    match path.rsplit_once('.') {
+        Some((_, method)) => (
+            line.len() - method.len(),
+            starting_with(&mut METHODS.iter().copied(), method, "("),
+        ),
+        None => (
+            line.len() - path.len(),
+            starting_with(&mut collections.iter().map(String::as_str), path, "."),
+        ),
+    }
+}
+
+async fn execute(database: &Database, statement: Statement) -> Result<String, String> {
+    match statement {
+        Statement::Help => Ok(HELP.to_string()),
+        Statement::ShowCollections => Ok(database
+            .collections()
+            .await
+            .map_err(describe)?
+            .iter()
+            .map(|name| format!("{}\n", name))
+            .collect()),
+        Statement::Exit => Ok(String::new()),
+        Statement::Call {
+            collection,
+            method,
+            args,
+        } => call(database, collection, &method, args).await,
+    }
+}
+
+async fn call(
+    database: &Database,
+    collection: String,
+    method: &str,
+    args: Vec<Value>,
+) -> Result<String, String> {
+    let mut args = args.into_iter();
+    let mut document = || {
+        args.next()
+            .map(to_document)
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(describe)
+    };
+
+    let result = match method {
+        "find" => {
+            let found = matching(database, &collection, document()?).await?;
+            return Ok(format_documents(found, Format::Json, &[], true));
+        }
+        "findOne" => {
+            let found = matching(database, &collection, document()?).await?;
+            return Ok(match found.into_iter().next() {
+                Some(found) => format_documents(vec![found], Format::Json, &[], false),
+                None => "null\n".to_string(),
+            });
+        }
+        "countDocuments" => json!(matching(database, &collection, document()?).await?.len()),
+        "insertOne" => {
+            let id = insert(database, collection, document()?)
+                .await
+                .map_err(describe)?;
+            json!({ "insertedId": id })
+        }
+        "insertMany" => {
+            let Some(Value::Array(values)) = args.next() else {
+                return Err("insertMany takes an array of documents".to_string());
+            };
+            let mut ids = Vec::new();
+            for value in values {
+                let doc = to_document(value).map_err(describe)?;
+                ids.push(
+                    insert(database, collection.clone(), doc)
+                        .await
+                        .map_err(describe)?,
+                );
+            }
+            json!({ "insertedIds": ids })
+        }
+        "updateOne" => {
+            let (filter, update) = (document()?, document()?);
+            let matched = match first(database, &collection, filter).await? {
+                Some(id) => database
+                    .update_one(collection, id, update)
+                    .await
+                    .map_err(describe)?,
+                None => false,
+            };
+            json!({ "matchedCount": u32::from(matched) })
+        }
+        "deleteOne" => {
+            let deleted = match first(database, &collection, document()?).await? {
+                Some(id) => {
+                    database
+                        .delete_one(collection, id)
+                        .await
+                        .map_err(describe)?;
+                    1
+                }
+                None => 0,
+            };
+            json!({ "deletedCount": deleted })
+        }
+        "deleteMany" => {
+            let deleted = match database.delete(collection, document()?).await {
+                Ok(deleted) => deleted.len(),
+                // Nothing written to the collection yet.
+                Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(describe(e)),
+            };
+            json!({ "deletedCount": deleted })
+        }
+        _ => return Err(format!("unknown method '{}'; try 'help'", method)),
+    };
+    Ok(format!(
+        "{}\n",
+        serde_json::to_string_pretty(&result).unwrap_or_default()
+    ))
+}
+
+/// The documents of `collection` matching `filter`, none if it doesn't
+/// exist yet.
+async fn matching(
+    database: &Database,
+    collection: &str,
+    filter: Document,
+) -> Result<Vec<(String, Document)>, String> {
+    match database.find_with_ids(collection.to_string(), filter).await {
+        Ok(found) => Ok(found),
+        // Nothing written to the collection yet.
+        Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Vec::new())
+        }
+        Err(e) => Err(describe(e)),
+    }
+}
+
+async fn first(
+    database: &Database,
+    collection: &str,
+    filter: Document,
+) -> Result<Option<String>, String> {
+    Ok(matching(database, collection, filter)
+        .await?
+        .into_iter()
+        .next()
+        .map(|(id, _)| id))
+}
+
+fn describe(e: DatabaseError) -> String {
+    format!("{:?}", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_statement() {
+        assert_eq!(
+            parse_statement("show collections;"),
+            Ok(Statement::ShowCollections)
+        );
+        assert_eq!(
+            parse_statement(r#"db.users.updateOne({"name": "John"}, {"$set": {"age": 31}})"#),
+            Ok(Statement::Call {
+                collection: "users".to_string(),
+                method: "updateOne".to_string(),
+                args: vec![json!({ "name": "John" }), json!({ "$set": { "age": 31 } })],
+            })
+        );
+        assert!(parse_statement("db.users.find({name: 1})").is_err());
+        assert!(parse_statement("users.find()").is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let collections = ["orders".to_string(), "users".to_string()];
+        assert_eq!(
+            candidates("sh", &collections),
+            (0, vec!["show collections".to_string()])
+        );
+        assert_eq!(
+            candidates("db.u", &collections),
+            (3, vec!["users.".to_string()])
+        );
+        assert_eq!(
+            candidates("db.users.find", &collections),
+            (9, vec!["find(".to_string(), "findOne(".to_string()])
+        );
+        assert_eq!(
+            candidates("db.users.find({\"a\": 1}, ", &collections)
+                .1
+                .len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statements() {
+        let _ = tokio::fs::remove_dir_all("data_tests/test_shell").await;
+        let db = Database::init("data_tests/test_shell".to_string())
+            .await
+            .unwrap();
+        let run = |line: &str| {
+            let statement = parse_statement(line).unwrap();
+            let db = db.clone();
+            async move { execute(&db, statement).await }
+        };
+
+        assert_eq!(run("db.users.find()").await.unwrap(), "[]\n");
+        run(r#"db.users.insertMany([{"name": "John", "age": 30}, {"name": "Jane", "age": 25}])"#)
+            .await
+            .unwrap();
+        assert_eq!(run("show collections").await.unwrap(), "users\n");
+        assert_eq!(
+            run(r#"db.users.countDocuments({"age": 30})"#)
+                .await
+                .unwrap(),
+            "1\n"
+        );
+        run(r#"db.users.updateOne({"name": "Jane"}, {"$set": {"age": 26}})"#)
+            .await
+            .unwrap();
+        let jane: Value =
+            serde_json::from_str(&run(r#"db.users.findOne({"name": "Jane"})"#).await.unwrap())
+                .unwrap();
+        assert_eq!(jane["age"], 26);
+        assert_eq!(
+            run("db.users.deleteMany({})").await.unwrap(),
+            "{\n  \"deletedCount\": 2\n}\n"
+        );
+        assert!(run("db.users.drop()").await.is_err());
+        db.close().await.unwrap();
+    }
+}
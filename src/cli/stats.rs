@@ -0,0 +1,128 @@
+//! `owldb stats`: where the disk goes, collection by collection, biggest
+//! first.
+
+use owldb::db::{Database, DatabaseError, DatabaseStats};
+use serde_json::json;
+
+use super::{human_bytes, table};
+
+pub(crate) async fn run(database: &Database, json: bool) -> Result<String, DatabaseError> {
+    let mut stats = database.stats().await?;
+    stats
+        .collections
+        .sort_by(|a, b| b.size_bytes.cmp(&a.size_bytes).then(a.name.cmp(&b.name)));
+    Ok(match json {
+        true => format!(
+            "{}\n",
+            serde_json::to_string_pretty(&to_json(&stats)).unwrap_or_default()
+        ),
+        false => format_table(&stats),
+    })
+}
+
+fn to_json(stats: &DatabaseStats) -> serde_json::Value {
+    let collections: Vec<_> = stats
+        .collections
+        .iter()
+        .map(|collection| {
+            let largest: Vec<_> = collection
+                .largest_documents
+                .iter()
+                .map(|document| json!({ "_id": document.id, "size_bytes": document.size_bytes }))
+                .collect();
+            json!({
+                "name": collection.name,
+                "documents": collection.documents,
+                "size_bytes": collection.size_bytes,
+                "index_bytes": collection.index_bytes,
+                "largest_documents": largest,
+            })
+        })
+        .collect();
+    json!({
+        "disk_usage_bytes": stats.disk_usage_bytes,
+        "collections": collections,
+    })
+}
+
+fn format_table(stats: &DatabaseStats) -> String {
+    let rows: Vec<_> = stats
+        .collections
+        .iter()
+        .map(|collection| {
+            vec![
+                collection.name.clone(),
+                collection.documents.to_string(),
+                human_bytes(collection.size_bytes),
+                human_bytes(collection.index_bytes),
+            ]
+        })
+        .collect();
+    let largest: Vec<_> = stats
+        .collections
+        .iter()
+        .flat_map(|collection| {
+            collection.largest_documents.iter().map(|document| {
+                vec![
+                    collection.name.clone(),
+                    document.id.clone(),
+                    human_bytes(document.size_bytes),
+                ]
+            })
+        })
+        .collect();
+
+    let mut output = table(&["COLLECTION", "DOCUMENTS", "SIZE", "INDEXES"], &rows);
+    if !largest.is_empty() {
+        output.push('\n');
+        output.push_str(&table(
+            &["COLLECTION", "LARGEST DOCUMENTS", "SIZE"],
+            &largest,
+        ));
+    }
+    // Logs, versions and backups make up the rest.
+    output.push_str(&format!(
+        "\nOn disk: {}\n",
+        human_bytes(stats.disk_usage_bytes)
+    ));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stats_report() {
+        let _ = tokio::fs::remove_dir_all("data_tests/test_cli_stats").await;
+        let db = Database::init("data_tests/test_cli_stats".to_string())
+            .await
+            .unwrap();
+        db.put(
+            "logs".to_string(),
+            "big".to_string(),
+            bson::doc! { "line": "x".repeat(2000) },
+        )
+        .await
+        .unwrap();
+        db.put(
+            "users".to_string(),
+            "ada".to_string(),
+            bson::doc! { "name": "Ada" },
+        )
+        .await
+        .unwrap();
+
+        let report: serde_json::Value =
+            serde_json::from_str(&run(&db, true).await.unwrap()).unwrap();
+        let collections = report["collections"].as_array().unwrap();
+        assert_eq!(collections[0]["name"], "logs");
+        assert_eq!(collections[0]["largest_documents"][0]["_id"], "big");
+        assert_eq!(collections[1]["documents"], 1);
+
+        let table = run(&db, false).await.unwrap();
+        assert!(table.starts_with("COLLECTION  DOCUMENTS  SIZE"));
+        assert!(table.contains("logs        big"));
+        db.close().await.unwrap();
+    }
+}
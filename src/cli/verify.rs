@@ -0,0 +1,96 @@
+//! `owldb verify`: checks a database folder no server has open, exiting
+//! with a status cron can alert on.
+
+use owldb::db::{Problem, VerifyReport};
+
+use super::Output;
+
+/// Problems were found and all of them repaired.
+pub(crate) const REPAIRED: i32 = 2;
+/// Problems were found and some remain.
+pub(crate) const DAMAGED: i32 = 3;
+
+/// One line per problem, then a summary.
+pub(crate) fn output(report: VerifyReport) -> Output {
+    let status = match (report.repaired.is_empty(), report.problems.is_empty()) {
+        (_, false) => DAMAGED,
+        (false, true) => REPAIRED,
+        (true, true) => 0,
+    };
+
+    let mut text: String = report
+        .repaired
+        .iter()
+        .map(|problem| format!("repaired: {}\n", describe(problem)))
+        .chain(
+            report
+                .problems
+                .iter()
+                .map(|problem| format!("problem: {}\n", describe(problem))),
+        )
+        .collect();
+    text.push_str(&format!(
+        "Checked {} collections, {} documents: {} problems, {} repaired\n",
+        report.collections,
+        report.documents,
+        report.problems.len() + report.repaired.len(),
+        report.repaired.len()
+    ));
+    Output { text, status }
+}
+
+fn describe(problem: &Problem) -> String {
+    match problem {
+        Problem::CorruptDocument {
+            collection,
+            file,
+            reason,
+        } => format!("{}/{} doesn't decode: {}", collection, file, reason),
+        Problem::UnreadableCollection { collection, reason } => {
+            format!("{} can't be read: {}", collection, reason)
+        }
+        Problem::UnappliedLog { log, records } => {
+            format!("{} holds {} unapplied writes", log, records)
+        }
+        Problem::Leftover { path } => format!("{} is left from an interrupted change", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output() {
+        let clean = VerifyReport {
+            collections: 1,
+            documents: 3,
+            ..VerifyReport::default()
+        };
+        let output = output(clean.clone());
+        assert_eq!(output.status, 0);
+        assert_eq!(
+            output.text,
+            "Checked 1 collections, 3 documents: 0 problems, 0 repaired\n"
+        );
+
+        let leftover = Problem::Leftover {
+            path: "users.defrag".to_string(),
+        };
+        let repaired = VerifyReport {
+            repaired: vec![leftover.clone()],
+            ..clean.clone()
+        };
+        assert_eq!(super::output(repaired).status, REPAIRED);
+        let damaged = VerifyReport {
+            repaired: vec![leftover.clone()],
+            problems: vec![leftover],
+            ..clean
+        };
+        let output = super::output(damaged);
+        assert_eq!(output.status, DAMAGED);
+        assert!(output
+            .text
+            .starts_with("repaired: users.defrag is left from an interrupted change\nproblem: "));
+    }
+}
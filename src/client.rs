@@ -0,0 +1,1035 @@
+//! Async client for the gRPC front end, `owldb::server::grpc`, shaped like
+//! `Database`: `insert_one`, `find_one`, `find`, `update_one`, `delete_one`,
+//! and transactions whose writes commit as one unit on the server.
+//!
+//! A client keeps a pool of connections and hands calls to them in turn; a
+//! dropped connection is reopened on its next call. Calls failing
+//! transiently, because the server was unreachable or aborted a write that
+//! lost a race, are retried as `ClientOptions::retry` says. Updates and
+//! transaction commits, which would change the data again if applied
+//! twice, are the exception: once the server may have got one, an
+//! unreachable server or a missed deadline fails it with
+//! `DatabaseError::Unavailable`, leaving the caller to check what landed.
+//!
+//! `tail`, `replicate` and `snapshot` read a server's oplog and documents,
+//! as followers do (see `owldb::server::replication`).
+//!
+//! A client may also know some of the server's followers, to send reads to
+//! as its `ReadPreference` says; writes always go to the server. Followers
+//! lag their leader a little, so such reads may miss the latest writes.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use bson::Document;
+use log::{info, warn};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Status, Streaming};
+
+use crate::db::{
+    with_retry, with_retry_if, BatchResult, DatabaseError, OperationType, OplogEntry, RetryOptions,
+    WriteOp, WriteStamp,
+};
+use crate::server::grpc;
+use crate::server::grpc::proto::{self, owl_db_client::OwlDbClient};
+
+/// Acknowledgements a `ReplicationStream` may queue before sending waits.
+const ACK_BUFFER: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Connections opened to the server.
+    pub connections: usize,
+    pub connect_timeout: Duration,
+    /// Deadline for each attempt at a call.
+    pub request_timeout: Duration,
+    pub retry: RetryOptions,
+    /// The database to use on a server hosting several; its default one
+    /// when unset.
+    pub database: Option<String>,
+    /// Followers of the server, such as `http://10.0.0.2:50051`, that reads
+    /// may go to. They're connected to on first use.
+    pub replicas: Vec<String>,
+    pub read_preference: ReadPreference,
+}
+
+/// Where a client sends its reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadPreference {
+    /// Always the server.
+    #[default]
+    Primary,
+    /// The server, or the nearest replica while it's unreachable.
+    PrimaryPreferred,
+    /// Whichever of the server and its replicas has answered fastest
+    /// lately, trying each one at least once.
+    Nearest,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            connections: 4,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            retry: RetryOptions::default(),
+            database: None,
+            replicas: Vec::new(),
+            read_preference: ReadPreference::default(),
+        }
+    }
+}
+
+/// A handle to a server. Clones share the connections and the login.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<ClientInner>,
+}
+
+struct ClientInner {
+    /// The server, then its replicas.
+    members: Vec<Member>,
+    retry: RetryOptions,
+    read_preference: ReadPreference,
+    /// Sent as `x-owldb-database` with every call.
+    database: Option<MetadataValue<Ascii>>,
+    /// Kept from `login`, for logging in to replicas as they're first used.
+    credentials: RwLock<Option<(String, String)>>,
+}
+
+/// The server or one of its replicas.
+struct Member {
+    uri: String,
+    pool: Vec<OwlDbClient<Channel>>,
+    next: AtomicUsize,
+    /// Sent as `authorization: Bearer <token>` once logged in; each server
+    /// hands out its own.
+    token: RwLock<Option<String>>,
+    /// Smoothed duration of its calls, in microseconds; 0 until it's
+    /// answered one.
+    latency_micros: AtomicU64,
+}
+
+impl Member {
+    fn new(uri: &str, pool: Vec<OwlDbClient<Channel>>) -> Self {
+        Self {
+            uri: uri.to_string(),
+            pool,
+            next: AtomicUsize::new(0),
+            token: RwLock::new(None),
+            latency_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn connection(&self) -> OwlDbClient<Channel> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.pool[next % self.pool.len()].clone()
+    }
+
+    fn record_latency(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let _ = self
+            .latency_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |latency| {
+                Some(match latency {
+                    0 => sample,
+                    latency => (latency * 7 + sample) / 8,
+                })
+            });
+    }
+}
+
+impl Client {
+    /// Connects to the server at `uri`, such as `http://127.0.0.1:50051`.
+    pub async fn connect(uri: &str) -> Result<Self, DatabaseError> {
+        Self::connect_with_options(uri, ClientOptions::default()).await
+    }
+
+    pub async fn connect_with_options(
+        uri: &str,
+        options: ClientOptions,
+    ) -> Result<Self, DatabaseError> {
+        let invalid_input = |message: String| {
+            DatabaseError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            ))
+        };
+        let database = options
+            .database
+            .map(|name| name.parse())
+            .transpose()
+            .map_err(|_| invalid_input("invalid database name".to_string()))?;
+        let endpoint = |uri: &str| {
+            Ok::<_, DatabaseError>(
+                Endpoint::from_shared(uri.to_string())
+                    .map_err(|e| invalid_input(e.to_string()))?
+                    .connect_timeout(options.connect_timeout)
+                    .timeout(options.request_timeout),
+            )
+        };
+        let connections = options.connections.max(1);
+
+        let primary = endpoint(uri)?;
+        let mut pool = Vec::with_capacity(connections);
+        for _ in 0..connections {
+            let channel = primary
+                .connect()
+                .await
+                .map_err(|e| DatabaseError::Unavailable(e.to_string()))?;
+            pool.push(OwlDbClient::new(channel));
+        }
+        let mut members = vec![Member::new(uri, pool)];
+        for replica in &options.replicas {
+            let replica_endpoint = endpoint(replica)?;
+            let pool = (0..connections)
+                .map(|_| OwlDbClient::new(replica_endpoint.connect_lazy()))
+                .collect();
+            members.push(Member::new(replica, pool));
+        }
+
+        info!(
+            "Successfully connected to {} with {} connections",
+            uri, connections
+        );
+
+        Ok(Self {
+            inner: Arc::new(ClientInner {
+                members,
+                retry: options.retry,
+                read_preference: options.read_preference,
+                database,
+                credentials: RwLock::new(None),
+            }),
+        })
+    }
+
+    /// Logs in, so that later calls are made as `username`. Replicas are
+    /// logged in to as they're first used.
+    pub async fn login(&self, username: &str, password: &str) -> Result<(), DatabaseError> {
+        self.login_to(&self.inner.members[0], username, password)
+            .await?;
+        for replica in &self.inner.members[1..] {
+            *replica.token.write().unwrap() = None;
+        }
+        *self.inner.credentials.write().unwrap() =
+            Some((username.to_string(), password.to_string()));
+        Ok(())
+    }
+
+    async fn login_to(
+        &self,
+        member: &Member,
+        username: &str,
+        password: &str,
+    ) -> Result<(), DatabaseError> {
+        let message = proto::LoginRequest {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        let token = with_retry(&self.inner.retry, || {
+            let mut client = member.connection();
+            let request = self.request(member, message.clone());
+            async move { client.login(request).await.map_err(from_status) }
+        })
+        .await?
+        .into_inner()
+        .token;
+
+        *member.token.write().unwrap() = Some(token);
+        Ok(())
+    }
+
+    /// The ID is picked here, so a retried insert whose first attempt did
+    /// land finds its document there instead of writing a second one.
+    pub async fn insert_one(
+        &self,
+        collection: String,
+        doc: Document,
+    ) -> Result<String, DatabaseError> {
+        let id = bson::oid::ObjectId::new().to_hex();
+        let message = proto::InsertRequest {
+            collection,
+            document: encode(&doc)?,
+            id: id.clone(),
+            if_absent: true,
+        };
+        let inserted = self
+            .call(|mut client, member| {
+                let request = self.request(member, message.clone());
+                async move { client.insert(request).await }
+            })
+            .await;
+
+        match inserted {
+            // Nobody else has this ID; an earlier attempt wrote it.
+            Ok(_) | Err(DatabaseError::DocumentExists(_)) => Ok(id),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `doc` under `id`, replacing any document there.
+    pub async fn put(
+        &self,
+        collection: String,
+        id: String,
+        doc: Document,
+    ) -> Result<(), DatabaseError> {
+        let message = proto::InsertRequest {
+            collection,
+            document: encode(&doc)?,
+            id,
+            if_absent: false,
+        };
+        self.call(|mut client, member| {
+            let request = self.request(member, message.clone());
+            async move { client.insert(request).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn find_one(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Document>, DatabaseError> {
+        let message = proto::GetRequest { collection, id };
+        let response = self
+            .read(|mut client, member| {
+                let request = self.request(member, message.clone());
+                async move { client.get(request).await }
+            })
+            .await?;
+
+        response.document.map(|doc| decode(&doc.bson)).transpose()
+    }
+
+    pub async fn find(
+        &self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<Document>, DatabaseError> {
+        Ok(self
+            .find_with_ids(collection, query)
+            .await?
+            .into_iter()
+            .map(|(_, doc)| doc)
+            .collect())
+    }
+
+    /// Like `find`, but pairs every document with its id.
+    pub async fn find_with_ids(
+        &self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<(String, Document)>, DatabaseError> {
+        let message = proto::FindRequest {
+            collection,
+            query: encode(&query)?,
+        };
+        let response = self
+            .read(|mut client, member| {
+                let request = self.request(member, message.clone());
+                async move { client.find(request).await }
+            })
+            .await?;
+
+        response
+            .documents
+            .into_iter()
+            .map(|doc| Ok((doc.id, decode(&doc.bson)?)))
+            .collect()
+    }
+
+    /// Same update forms as `Database::update_one`.
+    pub async fn update_one(
+        &self,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<bool, DatabaseError> {
+        let message = proto::UpdateRequest {
+            collection,
+            id,
+            update: encode(&update)?,
+        };
+        let response = self
+            .call_unrepeated(|mut client, member| {
+                let request = self.request(member, message.clone());
+                async move { client.update(request).await }
+            })
+            .await?;
+
+        Ok(response.updated)
+    }
+
+    pub async fn delete_one(&self, collection: String, id: String) -> Result<(), DatabaseError> {
+        let message = proto::DeleteRequest { collection, id };
+        self.call(|mut client, member| {
+            let request = self.request(member, message.clone());
+            async move { client.delete(request).await }
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Streams the server's oplog from position `from`, or from the oldest
+    /// entry it holds when 0, then its writes as they're made. Only admins
+    /// may.
+    pub async fn tail(&self, from: u64) -> Result<OplogStream, DatabaseError> {
+        let message = proto::TailRequest { from };
+        let stream = self
+            .call(|mut client, member| {
+                let request = self.request(member, message.clone());
+                async move { client.tail(request).await }
+            })
+            .await?;
+
+        Ok(OplogStream { stream })
+    }
+
+    /// Streams the server's oplog from position `from` like `tail`, in
+    /// batches of at most `batch_size` entries; the server sends no more
+    /// than `window` entries ahead of the last one acknowledged with
+    /// `ReplicationStream::acknowledge`. 0 leaves either to the server.
+    /// Naming the `follower` has the server hold the entries it misses
+    /// while away, for the next call.
+    pub async fn replicate(
+        &self,
+        from: u64,
+        batch_size: u32,
+        window: u32,
+        follower: Option<&str>,
+    ) -> Result<ReplicationStream, DatabaseError> {
+        let first = proto::ReplicateRequest {
+            from,
+            batch_size,
+            window,
+            acknowledged: 0,
+            follower: follower.unwrap_or_default().to_string(),
+        };
+        let mut acks = None;
+        let stream = self
+            .call(|mut client, member| {
+                // A fresh request stream for every attempt.
+                let (sender, receiver) = mpsc::channel(ACK_BUFFER);
+                let _ = sender.try_send(first.clone());
+                acks = Some(sender);
+                let request = self.request(member, ReceiverStream::new(receiver));
+                async move { client.replicate(request).await }
+            })
+            .await?;
+
+        Ok(ReplicationStream {
+            stream,
+            acks: acks.expect("the call was made"),
+        })
+    }
+
+    /// Streams every document on the server, along with the oplog position
+    /// to `tail` from to see the writes made since. Only admins may.
+    pub async fn snapshot(&self) -> Result<SnapshotStream, DatabaseError> {
+        let response = self
+            .call_response(|mut client, member| {
+                let request = self.request(member, proto::SnapshotRequest {});
+                async move { client.snapshot(request).await }
+            })
+            .await?;
+        let position = response
+            .metadata()
+            .get(grpc::OPLOG_POSITION_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                DatabaseError::ServerError("snapshot without an oplog position".to_string())
+            })?;
+
+        Ok(SnapshotStream {
+            position,
+            stream: response.into_inner(),
+        })
+    }
+
+    pub fn begin_transaction(&self) -> Transaction {
+        Transaction {
+            client: self.clone(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Runs `operation` on the server, retrying transient failures.
+    async fn call<T, F, Fut>(&self, operation: F) -> Result<T, DatabaseError>
+    where
+        F: FnMut(OwlDbClient<Channel>, &Member) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        self.call_response(operation)
+            .await
+            .map(tonic::Response::into_inner)
+    }
+
+    /// Like `call`, keeping the response's metadata.
+    async fn call_response<T, F, Fut>(
+        &self,
+        operation: F,
+    ) -> Result<tonic::Response<T>, DatabaseError>
+    where
+        F: FnMut(OwlDbClient<Channel>, &Member) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        self.call_on(&self.inner.members[0], operation).await
+    }
+
+    /// Runs the read `operation` where the read preference says.
+    async fn read<T, F, Fut>(&self, mut operation: F) -> Result<T, DatabaseError>
+    where
+        F: FnMut(OwlDbClient<Channel>, &Member) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let members = &self.inner.members;
+        let response = match self.inner.read_preference {
+            ReadPreference::Primary => self.call_on(&members[0], operation).await,
+            ReadPreference::PrimaryPreferred => {
+                match self.call_on(&members[0], &mut operation).await {
+                    Err(DatabaseError::Unavailable(message)) if members.len() > 1 => {
+                        warn!(
+                            "Reading from a replica, {} is unavailable: {}",
+                            members[0].uri, message
+                        );
+                        self.call_on(nearest(&members[1..]), operation).await
+                    }
+                    response => response,
+                }
+            }
+            ReadPreference::Nearest => self.call_on(nearest(members), operation).await,
+        };
+        response.map(tonic::Response::into_inner)
+    }
+
+    /// Like `call`, for a write that mustn't be applied twice. Only retries
+    /// what the server turned down without writing anything, a lost race;
+    /// an `Unavailable` server may have applied it before the connection
+    /// dropped or the deadline passed.
+    async fn call_unrepeated<T, F, Fut>(&self, operation: F) -> Result<T, DatabaseError>
+    where
+        F: FnMut(OwlDbClient<Channel>, &Member) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let retryable =
+            |e: &DatabaseError| e.is_transient() && !matches!(e, DatabaseError::Unavailable(_));
+        self.call_on_if(&self.inner.members[0], retryable, operation)
+            .await
+            .map(tonic::Response::into_inner)
+    }
+
+    /// Runs `operation` on the next connection of `member`'s pool,
+    /// retrying transient failures.
+    async fn call_on<T, F, Fut>(
+        &self,
+        member: &Member,
+        operation: F,
+    ) -> Result<tonic::Response<T>, DatabaseError>
+    where
+        F: FnMut(OwlDbClient<Channel>, &Member) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        self.call_on_if(member, DatabaseError::is_transient, operation)
+            .await
+    }
+
+    /// Like `call_on`, retrying only the failures `retryable` accepts.
+    async fn call_on_if<T, F, Fut>(
+        &self,
+        member: &Member,
+        retryable: fn(&DatabaseError) -> bool,
+        mut operation: F,
+    ) -> Result<tonic::Response<T>, DatabaseError>
+    where
+        F: FnMut(OwlDbClient<Channel>, &Member) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let is_replica = !std::ptr::eq(member, &self.inner.members[0]);
+        let credentials = self.inner.credentials.read().unwrap().clone();
+        if let Some((username, password)) = credentials {
+            if is_replica && member.token.read().unwrap().is_none() {
+                self.login_to(member, &username, &password).await?;
+            }
+        }
+
+        let started = Instant::now();
+        let response = with_retry_if(&self.inner.retry, retryable, || {
+            let response = operation(member.connection(), member);
+            async move { response.await.map_err(from_status) }
+        })
+        .await?;
+        member.record_latency(started.elapsed());
+        Ok(response)
+    }
+
+    fn request<T>(&self, member: &Member, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(database) = &self.inner.database {
+            request
+                .metadata_mut()
+                .insert(grpc::DATABASE_METADATA_KEY, database.clone());
+        }
+        if let Some(token) = member.token.read().unwrap().as_ref() {
+            // Tokens are hex, so always valid metadata.
+            if let Ok(value) = format!("Bearer {}", token).parse() {
+                request.metadata_mut().insert("authorization", value);
+            }
+        }
+        request
+    }
+}
+
+/// The member of `members` that's answered fastest, preferring ones that
+/// haven't answered yet, then the earliest.
+fn nearest(members: &[Member]) -> &Member {
+    members
+        .iter()
+        .min_by_key(|member| member.latency_micros.load(Ordering::Relaxed))
+        .expect("a client has a server")
+}
+
+/// Writes collected on the client and sent to the server by `commit`, which
+/// applies all of them or none. Reads made meanwhile don't see them, and
+/// dropping the transaction discards them.
+pub struct Transaction {
+    client: Client,
+    writes: Vec<proto::Write>,
+}
+
+impl Transaction {
+    pub fn insert_one(&mut self, collection: String, doc: Document) -> Result<(), DatabaseError> {
+        self.push(collection, WriteOp::Insert(doc))
+    }
+
+    pub fn update_one(
+        &mut self,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<(), DatabaseError> {
+        self.push(collection, WriteOp::Update { id, update })
+    }
+
+    pub fn delete_one(&mut self, collection: String, id: String) -> Result<(), DatabaseError> {
+        self.push(collection, WriteOp::Delete { id })
+    }
+
+    fn push(&mut self, collection: String, op: WriteOp) -> Result<(), DatabaseError> {
+        let write = match op {
+            WriteOp::Insert(doc) => proto::Write {
+                operation: proto::Operation::Insert as i32,
+                collection,
+                id: String::new(),
+                document: encode(&doc)?,
+            },
+            WriteOp::Update { id, update } => proto::Write {
+                operation: proto::Operation::Update as i32,
+                collection,
+                id,
+                document: encode(&update)?,
+            },
+            WriteOp::Delete { id } => proto::Write {
+                operation: proto::Operation::Delete as i32,
+                collection,
+                id,
+                document: Vec::new(),
+            },
+        };
+        self.writes.push(write);
+        Ok(())
+    }
+
+    /// Sends the writes to the server. Counts are as `Database::apply_batch`
+    /// reports them.
+    pub async fn commit(self) -> Result<BatchResult, DatabaseError> {
+        let message = proto::CommitRequest {
+            writes: self.writes,
+        };
+        let response = self
+            .client
+            .call_unrepeated(|mut client, member| {
+                let request = self.client.request(member, message.clone());
+                async move { client.commit(request).await }
+            })
+            .await?;
+
+        Ok(BatchResult {
+            inserted_ids: response.inserted_ids,
+            updated: response.updated as usize,
+            deleted: response.deleted as usize,
+        })
+    }
+}
+
+/// A server's oplog entries, from `Client::tail`.
+pub struct OplogStream {
+    stream: Streaming<proto::OplogEntry>,
+}
+
+impl OplogStream {
+    /// The next entry, waiting for the server to write one; `None` once
+    /// the server ends the stream.
+    pub async fn next(&mut self) -> Result<Option<OplogEntry>, DatabaseError> {
+        match self.stream.message().await.map_err(from_status)? {
+            Some(entry) => from_oplog_entry(entry).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The oplog in batches, from `Client::replicate`.
+pub struct ReplicationStream {
+    stream: Streaming<proto::OplogBatch>,
+    acks: mpsc::Sender<proto::ReplicateRequest>,
+}
+
+impl ReplicationStream {
+    /// The next batch, waiting for the server to write, or for room in the
+    /// window; `None` once the server ends the stream.
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<OplogEntry>>, DatabaseError> {
+        let Some(batch) = self.stream.message().await.map_err(from_status)? else {
+            return Ok(None);
+        };
+        batch
+            .entries
+            .into_iter()
+            .map(from_oplog_entry)
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Tells the server the entries up to `position` have been applied,
+    /// making room for more.
+    pub async fn acknowledge(&self, position: u64) -> Result<(), DatabaseError> {
+        let ack = proto::ReplicateRequest {
+            acknowledged: position,
+            ..proto::ReplicateRequest::default()
+        };
+        self.acks
+            .send(ack)
+            .await
+            .map_err(|_| DatabaseError::Unavailable("the replication stream ended".to_string()))
+    }
+}
+
+fn from_oplog_entry(entry: proto::OplogEntry) -> Result<OplogEntry, DatabaseError> {
+    let operation = match entry.operation() {
+        proto::Operation::Insert => OperationType::Insert,
+        proto::Operation::Update => OperationType::Update,
+        proto::Operation::Delete => OperationType::Delete,
+        proto::Operation::Expire => OperationType::Expire,
+    };
+    let decode_image = |bytes: &[u8]| match bytes.is_empty() {
+        true => Ok(None),
+        false => decode(bytes).map(Some),
+    };
+
+    Ok(OplogEntry {
+        position: entry.position,
+        timestamp: bson::DateTime::from_millis(entry.timestamp),
+        operation,
+        collection: entry.collection,
+        id: entry.id,
+        previous: decode_image(&entry.previous)?,
+        document: decode_image(&entry.document)?,
+        origin: match entry.origin_node.is_empty() {
+            true => None,
+            false => Some(WriteStamp {
+                timestamp: bson::DateTime::from_millis(entry.origin_timestamp),
+                node: entry.origin_node,
+            }),
+        },
+    })
+}
+
+/// A server's documents, from `Client::snapshot`.
+pub struct SnapshotStream {
+    position: u64,
+    stream: Streaming<proto::SnapshotDocument>,
+}
+
+impl SnapshotStream {
+    /// Where to tail the oplog from once the snapshot's been read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The next document, with its collection and id; `None` once they've
+    /// all been sent.
+    pub async fn next(&mut self) -> Result<Option<(String, String, Document)>, DatabaseError> {
+        match self.stream.message().await.map_err(from_status)? {
+            Some(doc) => Ok(Some((doc.collection, doc.id, decode(&doc.document)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn from_status(status: Status) -> DatabaseError {
+    let message = status.message().to_string();
+    match status.code() {
+        Code::Unauthenticated => DatabaseError::Unauthenticated,
+        Code::PermissionDenied => DatabaseError::PermissionDenied(message),
+        Code::InvalidArgument => DatabaseError::InvalidUpdate(message),
+        Code::AlreadyExists => DatabaseError::DocumentExists(message),
+        // The server aborts writes that lost a race, before writing anything.
+        Code::Aborted => DatabaseError::WriteConflict(message),
+        Code::Unavailable | Code::DeadlineExceeded => DatabaseError::Unavailable(message),
+        // The server sends these as their `Debug` form.
+        Code::OutOfRange => match message
+            .strip_prefix("OplogTruncated(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .and_then(|position| position.parse().ok())
+        {
+            Some(position) => DatabaseError::OplogTruncated(position),
+            None => DatabaseError::ServerError(message),
+        },
+        Code::FailedPrecondition if message == "OplogDisabled" => DatabaseError::OplogDisabled,
+        _ => DatabaseError::ServerError(message),
+    }
+}
+
+fn encode(doc: &Document) -> Result<Vec<u8>, DatabaseError> {
+    let mut buffer = Vec::new();
+    doc.to_writer(&mut buffer)
+        .map_err(DatabaseError::BsonSerError)?;
+    Ok(buffer)
+}
+
+fn decode(bytes: &[u8]) -> Result<Document, DatabaseError> {
+    Document::from_reader(&mut &bytes[..]).map_err(DatabaseError::BsonDeError)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+    use crate::db::Database;
+    use crate::server::auth::Auth;
+    use crate::server::tenants::Tenants;
+
+    #[tokio::test]
+    async fn test_client_roundtrip() {
+        let folder_path = "data_tests/test_client".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(grpc::service(Tenants::single(db.clone(), Auth::disabled())))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let options = ClientOptions {
+            connections: 2,
+            ..ClientOptions::default()
+        };
+        let client = Client::connect_with_options(&format!("http://{}", addr), options)
+            .await
+            .unwrap();
+
+        let id = client
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        assert!(client
+            .update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$set": { "age": 30 } },
+            )
+            .await
+            .unwrap());
+        assert_eq!(
+            client
+                .find_one("users".to_string(), id.clone())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "name": "John", "age": 30 })
+        );
+
+        let mut transaction = client.begin_transaction();
+        transaction
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .unwrap();
+        transaction
+            .delete_one("users".to_string(), id.clone())
+            .unwrap();
+        transaction
+            .insert_one("audit".to_string(), bson::doc! { "deleted": &id })
+            .unwrap();
+        let result = transaction.commit().await.unwrap();
+        assert_eq!(result.inserted_ids.len(), 2);
+        assert_eq!(result.deleted, 1);
+
+        assert_eq!(
+            client
+                .find("users".to_string(), bson::doc! {})
+                .await
+                .unwrap(),
+            vec![bson::doc! { "name": "Jane" }]
+        );
+        assert_eq!(
+            db.find("audit".to_string(), bson::doc! {}).await.unwrap(),
+            vec![bson::doc! { "deleted": id }]
+        );
+
+        // A failed update makes the whole transaction fail.
+        let mut transaction = client.begin_transaction();
+        transaction
+            .insert_one("users".to_string(), bson::doc! { "name": "Bob" })
+            .unwrap();
+        transaction
+            .update_one(
+                "users".to_string(),
+                result.inserted_ids[0].clone(),
+                bson::doc! { "$bogus": { "name": "Janet" } },
+            )
+            .unwrap();
+        assert!(transaction.commit().await.is_err());
+        assert_eq!(
+            client
+                .find("users".to_string(), bson::doc! {})
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_preferences() {
+        let mut addrs = Vec::new();
+        let mut shutdowns = Vec::new();
+        for name in ["primary", "replica"] {
+            let folder_path = format!("data_tests/test_client_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let db = Database::init(folder_path).await.unwrap();
+            db.insert_one("whoami".to_string(), bson::doc! { "name": name })
+                .await
+                .unwrap();
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(format!("http://{}", listener.local_addr().unwrap()));
+            let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+            shutdowns.push(shutdown);
+            tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(grpc::service(Tenants::single(db, Auth::disabled())))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                        let _ = stopped.await;
+                    }),
+            );
+        }
+        let connect = |read_preference| {
+            let options = ClientOptions {
+                connections: 1,
+                retry: RetryOptions {
+                    max_attempts: 1,
+                    ..RetryOptions::default()
+                },
+                replicas: vec![addrs[1].clone()],
+                read_preference,
+                ..ClientOptions::default()
+            };
+            Client::connect_with_options(&addrs[0], options)
+        };
+        async fn whoami(client: &Client) -> String {
+            let docs = client
+                .find("whoami".to_string(), bson::doc! {})
+                .await
+                .unwrap();
+            docs[0].get_str("name").unwrap().to_string()
+        }
+
+        let primary = connect(ReadPreference::Primary).await.unwrap();
+        assert_eq!(whoami(&primary).await, "primary");
+        assert_eq!(whoami(&primary).await, "primary");
+
+        // Each member is tried once before latencies decide.
+        let nearest = connect(ReadPreference::Nearest).await.unwrap();
+        let mut seen = vec![whoami(&nearest).await, whoami(&nearest).await];
+        seen.sort();
+        assert_eq!(seen, ["primary", "replica"]);
+
+        let preferred = connect(ReadPreference::PrimaryPreferred).await.unwrap();
+        assert_eq!(whoami(&preferred).await, "primary");
+        for shutdown in shutdowns.drain(..1) {
+            let _ = shutdown.send(());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(whoami(&preferred).await, "replica");
+        assert!(primary
+            .insert_one("whoami".to_string(), bson::doc! { "name": "nobody" })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_not_retried_once_sent() {
+        use std::sync::atomic::AtomicBool;
+
+        let folder_path = "data_tests/test_client_unrepeated".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        // Answers each write, once made, as a server shutting down or a
+        // proxy losing the connection would.
+        let unavailable = Arc::new(AtomicBool::new(false));
+        let answer = unavailable.clone();
+        let layer = tower::util::MapResponseLayer::new(move |response| {
+            match answer.load(Ordering::SeqCst) {
+                true => Status::unavailable("connection reset").into_http(),
+                false => response,
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .layer(layer)
+                .add_service(grpc::service(Tenants::single(db.clone(), Auth::disabled())))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let client = Client::connect(&format!("http://{}", addr)).await.unwrap();
+        let id = client
+            .insert_one("counters".to_string(), bson::doc! { "n": 0 })
+            .await
+            .unwrap();
+
+        unavailable.store(true, Ordering::SeqCst);
+        let increment = || bson::doc! { "$inc": { "n": 1 } };
+        assert!(matches!(
+            client
+                .update_one("counters".to_string(), id.clone(), increment())
+                .await,
+            Err(DatabaseError::Unavailable(_))
+        ));
+        let mut transaction = client.begin_transaction();
+        transaction
+            .update_one("counters".to_string(), id.clone(), increment())
+            .unwrap();
+        assert!(matches!(
+            transaction.commit().await,
+            Err(DatabaseError::Unavailable(_))
+        ));
+
+        // Each landed once.
+        assert_eq!(
+            db.find_one("counters".to_string(), id).await.unwrap(),
+            Some(bson::doc! { "n": 2 })
+        );
+    }
+}
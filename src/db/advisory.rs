@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bson::Document;
+use log::{error, info, warn};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::{Database, DatabaseError};
+
+/// Directory, inside the database folder, holding the advisory lock leases.
+const ADVISORY_DIR: &str = ".advisory";
+
+/// Names up to this many bytes are hex-encoded whole into their file name;
+/// longer ones would run past the file name limit of most file systems.
+const MAX_PLAIN_NAME: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct AdvisoryLockOptions {
+    /// How long the lock stays held without `AdvisoryLock::renew`. A holder
+    /// that dies without releasing it blocks others for at most this long.
+    pub lease: Duration,
+    /// How long `Database::lock_with_options` waits; `None` waits forever.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AdvisoryLockOptions {
+    fn default() -> Self {
+        Self {
+            lease: Duration::from_secs(30),
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Lease {
+    token: u64,
+    expires_at: SystemTime,
+}
+
+impl Lease {
+    fn expired(&self) -> bool {
+        self.expires_at <= SystemTime::now()
+    }
+}
+
+/// Leases on named locks, mirrored to one file per lock so they outlive a
+/// restart of the process: a holder reaching the database through a server
+/// keeps its lock until the lease runs out.
+pub(crate) struct AdvisoryLocks {
+    dir: PathBuf,
+    leases: Mutex<HashMap<String, Lease>>,
+    /// Serializes the writes to the lease files, see `sync_file`.
+    files: tokio::sync::Mutex<()>,
+    released: Notify,
+    next_token: AtomicU64,
+}
+
+impl AdvisoryLocks {
+    pub(crate) fn new(folder_path: &str) -> Self {
+        // Tokens from before a restart must not be handed out again.
+        let first_token = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |duration| duration.as_nanos() as u64);
+
+        Self {
+            dir: Path::new(folder_path).join(ADVISORY_DIR),
+            leases: Mutex::new(HashMap::new()),
+            files: tokio::sync::Mutex::new(()),
+            released: Notify::new(),
+            next_token: AtomicU64::new(first_token),
+        }
+    }
+
+    fn lock_leases(&self) -> MutexGuard<'_, HashMap<String, Lease>> {
+        self.leases.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Picks up the leases persisted by a previous run.
+    pub(crate) async fn load(&self) -> Result<(), DatabaseError> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(DatabaseError::IoError(e)),
+        };
+
+        let mut loaded = HashMap::new();
+        while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+            let buffer = tokio::fs::read(entry.path())
+                .await
+                .map_err(DatabaseError::IoError)?;
+            let doc = match Document::from_reader(&mut buffer.as_slice()) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    warn!(
+                        "Ignoring unreadable advisory lock {:?}: {}",
+                        entry.path(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if let (Ok(name), Ok(token), Ok(expires_at)) = (
+                doc.get_str("name"),
+                doc.get_i64("token"),
+                doc.get_datetime("expires_at"),
+            ) {
+                loaded.insert(
+                    name.to_string(),
+                    Lease {
+                        token: token as u64,
+                        expires_at: expires_at.to_system_time(),
+                    },
+                );
+            }
+        }
+
+        self.lock_leases().extend(loaded);
+        Ok(())
+    }
+
+    fn lease_path(&self, name: &str) -> PathBuf {
+        // Names are arbitrary strings; hex keeps them safe as file names.
+        // Long ones keep a prefix and a checksum of the whole name instead.
+        let bytes = name.as_bytes();
+        let file: String = if bytes.len() <= MAX_PLAIN_NAME {
+            hex(bytes)
+        } else {
+            format!(
+                "{}_{:08x}",
+                hex(&bytes[..MAX_PLAIN_NAME]),
+                crc32fast::hash(bytes)
+            )
+        };
+        self.dir.join(format!("{}.bson", file))
+    }
+
+    /// Brings the lease file of `name` in line with the map. The writes are
+    /// serialized and each one mirrors the map as it is by then, so the file
+    /// ends up matching it even with a release and an acquire racing.
+    async fn sync_file(&self, name: &str) -> Result<(), DatabaseError> {
+        let _files = self.files.lock().await;
+        let lease = self.lock_leases().get(name).copied();
+        let path = self.lease_path(name);
+
+        let result = match lease {
+            Some(lease) => {
+                let doc = bson::doc! {
+                    "name": name,
+                    "token": lease.token as i64,
+                    "expires_at": bson::DateTime::from_system_time(lease.expires_at),
+                };
+                let mut buffer = Vec::new();
+                doc.to_writer(&mut buffer)
+                    .map_err(DatabaseError::BsonSerError)?;
+
+                match tokio::fs::create_dir_all(&self.dir).await {
+                    Ok(()) => tokio::fs::write(path, buffer).await,
+                    Err(e) => Err(e),
+                }
+            }
+            None => match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+        };
+
+        result.map_err(|e| {
+            error!("Failed to persist advisory lock '{}': {}", name, e);
+            DatabaseError::IoError(e)
+        })
+    }
+
+    async fn try_acquire(
+        &self,
+        name: &str,
+        lease: Duration,
+    ) -> Result<Result<u64, SystemTime>, DatabaseError> {
+        let acquired = {
+            let mut leases = self.lock_leases();
+            if let Some(current) = leases.get(name) {
+                if !current.expired() {
+                    return Ok(Err(current.expires_at));
+                }
+            }
+
+            let acquired = Lease {
+                token: self.next_token.fetch_add(1, Ordering::Relaxed),
+                expires_at: SystemTime::now() + lease,
+            };
+            leases.insert(name.to_string(), acquired);
+            acquired
+        };
+
+        if let Err(e) = self.sync_file(name).await {
+            self.forget(name, acquired.token);
+            return Err(e);
+        }
+
+        Ok(Ok(acquired.token))
+    }
+
+    async fn acquire(
+        &self,
+        name: &str,
+        options: &AdvisoryLockOptions,
+    ) -> Result<u64, DatabaseError> {
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let notified = self.released.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let expires_at = match self.try_acquire(name, options.lease).await? {
+                Ok(token) => return Ok(token),
+                Err(expires_at) => expires_at,
+            };
+
+            // Wake up when the holder releases the lock or its lease runs out.
+            let until_expiry = expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO);
+            let mut wake_at = Instant::now() + until_expiry;
+            if let Some(deadline) = deadline {
+                if deadline <= Instant::now() {
+                    return Err(DatabaseError::LockTimeout(name.to_string()));
+                }
+                wake_at = wake_at.min(deadline);
+            }
+            let _ = tokio::time::timeout_at(wake_at, notified).await;
+        }
+    }
+
+    async fn renew(&self, name: &str, token: u64, lease: Duration) -> Result<(), DatabaseError> {
+        {
+            let mut leases = self.lock_leases();
+            match leases.get_mut(name) {
+                Some(current) if current.token == token => {
+                    current.expires_at = SystemTime::now() + lease;
+                }
+                _ => return Err(DatabaseError::AdvisoryLockLost(name.to_string())),
+            }
+        }
+
+        self.sync_file(name).await
+    }
+
+    /// Drops the lease from the map if `token` still holds it, waking up the
+    /// waiters. Returns whether it did; the file is left to `sync_file`.
+    fn forget(&self, name: &str, token: u64) -> bool {
+        let mut leases = self.lock_leases();
+        if leases.get(name).map(|lease| lease.token) != Some(token) {
+            return false;
+        }
+
+        leases.remove(name);
+        self.released.notify_waiters();
+        true
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A held named lock. Released when dropped, or by the lease running out if
+/// the holder stops renewing it.
+pub struct AdvisoryLock {
+    db: Database,
+    name: String,
+    token: u64,
+    lease: Duration,
+}
+
+impl AdvisoryLock {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Extends the lease by another full period. Fails with
+    /// `DatabaseError::AdvisoryLockLost` if the lease ran out and someone
+    /// else took the lock meanwhile.
+    pub async fn renew(&self) -> Result<(), DatabaseError> {
+        self.db
+            .inner
+            .advisory_locks
+            .renew(&self.name, self.token, self.lease)
+            .await
+    }
+
+    pub fn release(self) {}
+}
+
+impl Drop for AdvisoryLock {
+    fn drop(&mut self) {
+        if self.db.inner.advisory_locks.forget(&self.name, self.token) {
+            // Removing the file can't block the dropping task; until it is
+            // gone a restart only sees a lease that runs out on its own.
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                let db = self.db.clone();
+                let name = self.name.clone();
+                handle.spawn(async move {
+                    let _ = db.inner.advisory_locks.sync_file(&name).await;
+                });
+            }
+        }
+        info!("Released advisory lock '{}'", self.name);
+    }
+}
+
+impl Database {
+    /// Waits for the named lock, for coordinating work (e.g. electing the
+    /// one instance that runs a periodic job) between users of the database.
+    /// Nothing stops code that doesn't ask for the lock.
+    pub async fn lock(&self, name: &str) -> Result<AdvisoryLock, DatabaseError> {
+        self.lock_with_options(name, AdvisoryLockOptions::default())
+            .await
+    }
+
+    pub async fn lock_with_options(
+        &self,
+        name: &str,
+        options: AdvisoryLockOptions,
+    ) -> Result<AdvisoryLock, DatabaseError> {
+        self.check_writable()?;
+        let token = self.inner.advisory_locks.acquire(name, &options).await?;
+
+        info!("Successfully acquired advisory lock '{}'", name);
+
+        Ok(AdvisoryLock {
+            db: self.clone(),
+            name: name.to_string(),
+            token,
+            lease: options.lease,
+        })
+    }
+
+    /// Takes the named lock only if it is free right now.
+    pub async fn try_lock(&self, name: &str) -> Result<Option<AdvisoryLock>, DatabaseError> {
+        self.check_writable()?;
+        let options = AdvisoryLockOptions::default();
+
+        Ok(self
+            .inner
+            .advisory_locks
+            .try_acquire(name, options.lease)
+            .await?
+            .ok()
+            .map(|token| AdvisoryLock {
+                db: self.clone(),
+                name: name.to_string(),
+                token,
+                lease: options.lease,
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DatabaseOptions;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_advisory_lock".to_string()).await;
+        db.clear().await.unwrap();
+
+        let held = db.lock("cron").await.unwrap();
+        assert!(db.try_lock("cron").await.unwrap().is_none());
+        assert!(db.try_lock("other").await.unwrap().is_some());
+
+        let waiting = {
+            let db = db.clone();
+            tokio::spawn(async move { db.lock("cron").await.map(|lock| lock.token) })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        held.release();
+        assert!(waiting.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lease_survives_restart_and_expires() {
+        let folder_path = "data_tests/test_advisory_restart".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+
+        let db = Database::init(folder_path.clone()).await.unwrap();
+        let options = AdvisoryLockOptions {
+            lease: Duration::from_millis(200),
+            timeout: Some(Duration::from_millis(20)),
+        };
+        // Taken without a guard, as by a holder that went away without
+        // releasing it.
+        db.inner
+            .advisory_locks
+            .acquire("cron", &options)
+            .await
+            .unwrap();
+        drop(db);
+
+        let db = Database::init_with_options(folder_path, DatabaseOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.lock_with_options("cron", options).await,
+            Err(DatabaseError::LockTimeout(_))
+        ));
+
+        let lock = db
+            .lock_with_options(
+                "cron",
+                AdvisoryLockOptions {
+                    timeout: Some(Duration::from_secs(5)),
+                    ..AdvisoryLockOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        lock.renew().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_long_names_fit_in_file_names() {
+        let folder_path = "data_tests/test_advisory_long_names".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+
+        let db = Database::init(folder_path.clone()).await.unwrap();
+        let first = "job/".repeat(100);
+        let second = format!("{}other", first);
+        let held = db.lock(&first).await.unwrap();
+        db.inner
+            .advisory_locks
+            .acquire(&second, &AdvisoryLockOptions::default())
+            .await
+            .unwrap();
+        held.release();
+
+        // The release removes its file in the background.
+        let dir = Path::new(&folder_path).join(ADVISORY_DIR);
+        let mut files = usize::MAX;
+        for _ in 0..50 {
+            files = std::fs::read_dir(&dir).unwrap().count();
+            if files == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(files, 1);
+        drop(db);
+
+        let db = Database::init(folder_path).await.unwrap();
+        assert!(db.try_lock(&second).await.unwrap().is_none());
+        assert!(db.try_lock(&first).await.unwrap().is_some());
+    }
+}
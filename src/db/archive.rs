@@ -0,0 +1,459 @@
+//! The single file a backup is written to: a gzip stream of documents,
+//! followed by the backup's manifest, uncompressed, so it can be read
+//! without going through the documents.
+//!
+//! ```text
+//! MAGIC | gzip(entry*) | manifest | manifest length (u32 LE) | MAGIC
+//! ```
+//!
+//! Each entry is a BSON document holding a document's `collection`, `id`
+//! and the document itself under `doc`, left out where an incremental
+//! backup records a delete.
+//!
+//! Archives are kept in local files or, with the `object_store` feature,
+//! as objects in a bucket; either way they're written and read as streams.
+
+use std::io::{SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::pin::Pin;
+#[cfg(feature = "object_store")]
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+#[cfg(feature = "object_store")]
+use ::object_store::{path::Path as ObjectPath, ObjectStore};
+use bson::{Bson, Document, RawDocumentBuf};
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+#[cfg(feature = "object_store")]
+use futures_util::stream::{BoxStream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+use super::DatabaseError;
+
+const MAGIC: &[u8; 8] = b"OWLBAK01";
+
+/// Compressed bytes held before they're written out, and read at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The manifest length and the closing magic.
+const TRAILER_SIZE: u64 = 4 + MAGIC.len() as u64;
+
+/// Where an archive is kept.
+#[derive(Clone, Copy)]
+pub(crate) enum Location<'a> {
+    File(&'a Path),
+    #[cfg(feature = "object_store")]
+    Object(&'a Arc<dyn ObjectStore>, &'a ObjectPath),
+}
+
+impl Location<'_> {
+    pub(crate) fn name(&self) -> String {
+        match self {
+            Location::File(path) => path.display().to_string(),
+            #[cfg(feature = "object_store")]
+            Location::Object(store, path) => format!("{}/{}", store, path),
+        }
+    }
+
+    fn missing(&self) -> DatabaseError {
+        DatabaseError::InvalidBackup(format!("'{}' holds no backup", self.name()))
+    }
+
+    async fn size(&self) -> Result<u64, DatabaseError> {
+        match self {
+            Location::File(path) => match tokio::fs::metadata(path).await {
+                Ok(metadata) => Ok(metadata.len()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(self.missing()),
+                Err(e) => Err(DatabaseError::IoError(e)),
+            },
+            #[cfg(feature = "object_store")]
+            Location::Object(store, path) => match store.head(path).await {
+                Ok(meta) => Ok(meta.size),
+                Err(e) => Err(self.object_error(e)),
+            },
+        }
+    }
+
+    async fn read_range(&self, range: Range<u64>) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            Location::File(path) => {
+                let mut file = self.open_file(path).await?;
+                file.seek(SeekFrom::Start(range.start))
+                    .await
+                    .map_err(DatabaseError::IoError)?;
+                let mut bytes = vec![0; (range.end - range.start) as usize];
+                file.read_exact(&mut bytes)
+                    .await
+                    .map_err(DatabaseError::IoError)?;
+                Ok(bytes)
+            }
+            #[cfg(feature = "object_store")]
+            Location::Object(store, path) => match store.get_range(path, range).await {
+                Ok(bytes) => Ok(bytes.to_vec()),
+                Err(e) => Err(self.object_error(e)),
+            },
+        }
+    }
+
+    async fn open_file(&self, path: &Path) -> Result<tokio::fs::File, DatabaseError> {
+        match tokio::fs::File::open(path).await {
+            Ok(file) => Ok(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(self.missing()),
+            Err(e) => Err(DatabaseError::IoError(e)),
+        }
+    }
+
+    #[cfg(feature = "object_store")]
+    fn object_error(&self, e: ::object_store::Error) -> DatabaseError {
+        match e {
+            ::object_store::Error::NotFound { .. } => self.missing(),
+            e => DatabaseError::IoError(std::io::Error::other(e)),
+        }
+    }
+}
+
+/// Where an archive being written goes.
+pub(crate) enum Sink {
+    File(BufWriter<tokio::fs::File>),
+    /// Uploaded in parts as it's written.
+    #[cfg(feature = "object_store")]
+    Object(::object_store::buffered::BufWriter),
+}
+
+impl AsyncWrite for Sink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Sink::File(file) => Pin::new(file).poll_write(cx, buf),
+            #[cfg(feature = "object_store")]
+            Sink::Object(object) => Pin::new(object).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Sink::File(file) => Pin::new(file).poll_flush(cx),
+            #[cfg(feature = "object_store")]
+            Sink::Object(object) => Pin::new(object).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Sink::File(file) => Pin::new(file).poll_shutdown(cx),
+            #[cfg(feature = "object_store")]
+            Sink::Object(object) => Pin::new(object).poll_shutdown(cx),
+        }
+    }
+}
+
+pub(crate) struct ArchiveWriter<W> {
+    writer: W,
+    encoder: GzEncoder<Vec<u8>>,
+}
+
+impl ArchiveWriter<Sink> {
+    /// Starts an archive at `location`, which mustn't exist yet.
+    pub(crate) async fn create(location: Location<'_>) -> Result<Self, DatabaseError> {
+        let exists =
+            || DatabaseError::InvalidBackup(format!("'{}' already exists", location.name()));
+        let sink = match location {
+            Location::File(path) => {
+                let file = match tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .await
+                {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(exists()),
+                    Err(e) => return Err(DatabaseError::IoError(e)),
+                };
+                Sink::File(BufWriter::new(file))
+            }
+            #[cfg(feature = "object_store")]
+            Location::Object(store, path) => match store.head(path).await {
+                Ok(_) => return Err(exists()),
+                Err(::object_store::Error::NotFound { .. }) => Sink::Object(
+                    ::object_store::buffered::BufWriter::new(store.clone(), path.clone()),
+                ),
+                Err(e) => return Err(location.object_error(e)),
+            },
+        };
+        Self::new(sink).await
+    }
+
+    /// Like `finish`, then makes the archive durable or, for an object,
+    /// completes its upload.
+    pub(crate) async fn commit(self, manifest: &Document) -> Result<(), DatabaseError> {
+        match self.finish(manifest).await? {
+            Sink::File(file) => file
+                .into_inner()
+                .sync_all()
+                .await
+                .map_err(DatabaseError::IoError),
+            #[cfg(feature = "object_store")]
+            Sink::Object(mut object) => object.shutdown().await.map_err(DatabaseError::IoError),
+        }
+    }
+
+    /// Drops an archive that won't be finished, so it can't be mistaken for
+    /// a backup.
+    pub(crate) async fn discard(self, location: Location<'_>) {
+        match (self.writer, location) {
+            (Sink::File(_), Location::File(path)) => {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            #[cfg(feature = "object_store")]
+            (Sink::Object(mut object), _) => {
+                let _ = object.abort().await;
+            }
+            #[cfg(feature = "object_store")]
+            (Sink::File(_), Location::Object(..)) => {}
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ArchiveWriter<W> {
+    pub(crate) async fn new(mut writer: W) -> Result<Self, DatabaseError> {
+        writer
+            .write_all(MAGIC)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        Ok(Self {
+            writer,
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+        })
+    }
+
+    pub(crate) async fn write(
+        &mut self,
+        collection: &str,
+        id: &str,
+        doc: Option<RawDocumentBuf>,
+    ) -> Result<(), DatabaseError> {
+        let mut entry = RawDocumentBuf::new();
+        entry.append("collection", collection);
+        entry.append("id", id);
+        if let Some(doc) = doc {
+            entry.append("doc", doc);
+        }
+        self.encoder
+            .write_all(entry.as_bytes())
+            .map_err(DatabaseError::IoError)?;
+
+        if self.encoder.get_ref().len() >= CHUNK_SIZE {
+            let compressed = std::mem::take(self.encoder.get_mut());
+            self.writer
+                .write_all(&compressed)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the documents and writes `manifest` after them. Hands back the
+    /// writer, flushed.
+    pub(crate) async fn finish(mut self, manifest: &Document) -> Result<W, DatabaseError> {
+        let compressed = self.encoder.finish().map_err(DatabaseError::IoError)?;
+        let mut trailer = Vec::new();
+        manifest
+            .to_writer(&mut trailer)
+            .map_err(DatabaseError::BsonSerError)?;
+        let length = trailer.len() as u32;
+        trailer.extend_from_slice(&length.to_le_bytes());
+        trailer.extend_from_slice(MAGIC);
+
+        for bytes in [&compressed, &trailer] {
+            self.writer
+                .write_all(bytes)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+        self.writer.flush().await.map_err(DatabaseError::IoError)?;
+        Ok(self.writer)
+    }
+}
+
+/// Where the compressed documents are read from.
+enum Source {
+    File(tokio::fs::File),
+    #[cfg(feature = "object_store")]
+    Object(BoxStream<'static, std::io::Result<Vec<u8>>>),
+}
+
+pub(crate) struct ArchiveReader {
+    name: String,
+    source: Source,
+    /// Compressed bytes still to be read.
+    remaining: u64,
+    decoder: GzDecoder<Vec<u8>>,
+    decoded: Vec<u8>,
+    /// How much of `decoded` was handed out already.
+    offset: usize,
+    /// Whether everything was decompressed.
+    finished: bool,
+}
+
+impl ArchiveReader {
+    /// Opens the archive at `location`, answering its manifest too.
+    pub(crate) async fn open(location: Location<'_>) -> Result<(Self, Document), DatabaseError> {
+        let (manifest, compressed) = read_manifest(location).await?;
+        let start = MAGIC.len() as u64;
+        let source = match location {
+            Location::File(path) => {
+                let mut file = location.open_file(path).await?;
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(DatabaseError::IoError)?;
+                Source::File(file)
+            }
+            #[cfg(feature = "object_store")]
+            Location::Object(store, path) => {
+                let options = ::object_store::GetOptions {
+                    range: Some((start..start + compressed).into()),
+                    ..Default::default()
+                };
+                match store.get_opts(path, options).await {
+                    Ok(result) => Source::Object(
+                        result
+                            .into_stream()
+                            .map(|chunk| {
+                                chunk
+                                    .map(|bytes| bytes.to_vec())
+                                    .map_err(std::io::Error::other)
+                            })
+                            .boxed(),
+                    ),
+                    Err(e) => return Err(location.object_error(e)),
+                }
+            }
+        };
+
+        let reader = Self {
+            name: location.name(),
+            source,
+            remaining: compressed,
+            decoder: GzDecoder::new(Vec::new()),
+            decoded: Vec::new(),
+            offset: 0,
+            finished: false,
+        };
+        Ok((reader, manifest))
+    }
+
+    /// The next document as `(collection, id, doc)`, or `None` past the
+    /// last one. `doc` is `None` for a delete.
+    pub(crate) async fn next(
+        &mut self,
+    ) -> Result<Option<(String, String, Option<Document>)>, DatabaseError> {
+        loop {
+            if let Some(entry) = self.take_entry()? {
+                let invalid = || self.damaged();
+                let collection = entry.get_str("collection").map_err(|_| invalid())?;
+                let id = entry.get_str("id").map_err(|_| invalid())?;
+                let doc = match entry.get("doc") {
+                    Some(Bson::Document(doc)) => Some(doc.clone()),
+                    Some(_) => return Err(invalid()),
+                    None => None,
+                };
+                return Ok(Some((collection.to_string(), id.to_string(), doc)));
+            }
+
+            if self.finished {
+                if self.offset < self.decoded.len() {
+                    return Err(self.damaged());
+                }
+                return Ok(None);
+            }
+            if self.remaining == 0 {
+                self.decoder.try_finish().map_err(|_| self.damaged())?;
+                self.refill();
+                self.finished = true;
+                continue;
+            }
+
+            let chunk = self.read_chunk().await?;
+            self.remaining = self.remaining.saturating_sub(chunk.len() as u64);
+            self.decoder.write_all(&chunk).map_err(|_| self.damaged())?;
+            self.refill();
+        }
+    }
+
+    async fn read_chunk(&mut self) -> Result<Vec<u8>, DatabaseError> {
+        match &mut self.source {
+            Source::File(file) => {
+                let mut chunk = vec![0; CHUNK_SIZE.min(self.remaining as usize)];
+                file.read_exact(&mut chunk)
+                    .await
+                    .map_err(DatabaseError::IoError)?;
+                Ok(chunk)
+            }
+            #[cfg(feature = "object_store")]
+            Source::Object(stream) => match stream.next().await {
+                Some(chunk) => chunk.map_err(DatabaseError::IoError),
+                None => Err(self.damaged()),
+            },
+        }
+    }
+
+    /// Moves what the decoder has produced behind what's left to hand out.
+    fn refill(&mut self) {
+        self.decoded.drain(..self.offset);
+        self.offset = 0;
+        self.decoded.append(self.decoder.get_mut());
+    }
+
+    fn take_entry(&mut self) -> Result<Option<Document>, DatabaseError> {
+        let rest = &self.decoded[self.offset..];
+        if rest.len() < 4 {
+            return Ok(None);
+        }
+        let length = i32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        if length < 5 {
+            return Err(self.damaged());
+        }
+        let length = length as usize;
+        if rest.len() < length {
+            return Ok(None);
+        }
+        let entry = Document::from_reader(&mut &rest[..length]).map_err(|_| self.damaged())?;
+        self.offset += length;
+        Ok(Some(entry))
+    }
+
+    fn damaged(&self) -> DatabaseError {
+        DatabaseError::InvalidBackup(format!("'{}' is damaged", self.name))
+    }
+}
+
+/// Answers the manifest of the archive at `location` and how long its
+/// compressed documents are.
+pub(crate) async fn read_manifest(
+    location: Location<'_>,
+) -> Result<(Document, u64), DatabaseError> {
+    let size = location.size().await?;
+    if size < MAGIC.len() as u64 + TRAILER_SIZE {
+        return Err(location.missing());
+    }
+
+    let magic = location.read_range(0..MAGIC.len() as u64).await?;
+    let trailer = location.read_range(size - TRAILER_SIZE..size).await?;
+    if magic != MAGIC || &trailer[4..] != MAGIC {
+        return Err(location.missing());
+    }
+
+    let length = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) as u64;
+    let Some(compressed) = size.checked_sub(MAGIC.len() as u64 + length + TRAILER_SIZE) else {
+        return Err(location.missing());
+    };
+    let start = size - TRAILER_SIZE - length;
+    let manifest = location.read_range(start..start + length).await?;
+    let manifest = Document::from_reader(&mut &manifest[..]).map_err(|_| location.missing())?;
+    Ok((manifest, compressed))
+}
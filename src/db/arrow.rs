@@ -0,0 +1,216 @@
+//! Reading a collection as Arrow record batches, for DataFusion, Polars and
+//! anything else speaking Arrow. Documents are laid out as for the Parquet
+//! export: embedded documents flattened into dotted columns such as
+//! `address.city`, arrays as JSON strings, and each column's type inferred
+//! from the values found. A value that doesn't fit its column is null.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use arrow_array::builder::{
+    BooleanBuilder, Float64Builder, Int32Builder, Int64Builder, StringBuilder,
+    TimestampMillisecondBuilder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use bson::{Bson, Document};
+use futures_util::{Stream, StreamExt};
+
+use super::columnar::{as_f64, as_i64, as_text, infer_columns, Kind, Row};
+use super::{Database, DatabaseError, Projection};
+
+#[derive(Debug, Clone)]
+pub struct ArrowOptions {
+    /// Only documents matching it, as `find` would.
+    pub filter: Document,
+    /// The columns wanted besides `_id`.
+    pub projection: Projection,
+    /// Most rows in one batch.
+    pub batch_size: usize,
+}
+
+impl Default for ArrowOptions {
+    fn default() -> Self {
+        Self {
+            filter: Document::new(),
+            projection: Projection::All,
+            batch_size: 8192,
+        }
+    }
+}
+
+/// A collection's documents as record batches, from
+/// `Database::record_batches`, all with the same schema.
+pub struct RecordBatchStream {
+    schema: SchemaRef,
+    batches: Pin<Box<dyn Stream<Item = Result<RecordBatch, DatabaseError>> + Send>>,
+}
+
+impl RecordBatchStream {
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+impl Stream for RecordBatchStream {
+    type Item = Result<RecordBatch, DatabaseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.batches.as_mut().poll_next(cx)
+    }
+}
+
+impl Database {
+    /// Reads `collection`'s matching documents, by ID, as record batches
+    /// whose first column is `_id`.
+    pub async fn record_batches(
+        &self,
+        collection: String,
+        options: ArrowOptions,
+    ) -> Result<RecordBatchStream, DatabaseError> {
+        let rows = self
+            .rows(&collection, options.filter, &options.projection)
+            .await?;
+        let columns = infer_columns(&rows);
+
+        let mut fields = vec![Field::new("_id", DataType::Utf8, false)];
+        fields.extend(
+            columns
+                .iter()
+                .map(|(path, kind)| Field::new(path, data_type(*kind), true)),
+        );
+        let schema = Arc::new(Schema::new(fields));
+
+        let batch_size = options.batch_size.max(1);
+        let mut chunks = Vec::new();
+        let mut rows = rows.into_iter().peekable();
+        while rows.peek().is_some() {
+            chunks.push(rows.by_ref().take(batch_size).collect::<Vec<_>>());
+        }
+        let batch_schema = schema.clone();
+        let batches = futures_util::stream::iter(chunks).map(move |chunk| {
+            to_batch(&batch_schema, &columns, chunk)
+                .map_err(|e| DatabaseError::IoError(std::io::Error::other(e)))
+        });
+        Ok(RecordBatchStream {
+            schema,
+            batches: Box::pin(batches),
+        })
+    }
+}
+
+fn data_type(kind: Kind) -> DataType {
+    match kind {
+        Kind::Bool => DataType::Boolean,
+        Kind::Int32 => DataType::Int32,
+        Kind::Int64 => DataType::Int64,
+        Kind::Double => DataType::Float64,
+        Kind::String => DataType::Utf8,
+        Kind::Timestamp => DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+    }
+}
+
+fn to_batch(
+    schema: &SchemaRef,
+    columns: &[(String, Kind)],
+    rows: Vec<(String, Row)>,
+) -> Result<RecordBatch, arrow_schema::ArrowError> {
+    let mut ids = StringBuilder::new();
+    for (id, _) in &rows {
+        ids.append_value(id);
+    }
+    let mut arrays: Vec<ArrayRef> = vec![Arc::new(ids.finish())];
+    for (path, kind) in columns {
+        let values = rows.iter().map(|(_, row)| {
+            row.iter()
+                .find(|(field, _)| field == path)
+                .map(|(_, value)| value)
+        });
+        arrays.push(to_array(*kind, values));
+    }
+    RecordBatch::try_new(schema.clone(), arrays)
+}
+
+fn to_array<'a>(kind: Kind, values: impl Iterator<Item = Option<&'a Bson>>) -> ArrayRef {
+    macro_rules! build {
+        ($builder:expr, $convert:expr) => {{
+            let mut builder = $builder;
+            for value in values {
+                builder.append_option(value.and_then($convert));
+            }
+            Arc::new(builder.finish())
+        }};
+    }
+    match kind {
+        Kind::Bool => build!(BooleanBuilder::new(), |value: &Bson| value.as_bool()),
+        Kind::Int32 => build!(Int32Builder::new(), |value: &Bson| value.as_i32()),
+        Kind::Int64 => build!(Int64Builder::new(), as_i64),
+        Kind::Double => build!(Float64Builder::new(), as_f64),
+        Kind::String => build!(StringBuilder::new(), |value: &Bson| Some(as_text(value))),
+        Kind::Timestamp => build!(
+            TimestampMillisecondBuilder::new().with_timezone("UTC"),
+            |value: &Bson| value.as_datetime().map(|date| date.timestamp_millis())
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Array, Float64Array, StringArray};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_batches() {
+        let db = Database::init_test("data_tests".to_string(), "test_arrow".to_string()).await;
+        db.clear().await.unwrap();
+        for (id, doc) in [
+            (
+                "a",
+                bson::doc! { "total": 5, "ship": { "city": "Oslo", "zip": "0150" } },
+            ),
+            ("b", bson::doc! { "total": 2.5, "note": "rush" }),
+            ("c", bson::doc! { "total": 1, "paid": false }),
+        ] {
+            db.put("orders".to_string(), id.to_string(), doc)
+                .await
+                .unwrap();
+        }
+
+        let options = ArrowOptions {
+            projection: Projection::Include(vec!["total".to_string(), "ship".to_string()]),
+            batch_size: 2,
+            ..ArrowOptions::default()
+        };
+        let mut batches = db
+            .record_batches("orders".to_string(), options)
+            .await
+            .unwrap();
+        let names: Vec<_> = batches
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        assert_eq!(names, ["_id", "total", "ship.city", "ship.zip"]);
+
+        let first = batches.next().await.unwrap().unwrap();
+        assert_eq!(first.num_rows(), 2);
+        let totals = first
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(totals.values(), &[5.0, 2.5]);
+        let cities = first
+            .column(2)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(cities.value(0), "Oslo");
+        assert!(cities.is_null(1));
+        assert_eq!(batches.next().await.unwrap().unwrap().num_rows(), 1);
+        assert!(batches.next().await.is_none());
+    }
+}
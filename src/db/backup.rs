@@ -0,0 +1,673 @@
+//! Backups and point-in-time recovery. A backup writes a consistent
+//! snapshot of every collection to a single compressed archive while the
+//! database keeps serving, along with which fields are indexed and the
+//! oplog positions the copy spans (see `archive` for the format). An
+//! incremental backup instead holds the oplog entries written since the
+//! backup before it, so a full backup followed by incremental ones forms a
+//! chain that restores to when the last one was taken.
+//!
+//! A chain can be restored as it is into the open database, and a full
+//! backup into a new folder with the oplog replayed over it up to a chosen
+//! moment, so a mistaken write can be undone by recovering to just before
+//! it. The latter can only recover to a moment after the copy finished,
+//! and only while the oplog still holds where it started.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use bson::{Bson, DateTime, Document};
+use log::info;
+
+use super::archive::{self, ArchiveReader, ArchiveWriter, Location, Sink};
+use super::defrag::list_collections;
+use super::wal::WalRecord;
+use super::{Database, DatabaseError, FindOptions};
+
+/// Oplog entries replayed, or archived documents restored, at a time.
+const REPLAY_BATCH: usize = 256;
+
+/// What a backup holds, as noted in its manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupInfo {
+    /// The first oplog entry the copy may be missing; 0 if the database
+    /// keeps no oplog, in which case the backup can only be restored with
+    /// `Database::restore`.
+    pub start_position: u64,
+    /// The first entry written after the copy finished; recovery replays
+    /// at least the entries before it.
+    pub end_position: u64,
+    /// When the copy finished, the earliest moment it recovers to.
+    pub finished_at: DateTime,
+    /// Holds the oplog entries from `start_position` up to `end_position`
+    /// rather than a copy of the collections.
+    pub incremental: bool,
+}
+
+/// What goes into an archive.
+enum Contents {
+    /// Every collection, as of the snapshot these options read at.
+    Snapshot(FindOptions),
+    /// The oplog entries in this range.
+    Oplog(Range<u64>),
+}
+
+impl BackupInfo {
+    fn to_document(&self) -> Document {
+        bson::doc! {
+            "start": self.start_position as i64,
+            "end": self.end_position as i64,
+            "finished_at": self.finished_at,
+            "incremental": self.incremental,
+        }
+    }
+
+    /// Where the oplog entries a backup taken after this one holds start.
+    fn next_position(&self) -> u64 {
+        match self.incremental {
+            true => self.end_position,
+            // The copy may be missing writes from its start on.
+            false => self.start_position,
+        }
+    }
+
+    fn from_document(doc: &Document) -> Option<Self> {
+        Some(Self {
+            start_position: doc.get_i64("start").ok()? as u64,
+            end_position: doc.get_i64("end").ok()? as u64,
+            finished_at: *doc.get_datetime("finished_at").ok()?,
+            incremental: doc.get_bool("incremental").unwrap_or(false),
+        })
+    }
+
+    /// Reads the manifest of the backup at `path`.
+    pub async fn read(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        Self::read_at(Location::File(path.as_ref())).await
+    }
+
+    pub(crate) async fn read_at(location: Location<'_>) -> Result<Self, DatabaseError> {
+        let (manifest, _) = archive::read_manifest(location).await?;
+        Self::from_document(&manifest).ok_or_else(|| invalid(location))
+    }
+}
+
+impl Database {
+    /// Writes every collection, as of one snapshot, to an archive at
+    /// `path`, a file that mustn't exist yet. With the oplog kept, the
+    /// archive can also be restored to a later moment; see
+    /// `Database::restore_backup`.
+    pub async fn backup(&self, path: impl AsRef<Path>) -> Result<BackupInfo, DatabaseError> {
+        self.backup_at(Location::File(path.as_ref())).await
+    }
+
+    pub(crate) async fn backup_at(
+        &self,
+        location: Location<'_>,
+    ) -> Result<BackupInfo, DatabaseError> {
+        let _guard = self.inner.activity.begin().await?;
+        let start_position = self.oplog_end().await?;
+        // Buffered writes to new collections aren't listed until flushed.
+        self.flush().await?;
+        let options = FindOptions {
+            snapshot: Some(self.inner.versions.snapshot_latest().await),
+            ..FindOptions::default()
+        };
+
+        let (info, count) = self
+            .write_archive(location, start_position, Contents::Snapshot(options))
+            .await?;
+
+        info!(
+            "Backed up {} documents from '{}' to '{}' as of oplog positions {}..{}",
+            count,
+            self.inner.folder_path,
+            location.name(),
+            info.start_position,
+            info.end_position
+        );
+        Ok(info)
+    }
+
+    /// Writes the writes made since the backup at `previous` was taken,
+    /// full or incremental, to an archive at `path`, a file that mustn't
+    /// exist yet. Needs the oplog, which must still hold where `previous`
+    /// ends; see `Database::restore_chain`.
+    pub async fn backup_incremental(
+        &self,
+        previous: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+    ) -> Result<BackupInfo, DatabaseError> {
+        self.backup_incremental_at(
+            Location::File(previous.as_ref()),
+            Location::File(path.as_ref()),
+        )
+        .await
+    }
+
+    pub(crate) async fn backup_incremental_at(
+        &self,
+        previous: Location<'_>,
+        location: Location<'_>,
+    ) -> Result<BackupInfo, DatabaseError> {
+        let _guard = self.inner.activity.begin().await?;
+        let from = BackupInfo::read_at(previous).await?.next_position();
+        let until = self.oplog_range().await?.end;
+        // Fails before writing anything if the oplog moved on.
+        self.read_oplog(from, 0).await?;
+
+        let (info, count) = self
+            .write_archive(location, from, Contents::Oplog(from..until))
+            .await?;
+
+        info!(
+            "Backed up {} writes from '{}' to '{}' as of oplog positions {}..{}",
+            count,
+            self.inner.folder_path,
+            location.name(),
+            info.start_position,
+            info.end_position
+        );
+        Ok(info)
+    }
+
+    /// Replaces everything in this database with the full backup at `path`;
+    /// see `Database::restore_chain`.
+    pub async fn restore(&self, path: impl AsRef<Path>) -> Result<u64, DatabaseError> {
+        self.restore_chain(&[path]).await
+    }
+
+    /// Replaces everything in this database with the full backup that
+    /// starts `chain`, then applies each incremental one after it in turn,
+    /// without closing it. Which fields are indexed is taken from the last.
+    /// Readers may see a mix of the old and the restored state until it's
+    /// done, and the oplog starts over as after `clear`. Answers how many
+    /// documents were written or deleted.
+    pub async fn restore_chain(&self, chain: &[impl AsRef<Path>]) -> Result<u64, DatabaseError> {
+        let chain: Vec<Location> = chain
+            .iter()
+            .map(|path| Location::File(path.as_ref()))
+            .collect();
+        self.restore_chain_at(&chain).await
+    }
+
+    pub(crate) async fn restore_chain_at(
+        &self,
+        chain: &[Location<'_>],
+    ) -> Result<u64, DatabaseError> {
+        self.check_writable()?;
+        let Some(last) = chain.last() else {
+            return Err(DatabaseError::InvalidBackup(
+                "no backup to restore".to_string(),
+            ));
+        };
+
+        // Checks every link before touching anything.
+        let mut previous: Option<BackupInfo> = None;
+        for &location in chain {
+            let info = BackupInfo::read_at(location).await?;
+            let follows = match &previous {
+                None => !info.incremental,
+                Some(previous) => {
+                    info.incremental && info.start_position == previous.next_position()
+                }
+            };
+            if !follows {
+                return Err(DatabaseError::InvalidBackup(format!(
+                    "'{}' doesn't follow in the chain",
+                    location.name()
+                )));
+            }
+            previous = Some(info);
+        }
+        let (manifest, _) = archive::read_manifest(*last).await?;
+        let Ok(indexes) = manifest.get_document("indexes") else {
+            return Err(invalid(*last));
+        };
+
+        self.clear().await?;
+        *self.write_index() = indexes
+            .iter()
+            .map(|(collection, fields)| {
+                let fields = fields
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|field| Some((field.as_str()?.to_string(), Vec::new())))
+                    .collect();
+                (collection.clone(), fields)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut count = 0;
+        for &location in chain {
+            let (mut archive, _) = ArchiveReader::open(location).await?;
+            let mut records = Vec::with_capacity(REPLAY_BATCH);
+            while let Some((collection, id, doc)) = archive.next().await? {
+                records.push(match doc {
+                    Some(doc) => WalRecord::Insert {
+                        collection,
+                        id,
+                        doc,
+                    },
+                    None => WalRecord::Delete { collection, id },
+                });
+                if records.len() == REPLAY_BATCH {
+                    count += records.len() as u64;
+                    self.apply_records(std::mem::take(&mut records), None, None)
+                        .await?;
+                }
+            }
+            count += records.len() as u64;
+            if !records.is_empty() {
+                self.apply_records(records, None, None).await?;
+            }
+        }
+
+        info!(
+            "Restored {} writes from {} backups ending with '{}' into '{}'",
+            count,
+            chain.len(),
+            last.name(),
+            self.inner.folder_path
+        );
+        Ok(count)
+    }
+
+    /// Restores the backup at `backup_path` into `folder_path`, a folder
+    /// that mustn't exist yet, then replays this database's oplog over it
+    /// up to the last write made no later than `until`, or to the latest
+    /// write when `None`. Answers the position of the last entry replayed.
+    pub async fn restore_backup(
+        &self,
+        backup_path: impl AsRef<Path>,
+        folder_path: String,
+        until: Option<DateTime>,
+    ) -> Result<u64, DatabaseError> {
+        let backup_path = backup_path.as_ref();
+        let location = Location::File(backup_path);
+        let (mut archive, manifest) = ArchiveReader::open(location).await?;
+        let info = BackupInfo::from_document(&manifest).ok_or_else(|| invalid(location))?;
+        if info.incremental {
+            return Err(DatabaseError::InvalidBackup(format!(
+                "'{}' is an incremental backup",
+                backup_path.display()
+            )));
+        }
+        if let Some(until) = until {
+            if until < info.finished_at {
+                return Err(DatabaseError::InvalidBackup(format!(
+                    "the backup recovers to {} at the earliest",
+                    info.finished_at
+                )));
+            }
+        }
+        // Fails before copying anything if the oplog moved on.
+        self.read_oplog(info.start_position, 0).await?;
+
+        let folder = Path::new(&folder_path);
+        if tokio::fs::try_exists(folder)
+            .await
+            .map_err(DatabaseError::IoError)?
+        {
+            return Err(DatabaseError::InvalidBackup(format!(
+                "'{}' already exists",
+                folder_path
+            )));
+        }
+        // Full backups hold no deletes.
+        while let Some((collection, id, Some(doc))) = archive.next().await? {
+            let collection_path = folder.join(&collection);
+            tokio::fs::create_dir_all(&collection_path)
+                .await
+                .map_err(DatabaseError::IoError)?;
+            let mut buffer = Vec::new();
+            doc.to_writer(&mut buffer)
+                .map_err(DatabaseError::BsonSerError)?;
+            tokio::fs::write(collection_path.join(format!("{}.bson", id)), buffer)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+
+        let restored = Database::init(folder_path.clone()).await?;
+        let replayed = self.replay_oplog(&restored, &info, until).await;
+        restored.close().await?;
+        let replayed = replayed?;
+
+        info!(
+            "Restored '{}' into '{}' up to oplog position {}",
+            backup_path.display(),
+            folder_path,
+            replayed
+        );
+        Ok(replayed)
+    }
+
+    /// Writes `contents` to a new archive at `location`, answering its
+    /// manifest and how many documents or writes it holds.
+    async fn write_archive(
+        &self,
+        location: Location<'_>,
+        start_position: u64,
+        contents: Contents,
+    ) -> Result<(BackupInfo, u64), DatabaseError> {
+        let mut archive = ArchiveWriter::create(location).await?;
+        match self
+            .fill_archive(&mut archive, start_position, contents)
+            .await
+        {
+            Ok((info, manifest, count)) => {
+                archive.commit(&manifest).await?;
+                Ok((info, count))
+            }
+            Err(e) => {
+                archive.discard(location).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Answers the manifest to finish the archive with as well.
+    async fn fill_archive(
+        &self,
+        archive: &mut ArchiveWriter<Sink>,
+        start_position: u64,
+        contents: Contents,
+    ) -> Result<(BackupInfo, Document, u64), DatabaseError> {
+        let mut count = 0;
+        let end_position = match &contents {
+            Contents::Snapshot(options) => {
+                for collection in list_collections(&self.inner.folder_path).await? {
+                    for (id, doc) in self
+                        .scan_raw(&collection, &Document::new(), options)
+                        .await?
+                    {
+                        archive.write(&collection, &id, Some(doc)).await?;
+                        count += 1;
+                    }
+                }
+                self.oplog_end().await?
+            }
+            Contents::Oplog(range) => {
+                let mut next = range.start;
+                while next < range.end {
+                    let entries = self.read_oplog(next, REPLAY_BATCH).await?;
+                    if entries.is_empty() {
+                        break;
+                    }
+                    for entry in entries {
+                        if entry.position >= range.end {
+                            break;
+                        }
+                        let doc = entry
+                            .document
+                            .as_ref()
+                            .map(bson::RawDocumentBuf::from_document)
+                            .transpose()
+                            .map_err(DatabaseError::BsonRawError)?;
+                        archive.write(&entry.collection, &entry.id, doc).await?;
+                        count += 1;
+                        next = entry.position + 1;
+                    }
+                }
+                range.end
+            }
+        };
+
+        let info = BackupInfo {
+            start_position,
+            end_position,
+            finished_at: DateTime::now(),
+            incremental: matches!(contents, Contents::Oplog(_)),
+        };
+        let mut manifest = info.to_document();
+        let indexes: Document = self
+            .read_index()
+            .iter()
+            .map(|(collection, fields)| {
+                let fields = fields.keys().cloned().map(Bson::String).collect();
+                (collection.clone(), Bson::Array(fields))
+            })
+            .collect();
+        manifest.insert("indexes", indexes);
+        Ok((info, manifest, count))
+    }
+
+    /// Where the next oplog entry goes, or 0 without an oplog.
+    async fn oplog_end(&self) -> Result<u64, DatabaseError> {
+        match self.inner.oplog {
+            Some(_) => Ok(self.oplog_range().await?.end),
+            None => Ok(0),
+        }
+    }
+
+    async fn replay_oplog(
+        &self,
+        restored: &Database,
+        info: &BackupInfo,
+        until: Option<DateTime>,
+    ) -> Result<u64, DatabaseError> {
+        let mut next = info.start_position;
+        loop {
+            let entries = self.read_oplog(next, REPLAY_BATCH).await?;
+            if entries.is_empty() {
+                break;
+            }
+            for entry in entries {
+                // Entries the copy may be missing are replayed regardless.
+                let late = until.is_some_and(|until| entry.timestamp > until);
+                if late && entry.position >= info.end_position {
+                    return Ok(next - 1);
+                }
+                restored
+                    .apply_replicated(entry.collection, entry.id, entry.document)
+                    .await?;
+                next = entry.position + 1;
+            }
+        }
+
+        if next < info.end_position {
+            return Err(DatabaseError::OplogTruncated(next));
+        }
+        Ok(next - 1)
+    }
+}
+
+fn invalid(location: Location<'_>) -> DatabaseError {
+    DatabaseError::InvalidBackup(format!("'{}' holds no backup", location.name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DatabaseOptions, OplogOptions};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_point_in_time_recovery() {
+        let folder_path = "data_tests/test_backup".to_string();
+        let backup_path = "data_tests/test_backup.bak";
+        let restored_path = "data_tests/test_backup_restored";
+        for path in [folder_path.as_str(), restored_path] {
+            let _ = tokio::fs::remove_dir_all(path).await;
+        }
+        let _ = tokio::fs::remove_file(backup_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let john = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let info = db.backup(backup_path).await.unwrap();
+        assert_eq!((info.start_position, info.end_position), (2, 2));
+        assert!(db.backup(backup_path).await.is_err());
+
+        db.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            john.clone(),
+            bson::doc! { "$set": { "age": 30 } },
+        )
+        .await
+        .unwrap();
+        let before_mistake = DateTime::now();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        db.delete("users".to_string(), bson::doc! {}).await.unwrap();
+
+        assert!(matches!(
+            db.restore_backup(
+                backup_path,
+                restored_path.to_string(),
+                Some(DateTime::from_millis(0))
+            )
+            .await,
+            Err(DatabaseError::InvalidBackup(_))
+        ));
+        let replayed = db
+            .restore_backup(backup_path, restored_path.to_string(), Some(before_mistake))
+            .await
+            .unwrap();
+        assert_eq!(replayed, 3);
+
+        let restored = Database::init(restored_path.to_string()).await.unwrap();
+        let mut names: Vec<(String, Option<i32>)> = restored
+            .find("users".to_string(), bson::doc! {})
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|doc| {
+                (
+                    doc.get_str("name").unwrap().to_string(),
+                    doc.get_i32("age").ok(),
+                )
+            })
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![("Jane".to_string(), None), ("John".to_string(), Some(30))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_in_place() {
+        let db = Database::init_test("data_tests".to_string(), "test_restore".to_string()).await;
+        db.clear().await.unwrap();
+        let backup_path = "data_tests/test_restore.bak";
+        let _ = tokio::fs::remove_file(backup_path).await;
+        db.add_index("users".to_string(), "name".to_string());
+        for i in 0..300 {
+            db.put(
+                "users".to_string(),
+                i.to_string(),
+                bson::doc! { "name": format!("user{}", i) },
+            )
+            .await
+            .unwrap();
+        }
+
+        let info = db.backup(backup_path).await.unwrap();
+        assert_eq!((info.start_position, info.end_position), (0, 0));
+        assert_eq!(BackupInfo::read(backup_path).await.unwrap(), info);
+
+        db.delete("users".to_string(), bson::doc! {}).await.unwrap();
+        let log = db
+            .insert_one("logs".to_string(), bson::doc! { "level": "warn" })
+            .await
+            .unwrap();
+        *db.write_index() = Default::default();
+
+        assert_eq!(db.restore(backup_path).await.unwrap(), 300);
+        assert_eq!(
+            db.find_one("users".to_string(), "42".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "name": "user42" })
+        );
+        assert_eq!(db.find_one("logs".to_string(), log).await.unwrap(), None);
+        assert_eq!(db.read_index()["users"]["name"].len(), 300);
+
+        tokio::fs::write(backup_path, b"not a backup")
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.restore(backup_path).await,
+            Err(DatabaseError::InvalidBackup(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_incremental_chain() {
+        let folder_path = "data_tests/test_incremental".to_string();
+        let paths = [
+            "data_tests/test_incremental_0.bak",
+            "data_tests/test_incremental_1.bak",
+            "data_tests/test_incremental_2.bak",
+        ];
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        for path in paths {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        db.put("users".to_string(), "1".to_string(), bson::doc! { "n": 1 })
+            .await
+            .unwrap();
+        db.put("users".to_string(), "2".to_string(), bson::doc! { "n": 2 })
+            .await
+            .unwrap();
+        let full = db.backup(paths[0]).await.unwrap();
+
+        db.put("users".to_string(), "3".to_string(), bson::doc! { "n": 3 })
+            .await
+            .unwrap();
+        let first = db.backup_incremental(paths[0], paths[1]).await.unwrap();
+        assert!(first.incremental);
+        assert_eq!(
+            (first.start_position, first.end_position),
+            (full.start_position, 4)
+        );
+
+        db.delete_one("users".to_string(), "1".to_string())
+            .await
+            .unwrap();
+        db.put("users".to_string(), "2".to_string(), bson::doc! { "n": 20 })
+            .await
+            .unwrap();
+        let second = db.backup_incremental(paths[1], paths[2]).await.unwrap();
+        assert_eq!((second.start_position, second.end_position), (4, 6));
+
+        db.delete("users".to_string(), bson::doc! {}).await.unwrap();
+        assert!(matches!(
+            db.restore_chain(&[paths[0], paths[2]]).await,
+            Err(DatabaseError::InvalidBackup(_))
+        ));
+        assert!(matches!(
+            db.restore(paths[1]).await,
+            Err(DatabaseError::InvalidBackup(_))
+        ));
+
+        assert_eq!(db.restore_chain(&paths).await.unwrap(), 5);
+        let mut users: Vec<(String, i32)> = db
+            .find_with_ids("users".to_string(), bson::doc! {})
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, doc)| (id, doc.get_i32("n").unwrap()))
+            .collect();
+        users.sort();
+        assert_eq!(users, [("2".to_string(), 20), ("3".to_string(), 3)]);
+    }
+}
@@ -0,0 +1,498 @@
+//! Scheduled backups: a background task takes a full backup into a folder
+//! whenever a cron expression matches, then prunes the scheduled backups
+//! there that the retention policy no longer keeps. How it has fared shows
+//! in `DatabaseStats::backups`.
+//!
+//! Scheduled backups are named after the minute they were due, as in
+//! `backup-20240131T0200Z.bak`, which is also what retention goes by;
+//! other files in the folder are left alone.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use bson::DateTime;
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::{BackupInfo, Database, DatabaseError};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+/// How far ahead a schedule is searched for its next match; enough to find
+/// a leap day.
+const SEARCH_DAYS: i64 = 8 * 366;
+
+/// When scheduled backups run: a cron expression of five fields, read in
+/// UTC, for the minute (0-59), hour (0-23), day of month (1-31), month
+/// (1-12) and day of week (0-7, both 0 and 7 being Sunday). A field is `*`,
+/// a number or a range `a-b`, either optionally stepped as in `*/15`, or a
+/// list of those separated by commas. `@hourly`, `@daily` and `@weekly`
+/// stand for the usual expressions.
+///
+/// As in cron, when both the day of month and the day of week are
+/// restricted, a day matches if either does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    restricts_day: bool,
+    restricts_weekday: bool,
+}
+
+impl FromStr for BackupSchedule {
+    type Err = DatabaseError;
+
+    fn from_str(expression: &str) -> Result<Self, DatabaseError> {
+        let invalid =
+            || DatabaseError::InvalidConfig(format!("invalid backup schedule '{}'", expression));
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(invalid());
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7).ok_or_else(invalid)?;
+        if weekday_bits & 1 << 7 != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).ok_or_else(invalid)?,
+            hours: parse_field(hours, 0, 23).ok_or_else(invalid)?,
+            days: parse_field(days, 1, 31).ok_or_else(invalid)?,
+            months: parse_field(months, 1, 12).ok_or_else(invalid)?,
+            weekdays: weekday_bits,
+            restricts_day: !days.starts_with('*'),
+            restricts_weekday: !weekdays.starts_with('*'),
+        })
+    }
+}
+
+/// The values a cron field matches, as bits; `None` if it's malformed or
+/// out of `min..=max`.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse().ok().filter(|&step| step > 0)?)),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            // A stepped number runs to the end, as in `5/15`.
+            None if step.is_some() => (range.parse().ok()?, max),
+            None => {
+                let value = range.parse().ok()?;
+                (value, value)
+            }
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1)) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+impl BackupSchedule {
+    /// The first minute after `time` the schedule matches; `None` if there
+    /// is none for years, as for the 30th of February.
+    pub fn next_after(&self, time: DateTime) -> Option<DateTime> {
+        let minute = time.timestamp_millis().div_euclid(60_000) + 1;
+        let first_day = minute.div_euclid(MINUTES_PER_DAY);
+        let mut from = minute.rem_euclid(MINUTES_PER_DAY);
+        for day in first_day..first_day + SEARCH_DAYS {
+            if self.matches_day(day) {
+                let found = (from..MINUTES_PER_DAY).find(|minute| {
+                    self.hours & 1 << (minute / 60) != 0 && self.minutes & 1 << (minute % 60) != 0
+                });
+                if let Some(minute) = found {
+                    return Some(DateTime::from_millis(
+                        (day * MINUTES_PER_DAY + minute) * 60_000,
+                    ));
+                }
+            }
+            from = 0;
+        }
+        None
+    }
+
+    /// Whether the schedule runs on `day`, counted from 1970-01-01.
+    fn matches_day(&self, day: i64) -> bool {
+        let (_, month, day_of_month) = civil_from_days(day);
+        // 1970-01-01 was a Thursday.
+        let weekday = (day + 4).rem_euclid(7);
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        let by_day = self.days & 1 << day_of_month != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        match self.restricts_day && self.restricts_weekday {
+            true => by_day || by_weekday,
+            false => by_day && by_weekday,
+        }
+    }
+}
+
+/// Which scheduled backups are kept: the newest of each of the last `daily`
+/// days and of the last `weekly` weeks, starting on Mondays, that have one.
+/// The newest backup is always kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            daily: 7,
+            weekly: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupScheduleOptions {
+    pub schedule: BackupSchedule,
+    /// Where backups are written, created if missing.
+    pub folder: PathBuf,
+    pub retention: RetentionPolicy,
+}
+
+/// How the backup scheduler has fared, since it was spawned.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackupStats {
+    pub succeeded: u64,
+    pub failed: u64,
+    /// The manifest of the latest backup taken.
+    pub last_backup: Option<BackupInfo>,
+    /// When the latest run failed, and why.
+    pub last_failure: Option<(DateTime, String)>,
+    /// Scheduled backups in the folder after the latest pruning.
+    pub archives: usize,
+    /// Scheduled backups deleted by pruning.
+    pub pruned: u64,
+}
+
+pub struct BackupScheduleHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl BackupScheduleHandle {
+    /// Stops the scheduler, waiting for a backup in progress to finish.
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Backup scheduler task failed: {}", e);
+        }
+    }
+}
+
+impl Database {
+    /// Takes a backup into `options.folder` whenever `options.schedule`
+    /// matches, in the background, pruning older ones after each.
+    pub fn spawn_backup_scheduler(&self, options: BackupScheduleOptions) -> BackupScheduleHandle {
+        *self.backup_stats() = Some(BackupStats::default());
+        let (shutdown, mut shutdown_rx) = watch::channel(false);
+        let db = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let now = DateTime::now();
+                let Some(next) = options.schedule.next_after(now) else {
+                    warn!("Backup schedule never matches; no backups will be taken");
+                    break;
+                };
+                let wait = (next.timestamp_millis() - now.timestamp_millis()).max(0) as u64;
+                tokio::select! {
+                    _ = shutdown_rx.changed() => break,
+                    _ = tokio::time::sleep(Duration::from_millis(wait)) => {}
+                }
+                db.run_scheduled_backup(&options, next).await;
+            }
+        });
+        BackupScheduleHandle { shutdown, task }
+    }
+
+    /// Takes the backup due at `due` and prunes, noting how it went.
+    async fn run_scheduled_backup(&self, options: &BackupScheduleOptions, due: DateTime) {
+        let result = self.take_scheduled_backup(options, due).await;
+        let mut stats = self.backup_stats();
+        let stats = stats.get_or_insert_with(BackupStats::default);
+        match result {
+            Ok((info, archives, pruned)) => {
+                stats.succeeded += 1;
+                stats.last_backup = Some(info);
+                stats.archives = archives;
+                stats.pruned += pruned as u64;
+            }
+            Err(e) => {
+                warn!("Scheduled backup failed: {:?}", e);
+                stats.failed += 1;
+                stats.last_failure = Some((DateTime::now(), format!("{:?}", e)));
+            }
+        }
+    }
+
+    /// Answers the backup's manifest, how many scheduled backups remain and
+    /// how many were pruned.
+    async fn take_scheduled_backup(
+        &self,
+        options: &BackupScheduleOptions,
+        due: DateTime,
+    ) -> Result<(BackupInfo, usize, usize), DatabaseError> {
+        tokio::fs::create_dir_all(&options.folder)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        let minute = due.timestamp_millis().div_euclid(60_000);
+        let info = self
+            .backup(options.folder.join(backup_name(minute)))
+            .await?;
+        let (archives, pruned) = prune_backups(&options.folder, &options.retention).await?;
+        if pruned > 0 {
+            info!(
+                "Pruned {} scheduled backups from '{}'",
+                pruned,
+                options.folder.display()
+            );
+        }
+        Ok((info, archives, pruned))
+    }
+
+    fn backup_stats(&self) -> std::sync::MutexGuard<'_, Option<BackupStats>> {
+        self.inner
+            .backup_stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn scheduled_backup_stats(&self) -> Option<BackupStats> {
+        self.backup_stats().clone()
+    }
+}
+
+/// Deletes the scheduled backups in `folder` that `retention` doesn't keep,
+/// answering how many remain and how many were deleted.
+async fn prune_backups(
+    folder: &Path,
+    retention: &RetentionPolicy,
+) -> Result<(usize, usize), DatabaseError> {
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(folder)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        if let Some(minute) = entry.file_name().to_str().and_then(parse_backup_name) {
+            backups.push((minute, entry.path()));
+        }
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.0));
+
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    let mut pruned = 0;
+    for (i, (minute, path)) in backups.iter().enumerate() {
+        let day = minute.div_euclid(MINUTES_PER_DAY);
+        // Counted from the Monday before 1970-01-01, a Thursday.
+        let week = (day + 3).div_euclid(7);
+        let new_day = days.len() < retention.daily && days.insert(day);
+        let new_week = weeks.len() < retention.weekly && weeks.insert(week);
+        if i == 0 || new_day || new_week {
+            continue;
+        }
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => pruned += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(DatabaseError::IoError(e)),
+        }
+    }
+    Ok((backups.len() - pruned, pruned))
+}
+
+/// The file name of the scheduled backup due at `minute`, counted from the
+/// epoch.
+fn backup_name(minute: i64) -> String {
+    let (year, month, day) = civil_from_days(minute.div_euclid(MINUTES_PER_DAY));
+    let minute = minute.rem_euclid(MINUTES_PER_DAY);
+    format!(
+        "backup-{:04}{:02}{:02}T{:02}{:02}Z.bak",
+        year,
+        month,
+        day,
+        minute / 60,
+        minute % 60
+    )
+}
+
+/// The minute a scheduled backup was due at, from its file name.
+fn parse_backup_name(name: &str) -> Option<i64> {
+    let stamp = name.strip_prefix("backup-")?.strip_suffix("Z.bak")?;
+    if stamp.len() != 13 || stamp.as_bytes()[8] != b'T' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| stamp.get(range)?.parse::<u32>().ok();
+    let (year, month, day) = (number(0..4)?, number(4..6)?, number(6..8)?);
+    let (hour, minute) = (number(9..11)?, number(11..13)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day);
+    Some(days * MINUTES_PER_DAY + (hour * 60 + minute) as i64)
+}
+
+/// The year, month and day of `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March, so the leap day comes last.
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i64, month: u32, day: u32, hour: i64, minute: i64) -> DateTime {
+        let days = days_from_civil(year, month, day);
+        DateTime::from_millis((days * MINUTES_PER_DAY + hour * 60 + minute) * 60_000)
+    }
+
+    #[test]
+    fn test_backup_schedule() {
+        let schedule: BackupSchedule = "30 2 * * *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2024, 1, 31, 2, 30)),
+            Some(at(2024, 2, 1, 2, 30))
+        );
+        assert_eq!(
+            schedule.next_after(at(2024, 1, 31, 2, 29)),
+            Some(at(2024, 1, 31, 2, 30))
+        );
+
+        // 2024-01-31 is a Wednesday.
+        let schedule: BackupSchedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2024, 2, 2, 17, 45)),
+            Some(at(2024, 2, 5, 9, 0))
+        );
+        let schedule: BackupSchedule = "@weekly".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2024, 1, 31, 0, 0)),
+            Some(at(2024, 2, 4, 0, 0))
+        );
+        // Either the 1st or a Sunday.
+        let schedule: BackupSchedule = "0 0 1 * 7".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2024, 2, 1, 0, 0)),
+            Some(at(2024, 2, 4, 0, 0))
+        );
+        let schedule: BackupSchedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        let schedule: BackupSchedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(schedule.next_after(at(2024, 1, 1, 0, 0)), None);
+
+        for invalid in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(matches!(
+                invalid.parse::<BackupSchedule>(),
+                Err(DatabaseError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_retention() {
+        let folder_path = "data_tests/test_backup_retention".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path.clone()).await.unwrap();
+        db.insert_one("users".to_string(), bson::doc! { "name": "ada" })
+            .await
+            .unwrap();
+        let options = BackupScheduleOptions {
+            schedule: "@daily".parse().unwrap(),
+            folder: PathBuf::from(format!("{}_backups", folder_path)),
+            retention: RetentionPolicy {
+                daily: 2,
+                weekly: 2,
+            },
+        };
+        let _ = tokio::fs::remove_dir_all(&options.folder).await;
+
+        // Monday to Sunday of one week, then Monday to Wednesday of the
+        // next, twice on the last day.
+        for day in 1..=10 {
+            db.run_scheduled_backup(&options, at(2024, 1, day, 0, 0))
+                .await;
+        }
+        db.run_scheduled_backup(&options, at(2024, 1, 10, 12, 0))
+            .await;
+        db.run_scheduled_backup(&options, at(2024, 1, 10, 12, 0))
+            .await;
+
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(&options.folder).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().into_string().unwrap());
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "backup-20240107T0000Z.bak",
+                "backup-20240109T0000Z.bak",
+                "backup-20240110T1200Z.bak",
+            ]
+        );
+
+        let stats = db.stats().await.unwrap().backups.unwrap();
+        assert_eq!(stats.succeeded, 11);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.archives, 3);
+        assert_eq!(stats.pruned, 8);
+        assert!(matches!(
+            stats.last_failure,
+            Some((_, error)) if error.contains("already exists")
+        ));
+        assert!(stats.last_backup.is_some());
+    }
+}
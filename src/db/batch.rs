@@ -0,0 +1,166 @@
+use bson::Document;
+use log::info;
+
+use super::{Database, DatabaseError};
+
+/// One write in a batch passed to `Database::apply_batch`.
+#[derive(Debug, Clone)]
+pub enum WriteOp {
+    Insert(Document),
+    /// Same update forms as `Database::update_one`.
+    Update {
+        id: String,
+        update: Document,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchResult {
+    /// Ids of the inserted documents, in batch order.
+    pub inserted_ids: Vec<String>,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+impl Database {
+    /// Applies writes across any number of collections as one unit: either
+    /// every write lands or, should anything fail, none of them do.
+    ///
+    /// Updates and deletes of documents that don't exist are skipped and
+    /// left out of the counts.
+    pub async fn apply_batch(
+        &self,
+        ops: Vec<(String, WriteOp)>,
+    ) -> Result<BatchResult, DatabaseError> {
+        self.check_writable()?;
+
+        let mut transaction = self.begin_transaction().await;
+        let mut result = BatchResult::default();
+
+        for (collection, op) in ops {
+            match op {
+                WriteOp::Insert(doc) => {
+                    let id = transaction.insert_one(collection, doc).await?;
+                    result.inserted_ids.push(id);
+                }
+                WriteOp::Update { id, update } => {
+                    if transaction.update_one(collection, id, update).await? {
+                        result.updated += 1;
+                    }
+                }
+                WriteOp::Delete { id } => {
+                    if transaction.delete_one(collection, id).await? {
+                        result.deleted += 1;
+                    }
+                }
+            }
+        }
+
+        transaction.commit().await?;
+
+        info!(
+            "Successfully applied batch: {} inserted, {} updated, {} deleted",
+            result.inserted_ids.len(),
+            result.updated,
+            result.deleted
+        );
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_batch() {
+        let db = Database::init_test("data_tests".to_string(), "test_batch".to_string()).await;
+        db.clear().await.unwrap();
+
+        let user = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+
+        let result = db
+            .apply_batch(vec![
+                (
+                    "users".to_string(),
+                    WriteOp::Update {
+                        id: user.clone(),
+                        update: bson::doc! { "$set": { "name": "Johnny" } },
+                    },
+                ),
+                (
+                    "orders".to_string(),
+                    WriteOp::Insert(bson::doc! { "user_name": "Johnny" }),
+                ),
+                (
+                    "users".to_string(),
+                    WriteOp::Delete {
+                        id: "missing".to_string(),
+                    },
+                ),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(result.inserted_ids.len(), 1);
+        assert_eq!(result.updated, 1);
+        assert_eq!(result.deleted, 0);
+        assert_eq!(
+            db.find_one("users".to_string(), user).await.unwrap(),
+            Some(bson::doc! { "name": "Johnny" })
+        );
+        assert_eq!(
+            db.find_one("orders".to_string(), result.inserted_ids[0].clone())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "user_name": "Johnny" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_applies_nothing() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_batch_failed".to_string()).await;
+        db.clear().await.unwrap();
+
+        let user = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        db.insert_one("orders".to_string(), bson::doc! { "user_name": "Jane" })
+            .await
+            .unwrap();
+
+        let result = db
+            .apply_batch(vec![
+                (
+                    "orders".to_string(),
+                    WriteOp::Insert(bson::doc! { "user_name": "John" }),
+                ),
+                (
+                    "users".to_string(),
+                    WriteOp::Update {
+                        id: user,
+                        update: bson::doc! { "$inc": { "name": 1 } },
+                    },
+                ),
+            ])
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(
+            db.find("orders".to_string(), bson::doc! {})
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+/// In-memory LRU of recently read documents, keyed by (collection, id).
+pub(crate) struct DocumentCache {
+    state: Option<Mutex<CacheState>>,
+}
+
+struct CacheState {
+    entries: LruCache<(String, String), bson::RawDocumentBuf>,
+    /// Bumped on every invalidation so a read that raced with a write can
+    /// tell its result is stale before caching it.
+    epoch: u64,
+}
+
+impl DocumentCache {
+    /// A zero capacity disables caching entirely.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let state = NonZeroUsize::new(capacity).map(|capacity| {
+            Mutex::new(CacheState {
+                entries: LruCache::new(capacity),
+                epoch: 0,
+            })
+        });
+
+        Self { state }
+    }
+
+    pub(crate) fn epoch(&self) -> u64 {
+        self.state.as_ref().map_or(0, |state| lock(state).epoch)
+    }
+
+    pub(crate) fn get(&self, collection: &str, id: &str) -> Option<bson::RawDocumentBuf> {
+        let state = self.state.as_ref()?;
+        let mut state = lock(state);
+        state
+            .entries
+            .get(&(collection.to_string(), id.to_string()))
+            .cloned()
+    }
+
+    /// Caches a document read at `epoch`, unless a write happened since.
+    pub(crate) fn put(&self, collection: &str, id: &str, doc: bson::RawDocumentBuf, epoch: u64) {
+        if let Some(state) = &self.state {
+            let mut state = lock(state);
+            if state.epoch == epoch {
+                state
+                    .entries
+                    .put((collection.to_string(), id.to_string()), doc);
+            }
+        }
+    }
+
+    pub(crate) fn invalidate(&self, collection: &str, id: &str) {
+        if let Some(state) = &self.state {
+            let mut state = lock(state);
+            state.epoch += 1;
+            state.entries.pop(&(collection.to_string(), id.to_string()));
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        if let Some(state) = &self.state {
+            let mut state = lock(state);
+            state.epoch += 1;
+            state.entries.clear();
+        }
+    }
+}
+
+fn lock(state: &Mutex<CacheState>) -> std::sync::MutexGuard<'_, CacheState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = DocumentCache::new(2);
+        let doc = bson::rawdoc! { "name": "John" };
+
+        cache.put("users", "a", doc.clone(), cache.epoch());
+        cache.put("users", "b", doc.clone(), cache.epoch());
+        cache.get("users", "a");
+        cache.put("users", "c", doc.clone(), cache.epoch());
+
+        assert!(cache.get("users", "a").is_some());
+        assert!(cache.get("users", "b").is_none());
+        assert!(cache.get("users", "c").is_some());
+    }
+
+    #[test]
+    fn test_stale_put_is_ignored() {
+        let cache = DocumentCache::new(2);
+        let doc = bson::rawdoc! { "name": "John" };
+
+        let epoch = cache.epoch();
+        cache.invalidate("users", "a");
+        cache.put("users", "a", doc, epoch);
+
+        assert!(cache.get("users", "a").is_none());
+    }
+
+    #[test]
+    fn test_disabled_cache() {
+        let cache = DocumentCache::new(0);
+        cache.put("users", "a", bson::rawdoc! {}, cache.epoch());
+
+        assert!(cache.get("users", "a").is_none());
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bson::{Bson, Document};
+use futures_util::{Stream, StreamExt};
+use log::error;
+use tokio::sync::broadcast;
+
+use super::wal::{self, WalRecord, WalWriter};
+use super::{filter, Database, DatabaseError, WriteStamp};
+
+/// Events a subscriber may fall behind by before it starts losing the
+/// oldest ones.
+pub(crate) const CHANGE_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationType {
+    Insert,
+    Update,
+    Delete,
+    /// A delete made by TTL expiry; its event carries the last document.
+    Expire,
+}
+
+/// A write being made, as the oplog records it and subscribers see it.
+pub(crate) struct Change<'a> {
+    pub(crate) operation: OperationType,
+    pub(crate) collection: &'a str,
+    pub(crate) id: &'a str,
+    /// The document as the write found it.
+    pub(crate) previous: Option<&'a Document>,
+    /// The document as the write leaves it.
+    pub(crate) document: Option<&'a Document>,
+}
+
+/// One write, as seen by change subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub operation: OperationType,
+    pub collection: String,
+    pub id: String,
+    /// The document as the write left it; `None` for deletes, and the last
+    /// one for expiries.
+    pub document: Option<Document>,
+    /// The fields an update changed; empty for other writes.
+    pub changes: Vec<FieldChange>,
+    /// The write's oplog position, with an oplog; `watch_after` resumes
+    /// from it.
+    pub token: Option<u64>,
+}
+
+/// One field an update set, changed or removed. Embedded documents are
+/// compared field by field, anything else as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    /// Dotted, such as `address.city`.
+    pub path: String,
+    /// `None` if the update added the field.
+    pub old: Option<Bson>,
+    /// `None` if the update removed the field.
+    pub new: Option<Bson>,
+}
+
+/// Writes as they're made, from `Database::watch` or `Database::watch_all`.
+pub struct ChangeStream {
+    events: Pin<Box<dyn Stream<Item = Result<ChangeEvent, DatabaseError>> + Send>>,
+}
+
+impl Stream for ChangeStream {
+    type Item = Result<ChangeEvent, DatabaseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+impl Database {
+    /// Streams every write made to `collection` from now on, in order. A
+    /// watcher that falls behind like a `subscribe_changes` one gets
+    /// `DatabaseError::ChangesMissed` and carries on from the latest. The
+    /// stream ends once the database is dropped.
+    pub fn watch(&self, collection: &str) -> ChangeStream {
+        self.watch_collections(Some(collection.to_string()))
+    }
+
+    /// Like `watch`, only with the writes whose document matches `filter`,
+    /// as `find` would. Deletes leave no document to match, so all of them
+    /// pass. Fails with `DatabaseError::InvalidQuery` if `find` would refuse
+    /// the filter.
+    pub fn watch_filtered(
+        &self,
+        collection: &str,
+        filter: Document,
+    ) -> Result<ChangeStream, DatabaseError> {
+        filter::validate(&filter)?;
+        let events = self.watch(collection).filter(move |event| {
+            let passes = match event {
+                Ok(ChangeEvent {
+                    document: Some(doc),
+                    ..
+                }) => filter::matches(doc, &filter),
+                _ => true,
+            };
+            std::future::ready(passes)
+        });
+        Ok(ChangeStream {
+            events: Box::pin(events),
+        })
+    }
+
+    /// Like `watch`, across every collection; each event names its own.
+    pub fn watch_all(&self) -> ChangeStream {
+        self.watch_collections(None)
+    }
+
+    /// Like `watch`, starting with the writes after the one `token` came
+    /// with, which the oplog must still hold; for a watcher picking up where
+    /// it stopped.
+    pub async fn watch_after(
+        &self,
+        collection: &str,
+        token: u64,
+    ) -> Result<ChangeStream, DatabaseError> {
+        self.watch_collections_after(Some(collection.to_string()), token)
+            .await
+    }
+
+    /// Like `watch_all`, resuming as `watch_after` does.
+    pub async fn watch_all_after(&self, token: u64) -> Result<ChangeStream, DatabaseError> {
+        self.watch_collections_after(None, token).await
+    }
+
+    async fn watch_collections_after(
+        &self,
+        collection: Option<String>,
+        token: u64,
+    ) -> Result<ChangeStream, DatabaseError> {
+        // Listening before reading the log, so nothing falls in between.
+        let live = self.watch_collections(collection.clone());
+        let logged = self.oplog_since(token).await?;
+        let end = self.oplog_range().await?.end;
+
+        let past = futures_util::stream::unfold(logged, move |mut logged| {
+            let collection = collection.clone();
+            async move {
+                while logged.position() + 1 < end {
+                    let next = logged.next().await.map(|entry| ChangeEvent {
+                        operation: entry.operation,
+                        changes: field_changes(
+                            entry.operation,
+                            entry.previous.as_ref(),
+                            entry.document.as_ref(),
+                        ),
+                        collection: entry.collection,
+                        id: entry.id,
+                        document: match entry.operation {
+                            OperationType::Expire => entry.previous,
+                            _ => entry.document,
+                        },
+                        token: Some(entry.position),
+                    });
+                    match next {
+                        Ok(event) if !watches(&collection, &event) => {}
+                        next => return Some((next, logged)),
+                    }
+                }
+                None
+            }
+        });
+        // Those before `end` were read from the log.
+        let live = live.filter(move |event| {
+            let logged =
+                matches!(event, Ok(ChangeEvent { token: Some(token), .. }) if *token < end);
+            std::future::ready(!logged)
+        });
+        Ok(ChangeStream {
+            events: Box::pin(past.chain(live)),
+        })
+    }
+
+    fn watch_collections(&self, collection: Option<String>) -> ChangeStream {
+        let events = futures_util::stream::unfold(self.subscribe_changes(), move |mut changes| {
+            let collection = collection.clone();
+            async move {
+                loop {
+                    let next = match changes.recv().await {
+                        Ok(event) if !watches(&collection, &event) => continue,
+                        Ok(event) => Ok(event),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            Err(DatabaseError::ChangesMissed(missed))
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    };
+                    return Some((next, changes));
+                }
+            }
+        });
+        ChangeStream {
+            events: Box::pin(events),
+        }
+    }
+
+    /// Receives every write made from now on, across all collections, in
+    /// the order the writes were applied to each collection. A subscriber
+    /// that falls more than 1024 events behind gets
+    /// `broadcast::error::RecvError::Lagged` and misses the oldest ones.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.inner.changes.subscribe()
+    }
+
+    /// Makes `records` durable in one write-ahead log record along with the
+    /// oplog entries of `changes`, applies them, then appends the entries
+    /// to the oplog and publishes the changes. The caller holds the write
+    /// locks of the collections and records the writes in a commit.
+    pub(crate) async fn apply_logged(
+        &self,
+        records: &[WalRecord],
+        changes: &[Change<'_>],
+        origin: Option<&WriteStamp>,
+    ) -> Result<(), DatabaseError> {
+        // Held until the changes are published, so tokens go out in order.
+        let mut oplog = match &self.inner.oplog {
+            Some(oplog) => Some((oplog, oplog.lock().await)),
+            None => None,
+        };
+        let entries: Vec<Document> = match &oplog {
+            Some((oplog, state)) => changes
+                .iter()
+                .enumerate()
+                .map(|(offset, change)| oplog.entry(state, offset as u64, change, origin))
+                .collect(),
+            None => Vec::new(),
+        };
+        let tokens: Vec<Option<u64>> = match oplog {
+            Some(_) => entries
+                .iter()
+                .map(|entry| {
+                    entry
+                        .get_i64("position")
+                        .ok()
+                        .map(|position| position as u64)
+                })
+                .collect(),
+            None => vec![None; changes.len()],
+        };
+
+        let mut batch = records.to_vec();
+        batch.extend(entries.iter().cloned().map(WalRecord::Logged));
+        let mut log =
+            WalWriter::create(&self.inner.folder_path, self.inner.wal_sequence.next(), 0).await?;
+        log.append(&[WalRecord::Batch(batch)]).await?;
+
+        let mut collections = BTreeSet::new();
+        for record in records {
+            wal::apply(&self.inner.folder_path, record).await?;
+            record.collections(&mut collections);
+
+            match record {
+                WalRecord::Insert {
+                    collection,
+                    id,
+                    doc,
+                } => {
+                    self.inner.cache.invalidate(collection, id);
+                    self.index_document(collection, id, doc);
+                    self.record_version(collection, id, Some(doc)).await?;
+                }
+                WalRecord::Delete { collection, id } => {
+                    self.inner.cache.invalidate(collection, id);
+                    self.record_version(collection, id, None).await?;
+                }
+                WalRecord::Batch(_) | WalRecord::Logged(_) => {}
+            }
+        }
+
+        if let Some((oplog, state)) = &mut oplog {
+            if let Err(e) = oplog.append(state, entries).await {
+                // The writes are made; their entries go in with the next.
+                error!("Failed to append to the oplog: {:?}", e);
+            }
+        }
+        wal::sync_collections(&self.inner.folder_path, &collections).await?;
+        wal::recycle(&self.inner.folder_path, log.path()).await?;
+
+        for (change, token) in changes.iter().zip(tokens) {
+            self.publish_change(change, token);
+        }
+        Ok(())
+    }
+
+    /// Publishes a write and starts its post-hooks. `token` is its oplog
+    /// position, if it was logged.
+    pub(crate) fn publish_change(&self, change: &Change<'_>, token: Option<u64>) {
+        let event = || ChangeEvent {
+            operation: change.operation,
+            collection: change.collection.to_string(),
+            id: change.id.to_string(),
+            document: match change.operation {
+                OperationType::Expire => change.previous.cloned(),
+                _ => change.document.cloned(),
+            },
+            changes: field_changes(change.operation, change.previous, change.document),
+            token,
+        };
+        // Spare building the event when nobody is listening.
+        if self.inner.changes.receiver_count() > 0 {
+            let _ = self.inner.changes.send(event());
+        }
+        self.inner
+            .hooks
+            .after(change.operation, change.collection, event);
+    }
+}
+
+/// What an update changed, from the document before and after it; nothing
+/// for other writes.
+fn field_changes(
+    operation: OperationType,
+    previous: Option<&Document>,
+    document: Option<&Document>,
+) -> Vec<FieldChange> {
+    let (OperationType::Update, Some(previous), Some(document)) = (operation, previous, document)
+    else {
+        return Vec::new();
+    };
+    let mut changes = Vec::new();
+    diff_fields("", previous, document, &mut changes);
+    changes
+}
+
+/// Compares embedded documents field by field and anything else, arrays
+/// included, as a whole.
+fn diff_fields(prefix: &str, old: &Document, new: &Document, changes: &mut Vec<FieldChange>) {
+    for (key, old_value) in old {
+        let path = format!("{}{}", prefix, key);
+        match (old_value, new.get(key)) {
+            (Bson::Document(old), Some(Bson::Document(new))) => {
+                diff_fields(&format!("{}.", path), old, new, changes)
+            }
+            (old_value, Some(new_value)) if old_value == new_value => {}
+            (old_value, new_value) => changes.push(FieldChange {
+                path,
+                old: Some(old_value.clone()),
+                new: new_value.cloned(),
+            }),
+        }
+    }
+    for (key, new_value) in new {
+        if !old.contains_key(key) {
+            changes.push(FieldChange {
+                path: format!("{}{}", prefix, key),
+                old: None,
+                new: Some(new_value.clone()),
+            });
+        }
+    }
+}
+
+/// Whether a stream of `collection`'s writes, or every one's, has `event`.
+fn watches(collection: &Option<String>, event: &ChangeEvent) -> bool {
+    collection
+        .as_ref()
+        .is_none_or(|collection| *collection == event.collection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DatabaseOptions, OplogOptions};
+
+    #[tokio::test]
+    async fn test_writes_are_published() {
+        let db = Database::init_test("data_tests".to_string(), "test_changes".to_string()).await;
+        db.clear().await.unwrap();
+        let mut changes = db.subscribe_changes();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "age": 1 } },
+        )
+        .await
+        .unwrap();
+        let mut txn = db.begin_transaction().await;
+        txn.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let expected = [
+            (OperationType::Insert, Some(bson::doc! { "age": 30 })),
+            (OperationType::Update, Some(bson::doc! { "age": 31 })),
+            (OperationType::Delete, None),
+        ];
+        for (operation, document) in expected {
+            let event = changes.recv().await.unwrap();
+            assert_eq!(event.operation, operation);
+            assert_eq!(event.collection, "users");
+            assert_eq!(event.id, id);
+            assert_eq!(event.document, document);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_field_changes() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_field_changes".to_string()).await;
+        db.clear().await.unwrap();
+        let mut changes = db.subscribe_changes();
+
+        let id = db
+            .insert_one(
+                "users".to_string(),
+                bson::doc! { "name": "Ada", "age": 30, "address": { "city": "Paris", "zip": "75001" } },
+            )
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            id,
+            bson::doc! {
+                "$set": {
+                    "address": { "city": "Lyon", "zip": "75001" },
+                    "role": "admin",
+                    "name": "Ada",
+                },
+                "$unset": { "age": "" },
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(changes.recv().await.unwrap().changes.is_empty());
+        let mut changed = changes.recv().await.unwrap().changes;
+        changed.sort_by(|a, b| a.path.cmp(&b.path));
+        let expected = [
+            ("address.city", Some("Paris".into()), Some("Lyon".into())),
+            ("age", Some(Bson::Int32(30)), None),
+            ("role", None, Some("admin".into())),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(path, old, new)| FieldChange {
+                path: path.to_string(),
+                old,
+                new,
+            })
+            .collect();
+        assert_eq!(changed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let db = Database::init_test("data_tests".to_string(), "test_watch".to_string()).await;
+        db.clear().await.unwrap();
+        let mut users = db.watch("users");
+        let mut all = db.watch_all();
+
+        db.insert_one("notes".to_string(), bson::doc! { "text": "hi" })
+            .await
+            .unwrap();
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let event = users.next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.operation, event.id.as_str(), event.document),
+            (
+                OperationType::Insert,
+                id.as_str(),
+                Some(bson::doc! { "age": 30 })
+            )
+        );
+        let event = users.next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.operation, event.document),
+            (OperationType::Delete, None)
+        );
+
+        let mut collections = Vec::new();
+        for _ in 0..3 {
+            collections.push(all.next().await.unwrap().unwrap().collection);
+        }
+        assert_eq!(collections, ["notes", "users", "users"]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_all() {
+        let db = Database::init_test("data_tests".to_string(), "test_watch_all".to_string()).await;
+        db.clear().await.unwrap();
+        let mut all = db.watch_all();
+
+        let note = db
+            .insert_one("notes".to_string(), bson::doc! { "text": "hi" })
+            .await
+            .unwrap();
+        let user = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+        // One commit writing to both collections.
+        let mut transaction = db.begin_transaction().await;
+        transaction
+            .delete_one("notes".to_string(), note.clone())
+            .await
+            .unwrap();
+        transaction
+            .delete_one("users".to_string(), user.clone())
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let mut events = Vec::new();
+        for _ in 0..4 {
+            let event = all.next().await.unwrap().unwrap();
+            events.push((event.collection, event.id, event.operation));
+        }
+        assert_eq!(
+            events,
+            vec![
+                ("notes".to_string(), note.clone(), OperationType::Insert),
+                ("users".to_string(), user.clone(), OperationType::Insert),
+                ("notes".to_string(), note, OperationType::Delete),
+                ("users".to_string(), user, OperationType::Delete),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_filtered() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_watch_filtered".to_string()).await;
+        db.clear().await.unwrap();
+        let mut admins = db
+            .watch_filtered("users", bson::doc! { "role": "admin" })
+            .unwrap();
+        assert!(matches!(
+            db.watch_filtered("users", bson::doc! { "role": { "$like": "adm" } }),
+            Err(DatabaseError::InvalidQuery(_))
+        ));
+
+        let guest = db
+            .insert_one("users".to_string(), bson::doc! { "role": "guest" })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            guest.clone(),
+            bson::doc! { "$set": { "role": "admin" } },
+        )
+        .await
+        .unwrap();
+        db.delete_one("users".to_string(), guest.clone())
+            .await
+            .unwrap();
+
+        let mut operations = Vec::new();
+        for _ in 0..2 {
+            let event = admins.next().await.unwrap().unwrap();
+            assert_eq!(event.id, guest);
+            operations.push(event.operation);
+        }
+        assert_eq!(operations, [OperationType::Update, OperationType::Delete]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_after() {
+        let folder_path = "data_tests/test_watch_after".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        let mut all = db.watch_all();
+
+        let john = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let token = all.next().await.unwrap().unwrap().token.unwrap();
+        db.insert_one("notes".to_string(), bson::doc! { "text": "hi" })
+            .await
+            .unwrap();
+        db.delete_one("users".to_string(), john.clone())
+            .await
+            .unwrap();
+
+        // The delete comes from the log, the insert after it live.
+        let mut users = db.watch_after("users", token).await.unwrap();
+        let jane = db
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let event = users.next().await.unwrap().unwrap();
+            events.push((event.id, event.token.unwrap()));
+        }
+        assert_eq!(events, [(john, token + 2), (jane, token + 3)]);
+
+        let mut resumed = db.watch_all_after(token + 1).await.unwrap();
+        let event = resumed.next().await.unwrap().unwrap();
+        assert_eq!(event.token, Some(token + 2));
+        assert!(matches!(
+            db.watch_after("users", token + 10).await,
+            Err(DatabaseError::OplogTruncated(_))
+        ));
+    }
+}
@@ -0,0 +1,331 @@
+//! How a collection's documents are encoded in their files: BSON, or CBOR
+//! or MessagePack with the `cbor` and `msgpack` features, for systems that
+//! read the files but don't speak BSON. The codec is chosen when the
+//! collection is created with `Database::create_collection` and recorded in
+//! a `.codec` file in its directory; collections created by their first
+//! write are BSON. A document file's extension names its encoding, e.g.
+//! `<id>.cbor`.
+//!
+//! CBOR and MessagePack have no width for integers, so a 64-bit integer
+//! small enough for 32 bits reads back as one. Documents are BSON
+//! everywhere else: in the write-ahead log, the cache, change events and
+//! backups. A restored collection is BSON.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use bson::{Document, RawDocumentBuf};
+use log::info;
+
+use super::{check_name, Database, DatabaseError};
+
+const CODEC_FILE: &str = ".codec";
+/// Ends the name of a collection directory being set up.
+pub(crate) const CREATING_SUFFIX: &str = ".creating";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Bson,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CollectionOptions {
+    pub codec: Codec,
+}
+
+impl Codec {
+    /// The extension of its document files, and its name in `.codec`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Bson => "bson",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "cbor",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "msgpack",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "bson" => Some(Codec::Bson),
+            #[cfg(feature = "cbor")]
+            "cbor" => Some(Codec::Cbor),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// The encoding of a document file; `None` for files that aren't
+    /// documents, like `.codec`.
+    pub(crate) fn of_file(path: &Path) -> Option<Self> {
+        Self::from_extension(path.extension()?.to_str()?)
+    }
+
+    pub(crate) fn encode(self, doc: &Document) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            Codec::Bson => {
+                let mut buffer = Vec::new();
+                doc.to_writer(&mut buffer)
+                    .map_err(DatabaseError::BsonSerError)?;
+                Ok(buffer)
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(doc, &mut buffer).map_err(codec_error)?;
+                Ok(buffer)
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(doc).map_err(codec_error),
+        }
+    }
+
+    /// Reads a document file's contents as raw BSON.
+    pub(crate) fn decode(self, bytes: Vec<u8>) -> Result<RawDocumentBuf, DatabaseError> {
+        match self {
+            Codec::Bson => RawDocumentBuf::from_bytes(bytes).map_err(DatabaseError::BsonRawError),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => to_raw(ciborium::from_reader(bytes.as_slice()).map_err(codec_error)?),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => to_raw(rmp_serde::from_slice(&bytes).map_err(codec_error)?),
+        }
+    }
+}
+
+#[cfg(any(feature = "cbor", feature = "msgpack"))]
+fn to_raw(doc: Document) -> Result<RawDocumentBuf, DatabaseError> {
+    RawDocumentBuf::from_document(&doc).map_err(DatabaseError::BsonRawError)
+}
+
+#[cfg(any(feature = "cbor", feature = "msgpack"))]
+fn codec_error(e: impl std::fmt::Display) -> DatabaseError {
+    DatabaseError::CodecError(e.to_string())
+}
+
+/// The codecs of the collections looked up so far.
+#[derive(Default)]
+pub(crate) struct Codecs {
+    collections: RwLock<HashMap<String, Codec>>,
+}
+
+impl Codecs {
+    pub(crate) fn clear(&self) {
+        self.collections
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+/// The codec `collection` was created with, or `None` if it doesn't exist
+/// yet.
+pub(crate) async fn read(
+    folder_path: &str,
+    collection: &str,
+) -> Result<Option<Codec>, DatabaseError> {
+    let collection_path = format!("{}/{}", folder_path, collection);
+    match tokio::fs::read_to_string(format!("{}/{}", collection_path, CODEC_FILE)).await {
+        Ok(name) => match Codec::from_extension(name.trim()) {
+            Some(codec) => Ok(Some(codec)),
+            None => Err(DatabaseError::CodecError(format!(
+                "'{}' is stored as '{}', which this build doesn't support",
+                collection,
+                name.trim()
+            ))),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            match tokio::fs::try_exists(&collection_path).await {
+                Ok(true) => Ok(Some(Codec::Bson)),
+                Ok(false) => Ok(None),
+                Err(e) => Err(DatabaseError::IoError(e)),
+            }
+        }
+        Err(e) => Err(DatabaseError::IoError(e)),
+    }
+}
+
+impl Database {
+    /// Creates `name`, storing its documents with `options.codec`. Fails
+    /// with `DatabaseError::CollectionExists` if it already exists, even
+    /// empty, since its codec is settled then.
+    pub async fn create_collection(
+        &self,
+        name: &str,
+        options: CollectionOptions,
+    ) -> Result<(), DatabaseError> {
+        self.check_writable()?;
+        let collection_path = self.get_collection_path(name)?;
+        let _guard = self.inner.activity.begin().await?;
+        let _lock = self.inner.locks.write(name).await;
+
+        let exists = || DatabaseError::CollectionExists(name.to_string());
+        if tokio::fs::try_exists(&collection_path)
+            .await
+            .map_err(DatabaseError::IoError)?
+        {
+            return Err(exists());
+        }
+
+        // Set up aside and moved into place, so the collection never exists
+        // without its codec.
+        let staging = format!("{}/.{}{}", self.inner.folder_path, name, CREATING_SUFFIX);
+        let _ = tokio::fs::remove_dir_all(&staging).await;
+        Self::create_path_dirs(&staging).await?;
+        tokio::fs::write(
+            format!("{}/{}", staging, CODEC_FILE),
+            options.codec.extension(),
+        )
+        .await
+        .map_err(DatabaseError::IoError)?;
+        if tokio::fs::rename(&staging, &collection_path).await.is_err() {
+            let _ = tokio::fs::remove_dir_all(&staging).await;
+            return Err(exists());
+        }
+
+        self.inner
+            .codecs
+            .collections
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), options.codec);
+        info!(
+            "Created collection '{}' stored as {}",
+            name,
+            options.codec.extension()
+        );
+        Ok(())
+    }
+
+    /// The codec of `collection`'s document files: BSON until it exists.
+    pub(crate) async fn codec(&self, collection: &str) -> Result<Codec, DatabaseError> {
+        check_name(collection)?;
+        let cached = self
+            .inner
+            .codecs
+            .collections
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(collection)
+            .copied();
+        if let Some(codec) = cached {
+            return Ok(codec);
+        }
+
+        match read(&self.inner.folder_path, collection).await? {
+            Some(codec) => {
+                self.inner
+                    .codecs
+                    .collections
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(collection.to_string(), codec);
+                Ok(codec)
+            }
+            None => Ok(Codec::Bson),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_create_collection() {
+        let db = Database::init_test("data_tests".to_string(), "test_codec".to_string()).await;
+        db.clear().await.unwrap();
+        db.put(
+            "users".to_string(),
+            "ada".to_string(),
+            bson::doc! { "name": "Ada" },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            db.create_collection("users", CollectionOptions::default())
+                .await,
+            Err(DatabaseError::CollectionExists(name)) if name == "users"
+        ));
+        assert_eq!(db.codec("users").await.unwrap(), Codec::Bson);
+
+        db.create_collection("empty", CollectionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(db.collections().await.unwrap().len(), 2);
+        assert!(db
+            .find("empty".to_string(), Document::new())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(all(feature = "cbor", feature = "msgpack"))]
+    #[tokio::test]
+    async fn test_codecs_round_trip() {
+        let db = Database::init_test(
+            "data_tests".to_string(),
+            "test_codec_round_trip".to_string(),
+        )
+        .await;
+        db.clear().await.unwrap();
+        let doc = bson::doc! {
+            "name": "Ada",
+            "age": 36,
+            "score": 9.5,
+            "tags": ["math", "engines"],
+            "address": { "city": "London" },
+            "born": bson::DateTime::from_millis(-4_766_400_000_000),
+            "ref": bson::oid::ObjectId::parse_str("65f1a0c2e4b0a1b2c3d4e5f6").unwrap(),
+            "active": true,
+            "notes": bson::Bson::Null,
+        };
+
+        for codec in [Codec::Cbor, Codec::MessagePack] {
+            let collection = format!("people_{}", codec.extension());
+            db.create_collection(&collection, CollectionOptions { codec })
+                .await
+                .unwrap();
+            db.put(collection.clone(), "ada".to_string(), doc.clone())
+                .await
+                .unwrap();
+            let path = format!(
+                "data_tests/test_codec_round_trip/{}/ada.{}",
+                collection,
+                codec.extension()
+            );
+            let bytes = tokio::fs::read(&path).await.unwrap();
+            assert_eq!(codec.decode(bytes).unwrap().to_document().unwrap(), doc);
+
+            db.inner.cache.clear();
+            assert_eq!(
+                db.find_one(collection.clone(), "ada".to_string())
+                    .await
+                    .unwrap(),
+                Some(doc.clone())
+            );
+            let found = db
+                .find(collection.clone(), bson::doc! { "name": "Ada" })
+                .await
+                .unwrap();
+            assert_eq!(found.len(), 1);
+            db.delete_one(collection.clone(), "ada".to_string())
+                .await
+                .unwrap();
+            assert!(tokio::fs::metadata(&path).await.is_err());
+        }
+
+        let reopened = Database::init_test(
+            "data_tests".to_string(),
+            "test_codec_round_trip".to_string(),
+        )
+        .await;
+        assert_eq!(reopened.codec("people_cbor").await.unwrap(), Codec::Cbor);
+    }
+}
@@ -0,0 +1,185 @@
+//! Typed access to a collection: values of a serde type go in and come out,
+//! converted to and from BSON documents on the way. A document that doesn't
+//! deserialize into the type fails with `DatabaseError::SchemaMismatch`,
+//! naming it. IDs stay outside the type, as with untyped documents.
+
+use std::marker::PhantomData;
+
+use bson::Document;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Database, DatabaseError};
+
+/// A collection whose documents are `T`s, from `Database::collection`.
+/// Cloning is cheap.
+pub struct Collection<T> {
+    db: Database,
+    name: String,
+    kind: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Collection<T> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            name: self.name.clone(),
+            kind: PhantomData,
+        }
+    }
+}
+
+impl Database {
+    pub fn collection<T>(&self, name: &str) -> Collection<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        Collection {
+            db: self.clone(),
+            name: name.to_string(),
+            kind: PhantomData,
+        }
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Returns the new document's ID.
+    pub async fn insert_one(&self, value: &T) -> Result<String, DatabaseError> {
+        self.db
+            .insert_one(self.name.clone(), to_document(value)?)
+            .await
+    }
+
+    /// Writes `value` under `id`, replacing any document there.
+    pub async fn put(&self, id: &str, value: &T) -> Result<(), DatabaseError> {
+        self.db
+            .put(self.name.clone(), id.to_string(), to_document(value)?)
+            .await
+    }
+
+    pub async fn find_one(&self, id: &str) -> Result<Option<T>, DatabaseError> {
+        self.db
+            .find_one(self.name.clone(), id.to_string())
+            .await?
+            .map(|doc| from_document(id, doc))
+            .transpose()
+    }
+
+    /// The documents matching `filter`, by ID.
+    pub async fn find_with_ids(&self, filter: Document) -> Result<Vec<(String, T)>, DatabaseError> {
+        self.db
+            .find_with_ids(self.name.clone(), filter)
+            .await?
+            .into_iter()
+            .map(|(id, doc)| {
+                let value = from_document(&id, doc)?;
+                Ok((id, value))
+            })
+            .collect()
+    }
+
+    pub async fn find(&self, filter: Document) -> Result<Vec<T>, DatabaseError> {
+        Ok(self
+            .find_with_ids(filter)
+            .await?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    /// Applies `update` as `Database::update_one` does. Returns `false` if
+    /// there is no such document.
+    pub async fn update_one(&self, id: &str, update: Document) -> Result<bool, DatabaseError> {
+        self.db
+            .update_one(self.name.clone(), id.to_string(), update)
+            .await
+    }
+
+    pub async fn delete_one(&self, id: &str) -> Result<(), DatabaseError> {
+        self.db
+            .delete_one(self.name.clone(), id.to_string())
+            .await?;
+        Ok(())
+    }
+}
+
+fn to_document<T: Serialize>(value: &T) -> Result<Document, DatabaseError> {
+    bson::to_document(value).map_err(DatabaseError::BsonSerError)
+}
+
+fn from_document<T: DeserializeOwned>(id: &str, doc: Document) -> Result<T, DatabaseError> {
+    bson::from_document(doc).map_err(|e| DatabaseError::SchemaMismatch(id.to_string(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    #[tokio::test]
+    async fn test_typed_collection() {
+        let db = Database::init_test("data_tests".to_string(), "test_collection".to_string()).await;
+        db.clear().await.unwrap();
+        let users = db.collection::<User>("users");
+        let ada = User {
+            name: "Ada".to_string(),
+            age: 36,
+            tags: vec!["math".to_string()],
+        };
+
+        let id = users.insert_one(&ada).await.unwrap();
+        assert_eq!(users.find_one(&id).await.unwrap(), Some(ada));
+        users
+            .put(
+                "grace",
+                &User {
+                    name: "Grace".to_string(),
+                    age: 85,
+                    tags: Vec::new(),
+                },
+            )
+            .await
+            .unwrap();
+        assert!(users
+            .update_one("grace", bson::doc! { "$inc": { "age": 1 } })
+            .await
+            .unwrap());
+        let found = users.find(bson::doc! { "name": "Grace" }).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].age, 86);
+
+        db.put(
+            "users".to_string(),
+            "bad".to_string(),
+            bson::doc! { "name": "Bad", "age": "old" },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            users.find_one("bad").await,
+            Err(DatabaseError::SchemaMismatch(id, _)) if id == "bad"
+        ));
+        users.delete_one("bad").await.unwrap();
+        assert_eq!(users.find(Document::new()).await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,120 @@
+//! What the Parquet and Arrow exports share: documents flattened into rows
+//! of dotted paths, and a type inferred for each column.
+
+use bson::{Bson, Document};
+
+use super::{Database, DatabaseError, Projection};
+
+/// A document's fields, embedded documents flattened, nulls left out.
+pub(crate) type Row = Vec<(String, Bson)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Bool,
+    Int32,
+    Int64,
+    Double,
+    String,
+    Timestamp,
+}
+
+impl Database {
+    /// The documents matching `filter`, cut down by `projection`, as rows
+    /// by ID.
+    pub(crate) async fn rows(
+        &self,
+        collection: &str,
+        filter: Document,
+        projection: &Projection,
+    ) -> Result<Vec<(String, Row)>, DatabaseError> {
+        let mut documents = self
+            .find_projected(collection.to_string(), filter, projection)
+            .await?;
+        documents.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(documents
+            .into_iter()
+            .map(|(id, doc)| {
+                let mut row = Vec::new();
+                flatten("", doc, &mut row);
+                (id, row)
+            })
+            .collect())
+    }
+}
+
+/// Embedded documents become dotted paths.
+fn flatten(prefix: &str, doc: Document, row: &mut Row) {
+    for (key, value) in doc {
+        let path = format!("{}{}", prefix, key);
+        match value {
+            Bson::Document(inner) => flatten(&format!("{}.", path), inner, row),
+            Bson::Null => {}
+            value => row.push((path, value)),
+        }
+    }
+}
+
+/// A column for each path found, in the order first seen, of the narrowest
+/// kind holding all its values: integers widen to 64 bits and to doubles as
+/// needed, and a column mixing anything else holds strings.
+pub(crate) fn infer_columns(rows: &[(String, Row)]) -> Vec<(String, Kind)> {
+    let mut columns: Vec<(String, Kind)> = Vec::new();
+    for (_, row) in rows {
+        for (path, value) in row {
+            let kind = kind_of(value);
+            match columns.iter_mut().find(|(column, _)| column == path) {
+                Some((_, column)) => *column = widen(*column, kind),
+                None => columns.push((path.clone(), kind)),
+            }
+        }
+    }
+    columns
+}
+
+fn kind_of(value: &Bson) -> Kind {
+    match value {
+        Bson::Boolean(_) => Kind::Bool,
+        Bson::Int32(_) => Kind::Int32,
+        Bson::Int64(_) => Kind::Int64,
+        Bson::Double(_) => Kind::Double,
+        Bson::DateTime(_) => Kind::Timestamp,
+        _ => Kind::String,
+    }
+}
+
+fn widen(a: Kind, b: Kind) -> Kind {
+    use Kind::*;
+    match (a, b) {
+        (a, b) if a == b => a,
+        (Int32, Int64) | (Int64, Int32) => Int64,
+        (Int32 | Int64 | Double, Int32 | Int64 | Double) => Double,
+        _ => String,
+    }
+}
+
+pub(crate) fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(n) => Some(*n as i64),
+        Bson::Int64(n) => Some(*n),
+        _ => None,
+    }
+}
+
+pub(crate) fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Any value as text: strings as they are, ObjectIds as hex, anything else,
+/// arrays included, as JSON.
+pub(crate) fn as_text(value: &Bson) -> String {
+    match value {
+        Bson::String(s) => s.clone(),
+        Bson::ObjectId(id) => id.to_hex(),
+        value => value.clone().into_relaxed_extjson().to_string(),
+    }
+}
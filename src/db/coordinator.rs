@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use bson::Document;
+use log::{error, info};
+use tokio::sync::{mpsc, oneshot};
+
+use super::batch::WriteOp;
+use super::{Database, DatabaseError, DatabaseInner, TransactionLimits, TransactionOptions};
+
+#[derive(Debug, Clone)]
+pub struct WriteCoordinatorOptions {
+    /// Most writes committed together in one write-ahead log record.
+    pub max_batch: usize,
+    /// Writes that may wait for a collection's writer before callers block.
+    pub queue_capacity: usize,
+}
+
+impl Default for WriteCoordinatorOptions {
+    fn default() -> Self {
+        Self {
+            max_batch: 128,
+            queue_capacity: 1024,
+        }
+    }
+}
+
+enum WriteOutcome {
+    Inserted(String),
+    Updated(bool),
+    Deleted(bool),
+}
+
+struct WriteRequest {
+    op: WriteOp,
+    reply: oneshot::Sender<Result<WriteOutcome, DatabaseError>>,
+}
+
+/// Routes writes to one writer task per collection. Each task drains what
+/// has queued up and commits it as a single batch (group commit), so writes
+/// to a collection are serialized without callers contending for locks.
+pub(crate) struct WriteCoordinator {
+    options: WriteCoordinatorOptions,
+    writers: Mutex<HashMap<String, mpsc::Sender<WriteRequest>>>,
+}
+
+impl WriteCoordinator {
+    pub(crate) fn new(options: WriteCoordinatorOptions) -> Self {
+        Self {
+            options,
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) async fn insert(
+        &self,
+        db: &Database,
+        collection: String,
+        doc: Document,
+    ) -> Result<String, DatabaseError> {
+        match self.submit(db, collection, WriteOp::Insert(doc)).await? {
+            WriteOutcome::Inserted(id) => Ok(id),
+            _ => unreachable!("insert answered with another outcome"),
+        }
+    }
+
+    pub(crate) async fn update(
+        &self,
+        db: &Database,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<bool, DatabaseError> {
+        match self
+            .submit(db, collection, WriteOp::Update { id, update })
+            .await?
+        {
+            WriteOutcome::Updated(updated) => Ok(updated),
+            _ => unreachable!("update answered with another outcome"),
+        }
+    }
+
+    pub(crate) async fn delete(
+        &self,
+        db: &Database,
+        collection: String,
+        id: String,
+    ) -> Result<bool, DatabaseError> {
+        match self.submit(db, collection, WriteOp::Delete { id }).await? {
+            WriteOutcome::Deleted(deleted) => Ok(deleted),
+            _ => unreachable!("delete answered with another outcome"),
+        }
+    }
+
+    async fn submit(
+        &self,
+        db: &Database,
+        collection: String,
+        op: WriteOp,
+    ) -> Result<WriteOutcome, DatabaseError> {
+        let (reply, response) = oneshot::channel();
+        let writer = self.writer(db, &collection);
+
+        writer
+            .send(WriteRequest { op, reply })
+            .await
+            .map_err(|_| writer_gone(&collection))?;
+
+        response.await.map_err(|_| writer_gone(&collection))?
+    }
+
+    fn writer(&self, db: &Database, collection: &str) -> mpsc::Sender<WriteRequest> {
+        let mut writers = self.writers.lock().unwrap_or_else(|e| e.into_inner());
+
+        writers
+            .entry(collection.to_string())
+            .or_insert_with(|| {
+                let (sender, receiver) = mpsc::channel(self.options.queue_capacity);
+                tokio::spawn(run_writer(
+                    Arc::downgrade(&db.inner),
+                    collection.to_string(),
+                    receiver,
+                    self.options.max_batch,
+                ));
+                sender
+            })
+            .clone()
+    }
+}
+
+fn writer_gone(collection: &str) -> DatabaseError {
+    DatabaseError::IoError(std::io::Error::other(format!(
+        "writer for collection '{}' stopped",
+        collection
+    )))
+}
+
+/// Holds only a weak reference, so the task ends once the database (and
+/// with it the sending side of `requests`) is dropped.
+async fn run_writer(
+    db: Weak<DatabaseInner>,
+    collection: String,
+    mut requests: mpsc::Receiver<WriteRequest>,
+    max_batch: usize,
+) {
+    info!("Writer started for collection '{}'", collection);
+
+    while let Some(request) = requests.recv().await {
+        let mut batch = vec![request];
+        while batch.len() < max_batch {
+            match requests.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+
+        let inner = match db.upgrade() {
+            Some(inner) => inner,
+            None => break,
+        };
+        let db = Database { inner };
+
+        commit_batch(&db, &collection, batch).await;
+    }
+
+    info!("Writer stopped for collection '{}'", collection);
+}
+
+/// Applies a batch in one transaction. A write that fails on its own is
+/// rolled back alone; anything that aborts the transaction fails the batch.
+async fn commit_batch(db: &Database, collection: &str, batch: Vec<WriteRequest>) {
+    // Batches are bounded by `max_batch` already; they shouldn't fail as a
+    // whole because they happen to be large.
+    let mut transaction = db
+        .begin_transaction_with_options(TransactionOptions {
+            limits: Some(TransactionLimits::unlimited()),
+            ..TransactionOptions::default()
+        })
+        .await;
+    let mut outcomes = Vec::with_capacity(batch.len());
+    let mut replies = Vec::with_capacity(batch.len());
+
+    for request in batch {
+        transaction.savepoint("write");
+        let outcome = match request.op {
+            WriteOp::Insert(doc) => transaction
+                .insert_one(collection.to_string(), doc)
+                .await
+                .map(WriteOutcome::Inserted),
+            WriteOp::Update { id, update } => transaction
+                .update_one(collection.to_string(), id, update)
+                .await
+                .map(WriteOutcome::Updated),
+            WriteOp::Delete { id } => transaction
+                .delete_one(collection.to_string(), id)
+                .await
+                .map(WriteOutcome::Deleted),
+        };
+
+        match outcome {
+            Ok(outcome) => {
+                outcomes.push(outcome);
+                replies.push(request.reply);
+            }
+            Err(e) => {
+                if transaction.rollback_to("write").is_err() {
+                    // The transaction itself was aborted, taking the other
+                    // writes with it; they fail for the same reason.
+                    fail_all(replies, &e);
+                    let _ = request.reply.send(Err(e));
+                    return;
+                }
+                let _ = request.reply.send(Err(e));
+            }
+        }
+    }
+
+    match transaction.commit().await {
+        Ok(()) => {
+            for (reply, outcome) in replies.into_iter().zip(outcomes) {
+                let _ = reply.send(Ok(outcome));
+            }
+        }
+        Err(e) => {
+            error!("Failed to commit writes to '{}': {:?}", collection, e);
+            fail_all(replies, &e);
+        }
+    }
+}
+
+fn fail_all(
+    replies: Vec<oneshot::Sender<Result<WriteOutcome, DatabaseError>>>,
+    error: &DatabaseError,
+) {
+    for reply in replies {
+        let _ = reply.send(Err(copy_error(error)));
+    }
+}
+
+/// `error` again for another waiter. `DatabaseError` can't be cloned, but
+/// callers decide whether to retry by its variant, so that is kept; only
+/// errors wrapping a library's error are reduced to their description.
+fn copy_error(error: &DatabaseError) -> DatabaseError {
+    match error {
+        DatabaseError::IoError(e) => {
+            DatabaseError::IoError(std::io::Error::new(e.kind(), e.to_string()))
+        }
+        DatabaseError::WalCorrupted(m) => DatabaseError::WalCorrupted(m.clone()),
+        DatabaseError::InvalidUpdate(m) => DatabaseError::InvalidUpdate(m.clone()),
+        DatabaseError::InvalidQuery(m) => DatabaseError::InvalidQuery(m.clone()),
+        DatabaseError::ReadOnly => DatabaseError::ReadOnly,
+        DatabaseError::Closed => DatabaseError::Closed,
+        DatabaseError::LockTimeout(m) => DatabaseError::LockTimeout(m.clone()),
+        DatabaseError::Deadlock(m) => DatabaseError::Deadlock(m.clone()),
+        DatabaseError::WriteConflict(m) => DatabaseError::WriteConflict(m.clone()),
+        DatabaseError::SerializationFailure(m) => DatabaseError::SerializationFailure(m.clone()),
+        DatabaseError::TransactionLimitExceeded(m) => {
+            DatabaseError::TransactionLimitExceeded(m.clone())
+        }
+        DatabaseError::TransactionAborted => DatabaseError::TransactionAborted,
+        DatabaseError::QuotaExceeded(m) => DatabaseError::QuotaExceeded(m.clone()),
+        other => DatabaseError::IoError(std::io::Error::other(format!("{:?}", other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DatabaseOptions;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrent_writes_through_coordinator() {
+        let folder_path = "data_tests/test_coordinator".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            write_coordinator: Some(WriteCoordinatorOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let id = db
+            .insert_one("counters".to_string(), bson::doc! { "count": 0 })
+            .await
+            .unwrap();
+
+        let mut tasks = Vec::new();
+        for _ in 0..20 {
+            let db = db.clone();
+            let id = id.clone();
+            tasks.push(tokio::spawn(async move {
+                db.update_one(
+                    "counters".to_string(),
+                    id,
+                    bson::doc! { "$inc": { "count": 1 } },
+                )
+                .await
+            }));
+        }
+        for task in tasks {
+            assert!(task.await.unwrap().unwrap());
+        }
+
+        assert_eq!(
+            db.find_one("counters".to_string(), id.clone())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "count": 20 })
+        );
+
+        let failed = db
+            .update_one(
+                "counters".to_string(),
+                id.clone(),
+                bson::doc! { "$inc": { "missing": "x" } },
+            )
+            .await;
+        assert!(matches!(failed, Err(DatabaseError::InvalidUpdate(_))));
+
+        db.delete_one("counters".to_string(), id.clone())
+            .await
+            .unwrap();
+        assert!(db
+            .find_one("counters".to_string(), id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_conflict_in_batch_is_transient() {
+        let folder_path = "data_tests/test_coordinator_conflict".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let id = db
+            .insert_one("counters".to_string(), bson::doc! { "count": 0 })
+            .await
+            .unwrap();
+
+        // Holds the document while the batch is waiting for it, then
+        // changes it under the batch's snapshot.
+        let mut transaction = db.begin_transaction().await;
+        transaction
+            .update_one(
+                "counters".to_string(),
+                id.clone(),
+                bson::doc! { "$inc": { "count": 1 } },
+            )
+            .await
+            .unwrap();
+
+        let mut batch = Vec::new();
+        let mut responses = Vec::new();
+        for op in [
+            WriteOp::Insert(bson::doc! { "count": 0 }),
+            WriteOp::Update {
+                id,
+                update: bson::doc! { "$inc": { "count": 1 } },
+            },
+        ] {
+            let (reply, response) = oneshot::channel();
+            batch.push(WriteRequest { op, reply });
+            responses.push(response);
+        }
+        let committing = tokio::spawn({
+            let db = db.clone();
+            async move { commit_batch(&db, "counters", batch).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        transaction.commit().await.unwrap();
+        committing.await.unwrap();
+
+        // The insert fails with the conflict that aborted its batch.
+        for response in responses {
+            let error = response.await.unwrap().err().unwrap();
+            assert!(matches!(error, DatabaseError::WriteConflict(_)));
+            assert!(error.is_transient());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_keeps_error_kind() {
+        let (reply, response) = oneshot::channel();
+        fail_all(
+            vec![reply],
+            &DatabaseError::WriteConflict("counters/a".to_string()),
+        );
+        let error = response.await.unwrap().err().unwrap();
+        assert!(matches!(&error, DatabaseError::WriteConflict(m) if m == "counters/a"));
+        assert!(error.is_transient());
+
+        let io = DatabaseError::IoError(std::io::ErrorKind::NotFound.into());
+        assert!(matches!(
+            copy_error(&io),
+            DatabaseError::IoError(e) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Copying a collection from one database to another, say to promote data
+//! from staging to production. Documents keep their IDs, replacing any the
+//! target holds under the same ones, and are read from one snapshot of the
+//! source.
+
+use bson::Document;
+use log::info;
+
+use super::wal::WalRecord;
+use super::{Database, DatabaseError};
+
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Only documents matching it, as `find` would.
+    pub filter: Document,
+    /// Index the target collection on the fields the source one is.
+    pub indexes: bool,
+    /// Documents written together; each batch is atomic.
+    pub batch_size: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            filter: Document::new(),
+            indexes: true,
+            batch_size: 1000,
+        }
+    }
+}
+
+/// Copies `source_collection`'s matching documents in `source` to
+/// `target_collection` in `target`, which may be the same database.
+/// Bypasses the target's hooks and validators, like an import. Answers how
+/// many documents were copied.
+pub async fn copy_collection(
+    source: &Database,
+    source_collection: &str,
+    target: &Database,
+    target_collection: &str,
+    options: CopyOptions,
+) -> Result<usize, DatabaseError> {
+    target.check_writable()?;
+    let documents = source
+        .find_with_ids(source_collection.to_string(), options.filter)
+        .await?;
+
+    if options.indexes {
+        let fields: Vec<String> = source
+            .read_index()
+            .get(source_collection)
+            .map(|fields| fields.keys().cloned().collect())
+            .unwrap_or_default();
+        for field in fields {
+            target.add_index(target_collection.to_string(), field);
+        }
+    }
+
+    let count = documents.len();
+    let mut documents = documents.into_iter().peekable();
+    while documents.peek().is_some() {
+        let records = documents
+            .by_ref()
+            .take(options.batch_size.max(1))
+            .map(|(id, doc)| WalRecord::Insert {
+                collection: target_collection.to_string(),
+                id,
+                doc,
+            })
+            .collect();
+        target.apply_records(records, None, None).await?;
+    }
+
+    info!(
+        "Copied {} documents from '{}' in '{}' to '{}' in '{}'",
+        count,
+        source_collection,
+        source.inner.folder_path,
+        target_collection,
+        target.inner.folder_path
+    );
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_copy_collection() {
+        let source =
+            Database::init_test("data_tests".to_string(), "test_copy_source".to_string()).await;
+        let target =
+            Database::init_test("data_tests".to_string(), "test_copy_target".to_string()).await;
+        source.clear().await.unwrap();
+        target.clear().await.unwrap();
+        source.add_index("users".to_string(), "tenant".to_string());
+        for (id, tenant) in [("ada", "acme"), ("grace", "acme"), ("alan", "other")] {
+            source
+                .put(
+                    "users".to_string(),
+                    id.to_string(),
+                    bson::doc! { "tenant": tenant, "name": id },
+                )
+                .await
+                .unwrap();
+        }
+        target
+            .put(
+                "people".to_string(),
+                "ada".to_string(),
+                bson::doc! { "name": "stale" },
+            )
+            .await
+            .unwrap();
+
+        let options = CopyOptions {
+            filter: bson::doc! { "tenant": "acme" },
+            batch_size: 1,
+            ..CopyOptions::default()
+        };
+        assert_eq!(
+            copy_collection(&source, "users", &target, "people", options)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            target
+                .find_one("people".to_string(), "ada".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "tenant": "acme", "name": "ada" })
+        );
+        assert_eq!(
+            target
+                .find_one("people".to_string(), "alan".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(target.read_index()["people"]["tenant"].len(), 2);
+    }
+}
@@ -0,0 +1,504 @@
+//! CSV in and out, for spreadsheets. Importing maps each column to the
+//! field its header names, a dotted header such as `address.city` to an
+//! embedded one, and the `_id` column to the ID. Cells are numbers, booleans
+//! or strings as they look, unless a type hint says otherwise; empty ones
+//! leave the field out. Exporting flattens embedded documents the same way
+//! and writes arrays as JSON.
+//!
+//! Fields follow RFC 4180: separated by the delimiter, quoted with `"` when
+//! they hold it, a quote or a line break, quotes doubled inside.
+
+use std::collections::HashMap;
+
+use bson::{Bson, Document};
+use log::info;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::import::{ImportReport, LineError};
+use super::wal::WalRecord;
+use super::{Database, DatabaseError, Projection};
+
+/// Records written together while importing.
+const CSV_BATCH: usize = 1000;
+
+/// What a column's cells are read as, instead of what they look like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsvType {
+    String,
+    Int,
+    Double,
+    Bool,
+    /// RFC 3339, such as `2024-05-01T12:00:00Z`.
+    Date,
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvImportOptions {
+    pub delimiter: char,
+    /// Types for columns, by header.
+    pub types: HashMap<String, CsvType>,
+}
+
+impl Default for CsvImportOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            types: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvExportOptions {
+    pub delimiter: char,
+    /// Dotted paths to write, in this order; empty for every field found.
+    pub fields: Vec<String>,
+    /// Only documents matching it, as `find` would.
+    pub filter: Document,
+    pub projection: Projection,
+}
+
+impl Default for CsvExportOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            fields: Vec::new(),
+            filter: Document::new(),
+            projection: Projection::All,
+        }
+    }
+}
+
+impl Database {
+    /// Imports every record of `reader` into `collection`, the first one
+    /// naming the columns. Records without an `_id` get a new ID; those
+    /// that fail are listed in the report, by the line they start on.
+    pub async fn import_csv<R>(
+        &self,
+        collection: String,
+        mut reader: R,
+        options: CsvImportOptions,
+    ) -> Result<ImportReport, DatabaseError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        self.check_writable()?;
+        let mut line = 0;
+        let header = match read_record(&mut reader, options.delimiter, &mut line).await? {
+            Some((_, header)) => header,
+            None => return Ok(ImportReport::default()),
+        };
+
+        let mut report = ImportReport::default();
+        let mut batch = Vec::new();
+        while let Some((start, cells)) =
+            read_record(&mut reader, options.delimiter, &mut line).await?
+        {
+            if cells.iter().all(|cell| cell.is_empty()) {
+                continue;
+            }
+            match to_document(&header, cells, &options) {
+                Ok((id, doc)) => batch.push(WalRecord::Insert {
+                    collection: collection.clone(),
+                    id,
+                    doc,
+                }),
+                Err(reason) => report.errors.push(LineError {
+                    line: start,
+                    reason,
+                }),
+            }
+            if batch.len() == CSV_BATCH {
+                report.imported += batch.len() as u64;
+                self.apply_records(std::mem::take(&mut batch), None, None)
+                    .await?;
+            }
+        }
+        if !batch.is_empty() {
+            report.imported += batch.len() as u64;
+            self.apply_records(batch, None, None).await?;
+        }
+
+        info!(
+            "Imported {} records into '{}' from CSV",
+            report.imported, collection
+        );
+        Ok(report)
+    }
+
+    /// Writes `collection`'s matching documents to `writer` as CSV, a
+    /// header first and then a record per document. Answers how many.
+    pub async fn export_csv<W>(
+        &self,
+        collection: String,
+        writer: &mut W,
+        options: CsvExportOptions,
+    ) -> Result<usize, DatabaseError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let documents = self
+            .find_projected(collection.clone(), options.filter, &options.projection)
+            .await?;
+        let rows: Vec<Vec<(String, String)>> = documents
+            .into_iter()
+            .map(|(id, doc)| csv_row(id, doc))
+            .collect();
+
+        let fields = match options.fields.is_empty() {
+            true => {
+                let mut fields: Vec<String> = Vec::new();
+                for (path, _) in rows.iter().flatten() {
+                    if !fields.contains(path) {
+                        fields.push(path.clone());
+                    }
+                }
+                fields
+            }
+            false => options.fields,
+        };
+
+        let mut out = join_record(fields.iter().map(String::as_str), options.delimiter);
+        for row in &rows {
+            out.push_str(&row_record(&fields, row, options.delimiter));
+            if out.len() >= 64 * 1024 {
+                writer
+                    .write_all(out.as_bytes())
+                    .await
+                    .map_err(DatabaseError::IoError)?;
+                out.clear();
+            }
+        }
+        writer
+            .write_all(out.as_bytes())
+            .await
+            .map_err(DatabaseError::IoError)?;
+        writer.flush().await.map_err(DatabaseError::IoError)?;
+
+        info!(
+            "Exported {} documents from '{}' as CSV",
+            rows.len(),
+            collection
+        );
+        Ok(rows.len())
+    }
+}
+
+/// Reads one record, which quoted line breaks spread over several lines.
+/// Answers the line it starts on, counting from 1, with its fields.
+async fn read_record<R>(
+    reader: &mut R,
+    delimiter: char,
+    line: &mut u64,
+) -> Result<Option<(u64, Vec<String>)>, DatabaseError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let start = *line + 1;
+    let mut text = String::new();
+    loop {
+        let read = reader
+            .read_line(&mut text)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        if read == 0 {
+            return match text.is_empty() {
+                true => Ok(None),
+                false => Err(DatabaseError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: unterminated quoted field", start),
+                ))),
+            };
+        }
+        *line += 1;
+        if let Some(fields) = split_record(&text, delimiter) {
+            return Ok(Some((start, fields)));
+        }
+    }
+}
+
+/// The fields of `text`, or `None` while a quoted one is still open.
+fn split_record(text: &str, delimiter: char) -> Option<Vec<String>> {
+    let text = text
+        .strip_suffix('\n')
+        .map(|text| text.strip_suffix('\r').unwrap_or(text))
+        .unwrap_or(text);
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+/// A document as `Database::export_csv` writes it: its ID under `_id`,
+/// then its fields, embedded ones under dotted paths, each as text.
+pub fn csv_row(id: String, doc: Document) -> Vec<(String, String)> {
+    let mut row = vec![("_id".to_string(), id)];
+    flatten("", doc, &mut row);
+    row
+}
+
+/// `rows` as CSV under a header of `fields`, which pick and order the
+/// cells; a row without one of them has it empty.
+pub fn format_csv(fields: &[String], rows: &[Vec<(String, String)>], delimiter: char) -> String {
+    let mut out = join_record(fields.iter().map(String::as_str), delimiter);
+    for row in rows {
+        out.push_str(&row_record(fields, row, delimiter));
+    }
+    out
+}
+
+fn row_record(fields: &[String], row: &[(String, String)], delimiter: char) -> String {
+    let row: HashMap<_, _> = row.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let cells = fields
+        .iter()
+        .map(|field| row.get(field.as_str()).copied().unwrap_or_default());
+    join_record(cells, delimiter)
+}
+
+fn join_record<'a>(cells: impl Iterator<Item = &'a str>, delimiter: char) -> String {
+    let mut record = String::new();
+    for (i, cell) in cells.enumerate() {
+        if i > 0 {
+            record.push(delimiter);
+        }
+        if cell.contains([delimiter, '"', '\n', '\r']) {
+            record.push('"');
+            record.push_str(&cell.replace('"', "\"\""));
+            record.push('"');
+        } else {
+            record.push_str(cell);
+        }
+    }
+    record.push_str("\r\n");
+    record
+}
+
+/// A record's ID and document.
+fn to_document(
+    header: &[String],
+    cells: Vec<String>,
+    options: &CsvImportOptions,
+) -> Result<(String, Document), String> {
+    if cells.len() > header.len() {
+        return Err(format!(
+            "{} fields, but the header names {}",
+            cells.len(),
+            header.len()
+        ));
+    }
+    let mut id = None;
+    let mut doc = Document::new();
+    for (column, cell) in header.iter().zip(cells) {
+        if cell.is_empty() {
+            continue;
+        }
+        if column == "_id" {
+            id = Some(cell);
+            continue;
+        }
+        let value = match options.types.get(column) {
+            Some(hint) => convert(&cell, *hint)
+                .ok_or_else(|| format!("'{}' isn't a valid {:?}: {}", column, hint, cell))?,
+            None => infer(cell),
+        };
+        set_path(&mut doc, column, value)?;
+    }
+    let id = id.unwrap_or_else(|| bson::oid::ObjectId::new().to_hex());
+    Ok((id, doc))
+}
+
+fn convert(cell: &str, hint: CsvType) -> Option<Bson> {
+    match hint {
+        CsvType::String => Some(Bson::String(cell.to_string())),
+        CsvType::Int => cell.parse::<i64>().ok().map(integer),
+        CsvType::Double => cell.parse().ok().map(Bson::Double),
+        CsvType::Bool => match cell.to_ascii_lowercase().as_str() {
+            "true" => Some(Bson::Boolean(true)),
+            "false" => Some(Bson::Boolean(false)),
+            _ => None,
+        },
+        CsvType::Date => bson::DateTime::parse_rfc3339_str(cell)
+            .ok()
+            .map(Bson::DateTime),
+    }
+}
+
+/// What a cell looks like: an integer, a decimal number, a boolean or else
+/// a string.
+fn infer(cell: String) -> Bson {
+    if let Ok(n) = cell.parse::<i64>() {
+        return integer(n);
+    }
+    let numeric = cell
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'));
+    if numeric {
+        if let Ok(n) = cell.parse() {
+            return Bson::Double(n);
+        }
+    }
+    match cell.as_str() {
+        "true" => Bson::Boolean(true),
+        "false" => Bson::Boolean(false),
+        _ => Bson::String(cell),
+    }
+}
+
+fn integer(n: i64) -> Bson {
+    match i32::try_from(n) {
+        Ok(n) => Bson::Int32(n),
+        Err(_) => Bson::Int64(n),
+    }
+}
+
+fn set_path(doc: &mut Document, path: &str, value: Bson) -> Result<(), String> {
+    match path.split_once('.') {
+        None => {
+            doc.insert(path, value);
+            Ok(())
+        }
+        Some((key, rest)) => {
+            let inner = doc
+                .entry(key.to_string())
+                .or_insert_with(|| Bson::Document(Document::new()));
+            match inner {
+                Bson::Document(inner) => set_path(inner, rest, value),
+                _ => Err(format!("'{}' is both a value and a document", key)),
+            }
+        }
+    }
+}
+
+/// Embedded documents become dotted paths; every other value its text.
+fn flatten(prefix: &str, doc: Document, row: &mut Vec<(String, String)>) {
+    for (key, value) in doc {
+        let path = format!("{}{}", prefix, key);
+        let text = match value {
+            Bson::Document(inner) => {
+                flatten(&format!("{}.", path), inner, row);
+                continue;
+            }
+            Bson::String(s) => s,
+            Bson::Null => String::new(),
+            Bson::ObjectId(id) => id.to_hex(),
+            Bson::DateTime(date) => date
+                .try_to_rfc3339_string()
+                .unwrap_or_else(|_| date.timestamp_millis().to_string()),
+            Bson::Int32(n) => n.to_string(),
+            Bson::Int64(n) => n.to_string(),
+            Bson::Double(n) => n.to_string(),
+            Bson::Boolean(b) => b.to_string(),
+            value => value.into_relaxed_extjson().to_string(),
+        };
+        row.push((path, text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_csv_round_trip() {
+        let db = Database::init_test("data_tests".to_string(), "test_csv".to_string()).await;
+        db.clear().await.unwrap();
+
+        let input = "_id,name,age,zip,address.city,note\r\n\
+                     a,Ada,36,01234,\"Paris, FR\",\"said \"\"hi\"\"\nthen left\"\n\
+                     b,Bob,x,,Lyon,\n\
+                     c,Cy,1,2,3,4,5\n";
+        let options = CsvImportOptions {
+            types: HashMap::from([
+                ("zip".to_string(), CsvType::String),
+                ("age".to_string(), CsvType::Int),
+            ]),
+            ..CsvImportOptions::default()
+        };
+        let report = db
+            .import_csv("people".to_string(), input.as_bytes(), options)
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 1);
+        let failed: Vec<_> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(failed, [4, 5]);
+        assert_eq!(
+            db.find_one("people".to_string(), "a".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! {
+                "name": "Ada",
+                "age": 36,
+                "zip": "01234",
+                "address": { "city": "Paris, FR" },
+                "note": "said \"hi\"\nthen left",
+            })
+        );
+
+        db.insert_one(
+            "people".to_string(),
+            bson::doc! { "name": "Di", "tags": ["x"], "active": true },
+        )
+        .await
+        .unwrap();
+        let mut exported = Vec::new();
+        let options = CsvExportOptions {
+            fields: vec![
+                "name".to_string(),
+                "address.city".to_string(),
+                "tags".to_string(),
+            ],
+            ..CsvExportOptions::default()
+        };
+        db.export_csv("people".to_string(), &mut exported, options)
+            .await
+            .unwrap();
+        let mut lines: Vec<_> = String::from_utf8(exported)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "Ada,\"Paris, FR\",",
+                "Di,,\"[\"\"x\"\"]\"",
+                "name,address.city,tags"
+            ]
+        );
+
+        let mut exported = Vec::new();
+        let options = CsvExportOptions {
+            filter: bson::doc! { "name": "Ada" },
+            projection: Projection::Exclude(vec!["note".to_string()]),
+            ..CsvExportOptions::default()
+        };
+        db.export_csv("people".to_string(), &mut exported, options)
+            .await
+            .unwrap();
+        let exported = String::from_utf8(exported).unwrap();
+        let mut lines = exported.lines();
+        assert_eq!(lines.next(), Some("_id,name,age,zip,address.city"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .ends_with(",Ada,36,01234,\"Paris, FR\""));
+        assert_eq!(lines.next(), None);
+    }
+}
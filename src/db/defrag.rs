@@ -0,0 +1,569 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::sync::{watch, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+
+use super::{Database, DatabaseError};
+
+/// Rough on-disk size of one directory entry, used to estimate how big a
+/// collection directory should be for the documents it currently holds.
+const DIRENT_SIZE_ESTIMATE: u64 = 64;
+
+/// Directories below this size are never worth rewriting.
+const MIN_DIRECTORY_SIZE: u64 = 64 * 1024;
+
+/// The smallest a directory gets on common filesystems: one block.
+const MIN_DIRECTORY_BLOCK: u64 = 4096;
+
+pub(crate) const STAGING_SUFFIX: &str = ".defrag";
+pub(crate) const RETIRED_SUFFIX: &str = ".old";
+
+#[derive(Debug, Clone)]
+pub struct DefragOptions {
+    /// How long the database must go without foreground operations before
+    /// the defragmenter touches anything.
+    pub idle_after: Duration,
+    /// Upper bound on documents relinked per second.
+    pub max_files_per_second: u32,
+    /// Pause between two passes over all collections.
+    pub pass_interval: Duration,
+    /// A collection directory is rewritten once its size exceeds the
+    /// estimate for its entries by this factor.
+    pub min_fragmentation: f64,
+}
+
+impl Default for DefragOptions {
+    fn default() -> Self {
+        Self {
+            idle_after: Duration::from_secs(5),
+            max_files_per_second: 200,
+            pass_interval: Duration::from_secs(60),
+            min_fragmentation: 4.0,
+        }
+    }
+}
+
+/// What rewriting a collection directory would likely give back. File
+/// systems such as ext4 never shrink a directory, so one that once held
+/// many more documents keeps their entries' space until it's rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefragEstimate {
+    pub collection: String,
+    pub entries: u64,
+    /// Of the directory itself, not of the documents in it.
+    pub directory_bytes: u64,
+    pub reclaimable_bytes: u64,
+}
+
+/// How far a rewrite has got, as its progress callback sees it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefragProgress {
+    /// Entries staged in the new directory so far.
+    pub linked: u64,
+    pub entries: u64,
+}
+
+/// Tracks foreground activity and keeps maintenance out of the way of it.
+///
+/// Every foreground operation holds a shared guard for its duration; the
+/// defragmenter only takes the exclusive side for the final directory swap,
+/// and closing the database takes it for good.
+pub(crate) struct Activity {
+    started: Instant,
+    last_op_ms: AtomicU64,
+    maintenance: RwLock<()>,
+    closed: AtomicBool,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_op_ms: AtomicU64::new(0),
+            maintenance: RwLock::new(()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) async fn begin(&self) -> Result<RwLockReadGuard<'_, ()>, DatabaseError> {
+        self.last_op_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        let guard = self.maintenance.read().await;
+        self.check_open()?;
+        Ok(guard)
+    }
+
+    async fn exclusive(&self) -> Result<RwLockWriteGuard<'_, ()>, DatabaseError> {
+        let guard = self.maintenance.write().await;
+        self.check_open()?;
+        Ok(guard)
+    }
+
+    /// Waits for the operations in progress to finish and turns away every
+    /// later one. Gives out the exclusive side to finish closing with, or
+    /// nothing if the database was already closed.
+    pub(crate) async fn close(&self) -> Option<RwLockWriteGuard<'_, ()>> {
+        let guard = self.maintenance.write().await;
+        match self.closed.swap(true, Ordering::AcqRel) {
+            true => None,
+            false => Some(guard),
+        }
+    }
+
+    fn check_open(&self) -> Result<(), DatabaseError> {
+        match self.closed.load(Ordering::Acquire) {
+            true => Err(DatabaseError::Closed),
+            false => Ok(()),
+        }
+    }
+
+    fn idle_for(&self) -> Duration {
+        let now = self.started.elapsed().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.last_op_ms.load(Ordering::Relaxed)))
+    }
+}
+
+/// Handle to a running defragmenter task.
+pub struct DefragHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl DefragHandle {
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Defragmenter task failed: {}", e);
+        }
+    }
+}
+
+impl Database {
+    /// Starts a low-priority task that rewrites bloated collection
+    /// directories while the database is idle.
+    pub fn spawn_defragmenter(&self, options: DefragOptions) -> DefragHandle {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        if self.inner.read_only {
+            warn!(
+                "Not defragmenting read-only database at '{}'",
+                self.inner.folder_path
+            );
+            return DefragHandle {
+                shutdown,
+                task: tokio::spawn(async {}),
+            };
+        }
+
+        let defragmenter = Defragmenter {
+            folder_path: self.inner.folder_path.clone(),
+            activity: self.inner.activity.clone(),
+            options,
+            shutdown: shutdown_rx,
+        };
+
+        let task = tokio::spawn(defragmenter.run());
+
+        DefragHandle { shutdown, task }
+    }
+
+    /// Rewrites a collection directory right away, without waiting for idle
+    /// periods or rate limiting.
+    pub async fn defragment_collection(&self, collection: String) -> Result<(), DatabaseError> {
+        self.defragment_collection_with_progress(collection, |_| {})
+            .await
+    }
+
+    /// Like `defragment_collection`, calling `progress` after each entry
+    /// is staged.
+    pub async fn defragment_collection_with_progress<F>(
+        &self,
+        collection: String,
+        mut progress: F,
+    ) -> Result<(), DatabaseError>
+    where
+        F: FnMut(&DefragProgress) + Send,
+    {
+        self.check_writable()?;
+        let collection_path = self.get_collection_path(&collection)?;
+        rewrite_directory(&collection_path, &self.inner.activity, None, &mut progress).await?;
+
+        info!("Successfully defragmented collection '{}'", collection);
+
+        Ok(())
+    }
+
+    /// How much `defragment_collection` would likely reclaim, without
+    /// touching anything; works on a read-only database too.
+    pub async fn defragment_estimate(
+        &self,
+        collection: String,
+    ) -> Result<DefragEstimate, DatabaseError> {
+        let (entries, directory_bytes) =
+            directory_size(&self.get_collection_path(&collection)?).await?;
+        let expected = (entries * DIRENT_SIZE_ESTIMATE).max(MIN_DIRECTORY_BLOCK);
+
+        Ok(DefragEstimate {
+            collection,
+            entries,
+            directory_bytes,
+            reclaimable_bytes: directory_bytes.saturating_sub(expected),
+        })
+    }
+}
+
+struct Defragmenter {
+    folder_path: String,
+    activity: Arc<Activity>,
+    options: DefragOptions,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Defragmenter {
+    async fn run(mut self) {
+        info!("Defragmenter started for '{}'", self.folder_path);
+
+        loop {
+            tokio::select! {
+                _ = self.shutdown.changed() => break,
+                _ = tokio::time::sleep(self.options.pass_interval) => {}
+            }
+
+            if let Err(e) = self.run_pass().await {
+                error!("Defragmentation pass failed: {:?}", e);
+            }
+
+            if *self.shutdown.borrow() {
+                break;
+            }
+        }
+
+        info!("Defragmenter stopped for '{}'", self.folder_path);
+    }
+
+    async fn run_pass(&mut self) -> Result<(), DatabaseError> {
+        for collection in list_collections(&self.folder_path).await? {
+            if !self.wait_until_idle().await {
+                return Ok(());
+            }
+
+            let path = format!("{}/{}", self.folder_path, collection);
+            if !self.is_fragmented(&path).await? {
+                continue;
+            }
+
+            let delay = Duration::from_secs(1) / self.options.max_files_per_second.max(1);
+            let pacing = Pacing {
+                delay,
+                idle_after: self.options.idle_after,
+                shutdown: self.shutdown.clone(),
+            };
+
+            if rewrite_directory(&path, &self.activity, Some(pacing), &mut |_| {}).await? {
+                info!("Successfully defragmented collection '{}'", collection);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn is_fragmented(&self, path: &str) -> Result<bool, DatabaseError> {
+        let (entries, size) = directory_size(path).await?;
+        if size < MIN_DIRECTORY_SIZE {
+            return Ok(false);
+        }
+
+        let expected = (entries * DIRENT_SIZE_ESTIMATE).max(1);
+
+        Ok(size as f64 / expected as f64 >= self.options.min_fragmentation)
+    }
+
+    async fn wait_until_idle(&self) -> bool {
+        wait_until_idle(&self.activity, self.options.idle_after, &self.shutdown).await
+    }
+}
+
+/// Rate limiting applied while staging a background rewrite.
+struct Pacing {
+    delay: Duration,
+    idle_after: Duration,
+    shutdown: watch::Receiver<bool>,
+}
+
+async fn wait_until_idle(
+    activity: &Activity,
+    idle_after: Duration,
+    shutdown: &watch::Receiver<bool>,
+) -> bool {
+    loop {
+        if *shutdown.borrow() {
+            return false;
+        }
+
+        let idle = activity.idle_for();
+        if idle >= idle_after {
+            return true;
+        }
+
+        tokio::time::sleep(idle_after - idle).await;
+    }
+}
+
+/// The entries of the directory at `path`, and its own size.
+async fn directory_size(path: &str) -> Result<(u64, u64), DatabaseError> {
+    let size = tokio::fs::metadata(path)
+        .await
+        .map_err(DatabaseError::IoError)?
+        .len();
+    Ok((list_entries(path).await?.len() as u64, size))
+}
+
+/// Rebuilds `path` into a fresh directory and swaps it in.
+///
+/// Documents are hard-linked into a staging directory without blocking
+/// foreground work; only the final reconciliation and swap run under the
+/// exclusive maintenance lock. Returns `false` if the rewrite was abandoned.
+async fn rewrite_directory(
+    path: &str,
+    activity: &Activity,
+    pacing: Option<Pacing>,
+    progress: &mut (dyn FnMut(&DefragProgress) + Send),
+) -> Result<bool, DatabaseError> {
+    let staging = format!("{}{}", path, STAGING_SUFFIX);
+    let retired = format!("{}{}", path, RETIRED_SUFFIX);
+
+    remove_dir_if_exists(&staging).await?;
+    tokio::fs::create_dir_all(&staging).await.map_err(|e| {
+        error!("Failed to create directory: {}", e);
+        DatabaseError::IoError(e)
+    })?;
+
+    let names = list_entries(path).await?;
+    let mut state = DefragProgress {
+        linked: 0,
+        entries: names.len() as u64,
+    };
+    for name in names {
+        if let Some(pacing) = &pacing {
+            if !wait_until_idle(activity, pacing.idle_after, &pacing.shutdown).await {
+                remove_dir_if_exists(&staging).await?;
+                return Ok(false);
+            }
+            tokio::time::sleep(pacing.delay).await;
+        }
+
+        link_entry(path, &staging, &name).await?;
+        state.linked += 1;
+        progress(&state);
+    }
+
+    let _guard = activity.exclusive().await?;
+
+    let current = list_entries(path).await?;
+    let staged = list_entries(&staging).await?;
+
+    for name in current.difference(&staged) {
+        link_entry(path, &staging, name).await?;
+    }
+    for name in staged.difference(&current) {
+        remove_file_if_exists(&format!("{}/{}", staging, name)).await?;
+    }
+
+    tokio::fs::rename(path, &retired)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    tokio::fs::rename(&staging, path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    remove_dir_if_exists(&retired).await?;
+
+    Ok(true)
+}
+
+/// Names of the collection directories, leaving out internal ones and
+/// those of an unfinished rewrite.
+pub(crate) async fn list_collections(folder_path: &str) -> Result<Vec<String>, DatabaseError> {
+    let mut entries = tokio::fs::read_dir(folder_path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+
+    let mut collections = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || name.ends_with(STAGING_SUFFIX) || name.ends_with(RETIRED_SUFFIX)
+        {
+            continue;
+        }
+        if entry
+            .file_type()
+            .await
+            .map_err(DatabaseError::IoError)?
+            .is_dir()
+        {
+            collections.push(name);
+        }
+    }
+
+    Ok(collections)
+}
+
+/// Finishes or rolls back a directory swap interrupted by a crash.
+pub(crate) async fn recover(folder_path: &str) -> Result<(), DatabaseError> {
+    let mut entries = match tokio::fs::read_dir(folder_path).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(DatabaseError::IoError(e)),
+    };
+
+    let mut retired = Vec::new();
+    let mut staging = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let path = entry.path().to_string_lossy().to_string();
+        if let Some(base) = path.strip_suffix(RETIRED_SUFFIX) {
+            retired.push(base.to_string());
+        } else if let Some(base) = path.strip_suffix(STAGING_SUFFIX) {
+            staging.push(base.to_string());
+        }
+    }
+
+    for base in retired {
+        let retired_path = format!("{}{}", base, RETIRED_SUFFIX);
+        let staging_path = format!("{}{}", base, STAGING_SUFFIX);
+
+        if tokio::fs::metadata(&base).await.is_err() {
+            // The old directory is only retired after reconciliation, so a
+            // surviving staging directory is complete.
+            let source = if tokio::fs::metadata(&staging_path).await.is_ok() {
+                &staging_path
+            } else {
+                &retired_path
+            };
+            warn!("Recovering interrupted defragmentation of '{}'", base);
+            tokio::fs::rename(source, &base)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+
+        remove_dir_if_exists(&retired_path).await?;
+    }
+
+    for base in staging {
+        remove_dir_if_exists(&format!("{}{}", base, STAGING_SUFFIX)).await?;
+    }
+
+    Ok(())
+}
+
+async fn list_entries(path: &str) -> Result<HashSet<String>, DatabaseError> {
+    let mut names = HashSet::new();
+    let mut entries = tokio::fs::read_dir(path).await.map_err(|e| {
+        error!("Failed to read collection directory: {}", e);
+        DatabaseError::IoError(e)
+    })?;
+
+    while let Some(entry) = entries.next_entry().await.map_err(|e| {
+        error!("Failed to read next entry: {}", e);
+        DatabaseError::IoError(e)
+    })? {
+        names.insert(entry.file_name().to_string_lossy().to_string());
+    }
+
+    Ok(names)
+}
+
+async fn link_entry(from: &str, to: &str, name: &str) -> Result<(), DatabaseError> {
+    match tokio::fs::hard_link(format!("{}/{}", from, name), format!("{}/{}", to, name)).await {
+        Ok(_) => Ok(()),
+        // Deleted or already staged in the meantime; reconciliation settles it.
+        Err(e)
+            if e.kind() == std::io::ErrorKind::NotFound
+                || e.kind() == std::io::ErrorKind::AlreadyExists =>
+        {
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to relink document: {}", e);
+            Err(DatabaseError::IoError(e))
+        }
+    }
+}
+
+async fn remove_file_if_exists(path: &str) -> Result<(), DatabaseError> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(DatabaseError::IoError(e)),
+        _ => Ok(()),
+    }
+}
+
+async fn remove_dir_if_exists(path: &str) -> Result<(), DatabaseError> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(DatabaseError::IoError(e)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_defragment_collection() {
+        let db = Database::init_test("data_tests".to_string(), "test_defragment".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut ids = Vec::new();
+        for i in 0..10 {
+            let id = db
+                .insert_one("users".to_string(), bson::doc! { "n": i })
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+        for id in ids.drain(..5) {
+            db.delete_one("users".to_string(), id).await.unwrap();
+        }
+
+        let estimate = db.defragment_estimate("users".to_string()).await.unwrap();
+        assert_eq!(estimate.entries, 5);
+        let mut linked = 0;
+        db.defragment_collection_with_progress("users".to_string(), |progress| {
+            linked = progress.linked;
+            assert_eq!(progress.entries, 5);
+        })
+        .await
+        .unwrap();
+        assert_eq!(linked, 5);
+
+        let all = db.find("users".to_string(), bson::doc! {}).await.unwrap();
+        assert_eq!(all.len(), 5);
+
+        for id in ids {
+            let doc = db.find_one("users".to_string(), id).await.unwrap();
+            assert!(doc.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_interrupted_swap() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_defrag_recover".to_string()).await;
+        db.clear().await.unwrap();
+
+        let retired = format!("{}/users{}", db.inner.folder_path, RETIRED_SUFFIX);
+        tokio::fs::create_dir_all(&retired).await.unwrap();
+        tokio::fs::write(format!("{}/a.bson", retired), b"")
+            .await
+            .unwrap();
+
+        recover(&db.inner.folder_path).await.unwrap();
+
+        let restored = list_entries(&format!("{}/users", db.inner.folder_path))
+            .await
+            .unwrap();
+        assert!(restored.contains("a.bson"));
+        assert!(tokio::fs::metadata(&retired).await.is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+
+/// Alignment required for `O_DIRECT` buffers, offsets and lengths on the
+/// filesystems we care about.
+#[cfg(target_os = "linux")]
+const ALIGNMENT: usize = 4096;
+
+/// Reads a whole file while bypassing the OS page cache where the platform
+/// and filesystem allow it, falling back to a regular read otherwise.
+pub(crate) async fn read(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
+    let path: PathBuf = path.as_ref().to_path_buf();
+
+    tokio::task::spawn_blocking(move || read_uncached(&path))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+#[cfg(target_os = "linux")]
+fn read_uncached(path: &Path) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Ok(file) => file,
+        // tmpfs and friends reject O_DIRECT outright.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return std::fs::read(path),
+        Err(e) => return Err(e),
+    };
+
+    read_aligned(file)
+}
+
+#[cfg(target_os = "macos")]
+fn read_uncached(path: &Path) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = std::fs::File::open(path)?;
+    // SAFETY: `fcntl` with `F_NOCACHE` only toggles a flag on a descriptor we own.
+    unsafe {
+        libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1);
+    }
+
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_uncached(path: &Path) -> std::io::Result<Vec<u8>> {
+    std::fs::read(path)
+}
+
+/// Reads `file` into an over-allocated buffer, using an aligned window of it
+/// so the kernel accepts the unbuffered transfer.
+#[cfg(target_os = "linux")]
+fn read_aligned(mut file: std::fs::File) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let len = file.metadata()?.len() as usize;
+    let aligned_len = len.div_ceil(ALIGNMENT).max(1) * ALIGNMENT;
+
+    let mut buffer = vec![0u8; aligned_len + ALIGNMENT];
+    let offset = buffer.as_ptr().align_offset(ALIGNMENT);
+    let window = &mut buffer[offset..offset + aligned_len];
+
+    let mut read = 0;
+    while read < aligned_len {
+        match file.read(&mut window[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        // Anything short of a full block means we hit end of file.
+        if read % ALIGNMENT != 0 {
+            break;
+        }
+    }
+
+    Ok(window[..read.min(len)].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_matches_buffered_read() {
+        let dir = "data_tests/test_direct_io";
+        tokio::fs::create_dir_all(dir).await.unwrap();
+
+        let path = format!("{}/doc.bson", dir);
+        let contents: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&path, &contents).await.unwrap();
+
+        let buffer = read(&path).await.unwrap();
+
+        assert_eq!(buffer, contents);
+    }
+}
@@ -0,0 +1,200 @@
+//! Moving collections in and out as MongoDB Extended JSON, one relaxed
+//! document per line, the way `mongoexport` writes and `mongoimport` reads
+//! by default. Each document carries its ID in `_id`.
+
+use bson::{Bson, Document};
+use log::info;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::wal::WalRecord;
+use super::{Database, DatabaseError, Projection};
+
+/// Documents written together while importing.
+const IMPORT_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct JsonExportOptions {
+    /// Only documents matching it, as `find` would.
+    pub filter: Document,
+    pub projection: Projection,
+}
+
+impl Database {
+    /// Writes `collection`'s matching documents to `writer`. Answers how
+    /// many.
+    pub async fn export_json<W>(
+        &self,
+        collection: String,
+        writer: &mut W,
+        options: JsonExportOptions,
+    ) -> Result<usize, DatabaseError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let documents = self
+            .find_projected(collection.clone(), options.filter, &options.projection)
+            .await?;
+        let count = documents.len();
+        for (id, doc) in documents {
+            let mut with_id = bson::doc! { "_id": id };
+            with_id.extend(doc);
+            let mut line = Bson::Document(with_id).into_relaxed_extjson().to_string();
+            line.push('\n');
+            writer
+                .write_all(line.as_bytes())
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+        writer.flush().await.map_err(DatabaseError::IoError)?;
+
+        info!("Exported {} documents from '{}'", count, collection);
+        Ok(count)
+    }
+
+    /// Writes each document read from `reader` to `collection`, replacing
+    /// any with the same `_id`; those without one get a new ID. Bypasses
+    /// hooks and validators, like a restore. Answers how many.
+    pub async fn import_json<R>(
+        &self,
+        collection: String,
+        reader: R,
+    ) -> Result<usize, DatabaseError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        self.check_writable()?;
+        let mut lines = reader.lines();
+        let mut records = Vec::new();
+        let mut count = 0;
+        let mut number = 0;
+        while let Some(line) = lines.next_line().await.map_err(DatabaseError::IoError)? {
+            number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (id, doc) = parse_line(&line).map_err(|reason| {
+                DatabaseError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number, reason),
+                ))
+            })?;
+            records.push(WalRecord::Insert {
+                collection: collection.clone(),
+                id,
+                doc,
+            });
+            if records.len() == IMPORT_BATCH {
+                count += records.len();
+                self.apply_records(std::mem::take(&mut records), None, None)
+                    .await?;
+            }
+        }
+        if !records.is_empty() {
+            count += records.len();
+            self.apply_records(records, None, None).await?;
+        }
+
+        info!("Imported {} documents into '{}'", count, collection);
+        Ok(count)
+    }
+}
+
+/// A document's ID and the rest of its fields.
+pub(super) fn parse_line(line: &str) -> Result<(String, Document), String> {
+    let value: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    parse_value(value)
+}
+
+/// Like `parse_line`, for a JSON value already parsed.
+pub(super) fn parse_value(value: serde_json::Value) -> Result<(String, Document), String> {
+    match Bson::try_from(value).map_err(|e| e.to_string())? {
+        Bson::Document(doc) => take_id(doc),
+        _ => Err("not a JSON object".to_string()),
+    }
+}
+
+/// Splits the `_id` off `doc`, making one up if it has none.
+pub(super) fn take_id(mut doc: Document) -> Result<(String, Document), String> {
+    let id = match doc.remove("_id") {
+        Some(Bson::String(id)) => id,
+        Some(Bson::ObjectId(id)) => id.to_hex(),
+        Some(other) => return Err(format!("unsupported _id {}", other)),
+        None => bson::oid::ObjectId::new().to_hex(),
+    };
+    Ok((id, doc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_import_json() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_export_json".to_string()).await;
+        db.clear().await.unwrap();
+        let born = bson::DateTime::from_millis(1_000_000);
+        let id = db
+            .insert_one(
+                "users".to_string(),
+                bson::doc! { "name": "Ada", "born": born, "tags": ["math"] },
+            )
+            .await
+            .unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(
+            db.export_json(
+                "users".to_string(),
+                &mut exported,
+                JsonExportOptions::default()
+            )
+            .await
+            .unwrap(),
+            1
+        );
+        let mut lines = String::from_utf8(exported).unwrap();
+        assert!(lines.contains("\"$date\""));
+        lines.push_str("\n{\"_id\": {\"$oid\": \"65f000000000000000000001\"}, \"n\": 1}\n");
+
+        assert_eq!(
+            db.import_json("copies".to_string(), lines.as_bytes())
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            db.find_one("copies".to_string(), id).await.unwrap(),
+            Some(bson::doc! { "name": "Ada", "born": born, "tags": ["math"] })
+        );
+        let oid = "65f000000000000000000001".to_string();
+        assert_eq!(
+            db.find_one("copies".to_string(), oid).await.unwrap(),
+            Some(bson::doc! { "n": 1 })
+        );
+        assert!(db
+            .import_json("copies".to_string(), &b"[1, 2]\n"[..])
+            .await
+            .is_err());
+
+        db.insert_one(
+            "users".to_string(),
+            bson::doc! { "name": "Grace", "born": born, "tags": ["navy"] },
+        )
+        .await
+        .unwrap();
+        let options = JsonExportOptions {
+            filter: bson::doc! { "name": "Grace" },
+            projection: Projection::Exclude(vec!["born".to_string()]),
+        };
+        let mut exported = Vec::new();
+        assert_eq!(
+            db.export_json("users".to_string(), &mut exported, options)
+                .await
+                .unwrap(),
+            1
+        );
+        let (_, doc) = parse_line(std::str::from_utf8(&exported).unwrap().trim()).unwrap();
+        assert_eq!(doc, bson::doc! { "name": "Grace", "tags": ["navy"] });
+    }
+}
@@ -0,0 +1,214 @@
+use std::cmp::Ordering;
+
+use bson::{Bson, Document, RawDocument};
+
+use super::DatabaseError;
+
+/// Evaluates `query` directly against raw BSON bytes. Each field must equal
+/// its value in the query or, given a document of operators like
+/// `{"$gt": 25}`, pass all of them: `$eq`, `$ne`, `$gt`, `$gte`, `$lt`,
+/// `$lte`, `$in` and `$nin`. Numbers compare by value whatever their type.
+///
+/// Only the fields named in the query are decoded, so documents that don't
+/// match are never fully deserialized.
+pub(crate) fn matches_raw(doc: &RawDocument, query: &Document) -> Result<bool, DatabaseError> {
+    for (key, condition) in query.iter() {
+        let value = match doc.get(key).map_err(DatabaseError::BsonRawError)? {
+            Some(value) => Some(Bson::try_from(value).map_err(DatabaseError::BsonRawError)?),
+            None => None,
+        };
+
+        if !satisfies(value.as_ref(), condition)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Like `matches_raw`, for a document already decoded. A query with an
+/// unknown operator matches nothing; see `validate`.
+pub(crate) fn matches(doc: &Document, query: &Document) -> bool {
+    query
+        .iter()
+        .all(|(key, condition)| satisfies(doc.get(key), condition).unwrap_or(false))
+}
+
+/// Fails with `DatabaseError::InvalidQuery` if `query` uses an operator
+/// `matches_raw` would refuse, for a filter kept to match documents later.
+pub(crate) fn validate(query: &Document) -> Result<(), DatabaseError> {
+    for condition in query.values() {
+        for (operator, operand) in operators(condition).into_iter().flatten() {
+            check_operator(operator, operand)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `condition` is a document of operators rather than a value the
+/// field must equal.
+pub(crate) fn is_operators(condition: &Bson) -> bool {
+    operators(condition).is_some()
+}
+
+fn operators(condition: &Bson) -> Option<&Document> {
+    match condition {
+        Bson::Document(operators) if operators.keys().any(|key| key.starts_with('$')) => {
+            Some(operators)
+        }
+        _ => None,
+    }
+}
+
+fn check_operator(operator: &str, operand: &Bson) -> Result<(), DatabaseError> {
+    match (operator, operand) {
+        ("$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte", _) | ("$in" | "$nin", Bson::Array(_)) => {
+            Ok(())
+        }
+        ("$in" | "$nin", _) => Err(DatabaseError::InvalidQuery(format!(
+            "'{}' takes an array",
+            operator
+        ))),
+        _ => Err(DatabaseError::InvalidQuery(format!(
+            "unknown query operator '{}'",
+            operator
+        ))),
+    }
+}
+
+/// Whether a field holding `value` (`None` when missing) passes
+/// `condition`.
+fn satisfies(value: Option<&Bson>, condition: &Bson) -> Result<bool, DatabaseError> {
+    let Some(operators) = operators(condition) else {
+        return Ok(value.is_some_and(|value| equals(value, condition)));
+    };
+
+    for (operator, operand) in operators {
+        check_operator(operator, operand)?;
+        let passed = match (operator.as_str(), operand) {
+            ("$eq", _) => value.is_some_and(|value| equals(value, operand)),
+            ("$ne", _) => !value.is_some_and(|value| equals(value, operand)),
+            ("$gt", _) => compare(value, operand) == Some(Ordering::Greater),
+            ("$gte", _) => matches!(
+                compare(value, operand),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            ("$lt", _) => compare(value, operand) == Some(Ordering::Less),
+            ("$lte", _) => matches!(
+                compare(value, operand),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            ("$in" | "$nin", Bson::Array(values)) => {
+                let found =
+                    value.is_some_and(|value| values.iter().any(|option| equals(value, option)));
+                found == (operator == "$in")
+            }
+            _ => unreachable!("checked by check_operator"),
+        };
+        if !passed {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether only documents holding the field can pass `condition`; not so
+/// for `$ne` and `$nin`, which a missing field passes.
+pub(crate) fn requires_field(condition: &Bson) -> bool {
+    match operators(condition) {
+        Some(operators) => !operators
+            .keys()
+            .any(|operator| operator == "$ne" || operator == "$nin"),
+        None => true,
+    }
+}
+
+fn equals(value: &Bson, expected: &Bson) -> bool {
+    match (number(value), number(expected)) {
+        (Some(a), Some(b)) => a == b,
+        _ => value == expected,
+    }
+}
+
+/// How `value` orders against `operand`, if they are of comparable types:
+/// both numbers, strings, dates, booleans or ObjectIds.
+fn compare(value: Option<&Bson>, operand: &Bson) -> Option<Ordering> {
+    match (value?, operand) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.cmp(b)),
+        (a, b) => number(a)?.partial_cmp(&number(b)?),
+    }
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_raw() {
+        let doc = bson::rawdoc! { "name": "John", "age": 30 };
+
+        assert!(matches_raw(&doc, &bson::doc! {}).unwrap());
+        assert!(matches_raw(&doc, &bson::doc! { "name": "John" }).unwrap());
+        assert!(matches_raw(&doc, &bson::doc! { "name": "John", "age": 30 }).unwrap());
+        assert!(!matches_raw(&doc, &bson::doc! { "name": "John", "age": 25 }).unwrap());
+        assert!(!matches_raw(&doc, &bson::doc! { "email": "john@example.com" }).unwrap());
+    }
+
+    #[test]
+    fn test_operators() {
+        let doc = bson::rawdoc! { "name": "John", "age": 30 };
+        let passes = |query: Document| matches_raw(&doc, &query).unwrap();
+
+        assert!(passes(bson::doc! { "age": { "$gt": 25 } }));
+        assert!(passes(
+            bson::doc! { "age": { "$gt": 25.5, "$lte": 30_i64 } }
+        ));
+        assert!(!passes(bson::doc! { "age": { "$lt": 30 } }));
+        assert!(passes(bson::doc! { "age": { "$gte": 30 } }));
+        assert!(passes(bson::doc! { "age": 30.0 }));
+        assert!(passes(bson::doc! { "name": { "$ne": "Jane" } }));
+        assert!(passes(bson::doc! { "name": { "$in": ["Jane", "John"] } }));
+        assert!(!passes(bson::doc! { "name": { "$nin": ["John"] } }));
+        assert!(passes(bson::doc! { "name": { "$gt": "Jane" } }));
+        // Strings and numbers don't compare.
+        assert!(!passes(bson::doc! { "name": { "$gt": 25 } }));
+        // A missing field is unequal to everything, and no greater.
+        assert!(passes(
+            bson::doc! { "email": { "$ne": "john@example.com" } }
+        ));
+        assert!(!passes(bson::doc! { "email": { "$gt": "" } }));
+        assert!(!requires_field(&bson::bson!({ "$nin": ["John"] })));
+        assert!(requires_field(&bson::bson!({ "$gt": 25 })));
+        assert!(is_operators(&bson::bson!({ "$in": [1, 2] })));
+        assert!(!is_operators(&bson::bson!({ "city": "Paris" })));
+        assert!(validate(&bson::doc! { "age": { "$gte": 18 }, "name": "John" }).is_ok());
+
+        for query in [
+            bson::doc! { "age": { "$regex": "3" } },
+            bson::doc! { "name": { "$in": "John" } },
+        ] {
+            assert!(matches!(
+                matches_raw(&doc, &query),
+                Err(DatabaseError::InvalidQuery(_))
+            ));
+            assert!(matches!(
+                validate(&query),
+                Err(DatabaseError::InvalidQuery(_))
+            ));
+        }
+    }
+}
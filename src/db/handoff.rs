@@ -0,0 +1,330 @@
+//! Hinted handoff: a leader holding on to the oplog entries a follower
+//! that went away still needs, so that once back it can catch up from them
+//! even if the oplog was trimmed past where it stopped, rather than copying
+//! everything again. `Database::hold_for` starts holding them, and
+//! `Database::handoff` hands them over.
+//!
+//! Entries are held as trims drop them: the latest
+//! `HandoffOptions::memory_entries` in memory, older ones spilled to a file
+//! per follower in `.hints`. A follower missing more than `max_entries` is
+//! given up on. Hints don't outlive the process.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+use super::oplog::{read_record, OplogCursor, OplogEntry, HEADER_LEN};
+use super::{Database, DatabaseError};
+
+pub(crate) const HINTS_FOLDER: &str = ".hints";
+
+#[derive(Debug, Clone)]
+pub struct HandoffOptions {
+    /// Entries held in memory for each follower; older ones spill to disk.
+    pub memory_entries: usize,
+    /// The most entries held for each follower.
+    pub max_entries: u64,
+}
+
+impl Default for HandoffOptions {
+    fn default() -> Self {
+        Self {
+            memory_entries: 4096,
+            max_entries: 1_000_000,
+        }
+    }
+}
+
+/// The entries held for one follower: every one from `first` on that the
+/// oplog no longer holds.
+pub(crate) struct Hints {
+    path: PathBuf,
+    first: u64,
+    /// Entries in the file, which come before those in memory.
+    spilled: u64,
+    /// Framed records.
+    memory: Vec<Vec<u8>>,
+}
+
+impl Hints {
+    pub(crate) fn new(folder: &Path, follower: &str, first: u64) -> Self {
+        let name = follower.replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_',
+            "_",
+        );
+        Self {
+            path: folder.join(name),
+            first,
+            spilled: 0,
+            memory: Vec::new(),
+        }
+    }
+
+    /// The position of the first entry not held.
+    pub(crate) fn end(&self) -> u64 {
+        self.first + self.spilled + self.memory.len() as u64
+    }
+
+    /// Holds the framed `record` of the entry at `end`, answering false
+    /// once there are too many.
+    pub(crate) async fn push(
+        &mut self,
+        record: &[u8],
+        options: &HandoffOptions,
+    ) -> Result<bool, DatabaseError> {
+        if self.end() - self.first >= options.max_entries {
+            return Ok(false);
+        }
+        self.memory.push(record.to_vec());
+        if self.memory.len() > options.memory_entries {
+            self.spill().await?;
+        }
+        Ok(true)
+    }
+
+    async fn spill(&mut self) -> Result<(), DatabaseError> {
+        if let Some(folder) = self.path.parent() {
+            tokio::fs::create_dir_all(folder)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        file.write_all(&self.memory.concat())
+            .await
+            .map_err(DatabaseError::IoError)?;
+        file.flush().await.map_err(DatabaseError::IoError)?;
+        self.spilled += self.memory.len() as u64;
+        self.memory.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Hints {
+    fn drop(&mut self) {
+        if self.spilled > 0 {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Reads out the entries held for a follower, from some position on.
+pub(crate) struct HintReader {
+    hints: Hints,
+    spilled: Option<BufReader<tokio::fs::File>>,
+    /// The position of the next entry to read.
+    next: u64,
+    /// Entries before this one are skipped.
+    from: u64,
+}
+
+impl HintReader {
+    /// Up to `limit` entries; none once all are read.
+    pub(crate) async fn read(&mut self, limit: usize) -> Result<Vec<OplogEntry>, DatabaseError> {
+        let mut entries = Vec::new();
+        while entries.len() < limit && self.next < self.hints.end() {
+            let spilled = self.next - self.hints.first < self.hints.spilled;
+            let record = match spilled {
+                true => self.read_spilled().await?,
+                false => {
+                    let at = self.next - self.hints.first - self.hints.spilled;
+                    self.hints.memory[at as usize].clone()
+                }
+            };
+            if self.next >= self.from {
+                let (doc, _) = read_record(&record, 0).ok_or_else(|| {
+                    DatabaseError::IoError(std::io::ErrorKind::InvalidData.into())
+                })?;
+                entries.push(OplogEntry::from_document(&doc)?);
+            }
+            self.next += 1;
+        }
+        Ok(entries)
+    }
+
+    async fn read_spilled(&mut self) -> Result<Vec<u8>, DatabaseError> {
+        if self.spilled.is_none() {
+            let file = tokio::fs::File::open(&self.hints.path)
+                .await
+                .map_err(DatabaseError::IoError)?;
+            self.spilled = Some(BufReader::new(file));
+        }
+        let reader = self.spilled.as_mut().unwrap();
+
+        let mut record = vec![0; HEADER_LEN];
+        reader
+            .read_exact(&mut record)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        let len = u32::from_le_bytes(record[..4].try_into().unwrap()) as usize;
+        record.resize(HEADER_LEN + len, 0);
+        reader
+            .read_exact(&mut record[HEADER_LEN..])
+            .await
+            .map_err(DatabaseError::IoError)?;
+        Ok(record)
+    }
+}
+
+impl Database {
+    /// Starts holding the oplog entries after `position`, the last one
+    /// `follower` applied before going away, for `handoff`. Answers false
+    /// if the oplog no longer holds them, or the database doesn't hand
+    /// off.
+    pub async fn hold_for(&self, follower: &str, position: u64) -> Result<bool, DatabaseError> {
+        let oplog = self.oplog()?;
+        if oplog.handoff().is_none() {
+            return Ok(false);
+        }
+        let mut state = oplog.state.lock().await;
+        let next = position + 1;
+        if next < state.first || next > state.next {
+            return Ok(false);
+        }
+        let hints = Hints::new(&oplog.hints_folder(), follower, next);
+        state.hints.insert(follower.to_string(), hints);
+        info!("Holding oplog entries from {} for {}", next, follower);
+        Ok(true)
+    }
+
+    /// Like `oplog_since`, for `follower` coming back: the entries held for
+    /// it come first, if the oplog no longer holds those after `position`.
+    /// Stops holding them either way.
+    pub async fn handoff(
+        &self,
+        follower: &str,
+        position: u64,
+    ) -> Result<OplogCursor, DatabaseError> {
+        let next = position + 1;
+        let hints = {
+            let mut state = self.oplog()?.state.lock().await;
+            let hints = state.hints.remove(follower);
+            if next >= state.first {
+                None
+            } else {
+                match hints {
+                    Some(hints) if hints.first <= next => Some(hints),
+                    _ => return Err(DatabaseError::OplogTruncated(next)),
+                }
+            }
+        };
+
+        let Some(hints) = hints else {
+            return self.oplog_since(position).await;
+        };
+        info!(
+            "Handing off {} held oplog entries to {}",
+            hints.end() - next,
+            follower
+        );
+        let reader = HintReader {
+            next: hints.first,
+            from: next,
+            hints,
+            spilled: None,
+        };
+        Ok(OplogCursor::with_hints(self.clone(), next, reader))
+    }
+}
+
+/// Holds the entries a trim is about to drop, framed in `records` from
+/// position `first` on, for every follower needing them.
+pub(crate) async fn hold_dropped(
+    hints: &mut HashMap<String, Hints>,
+    records: &[u8],
+    first: u64,
+    options: &HandoffOptions,
+) -> Result<(), DatabaseError> {
+    let mut dropped = Vec::new();
+    let mut at = 0;
+    while let Some((_, end)) = read_record(records, at) {
+        dropped.push(&records[at..end]);
+        at = end;
+    }
+
+    let mut given_up = Vec::new();
+    for (follower, held) in hints.iter_mut() {
+        for (position, record) in (first..).zip(&dropped) {
+            if position == held.end() && !held.push(record, options).await? {
+                given_up.push(follower.clone());
+                break;
+            }
+        }
+    }
+    for follower in given_up {
+        warn!(
+            "Stopped holding oplog entries for {}, which missed more than {}",
+            follower, options.max_entries
+        );
+        hints.remove(&follower);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DatabaseOptions, OplogOptions};
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let folder_path = "data_tests/test_handoff".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions {
+                retained_entries: 300,
+                handoff: Some(HandoffOptions {
+                    memory_entries: 100,
+                    max_entries: 200,
+                }),
+                ..OplogOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        for n in 0..100 {
+            db.insert_one("notes".to_string(), bson::doc! { "n": n })
+                .await
+                .unwrap();
+        }
+        assert!(db.hold_for("west", 99).await.unwrap());
+        assert!(db.hold_for("east", 5).await.unwrap());
+        assert!(!db.hold_for("north", 500).await.unwrap());
+
+        // Trims the entries before 257; east misses more than 200.
+        for n in 100..610 {
+            db.insert_one("notes".to_string(), bson::doc! { "n": n })
+                .await
+                .unwrap();
+        }
+        assert_eq!(db.oplog_range().await.unwrap().start, 257);
+        assert!(matches!(
+            db.oplog_since(99).await,
+            Err(DatabaseError::OplogTruncated(100))
+        ));
+        assert!(matches!(
+            db.handoff("east", 5).await,
+            Err(DatabaseError::OplogTruncated(6))
+        ));
+
+        let mut cursor = db.handoff("west", 99).await.unwrap();
+        let mut positions = Vec::new();
+        while cursor.position() < 610 {
+            let batch = cursor.next_batch(64).await.unwrap();
+            positions.extend(batch.iter().map(|entry| entry.position));
+        }
+        assert_eq!(positions, (100..=610).collect::<Vec<_>>());
+        assert!(matches!(
+            db.handoff("west", 99).await,
+            Err(DatabaseError::OplogTruncated(100))
+        ));
+    }
+}
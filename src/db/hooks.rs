@@ -0,0 +1,297 @@
+//! Write triggers: async callbacks registered per collection. Pre-hooks
+//! (`before_insert`, `before_update`) see a document before it is written
+//! and hand back the one to write, or an error to refuse the write.
+//! Post-hooks (`after_insert`, `after_update`, `after_delete`) get the
+//! write's `ChangeEvent` once it is made, for side effects.
+//!
+//! Pre-hooks run for inserts and updates made directly or in transactions,
+//! in the order registered, before the collection is locked, so they may
+//! read it; an update whose document changes meanwhile runs them again on
+//! the new version. An update holds its document's lock throughout, so a
+//! pre-hook mustn't update or delete that same document. Post-hooks run for every write
+//! applied, replicated ones included, each in a task of its own, so they
+//! don't hold the write up and needn't finish in order.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use bson::Document;
+use futures_util::future::BoxFuture;
+
+use super::{ChangeEvent, Database, DatabaseError, OperationType};
+
+type PreHook =
+    Arc<dyn Fn(Document) -> BoxFuture<'static, Result<Document, DatabaseError>> + Send + Sync>;
+type PostHook = Arc<dyn Fn(ChangeEvent) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// The hooks registered on each collection.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pre: RwLock<HashMap<String, Vec<(OperationType, PreHook)>>>,
+    post: RwLock<HashMap<String, Vec<(OperationType, PostHook)>>>,
+}
+
+impl Hooks {
+    fn add_pre(&self, operation: OperationType, collection: &str, hook: PreHook) {
+        let mut pre = self.pre.write().unwrap_or_else(|e| e.into_inner());
+        pre.entry(collection.to_string())
+            .or_default()
+            .push((operation, hook));
+    }
+
+    fn add_post(&self, operation: OperationType, collection: &str, hook: PostHook) {
+        let mut post = self.post.write().unwrap_or_else(|e| e.into_inner());
+        post.entry(collection.to_string())
+            .or_default()
+            .push((operation, hook));
+    }
+
+    /// Passes `doc` through the collection's pre-hooks for `operation`.
+    pub(crate) async fn before(
+        &self,
+        operation: OperationType,
+        collection: &str,
+        mut doc: Document,
+    ) -> Result<Document, DatabaseError> {
+        let hooks: Vec<PreHook> = {
+            let pre = self.pre.read().unwrap_or_else(|e| e.into_inner());
+            let Some(hooks) = pre.get(collection) else {
+                return Ok(doc);
+            };
+            hooks
+                .iter()
+                .filter(|(hooked, _)| *hooked == operation)
+                .map(|(_, hook)| hook.clone())
+                .collect()
+        };
+        for hook in hooks {
+            doc = hook(doc).await?;
+        }
+        Ok(doc)
+    }
+
+    /// Starts the collection's post-hooks for `operation`, if it has any,
+    /// on the event `event` makes.
+    pub(crate) fn after(
+        &self,
+        operation: OperationType,
+        collection: &str,
+        event: impl FnOnce() -> ChangeEvent,
+    ) {
+        let post = self.post.read().unwrap_or_else(|e| e.into_inner());
+        let Some(hooks) = post.get(collection) else {
+            return;
+        };
+        let mut hooks = hooks
+            .iter()
+            .filter(|(hooked, _)| *hooked == operation)
+            .peekable();
+        if hooks.peek().is_none() {
+            return;
+        }
+        let event = event();
+        for (_, hook) in hooks {
+            tokio::spawn(hook(event.clone()));
+        }
+    }
+}
+
+impl Database {
+    /// Passes a document about to be written through the collection's
+    /// pre-hooks, then its validator.
+    pub(crate) async fn before_write(
+        &self,
+        operation: OperationType,
+        collection: &str,
+        doc: Document,
+    ) -> Result<Document, DatabaseError> {
+        let doc = self.inner.hooks.before(operation, collection, doc).await?;
+        self.inner.validators.check(collection, &doc)?;
+        Ok(doc)
+    }
+
+    /// Runs `hook` on each document about to be inserted into `collection`;
+    /// what it answers is inserted instead, and an error fails the insert.
+    pub fn before_insert<F, Fut>(&self, collection: &str, hook: F)
+    where
+        F: Fn(Document) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Document, DatabaseError>> + Send + 'static,
+    {
+        let hook: PreHook = Arc::new(move |doc| Box::pin(hook(doc)));
+        self.inner
+            .hooks
+            .add_pre(OperationType::Insert, collection, hook);
+    }
+
+    /// Like `before_insert`, on each document as an update leaves it.
+    pub fn before_update<F, Fut>(&self, collection: &str, hook: F)
+    where
+        F: Fn(Document) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Document, DatabaseError>> + Send + 'static,
+    {
+        let hook: PreHook = Arc::new(move |doc| Box::pin(hook(doc)));
+        self.inner
+            .hooks
+            .add_pre(OperationType::Update, collection, hook);
+    }
+
+    /// Runs `hook` after each insert into `collection`.
+    pub fn after_insert<F, Fut>(&self, collection: &str, hook: F)
+    where
+        F: Fn(ChangeEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.add_post_hook(OperationType::Insert, collection, hook);
+    }
+
+    /// Runs `hook` after each update in `collection`.
+    pub fn after_update<F, Fut>(&self, collection: &str, hook: F)
+    where
+        F: Fn(ChangeEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.add_post_hook(OperationType::Update, collection, hook);
+    }
+
+    /// Runs `hook` after each delete from `collection`.
+    pub fn after_delete<F, Fut>(&self, collection: &str, hook: F)
+    where
+        F: Fn(ChangeEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.add_post_hook(OperationType::Delete, collection, hook);
+    }
+
+    fn add_post_hook<F, Fut>(&self, operation: OperationType, collection: &str, hook: F)
+    where
+        F: Fn(ChangeEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let hook: PostHook = Arc::new(move |event| Box::pin(hook(event)));
+        self.inner.hooks.add_post(operation, collection, hook);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let db = Database::init_test("data_tests".to_string(), "test_hooks".to_string()).await;
+        db.clear().await.unwrap();
+        db.before_insert("users", |mut doc| async move {
+            if !doc.contains_key("name") {
+                return Err(DatabaseError::InvalidUpdate(
+                    "a user needs a name".to_string(),
+                ));
+            }
+            doc.insert("visits", 0);
+            Ok(doc)
+        });
+        db.before_update("users", |mut doc| async move {
+            let visits = doc.get_i32("visits").unwrap_or_default();
+            doc.insert("regular", visits >= 2);
+            Ok(doc)
+        });
+        let (events, mut received) = mpsc::unbounded_channel();
+        let updates = events.clone();
+        db.after_update("users", move |event| {
+            let updates = updates.clone();
+            async move {
+                let _ = updates.send(event);
+            }
+        });
+        db.after_delete("users", move |event| {
+            let events = events.clone();
+            async move {
+                let _ = events.send(event);
+            }
+        });
+
+        assert!(db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .is_err());
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let mut txn = db.begin_transaction().await;
+        txn.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "visits": 2 } },
+        )
+        .await
+        .unwrap();
+        txn.commit().await.unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            let event = tokio::time::timeout(Duration::from_secs(2), received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            seen.push((event.operation, event.document));
+        }
+        seen.sort_by_key(|(operation, _)| *operation as u8);
+        assert_eq!(
+            seen,
+            [
+                (
+                    OperationType::Update,
+                    Some(bson::doc! { "name": "John", "visits": 2, "regular": true })
+                ),
+                (OperationType::Delete, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_hook_reads_its_collection() {
+        let db = Database::init_test(
+            "data_tests".to_string(),
+            "test_hooks_read_collection".to_string(),
+        )
+        .await;
+        db.clear().await.unwrap();
+        let reader = db.clone();
+        db.before_update("users", move |mut doc| {
+            let reader = reader.clone();
+            async move {
+                let users = reader.find("users".to_string(), bson::doc! {}).await?;
+                doc.insert("users", users.len() as i32);
+                Ok(doc)
+            }
+        });
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let updated = tokio::time::timeout(
+            Duration::from_secs(2),
+            db.update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$set": { "age": 30 } },
+            ),
+        )
+        .await
+        .expect("the hook's read waited for the update's lock");
+        assert!(updated.unwrap());
+        assert_eq!(
+            db.find_one("users".to_string(), id).await.unwrap(),
+            Some(bson::doc! { "name": "John", "age": 30, "users": 1 })
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Bulk loading newline-delimited JSON, such as a `mongoexport` or
+//! `export_json` file many gigabytes long. Lines are read as they come,
+//! each checked against the collection's validator, and written in
+//! batches, several at once while the next ones are read. A line that
+//! isn't a valid document is reported with its number and skipped.
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::task::JoinSet;
+
+use super::export::parse_line;
+use super::wal::WalRecord;
+use super::{Database, DatabaseError};
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// Documents written together.
+    pub batch_size: usize,
+    /// Most batches being written at once.
+    pub parallelism: usize,
+    /// Lines that may fail before the import gives up; `None` for no limit.
+    pub max_errors: Option<usize>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            parallelism: 4,
+            max_errors: Some(1000),
+        }
+    }
+}
+
+/// How far an import has got, as its progress callback sees it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportProgress {
+    pub lines: u64,
+    pub bytes: u64,
+    pub imported: u64,
+    pub failed: u64,
+}
+
+/// A line that couldn't be imported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineError {
+    /// Counting from 1.
+    pub line: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: u64,
+    pub errors: Vec<LineError>,
+}
+
+impl Database {
+    /// Imports every line of `reader` into `collection`, replacing
+    /// documents with the same `_id`; those without one get a new ID.
+    /// `progress` is called after each batch is written. Lines that fail
+    /// are listed in the report, unless more than `max_errors` do: then
+    /// the import stops, keeping the batches already written. Batches may
+    /// land out of order, so of two lines with the same `_id` either wins.
+    pub async fn bulk_import<R, F>(
+        &self,
+        collection: String,
+        reader: R,
+        options: ImportOptions,
+        mut progress: F,
+    ) -> Result<ImportReport, DatabaseError>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(&ImportProgress),
+    {
+        self.check_writable()?;
+        let batch_size = options.batch_size.max(1);
+        let mut lines = BufReader::new(reader).lines();
+        let mut writes = JoinSet::new();
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut state = ImportProgress::default();
+        let mut errors = Vec::new();
+
+        let mut done = false;
+        while !done {
+            match lines.next_line().await.map_err(DatabaseError::IoError)? {
+                Some(line) => {
+                    state.lines += 1;
+                    state.bytes += line.len() as u64 + 1;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let parsed = parse_line(&line).and_then(|(id, doc)| {
+                        match self.inner.validators.check(&collection, &doc) {
+                            Ok(()) => Ok((id, doc)),
+                            Err(DatabaseError::ValidationError(e)) => {
+                                Err(format!("'{}' {}", e.field, e.reason))
+                            }
+                            Err(e) => Err(format!("{:?}", e)),
+                        }
+                    });
+                    match parsed {
+                        Ok((id, doc)) => batch.push(WalRecord::Insert {
+                            collection: collection.clone(),
+                            id,
+                            doc,
+                        }),
+                        Err(reason) => {
+                            state.failed += 1;
+                            errors.push(LineError {
+                                line: state.lines,
+                                reason,
+                            });
+                            if options.max_errors.is_some_and(|max| errors.len() > max) {
+                                return Err(too_many_errors(&errors));
+                            }
+                        }
+                    }
+                    if batch.len() < batch_size {
+                        continue;
+                    }
+                }
+                None => done = true,
+            }
+            if batch.is_empty() {
+                continue;
+            }
+
+            while writes.len() >= options.parallelism.max(1) {
+                state.imported += finish_write(&mut writes).await?;
+                progress(&state);
+            }
+            let db = self.clone();
+            let records = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            writes.spawn(async move {
+                let count = records.len() as u64;
+                db.apply_records(records, None, None).await.map(|()| count)
+            });
+        }
+        while !writes.is_empty() {
+            state.imported += finish_write(&mut writes).await?;
+            progress(&state);
+        }
+
+        Ok(ImportReport {
+            imported: state.imported,
+            errors,
+        })
+    }
+}
+
+/// Waits for a batch to be written. Answers how many documents it held.
+async fn finish_write(
+    writes: &mut JoinSet<Result<u64, DatabaseError>>,
+) -> Result<u64, DatabaseError> {
+    match writes.join_next().await {
+        Some(written) => written.map_err(|e| DatabaseError::IoError(std::io::Error::other(e)))?,
+        None => Ok(0),
+    }
+}
+
+fn too_many_errors(errors: &[LineError]) -> DatabaseError {
+    let last = &errors[errors.len() - 1];
+    DatabaseError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "gave up after {} lines failed, the last, line {}: {}",
+            errors.len(),
+            last.line,
+            last.reason
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ValidationError, Validator};
+
+    #[tokio::test]
+    async fn test_bulk_import() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_bulk_import".to_string()).await;
+        db.clear().await.unwrap();
+        db.set_validator(
+            "items",
+            Validator::custom(|doc| match doc.contains_key("n") {
+                true => Ok(()),
+                false => Err(ValidationError::new("n", "is required")),
+            }),
+        )
+        .unwrap();
+
+        let mut input = String::new();
+        for n in 0..25 {
+            input.push_str(&format!("{{\"_id\": \"item{}\", \"n\": {}}}\n", n, n));
+        }
+        input.push_str("{\"name\": \"no n\"}\n\nnot json\n");
+
+        let mut seen = Vec::new();
+        let options = ImportOptions {
+            batch_size: 10,
+            parallelism: 2,
+            ..ImportOptions::default()
+        };
+        let report = db
+            .bulk_import("items".to_string(), input.as_bytes(), options, |state| {
+                seen.push(state.imported)
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(report.imported, 25);
+        let failed: Vec<_> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(failed, [26, 28]);
+        assert_eq!(seen.last(), Some(&25));
+        assert_eq!(
+            db.find("items".to_string(), bson::doc! {})
+                .await
+                .unwrap()
+                .len(),
+            25
+        );
+        assert_eq!(
+            db.find_one("items".to_string(), "item7".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "n": 7 })
+        );
+
+        let options = ImportOptions {
+            max_errors: Some(0),
+            ..ImportOptions::default()
+        };
+        let failed = db
+            .bulk_import("items".to_string(), &b"oops\n"[..], options, |_| {})
+            .await;
+        assert!(failed.is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::{File, TryLockError};
+
+use log::error;
+
+use super::DatabaseError;
+
+/// Name of the lock file inside the database folder.
+pub(crate) const LOCK_FILE: &str = ".lock";
+
+/// Advisory lock keeping a second process from opening the same folder.
+///
+/// The lock belongs to the open file, so the OS drops it when the process
+/// exits, even if it crashes; there is never a stale lock to clean up.
+/// Taken with `flock` on unix and `LockFileEx` on Windows; platforms with
+/// neither refuse to open the database rather than risk two writers.
+pub(crate) struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    pub(crate) fn acquire(folder_path: &str) -> Result<Self, DatabaseError> {
+        let path = format!("{}/{}", folder_path, LOCK_FILE);
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| {
+                error!("Failed to open lock file: {}", e);
+                DatabaseError::IoError(e)
+            })?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => {
+                error!("Database at '{}' is already open elsewhere", folder_path);
+                Err(DatabaseError::AlreadyLocked(path))
+            }
+            Err(TryLockError::Error(e)) => {
+                error!("Failed to lock database directory: {}", e);
+                Err(DatabaseError::IoError(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_fails() {
+        let dir = "data_tests/test_lock_file";
+        std::fs::create_dir_all(dir).unwrap();
+
+        let lock = LockFile::acquire(dir).unwrap();
+        assert!(matches!(
+            LockFile::acquire(dir),
+            Err(DatabaseError::AlreadyLocked(_))
+        ));
+
+        drop(lock);
+        assert!(LockFile::acquire(dir).is_ok());
+    }
+}
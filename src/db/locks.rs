@@ -0,0 +1,266 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::warn;
+use tokio::sync::{Notify, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+use tokio::time::Instant;
+
+use super::DatabaseError;
+
+type Key = (String, String);
+
+/// One read-write lock per collection: writers to different collections
+/// never wait on each other, and scans of a collection share it.
+pub(crate) struct CollectionLocks {
+    locks: Mutex<HashMap<String, Arc<RwLock<()>>>>,
+}
+
+impl CollectionLocks {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock_for(&self, collection: &str) -> Arc<RwLock<()>> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(collection.to_string()).or_default().clone()
+    }
+
+    pub(crate) async fn read(&self, collection: &str) -> OwnedRwLockReadGuard<()> {
+        self.lock_for(collection).read_owned().await
+    }
+
+    pub(crate) async fn write(&self, collection: &str) -> OwnedRwLockWriteGuard<()> {
+        self.lock_for(collection).write_owned().await
+    }
+
+    /// Locks several collections for writing. They are always taken in name
+    /// order, so two multi-collection writers can't deadlock each other.
+    pub(crate) async fn write_all<'a>(
+        &self,
+        collections: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<OwnedRwLockWriteGuard<()>> {
+        let collections: BTreeSet<&str> = collections.into_iter().collect();
+
+        let mut guards = Vec::with_capacity(collections.len());
+        for collection in collections {
+            guards.push(self.write(collection).await);
+        }
+        guards
+    }
+}
+
+/// Exclusive per-document locks, held by transactions until they finish.
+///
+/// Every owner waits on at most one lock at a time, so the wait-for graph is
+/// a set of chains; a request that would close a cycle fails right away
+/// instead of hanging until its timeout.
+pub(crate) struct DocumentLocks {
+    table: Mutex<LockTable>,
+    released: Notify,
+    next_owner: AtomicU64,
+}
+
+#[derive(Default)]
+struct LockTable {
+    holders: HashMap<Key, u64>,
+    held: HashMap<u64, Vec<Key>>,
+    /// Owner -> owner it is waiting on.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    fn closes_cycle(&self, owner: u64) -> bool {
+        let mut visited = HashSet::new();
+        let mut current = owner;
+        while let Some(&next) = self.waits_for.get(&current) {
+            if next == owner {
+                return true;
+            }
+            if !visited.insert(next) {
+                break;
+            }
+            current = next;
+        }
+        false
+    }
+}
+
+impl DocumentLocks {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            table: Mutex::new(LockTable::default()),
+            released: Notify::new(),
+            next_owner: AtomicU64::new(1),
+        })
+    }
+
+    fn lock_table(&self) -> MutexGuard<'_, LockTable> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn new_owner(&self) -> u64 {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Locks a document for `owner`, waiting at most `timeout` for another
+    /// owner to let go of it.
+    pub(crate) async fn acquire(
+        &self,
+        owner: u64,
+        collection: &str,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<(), DatabaseError> {
+        let key = (collection.to_string(), id.to_string());
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let notified = self.released.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut table = self.lock_table();
+                match table.holders.get(&key).copied() {
+                    Some(holder) if holder != owner => {
+                        table.waits_for.insert(owner, holder);
+                        if table.closes_cycle(owner) {
+                            table.waits_for.remove(&owner);
+                            warn!("Deadlock on '{}' document '{}'", collection, id);
+                            return Err(DatabaseError::Deadlock(format!("{}/{}", collection, id)));
+                        }
+                    }
+                    Some(_) => {
+                        table.waits_for.remove(&owner);
+                        return Ok(());
+                    }
+                    None => {
+                        table.holders.insert(key.clone(), owner);
+                        table.held.entry(owner).or_default().push(key);
+                        table.waits_for.remove(&owner);
+                        return Ok(());
+                    }
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                self.lock_table().waits_for.remove(&owner);
+                warn!("Timed out locking '{}' document '{}'", collection, id);
+                return Err(DatabaseError::LockTimeout(format!("{}/{}", collection, id)));
+            }
+        }
+    }
+
+    pub(crate) fn release_all(&self, owner: u64) {
+        let mut table = self.lock_table();
+        table.waits_for.remove(&owner);
+        if let Some(keys) = table.held.remove(&owner) {
+            for key in keys {
+                table.holders.remove(&key);
+            }
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Locks a single document for the duration of one operation.
+    pub(crate) async fn lock(
+        self: &Arc<Self>,
+        collection: &str,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<DocumentLockGuard, DatabaseError> {
+        let owner = self.new_owner();
+        self.acquire(owner, collection, id, timeout).await?;
+
+        Ok(DocumentLockGuard {
+            locks: self.clone(),
+            owner,
+        })
+    }
+}
+
+pub(crate) struct DocumentLockGuard {
+    locks: Arc<DocumentLocks>,
+    owner: u64,
+}
+
+impl Drop for DocumentLockGuard {
+    fn drop(&mut self) {
+        self.locks.release_all(self.owner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collections_lock_independently() {
+        let locks = CollectionLocks::new();
+
+        let _users = locks.write("users").await;
+        let orders = tokio::time::timeout(Duration::from_secs(1), locks.write("orders")).await;
+        assert!(orders.is_ok());
+
+        let blocked = tokio::time::timeout(Duration::from_millis(50), locks.read("users")).await;
+        assert!(blocked.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_document_lock_times_out() {
+        let locks = DocumentLocks::new();
+        let _held = locks
+            .lock("users", "a", Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            locks.lock("users", "a", Duration::from_millis(20)).await,
+            Err(DatabaseError::LockTimeout(_))
+        ));
+        assert!(locks
+            .lock("users", "b", Duration::from_millis(20))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deadlock_is_detected() {
+        let locks = DocumentLocks::new();
+        let first = locks.new_owner();
+        let second = locks.new_owner();
+        let timeout = Duration::from_secs(5);
+
+        locks.acquire(first, "users", "a", timeout).await.unwrap();
+        locks.acquire(second, "users", "b", timeout).await.unwrap();
+
+        let waiting = {
+            let locks = locks.clone();
+            tokio::spawn(async move { locks.acquire(first, "users", "b", timeout).await })
+        };
+        while !locks.lock_table().waits_for.contains_key(&first) {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            locks.acquire(second, "users", "a", timeout).await,
+            Err(DatabaseError::Deadlock(_))
+        ));
+
+        locks.release_all(second);
+        assert!(waiting.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_readers_share_a_collection() {
+        let locks = CollectionLocks::new();
+
+        let _first = locks.read("users").await;
+        let second = tokio::time::timeout(Duration::from_secs(1), locks.read("users")).await;
+        assert!(second.is_ok());
+    }
+}
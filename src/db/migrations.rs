@@ -0,0 +1,441 @@
+//! Schema migrations: numbered steps that rewrite a collection's documents,
+//! each one run once. Which version each collection is at is kept in the
+//! `_migrations` collection, one document per collection, so `migrate`
+//! can be called with every migration on each start and only runs the new
+//! ones.
+//!
+//! A step is an update pipeline, update documents applied in turn as
+//! `update_one` would, or a closure, which can also delete a document. The
+//! pending migrations of a collection are worked out in memory first and
+//! then written, with the new version, as one atomic batch: a failing step
+//! leaves the collection as it was. Writes made to the collection while it
+//! migrates may be overwritten, so migrate before serving it. Like imports,
+//! migrations bypass hooks and validators.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use bson::Document;
+use log::info;
+
+use super::filter::matches;
+use super::update::apply_update;
+use super::wal::WalRecord;
+use super::{Database, DatabaseError};
+
+/// Where the version each collection has migrated to is kept.
+pub const MIGRATIONS_COLLECTION: &str = "_migrations";
+
+/// Answers the document rewritten, or `None` to delete it.
+pub type MigrateFn = Arc<dyn Fn(Document) -> Result<Option<Document>, String> + Send + Sync>;
+
+/// How a migration rewrites each document.
+#[derive(Clone)]
+pub enum MigrationStep {
+    /// Update documents, applied in turn.
+    Pipeline(Vec<Document>),
+    Custom(MigrateFn),
+}
+
+impl MigrationStep {
+    pub fn custom(
+        step: impl Fn(Document) -> Result<Option<Document>, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom(Arc::new(step))
+    }
+
+    fn run(&self, doc: Document) -> Result<Option<Document>, String> {
+        match self {
+            MigrationStep::Pipeline(updates) => updates
+                .iter()
+                .try_fold(doc, |doc, update| apply_update(&doc, update))
+                .map(Some)
+                .map_err(|e| format!("{:?}", e)),
+            MigrationStep::Custom(step) => step(doc),
+        }
+    }
+}
+
+impl fmt::Debug for MigrationStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationStep::Pipeline(updates) => f.debug_tuple("Pipeline").field(updates).finish(),
+            MigrationStep::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub collection: String,
+    /// Migrations of a collection run in the order of their versions, each
+    /// from the one before; the first is usually 1.
+    pub version: u64,
+    pub description: String,
+    /// Only documents matching it are rewritten, as `find` would match.
+    pub filter: Document,
+    pub up: MigrationStep,
+    /// Undoes `up`, for `Database::rollback_migrations`.
+    pub down: Option<MigrationStep>,
+}
+
+impl Migration {
+    pub fn new(collection: &str, version: u64, description: &str, up: MigrationStep) -> Self {
+        Self {
+            collection: collection.to_string(),
+            version,
+            description: description.to_string(),
+            filter: Document::new(),
+            up,
+            down: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrationOptions {
+    /// Work out what would change, writing nothing.
+    pub dry_run: bool,
+}
+
+/// A migration run, or rolled back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationRun {
+    pub collection: String,
+    pub version: u64,
+    /// Documents rewritten.
+    pub modified: u64,
+    pub deleted: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationReport {
+    /// In the order they ran; on a dry run, the ones that would have.
+    pub runs: Vec<MigrationRun>,
+    pub dry_run: bool,
+}
+
+impl Database {
+    /// The version `collection` has migrated to; 0 before any migration.
+    pub async fn migration_version(&self, collection: &str) -> Result<u64, DatabaseError> {
+        let found = match self
+            .find_one(MIGRATIONS_COLLECTION.to_string(), collection.to_string())
+            .await
+        {
+            Ok(found) => found,
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(found
+            .and_then(|doc| doc.get_i64("version").ok())
+            .map_or(0, |version| version as u64))
+    }
+
+    /// Runs the migrations each collection hasn't had yet, in the order of
+    /// their versions, and notes the version reached.
+    pub async fn migrate(
+        &self,
+        migrations: &[Migration],
+        options: MigrationOptions,
+    ) -> Result<MigrationReport, DatabaseError> {
+        self.check_writable()?;
+        let mut report = MigrationReport {
+            dry_run: options.dry_run,
+            ..MigrationReport::default()
+        };
+        for (collection, mut pending) in by_collection(migrations) {
+            let current = self.migration_version(collection).await?;
+            pending.retain(|migration| migration.version > current);
+            pending.sort_by_key(|migration| migration.version);
+            let steps: Vec<_> = pending
+                .iter()
+                .map(|migration| (migration.version, &migration.filter, &migration.up))
+                .collect();
+            if let Some(&(last, _, _)) = steps.last() {
+                let runs = self
+                    .run_steps(collection, &steps, last, options.dry_run)
+                    .await?;
+                report.runs.extend(runs);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Undoes `collection`'s migrations past `version`, newest first, with
+    /// their `down` steps. Fails before changing anything if one of them
+    /// has none or isn't among `migrations`.
+    pub async fn rollback_migrations(
+        &self,
+        migrations: &[Migration],
+        collection: &str,
+        version: u64,
+        options: MigrationOptions,
+    ) -> Result<MigrationReport, DatabaseError> {
+        self.check_writable()?;
+        let current = self.migration_version(collection).await?;
+        let mut steps = Vec::new();
+        for applied in (version + 1..=current).rev() {
+            let down = migrations
+                .iter()
+                .find(|migration| {
+                    migration.collection == collection && migration.version == applied
+                })
+                .and_then(|migration| Some((applied, &migration.filter, migration.down.as_ref()?)));
+            match down {
+                Some(step) => steps.push(step),
+                None => {
+                    return Err(DatabaseError::MigrationFailed(format!(
+                        "'{}' version {} can't be rolled back",
+                        collection, applied
+                    )))
+                }
+            }
+        }
+
+        let runs = match steps.is_empty() {
+            true => Vec::new(),
+            false => {
+                self.run_steps(collection, &steps, version, options.dry_run)
+                    .await?
+            }
+        };
+        Ok(MigrationReport {
+            runs,
+            dry_run: options.dry_run,
+        })
+    }
+
+    /// Runs `steps` over `collection`'s documents in memory, then writes
+    /// those they changed and `version` as one batch.
+    async fn run_steps(
+        &self,
+        collection: &str,
+        steps: &[(u64, &Document, &MigrationStep)],
+        version: u64,
+        dry_run: bool,
+    ) -> Result<Vec<MigrationRun>, DatabaseError> {
+        let mut documents: BTreeMap<String, Option<Document>> = match self
+            .find_with_ids(collection.to_string(), Document::new())
+            .await
+        {
+            Ok(found) => found.into_iter().map(|(id, doc)| (id, Some(doc))).collect(),
+            // Nothing written to the collection yet.
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let mut changed = Vec::new();
+        let mut runs = Vec::with_capacity(steps.len());
+        for &(step_version, filter, step) in steps {
+            let mut run = MigrationRun {
+                collection: collection.to_string(),
+                version: step_version,
+                modified: 0,
+                deleted: 0,
+            };
+            for (id, slot) in documents.iter_mut() {
+                let Some(doc) = slot.take_if(|doc| matches(doc, filter)) else {
+                    continue;
+                };
+                let before = doc.clone();
+                *slot = step.run(doc).map_err(|reason| {
+                    DatabaseError::MigrationFailed(format!(
+                        "'{}' version {} failed on '{}': {}",
+                        collection, step_version, id, reason
+                    ))
+                })?;
+                match slot {
+                    Some(doc) if *doc == before => continue,
+                    Some(_) => run.modified += 1,
+                    None => run.deleted += 1,
+                }
+                changed.push(id.clone());
+            }
+            runs.push(run);
+        }
+        if dry_run {
+            return Ok(runs);
+        }
+
+        changed.sort();
+        changed.dedup();
+        let mut records: Vec<WalRecord> = changed
+            .into_iter()
+            .map(|id| match documents.remove(&id).flatten() {
+                Some(doc) => WalRecord::Insert {
+                    collection: collection.to_string(),
+                    id,
+                    doc,
+                },
+                None => WalRecord::Delete {
+                    collection: collection.to_string(),
+                    id,
+                },
+            })
+            .collect();
+        records.push(WalRecord::Insert {
+            collection: MIGRATIONS_COLLECTION.to_string(),
+            id: collection.to_string(),
+            doc: bson::doc! {
+                "version": version as i64,
+                "migrated_at": bson::DateTime::now(),
+            },
+        });
+        self.apply_records(records, None, None).await?;
+
+        info!(
+            "Migrated '{}' to version {} ({} documents rewritten, {} deleted)",
+            collection,
+            version,
+            runs.iter().map(|run| run.modified).sum::<u64>(),
+            runs.iter().map(|run| run.deleted).sum::<u64>()
+        );
+        Ok(runs)
+    }
+}
+
+/// `migrations` grouped by collection, collections in order.
+fn by_collection(migrations: &[Migration]) -> BTreeMap<&str, Vec<&Migration>> {
+    let mut collections: BTreeMap<&str, Vec<&Migration>> = BTreeMap::new();
+    for migration in migrations {
+        collections
+            .entry(migration.collection.as_str())
+            .or_default()
+            .push(migration);
+    }
+    collections
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_migrations() {
+        let db = Database::init_test("data_tests".to_string(), "test_migrations".to_string()).await;
+        db.clear().await.unwrap();
+        for (id, doc) in [
+            ("ada", bson::doc! { "name": "Ada Lovelace", "kind": "user" }),
+            ("bot", bson::doc! { "name": "crawler", "kind": "bot" }),
+        ] {
+            db.put("users".to_string(), id.to_string(), doc)
+                .await
+                .unwrap();
+        }
+
+        let mut split = Migration::new(
+            "users",
+            1,
+            "split names",
+            MigrationStep::custom(|mut doc| {
+                let name = doc.get_str("name").map_err(|e| e.to_string())?.to_string();
+                let (first, last) = name.split_once(' ').unwrap_or((&name, ""));
+                doc.insert("first", first);
+                doc.insert("last", last);
+                doc.remove("name");
+                Ok(Some(doc))
+            }),
+        );
+        split.down = Some(MigrationStep::custom(|mut doc| {
+            let first = doc.remove("first").unwrap_or(Bson::Null);
+            let last = doc.remove("last").unwrap_or(Bson::Null);
+            let name = format!(
+                "{} {}",
+                first.as_str().unwrap_or(""),
+                last.as_str().unwrap_or("")
+            );
+            doc.insert("name", name.trim());
+            Ok(Some(doc))
+        }));
+        let mut drop_bots =
+            Migration::new("users", 2, "drop bots", MigrationStep::custom(|_| Ok(None)));
+        drop_bots.filter = bson::doc! { "kind": "bot" };
+        let mut flag = Migration::new(
+            "users",
+            3,
+            "flag users",
+            MigrationStep::Pipeline(vec![bson::doc! { "$set": { "active": true } }]),
+        );
+        flag.down = Some(MigrationStep::Pipeline(vec![
+            bson::doc! { "$unset": { "active": "" } },
+        ]));
+        let migrations = vec![flag, split, drop_bots];
+
+        let dry_run = MigrationOptions { dry_run: true };
+        let report = db.migrate(&migrations, dry_run.clone()).await.unwrap();
+        assert_eq!(
+            report
+                .runs
+                .iter()
+                .map(|run| (run.version, run.modified, run.deleted))
+                .collect::<Vec<_>>(),
+            [(1, 2, 0), (2, 0, 1), (3, 1, 0)]
+        );
+        assert_eq!(db.migration_version("users").await.unwrap(), 0);
+        assert_eq!(
+            db.find_one("users".to_string(), "bot".to_string())
+                .await
+                .unwrap()
+                .unwrap()
+                .get_str("name"),
+            Ok("crawler")
+        );
+
+        let applied = db
+            .migrate(&migrations, MigrationOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(applied.runs, report.runs);
+        assert_eq!(db.migration_version("users").await.unwrap(), 3);
+        assert_eq!(
+            db.find_one("users".to_string(), "ada".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "kind": "user", "first": "Ada", "last": "Lovelace", "active": true })
+        );
+        assert_eq!(
+            db.find_one("users".to_string(), "bot".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        let again = db
+            .migrate(&migrations, MigrationOptions::default())
+            .await
+            .unwrap();
+        assert!(again.runs.is_empty());
+
+        // Version 2 has no way back.
+        assert!(matches!(
+            db.rollback_migrations(&migrations, "users", 0, MigrationOptions::default())
+                .await,
+            Err(DatabaseError::MigrationFailed(_))
+        ));
+        db.rollback_migrations(&migrations, "users", 2, MigrationOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(db.migration_version("users").await.unwrap(), 2);
+        assert_eq!(
+            db.find_one("users".to_string(), "ada".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "kind": "user", "first": "Ada", "last": "Lovelace" })
+        );
+
+        let failing = vec![Migration::new(
+            "users",
+            3,
+            "fail",
+            MigrationStep::custom(|_| Err("no".to_string())),
+        )];
+        assert!(matches!(
+            db.migrate(&failing, MigrationOptions::default()).await,
+            Err(DatabaseError::MigrationFailed(_))
+        ));
+        assert_eq!(db.migration_version("users").await.unwrap(), 2);
+    }
+}
@@ -1,118 +1,1013 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
 use log::{error, info};
+use tokio::sync::broadcast;
+
+mod advisory;
+mod archive;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod backup;
+mod backup_schedule;
+mod batch;
+mod cache;
+mod changes;
+mod codec;
+mod collection;
+#[cfg(any(feature = "parquet", feature = "arrow"))]
+mod columnar;
+mod coordinator;
+mod copy;
+mod csv;
+mod defrag;
+mod direct_io;
+mod export;
+pub(crate) mod filter;
+mod handoff;
+mod hooks;
+mod import;
+mod lock_file;
+mod locks;
+mod migrations;
+mod model;
+mod multi_primary;
+mod mvcc;
+#[cfg(feature = "object_store")]
+mod object_store;
+mod oplog;
+mod outbox;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod projection;
+mod retry;
+mod seed;
+mod session;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod sync;
+mod transaction;
+mod ttl;
+mod update;
+mod validation;
+mod verify;
+mod versioning;
+mod wal;
+mod write_buffer;
+
+#[cfg(feature = "parquet")]
+pub use self::parquet::{ParquetColumn, ParquetExportOptions, ParquetType};
+pub use advisory::{AdvisoryLock, AdvisoryLockOptions};
+#[cfg(feature = "arrow")]
+pub use arrow::{ArrowOptions, RecordBatchStream};
+pub use backup::BackupInfo;
+pub use backup_schedule::{
+    BackupSchedule, BackupScheduleHandle, BackupScheduleOptions, BackupStats, RetentionPolicy,
+};
+pub use batch::{BatchResult, WriteOp};
+pub use changes::{ChangeEvent, ChangeStream, FieldChange, OperationType};
+pub use codec::{Codec, CollectionOptions};
+pub use collection::Collection;
+pub use coordinator::WriteCoordinatorOptions;
+pub use copy::{copy_collection, CopyOptions};
+pub use csv::{csv_row, format_csv, CsvExportOptions, CsvImportOptions, CsvType};
+pub use defrag::{DefragEstimate, DefragHandle, DefragOptions, DefragProgress};
+pub use export::JsonExportOptions;
+pub use handoff::HandoffOptions;
+pub use import::{ImportOptions, ImportProgress, ImportReport, LineError};
+pub use migrations::{
+    MigrateFn, Migration, MigrationOptions, MigrationReport, MigrationRun, MigrationStep,
+    MIGRATIONS_COLLECTION,
+};
+pub use model::{field_filter, OwlDocument};
+pub use multi_primary::CONFLICTS_COLLECTION;
+pub use mvcc::Snapshot;
+pub use oplog::{OplogCursor, OplogEntry, OplogOptions, WriteStamp};
+pub use outbox::{OutboxEvent, OutboxOptions, OutboxRelay, OUTBOX_COLLECTION};
+#[cfg(feature = "derive")]
+pub use owldb_derive::OwlDocument;
+pub use projection::Projection;
+#[cfg(feature = "client")]
+pub(crate) use retry::with_retry_if;
+pub use retry::{with_retry, RetryOptions};
+pub use seed::SeedOptions;
+pub use session::{Session, SessionOptions};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteImportOptions;
+pub use stats::{
+    CollectionStats, DatabaseStats, DocumentSize, FollowerLag, OplogStats, LARGEST_DOCUMENTS,
+};
+pub use sync::{
+    Conflict, ConflictResolver, MergeFn, SyncDirection, SyncFilter, SyncOptions, SyncResult,
+};
+pub use transaction::{IsolationLevel, Transaction, TransactionLimits, TransactionOptions};
+pub use ttl::{TtlHandle, TtlRule};
+pub use validation::{ValidationError, Validator};
+pub use verify::{verify, Problem, VerifyOptions, VerifyReport};
+pub use versioning::{Revision, VersioningOptions};
+pub use write_buffer::WriteBufferOptions;
+
+use advisory::AdvisoryLocks;
+use cache::DocumentCache;
+use changes::{Change, CHANGE_BUFFER};
+use codec::Codecs;
+use coordinator::WriteCoordinator;
+use defrag::Activity;
+use hooks::Hooks;
+use lock_file::{LockFile, LOCK_FILE};
+use locks::{CollectionLocks, DocumentLocks};
+use mvcc::VersionStore;
+use oplog::Oplog;
+use ttl::TtlRules;
+use validation::Validators;
+use versioning::VersionHistory;
+use wal::{LogSequence, WalRecord};
+use write_buffer::WriteBuffer;
+
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub enum DatabaseError {
     IoError(std::io::Error),
     BsonDeError(bson::de::Error),
     BsonSerError(bson::ser::Error),
+    BsonRawError(bson::raw::Error),
+    WalCorrupted(String),
+    InvalidUpdate(String),
+    SavepointNotFound(String),
+    /// Another process has the database folder open.
+    AlreadyLocked(String),
+    /// A write was attempted through a read-only handle, or to a database
+    /// following a leader.
+    ReadOnly,
+    /// The database was closed with `Database::close`.
+    Closed,
+    TransactionInProgress,
+    NoTransaction,
+    /// Gave up waiting for a document another transaction holds.
+    LockTimeout(String),
+    /// Waiting for the document would have deadlocked; the requesting
+    /// transaction was aborted and can be retried.
+    Deadlock(String),
+    /// The document changed after the transaction's snapshot was taken; the
+    /// transaction was aborted and can be retried.
+    WriteConflict(String),
+    /// A serializable transaction read something that changed before it
+    /// could commit; nothing was written and it can be retried.
+    SerializationFailure(String),
+    /// The transaction went over one of its `TransactionLimits` and was
+    /// aborted.
+    TransactionLimitExceeded(String),
+    TransactionAborted,
+    /// The advisory lock's lease ran out and another holder took it.
+    AdvisoryLockLost(String),
+    VersioningDisabled,
+    /// The database keeps no oplog; see `DatabaseOptions::oplog`.
+    OplogDisabled,
+    /// The oplog no longer holds this position, or hasn't reached it; the
+    /// consumer has to copy the data again and read on from there.
+    OplogTruncated(u64),
+    /// A backup is missing, malformed, or can't be restored as asked.
+    InvalidBackup(String),
+    /// The request carried no valid credentials.
+    Unauthenticated,
+    /// The authenticated user isn't allowed to do this.
+    PermissionDenied(String),
+    /// The requested time is older than the version retention window.
+    VersionPruned,
+    /// The document has no revision with this number, or no longer.
+    RevisionNotFound(u64),
+    /// The server couldn't be reached, or the connection dropped mid-request.
+    Unavailable(String),
+    /// The server failed the request; the message is its description.
+    ServerError(String),
+    /// The client went over its request rate; it may try again after the
+    /// given time.
+    RateLimited(Duration),
+    /// The server hosts no database by this name.
+    DatabaseNotFound(String),
+    /// The database has used up its disk quota; it only accepts deletes
+    /// until it shrinks back under.
+    QuotaExceeded(String),
+    /// The server's configuration is malformed or contradicts itself.
+    InvalidConfig(String),
+    /// No open cursor has this ID; it ran out, was killed or sat idle too
+    /// long.
+    CursorNotFound(i64),
+    /// Compressed data is malformed, or decompresses to too much.
+    InvalidCompression(String),
+    /// A change stream fell behind and missed this many writes.
+    ChangesMissed(u64),
+    /// The document doesn't satisfy its collection's validator.
+    ValidationError(ValidationError),
+    /// A migration step failed, or a migration can't be rolled back.
+    MigrationFailed(String),
+    /// The document under this ID doesn't fit the type it was read as.
+    SchemaMismatch(String, bson::de::Error),
+    /// `Database::create_collection` was asked for a collection that
+    /// already exists.
+    CollectionExists(String),
+    /// A document couldn't be encoded or decoded with its collection's
+    /// codec.
+    CodecError(String),
+    /// A collection name or document ID that can't be used as a file name;
+    /// see `is_valid_name`.
+    InvalidName(String),
+    /// `Database::insert_one_with_id` found a document under the ID.
+    DocumentExists(String),
+    /// A query uses an operator that doesn't exist, or misuses one.
+    InvalidQuery(String),
 }
 
+/// Whether `name` may name a collection or a document. Collections are
+/// directories of the database folder and documents files in them, so a
+/// name can't be empty, contain `/`, `\` or NUL, or start with `.`; that
+/// rules out `.` and `..`, and leaves such names to the database's own
+/// files, like `.wal`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', '\0'])
+}
+
+/// Fails with `DatabaseError::InvalidName` unless `is_valid_name`.
+pub(crate) fn check_name(name: &str) -> Result<(), DatabaseError> {
+    match is_valid_name(name) {
+        true => Ok(()),
+        false => Err(DatabaseError::InvalidName(name.to_string())),
+    }
+}
+
+/// How durable a write must be before the call returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteConcern {
+    /// Visible to readers, but possibly still only in the OS page cache.
+    #[default]
+    Acked,
+    /// Flushed to disk, so it survives a crash. Writes through the write
+    /// buffer are always journaled in the write-ahead log.
+    Journaled,
+}
+
+/// Which state of the database a read observes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadConcern {
+    /// Whatever is on disk right now, including writes whose commit is still
+    /// in progress. Skips the version history, so it is the cheapest.
+    Local,
+    /// A consistent snapshot taken when the read starts, including every
+    /// write that had returned by then. Waits for commits still in
+    /// progress ahead of those writes.
+    #[default]
+    Snapshot,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub write_concern: WriteConcern,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FindOptions {
+    /// Read documents bypassing the OS page cache, so large scans don't
+    /// evict the pages serving latency-sensitive point reads.
+    pub direct_io: bool,
+    /// Read as of this snapshot instead of the latest committed state.
+    /// Overrides `read_concern`.
+    pub snapshot: Option<Snapshot>,
+    pub read_concern: ReadConcern,
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    /// Number of recently read documents kept in memory; `0` disables the
+    /// cache.
+    pub cache_capacity: usize,
+    /// Buffer inserts in memory (made durable through the write-ahead log)
+    /// and write them to document files in the background.
+    pub write_buffer: Option<WriteBufferOptions>,
+    /// Refuse writes, and leave the folder alone otherwise: no exclusive
+    /// lock, recovery or log replay, so another process can keep owning it.
+    pub read_only: bool,
+    /// How long a write waits for a document locked by a transaction.
+    pub lock_timeout: Duration,
+    /// Keep past versions of documents for `Database::find_one_at`.
+    pub versioning: Option<VersioningOptions>,
+    /// Hand single-document writes to one writer task per collection, which
+    /// commits whatever has queued up as one batch.
+    pub write_coordinator: Option<WriteCoordinatorOptions>,
+    /// Limits every transaction is held to, unless it is begun with others.
+    pub transaction_limits: TransactionLimits,
+    /// Number every write in an operation log, for replication; see
+    /// `Database::read_oplog`. Read-only handles keep none. Writes then go
+    /// through the write-ahead log with their entries, bypassing any
+    /// `write_buffer`.
+    pub oplog: Option<OplogOptions>,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        Self {
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            write_buffer: None,
+            read_only: false,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            versioning: None,
+            write_coordinator: None,
+            transaction_limits: TransactionLimits::default(),
+            oplog: None,
+        }
+    }
+}
+
+type Index = HashMap<String, HashMap<String, Vec<String>>>; // colección -> campo -> [IDs]
+
+/// Handle to a database folder. Cloning is cheap and every clone shares the
+/// same state, so one database can be handed to many tasks.
+#[derive(Clone)]
 pub struct Database {
+    inner: Arc<DatabaseInner>,
+}
+
+struct DatabaseInner {
     folder_path: String,
-    index: HashMap<String, HashMap<String, Vec<String>>>, // colección -> campo -> [IDs]
+    index: RwLock<Index>,
+    activity: Arc<Activity>,
+    locks: Arc<CollectionLocks>,
+    document_locks: Arc<DocumentLocks>,
+    lock_timeout: Duration,
+    transaction_limits: TransactionLimits,
+    cache: DocumentCache,
+    read_only: bool,
+    /// Only takes replicated writes; see `Database::set_follower`.
+    follower: AtomicBool,
+    write_buffer: Option<Arc<WriteBuffer>>,
+    wal_sequence: Arc<LogSequence>,
+    versions: Arc<VersionStore>,
+    history: Option<VersionHistory>,
+    coordinator: Option<WriteCoordinator>,
+    advisory_locks: AdvisoryLocks,
+    changes: broadcast::Sender<ChangeEvent>,
+    hooks: Hooks,
+    validators: Validators,
+    ttl: TtlRules,
+    codecs: Codecs,
+    oplog: Option<Oplog>,
+    /// Set once a backup scheduler is spawned.
+    backup_stats: std::sync::Mutex<Option<BackupStats>>,
+    /// Released by `Database::close`, or when the last handle is dropped.
+    lock_file: std::sync::Mutex<Option<LockFile>>,
+}
+
+impl Drop for DatabaseInner {
+    fn drop(&mut self) {
+        // Before the lock file goes, so nothing writes to the folder after.
+        if let Some(write_buffer) = &self.write_buffer {
+            write_buffer.stop();
+        }
+    }
 }
 
 impl Database {
     pub async fn init(folder_path: String) -> Result<Self, DatabaseError> {
+        Self::init_with_options(folder_path, DatabaseOptions::default()).await
+    }
+
+    pub async fn init_with_options(
+        folder_path: String,
+        options: DatabaseOptions,
+    ) -> Result<Self, DatabaseError> {
         info!(
             "Successfully initialized database at directory: {}",
             folder_path
         );
 
-        let index = HashMap::new();
-        let db = Self { folder_path, index };
-        db.create_path_dirs(&db.folder_path).await?;
+        if options.read_only {
+            return Ok(Self::new(
+                folder_path,
+                &options,
+                Arc::new(LogSequence::new(1)),
+                None,
+                None,
+                None,
+            ));
+        }
+
+        Self::create_path_dirs(&folder_path).await?;
+        let lock_file = LockFile::acquire(&folder_path)?;
+        defrag::recover(&folder_path).await?;
+        let logged = wal::replay(&folder_path).await?;
+        let wal_sequence = Arc::new(LogSequence::new(wal::next_sequence(&folder_path).await?));
+
+        let write_buffer = match options.write_buffer.clone() {
+            Some(write_buffer) => Some(
+                WriteBuffer::start(folder_path.clone(), write_buffer, wal_sequence.clone()).await?,
+            ),
+            None => None,
+        };
+
+        let oplog = match options.oplog.clone() {
+            Some(oplog) => {
+                let oplog = Oplog::open(&folder_path, oplog).await?;
+                oplog.recover(logged).await?;
+                Some(oplog)
+            }
+            None => None,
+        };
+
+        let db = Self::new(
+            folder_path,
+            &options,
+            wal_sequence,
+            write_buffer,
+            oplog,
+            Some(lock_file),
+        );
+        db.inner.advisory_locks.load().await?;
 
         Ok(db)
     }
 
+    /// Opens a folder for reading only, e.g. for reports against a live
+    /// database. Writes fail with `DatabaseError::ReadOnly`; the folder is
+    /// not locked, and writes still sitting in the owner's write-ahead log
+    /// show up once the owner flushes them.
+    pub async fn open_read_only(folder_path: String) -> Result<Self, DatabaseError> {
+        let options = DatabaseOptions {
+            read_only: true,
+            ..DatabaseOptions::default()
+        };
+        Self::init_with_options(folder_path, options).await
+    }
+
     #[cfg(test)]
     async fn init_test(folder_path: String, id: String) -> Self {
-        let db = Self {
-            folder_path: format!("{}/{}", folder_path, id),
-            index: HashMap::new(),
-        };
-        db.create_path_dirs(&db.folder_path).await.unwrap();
-        db
+        let folder_path = format!("{}/{}", folder_path, id);
+        Self::create_path_dirs(&folder_path).await.unwrap();
+        Self::new(
+            folder_path,
+            &DatabaseOptions::default(),
+            Arc::new(LogSequence::new(1)),
+            None,
+            None,
+            None,
+        )
+    }
+
+    fn new(
+        folder_path: String,
+        options: &DatabaseOptions,
+        wal_sequence: Arc<LogSequence>,
+        write_buffer: Option<Arc<WriteBuffer>>,
+        oplog: Option<Oplog>,
+        lock_file: Option<LockFile>,
+    ) -> Self {
+        let history = options
+            .versioning
+            .clone()
+            .map(|versioning| VersionHistory::new(&folder_path, versioning));
+
+        let advisory_locks = AdvisoryLocks::new(&folder_path);
+        let activity = Arc::new(Activity::new());
+        let locks = Arc::new(CollectionLocks::new());
+        if let Some(write_buffer) = &write_buffer {
+            write_buffer.spawn_flusher(activity.clone(), locks.clone());
+        }
+
+        Self {
+            inner: Arc::new(DatabaseInner {
+                folder_path,
+                index: RwLock::new(HashMap::new()),
+                activity,
+                locks,
+                document_locks: DocumentLocks::new(),
+                lock_timeout: options.lock_timeout,
+                transaction_limits: options.transaction_limits.clone(),
+                // Nothing would invalidate entries when the owning process
+                // writes, so a read-only handle always goes to the files.
+                cache: DocumentCache::new(match options.read_only {
+                    true => 0,
+                    false => options.cache_capacity,
+                }),
+                read_only: options.read_only,
+                follower: AtomicBool::new(false),
+                write_buffer,
+                wal_sequence,
+                versions: VersionStore::new(),
+                history,
+                coordinator: options.write_coordinator.clone().map(WriteCoordinator::new),
+                advisory_locks,
+                changes: broadcast::channel(CHANGE_BUFFER).0,
+                hooks: Hooks::default(),
+                validators: Validators::default(),
+                ttl: TtlRules::default(),
+                codecs: Codecs::default(),
+                oplog,
+                backup_stats: std::sync::Mutex::new(None),
+                lock_file: std::sync::Mutex::new(lock_file),
+            }),
+        }
     }
 
     pub async fn clear(&self) -> Result<(), DatabaseError> {
-        tokio::fs::remove_dir_all(&self.folder_path)
+        self.check_writable()?;
+        let _guard = self.inner.activity.begin().await?;
+
+        self.inner.cache.clear();
+        self.inner.versions.clear();
+        self.inner.codecs.clear();
+
+        Self::create_path_dirs(&self.inner.folder_path).await?;
+
+        // Everything goes but the lock file, which this handle keeps holding.
+        let mut entries = tokio::fs::read_dir(&self.inner.folder_path)
             .await
             .map_err(|e| {
                 error!("Error removing database directory: {}", e);
                 DatabaseError::IoError(e)
             })?;
 
-        self.create_path_dirs(&self.folder_path).await?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| {
+            error!("Failed to read next entry: {}", e);
+            DatabaseError::IoError(e)
+        })? {
+            if entry.file_name() == LOCK_FILE {
+                continue;
+            }
+
+            let path = entry.path();
+            let result = if path.is_dir() {
+                tokio::fs::remove_dir_all(&path).await
+            } else {
+                tokio::fs::remove_file(&path).await
+            };
+            result.map_err(|e| {
+                error!("Error removing database directory: {}", e);
+                DatabaseError::IoError(e)
+            })?;
+        }
+
+        if let Some(write_buffer) = &self.inner.write_buffer {
+            write_buffer.reset().await?;
+        }
+        if let Some(oplog) = &self.inner.oplog {
+            oplog.reset().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Pins the current committed state for consistent reads through
+    /// `FindOptions::snapshot`, regardless of writes that happen later.
+    pub fn snapshot(&self) -> Snapshot {
+        self.inner.versions.snapshot()
+    }
+
+    /// Writes out any inserts still held by the write buffer.
+    pub async fn flush(&self) -> Result<(), DatabaseError> {
+        match &self.inner.write_buffer {
+            Some(write_buffer) => write_buffer.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Shuts the database down cleanly: waits for the operations in
+    /// progress, writes out the write buffer and releases the folder, so
+    /// another process can open it right away. Every clone of the handle is
+    /// closed; later operations fail with `DatabaseError::Closed`. Closing
+    /// again does nothing.
+    pub async fn close(&self) -> Result<(), DatabaseError> {
+        let Some(_guard) = self.inner.activity.close().await else {
+            return Ok(());
+        };
+
+        if let Some(write_buffer) = &self.inner.write_buffer {
+            write_buffer.stop();
+        }
+        let flushed = self.flush().await;
+        // Even if the flush failed: what it couldn't write is still in the
+        // write-ahead log, for the next process to replay.
+        self.inner.lock_file.lock().unwrap().take();
+        flushed?;
+
+        info!(
+            "Successfully closed database at directory: {}",
+            self.inner.folder_path
+        );
 
         Ok(())
     }
 
-    pub fn add_index(&mut self, collection: String, field: String) {
-        if let Some(field_index) = self.index.get_mut(&collection) {
-            if !field_index.contains_key(&field) {
-                field_index.insert(field, Vec::new());
-            }
+    pub fn add_index(&self, collection: String, field: String) {
+        let mut index = self.write_index();
+        if let Some(field_index) = index.get_mut(&collection) {
+            field_index.entry(field).or_default();
         } else {
             let mut field_index = HashMap::new();
             field_index.insert(field, Vec::new());
-            self.index.insert(collection, field_index);
+            index.insert(collection, field_index);
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), DatabaseError> {
+        if self.inner.read_only || self.inner.follower.load(Ordering::Acquire) {
+            error!(
+                "Refusing to write to read-only database at '{}'",
+                self.inner.folder_path
+            );
+            return Err(DatabaseError::ReadOnly);
         }
+        Ok(())
+    }
+
+    fn read_index(&self) -> RwLockReadGuard<'_, Index> {
+        self.inner.index.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_index(&self) -> RwLockWriteGuard<'_, Index> {
+        self.inner.index.write().unwrap_or_else(|e| e.into_inner())
     }
 
     pub async fn insert_one(
-        &mut self,
+        &self,
         collection: String,
         doc: bson::Document,
     ) -> Result<String, DatabaseError> {
+        self.insert_one_with_options(collection, doc, WriteOptions::default())
+            .await
+    }
+
+    pub async fn insert_one_with_options(
+        &self,
+        collection: String,
+        doc: bson::Document,
+        options: WriteOptions,
+    ) -> Result<String, DatabaseError> {
+        self.check_writable()?;
+        check_name(&collection)?;
+        if let Some(coordinator) = &self.inner.coordinator {
+            return coordinator.insert(self, collection, doc).await;
+        }
+        let doc = self
+            .before_write(OperationType::Insert, &collection, doc)
+            .await?;
+        let _guard = self.inner.activity.begin().await?;
+        let _lock = self.inner.locks.write(&collection).await;
+
         let id = bson::oid::ObjectId::new().to_string();
-        let collection_path = self.get_collection_path(&collection);
-        let full_path = self.get_document_path(&collection, &id);
 
-        let mut buffer = Vec::new();
-        doc.to_writer(&mut buffer)
-            .map_err(|e| DatabaseError::BsonSerError(e))?;
+        let commit = self.inner.versions.begin_commit();
+        commit.record(&collection, &id, None);
+        let change = Change {
+            operation: OperationType::Insert,
+            collection: &collection,
+            id: &id,
+            previous: None,
+            document: Some(&doc),
+        };
+        self.store_change(change, options.write_concern).await?;
+
+        info!(
+            "Successfully inserted document into '{}' with ID: '{}'",
+            collection, id
+        );
+
+        Ok(id)
+    }
+
+    /// Inserts `doc` under `id`, failing with `DocumentExists` if there is
+    /// a document there already. Hooks and validators run as for
+    /// `insert_one`; a client that picks the ID up front can retry the
+    /// insert without writing the document twice.
+    pub async fn insert_one_with_id(
+        &self,
+        collection: String,
+        id: String,
+        doc: bson::Document,
+    ) -> Result<(), DatabaseError> {
+        self.check_writable()?;
+        check_name(&collection)?;
+        check_name(&id)?;
+
+        let mut transaction = self.begin_transaction().await;
+        transaction.insert_one_with_id(collection, id, doc).await?;
+        transaction.commit().await
+    }
+
+    /// Writes `doc` under `id`, replacing any document there. For copying
+    /// documents whose IDs must survive, such as between shards.
+    pub async fn put(
+        &self,
+        collection: String,
+        id: String,
+        doc: bson::Document,
+    ) -> Result<(), DatabaseError> {
+        self.check_writable()?;
+        let record = WalRecord::Insert {
+            collection,
+            id,
+            doc,
+        };
+        self.apply_records(vec![record], None, None).await
+    }
+
+    /// Applies `update` (operators like `$set`, or a replacement document)
+    /// to a document. Returns `false` if there is no such document.
+    pub async fn update_one(
+        &self,
+        collection: String,
+        id: String,
+        update: bson::Document,
+    ) -> Result<bool, DatabaseError> {
+        self.update_one_with_options(collection, id, update, WriteOptions::default())
+            .await
+    }
+
+    pub async fn update_one_with_options(
+        &self,
+        collection: String,
+        id: String,
+        update: bson::Document,
+        options: WriteOptions,
+    ) -> Result<bool, DatabaseError> {
+        self.check_writable()?;
+        check_name(&collection)?;
+        check_name(&id)?;
+        if let Some(coordinator) = &self.inner.coordinator {
+            return coordinator.update(self, collection, id, update).await;
+        }
+        let _document_lock = self
+            .inner
+            .document_locks
+            .lock(&collection, &id, self.inner.lock_timeout)
+            .await?;
+
+        let (doc, updated, _guard, _lock) = loop {
+            let doc = {
+                let _guard = self.inner.activity.begin().await?;
+                self.read_document(&collection, &id, &FindOptions::default())
+                    .await?
+            };
+            let Some(doc) = doc else {
+                return Ok(false);
+            };
+            let updated = update::apply_update(&doc, &update)?;
+            // Before locking the collection, so the hooks may read it.
+            let updated = self
+                .before_write(OperationType::Update, &collection, updated)
+                .await?;
+
+            let guard = self.inner.activity.begin().await?;
+            let lock = self.inner.locks.write(&collection).await;
+            let current = self
+                .read_document(&collection, &id, &FindOptions::default())
+                .await?;
+            // Writers that don't take document locks, such as deletes by
+            // query, may have got in while the hooks ran; start over from
+            // what they left.
+            if current.as_ref() == Some(&doc) {
+                break (doc, updated, guard, lock);
+            }
+        };
+
+        let commit = self.inner.versions.begin_commit();
+        commit.record(&collection, &id, Some(doc.clone()));
+        let change = Change {
+            operation: OperationType::Update,
+            collection: &collection,
+            id: &id,
+            previous: Some(&doc),
+            document: Some(&updated),
+        };
+        self.store_change(change, options.write_concern).await?;
+
+        info!(
+            "Successfully updated document in '{}' with ID: '{}'",
+            collection, id
+        );
 
-        self.create_path_dirs(&collection_path).await?;
+        Ok(true)
+    }
+
+    /// Stores the document an insert or update leaves, under the caller's
+    /// collection lock and commit, and publishes the change. With an oplog
+    /// it goes through the write-ahead log, for its entry to be durable
+    /// along with it.
+    async fn store_change(
+        &self,
+        change: Change<'_>,
+        write_concern: WriteConcern,
+    ) -> Result<(), DatabaseError> {
+        let (collection, id) = (change.collection, change.id);
+        let doc = change
+            .document
+            .expect("inserts and updates leave a document");
+        if self.inner.oplog.is_some() {
+            let record = WalRecord::Insert {
+                collection: collection.to_string(),
+                id: id.to_string(),
+                doc: doc.clone(),
+            };
+            return self.apply_logged(&[record], &[change], None).await;
+        }
+
+        self.store_document(collection, id, doc, write_concern)
+            .await?;
+        self.inner.cache.invalidate(collection, id);
+        self.index_document(collection, id, doc);
+        self.record_version(collection, id, Some(doc)).await?;
+        self.publish_change(&change, None);
+        Ok(())
+    }
+
+    async fn store_document(
+        &self,
+        collection: &str,
+        id: &str,
+        doc: &bson::Document,
+        write_concern: WriteConcern,
+    ) -> Result<(), DatabaseError> {
+        if let Some(write_buffer) = &self.inner.write_buffer {
+            check_name(collection)?;
+            check_name(id)?;
+            return write_buffer.insert(collection, id, doc.clone()).await;
+        }
+
+        let codec = self.codec(collection).await?;
+        let collection_path = self.get_collection_path(collection)?;
+        let full_path = self.get_document_path(collection, id, codec)?;
+        let buffer = codec.encode(doc)?;
+
+        Self::create_path_dirs(&collection_path).await?;
+
+        if write_concern == WriteConcern::Journaled {
+            wal::write_synced(&full_path, &buffer).await?;
+            return wal::sync_directory(&collection_path).await;
+        }
 
         tokio::fs::write(&full_path, &buffer).await.map_err(|e| {
             error!("Failed to write document: {}", e);
             DatabaseError::IoError(e)
-        })?;
+        })
+    }
 
-        if let Some(field_index) = self.index.get_mut(&collection) {
+    fn index_document(&self, collection: &str, id: &str, doc: &bson::Document) {
+        if let Some(field_index) = self.write_index().get_mut(collection) {
             for (field, _) in doc.iter() {
                 if let Some(ids) = field_index.get_mut(field) {
-                    ids.push(id.clone());
+                    ids.push(id.to_string());
                 } else {
-                    field_index.insert(field.clone(), vec![id.clone()]);
+                    field_index.insert(field.clone(), vec![id.to_string()]);
                 }
             }
         }
+    }
 
-        info!(
-            "Successfully inserted document into '{}' with ID: '{}'",
-            collection, id
-        );
+    pub async fn find_one(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<bson::Document>, DatabaseError> {
+        self.find_one_with_options(collection, id, FindOptions::default())
+            .await
+    }
 
-        Ok(id)
+    pub async fn find_one_with_options(
+        &self,
+        collection: String,
+        id: String,
+        options: FindOptions,
+    ) -> Result<Option<bson::Document>, DatabaseError> {
+        match self
+            .find_one_raw_with_options(collection, id, options)
+            .await?
+        {
+            Some(raw) => Ok(Some(
+                raw.to_document().map_err(DatabaseError::BsonRawError)?,
+            )),
+            None => Ok(None),
+        }
     }
 
-    pub async fn find_one(
+    /// Like `find_one`, but hands back the stored bytes without decoding them.
+    pub async fn find_one_raw(
         &self,
         collection: String,
         id: String,
+    ) -> Result<Option<bson::RawDocumentBuf>, DatabaseError> {
+        self.find_one_raw_with_options(collection, id, FindOptions::default())
+            .await
+    }
+
+    pub async fn find_one_raw_with_options(
+        &self,
+        collection: String,
+        id: String,
+        options: FindOptions,
+    ) -> Result<Option<bson::RawDocumentBuf>, DatabaseError> {
+        check_name(&collection)?;
+        check_name(&id)?;
+        let _guard = self.inner.activity.begin().await?;
+
+        let snapshot = self.snapshot_for(&options).await;
+        self.read_visible(
+            &collection,
+            &id,
+            &options,
+            snapshot.as_ref().map(Snapshot::timestamp),
+        )
+        .await
+    }
+
+    /// The snapshot a read should see; `None` for `ReadConcern::Local`.
+    /// Must be taken before any collection lock, since it may wait on
+    /// writers holding one.
+    async fn snapshot_for(&self, options: &FindOptions) -> Option<Snapshot> {
+        match (&options.snapshot, options.read_concern) {
+            (Some(snapshot), _) => Some(snapshot.clone()),
+            (None, ReadConcern::Snapshot) => Some(self.inner.versions.snapshot_latest().await),
+            (None, ReadConcern::Local) => None,
+        }
+    }
+
+    /// Reads a document as it was at `timestamp`, or as it is now.
+    async fn read_visible(
+        &self,
+        collection: &str,
+        id: &str,
+        options: &FindOptions,
+        timestamp: Option<u64>,
+    ) -> Result<Option<bson::RawDocumentBuf>, DatabaseError> {
+        // The current state must be read before consulting the history: a
+        // writer records the prior image before it touches the file.
+        let current = self.read_raw_document(collection, id, options).await?;
+        self.visible_at(collection, id, current, timestamp)
+    }
+
+    /// What a reader at `timestamp` sees of a document whose current state
+    /// is `current`; that state itself without a timestamp.
+    fn visible_at(
+        &self,
+        collection: &str,
+        id: &str,
+        current: Option<bson::RawDocumentBuf>,
+        timestamp: Option<u64>,
+    ) -> Result<Option<bson::RawDocumentBuf>, DatabaseError> {
+        let prior = timestamp.and_then(|ts| self.inner.versions.visible(ts, collection, id));
+        match prior {
+            Some(prior) => prior
+                .map(|doc| bson::RawDocumentBuf::from_document(&doc))
+                .transpose()
+                .map_err(DatabaseError::BsonRawError),
+            None => Ok(current),
+        }
+    }
+
+    async fn read_document(
+        &self,
+        collection: &str,
+        id: &str,
+        options: &FindOptions,
     ) -> Result<Option<bson::Document>, DatabaseError> {
-        let path = self.get_document_path(&collection, &id);
+        match self.read_raw_document(collection, id, options).await? {
+            Some(raw) => Ok(Some(
+                raw.to_document().map_err(DatabaseError::BsonRawError)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_raw_document(
+        &self,
+        collection: &str,
+        id: &str,
+        options: &FindOptions,
+    ) -> Result<Option<bson::RawDocumentBuf>, DatabaseError> {
+        if let Some(write_buffer) = &self.inner.write_buffer {
+            if let Some(doc) = write_buffer.get(collection, id).await {
+                let raw = bson::RawDocumentBuf::from_document(&doc)
+                    .map_err(DatabaseError::BsonRawError)?;
+                return Ok(Some(raw));
+            }
+        }
 
-        match tokio::fs::read(&path).await {
+        if let Some(raw) = self.inner.cache.get(collection, id) {
+            return Ok(Some(raw));
+        }
+
+        let epoch = self.inner.cache.epoch();
+        let codec = self.codec(collection).await?;
+        let path = self.get_document_path(collection, id, codec)?;
+
+        match Self::read_file(&path, options).await {
             Ok(buffer) => {
-                let doc = bson::Document::from_reader(&buffer[..])
-                    .map_err(|e| DatabaseError::BsonDeError(e))?;
-                Ok(Some(doc))
+                let raw = codec.decode(buffer)?;
+                if !options.direct_io {
+                    self.inner.cache.put(collection, id, raw.clone(), epoch);
+                }
+                Ok(Some(raw))
             }
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
             Err(e) => {
@@ -122,19 +1017,107 @@ impl Database {
         }
     }
 
+    /// The documents whose fields match `query`: equal to its values, or
+    /// passing comparisons like `{"age": {"$gt": 25}}`.
     pub async fn find(
         &self,
         collection: String,
         query: bson::Document,
     ) -> Result<Vec<bson::Document>, DatabaseError> {
-        let collection_path = self.get_collection_path(&collection);
+        self.find_with_options(collection, query, FindOptions::default())
+            .await
+    }
+
+    pub async fn find_with_options(
+        &self,
+        collection: String,
+        query: bson::Document,
+        options: FindOptions,
+    ) -> Result<Vec<bson::Document>, DatabaseError> {
+        let _guard = self.inner.activity.begin().await?;
+
+        self.scan_raw(&collection, &query, &options)
+            .await?
+            .iter()
+            .map(|(_, raw)| raw.to_document().map_err(DatabaseError::BsonRawError))
+            .collect()
+    }
+
+    /// Like `find`, but returns matches as raw BSON so callers that forward
+    /// them (e.g. over the network) skip a decode/encode round trip.
+    pub async fn find_raw(
+        &self,
+        collection: String,
+        query: bson::Document,
+    ) -> Result<Vec<bson::RawDocumentBuf>, DatabaseError> {
+        self.find_raw_with_options(collection, query, FindOptions::default())
+            .await
+    }
+
+    pub async fn find_raw_with_options(
+        &self,
+        collection: String,
+        query: bson::Document,
+        options: FindOptions,
+    ) -> Result<Vec<bson::RawDocumentBuf>, DatabaseError> {
+        let _guard = self.inner.activity.begin().await?;
+
+        Ok(self
+            .scan_raw(&collection, &query, &options)
+            .await?
+            .into_iter()
+            .map(|(_, raw)| raw)
+            .collect())
+    }
+
+    /// Like `find`, but pairs every match with its id.
+    pub async fn find_with_ids(
+        &self,
+        collection: String,
+        query: bson::Document,
+    ) -> Result<Vec<(String, bson::Document)>, DatabaseError> {
+        let _guard = self.inner.activity.begin().await?;
+
+        self.scan_raw(&collection, &query, &FindOptions::default())
+            .await?
+            .into_iter()
+            .map(|(id, raw)| {
+                let doc = raw.to_document().map_err(DatabaseError::BsonRawError)?;
+                Ok((id, doc))
+            })
+            .collect()
+    }
+
+    /// Scans a collection, returning each match along with its id.
+    async fn scan_raw(
+        &self,
+        collection: &String,
+        query: &bson::Document,
+        options: &FindOptions,
+    ) -> Result<Vec<(String, bson::RawDocumentBuf)>, DatabaseError> {
+        let collection_path = self.get_collection_path(collection)?;
+        let snapshot = self.snapshot_for(options).await;
+        let _lock = self.inner.locks.read(collection).await;
+
+        // Taken before the directory is listed: a document flushed in
+        // between is then found twice rather than not at all.
+        let mut buffered = match &self.inner.write_buffer {
+            Some(write_buffer) => write_buffer.documents(collection).await,
+            None => HashMap::new(),
+        };
+
+        let timestamp = snapshot.as_ref().map(Snapshot::timestamp);
         let mut results = Vec::new();
+        let mut seen = HashSet::new();
 
-        if let Some(field_index) = self.index.get(&collection) {
-            // Filtro los IDs que coinciden con la consulta.
-            let mut candidate_ids: Option<HashSet<String>> = None;
+        // Filtro los IDs que coinciden con la consulta.
+        let mut candidate_ids: Option<HashSet<String>> = None;
 
-            for (field, _) in query.iter() {
+        if let Some(field_index) = self.read_index().get(collection) {
+            for (field, condition) in query.iter() {
+                if !filter::requires_field(condition) {
+                    continue;
+                }
                 if let Some(ids) = field_index.get(field) {
                     let ids_set: HashSet<String> = ids.clone().into_iter().collect();
 
@@ -145,12 +1128,16 @@ impl Database {
                     }
                 }
             }
+        }
 
-            if let Some(ids) = candidate_ids {
-                for id in ids {
-                    let doc = self.find_one(collection.clone(), id).await?;
-                    if let Some(doc) = doc {
-                        results.push(doc);
+        if let Some(ids) = candidate_ids {
+            for id in ids {
+                let raw = self
+                    .read_visible(collection, &id, options, timestamp)
+                    .await?;
+                if let Some(raw) = raw {
+                    if filter::matches_raw(&raw, query)? {
+                        results.push((id, raw));
                     }
                 }
             }
@@ -158,26 +1145,90 @@ impl Database {
             return Ok(results);
         }
 
-        let mut entries = tokio::fs::read_dir(collection_path).await.map_err(|e| {
-            error!("Failed to read collection directory: {}", e);
-            DatabaseError::IoError(e)
-        })?;
+        let mut entries = match tokio::fs::read_dir(collection_path).await {
+            Ok(entries) => Some(entries),
+            // A collection created after the snapshot may still have history,
+            // and one created since the last flush has only buffered documents.
+            Err(e)
+                if e.kind() == std::io::ErrorKind::NotFound
+                    && (options.snapshot.is_some() || !buffered.is_empty()) =>
+            {
+                None
+            }
+            Err(e) => {
+                error!("Failed to read collection directory: {}", e);
+                return Err(DatabaseError::IoError(e));
+            }
+        };
 
-        while let Some(entry) = entries.next_entry().await.map_err(|e| {
-            error!("Failed to read next entry: {}", e);
-            DatabaseError::IoError(e)
-        })? {
-            let path = entry.path();
-            let buffer = tokio::fs::read(&path).await.map_err(|e| {
-                error!("Failed to read document: {}", e);
+        while let Some(entry) = match entries.as_mut() {
+            Some(entries) => entries.next_entry().await.map_err(|e| {
+                error!("Failed to read next entry: {}", e);
                 DatabaseError::IoError(e)
-            })?;
+            })?,
+            None => None,
+        } {
+            let path = entry.path();
+            let Some(codec) = Codec::of_file(&path) else {
+                continue;
+            };
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+
+            let current = match buffered.remove(&id) {
+                Some(doc) => Some(
+                    bson::RawDocumentBuf::from_document(&doc)
+                        .map_err(DatabaseError::BsonRawError)?,
+                ),
+                None => match Self::read_file(&path, options).await {
+                    Ok(buffer) => Some(codec.decode(buffer)?),
+                    // Deleted since the directory was listed.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => {
+                        error!("Failed to read document: {}", e);
+                        return Err(DatabaseError::IoError(e));
+                    }
+                },
+            };
+            seen.insert(id.clone());
+
+            if let Some(raw) = self.visible_at(collection, &id, current, timestamp)? {
+                if filter::matches_raw(&raw, query)? {
+                    results.push((id, raw));
+                }
+            }
+        }
 
-            let doc = bson::Document::from_reader(&buffer[..])
-                .map_err(|e| DatabaseError::BsonDeError(e))?;
+        // Buffered documents with no file yet.
+        for (id, doc) in buffered {
+            let current =
+                bson::RawDocumentBuf::from_document(&doc).map_err(DatabaseError::BsonRawError)?;
+            seen.insert(id.clone());
 
-            if query.iter().all(|(k, v)| doc.get(k) == Some(v)) {
-                results.push(doc);
+            if let Some(raw) = self.visible_at(collection, &id, Some(current), timestamp)? {
+                if filter::matches_raw(&raw, query)? {
+                    results.push((id, raw));
+                }
+            }
+        }
+
+        // Documents deleted after the snapshot are gone from the directory.
+        let changed = match timestamp {
+            Some(timestamp) => self.inner.versions.changed_since(timestamp, collection),
+            None => Vec::new(),
+        };
+        for (id, prior) in changed {
+            if seen.contains(&id) {
+                continue;
+            }
+            if let Some(doc) = prior {
+                let raw = bson::RawDocumentBuf::from_document(&doc)
+                    .map_err(DatabaseError::BsonRawError)?;
+                if filter::matches_raw(&raw, query)? {
+                    results.push((id, raw));
+                }
             }
         }
 
@@ -189,19 +1240,122 @@ impl Database {
         collection: String,
         id: String,
     ) -> Result<Option<bson::Document>, DatabaseError> {
-        let path = self.get_document_path(&collection, &id);
+        self.delete_one_with_options(collection, id, WriteOptions::default())
+            .await
+    }
 
-        match tokio::fs::remove_file(&path).await {
+    pub async fn delete_one_with_options(
+        &self,
+        collection: String,
+        id: String,
+        options: WriteOptions,
+    ) -> Result<Option<bson::Document>, DatabaseError> {
+        self.check_writable()?;
+        check_name(&collection)?;
+        check_name(&id)?;
+        if let Some(coordinator) = &self.inner.coordinator {
+            coordinator.delete(self, collection, id).await?;
+            return Ok(None);
+        }
+        self.remove_document(&collection, &id, options.write_concern, None)
+            .await?;
+        Ok(None)
+    }
+
+    /// Deletes a document. With `expiry`, only if it has expired, and as an
+    /// expiry rather than a delete. Answers whether it was removed.
+    pub(crate) async fn remove_document(
+        &self,
+        collection: &String,
+        id: &String,
+        write_concern: WriteConcern,
+        expiry: Option<&TtlRule>,
+    ) -> Result<bool, DatabaseError> {
+        let _document_lock = self
+            .inner
+            .document_locks
+            .lock(collection, id, self.inner.lock_timeout)
+            .await?;
+        let _guard = self.inner.activity.begin().await?;
+        let _lock = self.inner.locks.write(collection).await;
+
+        let codec = self.codec(collection).await?;
+        let path = self.get_document_path(collection, id, codec)?;
+
+        let commit = self.inner.versions.begin_commit();
+        let prior = self
+            .read_document(collection, id, &FindOptions::default())
+            .await?;
+        let existed = prior.is_some();
+        let operation = match expiry {
+            None => OperationType::Delete,
+            Some(rule) if prior.as_ref().is_some_and(|doc| rule.has_expired(doc)) => {
+                OperationType::Expire
+            }
+            Some(_) => return Ok(false),
+        };
+        commit.record(collection, id, prior.clone());
+        let change = Change {
+            operation,
+            collection,
+            id,
+            previous: prior.as_ref(),
+            document: None,
+        };
+
+        if self.inner.oplog.is_some() {
+            // Through the write-ahead log, for the entry to be durable with
+            // the delete; nothing is buffered with an oplog.
+            if !existed {
+                return Ok(false);
+            }
+            let record = WalRecord::Delete {
+                collection: collection.to_string(),
+                id: id.to_string(),
+            };
+            self.apply_logged(&[record], &[change], None).await?;
+            info!(
+                "Successfully deleted document from '{}' with ID: '{}'",
+                collection, id
+            );
+            return Ok(true);
+        }
+
+        let buffered = match &self.inner.write_buffer {
+            Some(write_buffer) => write_buffer.delete(collection, id).await?,
+            None => false,
+        };
+
+        let result = tokio::fs::remove_file(&path).await;
+        self.inner.cache.invalidate(collection, id);
+
+        match result {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && buffered => {
+                self.record_version(collection, id, None).await?;
+                self.publish_change(&change, None);
+                info!(
+                    "Successfully deleted document from '{}' with ID: '{}'",
+                    collection, id
+                );
+                Ok(true)
+            }
             Ok(_) => {
+                if write_concern == WriteConcern::Journaled {
+                    wal::sync_directory(&self.get_collection_path(collection)?).await?;
+                }
+                if existed {
+                    self.record_version(collection, id, None).await?;
+                }
+                self.publish_change(&change, None);
                 info!(
                     "Successfully deleted document from '{}' with ID: '{}'",
                     collection, id
                 );
-                Ok(None)
+                Ok(true)
             }
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 info!("Document not found in '{}' with ID: '{}'", collection, id);
-                Ok(None)
+                Ok(false)
             }
             Err(e) => {
                 error!("Failed to delete document: {}", e);
@@ -215,7 +1369,13 @@ impl Database {
         collection: String,
         query: bson::Document,
     ) -> Result<Vec<String>, DatabaseError> {
-        let collection_path = self.get_collection_path(&collection);
+        self.check_writable()?;
+        let collection_path = self.get_collection_path(&collection)?;
+        let _guard = self.inner.activity.begin().await?;
+        let _lock = self.inner.locks.write(&collection).await;
+
+        self.flush().await?;
+
         let mut deleted_ids = Vec::new();
 
         let mut entries = tokio::fs::read_dir(collection_path).await.map_err(|e| {
@@ -228,20 +1388,44 @@ impl Database {
             DatabaseError::IoError(e)
         })? {
             let path = entry.path();
+            let Some(codec) = Codec::of_file(&path) else {
+                continue;
+            };
             let buffer = tokio::fs::read(&path).await.map_err(|e| {
                 error!("Failed to read document: {}", e);
                 DatabaseError::IoError(e)
             })?;
 
-            let doc = bson::Document::from_reader(&buffer[..])
-                .map_err(|e| DatabaseError::BsonDeError(e))?;
+            let raw = codec.decode(buffer)?;
 
-            if query.iter().all(|(k, v)| doc.get(k) == Some(v)) {
-                if let Err(e) = tokio::fs::remove_file(&path).await {
-                    error!("Failed to delete document: {}", e);
-                    return Err(DatabaseError::IoError(e));
-                }
+            if filter::matches_raw(&raw, &query)? {
                 let id = path.file_stem().unwrap().to_str().unwrap().to_string();
+                let prior = raw.to_document().map_err(DatabaseError::BsonRawError)?;
+                let commit = self.inner.versions.begin_commit();
+                commit.record(&collection, &id, Some(prior.clone()));
+                let change = Change {
+                    operation: OperationType::Delete,
+                    collection: &collection,
+                    id: &id,
+                    previous: Some(&prior),
+                    document: None,
+                };
+
+                if self.inner.oplog.is_some() {
+                    let record = WalRecord::Delete {
+                        collection: collection.clone(),
+                        id: id.clone(),
+                    };
+                    self.apply_logged(&[record], &[change], None).await?;
+                } else {
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        error!("Failed to delete document: {}", e);
+                        return Err(DatabaseError::IoError(e));
+                    }
+                    self.inner.cache.invalidate(&collection, &id);
+                    self.record_version(&collection, &id, None).await?;
+                    self.publish_change(&change, None);
+                }
                 deleted_ids.push(id.clone());
                 info!(
                     "Successfully deleted document from '{}' with ID: '{}'",
@@ -253,15 +1437,38 @@ impl Database {
         Ok(deleted_ids)
     }
 
-    fn get_collection_path(&self, collection: &String) -> String {
-        format!("{}/{}", self.folder_path, collection)
+    fn get_collection_path(&self, collection: &str) -> Result<String, DatabaseError> {
+        check_name(collection)?;
+        Ok(format!("{}/{}", self.inner.folder_path, collection))
     }
 
-    fn get_document_path(&self, collection: &String, id: &String) -> String {
-        format!("{}/{}.bson", self.get_collection_path(collection), id)
+    fn get_document_path(
+        &self,
+        collection: &str,
+        id: &str,
+        codec: Codec,
+    ) -> Result<String, DatabaseError> {
+        check_name(id)?;
+        Ok(format!(
+            "{}/{}.{}",
+            self.get_collection_path(collection)?,
+            id,
+            codec.extension()
+        ))
     }
 
-    async fn create_path_dirs(&self, path: &String) -> Result<(), DatabaseError> {
+    async fn read_file(
+        path: impl AsRef<std::path::Path>,
+        options: &FindOptions,
+    ) -> std::io::Result<Vec<u8>> {
+        if options.direct_io {
+            direct_io::read(path).await
+        } else {
+            tokio::fs::read(path).await
+        }
+    }
+
+    async fn create_path_dirs(path: &str) -> Result<(), DatabaseError> {
         tokio::fs::create_dir_all(path).await.map_err(|e| {
             error!("Failed to create directory: {}", e);
             DatabaseError::IoError(e)
@@ -275,7 +1482,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_insert_one() {
-        let mut db = Database::init("data_tests".to_string()).await.unwrap();
+        let db = Database::init("data_tests/test_insert_one".to_string())
+            .await
+            .unwrap();
 
         let doc = bson::doc! {
             "name": "John",
@@ -287,9 +1496,97 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_insert_one_with_id() {
+        let folder_path = "data_tests/test_insert_one_with_id".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        db.before_insert("users", |mut doc| async move {
+            doc.insert("checked", true);
+            Ok(doc)
+        });
+
+        db.insert_one_with_id(
+            "users".to_string(),
+            "john".to_string(),
+            bson::doc! { "name": "John" },
+        )
+        .await
+        .unwrap();
+        let again = db
+            .insert_one_with_id(
+                "users".to_string(),
+                "john".to_string(),
+                bson::doc! { "name": "Eve" },
+            )
+            .await;
+        assert!(matches!(again, Err(DatabaseError::DocumentExists(_))));
+
+        let found = db
+            .find_one("users".to_string(), "john".to_string())
+            .await
+            .unwrap();
+        assert_eq!(found, Some(bson::doc! { "name": "John", "checked": true }));
+    }
+
+    #[tokio::test]
+    async fn test_names_cannot_leave_the_folder() {
+        let _ = tokio::fs::remove_dir_all("data_tests/escaped").await;
+        let db = Database::init("data_tests/test_names".to_string())
+            .await
+            .unwrap();
+        let doc = bson::doc! { "name": "Eve" };
+
+        for name in ["", ".", "..", ".wal", "../escaped", "a/b", "a\\b", "a\0b"] {
+            assert!(!is_valid_name(name), "{:?}", name);
+        }
+        assert!(is_valid_name("users.archive"));
+        assert!(is_valid_name("_users"));
+
+        fn invalid<T>(result: Result<T, DatabaseError>) -> bool {
+            matches!(result, Err(DatabaseError::InvalidName(_)))
+        }
+        assert!(invalid(
+            db.insert_one("../escaped".to_string(), doc.clone()).await
+        ));
+        assert!(invalid(
+            db.put(
+                "users".to_string(),
+                "../../escaped".to_string(),
+                doc.clone()
+            )
+            .await
+        ));
+        assert!(invalid(
+            db.update_one("users".to_string(), ".".to_string(), doc.clone())
+                .await
+        ));
+        assert!(invalid(
+            db.find_one("users".to_string(), "../../escaped".to_string())
+                .await
+        ));
+        assert!(invalid(
+            db.find(".wal".to_string(), bson::Document::new()).await
+        ));
+        assert!(invalid(
+            db.delete_one("..".to_string(), "x".to_string()).await
+        ));
+        assert!(invalid(
+            db.delete("../escaped".to_string(), bson::Document::new())
+                .await
+        ));
+        assert!(invalid(
+            db.create_collection("a/b", CollectionOptions::default())
+                .await
+        ));
+        assert!(tokio::fs::metadata("data_tests/escaped").await.is_err());
+    }
+
     #[tokio::test]
     async fn test_find_one() {
-        let mut db = Database::init("data_tests".to_string()).await.unwrap();
+        let db = Database::init("data_tests/test_find_one".to_string())
+            .await
+            .unwrap();
 
         let doc = bson::doc! {
             "name": "John",
@@ -316,7 +1613,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_find() {
-        let mut db = Database::init_test("data_tests".to_string(), "test_find".to_string()).await;
+        let db = Database::init_test("data_tests".to_string(), "test_find".to_string()).await;
         db.clear().await.unwrap();
 
         let documents = test_documents();
@@ -340,7 +1637,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_find_filtered() {
-        let mut db =
+        let db =
             Database::init_test("data_tests".to_string(), "test_find_filtered".to_string()).await;
         db.clear().await.unwrap();
 
@@ -366,10 +1663,46 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_update_one() {
+        let db = Database::init_test("data_tests".to_string(), "test_update_one".to_string()).await;
+        db.clear().await.unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), test_documents()[0].clone())
+            .await
+            .expect("Failed to insert document");
+
+        let updated = db
+            .update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$set": { "age": 31 } },
+            )
+            .await
+            .expect("Failed to update document");
+        assert!(updated);
+
+        let found_doc = db
+            .find_one("users".to_string(), id)
+            .await
+            .expect("Failed to find document");
+        assert_eq!(found_doc, Some(bson::doc! { "name": "John", "age": 31 }));
+
+        let updated = db
+            .update_one(
+                "users".to_string(),
+                "missing".to_string(),
+                bson::doc! { "$set": { "age": 31 } },
+            )
+            .await
+            .expect("Failed to update document");
+        assert!(!updated);
+    }
+
     #[tokio::test]
     async fn test_delete_one() {
-        let mut db =
-            Database::init_test("data_tests".to_string(), "test_delete_one".to_string()).await;
+        let db = Database::init_test("data_tests".to_string(), "test_delete_one".to_string()).await;
 
         db.clear().await.unwrap();
 
@@ -397,7 +1730,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete() {
-        let mut db = Database::init_test("data_tests".to_string(), "test_delete".to_string()).await;
+        let db = Database::init_test("data_tests".to_string(), "test_delete".to_string()).await;
 
         db.clear().await.unwrap();
 
@@ -426,6 +1759,275 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_cache_invalidated_on_delete() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_cache_delete".to_string()).await;
+        db.clear().await.unwrap();
+
+        let documents = test_documents();
+        let mut ids = Vec::new();
+        for doc in documents.clone() {
+            let id = db
+                .insert_one("users".to_string(), doc)
+                .await
+                .expect("Failed to insert document");
+            ids.push(id);
+        }
+
+        for id in &ids {
+            let found_doc = db.find_one("users".to_string(), id.clone()).await.unwrap();
+            assert!(found_doc.is_some());
+        }
+
+        db.delete_one("users".to_string(), ids[0].clone())
+            .await
+            .unwrap();
+        db.delete("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+
+        assert!(db
+            .find_one("users".to_string(), ids[0].clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db
+            .find_one("users".to_string(), ids[1].clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db
+            .find_one("users".to_string(), ids[2].clone())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_find_raw() {
+        let db = Database::init_test("data_tests".to_string(), "test_find_raw".to_string()).await;
+        db.clear().await.unwrap();
+
+        let documents = test_documents();
+        let mut ids = Vec::new();
+        for doc in documents.clone() {
+            let id = db
+                .insert_one("users".to_string(), doc)
+                .await
+                .expect("Failed to insert document");
+            ids.push(id);
+        }
+
+        let raw = db
+            .find_one_raw("users".to_string(), ids[0].clone())
+            .await
+            .expect("Failed to find document")
+            .expect("Document not found");
+        assert_eq!(raw.to_document().unwrap(), documents[0]);
+
+        let found_docs = db
+            .find_raw("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .expect("Failed to find documents");
+        assert_eq!(found_docs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_find_direct_io() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_find_direct_io".to_string()).await;
+        db.clear().await.unwrap();
+
+        for doc in test_documents() {
+            db.insert_one("users".to_string(), doc)
+                .await
+                .expect("Failed to insert document");
+        }
+
+        let options = FindOptions {
+            direct_io: true,
+            ..Default::default()
+        };
+        let found_docs = db
+            .find_with_options("users".to_string(), bson::doc! { "name": "John" }, options)
+            .await
+            .expect("Failed to find documents");
+
+        assert_eq!(found_docs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_isolation() {
+        let db = Database::init_test("data_tests".to_string(), "test_snapshot".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut ids = Vec::new();
+        for doc in test_documents() {
+            ids.push(
+                db.insert_one("users".to_string(), doc)
+                    .await
+                    .expect("Failed to insert document"),
+            );
+        }
+
+        let snapshot = db.snapshot();
+
+        db.update_one(
+            "users".to_string(),
+            ids[0].clone(),
+            bson::doc! { "$set": { "name": "Johnny" } },
+        )
+        .await
+        .expect("Failed to update document");
+        db.delete_one("users".to_string(), ids[2].clone())
+            .await
+            .expect("Failed to delete document");
+        db.insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .expect("Failed to insert document");
+
+        let options = FindOptions {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+        let found_docs = db
+            .find_with_options("users".to_string(), bson::doc! { "name": "John" }, options)
+            .await
+            .expect("Failed to find documents");
+        assert_eq!(found_docs.len(), 2);
+
+        let options = FindOptions {
+            snapshot: Some(snapshot),
+            ..Default::default()
+        };
+        let found_doc = db
+            .find_one_with_options("users".to_string(), ids[2].clone(), options)
+            .await
+            .expect("Failed to find document");
+        assert_eq!(found_doc, Some(bson::doc! { "name": "John", "age": 25 }));
+
+        let found_docs = db
+            .find("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .expect("Failed to find documents");
+        assert_eq!(found_docs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_folder_is_locked() {
+        let folder_path = "data_tests/test_folder_locked".to_string();
+        let db = Database::init(folder_path.clone()).await.unwrap();
+        db.clear().await.unwrap();
+
+        assert!(matches!(
+            Database::init(folder_path.clone()).await,
+            Err(DatabaseError::AlreadyLocked(_))
+        ));
+
+        let options = DatabaseOptions {
+            read_only: true,
+            ..DatabaseOptions::default()
+        };
+        assert!(Database::init_with_options(folder_path.clone(), options)
+            .await
+            .is_ok());
+
+        drop(db);
+        assert!(Database::init(folder_path).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_refuses_writes() {
+        let folder_path = "data_tests/test_read_only".to_string();
+        let db = Database::init(folder_path.clone()).await.unwrap();
+        db.clear().await.unwrap();
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+
+        let reader = Database::open_read_only(folder_path).await.unwrap();
+
+        assert!(reader
+            .find_one("users".to_string(), id.clone())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            reader
+                .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+                .await,
+            Err(DatabaseError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.delete_one("users".to_string(), id).await,
+            Err(DatabaseError::ReadOnly)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_and_write_concerns() {
+        let db = Database::init_test("data_tests".to_string(), "test_concerns".to_string()).await;
+        db.clear().await.unwrap();
+
+        let journaled = WriteOptions {
+            write_concern: WriteConcern::Journaled,
+        };
+        let id = db
+            .insert_one_with_options(
+                "users".to_string(),
+                bson::doc! { "name": "John" },
+                journaled.clone(),
+            )
+            .await
+            .expect("Failed to insert document");
+
+        let local = FindOptions {
+            read_concern: ReadConcern::Local,
+            ..Default::default()
+        };
+        let found_doc = db
+            .find_one_with_options("users".to_string(), id.clone(), local.clone())
+            .await
+            .expect("Failed to find document");
+        assert_eq!(found_doc, Some(bson::doc! { "name": "John" }));
+
+        db.delete_one_with_options("users".to_string(), id, journaled)
+            .await
+            .expect("Failed to delete document");
+        let found_docs = db
+            .find_with_options("users".to_string(), bson::doc! {}, local)
+            .await
+            .expect("Failed to find documents");
+        assert!(found_docs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shared_across_tasks() {
+        let db = Database::init_test("data_tests".to_string(), "test_shared".to_string()).await;
+        db.clear().await.unwrap();
+        db.add_index("users".to_string(), "name".to_string());
+
+        let mut tasks = Vec::new();
+        for doc in test_documents() {
+            let db = db.clone();
+            tasks.push(tokio::spawn(async move {
+                db.insert_one("users".to_string(), doc).await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().expect("Failed to insert document");
+        }
+
+        let found_docs = db
+            .find("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .expect("Failed to find documents");
+
+        assert_eq!(found_docs.len(), 2);
+    }
+
     fn test_documents() -> Vec<bson::Document> {
         vec![
             bson::doc! {
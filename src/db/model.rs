@@ -0,0 +1,169 @@
+//! Document models: types that know their collection, which of their fields
+//! holds the ID and which are indexed, usually through
+//! `#[derive(OwlDocument)]` with the `derive` feature. A model's typed
+//! collection, from `Database::collection_for`, saves and loads it with its
+//! ID in place.
+
+use bson::{Bson, Document};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Collection, Database, DatabaseError};
+
+pub trait OwlDocument: Serialize + DeserializeOwned {
+    const COLLECTION: &'static str;
+    /// The field holding the document's ID, which isn't stored with the
+    /// rest of it.
+    const ID_FIELD: Option<&'static str>;
+    /// Fields the collection is indexed on.
+    const INDEXES: &'static [&'static str];
+
+    /// `None` until the document has been saved, or without an ID field.
+    fn id(&self) -> Option<&str>;
+    fn set_id(&mut self, id: String);
+}
+
+/// A filter matching documents whose `field` is `value`, for the filters a
+/// derived `OwlDocument` gets for its fields.
+pub fn field_filter<V>(field: &str, value: &V) -> Result<Document, DatabaseError>
+where
+    V: Serialize + ?Sized,
+{
+    let value = bson::to_bson(value).map_err(DatabaseError::BsonSerError)?;
+    Ok(bson::doc! { field: value })
+}
+
+impl Database {
+    /// `T`'s collection, indexed on `T::INDEXES`.
+    pub fn collection_for<T: OwlDocument>(&self) -> Collection<T> {
+        for field in T::INDEXES {
+            self.add_index(T::COLLECTION.to_string(), field.to_string());
+        }
+        self.collection(T::COLLECTION)
+    }
+}
+
+impl<T: OwlDocument> Collection<T> {
+    /// Writes `value` under its ID, or inserts it with a new one that's set
+    /// on it. Returns the ID.
+    pub async fn save(&self, value: &mut T) -> Result<String, DatabaseError> {
+        let mut doc = bson::to_document(value).map_err(DatabaseError::BsonSerError)?;
+        if let Some(field) = T::ID_FIELD {
+            doc.remove(field);
+        }
+        let db = self.database();
+        match value.id() {
+            Some(id) => {
+                let id = id.to_string();
+                db.put(self.name().to_string(), id.clone(), doc).await?;
+                Ok(id)
+            }
+            None => {
+                let id = db.insert_one(self.name().to_string(), doc).await?;
+                value.set_id(id.clone());
+                Ok(id)
+            }
+        }
+    }
+
+    /// The document under `id`, with its ID set.
+    pub async fn load(&self, id: &str) -> Result<Option<T>, DatabaseError> {
+        match self
+            .database()
+            .find_one(self.name().to_string(), id.to_string())
+            .await?
+        {
+            Some(doc) => Ok(Some(with_id(id, doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The documents matching `filter`, with their IDs set.
+    pub async fn load_many(&self, filter: Document) -> Result<Vec<T>, DatabaseError> {
+        self.database()
+            .find_with_ids(self.name().to_string(), filter)
+            .await?
+            .into_iter()
+            .map(|(id, doc)| with_id(&id, doc))
+            .collect()
+    }
+}
+
+fn with_id<T: OwlDocument>(id: &str, mut doc: Document) -> Result<T, DatabaseError> {
+    if let Some(field) = T::ID_FIELD {
+        doc.insert(field, Bson::String(id.to_string()));
+    }
+    bson::from_document(doc).map_err(|e| DatabaseError::SchemaMismatch(id.to_string(), e))
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::db::OwlDocument;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, OwlDocument)]
+    #[owldb(crate = "crate")]
+    struct UserProfile {
+        #[owldb(id)]
+        id: Option<String>,
+        #[owldb(index)]
+        email: String,
+        age: u32,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, OwlDocument)]
+    #[owldb(crate = "crate", collection = "events")]
+    struct Event {
+        kind: String,
+    }
+
+    #[tokio::test]
+    async fn test_derived_document() {
+        let db = Database::init_test("data_tests".to_string(), "test_model".to_string()).await;
+        db.clear().await.unwrap();
+        assert_eq!(UserProfile::COLLECTION, "user_profile");
+        assert_eq!(UserProfile::INDEXES, ["email"]);
+        assert_eq!(Event::COLLECTION, "events");
+        assert_eq!(Event::ID_FIELD, None);
+
+        let profiles = db.collection_for::<UserProfile>();
+        let mut ada = UserProfile {
+            id: None,
+            email: "ada@example.com".to_string(),
+            age: 36,
+        };
+        let id = profiles.save(&mut ada).await.unwrap();
+        assert_eq!(ada.id(), Some(id.as_str()));
+        assert_eq!(
+            db.find_one("user_profile".to_string(), id.clone())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "email": "ada@example.com", "age": 36_i64 })
+        );
+
+        ada.age = 37;
+        assert_eq!(profiles.save(&mut ada).await.unwrap(), id);
+        assert_eq!(profiles.load(&id).await.unwrap(), Some(ada.clone()));
+        let filter = UserProfile::by_email(&"ada@example.com".to_string()).unwrap();
+        assert_eq!(profiles.load_many(filter).await.unwrap(), [ada]);
+        assert!(db.read_index()["user_profile"]["email"].contains(&id));
+
+        let events = db.collection_for::<Event>();
+        events
+            .save(&mut Event {
+                kind: "signup".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            events
+                .load_many(Event::by_kind(&"signup".to_string()).unwrap())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Applying writes made on other primaries. With `OplogOptions::node` set,
+//! every oplog entry carries a `WriteStamp`; a primary applies another's
+//! entry only if it's stamped later than the last write to the document
+//! here, so all primaries settle on the same version whatever order the
+//! entries reach them in.
+//!
+//! An entry whose write didn't see the version here was concurrent with
+//! it. Either way one of the two is lost, and it's noted in the
+//! `_conflicts` collection for inspection.
+
+use bson::{Bson, DateTime, Document};
+use log::{debug, warn};
+
+use super::transaction::Read;
+use super::wal::WalRecord;
+use super::{Database, DatabaseError, FindOptions, OplogEntry, WriteStamp};
+
+/// Where concurrent writes are noted, one document each.
+pub const CONFLICTS_COLLECTION: &str = "_conflicts";
+
+impl Database {
+    /// Applies `entry`, from the oplog of the primary its write was made
+    /// on, unless a write stamped later got here first. Answers whether it
+    /// was applied.
+    pub async fn apply_from_primary(&self, entry: &OplogEntry) -> Result<bool, DatabaseError> {
+        let Some(stamp) = &entry.origin else {
+            return Err(DatabaseError::InvalidConfig(format!(
+                "oplog entry {} isn't stamped; every primary needs a node name",
+                entry.position
+            )));
+        };
+        self.check_writable()?;
+
+        loop {
+            // Writers hold the collection until the oplog has their stamp.
+            let (snapshot, current, current_stamp) = {
+                let _lock = self.inner.locks.read(&entry.collection).await;
+                let snapshot = self.snapshot();
+                let current = self
+                    .read_document(&entry.collection, &entry.id, &FindOptions::default())
+                    .await?;
+                let current_stamp = self.write_stamp(&entry.collection, &entry.id).await?;
+                (snapshot, current, current_stamp)
+            };
+
+            let applies = current_stamp
+                .as_ref()
+                .is_none_or(|current_stamp| stamp > current_stamp);
+            let concurrent = entry.previous != current
+                && current_stamp
+                    .as_ref()
+                    .is_some_and(|current_stamp| current_stamp.node != stamp.node);
+            if !applies {
+                if concurrent {
+                    let current_stamp = current_stamp.as_ref().unwrap();
+                    self.note_conflict(entry, current_stamp, stamp, entry.document.as_ref())
+                        .await?;
+                }
+                debug!(
+                    "Skipping write to '{}/{}' from {}, older than the one here",
+                    entry.collection, entry.id, stamp.node
+                );
+                return Ok(false);
+            }
+
+            let record = match &entry.document {
+                Some(doc) => WalRecord::Insert {
+                    collection: entry.collection.clone(),
+                    id: entry.id.clone(),
+                    doc: doc.clone(),
+                },
+                None => WalRecord::Delete {
+                    collection: entry.collection.clone(),
+                    id: entry.id.clone(),
+                },
+            };
+            let reads = [Read::Document {
+                collection: entry.collection.clone(),
+                id: entry.id.clone(),
+            }];
+            match self
+                .apply_records(vec![record], Some((&snapshot, &reads)), Some(stamp))
+                .await
+            {
+                // Written here meanwhile; compare again.
+                Err(DatabaseError::SerializationFailure(_)) => continue,
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+
+            if concurrent {
+                let current_stamp = current_stamp.as_ref().unwrap();
+                self.note_conflict(entry, stamp, current_stamp, current.as_ref())
+                    .await?;
+            }
+            return Ok(true);
+        }
+    }
+
+    async fn note_conflict(
+        &self,
+        entry: &OplogEntry,
+        kept: &WriteStamp,
+        lost: &WriteStamp,
+        lost_document: Option<&Document>,
+    ) -> Result<(), DatabaseError> {
+        warn!(
+            "Concurrent writes to '{}/{}' on {} and {}, keeping {}'s",
+            entry.collection, entry.id, kept.node, lost.node, kept.node
+        );
+        let conflict = bson::doc! {
+            "collection": &entry.collection,
+            "id": &entry.id,
+            "kept": { "node": &kept.node, "ts": kept.timestamp },
+            "lost": { "node": &lost.node, "ts": lost.timestamp },
+            "lost_document": lost_document.cloned().map_or(Bson::Null, Bson::Document),
+            "detected_at": DateTime::now(),
+        };
+        self.insert_one(CONFLICTS_COLLECTION.to_string(), conflict)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DatabaseOptions, OplogOptions};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_from_primary() {
+        let mut opened = Vec::new();
+        for node in ["a", "b"] {
+            let folder_path = format!("data_tests/test_multi_primary_{}", node);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions {
+                    node: Some(node.to_string()),
+                    ..OplogOptions::default()
+                }),
+                ..DatabaseOptions::default()
+            };
+            opened.push(
+                Database::init_with_options(folder_path, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let b = opened.pop().unwrap();
+        let a = opened.pop().unwrap();
+        let users = || "users".to_string();
+        let last_entry = |db: &Database| {
+            let db = db.clone();
+            async move {
+                let end = db.oplog_range().await.unwrap().end;
+                db.read_oplog(end - 1, 1).await.unwrap().remove(0)
+            }
+        };
+
+        let john = a
+            .insert_one(users(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let insert = last_entry(&a).await;
+        assert_eq!(insert.origin.as_ref().unwrap().node, "a");
+        assert!(b.apply_from_primary(&insert).await.unwrap());
+        // Applied with a's stamp, so passed on as a's write.
+        assert_eq!(last_entry(&b).await.origin, insert.origin);
+
+        // Both write John at once; the later write wins on both.
+        a.update_one(users(), john.clone(), bson::doc! { "$set": { "age": 30 } })
+            .await
+            .unwrap();
+        let from_a = last_entry(&a).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        b.update_one(users(), john.clone(), bson::doc! { "$set": { "age": 40 } })
+            .await
+            .unwrap();
+        let from_b = last_entry(&b).await;
+        assert!(!b.apply_from_primary(&from_a).await.unwrap());
+        assert!(a.apply_from_primary(&from_b).await.unwrap());
+        for db in [&a, &b] {
+            assert_eq!(
+                db.find_one(users(), john.clone()).await.unwrap(),
+                Some(bson::doc! { "name": "John", "age": 40 })
+            );
+            let conflicts = db
+                .find(CONFLICTS_COLLECTION.to_string(), bson::doc! {})
+                .await
+                .unwrap();
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(
+                conflicts[0].get_document("kept").unwrap().get_str("node"),
+                Ok("b")
+            );
+            assert_eq!(
+                conflicts[0].get_document("lost_document").unwrap(),
+                &bson::doc! { "name": "John", "age": 30 }
+            );
+        }
+
+        // The stamps outlive a restart, as long as the oplog holds them.
+        let stamp = b.write_stamp("users", &john).await.unwrap();
+        b.close().await.unwrap();
+        let b = Database::init_with_options(
+            "data_tests/test_multi_primary_b".to_string(),
+            DatabaseOptions {
+                oplog: Some(OplogOptions {
+                    node: Some("b".to_string()),
+                    ..OplogOptions::default()
+                }),
+                ..DatabaseOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(b.write_stamp("users", &john).await.unwrap(), stamp);
+        assert!(!b.apply_from_primary(&from_a).await.unwrap());
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bson::Document;
+use tokio::sync::watch;
+
+type Key = (String, String);
+
+/// Keeps the images documents had before recent writes, so readers can see
+/// the database as of a fixed commit timestamp while writers carry on.
+///
+/// Every write records the prior image of what it touches *before* changing
+/// any file and is published (made visible to new snapshots) only once all
+/// earlier commits have finished. A reader at timestamp `ts` therefore sees
+/// the current file unless a write newer than `ts` left a prior image.
+pub(crate) struct VersionStore {
+    state: Mutex<VersionState>,
+    /// Mirrors `committed`, for callers waiting on a particular commit.
+    published: watch::Sender<u64>,
+}
+
+struct VersionState {
+    /// Highest timestamp whose commit, and every earlier one, has finished.
+    committed: u64,
+    next: u64,
+    in_flight: BTreeSet<u64>,
+    /// Snapshot timestamps in use, with how many readers hold each.
+    active: BTreeMap<u64, usize>,
+    /// Prior images per document, oldest write first.
+    history: HashMap<Key, Vec<Version>>,
+}
+
+struct Version {
+    superseded_at: u64,
+    prior: Option<Document>,
+}
+
+impl VersionStore {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(VersionState {
+                committed: 0,
+                next: 1,
+                in_flight: BTreeSet::new(),
+                active: BTreeMap::new(),
+                history: HashMap::new(),
+            }),
+            published: watch::channel(0).0,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VersionState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn snapshot(self: &Arc<Self>) -> Snapshot {
+        let mut state = self.lock();
+        let timestamp = state.committed;
+        *state.active.entry(timestamp).or_default() += 1;
+
+        Snapshot {
+            inner: Arc::new(SnapshotInner {
+                timestamp,
+                store: self.clone(),
+            }),
+        }
+    }
+
+    /// A snapshot including every write that returned before this call,
+    /// once the commits started ahead of them have finished too.
+    pub(crate) async fn snapshot_latest(self: &Arc<Self>) -> Snapshot {
+        self.wait_for(self.latest()).await;
+        self.snapshot()
+    }
+
+    pub(crate) fn begin_commit(self: &Arc<Self>) -> Commit {
+        let mut state = self.lock();
+        let timestamp = state.next;
+        state.next += 1;
+        state.in_flight.insert(timestamp);
+
+        Commit {
+            timestamp,
+            store: self.clone(),
+        }
+    }
+
+    /// Timestamp of the most recently started commit. Once it is published,
+    /// every write that returned before this call is visible.
+    pub(crate) fn latest(&self) -> u64 {
+        self.lock().next - 1
+    }
+
+    /// Waits until snapshots include every commit up to `timestamp`.
+    pub(crate) async fn wait_for(&self, timestamp: u64) {
+        let mut published = self.published.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let _ = published
+            .wait_for(|committed| *committed >= timestamp)
+            .await;
+    }
+
+    /// The image of a document as of `timestamp`, if a later write changed
+    /// it; `None` means the current state is the visible one.
+    pub(crate) fn visible(
+        &self,
+        timestamp: u64,
+        collection: &str,
+        id: &str,
+    ) -> Option<Option<Document>> {
+        let state = self.lock();
+        state
+            .history
+            .get(&(collection.to_string(), id.to_string()))?
+            .iter()
+            .find(|version| version.superseded_at > timestamp)
+            .map(|version| version.prior.clone())
+    }
+
+    /// Documents of `collection` changed after `timestamp`, with the image
+    /// visible at `timestamp`.
+    pub(crate) fn changed_since(
+        &self,
+        timestamp: u64,
+        collection: &str,
+    ) -> Vec<(String, Option<Document>)> {
+        let state = self.lock();
+        state
+            .history
+            .iter()
+            .filter(|((c, _), _)| c == collection)
+            .filter_map(|((_, id), versions)| {
+                versions
+                    .iter()
+                    .find(|version| version.superseded_at > timestamp)
+                    .map(|version| (id.clone(), version.prior.clone()))
+            })
+            .collect()
+    }
+
+    /// Forgets all history, for when the database folder was wiped.
+    pub(crate) fn clear(&self) {
+        self.lock().history.clear();
+    }
+
+    fn record(&self, timestamp: u64, collection: &str, id: &str, prior: Option<Document>) {
+        self.lock()
+            .history
+            .entry((collection.to_string(), id.to_string()))
+            .or_default()
+            .push(Version {
+                superseded_at: timestamp,
+                prior,
+            });
+    }
+
+    fn finish(&self, timestamp: u64) {
+        let mut state = self.lock();
+        state.in_flight.remove(&timestamp);
+        state.committed = match state.in_flight.first() {
+            Some(oldest) => oldest - 1,
+            None => state.next - 1,
+        };
+        self.published.send_replace(state.committed);
+        Self::collect_garbage(&mut state);
+    }
+
+    fn release(&self, timestamp: u64) {
+        let mut state = self.lock();
+        if let Some(count) = state.active.get_mut(&timestamp) {
+            *count -= 1;
+            if *count == 0 {
+                state.active.remove(&timestamp);
+            }
+        }
+        Self::collect_garbage(&mut state);
+    }
+
+    /// Drops prior images no current or future snapshot can ask for.
+    fn collect_garbage(state: &mut VersionState) {
+        let horizon = state
+            .active
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(state.committed)
+            .min(state.committed);
+
+        state.history.retain(|_, versions| {
+            versions.retain(|version| version.superseded_at > horizon);
+            !versions.is_empty()
+        });
+    }
+}
+
+/// A write in progress. Dropping it publishes the write to new snapshots.
+pub(crate) struct Commit {
+    timestamp: u64,
+    store: Arc<VersionStore>,
+}
+
+impl Commit {
+    /// Must be called before the document is changed on disk.
+    pub(crate) fn record(&self, collection: &str, id: &str, prior: Option<Document>) {
+        self.store.record(self.timestamp, collection, id, prior);
+    }
+}
+
+impl Drop for Commit {
+    fn drop(&mut self) {
+        self.store.finish(self.timestamp);
+    }
+}
+
+/// A consistent, read-only view of the database as of one commit.
+#[derive(Clone)]
+pub struct Snapshot {
+    inner: Arc<SnapshotInner>,
+}
+
+struct SnapshotInner {
+    timestamp: u64,
+    store: Arc<VersionStore>,
+}
+
+impl Snapshot {
+    pub fn timestamp(&self) -> u64 {
+        self.inner.timestamp
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("timestamp", &self.inner.timestamp)
+            .finish()
+    }
+}
+
+impl Drop for SnapshotInner {
+    fn drop(&mut self) {
+        self.store.release(self.timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_sees_prior_image() {
+        let store = VersionStore::new();
+        let snapshot = store.snapshot();
+
+        let commit = store.begin_commit();
+        commit.record("users", "a", Some(bson::doc! { "age": 30 }));
+        drop(commit);
+
+        assert_eq!(
+            store.visible(snapshot.timestamp(), "users", "a"),
+            Some(Some(bson::doc! { "age": 30 }))
+        );
+
+        let later = store.snapshot();
+        assert_eq!(store.visible(later.timestamp(), "users", "a"), None);
+    }
+
+    #[test]
+    fn test_unfinished_commit_is_not_published() {
+        let store = VersionStore::new();
+
+        let first = store.begin_commit();
+        let second = store.begin_commit();
+        second.record("users", "b", None);
+        drop(second);
+
+        // `first` is still running, so nothing past it is visible yet.
+        let snapshot = store.snapshot();
+        assert_eq!(snapshot.timestamp(), 0);
+        assert_eq!(
+            store.visible(snapshot.timestamp(), "users", "b"),
+            Some(None)
+        );
+
+        drop(first);
+        assert_eq!(store.snapshot().timestamp(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_latest_snapshot_waits_for_earlier_commits() {
+        let store = VersionStore::new();
+
+        let first = store.begin_commit();
+        let second = store.begin_commit();
+        drop(second);
+
+        let waiting = tokio::spawn({
+            let store = store.clone();
+            async move { store.snapshot_latest().await.timestamp() }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        assert_eq!(waiting.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_history_is_collected() {
+        let store = VersionStore::new();
+        let snapshot = store.snapshot();
+
+        let commit = store.begin_commit();
+        commit.record("users", "a", None);
+        drop(commit);
+        assert_eq!(store.changed_since(snapshot.timestamp(), "users").len(), 1);
+
+        drop(snapshot);
+        assert!(store.lock().history.is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+//! Backups kept in an object store, such as S3, GCS or Azure Blob Storage,
+//! instead of on local disk. Archives are uploaded in parts as they're
+//! written and read back as a stream, so neither side needs room for a
+//! whole one. Stores are built with the `object_store` crate, e.g.
+//! `AmazonS3Builder::from_env()`.
+
+use std::sync::Arc;
+
+use ::object_store::path::Path as ObjectPath;
+use ::object_store::ObjectStore;
+
+use super::archive::Location;
+use super::{BackupInfo, Database, DatabaseError};
+
+impl BackupInfo {
+    /// Reads the manifest of the backup at `location` in `store`.
+    pub async fn read_from_store(
+        store: &Arc<dyn ObjectStore>,
+        location: &ObjectPath,
+    ) -> Result<Self, DatabaseError> {
+        Self::read_at(Location::Object(store, location)).await
+    }
+}
+
+impl Database {
+    /// Like `Database::backup`, to an object at `location` in `store`
+    /// that mustn't exist yet.
+    pub async fn backup_to_store(
+        &self,
+        store: &Arc<dyn ObjectStore>,
+        location: &ObjectPath,
+    ) -> Result<BackupInfo, DatabaseError> {
+        self.backup_at(Location::Object(store, location)).await
+    }
+
+    /// Like `Database::backup_incremental`, following the backup at
+    /// `previous` in `store`.
+    pub async fn backup_incremental_to_store(
+        &self,
+        store: &Arc<dyn ObjectStore>,
+        previous: &ObjectPath,
+        location: &ObjectPath,
+    ) -> Result<BackupInfo, DatabaseError> {
+        self.backup_incremental_at(
+            Location::Object(store, previous),
+            Location::Object(store, location),
+        )
+        .await
+    }
+
+    /// Like `Database::restore_chain`, with backups in `store`.
+    pub async fn restore_chain_from_store(
+        &self,
+        store: &Arc<dyn ObjectStore>,
+        chain: &[ObjectPath],
+    ) -> Result<u64, DatabaseError> {
+        let chain: Vec<Location> = chain
+            .iter()
+            .map(|location| Location::Object(store, location))
+            .collect();
+        self.restore_chain_at(&chain).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::object_store::memory::InMemory;
+
+    use super::super::{DatabaseOptions, OplogOptions};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_backup_to_store() {
+        let folder_path = "data_tests/test_object_store".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let full = ObjectPath::from("backups/full.bak");
+        let incremental = ObjectPath::from("backups/incremental.bak");
+
+        for i in 0..2000 {
+            db.put(
+                "users".to_string(),
+                i.to_string(),
+                bson::doc! { "name": format!("user{}", i), "padding": "x".repeat(100) },
+            )
+            .await
+            .unwrap();
+        }
+        let info = db.backup_to_store(&store, &full).await.unwrap();
+        assert_eq!(
+            BackupInfo::read_from_store(&store, &full).await.unwrap(),
+            info
+        );
+        assert!(matches!(
+            db.backup_to_store(&store, &full).await,
+            Err(DatabaseError::InvalidBackup(_))
+        ));
+
+        db.delete_one("users".to_string(), "7".to_string())
+            .await
+            .unwrap();
+        db.backup_incremental_to_store(&store, &full, &incremental)
+            .await
+            .unwrap();
+
+        db.delete("users".to_string(), bson::doc! {}).await.unwrap();
+        assert_eq!(
+            db.restore_chain_from_store(&store, &[full, incremental])
+                .await
+                .unwrap(),
+            2001
+        );
+        let users = db.find("users".to_string(), bson::doc! {}).await.unwrap();
+        assert_eq!(users.len(), 1999);
+        assert_eq!(
+            db.find_one("users".to_string(), "7".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
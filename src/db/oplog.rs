@@ -0,0 +1,1073 @@
+//! The operation log: every write, numbered in the order it was applied,
+//! so consumers like followers and change data capture pipelines can read
+//! all of a database's changes from a position and pick up where they left
+//! off after a disconnect. Entries carry the document both as the write
+//! found it and as it left it.
+//!
+//! Entries go to `.oplog` in the database folder, after a header record
+//! holding the position of the first one. Only the latest entries are
+//! kept; a consumer that falls further behind has to copy the data again
+//! and read on from there.
+//!
+//! Entries are made durable with the writes they record: they go into the
+//! same write-ahead log record, so replaying it after a crash restores the
+//! entries the log missed along with the writes.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use bson::{DateTime, Document};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::sync::{watch, Mutex, MutexGuard};
+
+use super::changes::Change;
+use super::handoff::{hold_dropped, HandoffOptions, HintReader, Hints, HINTS_FOLDER};
+use super::wal::{self, WalRecord};
+use super::{Database, DatabaseError, OperationType};
+
+const OPLOG_FILE: &str = ".oplog";
+
+/// Every record is framed as `[len: u32 LE][crc32: u32 LE][bson payload]`.
+pub(super) const HEADER_LEN: usize = 8;
+
+/// Entries between the offsets remembered for seeking to a position.
+const CHECKPOINT_INTERVAL: u64 = 256;
+
+#[derive(Debug, Clone)]
+pub struct OplogOptions {
+    /// Entries kept for consumers. The log is trimmed back to about this
+    /// many once it holds twice as many.
+    pub retained_entries: u64,
+    /// Names this node among the primaries of a multi-primary deployment
+    /// (see `owldb::server::multi_primary`); every entry is then stamped.
+    pub node: Option<String>,
+    /// Hold the entries followers miss while away past the trims; see
+    /// `Database::hold_for`.
+    pub handoff: Option<HandoffOptions>,
+}
+
+impl Default for OplogOptions {
+    fn default() -> Self {
+        Self {
+            retained_entries: 100_000,
+            node: None,
+            handoff: None,
+        }
+    }
+}
+
+/// When and on which node a write was first made. Concurrent writes to a
+/// document on different primaries are ordered by it: the later wins, the
+/// node name breaking ties.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WriteStamp {
+    pub timestamp: DateTime,
+    pub node: String,
+}
+
+impl WriteStamp {
+    fn to_document(&self) -> Document {
+        bson::doc! { "ts": self.timestamp, "node": &self.node }
+    }
+
+    fn from_document(doc: &Document) -> Option<Self> {
+        Some(Self {
+            timestamp: *doc.get_datetime("ts").ok()?,
+            node: doc.get_str("node").ok()?.to_string(),
+        })
+    }
+}
+
+/// One write, as recorded in the oplog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OplogEntry {
+    /// Starts at 1 and goes up by one with every write, for as long as the
+    /// database folder lives.
+    pub position: u64,
+    pub timestamp: DateTime,
+    pub operation: OperationType,
+    pub collection: String,
+    pub id: String,
+    /// The document as the write found it; `None` for inserts.
+    pub previous: Option<Document>,
+    /// The document as the write left it; `None` for deletes.
+    pub document: Option<Document>,
+    /// Set when `OplogOptions::node` is: the stamp of the write, from the
+    /// node it was first made on.
+    pub origin: Option<WriteStamp>,
+}
+
+impl OplogEntry {
+    pub(super) fn from_document(doc: &Document) -> Result<Self, DatabaseError> {
+        let corrupt = |key: &str| {
+            DatabaseError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("oplog entry without a valid '{}'", key),
+            ))
+        };
+
+        let operation = match doc.get_str("op").map_err(|_| corrupt("op"))? {
+            "insert" => OperationType::Insert,
+            "update" => OperationType::Update,
+            "delete" => OperationType::Delete,
+            "expired" => OperationType::Expire,
+            _ => return Err(corrupt("op")),
+        };
+        Ok(Self {
+            position: doc.get_i64("position").map_err(|_| corrupt("position"))? as u64,
+            timestamp: *doc.get_datetime("ts").map_err(|_| corrupt("ts"))?,
+            operation,
+            collection: doc
+                .get_str("collection")
+                .map_err(|_| corrupt("collection"))?
+                .to_string(),
+            id: doc.get_str("id").map_err(|_| corrupt("id"))?.to_string(),
+            previous: doc.get_document("prev").ok().cloned(),
+            document: doc.get_document("doc").ok().cloned(),
+            origin: doc
+                .get_document("origin")
+                .ok()
+                .and_then(WriteStamp::from_document),
+        })
+    }
+}
+
+pub(crate) struct Oplog {
+    path: PathBuf,
+    options: OplogOptions,
+    pub(super) state: Mutex<OplogState>,
+    /// The position the next entry gets, for consumers waiting on it.
+    next: watch::Sender<u64>,
+    /// The last position each follower replicating from it acknowledged.
+    pub(super) followers: std::sync::Mutex<HashMap<String, u64>>,
+}
+
+pub(super) struct OplogState {
+    file: tokio::fs::File,
+    /// Bytes of the file holding whole records.
+    len: u64,
+    pub(super) first: u64,
+    pub(super) next: u64,
+    /// `(position, offset)` of the first entry and every
+    /// `CHECKPOINT_INTERVAL`th one after it.
+    checkpoints: VecDeque<(u64, u64)>,
+    /// The stamp of the last write to each document, with a node set.
+    /// Only writes the log still held when opened are remembered.
+    stamps: HashMap<(String, String), WriteStamp>,
+    /// The entries held for each follower away.
+    pub(super) hints: HashMap<String, Hints>,
+    /// Entries of writes already made whose append failed; written ahead
+    /// of the next ones.
+    unwritten: Vec<Document>,
+}
+
+impl Oplog {
+    /// Opens the log in `folder_path`, starting one if there is none. A
+    /// torn record at the end, left by a crash, is cut off.
+    pub(crate) async fn open(
+        folder_path: &str,
+        options: OplogOptions,
+    ) -> Result<Self, DatabaseError> {
+        let path = Path::new(folder_path).join(OPLOG_FILE);
+        // Held for followers of a process gone.
+        match tokio::fs::remove_dir_all(Path::new(folder_path).join(HINTS_FOLDER)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(DatabaseError::IoError(e))
+            }
+            _ => {}
+        }
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(DatabaseError::IoError(e)),
+        };
+
+        let Some((header, mut at)) = read_record(&bytes, 0) else {
+            let (file, len) = create(&path, 1).await?;
+            return Ok(Self::new(path, options, file, len, 1, VecDeque::new()));
+        };
+        let first = header
+            .get_i64("first")
+            .map_err(|_| DatabaseError::IoError(std::io::ErrorKind::InvalidData.into()))?
+            as u64;
+
+        let mut next = first;
+        let mut checkpoints = VecDeque::new();
+        let mut stamps = HashMap::new();
+        while let Some((doc, end)) = read_record(&bytes, at) {
+            if doc.get_i64("position") != Ok(next as i64) {
+                break;
+            }
+            if options.node.is_some() {
+                if let Ok(entry) = OplogEntry::from_document(&doc) {
+                    if let Some(origin) = entry.origin {
+                        stamps.insert((entry.collection, entry.id), origin);
+                    }
+                }
+            }
+            if (next - first).is_multiple_of(CHECKPOINT_INTERVAL) {
+                checkpoints.push_back((next, at as u64));
+            }
+            next += 1;
+            at = end;
+        }
+
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        if at < bytes.len() {
+            warn!(
+                "Cutting {} bytes of torn records off the end of the oplog",
+                bytes.len() - at
+            );
+            file.set_len(at as u64)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+        info!("Opened the oplog at positions {}..{}", first, next);
+
+        let mut oplog = Self::new(path, options, file, at as u64, first, checkpoints);
+        oplog.state.get_mut().next = next;
+        oplog.state.get_mut().stamps = stamps;
+        oplog.next.send_replace(next);
+        Ok(oplog)
+    }
+
+    fn new(
+        path: PathBuf,
+        options: OplogOptions,
+        file: tokio::fs::File,
+        len: u64,
+        first: u64,
+        checkpoints: VecDeque<(u64, u64)>,
+    ) -> Self {
+        Self {
+            path,
+            options,
+            state: Mutex::new(OplogState {
+                file,
+                len,
+                first,
+                next: first,
+                checkpoints,
+                stamps: HashMap::new(),
+                hints: HashMap::new(),
+                unwritten: Vec::new(),
+            }),
+            next: watch::channel(first).0,
+            followers: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(super) fn handoff(&self) -> Option<&HandoffOptions> {
+        self.options.handoff.as_ref()
+    }
+
+    pub(super) fn hints_folder(&self) -> PathBuf {
+        self.path.with_file_name(HINTS_FOLDER)
+    }
+
+    /// Takes the log to add entries. Positions are handed out, and entries
+    /// written, in the order it is taken.
+    pub(super) async fn lock(&self) -> MutexGuard<'_, OplogState> {
+        self.state.lock().await
+    }
+
+    /// The entry recording `change`, the `offset`th of the writes about to
+    /// be logged with `state` held.
+    pub(super) fn entry(
+        &self,
+        state: &OplogState,
+        offset: u64,
+        change: &Change<'_>,
+        origin: Option<&WriteStamp>,
+    ) -> Document {
+        let position = state.next + state.unwritten.len() as u64 + offset;
+        let now = DateTime::now();
+
+        let mut entry = bson::doc! {
+            "position": position as i64,
+            "ts": now,
+            "op": match change.operation {
+                OperationType::Insert => "insert",
+                OperationType::Update => "update",
+                OperationType::Delete => "delete",
+                OperationType::Expire => "expired",
+            },
+            "collection": change.collection,
+            "id": change.id,
+        };
+        if let Some(previous) = change.previous {
+            entry.insert("prev", previous.clone());
+        }
+        if let Some(document) = change.document {
+            entry.insert("doc", document.clone());
+        }
+        let origin = match (origin, &self.options.node) {
+            (Some(origin), _) => Some(origin.clone()),
+            (None, Some(node)) => Some(WriteStamp {
+                timestamp: now,
+                node: node.clone(),
+            }),
+            (None, None) => None,
+        };
+        if let Some(origin) = origin {
+            entry.insert("origin", origin.to_document());
+        }
+        entry
+    }
+
+    /// Appends `entries`, made with `entry`, and waits until they are
+    /// durable. Entries whose append failed are kept and go ahead of the
+    /// next ones.
+    pub(super) async fn append(
+        &self,
+        state: &mut OplogState,
+        entries: Vec<Document>,
+    ) -> Result<(), DatabaseError> {
+        state.unwritten.extend(entries);
+        let mut records = Vec::new();
+        for entry in &state.unwritten {
+            records.extend(frame(entry)?);
+        }
+
+        let written = match state.file.write_all(&records).await {
+            Ok(()) => state.file.sync_data().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // Don't leave half a record for the next one to follow.
+            let _ = state.file.set_len(state.len).await;
+            return Err(DatabaseError::IoError(e));
+        }
+
+        for entry in std::mem::take(&mut state.unwritten) {
+            let position = state.next;
+            if (position - state.first).is_multiple_of(CHECKPOINT_INTERVAL) {
+                let offset = state.len;
+                state.checkpoints.push_back((position, offset));
+            }
+            state.len += frame(&entry)?.len() as u64;
+            state.next += 1;
+            let origin = entry
+                .get_document("origin")
+                .ok()
+                .and_then(WriteStamp::from_document);
+            if let (Some(origin), Ok(collection), Ok(id)) =
+                (origin, entry.get_str("collection"), entry.get_str("id"))
+            {
+                state
+                    .stamps
+                    .insert((collection.to_string(), id.to_string()), origin);
+            }
+        }
+        self.next.send_replace(state.next);
+
+        if state.next - state.first > 2 * self.options.retained_entries.max(1) {
+            // The entries are in; trimming can wait for the next append.
+            if let Err(e) = self.trim(state).await {
+                warn!("Failed to trim the oplog: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the entries a replayed write-ahead log held that the log
+    /// didn't get to before the process stopped.
+    pub(crate) async fn recover(&self, entries: Vec<Document>) -> Result<(), DatabaseError> {
+        let mut state = self.state.lock().await;
+
+        let mut missing = Vec::new();
+        for entry in entries {
+            let position = entry.get_i64("position").unwrap_or_default() as u64;
+            let expected = state.next + missing.len() as u64;
+            if position == expected {
+                missing.push(entry);
+            } else if position > expected {
+                warn!(
+                    "Oplog entries {}..{} were lost; dropping the ones after",
+                    expected, position
+                );
+                break;
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "Restoring {} oplog entries from the write-ahead log",
+            missing.len()
+        );
+        self.append(&mut state, missing).await
+    }
+
+    /// Drops the entries before the checkpoint leaving at least
+    /// `retained_entries`, rewriting the file.
+    async fn trim(&self, state: &mut OplogState) -> Result<(), DatabaseError> {
+        let keep_from = state.next - self.options.retained_entries.max(1);
+        let Some(&(first, offset)) = state
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(position, _)| *position <= keep_from)
+        else {
+            return Ok(());
+        };
+
+        let mut kept = Vec::new();
+        let mut file = tokio::fs::File::open(&self.path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(DatabaseError::IoError)?;
+        (&mut file)
+            .take(state.len - offset)
+            .read_to_end(&mut kept)
+            .await
+            .map_err(DatabaseError::IoError)?;
+
+        if let (Some(handoff), false) = (&self.options.handoff, state.hints.is_empty()) {
+            let (_, start) = state.checkpoints[0];
+            let mut dropped = Vec::new();
+            file.seek(SeekFrom::Start(start))
+                .await
+                .map_err(DatabaseError::IoError)?;
+            (&mut file)
+                .take(offset - start)
+                .read_to_end(&mut dropped)
+                .await
+                .map_err(DatabaseError::IoError)?;
+            hold_dropped(&mut state.hints, &dropped, state.first, handoff).await?;
+        }
+
+        let mut bytes = frame(&bson::doc! { "first": first as i64 })?;
+        let header_len = bytes.len() as u64;
+        bytes.extend_from_slice(&kept);
+        let staging = self.path.with_extension("new");
+        wal::write_synced(&staging.to_string_lossy(), &bytes).await?;
+        tokio::fs::rename(&staging, &self.path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        sync_folder(&self.path).await?;
+
+        state.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        state.len = bytes.len() as u64;
+        state.first = first;
+        state.checkpoints.retain(|(position, _)| *position >= first);
+        for (_, checkpoint) in state.checkpoints.iter_mut() {
+            *checkpoint = *checkpoint - offset + header_len;
+        }
+        info!("Trimmed the oplog to positions {}..{}", first, state.next);
+        Ok(())
+    }
+
+    /// Starts the log over, empty, after the database folder was wiped.
+    /// Positions carry on from where they were, so consumers find they
+    /// have to copy the data again.
+    pub(crate) async fn reset(&self) -> Result<(), DatabaseError> {
+        let mut state = self.state.lock().await;
+        let (file, len) = create(&self.path, state.next).await?;
+        state.file = file;
+        state.len = len;
+        state.first = state.next;
+        state.checkpoints.clear();
+        state.stamps.clear();
+        state.hints.clear();
+        state.unwritten.clear();
+        Ok(())
+    }
+
+    async fn read(&self, from: u64, limit: usize) -> Result<Vec<OplogEntry>, DatabaseError> {
+        let (file, mut position, offset, end) = {
+            let state = self.state.lock().await;
+            if from < state.first || from > state.next {
+                return Err(DatabaseError::OplogTruncated(from));
+            }
+            if from == state.next || limit == 0 {
+                return Ok(Vec::new());
+            }
+            let (position, offset) = *state
+                .checkpoints
+                .iter()
+                .rev()
+                .find(|(position, _)| *position <= from)
+                .expect("the first entry is a checkpoint");
+            // Opened under the lock, so a trim can't swap the file first.
+            let file = tokio::fs::File::open(&self.path)
+                .await
+                .map_err(DatabaseError::IoError)?;
+            (file, position, offset, state.len)
+        };
+
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(DatabaseError::IoError)?;
+        let mut reader = BufReader::new(file.take(end - offset));
+
+        let mut entries = Vec::new();
+        let mut record = Vec::new();
+        while entries.len() < limit {
+            let mut header = [0; HEADER_LEN];
+            match reader.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(DatabaseError::IoError(e)),
+            }
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            record.clear();
+            record.extend_from_slice(&header);
+            record.resize(HEADER_LEN + len, 0);
+            reader
+                .read_exact(&mut record[HEADER_LEN..])
+                .await
+                .map_err(DatabaseError::IoError)?;
+
+            if position >= from {
+                let (doc, _) = read_record(&record, 0).ok_or_else(|| {
+                    DatabaseError::IoError(std::io::ErrorKind::InvalidData.into())
+                })?;
+                entries.push(OplogEntry::from_document(&doc)?);
+            }
+            position += 1;
+        }
+        Ok(entries)
+    }
+}
+
+/// Entries read at a time by an `OplogCursor`.
+const CURSOR_BATCH: usize = 256;
+
+/// A consumer's place in the oplog, from `Database::oplog_since`.
+pub struct OplogCursor {
+    db: Database,
+    /// The position of the next entry to hand out.
+    next: u64,
+    buffered: VecDeque<OplogEntry>,
+    /// Entries held for a follower, read before the log's.
+    hints: Option<HintReader>,
+}
+
+impl OplogCursor {
+    pub(super) fn with_hints(db: Database, next: u64, hints: HintReader) -> Self {
+        Self {
+            db,
+            next,
+            buffered: VecDeque::new(),
+            hints: Some(hints),
+        }
+    }
+
+    /// Up to `limit` entries after those buffered, the held ones first.
+    async fn read(&mut self, limit: usize) -> Result<Vec<OplogEntry>, DatabaseError> {
+        if let Some(hints) = &mut self.hints {
+            let entries = hints.read(limit).await?;
+            if !entries.is_empty() {
+                return Ok(entries);
+            }
+            self.hints = None;
+        }
+        let from = self
+            .buffered
+            .back()
+            .map_or(self.next, |entry| entry.position + 1);
+        self.db.read_oplog(from, limit).await
+    }
+
+    /// The next entry, waiting for a write if there's none yet. Fails with
+    /// `DatabaseError::OplogTruncated` once the log has been trimmed past
+    /// the cursor. Cancelling the wait loses nothing.
+    pub async fn next(&mut self) -> Result<OplogEntry, DatabaseError> {
+        loop {
+            if let Some(entry) = self.buffered.pop_front() {
+                self.next = entry.position + 1;
+                return Ok(entry);
+            }
+            let entries = self.read(CURSOR_BATCH).await?;
+            match entries.is_empty() {
+                true => self.db.wait_for_oplog(self.next).await?,
+                false => self.buffered.extend(entries),
+            }
+        }
+    }
+
+    /// Up to `max` entries, as many as are written already, waiting for a
+    /// write only if there's none yet. Cancelling the wait loses nothing.
+    pub async fn next_batch(&mut self, max: usize) -> Result<Vec<OplogEntry>, DatabaseError> {
+        let max = max.max(1);
+        loop {
+            if self.buffered.len() < max {
+                let entries = self.read(max - self.buffered.len()).await?;
+                self.buffered.extend(entries);
+            }
+            if self.buffered.is_empty() {
+                self.db.wait_for_oplog(self.next).await?;
+                continue;
+            }
+
+            let count = self.buffered.len().min(max);
+            let batch: Vec<OplogEntry> = self.buffered.drain(..count).collect();
+            self.next = batch[count - 1].position + 1;
+            return Ok(batch);
+        }
+    }
+
+    /// The position of the last entry handed out, to resume from with
+    /// `Database::oplog_since`.
+    pub fn position(&self) -> u64 {
+        self.next - 1
+    }
+}
+
+/// Hands the oplog entries a write-ahead log replay turned up outside
+/// `Database::init` to the folder's oplog, if it keeps one.
+pub(crate) async fn recover_replayed(
+    folder_path: &str,
+    entries: Vec<Document>,
+) -> Result<(), DatabaseError> {
+    let path = Path::new(folder_path).join(OPLOG_FILE);
+    if entries.is_empty() || !path.exists() {
+        return Ok(());
+    }
+    // Trimming is left to the database, which knows how much to keep.
+    let options = OplogOptions {
+        retained_entries: u64::MAX / 2,
+        ..OplogOptions::default()
+    };
+    Oplog::open(folder_path, options)
+        .await?
+        .recover(entries)
+        .await
+}
+
+/// Writes a log holding no entries yet, the next to come getting `first`.
+async fn create(path: &Path, first: u64) -> Result<(tokio::fs::File, u64), DatabaseError> {
+    let header = frame(&bson::doc! { "first": first as i64 })?;
+    wal::write_synced(&path.to_string_lossy(), &header).await?;
+    sync_folder(path).await?;
+    let file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    Ok((file, header.len() as u64))
+}
+
+/// Makes the log's creation or replacement durable.
+async fn sync_folder(path: &Path) -> Result<(), DatabaseError> {
+    let folder = path
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    wal::sync_directory(&folder.to_string_lossy()).await
+}
+
+fn frame(doc: &Document) -> Result<Vec<u8>, DatabaseError> {
+    let mut payload = Vec::new();
+    doc.to_writer(&mut payload)
+        .map_err(DatabaseError::BsonSerError)?;
+
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// The record starting at `at` and where the next one starts, or `None`
+/// at the end of `bytes` or a torn record.
+pub(super) fn read_record(bytes: &[u8], at: usize) -> Option<(Document, usize)> {
+    let header = bytes.get(at..at + HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = bytes.get(at + HEADER_LEN..at + HEADER_LEN + len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    let doc = Document::from_reader(&mut &payload[..]).ok()?;
+    Some((doc, at + HEADER_LEN + len))
+}
+
+impl Database {
+    pub(super) fn oplog(&self) -> Result<&Oplog, DatabaseError> {
+        self.inner
+            .oplog
+            .as_ref()
+            .ok_or(DatabaseError::OplogDisabled)
+    }
+
+    /// Notes that the follower `name` applied the entries up to
+    /// `acknowledged`, for `DatabaseStats::oplog`.
+    pub fn track_follower(&self, name: &str, acknowledged: u64) {
+        if let Some(oplog) = &self.inner.oplog {
+            let mut followers = oplog.followers.lock().unwrap_or_else(|e| e.into_inner());
+            followers.insert(name.to_string(), acknowledged);
+        }
+    }
+
+    /// Forgets a follower that stopped replicating.
+    pub fn untrack_follower(&self, name: &str) {
+        if let Some(oplog) = &self.inner.oplog {
+            let mut followers = oplog.followers.lock().unwrap_or_else(|e| e.into_inner());
+            followers.remove(name);
+        }
+    }
+
+    /// The positions the oplog holds entries for; the end is the one the
+    /// next write gets.
+    pub async fn oplog_range(&self) -> Result<Range<u64>, DatabaseError> {
+        let state = self.oplog()?.state.lock().await;
+        Ok(state.first..state.next)
+    }
+
+    /// Up to `limit` entries from position `from` on; none if `from` is the
+    /// next write's. Fails with `DatabaseError::OplogTruncated` if the log
+    /// no longer holds `from`, or hasn't reached it.
+    pub async fn read_oplog(
+        &self,
+        from: u64,
+        limit: usize,
+    ) -> Result<Vec<OplogEntry>, DatabaseError> {
+        self.oplog()?.read(from, limit).await
+    }
+
+    /// Reads the entries after `position`, the last one a consumer saw, on
+    /// to those still to be written. Start from `oplog_range().start - 1`
+    /// for every entry held. Fails like `read_oplog` if the log no longer
+    /// holds the next one.
+    pub async fn oplog_since(&self, position: u64) -> Result<OplogCursor, DatabaseError> {
+        let next = position + 1;
+        self.read_oplog(next, 0).await?;
+        Ok(OplogCursor {
+            db: self.clone(),
+            next,
+            buffered: VecDeque::new(),
+            hints: None,
+        })
+    }
+
+    /// The stamp of the last write to document `id`, when the oplog stamps
+    /// writes and remembers one for it.
+    pub async fn write_stamp(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> Result<Option<WriteStamp>, DatabaseError> {
+        let state = self.oplog()?.state.lock().await;
+        Ok(state
+            .stamps
+            .get(&(collection.to_string(), id.to_string()))
+            .cloned())
+    }
+
+    /// Waits until the oplog holds an entry at `position`.
+    pub async fn wait_for_oplog(&self, position: u64) -> Result<(), DatabaseError> {
+        let mut next = self.oplog()?.next.subscribe();
+        // The sender lives as long as the database.
+        let _ = next.wait_for(|next| *next > position).await;
+        Ok(())
+    }
+
+    /// Makes the database a follower, whose writes come from its leader
+    /// through `apply_replicated`; any others fail with
+    /// `DatabaseError::ReadOnly`. Unset to promote it.
+    pub fn set_follower(&self, follower: bool) {
+        self.inner.follower.store(follower, Ordering::Release);
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.inner.follower.load(Ordering::Acquire)
+    }
+
+    /// Writes `document` under `id`, replacing any document there, or
+    /// deletes it when `None`, as a leader's oplog entry or snapshot has
+    /// it. Read-only handles still refuse.
+    pub async fn apply_replicated(
+        &self,
+        collection: String,
+        id: String,
+        document: Option<Document>,
+    ) -> Result<(), DatabaseError> {
+        if self.inner.read_only {
+            return Err(DatabaseError::ReadOnly);
+        }
+        let record = match document {
+            Some(doc) => WalRecord::Insert {
+                collection,
+                id,
+                doc,
+            },
+            None => WalRecord::Delete { collection, id },
+        };
+        self.apply_records(vec![record], None, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DatabaseOptions;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_oplog() {
+        let folder_path = "data_tests/test_oplog".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions {
+                retained_entries: 300,
+                ..OplogOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path.clone(), options.clone())
+            .await
+            .unwrap();
+        assert_eq!(db.oplog_range().await.unwrap(), 1..1);
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "age": 1 } },
+        )
+        .await
+        .unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let entries = db.read_oplog(2, 10).await.unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.position, entry.operation, entry.document.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, OperationType::Update, Some(bson::doc! { "age": 31 })),
+                (3, OperationType::Delete, None),
+            ]
+        );
+        assert_eq!(entries[0].id, id);
+        assert_eq!(entries[1].previous, Some(bson::doc! { "age": 31 }));
+        assert!(db.read_oplog(4, 10).await.unwrap().is_empty());
+
+        let mut cursor = db.oplog_since(1).await.unwrap();
+        let update = cursor.next().await.unwrap();
+        assert_eq!(
+            (update.previous, update.document),
+            (
+                Some(bson::doc! { "age": 30 }),
+                Some(bson::doc! { "age": 31 })
+            )
+        );
+        cursor.next().await.unwrap();
+        assert_eq!(cursor.position(), 3);
+        assert!(matches!(
+            db.read_oplog(5, 10).await,
+            Err(DatabaseError::OplogTruncated(5))
+        ));
+
+        let waiting = {
+            let db = db.clone();
+            tokio::spawn(async move { db.wait_for_oplog(4).await })
+        };
+        for n in 0..700 {
+            db.insert_one("notes".to_string(), bson::doc! { "n": n })
+                .await
+                .unwrap();
+        }
+        waiting.await.unwrap().unwrap();
+        assert!(matches!(
+            cursor.next().await,
+            Err(DatabaseError::OplogTruncated(4))
+        ));
+
+        // Trimmed to a checkpoint leaving at least 300 entries.
+        let range = db.oplog_range().await.unwrap();
+        assert_eq!(range.end, 704);
+        assert!(range.start > 1 && range.end - range.start >= 300);
+        assert!(db.read_oplog(1, 10).await.is_err());
+        let entries = db.read_oplog(600, 1000).await.unwrap();
+        assert_eq!(entries.len(), 104);
+        assert_eq!(entries[0].document, Some(bson::doc! { "n": 596 }));
+        let mut cursor = db.oplog_since(599).await.unwrap();
+        assert_eq!(cursor.next_batch(100).await.unwrap().len(), 100);
+        assert_eq!(cursor.next_batch(100).await.unwrap().len(), 4);
+        assert_eq!(cursor.position(), 703);
+
+        db.close().await.unwrap();
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        assert_eq!(db.oplog_range().await.unwrap(), range);
+        assert_eq!(db.read_oplog(703, 10).await.unwrap()[0].position, 703);
+
+        db.clear().await.unwrap();
+        assert_eq!(db.oplog_range().await.unwrap(), 704..704);
+        assert!(Database::init("data_tests/test_oplog_disabled".to_string())
+            .await
+            .unwrap()
+            .read_oplog(1, 10)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_entries_are_replayed_with_their_writes() {
+        let folder_path = "data_tests/test_oplog_replay".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path.clone(), options.clone())
+            .await
+            .unwrap();
+        db.put("users".to_string(), "a".to_string(), bson::doc! { "n": 1 })
+            .await
+            .unwrap();
+        db.close().await.unwrap();
+
+        // As a write leaves it when the process dies right after its log
+        // record is made durable: neither applied nor in the oplog.
+        let entry = |position: i64, n: i32| {
+            bson::doc! {
+                "position": position,
+                "ts": DateTime::now(),
+                "op": "update",
+                "collection": "users",
+                "id": "a",
+                "doc": { "n": n },
+            }
+        };
+        let sequence = wal::next_sequence(&folder_path).await.unwrap();
+        let mut log = wal::WalWriter::create(&folder_path, sequence, 0)
+            .await
+            .unwrap();
+        log.append(&[WalRecord::Batch(vec![
+            WalRecord::Insert {
+                collection: "users".to_string(),
+                id: "a".to_string(),
+                doc: bson::doc! { "n": 2 },
+            },
+            // Already in the oplog; not appended again.
+            WalRecord::Logged(entry(1, 1)),
+            WalRecord::Logged(entry(2, 2)),
+        ])])
+        .await
+        .unwrap();
+        drop(log);
+
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.find_one("users".to_string(), "a".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "n": 2 })
+        );
+        assert_eq!(db.oplog_range().await.unwrap(), 1..3);
+        let entries = db.read_oplog(1, 10).await.unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.operation, entry.document.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (OperationType::Insert, Some(bson::doc! { "n": 1 })),
+                (OperationType::Update, Some(bson::doc! { "n": 2 })),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oplog_since_carries_pre_images() {
+        let folder_path = "data_tests/test_oplog_pre_images".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions {
+                retained_entries: 10,
+                ..OplogOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$set": { "name": "Johnny" } },
+        )
+        .await
+        .unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let mut cursor = db.oplog_since(0).await.unwrap();
+        let mut changes = Vec::new();
+        for _ in 0..3 {
+            let entry = cursor.next().await.unwrap();
+            assert_eq!(entry.id, id);
+            changes.push((entry.operation, entry.previous, entry.document));
+        }
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    OperationType::Insert,
+                    None,
+                    Some(bson::doc! { "name": "John" })
+                ),
+                (
+                    OperationType::Update,
+                    Some(bson::doc! { "name": "John" }),
+                    Some(bson::doc! { "name": "Johnny" })
+                ),
+                (
+                    OperationType::Delete,
+                    Some(bson::doc! { "name": "Johnny" }),
+                    None
+                ),
+            ]
+        );
+
+        // Enough writes to pass a checkpoint and trim the log behind it.
+        for n in 0..2 * CHECKPOINT_INTERVAL {
+            db.insert_one("notes".to_string(), bson::doc! { "n": n as i64 })
+                .await
+                .unwrap();
+        }
+        assert!(db.oplog_range().await.unwrap().start > 4);
+        assert!(matches!(
+            cursor.next().await,
+            Err(DatabaseError::OplogTruncated(4))
+        ));
+        assert!(matches!(
+            db.oplog_since(0).await,
+            Err(DatabaseError::OplogTruncated(1))
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+//! Transactional outbox: a transaction appends events to the `_outbox`
+//! collection with `Transaction::publish`, so they're recorded if and only
+//! if its writes are, and a relay started with `spawn_outbox_relay` hands
+//! them to a callback, such as one producing to a message broker.
+//!
+//! The relay delivers events in the order they were published, one at a
+//! time. One the callback fails is retried, holding back those after it,
+//! until it succeeds. An event is marked delivered only after the callback
+//! returns, so a crash in between delivers it again: consumers should
+//! deduplicate by its ID. Only one relay should run per database.
+
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use bson::Document;
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::{Database, DatabaseError, Transaction};
+
+/// Where published events wait to be delivered.
+pub const OUTBOX_COLLECTION: &str = "_outbox";
+
+/// An event as the relay hands it over.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEvent {
+    /// Unique, and the same each time the event is delivered.
+    pub id: String,
+    pub topic: String,
+    pub payload: Document,
+    pub published_at: bson::DateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxOptions {
+    /// How often the outbox is checked when no publish wakes the relay.
+    pub poll_interval: Duration,
+    /// Most events read from the outbox at a time.
+    pub batch_size: usize,
+    /// Wait before trying a failed event again.
+    pub retry_delay: Duration,
+    /// Keep delivered events, marked `delivered`, rather than deleting them.
+    pub keep_delivered: bool,
+}
+
+impl Default for OutboxOptions {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(1),
+            batch_size: 100,
+            retry_delay: Duration::from_secs(1),
+            keep_delivered: false,
+        }
+    }
+}
+
+impl Transaction {
+    /// Appends an event to the outbox, to be delivered once the transaction
+    /// commits. Returns its ID.
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: Document,
+    ) -> Result<String, DatabaseError> {
+        let event = bson::doc! {
+            "topic": topic,
+            "payload": payload,
+            "published_at": bson::DateTime::from_system_time(SystemTime::now()),
+            "delivered": false,
+        };
+        self.insert_one(OUTBOX_COLLECTION.to_string(), event).await
+    }
+}
+
+pub struct OutboxRelay {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl OutboxRelay {
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Outbox relay task failed: {}", e);
+        }
+    }
+}
+
+impl Database {
+    /// Starts delivering the outbox's events to `deliver` in the background.
+    /// An error from it is logged and the event tried again.
+    pub fn spawn_outbox_relay<F, Fut>(&self, options: OutboxOptions, deliver: F) -> OutboxRelay
+    where
+        F: Fn(OutboxEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let (shutdown, mut shutdown_rx) = watch::channel(false);
+        let db = self.clone();
+        let mut published = self.watch(OUTBOX_COLLECTION);
+        let task = tokio::spawn(async move {
+            info!("Outbox relay started");
+            loop {
+                let wait = match db.relay_pending(&options, &deliver).await {
+                    Ok(true) => continue,
+                    Ok(false) => options.poll_interval,
+                    Err(e) => {
+                        warn!("Failed to relay outbox events: {:?}", e);
+                        options.retry_delay
+                    }
+                };
+                tokio::select! {
+                    _ = shutdown_rx.changed() => break,
+                    _ = published.next() => {}
+                    _ = tokio::time::sleep(wait) => {}
+                }
+            }
+            info!("Outbox relay stopped");
+        });
+        OutboxRelay { shutdown, task }
+    }
+
+    /// Delivers the oldest pending events. Answers whether a full batch
+    /// went out, so more may be waiting.
+    async fn relay_pending<F, Fut>(
+        &self,
+        options: &OutboxOptions,
+        deliver: &F,
+    ) -> Result<bool, DatabaseError>
+    where
+        F: Fn(OutboxEvent) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let mut pending = self
+            .find_with_ids(
+                OUTBOX_COLLECTION.to_string(),
+                bson::doc! { "delivered": false },
+            )
+            .await?;
+        // IDs are ObjectIds, so they sort in the order they were made.
+        pending.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pending.truncate(options.batch_size);
+        let full = pending.len() == options.batch_size;
+
+        for (id, doc) in pending {
+            let event = OutboxEvent {
+                id: id.clone(),
+                topic: doc.get_str("topic").unwrap_or_default().to_string(),
+                payload: doc.get_document("payload").cloned().unwrap_or_default(),
+                published_at: doc
+                    .get_datetime("published_at")
+                    .copied()
+                    .unwrap_or(bson::DateTime::MIN),
+            };
+            if let Err(e) = deliver(event).await {
+                warn!("Failed to deliver outbox event {}: {}", id, e);
+                return Err(DatabaseError::Unavailable(e));
+            }
+            match options.keep_delivered {
+                true => {
+                    self.update_one(
+                        OUTBOX_COLLECTION.to_string(),
+                        id,
+                        bson::doc! { "$set": { "delivered": true } },
+                    )
+                    .await?;
+                }
+                false => {
+                    self.delete_one(OUTBOX_COLLECTION.to_string(), id).await?;
+                }
+            }
+        }
+        Ok(full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_outbox() {
+        let db = Database::init_test("data_tests".to_string(), "test_outbox".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        txn.insert_one("orders".to_string(), bson::doc! { "total": 5 })
+            .await
+            .unwrap();
+        txn.publish("orders", bson::doc! { "placed": 1 })
+            .await
+            .unwrap();
+        txn.rollback();
+        let mut txn = db.begin_transaction().await;
+        let first = txn
+            .publish("orders", bson::doc! { "placed": 2 })
+            .await
+            .unwrap();
+        txn.publish("orders", bson::doc! { "placed": 3 })
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        // Fails its first delivery, which is then retried.
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let deliveries = delivered.clone();
+        let options = OutboxOptions {
+            retry_delay: Duration::from_millis(10),
+            keep_delivered: true,
+            ..OutboxOptions::default()
+        };
+        let relay = db.spawn_outbox_relay(options, move |event| {
+            let deliveries = deliveries.clone();
+            async move {
+                let mut deliveries = deliveries.lock().unwrap();
+                deliveries.push((event.id, event.payload.get_i32("placed").unwrap()));
+                match deliveries.len() {
+                    1 => Err("broker unavailable".to_string()),
+                    _ => Ok(()),
+                }
+            }
+        });
+
+        let mut txn = db.begin_transaction().await;
+        txn.publish("orders", bson::doc! { "placed": 4 })
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let relayed = async {
+            while delivered.lock().unwrap().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), relayed)
+            .await
+            .unwrap();
+        relay.stop().await;
+
+        let delivered = delivered.lock().unwrap().clone();
+        let placed: Vec<_> = delivered.iter().map(|(_, placed)| *placed).collect();
+        assert_eq!(placed, [2, 2, 3, 4]);
+        assert_eq!(delivered[0].0, first);
+        let event = db
+            .find_one(OUTBOX_COLLECTION.to_string(), first)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.get_bool("delivered"), Ok(true));
+    }
+}
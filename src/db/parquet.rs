@@ -0,0 +1,295 @@
+//! Exporting a collection to a Parquet file, for DuckDB, Spark and the
+//! like. Embedded documents are flattened into dotted column names such as
+//! `address.city`, and arrays written as JSON. Unless a schema is given,
+//! each column's type is inferred from the values found. Every column but
+//! `_id` is optional; a value that doesn't fit its column's type is written
+//! as null.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use ::parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+use ::parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type};
+use ::parquet::errors::ParquetError;
+use ::parquet::file::properties::WriterProperties;
+use ::parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use ::parquet::schema::types::Type;
+use bson::{Bson, Document};
+use log::info;
+
+use super::columnar::{as_f64, as_i64, as_text, infer_columns, Kind, Row};
+use super::{Database, DatabaseError, Projection};
+
+/// The type of a Parquet column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParquetType {
+    Bool,
+    Int32,
+    Int64,
+    Double,
+    /// UTF-8.
+    String,
+    /// Milliseconds since the epoch, in UTC.
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParquetColumn {
+    /// Dotted, such as `address.city`.
+    pub path: String,
+    pub kind: ParquetType,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParquetExportOptions {
+    /// Only documents matching it, as `find` would.
+    pub filter: Document,
+    /// Applied before columns are inferred.
+    pub projection: Projection,
+    /// The columns to write after `_id`; `None` to infer them.
+    pub schema: Option<Vec<ParquetColumn>>,
+    /// Rows written together, which readers can skip as a whole.
+    pub row_group_size: usize,
+}
+
+impl Default for ParquetExportOptions {
+    fn default() -> Self {
+        Self {
+            filter: Document::new(),
+            projection: Projection::All,
+            schema: None,
+            row_group_size: 64 * 1024,
+        }
+    }
+}
+
+impl Database {
+    /// Writes `collection`'s matching documents to a Parquet file at
+    /// `path`, replacing any there. Answers how many.
+    pub async fn export_parquet(
+        &self,
+        collection: String,
+        path: impl AsRef<Path>,
+        options: ParquetExportOptions,
+    ) -> Result<usize, DatabaseError> {
+        let rows = self
+            .rows(&collection, options.filter, &options.projection)
+            .await?;
+        let columns = match options.schema {
+            Some(columns) => columns,
+            None => infer_columns(&rows)
+                .into_iter()
+                .map(|(path, kind)| ParquetColumn {
+                    path,
+                    kind: kind.into(),
+                })
+                .collect(),
+        };
+
+        let path = path.as_ref().to_path_buf();
+        let row_group_size = options.row_group_size.max(1);
+        let count = rows.len();
+        tokio::task::spawn_blocking(move || write_file(&path, &columns, &rows, row_group_size))
+            .await
+            .map_err(|e| DatabaseError::IoError(std::io::Error::other(e)))?
+            .map_err(to_error)?;
+
+        info!(
+            "Exported {} documents from '{}' to Parquet",
+            count, collection
+        );
+        Ok(count)
+    }
+}
+
+impl From<Kind> for ParquetType {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Bool => ParquetType::Bool,
+            Kind::Int32 => ParquetType::Int32,
+            Kind::Int64 => ParquetType::Int64,
+            Kind::Double => ParquetType::Double,
+            Kind::String => ParquetType::String,
+            Kind::Timestamp => ParquetType::Timestamp,
+        }
+    }
+}
+
+fn to_error(e: ParquetError) -> DatabaseError {
+    DatabaseError::IoError(std::io::Error::other(e))
+}
+
+fn write_file(
+    path: &Path,
+    columns: &[ParquetColumn],
+    rows: &[(String, Row)],
+    row_group_size: usize,
+) -> Result<(), ParquetError> {
+    let mut fields = vec![Arc::new(
+        Type::primitive_type_builder("_id", PhysicalType::BYTE_ARRAY)
+            .with_repetition(Repetition::REQUIRED)
+            .with_converted_type(ConvertedType::UTF8)
+            .build()?,
+    )];
+    for column in columns {
+        let (physical, converted) = match column.kind {
+            ParquetType::Bool => (PhysicalType::BOOLEAN, ConvertedType::NONE),
+            ParquetType::Int32 => (PhysicalType::INT32, ConvertedType::NONE),
+            ParquetType::Int64 => (PhysicalType::INT64, ConvertedType::NONE),
+            ParquetType::Double => (PhysicalType::DOUBLE, ConvertedType::NONE),
+            ParquetType::String => (PhysicalType::BYTE_ARRAY, ConvertedType::UTF8),
+            ParquetType::Timestamp => (PhysicalType::INT64, ConvertedType::TIMESTAMP_MILLIS),
+        };
+        fields.push(Arc::new(
+            Type::primitive_type_builder(&column.path, physical)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_converted_type(converted)
+                .build()?,
+        ));
+    }
+    let schema = Type::group_type_builder("schema")
+        .with_fields(fields)
+        .build()?;
+
+    let file = File::create(path)?;
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), properties)?;
+    for group in rows.chunks(row_group_size) {
+        let mut row_group = writer.next_row_group()?;
+        if let Some(mut column) = row_group.next_column()? {
+            let ids: Vec<_> = group
+                .iter()
+                .map(|(id, _)| ByteArray::from(id.as_str()))
+                .collect();
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(&ids, None, None)?;
+            column.close()?;
+        }
+        let cells: Vec<HashMap<_, _>> = group
+            .iter()
+            .map(|(_, row)| row.iter().map(|(path, value)| (path, value)).collect())
+            .collect();
+        for spec in columns {
+            if let Some(column) = row_group.next_column()? {
+                let values = cells.iter().map(|row| row.get(&spec.path).copied());
+                write_column(column, spec.kind, values)?;
+            }
+        }
+        row_group.close()?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes an optional column: a definition level of 1 for each value
+/// present, 0 for each null.
+fn write_column<'a>(
+    mut column: SerializedColumnWriter<'_>,
+    kind: ParquetType,
+    values: impl Iterator<Item = Option<&'a Bson>>,
+) -> Result<(), ParquetError> {
+    macro_rules! write {
+        ($data_type:ty, $convert:expr) => {{
+            let mut present = Vec::new();
+            let mut levels = Vec::new();
+            for value in values {
+                match value.and_then($convert) {
+                    Some(value) => {
+                        present.push(value);
+                        levels.push(1);
+                    }
+                    None => levels.push(0),
+                }
+            }
+            column
+                .typed::<$data_type>()
+                .write_batch(&present, Some(&levels), None)?;
+        }};
+    }
+    match kind {
+        ParquetType::Bool => write!(BoolType, |value: &Bson| value.as_bool()),
+        ParquetType::Int32 => write!(Int32Type, |value: &Bson| value.as_i32()),
+        ParquetType::Int64 => write!(Int64Type, as_i64),
+        ParquetType::Double => write!(DoubleType, as_f64),
+        ParquetType::Timestamp => write!(Int64Type, |value: &Bson| value
+            .as_datetime()
+            .map(|date| date.timestamp_millis())),
+        ParquetType::String => write!(ByteArrayType, |value: &Bson| Some(ByteArray::from(
+            as_text(value).into_bytes()
+        ))),
+    }
+    column.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use ::parquet::file::reader::{FileReader, SerializedFileReader};
+    use ::parquet::record::Field;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_parquet() {
+        let db = Database::init_test("data_tests".to_string(), "test_parquet".to_string()).await;
+        db.clear().await.unwrap();
+        let when = bson::DateTime::from_millis(1_700_000_000_000);
+        db.put(
+            "orders".to_string(),
+            "a".to_string(),
+            bson::doc! { "total": 5, "at": when, "ship": { "city": "Oslo" }, "paid": true },
+        )
+        .await
+        .unwrap();
+        db.put(
+            "orders".to_string(),
+            "b".to_string(),
+            bson::doc! { "total": 2.5, "items": [1, 2], "paid": false },
+        )
+        .await
+        .unwrap();
+
+        let path = "data_tests/test_parquet.parquet";
+        let options = ParquetExportOptions {
+            filter: bson::doc! { "paid": true },
+            ..ParquetExportOptions::default()
+        };
+        assert_eq!(
+            db.export_parquet("orders".to_string(), path, options)
+                .await
+                .unwrap(),
+            1
+        );
+        db.export_parquet("orders".to_string(), path, ParquetExportOptions::default())
+            .await
+            .unwrap();
+
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        let rows: Vec<Vec<(String, Field)>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            })
+            .collect();
+
+        let columns: Vec<_> = rows[0].iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            columns,
+            ["_id", "total", "at", "ship.city", "paid", "items"]
+        );
+        let row: HashMap<_, _> = rows[0].iter().cloned().collect();
+        assert_eq!(row["total"], Field::Double(5.0));
+        assert_eq!(row["ship.city"], Field::Str("Oslo".to_string()));
+        assert_eq!(row["at"], Field::TimestampMillis(1_700_000_000_000));
+        assert_eq!(row["items"], Field::Null);
+        let row: HashMap<_, _> = rows[1].iter().cloned().collect();
+        assert_eq!(row["items"], Field::Str("[1,2]".to_string()));
+        assert_eq!(row["paid"], Field::Bool(false));
+    }
+}
@@ -0,0 +1,110 @@
+//! Projections, which cut exported documents down to some of their fields,
+//! say to leave personal data out of a copy. Fields are named by dotted
+//! path, and a path covers everything embedded under it: `address` covers
+//! `address.city`.
+
+use bson::{Bson, Document};
+
+use super::{Database, DatabaseError};
+
+/// Which fields of each document to keep. The ID is always kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Projection {
+    #[default]
+    All,
+    /// Only these paths.
+    Include(Vec<String>),
+    /// Every path but these.
+    Exclude(Vec<String>),
+}
+
+impl Projection {
+    /// `doc` with only the fields this keeps. An embedded document left
+    /// with nothing an inclusion asked for is dropped.
+    pub fn apply(&self, doc: Document) -> Document {
+        match self {
+            Projection::All => doc,
+            Projection::Include(paths) => project(doc, "", paths, true),
+            Projection::Exclude(paths) => project(doc, "", paths, false),
+        }
+    }
+}
+
+fn project(doc: Document, prefix: &str, paths: &[String], include: bool) -> Document {
+    let mut projected = Document::new();
+    for (key, value) in doc {
+        let path = format!("{}{}", prefix, key);
+        if paths.iter().any(|wanted| covers(wanted, &path)) {
+            if include {
+                projected.insert(key, value);
+            }
+            continue;
+        }
+        match value {
+            // Some path names a field embedded under this one.
+            Bson::Document(inner) if paths.iter().any(|wanted| covers(&path, wanted)) => {
+                let inner = project(inner, &format!("{}.", path), paths, include);
+                if !include || !inner.is_empty() {
+                    projected.insert(key, inner);
+                }
+            }
+            value if !include => {
+                projected.insert(key, value);
+            }
+            _ => {}
+        }
+    }
+    projected
+}
+
+/// Whether `path` is `prefix` or embedded under it.
+fn covers(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+impl Database {
+    /// `collection`'s documents matching `filter`, by ID and in no
+    /// particular order, cut down by `projection`.
+    pub(crate) async fn find_projected(
+        &self,
+        collection: String,
+        filter: Document,
+        projection: &Projection,
+    ) -> Result<Vec<(String, Document)>, DatabaseError> {
+        let documents = self.find_with_ids(collection, filter).await?;
+        Ok(documents
+            .into_iter()
+            .map(|(id, doc)| (id, projection.apply(doc)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection() {
+        let doc = bson::doc! {
+            "name": "Ada",
+            "ssn": "078-05-1120",
+            "address": { "city": "London", "street": "St James's Square" },
+            "tags": ["math"],
+        };
+
+        let include = Projection::Include(vec!["name".to_string(), "address.city".to_string()]);
+        assert_eq!(
+            include.apply(doc.clone()),
+            bson::doc! { "name": "Ada", "address": { "city": "London" } }
+        );
+        let exclude = Projection::Exclude(vec!["ssn".to_string(), "address.street".to_string()]);
+        assert_eq!(
+            exclude.apply(doc.clone()),
+            bson::doc! { "name": "Ada", "address": { "city": "London" }, "tags": ["math"] }
+        );
+        let include = Projection::Include(vec!["address.zip".to_string(), "nam".to_string()]);
+        assert_eq!(include.apply(doc.clone()), Document::new());
+        assert_eq!(Projection::All.apply(doc.clone()), doc);
+    }
+}
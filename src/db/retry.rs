@@ -0,0 +1,142 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use log::warn;
+
+use super::DatabaseError;
+
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Upper bound of the first backoff; doubled after every failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl DatabaseError {
+    /// Whether the operation may succeed if simply tried again: it lost a
+    /// race for a lock or a document, or couldn't reach the server, rather
+    /// than being wrong in itself.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            DatabaseError::LockTimeout(_)
+                | DatabaseError::Deadlock(_)
+                | DatabaseError::WriteConflict(_)
+                | DatabaseError::SerializationFailure(_)
+                | DatabaseError::Unavailable(_)
+        )
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with a non-transient error, or
+/// runs out of attempts, sleeping a random ("full jitter") backoff between
+/// attempts so that competing retries spread out.
+///
+/// `operation` is called afresh for every attempt, so a transaction must be
+/// begun inside it.
+pub async fn with_retry<T, F, Fut>(options: &RetryOptions, operation: F) -> Result<T, DatabaseError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DatabaseError>>,
+{
+    with_retry_if(options, DatabaseError::is_transient, operation).await
+}
+
+/// Like `with_retry`, retrying only the errors `retryable` accepts.
+pub(crate) async fn with_retry_if<T, F, Fut>(
+    options: &RetryOptions,
+    retryable: fn(&DatabaseError) -> bool,
+    mut operation: F,
+) -> Result<T, DatabaseError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DatabaseError>>,
+{
+    let mut backoff = options.initial_backoff;
+    let mut attempt = 1;
+
+    loop {
+        match operation().await {
+            Err(e) if retryable(&e) && attempt < options.max_attempts => {
+                warn!(
+                    "Retrying after transient error (attempt {}): {:?}",
+                    attempt, e
+                );
+                tokio::time::sleep(jitter(backoff)).await;
+
+                backoff = (backoff * 2).min(options.max_backoff);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A random duration between zero and `max`.
+fn jitter(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let nanos = max.as_nanos() as u64;
+    Duration::from_nanos(random.checked_rem(nanos + 1).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let attempts = AtomicU32::new(0);
+
+        let result = with_retry(&RetryOptions::default(), || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(DatabaseError::Deadlock("users/a".to_string())),
+                _ => Ok("done"),
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up() {
+        let attempts = AtomicU32::new(0);
+
+        let result: Result<(), _> = with_retry(&RetryOptions::default(), || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DatabaseError::InvalidUpdate("bad".to_string()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let options = RetryOptions {
+            max_attempts: 3,
+            ..RetryOptions::default()
+        };
+        let result: Result<(), _> = with_retry(&options, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DatabaseError::LockTimeout("users/a".to_string()))
+        })
+        .await;
+        assert!(matches!(result, Err(DatabaseError::LockTimeout(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,249 @@
+//! Loading fixtures, for test setups and demo environments: each file in a
+//! directory is loaded into the collection its name says, `users.json`
+//! into `users`. A `.json` file holds an array of documents, or one per
+//! line like `.jsonl` and `.ndjson` files, in MongoDB Extended JSON; a
+//! `.bson` file holds BSON documents one after another, as `mongodump`
+//! writes them. Documents keep their `_id`, and get a new ID without one.
+//! Other files and subdirectories are left alone.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use bson::Document;
+use log::info;
+
+use super::export::{parse_line, parse_value, take_id};
+use super::wal::WalRecord;
+use super::{Database, DatabaseError};
+
+#[derive(Debug, Clone, Default)]
+pub struct SeedOptions {
+    /// Delete what a seeded collection holds first.
+    pub truncate: bool,
+}
+
+impl Database {
+    /// Loads the fixtures in the directory at `path`. Answers how many
+    /// documents went into each collection.
+    pub async fn seed(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<BTreeMap<String, usize>, DatabaseError> {
+        self.seed_with_options(path, SeedOptions::default()).await
+    }
+
+    /// Like `seed`. Every fixture is read before anything is written, so a
+    /// malformed one fails the seed as a whole; each collection is then
+    /// written as one atomic batch, replacing documents with the same ID.
+    /// Bypasses hooks and validators, like a restore.
+    pub async fn seed_with_options(
+        &self,
+        path: impl AsRef<Path>,
+        options: SeedOptions,
+    ) -> Result<BTreeMap<String, usize>, DatabaseError> {
+        self.check_writable()?;
+        let path = path.as_ref();
+        let mut fixtures: BTreeMap<String, Vec<(String, Document)>> = BTreeMap::new();
+        let mut entries = tokio::fs::read_dir(path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+            let file = entry.path();
+            let (Some(collection), Some(extension)) = (
+                file.file_stem().and_then(|stem| stem.to_str()),
+                file.extension().and_then(|extension| extension.to_str()),
+            ) else {
+                continue;
+            };
+            if !entry
+                .file_type()
+                .await
+                .map_err(DatabaseError::IoError)?
+                .is_file()
+            {
+                continue;
+            }
+            let parse = match extension {
+                "json" | "jsonl" | "ndjson" => parse_json,
+                "bson" => parse_bson,
+                _ => continue,
+            };
+            let bytes = tokio::fs::read(&file)
+                .await
+                .map_err(DatabaseError::IoError)?;
+            let documents = parse(&bytes).map_err(|reason| {
+                DatabaseError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}", file.display(), reason),
+                ))
+            })?;
+            fixtures
+                .entry(collection.to_string())
+                .or_default()
+                .extend(documents);
+        }
+
+        let mut loaded = BTreeMap::new();
+        for (collection, documents) in fixtures {
+            let mut records = Vec::new();
+            if options.truncate {
+                let existing = match self
+                    .find_with_ids(collection.clone(), Document::new())
+                    .await
+                {
+                    Ok(existing) => existing,
+                    // Nothing written to the collection yet.
+                    Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                        Vec::new()
+                    }
+                    Err(e) => return Err(e),
+                };
+                records.extend(existing.into_iter().map(|(id, _)| WalRecord::Delete {
+                    collection: collection.clone(),
+                    id,
+                }));
+            }
+            let count = documents.len();
+            records.extend(documents.into_iter().map(|(id, doc)| WalRecord::Insert {
+                collection: collection.clone(),
+                id,
+                doc,
+            }));
+            self.apply_records(records, None, None).await?;
+            loaded.insert(collection, count);
+        }
+
+        info!(
+            "Seeded {} documents into {} collections from '{}'",
+            loaded.values().sum::<usize>(),
+            loaded.len(),
+            path.display()
+        );
+        Ok(loaded)
+    }
+}
+
+/// An array of documents, or one per line.
+fn parse_json(bytes: &[u8]) -> Result<Vec<(String, Document)>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+    if text.trim_start().starts_with('[') {
+        let values: Vec<serde_json::Value> =
+            serde_json::from_str(text).map_err(|e| e.to_string())?;
+        return values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| {
+                parse_value(value).map_err(|reason| format!("item {}: {}", i, reason))
+            })
+            .collect();
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_line(line).map_err(|reason| format!("line {}: {}", i + 1, reason)))
+        .collect()
+}
+
+/// BSON documents one after another.
+fn parse_bson(mut bytes: &[u8]) -> Result<Vec<(String, Document)>, String> {
+    let mut documents = Vec::new();
+    while !bytes.is_empty() {
+        let doc = Document::from_reader(&mut bytes)
+            .map_err(|e| format!("document {}: {}", documents.len(), e))?;
+        documents.push(take_id(doc)?);
+    }
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_seed() {
+        let db = Database::init_test("data_tests".to_string(), "test_seed".to_string()).await;
+        db.clear().await.unwrap();
+        let fixtures = Path::new("data_tests/test_seed_fixtures");
+        let _ = tokio::fs::remove_dir_all(fixtures).await;
+        tokio::fs::create_dir_all(fixtures.join("nested"))
+            .await
+            .unwrap();
+        tokio::fs::write(
+            fixtures.join("users.json"),
+            r#"[{"_id": "ada", "name": "Ada"}, {"_id": "grace", "name": "Grace"}]"#,
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            fixtures.join("orders.jsonl"),
+            "{\"_id\": \"1\", \"total\": 5}\n\n{\"total\": 7}\n",
+        )
+        .await
+        .unwrap();
+        let mut bson = Vec::new();
+        bson::doc! { "_id": "demo", "plan": "free" }
+            .to_writer(&mut bson)
+            .unwrap();
+        tokio::fs::write(fixtures.join("tenants.bson"), bson)
+            .await
+            .unwrap();
+        tokio::fs::write(fixtures.join("README.md"), "not a fixture")
+            .await
+            .unwrap();
+
+        db.put(
+            "users".to_string(),
+            "old".to_string(),
+            bson::doc! { "name": "Old" },
+        )
+        .await
+        .unwrap();
+        let loaded = db.seed(fixtures).await.unwrap();
+        assert_eq!(
+            loaded.into_iter().collect::<Vec<_>>(),
+            [
+                ("orders".to_string(), 2),
+                ("tenants".to_string(), 1),
+                ("users".to_string(), 2)
+            ]
+        );
+        assert_eq!(
+            db.find_one("tenants".to_string(), "demo".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "plan": "free" })
+        );
+        assert_eq!(
+            db.find("users".to_string(), Document::new())
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let options = SeedOptions { truncate: true };
+        db.seed_with_options(fixtures, options).await.unwrap();
+        assert_eq!(
+            db.find("users".to_string(), Document::new())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            db.find_one("users".to_string(), "old".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+
+        tokio::fs::write(fixtures.join("broken.json"), "[1]")
+            .await
+            .unwrap();
+        assert!(db.seed(fixtures).await.is_err());
+        assert!(db
+            .find("broken".to_string(), Document::new())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use bson::Document;
+use log::info;
+
+use super::{Database, DatabaseError, FindOptions, Transaction};
+
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Every read observes the writes the session made before it, even
+    /// while commits from other tasks are still being published.
+    pub causal_consistency: bool,
+    /// Applied to reads made outside a transaction.
+    pub find_options: FindOptions,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            causal_consistency: true,
+            find_options: FindOptions::default(),
+        }
+    }
+}
+
+/// A sequence of related operations, optionally grouped into transactions,
+/// sharing one set of options and consistency guarantees.
+pub struct Session {
+    db: Database,
+    options: SessionOptions,
+    /// Commit timestamp the session's reads must have caught up with.
+    operation_time: u64,
+    transaction: Option<Transaction>,
+}
+
+impl Database {
+    pub fn start_session(&self, options: SessionOptions) -> Session {
+        Session {
+            db: self.clone(),
+            options,
+            operation_time: 0,
+            transaction: None,
+        }
+    }
+}
+
+impl Session {
+    pub fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    /// Timestamp of the latest write this session has observed.
+    pub fn operation_time(&self) -> u64 {
+        self.operation_time
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Routes the session's operations into a new transaction until it is
+    /// committed or aborted.
+    pub async fn start_transaction(&mut self) -> Result<(), DatabaseError> {
+        if self.transaction.is_some() {
+            return Err(DatabaseError::TransactionInProgress);
+        }
+
+        self.catch_up().await;
+        self.transaction = Some(self.db.begin_transaction().await);
+
+        Ok(())
+    }
+
+    pub async fn commit_transaction(&mut self) -> Result<(), DatabaseError> {
+        let transaction = self
+            .transaction
+            .take()
+            .ok_or(DatabaseError::NoTransaction)?;
+
+        transaction.commit().await?;
+        self.advance();
+
+        Ok(())
+    }
+
+    pub fn abort_transaction(&mut self) -> Result<(), DatabaseError> {
+        let transaction = self
+            .transaction
+            .take()
+            .ok_or(DatabaseError::NoTransaction)?;
+
+        transaction.rollback();
+        info!("Aborted session transaction");
+
+        Ok(())
+    }
+
+    /// The transaction in progress, for savepoints and the like.
+    pub fn transaction(&mut self) -> Option<&mut Transaction> {
+        self.transaction.as_mut()
+    }
+
+    pub async fn insert_one(
+        &mut self,
+        collection: String,
+        doc: Document,
+    ) -> Result<String, DatabaseError> {
+        if let Some(transaction) = self.transaction.as_mut() {
+            return transaction.insert_one(collection, doc).await;
+        }
+
+        let id = self.db.insert_one(collection, doc).await?;
+        self.advance();
+
+        Ok(id)
+    }
+
+    pub async fn update_one(
+        &mut self,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<bool, DatabaseError> {
+        if let Some(transaction) = self.transaction.as_mut() {
+            return transaction.update_one(collection, id, update).await;
+        }
+
+        let updated = self.db.update_one(collection, id, update).await?;
+        self.advance();
+
+        Ok(updated)
+    }
+
+    /// Returns `false` if there was no such document.
+    pub async fn delete_one(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<bool, DatabaseError> {
+        if let Some(transaction) = self.transaction.as_mut() {
+            return transaction.delete_one(collection, id).await;
+        }
+
+        if self
+            .find_one(collection.clone(), id.clone())
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+        self.db.delete_one(collection, id).await?;
+        self.advance();
+
+        Ok(true)
+    }
+
+    pub async fn find_one(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Document>, DatabaseError> {
+        if let Some(transaction) = self.transaction.as_ref() {
+            return transaction.find_one(collection, id).await;
+        }
+
+        self.catch_up().await;
+        self.db
+            .find_one_with_options(collection, id, self.options.find_options.clone())
+            .await
+    }
+
+    pub async fn find(
+        &mut self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<Document>, DatabaseError> {
+        if let Some(transaction) = self.transaction.as_ref() {
+            return transaction.find(collection, query).await;
+        }
+
+        self.catch_up().await;
+        self.db
+            .find_with_options(collection, query, self.options.find_options.clone())
+            .await
+    }
+
+    /// Records that the session has written up to the latest commit.
+    fn advance(&mut self) {
+        self.operation_time = self.operation_time.max(self.db.inner.versions.latest());
+    }
+
+    /// Waits until fresh snapshots include the session's own writes.
+    async fn catch_up(&self) {
+        if self.options.causal_consistency {
+            self.db.inner.versions.wait_for(self.operation_time).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_session_reads_its_writes() {
+        let db = Database::init_test("data_tests".to_string(), "test_session".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut session = db.start_session(SessionOptions::default());
+        let id = session
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        assert!(session.operation_time() > 0);
+
+        assert_eq!(
+            session
+                .find_one("users".to_string(), id.clone())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "name": "John" })
+        );
+        assert!(session.delete_one("users".to_string(), id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_session_transaction() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_session_txn".to_string()).await;
+        db.clear().await.unwrap();
+        db.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+
+        let mut session = db.start_session(SessionOptions::default());
+        session.start_transaction().await.unwrap();
+        assert!(matches!(
+            session.start_transaction().await,
+            Err(DatabaseError::TransactionInProgress)
+        ));
+
+        session
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        assert_eq!(
+            session
+                .find("users".to_string(), bson::doc! { "name": "John" })
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(db
+            .find("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap()
+            .is_empty());
+
+        session.commit_transaction().await.unwrap();
+        assert!(!session.in_transaction());
+        assert_eq!(
+            session
+                .find("users".to_string(), bson::doc! {})
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! A one-shot importer for SQLite databases: each table becomes the
+//! collection of the same name, each row a document. Integers, reals, text
+//! and blobs keep their types, integers narrowed to 32 bits when they fit,
+//! and columns declared `BOOLEAN` or as a date or time become booleans and
+//! dates. A table with a single-column primary key has it as the ID;
+//! other tables' rows get new ones.
+
+use std::path::Path;
+
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson, Document};
+use log::info;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use tokio::sync::mpsc;
+
+use super::wal::WalRecord;
+use super::{Database, DatabaseError};
+
+/// Rows written together.
+const SQLITE_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct SqliteImportOptions {
+    /// The tables to import; empty for all of them.
+    pub tables: Vec<String>,
+}
+
+/// What the reader hands over: the start of a table, then its rows.
+enum Read {
+    Table(String),
+    Rows(Vec<(String, Document)>),
+}
+
+impl Database {
+    /// Copies the tables of the SQLite database at `path` into collections,
+    /// replacing documents with the same IDs. Answers how many rows each
+    /// table had.
+    pub async fn import_sqlite(
+        &self,
+        path: impl AsRef<Path>,
+        options: SqliteImportOptions,
+    ) -> Result<Vec<(String, u64)>, DatabaseError> {
+        self.check_writable()?;
+        let path = path.as_ref().to_path_buf();
+        let (sender, mut receiver) = mpsc::channel(2);
+        let reader = tokio::task::spawn_blocking(move || read_tables(&path, options, sender));
+
+        let mut imported: Vec<(String, u64)> = Vec::new();
+        while let Some(read) = receiver.recv().await {
+            match read {
+                Read::Table(table) => imported.push((table, 0)),
+                Read::Rows(rows) => {
+                    let Some((table, count)) = imported.last_mut() else {
+                        continue;
+                    };
+                    *count += rows.len() as u64;
+                    let records = rows
+                        .into_iter()
+                        .map(|(id, doc)| WalRecord::Insert {
+                            collection: table.clone(),
+                            id,
+                            doc,
+                        })
+                        .collect();
+                    self.apply_records(records, None, None).await?;
+                }
+            }
+        }
+        reader
+            .await
+            .map_err(|e| DatabaseError::IoError(std::io::Error::other(e)))?
+            .map_err(to_error)?;
+
+        for (table, count) in &imported {
+            info!("Imported {} rows from SQLite table '{}'", count, table);
+        }
+        Ok(imported)
+    }
+}
+
+fn to_error(e: rusqlite::Error) -> DatabaseError {
+    DatabaseError::IoError(std::io::Error::other(e))
+}
+
+fn read_tables(
+    path: &Path,
+    options: SqliteImportOptions,
+    sender: mpsc::Sender<Read>,
+) -> Result<(), rusqlite::Error> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let tables = match options.tables.is_empty() {
+        true => connection
+            .prepare(
+                "SELECT name FROM sqlite_master \
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?,
+        false => options.tables,
+    };
+
+    for table in tables {
+        // (name, declared type, position in the primary key)
+        let columns = connection
+            .prepare(&format!("PRAGMA table_info({})", quote(&table)))?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?.to_ascii_uppercase(),
+                    row.get::<_, i64>(5)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        if columns.is_empty() {
+            return Err(rusqlite::Error::InvalidParameterName(format!(
+                "no table '{}'",
+                table
+            )));
+        }
+        let key = match columns.iter().filter(|(_, _, pk)| *pk > 0).count() {
+            1 => columns.iter().position(|(_, _, pk)| *pk > 0),
+            _ => None,
+        };
+        if sender.blocking_send(Read::Table(table.clone())).is_err() {
+            return Ok(());
+        }
+
+        let mut statement = connection.prepare(&format!("SELECT * FROM {}", quote(&table)))?;
+        let mut rows = statement.query([])?;
+        let mut batch = Vec::with_capacity(SQLITE_BATCH);
+        while let Some(row) = rows.next()? {
+            let mut id = None;
+            let mut doc = Document::new();
+            for (i, (name, declared, _)) in columns.iter().enumerate() {
+                let value = to_bson(row.get_ref(i)?, declared);
+                match key == Some(i) {
+                    true => id = Some(id_of(value)),
+                    false => {
+                        doc.insert(name, value);
+                    }
+                }
+            }
+            let id = id.unwrap_or_else(|| bson::oid::ObjectId::new().to_hex());
+            batch.push((id, doc));
+            if batch.len() == SQLITE_BATCH {
+                let rows = std::mem::replace(&mut batch, Vec::with_capacity(SQLITE_BATCH));
+                if sender.blocking_send(Read::Rows(rows)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        if !batch.is_empty() && sender.blocking_send(Read::Rows(batch)).is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn id_of(value: Bson) -> String {
+    match value {
+        Bson::String(s) => s,
+        Bson::Int32(n) => n.to_string(),
+        Bson::Int64(n) => n.to_string(),
+        value => value.into_relaxed_extjson().to_string(),
+    }
+}
+
+fn to_bson(value: ValueRef<'_>, declared: &str) -> Bson {
+    let dated = ["DATE", "TIME"].iter().any(|kind| declared.contains(kind));
+    match value {
+        ValueRef::Null => Bson::Null,
+        ValueRef::Integer(n) if declared.starts_with("BOOL") => Bson::Boolean(n != 0),
+        ValueRef::Integer(n) if dated => Bson::DateTime(bson::DateTime::from_millis(n * 1000)),
+        ValueRef::Integer(n) => match i32::try_from(n) {
+            Ok(n) => Bson::Int32(n),
+            Err(_) => Bson::Int64(n),
+        },
+        ValueRef::Real(n) => Bson::Double(n),
+        ValueRef::Text(text) => {
+            let text = String::from_utf8_lossy(text).into_owned();
+            match dated.then(|| parse_date(&text)).flatten() {
+                Some(date) => Bson::DateTime(date),
+                None => Bson::String(text),
+            }
+        }
+        ValueRef::Blob(bytes) => Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: bytes.to_vec(),
+        }),
+    }
+}
+
+/// RFC 3339, or SQLite's own `YYYY-MM-DD HH:MM:SS` and `YYYY-MM-DD`, in UTC.
+fn parse_date(text: &str) -> Option<bson::DateTime> {
+    let rfc3339 = match text.len() {
+        10 => format!("{}T00:00:00Z", text),
+        19 => format!("{}Z", text.replacen(' ', "T", 1)),
+        _ => text.replacen(' ', "T", 1),
+    };
+    bson::DateTime::parse_rfc3339_str(&rfc3339).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_import_sqlite() {
+        let db = Database::init_test("data_tests".to_string(), "test_sqlite".to_string()).await;
+        db.clear().await.unwrap();
+        let path = "data_tests/test_sqlite.db";
+        let _ = std::fs::remove_file(path);
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, active BOOLEAN, \
+                     joined DATETIME, score REAL, avatar BLOB, big INTEGER);
+                 INSERT INTO users VALUES
+                     (1, 'Ada', 1, '2024-05-01 12:00:00', 9.5, x'0102', 5000000000),
+                     (2, 'Bob', 0, NULL, NULL, NULL, 7);
+                 CREATE TABLE tags (user INTEGER, tag TEXT, PRIMARY KEY (user, tag));
+                 INSERT INTO tags VALUES (1, 'math');",
+            )
+            .unwrap();
+        drop(connection);
+
+        let imported = db
+            .import_sqlite(path, SqliteImportOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            imported,
+            [("tags".to_string(), 1), ("users".to_string(), 2)]
+        );
+
+        let joined = bson::DateTime::parse_rfc3339_str("2024-05-01T12:00:00Z").unwrap();
+        assert_eq!(
+            db.find_one("users".to_string(), "1".to_string())
+                .await
+                .unwrap(),
+            Some(bson::doc! {
+                "name": "Ada",
+                "active": true,
+                "joined": joined,
+                "score": 9.5,
+                "avatar": Binary { subtype: BinarySubtype::Generic, bytes: vec![1, 2] },
+                "big": 5_000_000_000i64,
+            })
+        );
+        let tags = db.find("tags".to_string(), bson::doc! {}).await.unwrap();
+        assert_eq!(tags, [bson::doc! { "user": 1, "tag": "math" }]);
+
+        let missing = SqliteImportOptions {
+            tables: vec!["nope".to_string()],
+        };
+        assert!(db.import_sqlite(path, missing).await.is_err());
+    }
+}
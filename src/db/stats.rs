@@ -0,0 +1,319 @@
+use std::path::Path;
+use std::time::Duration;
+
+use bson::DateTime;
+
+use super::defrag::list_collections;
+use super::{wal, BackupStats, Codec, Database, DatabaseError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionStats {
+    pub name: String,
+    /// Documents in the collection's directory; inserts still in the write
+    /// buffer are counted in `DatabaseStats::wal_pending_writes` instead.
+    pub documents: u64,
+    pub size_bytes: u64,
+    /// Roughly what its indexes take in memory: their field names and the
+    /// IDs under them. Indexes aren't stored, so a database just opened has
+    /// none.
+    pub index_bytes: u64,
+    /// Its biggest document files, largest first, at most
+    /// `LARGEST_DOCUMENTS`.
+    pub largest_documents: Vec<DocumentSize>,
+}
+
+/// How many of each collection's biggest documents `Database::stats` names.
+pub const LARGEST_DOCUMENTS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentSize {
+    pub id: String,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseStats {
+    pub read_only: bool,
+    pub collections: Vec<CollectionStats>,
+    /// Every file under the database folder, logs and versions included.
+    pub disk_usage_bytes: u64,
+    /// Writes made durable in the write-ahead log but not yet in their
+    /// document files.
+    pub wal_pending_writes: usize,
+    /// Write-ahead logs not yet retired, counting the one being appended to.
+    pub wal_files: usize,
+    /// With an oplog.
+    pub oplog: Option<OplogStats>,
+    /// Once a backup scheduler is spawned.
+    pub backups: Option<BackupStats>,
+}
+
+/// How far back the oplog reaches, and how far behind it each follower is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OplogStats {
+    pub first: u64,
+    pub next: u64,
+    /// The age of the oldest entry held; a follower further behind than
+    /// this has to copy everything again.
+    pub window: Duration,
+    pub followers: Vec<FollowerLag>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FollowerLag {
+    /// The name it replicates under, or its address.
+    pub name: String,
+    pub acknowledged: u64,
+    /// Entries written but not yet applied there.
+    pub operations: u64,
+    /// How long the oldest of those has waited; zero when caught up.
+    pub behind: Duration,
+}
+
+impl Database {
+    /// Names of the collections, internal ones included, sorted.
+    pub async fn collections(&self) -> Result<Vec<String>, DatabaseError> {
+        let mut names = list_collections(&self.inner.folder_path).await?;
+        names.sort();
+        Ok(names)
+    }
+
+    /// Counts documents and bytes by walking the database folder, so it
+    /// costs a directory scan per collection.
+    pub async fn stats(&self) -> Result<DatabaseStats, DatabaseError> {
+        let folder_path = &self.inner.folder_path;
+
+        let mut collections = Vec::new();
+        for name in list_collections(folder_path).await? {
+            let usage = directory_usage(&Path::new(folder_path).join(&name)).await?;
+            collections.push(CollectionStats {
+                index_bytes: self.index_bytes(&name),
+                name,
+                documents: usage.documents,
+                size_bytes: usage.bytes,
+                largest_documents: usage.largest,
+            });
+        }
+        collections.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let wal_pending_writes = match &self.inner.write_buffer {
+            Some(write_buffer) => write_buffer.pending_count().await,
+            None => 0,
+        };
+
+        let oplog = match &self.inner.oplog {
+            Some(_) => Some(self.oplog_stats().await?),
+            None => None,
+        };
+
+        Ok(DatabaseStats {
+            read_only: self.inner.read_only,
+            collections,
+            disk_usage_bytes: self.disk_usage().await?,
+            wal_pending_writes,
+            wal_files: wal::list_logs(folder_path).await?.len(),
+            oplog,
+            backups: self.scheduled_backup_stats(),
+        })
+    }
+
+    async fn oplog_stats(&self) -> Result<OplogStats, DatabaseError> {
+        let range = self.oplog_range().await?;
+        let window = self.oplog_age(range.start).await?;
+
+        let mut followers: Vec<_> = self
+            .oplog()?
+            .followers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, acknowledged)| (name.clone(), *acknowledged))
+            .collect();
+        followers.sort();
+        let mut lags = Vec::with_capacity(followers.len());
+        for (name, acknowledged) in followers {
+            // One that fell off waited at least as long as the oldest entry.
+            let oldest = (acknowledged + 1).max(range.start);
+            lags.push(FollowerLag {
+                name,
+                acknowledged,
+                operations: range.end.saturating_sub(acknowledged + 1),
+                behind: self.oplog_age(oldest).await?,
+            });
+        }
+
+        Ok(OplogStats {
+            first: range.start,
+            next: range.end,
+            window,
+            followers: lags,
+        })
+    }
+
+    /// How long ago the entry at `position` was written; zero if there is
+    /// none yet.
+    async fn oplog_age(&self, position: u64) -> Result<Duration, DatabaseError> {
+        let entries = match self.read_oplog(position, 1).await {
+            // Trimmed meanwhile.
+            Err(DatabaseError::OplogTruncated(_)) => return Ok(Duration::ZERO),
+            entries => entries?,
+        };
+        Ok(entries.first().map_or(Duration::ZERO, |entry| {
+            let millis = DateTime::now().timestamp_millis() - entry.timestamp.timestamp_millis();
+            Duration::from_millis(millis.max(0) as u64)
+        }))
+    }
+
+    /// Bytes of every file under the database folder, as in
+    /// `DatabaseStats::disk_usage_bytes`, without the per-collection scans.
+    pub async fn disk_usage(&self) -> Result<u64, DatabaseError> {
+        Ok(directory_usage(Path::new(&self.inner.folder_path))
+            .await?
+            .bytes)
+    }
+
+    fn index_bytes(&self, collection: &str) -> u64 {
+        self.read_index().get(collection).map_or(0, |fields| {
+            fields
+                .iter()
+                .map(|(field, ids)| field.len() + ids.iter().map(String::len).sum::<usize>())
+                .sum::<usize>() as u64
+        })
+    }
+
+    /// Checks the database folder can still be read, for readiness probes.
+    pub async fn ping(&self) -> Result<(), DatabaseError> {
+        let _entries = tokio::fs::read_dir(&self.inner.folder_path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    /// Document files.
+    documents: u64,
+    /// Of every file.
+    bytes: u64,
+    largest: Vec<DocumentSize>,
+}
+
+/// What's under `path`.
+async fn directory_usage(path: &Path) -> Result<Usage, DatabaseError> {
+    let mut usage = Usage::default();
+    let mut pending = vec![path.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            // Removed while we were walking, e.g. by a defragmentation pass.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(DatabaseError::IoError(e)),
+        };
+
+        while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(DatabaseError::IoError(e)),
+            };
+
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                let path = entry.path();
+                usage.bytes += metadata.len();
+                if Codec::of_file(&path).is_some() {
+                    usage.documents += 1;
+                    let id = path.file_stem().unwrap_or_default().to_string_lossy();
+                    usage.keep_if_largest(&id, metadata.len());
+                }
+            }
+        }
+    }
+
+    Ok(usage)
+}
+
+impl Usage {
+    fn keep_if_largest(&mut self, id: &str, size_bytes: u64) {
+        let at = self
+            .largest
+            .partition_point(|kept| kept.size_bytes >= size_bytes);
+        if at < LARGEST_DOCUMENTS {
+            self.largest.insert(
+                at,
+                DocumentSize {
+                    id: id.to_string(),
+                    size_bytes,
+                },
+            );
+            self.largest.truncate(LARGEST_DOCUMENTS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DatabaseOptions, WriteBufferOptions};
+
+    #[tokio::test]
+    async fn test_stats() {
+        let folder_path = "data_tests/test_stats".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            write_buffer: Some(WriteBufferOptions {
+                flush_interval: std::time::Duration::from_secs(3600),
+                ..WriteBufferOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        db.insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        db.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        db.ping().await.unwrap();
+
+        let stats = db.stats().await.unwrap();
+        assert_eq!(stats.wal_pending_writes, 2);
+        assert_eq!(stats.wal_files, 1);
+        assert!(stats.disk_usage_bytes > 0);
+        assert_eq!(db.disk_usage().await.unwrap(), stats.disk_usage_bytes);
+
+        db.flush().await.unwrap();
+        let stats = db.stats().await.unwrap();
+        assert_eq!(stats.wal_pending_writes, 0);
+        assert_eq!(
+            stats
+                .collections
+                .iter()
+                .map(|c| (c.name.as_str(), c.documents))
+                .collect::<Vec<_>>(),
+            vec![("users", 2)]
+        );
+        assert!(stats.collections[0].size_bytes > 0);
+        assert_eq!(stats.collections[0].index_bytes, 0);
+
+        let big = db
+            .insert_one("users".to_string(), bson::doc! { "bio": "x".repeat(1000) })
+            .await
+            .unwrap();
+        db.add_index("users".to_string(), "name".to_string());
+        db.insert_one("users".to_string(), bson::doc! { "name": "Ada" })
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+        let users = &db.stats().await.unwrap().collections[0];
+        assert_eq!(users.largest_documents.len(), 4);
+        assert_eq!(users.largest_documents[0].id, big);
+        assert!(users.index_bytes > 0);
+    }
+}
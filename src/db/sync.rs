@@ -0,0 +1,720 @@
+//! Two-way sync for offline-first deployments: an edge database takes
+//! writes while disconnected, then merges with a central one. Both keep an
+//! oplog; a sync reads what each side wrote since the last one, and makes
+//! every document touched on either side the same on both.
+//!
+//! A document written on both sides in between is a conflict. By default
+//! each field goes to the side that wrote it last, by oplog timestamp, and
+//! a delete beats the other side's writes only if it came after all of
+//! them. A `ConflictResolver::Custom` callback can decide instead.
+//!
+//! Where the last sync stopped is kept in the edge database's `_sync`
+//! collection, one document per peer. The first sync, or one whose oplog
+//! no longer reaches back that far, compares every document instead,
+//! letting the remote win conflicting fields and bringing back documents
+//! deleted on one side only. Internal collections aren't synced.
+//!
+//! A sync may also go one way only, the sending side's version winning,
+//! and cover only some collections or documents matching a query, as
+//! periodic replication jobs between two databases do.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use bson::{Bson, DateTime, Document};
+use log::{info, warn};
+
+use super::filter;
+use super::wal::WalRecord;
+use super::{Database, DatabaseError, OperationType};
+
+/// Where each peer's checkpoint is kept.
+pub const SYNC_COLLECTION: &str = "_sync";
+
+/// Oplog entries read at a time.
+const SYNC_BATCH: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Names the remote among those this database syncs with, to keep
+    /// each one's checkpoint apart.
+    pub peer: String,
+    pub direction: SyncDirection,
+    pub filter: SyncFilter,
+    /// Only used syncing both ways.
+    pub resolver: ConflictResolver,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            peer: "central".to_string(),
+            direction: SyncDirection::default(),
+            filter: SyncFilter::default(),
+            resolver: ConflictResolver::default(),
+        }
+    }
+}
+
+impl SyncOptions {
+    /// Where the checkpoint is kept: one per peer and filter, so a sync
+    /// over some documents doesn't skip others' changes for the next.
+    fn checkpoint_id(&self) -> String {
+        // Peers may be paths, but IDs name files.
+        let peer = self.peer.replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_',
+            "_",
+        );
+        if self.filter == SyncFilter::default() {
+            return peer;
+        }
+        let mut bytes = Vec::new();
+        let filter = bson::doc! {
+            "collections": self.filter.collections.clone(),
+            "query": self.filter.query.clone(),
+        };
+        // A document of strings and a document always serializes.
+        let _ = filter.to_writer(&mut bytes);
+        format!("{}_{:08x}", peer, crc32fast::hash(&bytes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Only this database's changes go to the remote, overwriting its own.
+    Push,
+    /// Only the remote's changes come here, overwriting this database's.
+    Pull,
+    /// Changes go both ways, conflicts settled by the resolver.
+    #[default]
+    Both,
+}
+
+/// Which documents a sync covers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncFilter {
+    /// Empty for every collection but the internal ones.
+    pub collections: Vec<String>,
+    /// A query, as `find` takes, either side's version of a document must
+    /// match.
+    pub query: Document,
+}
+
+impl SyncFilter {
+    fn includes(&self, collection: &str) -> bool {
+        match self.collections.is_empty() {
+            true => !collection.starts_with('_'),
+            false => self
+                .collections
+                .iter()
+                .any(|included| included == collection),
+        }
+    }
+
+    fn matches(&self, doc: Option<&Document>) -> bool {
+        doc.is_some_and(|doc| filter::matches(doc, &self.query))
+    }
+}
+
+/// Answers the document both sides should hold, or `None` to delete it.
+pub type MergeFn = Arc<dyn Fn(&Conflict) -> Option<Document> + Send + Sync>;
+
+/// How a document written on both sides is settled.
+#[derive(Clone, Default)]
+pub enum ConflictResolver {
+    /// Field by field, the latest write wins.
+    #[default]
+    LastWriteWins,
+    /// Settled by a callback.
+    Custom(MergeFn),
+}
+
+impl fmt::Debug for ConflictResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictResolver::LastWriteWins => f.write_str("LastWriteWins"),
+            ConflictResolver::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// A document written on both sides since the last sync, as each side
+/// holds it now; `None` where it's been deleted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub collection: String,
+    pub id: String,
+    pub local: Option<Document>,
+    pub remote: Option<Document>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncResult {
+    /// Documents written to the remote.
+    pub pushed: usize,
+    /// Documents written here.
+    pub pulled: usize,
+    /// Documents written on both sides, settled by the resolver.
+    pub conflicts: usize,
+}
+
+/// What one side did to a document since the last sync.
+#[derive(Debug, Default)]
+struct Changes {
+    /// When each top-level field last changed.
+    fields: HashMap<String, DateTime>,
+    /// When the document was deleted, if that's how it was left.
+    deleted: Option<DateTime>,
+}
+
+impl Changes {
+    fn latest(&self) -> Option<DateTime> {
+        self.fields.values().copied().chain(self.deleted).max()
+    }
+}
+
+type Key = (String, String);
+
+impl Database {
+    /// Merges this database and `remote` as the module docs describe, in
+    /// the direction and over the documents `options` choose. Both need an
+    /// oplog, or only this one to push and only the remote to pull.
+    pub async fn sync(
+        &self,
+        remote: &Database,
+        options: SyncOptions,
+    ) -> Result<SyncResult, DatabaseError> {
+        self.check_writable()?;
+        filter::validate(&options.filter.query)?;
+        let pushing = options.direction != SyncDirection::Pull;
+        let pulling = options.direction != SyncDirection::Push;
+        let checkpoint_id = options.checkpoint_id();
+        let checkpoint = self
+            .find_one(SYNC_COLLECTION.to_string(), checkpoint_id.clone())
+            .await?
+            .unwrap_or_default();
+        let synced = |field| {
+            checkpoint
+                .get_i64(field)
+                .ok()
+                .map(|position| position as u64)
+        };
+        let (mut pushed, mut pulled) = (synced("pushed"), synced("pulled"));
+
+        let mut local_changes = HashMap::new();
+        if pushing {
+            let end = self.oplog_range().await?.end;
+            local_changes = self.changes_since(pushed, end, &options.filter).await?;
+            pushed = Some(end - 1);
+        }
+        let mut remote_changes = HashMap::new();
+        if pulling {
+            let end = remote.oplog_range().await?.end;
+            remote_changes = remote.changes_since(pulled, end, &options.filter).await?;
+            pulled = Some(end - 1);
+        }
+
+        let mut keys: Vec<&Key> = local_changes.keys().chain(remote_changes.keys()).collect();
+        keys.sort();
+        keys.dedup();
+
+        let none = Changes::default();
+        let mut result = SyncResult::default();
+        for key in keys {
+            let (collection, id) = key;
+            let local = self.find_synced(collection, id).await?;
+            let remote_doc = remote.find_synced(collection, id).await?;
+            if !options.filter.matches(local.as_ref())
+                && !options.filter.matches(remote_doc.as_ref())
+            {
+                continue;
+            }
+            let local_change = local_changes.get(key);
+            let remote_change = remote_changes.get(key);
+
+            let merged = match (&options.direction, &options.resolver) {
+                (SyncDirection::Push, _) => local.clone(),
+                (SyncDirection::Pull, _) => remote_doc.clone(),
+                (SyncDirection::Both, ConflictResolver::Custom(resolve))
+                    if local_change.is_some() && remote_change.is_some() =>
+                {
+                    result.conflicts += 1;
+                    resolve(&Conflict {
+                        collection: collection.clone(),
+                        id: id.clone(),
+                        local: local.clone(),
+                        remote: remote_doc.clone(),
+                    })
+                }
+                (SyncDirection::Both, _) => {
+                    if local_change.is_some() && remote_change.is_some() {
+                        result.conflicts += 1;
+                    }
+                    merge(
+                        local.as_ref(),
+                        remote_doc.as_ref(),
+                        local_change.unwrap_or(&none),
+                        remote_change.unwrap_or(&none),
+                    )
+                }
+            };
+
+            if pulling && merged != local {
+                self.put_synced(collection, id, merged.clone()).await?;
+                result.pulled += 1;
+            }
+            if pushing && merged != remote_doc {
+                remote.put_synced(collection, id, merged).await?;
+                result.pushed += 1;
+            }
+        }
+
+        // Writes made above come round again next time, but match by then.
+        let mut checkpoint = Document::new();
+        for (field, position) in [("pushed", pushed), ("pulled", pulled)] {
+            if let Some(position) = position {
+                checkpoint.insert(field, position as i64);
+            }
+        }
+        self.put_synced(SYNC_COLLECTION, &checkpoint_id, Some(checkpoint))
+            .await?;
+
+        info!(
+            "Synced with '{}': pushed {}, pulled {}, {} conflicts",
+            options.peer, result.pushed, result.pulled, result.conflicts
+        );
+        Ok(result)
+    }
+
+    /// Sends what changed here since the last sync with `remote` to it,
+    /// takes what changed there, or both, limited to the documents `filter`
+    /// lets through. Checkpoints are kept per remote folder and filter.
+    pub async fn sync_with(
+        &self,
+        remote: &Database,
+        direction: SyncDirection,
+        filter: SyncFilter,
+    ) -> Result<SyncResult, DatabaseError> {
+        let options = SyncOptions {
+            peer: remote.inner.folder_path.clone(),
+            direction,
+            filter,
+            ..SyncOptions::default()
+        };
+        self.sync(remote, options).await
+    }
+
+    /// What changed here after oplog position `since`, up to `end`; every
+    /// document when `since` is `None` or the oplog no longer reaches it.
+    async fn changes_since(
+        &self,
+        since: Option<u64>,
+        end: u64,
+        filter: &SyncFilter,
+    ) -> Result<HashMap<Key, Changes>, DatabaseError> {
+        let Some(since) = since else {
+            return self.every_document(filter).await;
+        };
+        match self.changes_between(since + 1, end, filter).await {
+            Err(DatabaseError::OplogTruncated(_)) => {
+                warn!(
+                    "The oplog of '{}' no longer reaches position {}, comparing every document",
+                    self.inner.folder_path,
+                    since + 1
+                );
+                self.every_document(filter).await
+            }
+            changes => changes,
+        }
+    }
+
+    /// What the oplog entries at positions `from..to` did to each document.
+    async fn changes_between(
+        &self,
+        mut from: u64,
+        to: u64,
+        filter: &SyncFilter,
+    ) -> Result<HashMap<Key, Changes>, DatabaseError> {
+        // Fails when `from` has been trimmed, even with nothing to read.
+        self.read_oplog(from, 0).await?;
+
+        let mut changes: HashMap<Key, Changes> = HashMap::new();
+        while from < to {
+            let entries = self
+                .read_oplog(from, SYNC_BATCH.min((to - from) as usize))
+                .await?;
+            let Some(last) = entries.last() else {
+                break;
+            };
+            from = last.position + 1;
+
+            for entry in entries {
+                if !filter.includes(&entry.collection) {
+                    continue;
+                }
+                let change = changes.entry((entry.collection, entry.id)).or_default();
+                if matches!(
+                    entry.operation,
+                    OperationType::Delete | OperationType::Expire
+                ) {
+                    change.deleted = Some(entry.timestamp);
+                    continue;
+                }
+                change.deleted = None;
+                let empty = Document::new();
+                let previous = entry.previous.as_ref().unwrap_or(&empty);
+                let document = entry.document.as_ref().unwrap_or(&empty);
+                for field in previous.keys().chain(document.keys()) {
+                    if previous.get(field) != document.get(field) {
+                        change.fields.insert(field.clone(), entry.timestamp);
+                    }
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Every document, as if each field had been written at the epoch.
+    async fn every_document(
+        &self,
+        filter: &SyncFilter,
+    ) -> Result<HashMap<Key, Changes>, DatabaseError> {
+        let epoch = DateTime::from_millis(0);
+        let mut changes = HashMap::new();
+        for collection in self.collections().await? {
+            if !filter.includes(&collection) {
+                continue;
+            }
+            for (id, doc) in self
+                .find_with_ids(collection.clone(), Document::new())
+                .await?
+            {
+                let fields = doc.keys().map(|field| (field.clone(), epoch)).collect();
+                changes.insert(
+                    (collection.clone(), id),
+                    Changes {
+                        fields,
+                        deleted: None,
+                    },
+                );
+            }
+        }
+        Ok(changes)
+    }
+
+    async fn find_synced(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> Result<Option<Document>, DatabaseError> {
+        match self.find_one(collection.to_string(), id.to_string()).await {
+            // A collection this side never had.
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            found => found,
+        }
+    }
+
+    /// Writes `document` under `id`, or deletes it when `None`.
+    async fn put_synced(
+        &self,
+        collection: &str,
+        id: &str,
+        document: Option<Document>,
+    ) -> Result<(), DatabaseError> {
+        self.check_writable()?;
+        let (collection, id) = (collection.to_string(), id.to_string());
+        let record = match document {
+            Some(doc) => WalRecord::Insert {
+                collection,
+                id,
+                doc,
+            },
+            None => WalRecord::Delete { collection, id },
+        };
+        self.apply_records(vec![record], None, None).await
+    }
+}
+
+/// The document both sides should hold, given how each holds it now and
+/// what each did to it: field by field the later write wins, the remote
+/// on ties.
+fn merge(
+    local: Option<&Document>,
+    remote: Option<&Document>,
+    local_changes: &Changes,
+    remote_changes: &Changes,
+) -> Option<Document> {
+    let deleted_after = |deleted: Option<DateTime>, other: &Changes| match deleted {
+        Some(deleted) => other.latest().is_none_or(|latest| deleted >= latest),
+        None => false,
+    };
+
+    match (local, remote) {
+        (None, None) => None,
+        (Some(local), None) => match deleted_after(remote_changes.deleted, local_changes) {
+            true => None,
+            false => Some(local.clone()),
+        },
+        (None, Some(remote)) => match deleted_after(local_changes.deleted, remote_changes) {
+            // A remote delete wins ties, but not a local one.
+            true if local_changes.latest() > remote_changes.latest() => None,
+            _ => Some(remote.clone()),
+        },
+        (Some(local), Some(remote)) => {
+            // Fields the local side wrote last, `None` where it removed them.
+            let mut won: HashMap<&str, Option<&Bson>> = local_changes
+                .fields
+                .iter()
+                .filter(|(field, written)| {
+                    remote_changes
+                        .fields
+                        .get(*field)
+                        .is_none_or(|remote_written| *written > remote_written)
+                })
+                .map(|(field, _)| (field.as_str(), local.get(field)))
+                .collect();
+
+            // In the remote's order, then fields only the local side has.
+            let mut merged = Document::new();
+            for (field, value) in remote {
+                match won.remove(field.as_str()) {
+                    Some(Some(value)) => merged.insert(field, value.clone()),
+                    Some(None) => None,
+                    None => merged.insert(field, value.clone()),
+                };
+            }
+            for (field, value) in won.into_iter().filter_map(|(f, v)| Some((f, v?))) {
+                merged.insert(field, value.clone());
+            }
+            Some(merged)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DatabaseOptions, OplogOptions};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sync() {
+        let mut opened = Vec::new();
+        for name in ["edge", "central"] {
+            let folder_path = format!("data_tests/test_sync_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions::default()),
+                ..DatabaseOptions::default()
+            };
+            opened.push(
+                Database::init_with_options(folder_path, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let central = opened.pop().unwrap();
+        let edge = opened.pop().unwrap();
+        let users = || "users".to_string();
+
+        // The first sync copies both ways.
+        let john = central
+            .insert_one(users(), bson::doc! { "name": "John", "age": 30 })
+            .await
+            .unwrap();
+        let jane = edge
+            .insert_one(users(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        let result = edge.sync(&central, SyncOptions::default()).await.unwrap();
+        assert_eq!((result.pushed, result.pulled), (1, 1));
+        assert_eq!(
+            edge.find_one(users(), john.clone()).await.unwrap(),
+            Some(bson::doc! { "name": "John", "age": 30 })
+        );
+
+        // Nothing changed, nothing to do, despite the writes the sync made.
+        let result = edge.sync(&central, SyncOptions::default()).await.unwrap();
+        assert_eq!(result, SyncResult::default());
+
+        // Both sides write John while apart: fields merge, the later wins.
+        edge.update_one(users(), john.clone(), bson::doc! { "$set": { "age": 31 } })
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        central
+            .update_one(
+                users(),
+                john.clone(),
+                bson::doc! { "$set": { "age": 32, "city": "Oslo" } },
+            )
+            .await
+            .unwrap();
+        edge.update_one(
+            users(),
+            john.clone(),
+            bson::doc! { "$set": { "name": "Johnny" } },
+        )
+        .await
+        .unwrap();
+        edge.delete_one(users(), jane.clone()).await.unwrap();
+        let result = edge.sync(&central, SyncOptions::default()).await.unwrap();
+        assert_eq!(result.conflicts, 1);
+        let merged = Some(bson::doc! { "name": "Johnny", "age": 32, "city": "Oslo" });
+        assert_eq!(edge.find_one(users(), john.clone()).await.unwrap(), merged);
+        assert_eq!(
+            central.find_one(users(), john.clone()).await.unwrap(),
+            merged
+        );
+        assert_eq!(central.find_one(users(), jane).await.unwrap(), None);
+
+        // A custom resolver decides conflicts instead.
+        edge.update_one(users(), john.clone(), bson::doc! { "$inc": { "age": 1 } })
+            .await
+            .unwrap();
+        central
+            .update_one(users(), john.clone(), bson::doc! { "$inc": { "age": 2 } })
+            .await
+            .unwrap();
+        let options = SyncOptions {
+            resolver: ConflictResolver::Custom(Arc::new(|conflict: &Conflict| {
+                let age = |doc: &Option<Document>| doc.as_ref().unwrap().get_i32("age").unwrap();
+                let mut doc = conflict.remote.clone().unwrap();
+                doc.insert("age", age(&conflict.local).max(age(&conflict.remote)) + 10);
+                Some(doc)
+            })),
+            ..SyncOptions::default()
+        };
+        let result = edge.sync(&central, options).await.unwrap();
+        assert_eq!((result.conflicts, result.pushed, result.pulled), (1, 1, 1));
+
+        assert_eq!(
+            central
+                .find_one(users(), john)
+                .await
+                .unwrap()
+                .unwrap()
+                .get_i32("age"),
+            Ok(44)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_with() {
+        let mut opened = Vec::new();
+        for name in ["source", "target"] {
+            let folder_path = format!("data_tests/test_sync_with_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions::default()),
+                ..DatabaseOptions::default()
+            };
+            opened.push(
+                Database::init_with_options(folder_path, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let target = opened.pop().unwrap();
+        let source = opened.pop().unwrap();
+        let orders = || "orders".to_string();
+        let paid = SyncFilter {
+            collections: vec![orders()],
+            query: bson::doc! { "paid": true },
+        };
+
+        let first = source
+            .insert_one(orders(), bson::doc! { "n": 1, "paid": true })
+            .await
+            .unwrap();
+        source
+            .insert_one(orders(), bson::doc! { "n": 2, "paid": false })
+            .await
+            .unwrap();
+        source
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let result = source
+            .sync_with(&target, SyncDirection::Push, paid.clone())
+            .await
+            .unwrap();
+        assert_eq!((result.pushed, result.pulled), (1, 0));
+        assert_eq!(
+            target.find(orders(), bson::doc! {}).await.unwrap(),
+            vec![bson::doc! { "n": 1, "paid": true }]
+        );
+
+        // Only what changed since goes, and pushing overwrites the target.
+        target
+            .update_one(orders(), first.clone(), bson::doc! { "$set": { "n": 10 } })
+            .await
+            .unwrap();
+        let result = source
+            .sync_with(&target, SyncDirection::Push, paid.clone())
+            .await
+            .unwrap();
+        assert_eq!(result, SyncResult::default());
+        source
+            .update_one(orders(), first.clone(), bson::doc! { "$set": { "n": 100 } })
+            .await
+            .unwrap();
+        let result = source
+            .sync_with(&target, SyncDirection::Push, paid)
+            .await
+            .unwrap();
+        assert_eq!(result.pushed, 1);
+        assert_eq!(
+            target.find_one(orders(), first.clone()).await.unwrap(),
+            Some(bson::doc! { "n": 100, "paid": true })
+        );
+
+        // Pulling everything keeps a checkpoint of its own, so first
+        // compares every document the target has.
+        target
+            .insert_one(orders(), bson::doc! { "n": 3 })
+            .await
+            .unwrap();
+        let result = source
+            .sync_with(&target, SyncDirection::Pull, SyncFilter::default())
+            .await
+            .unwrap();
+        assert_eq!((result.pushed, result.pulled), (0, 1));
+        target.delete_one(orders(), first.clone()).await.unwrap();
+        let result = source
+            .sync_with(&target, SyncDirection::Pull, SyncFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(result.pulled, 1);
+        assert_eq!(source.find_one(orders(), first).await.unwrap(), None);
+
+        // Queries take operators as `find` does.
+        let unpaid = SyncFilter {
+            collections: vec![orders()],
+            query: bson::doc! { "paid": { "$ne": true }, "n": { "$lt": 3 } },
+        };
+        let result = source
+            .sync_with(&target, SyncDirection::Push, unpaid)
+            .await
+            .unwrap();
+        assert_eq!(result.pushed, 1);
+        assert_eq!(
+            target
+                .find(orders(), bson::doc! { "n": 2 })
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let unknown = SyncFilter {
+            collections: vec![orders()],
+            query: bson::doc! { "n": { "$near": 2 } },
+        };
+        assert!(matches!(
+            source
+                .sync_with(&target, SyncDirection::Push, unknown)
+                .await,
+            Err(DatabaseError::InvalidQuery(_))
+        ));
+    }
+}
@@ -0,0 +1,1081 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bson::{Document, RawDocumentBuf};
+use log::info;
+
+use super::changes::Change;
+use super::filter;
+use super::update::apply_update;
+use super::wal::WalRecord;
+use super::{
+    check_name, Database, DatabaseError, FindOptions, OperationType, Snapshot, WriteStamp,
+};
+
+/// How strictly a transaction is isolated from concurrent ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Reads see the transaction's snapshot; only writes to the same
+    /// document conflict.
+    #[default]
+    Snapshot,
+    /// Commit also fails if a document the transaction read, or one matching
+    /// a query it ran, changed since its snapshot. Checks made by reading
+    /// ("no user is named X yet") then still hold when the writes land.
+    Serializable,
+}
+
+/// Bounds on a single transaction. Going over one aborts the transaction
+/// with `DatabaseError::TransactionLimitExceeded`; `None` means unbounded.
+#[derive(Debug, Clone)]
+pub struct TransactionLimits {
+    /// How long the transaction may stay open, which is also how long it
+    /// holds its locks and keeps old versions from being collected. Checked
+    /// whenever the transaction is used.
+    pub max_duration: Option<Duration>,
+    pub max_writes: Option<usize>,
+    /// Total encoded size of the staged documents.
+    pub max_bytes: Option<usize>,
+}
+
+impl TransactionLimits {
+    pub fn unlimited() -> Self {
+        Self {
+            max_duration: None,
+            max_writes: None,
+            max_bytes: None,
+        }
+    }
+}
+
+impl Default for TransactionLimits {
+    fn default() -> Self {
+        Self {
+            max_duration: Some(Duration::from_secs(60)),
+            max_writes: Some(100_000),
+            max_bytes: Some(64 * 1024 * 1024),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransactionOptions {
+    pub isolation: IsolationLevel,
+    /// Overrides `DatabaseOptions::transaction_limits`.
+    pub limits: Option<TransactionLimits>,
+}
+
+/// Something a serializable transaction read, checked again on commit.
+pub(crate) enum Read {
+    Document { collection: String, id: String },
+    Query { collection: String, query: Document },
+}
+
+impl Read {
+    fn collection(&self) -> &str {
+        match self {
+            Read::Document { collection, .. } | Read::Query { collection, .. } => collection,
+        }
+    }
+}
+
+/// Writes across collections staged in memory and applied all together on
+/// `commit`, or dropped on `rollback`.
+///
+/// Documents the transaction updates or deletes stay locked until it ends,
+/// so other writers wait for it rather than overwrite its changes. Should
+/// one that doesn't wait, such as a delete by query, change one of them
+/// first, the commit fails with `WriteConflict`.
+pub struct Transaction {
+    db: Database,
+    /// Owner of the transaction's document locks.
+    owner: u64,
+    /// Set once a deadlock or write conflict has aborted the transaction.
+    aborted: bool,
+    writes: Vec<WalRecord>,
+    /// Encoded size of each staged write, parallel to `writes`.
+    write_sizes: Vec<usize>,
+    staged_bytes: usize,
+    /// Named positions in `writes`, oldest first.
+    savepoints: Vec<(String, usize)>,
+    snapshot: Snapshot,
+    isolation: IsolationLevel,
+    limits: TransactionLimits,
+    started: Instant,
+    /// What the transaction read, when it is serializable.
+    reads: Mutex<Vec<Read>>,
+}
+
+impl Database {
+    pub async fn begin_transaction(&self) -> Transaction {
+        self.begin_transaction_with_options(TransactionOptions::default())
+            .await
+    }
+
+    /// Starts a transaction seeing every write that returned before the
+    /// call.
+    pub async fn begin_transaction_with_options(&self, options: TransactionOptions) -> Transaction {
+        Transaction {
+            db: self.clone(),
+            owner: self.inner.document_locks.new_owner(),
+            aborted: false,
+            writes: Vec::new(),
+            write_sizes: Vec::new(),
+            staged_bytes: 0,
+            savepoints: Vec::new(),
+            snapshot: self.inner.versions.snapshot_latest().await,
+            isolation: options.isolation,
+            limits: options
+                .limits
+                .unwrap_or_else(|| self.inner.transaction_limits.clone()),
+            started: Instant::now(),
+            reads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Makes `records` durable as a single log record, then applies them.
+    ///
+    /// Should the process die half way, replaying the log on the next start
+    /// finishes the job, so either all of the writes land or none do.
+    ///
+    /// With `reads`, first checks that nothing read or written changed after
+    /// the snapshot. The collections involved stay locked until the writes
+    /// land, so the check can't go stale in between.
+    async fn apply_atomically(
+        &self,
+        records: Vec<WalRecord>,
+        reads: Option<(&Snapshot, &[Read])>,
+    ) -> Result<(), DatabaseError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        self.check_writable()?;
+        self.apply_records(records, reads, None).await
+    }
+
+    /// `apply_atomically`, skipping the check that the database takes
+    /// writes, for replicated ones. Writes from another primary log its
+    /// `origin` stamp rather than a new one.
+    pub(crate) async fn apply_records(
+        &self,
+        records: Vec<WalRecord>,
+        reads: Option<(&Snapshot, &[Read])>,
+        origin: Option<&WriteStamp>,
+    ) -> Result<(), DatabaseError> {
+        for record in &records {
+            if let WalRecord::Insert { collection, id, .. } | WalRecord::Delete { collection, id } =
+                record
+            {
+                check_name(collection)?;
+                check_name(id)?;
+            }
+        }
+        let _guard = self.inner.activity.begin().await?;
+        let written = records.iter().filter_map(|record| match record {
+            WalRecord::Insert { collection, .. } | WalRecord::Delete { collection, .. } => {
+                Some(collection.as_str())
+            }
+            WalRecord::Batch(_) | WalRecord::Logged(_) => None,
+        });
+        let read = reads
+            .iter()
+            .flat_map(|(_, reads)| reads.iter().map(Read::collection));
+        let _locks = self.inner.locks.write_all(written.chain(read)).await;
+
+        // Buffered inserts must reach their files first, or the flusher
+        // could later overwrite what this batch writes.
+        self.flush().await?;
+
+        if let Some((snapshot, reads)) = reads {
+            self.validate_reads(snapshot.timestamp(), reads).await?;
+            self.validate_writes(snapshot.timestamp(), &records)?;
+        }
+
+        let commit = self.inner.versions.begin_commit();
+        let mut priors = Vec::with_capacity(records.len());
+        for record in &records {
+            if let WalRecord::Insert { collection, id, .. } | WalRecord::Delete { collection, id } =
+                record
+            {
+                let prior = self
+                    .read_document(collection, id, &FindOptions::default())
+                    .await?;
+                commit.record(collection, id, prior.clone());
+                priors.push(prior);
+            } else {
+                priors.push(None);
+            }
+        }
+
+        let changes: Vec<Change> = records
+            .iter()
+            .zip(&priors)
+            .filter_map(|(record, prior)| match record {
+                WalRecord::Insert {
+                    collection,
+                    id,
+                    doc,
+                } => Some(Change {
+                    operation: match prior {
+                        Some(_) => OperationType::Update,
+                        None => OperationType::Insert,
+                    },
+                    collection,
+                    id,
+                    previous: prior.as_ref(),
+                    document: Some(doc),
+                }),
+                WalRecord::Delete { collection, id } => prior.as_ref().map(|prior| Change {
+                    operation: OperationType::Delete,
+                    collection,
+                    id,
+                    previous: Some(prior),
+                    document: None,
+                }),
+                WalRecord::Batch(_) | WalRecord::Logged(_) => None,
+            })
+            .collect();
+
+        self.apply_logged(&records, &changes, origin).await
+    }
+
+    async fn validate_reads(&self, timestamp: u64, reads: &[Read]) -> Result<(), DatabaseError> {
+        for read in reads {
+            match read {
+                Read::Document { collection, id } => {
+                    if self
+                        .inner
+                        .versions
+                        .visible(timestamp, collection, id)
+                        .is_some()
+                    {
+                        return Err(DatabaseError::SerializationFailure(format!(
+                            "{}/{}",
+                            collection, id
+                        )));
+                    }
+                }
+                Read::Query { collection, query } => {
+                    for (id, prior) in self.inner.versions.changed_since(timestamp, collection) {
+                        let current = self
+                            .read_document(collection, &id, &FindOptions::default())
+                            .await?;
+                        if matches(prior.as_ref(), query)? || matches(current.as_ref(), query)? {
+                            return Err(DatabaseError::SerializationFailure(format!(
+                                "{}/{}",
+                                collection, id
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `WriteConflict` if a document about to be written changed
+    /// after `timestamp`. A transaction's document locks keep other writers
+    /// off what it writes, but not those that don't take them, such as
+    /// deletes by query; this stops its commit from undoing their writes.
+    fn validate_writes(&self, timestamp: u64, records: &[WalRecord]) -> Result<(), DatabaseError> {
+        for record in records {
+            if let WalRecord::Insert { collection, id, .. } | WalRecord::Delete { collection, id } =
+                record
+            {
+                if self
+                    .inner
+                    .versions
+                    .visible(timestamp, collection, id)
+                    .is_some()
+                {
+                    return Err(DatabaseError::WriteConflict(format!(
+                        "{}/{}",
+                        collection, id
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn matches(doc: Option<&Document>, query: &Document) -> Result<bool, DatabaseError> {
+    match doc {
+        Some(doc) => {
+            let raw = RawDocumentBuf::from_document(doc).map_err(DatabaseError::BsonRawError)?;
+            filter::matches_raw(&raw, query)
+        }
+        None => Ok(false),
+    }
+}
+
+impl Transaction {
+    pub async fn insert_one(
+        &mut self,
+        collection: String,
+        doc: Document,
+    ) -> Result<String, DatabaseError> {
+        self.check_active()?;
+        let doc = self
+            .db
+            .before_write(OperationType::Insert, &collection, doc)
+            .await?;
+
+        let id = bson::oid::ObjectId::new().to_string();
+
+        self.stage(WalRecord::Insert {
+            collection,
+            id: id.clone(),
+            doc,
+        })?;
+
+        Ok(id)
+    }
+
+    /// Stages an insert under `id`. Fails with `DocumentExists` if the
+    /// transaction sees a document there.
+    pub async fn insert_one_with_id(
+        &mut self,
+        collection: String,
+        id: String,
+        doc: Document,
+    ) -> Result<(), DatabaseError> {
+        self.lock_for_write(&collection, &id).await?;
+
+        if self
+            .find_one(collection.clone(), id.clone())
+            .await?
+            .is_some()
+        {
+            return Err(DatabaseError::DocumentExists(format!(
+                "{}/{}",
+                collection, id
+            )));
+        }
+
+        let doc = self
+            .db
+            .before_write(OperationType::Insert, &collection, doc)
+            .await?;
+        self.stage(WalRecord::Insert {
+            collection,
+            id,
+            doc,
+        })?;
+
+        Ok(())
+    }
+
+    /// Stages an update of the document as this transaction currently sees
+    /// it. Returns `false` if there is no such document.
+    pub async fn update_one(
+        &mut self,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<bool, DatabaseError> {
+        self.lock_for_write(&collection, &id).await?;
+
+        let doc = match self.find_one(collection.clone(), id.clone()).await? {
+            Some(doc) => doc,
+            None => return Ok(false),
+        };
+
+        let doc = self
+            .db
+            .before_write(
+                OperationType::Update,
+                &collection,
+                apply_update(&doc, &update)?,
+            )
+            .await?;
+        self.stage(WalRecord::Insert {
+            collection,
+            id,
+            doc,
+        })?;
+
+        Ok(true)
+    }
+
+    pub async fn delete_one(
+        &mut self,
+        collection: String,
+        id: String,
+    ) -> Result<bool, DatabaseError> {
+        self.lock_for_write(&collection, &id).await?;
+
+        if self
+            .find_one(collection.clone(), id.clone())
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+
+        self.stage(WalRecord::Delete { collection, id })?;
+
+        Ok(true)
+    }
+
+    /// Reads a document including the writes staged so far. Everything else
+    /// is read as of the moment the transaction began.
+    pub async fn find_one(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Document>, DatabaseError> {
+        self.check_active()?;
+        self.record_read(|| Read::Document {
+            collection: collection.clone(),
+            id: id.clone(),
+        });
+
+        for record in self.writes.iter().rev() {
+            match record {
+                WalRecord::Insert {
+                    collection: c,
+                    id: i,
+                    doc,
+                } if *c == collection && *i == id => return Ok(Some(doc.clone())),
+                WalRecord::Delete {
+                    collection: c,
+                    id: i,
+                } if *c == collection && *i == id => return Ok(None),
+                _ => {}
+            }
+        }
+
+        let options = FindOptions {
+            snapshot: Some(self.snapshot.clone()),
+            ..FindOptions::default()
+        };
+        self.db.find_one_with_options(collection, id, options).await
+    }
+
+    /// Marks the writes staged so far, so `rollback_to` can return here.
+    /// Reusing a name hides the older savepoint until this one is released
+    /// by rolling back past it.
+    pub fn savepoint(&mut self, name: &str) {
+        self.savepoints.push((name.to_string(), self.writes.len()));
+    }
+
+    /// Discards every write staged after the savepoint `name`, along with
+    /// any savepoints taken since. The savepoint itself stays usable.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), DatabaseError> {
+        let position = self
+            .savepoints
+            .iter()
+            .rposition(|(savepoint, _)| savepoint == name)
+            .ok_or_else(|| DatabaseError::SavepointNotFound(name.to_string()))?;
+
+        let staged = self.savepoints[position].1;
+        info!(
+            "Rolled back to savepoint '{}' discarding {} writes",
+            name,
+            self.writes.len() - staged
+        );
+
+        self.savepoints.truncate(position + 1);
+        self.writes.truncate(staged);
+        self.staged_bytes -= self.write_sizes.drain(staged..).sum::<usize>();
+
+        Ok(())
+    }
+
+    /// Finds documents as of the moment the transaction began, with the
+    /// writes staged so far applied on top.
+    pub async fn find(
+        &self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<Document>, DatabaseError> {
+        self.check_active()?;
+        self.record_read(|| Read::Query {
+            collection: collection.clone(),
+            query: query.clone(),
+        });
+
+        let mut staged: HashMap<&str, Option<&Document>> = HashMap::new();
+        for record in &self.writes {
+            match record {
+                WalRecord::Insert {
+                    collection: c,
+                    id,
+                    doc,
+                } if *c == collection => {
+                    staged.insert(id, Some(doc));
+                }
+                WalRecord::Delete { collection: c, id } if *c == collection => {
+                    staged.insert(id, None);
+                }
+                _ => {}
+            }
+        }
+
+        let options = FindOptions {
+            snapshot: Some(self.snapshot.clone()),
+            ..FindOptions::default()
+        };
+        let _guard = self.db.inner.activity.begin().await?;
+
+        let mut results = Vec::new();
+        for (id, raw) in self.db.scan_raw(&collection, &query, &options).await? {
+            if !staged.contains_key(id.as_str()) {
+                results.push(raw.to_document().map_err(DatabaseError::BsonRawError)?);
+            }
+        }
+        for doc in staged.into_values().flatten() {
+            if matches(Some(doc), &query)? {
+                results.push(doc.clone());
+            }
+        }
+
+        Ok(results)
+    }
+
+    pub async fn commit(mut self) -> Result<(), DatabaseError> {
+        self.check_active()?;
+        self.check_duration()?;
+
+        let writes = std::mem::take(&mut self.writes);
+        let count = writes.len();
+        let reads = std::mem::take(self.reads.get_mut().unwrap_or_else(|e| e.into_inner()));
+        // Only serializable transactions record reads; the writes of
+        // either kind are checked against the snapshot.
+        self.db
+            .apply_atomically(writes, Some((&self.snapshot, reads.as_slice())))
+            .await?;
+
+        info!("Successfully committed transaction with {} writes", count);
+
+        Ok(())
+    }
+
+    pub fn rollback(self) {
+        info!(
+            "Rolled back transaction discarding {} writes",
+            self.writes.len()
+        );
+    }
+
+    fn record_read(&self, read: impl FnOnce() -> Read) {
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(read());
+        }
+    }
+
+    fn check_active(&self) -> Result<(), DatabaseError> {
+        match self.aborted {
+            true => Err(DatabaseError::TransactionAborted),
+            false => Ok(()),
+        }
+    }
+
+    fn check_duration(&mut self) -> Result<(), DatabaseError> {
+        match self.limits.max_duration {
+            Some(max) if self.started.elapsed() > max => {
+                self.exceeded(format!("open for longer than {:?}", max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a write to the transaction, aborting it instead if that would
+    /// take it over its limits.
+    fn stage(&mut self, record: WalRecord) -> Result<(), DatabaseError> {
+        self.check_duration()?;
+
+        if let Some(max) = self.limits.max_writes {
+            if self.writes.len() >= max {
+                return self.exceeded(format!("more than {} writes", max));
+            }
+        }
+
+        let size = match &record {
+            WalRecord::Insert { doc, .. } => RawDocumentBuf::from_document(doc)
+                .map_err(DatabaseError::BsonRawError)?
+                .as_bytes()
+                .len(),
+            _ => 0,
+        };
+        if let Some(max) = self.limits.max_bytes {
+            if self.staged_bytes + size > max {
+                return self.exceeded(format!("more than {} bytes staged", max));
+            }
+        }
+
+        self.writes.push(record);
+        self.write_sizes.push(size);
+        self.staged_bytes += size;
+
+        Ok(())
+    }
+
+    fn exceeded(&mut self, limit: String) -> Result<(), DatabaseError> {
+        self.abort();
+        Err(DatabaseError::TransactionLimitExceeded(limit))
+    }
+
+    /// Locks a document for the rest of the transaction, aborting it if the
+    /// lock would deadlock or the document changed since the snapshot.
+    async fn lock_for_write(&mut self, collection: &str, id: &str) -> Result<(), DatabaseError> {
+        self.check_active()?;
+
+        let locked = self
+            .db
+            .inner
+            .document_locks
+            .acquire(self.owner, collection, id, self.db.inner.lock_timeout)
+            .await;
+        if let Err(e) = locked {
+            if matches!(e, DatabaseError::Deadlock(_)) {
+                self.abort();
+            }
+            return Err(e);
+        }
+
+        let timestamp = self.snapshot.timestamp();
+        if self
+            .db
+            .inner
+            .versions
+            .visible(timestamp, collection, id)
+            .is_some()
+        {
+            self.abort();
+            return Err(DatabaseError::WriteConflict(format!(
+                "{}/{}",
+                collection, id
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        info!(
+            "Aborted transaction discarding {} writes",
+            self.writes.len()
+        );
+
+        self.aborted = true;
+        self.writes.clear();
+        self.write_sizes.clear();
+        self.staged_bytes = 0;
+        self.savepoints.clear();
+        self.db.inner.document_locks.release_all(self.owner);
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.db.inner.document_locks.release_all(self.owner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::{self, WalWriter};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_commit_applies_all_writes() {
+        let db = Database::init_test("data_tests".to_string(), "test_txn_commit".to_string()).await;
+        db.clear().await.unwrap();
+
+        let existing = db
+            .insert_one(
+                "users".to_string(),
+                bson::doc! { "name": "John", "age": 30 },
+            )
+            .await
+            .unwrap();
+        let removed = db
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        let order = txn
+            .insert_one(
+                "orders".to_string(),
+                bson::doc! { "user": existing.clone() },
+            )
+            .await
+            .unwrap();
+        assert!(txn
+            .update_one(
+                "users".to_string(),
+                existing.clone(),
+                bson::doc! { "$inc": { "age": 1 } },
+            )
+            .await
+            .unwrap());
+        assert!(txn
+            .delete_one("users".to_string(), removed.clone())
+            .await
+            .unwrap());
+        assert!(txn
+            .find_one("users".to_string(), removed.clone())
+            .await
+            .unwrap()
+            .is_none());
+        txn.commit().await.unwrap();
+
+        let user = db
+            .find_one("users".to_string(), existing)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.get_i32("age").unwrap(), 31);
+        assert!(db
+            .find_one("orders".to_string(), order)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(db
+            .find_one("users".to_string(), removed)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(wal::list_logs(&db.inner.folder_path)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_conflict_aborts() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_txn_conflict".to_string()).await;
+        db.clear().await.unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        db.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "age": 1 } },
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            txn.update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$inc": { "age": 1 } }
+            )
+            .await,
+            Err(DatabaseError::WriteConflict(_))
+        ));
+        assert!(matches!(
+            txn.commit().await,
+            Err(DatabaseError::TransactionAborted)
+        ));
+
+        assert_eq!(
+            db.find_one("users".to_string(), id).await.unwrap(),
+            Some(bson::doc! { "age": 31 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commit_conflicts_with_delete_by_query() {
+        let db = Database::init_test(
+            "data_tests".to_string(),
+            "test_txn_delete_by_query".to_string(),
+        )
+        .await;
+        db.clear().await.unwrap();
+
+        let id = db
+            .insert_one(
+                "users".to_string(),
+                bson::doc! { "name": "John", "age": 30 },
+            )
+            .await
+            .unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        assert!(txn
+            .update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$inc": { "age": 1 } },
+            )
+            .await
+            .unwrap());
+        // Deletes by query don't wait for the transaction's locks.
+        let deleted = db
+            .delete("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        assert_eq!(deleted, vec![id.clone()]);
+
+        assert!(matches!(
+            txn.commit().await,
+            Err(DatabaseError::WriteConflict(_))
+        ));
+        assert!(db
+            .find_one("users".to_string(), id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serializable_prevents_write_skew() {
+        let db = Database::init_test(
+            "data_tests".to_string(),
+            "test_txn_serializable".to_string(),
+        )
+        .await;
+        db.clear().await.unwrap();
+        db.insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+
+        let options = TransactionOptions {
+            isolation: IsolationLevel::Serializable,
+            ..TransactionOptions::default()
+        };
+        let mut first = db.begin_transaction_with_options(options.clone()).await;
+        let mut second = db.begin_transaction_with_options(options).await;
+        for txn in [&mut first, &mut second] {
+            assert!(txn
+                .find("users".to_string(), bson::doc! { "name": "Jane" })
+                .await
+                .unwrap()
+                .is_empty());
+            txn.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+                .await
+                .unwrap();
+        }
+
+        first.commit().await.unwrap();
+        assert!(matches!(
+            second.commit().await,
+            Err(DatabaseError::SerializationFailure(_))
+        ));
+        assert_eq!(
+            db.find("users".to_string(), bson::doc! { "name": "Jane" })
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits_abort_transaction() {
+        let db = Database::init_test("data_tests".to_string(), "test_txn_limits".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut txn = db
+            .begin_transaction_with_options(TransactionOptions {
+                limits: Some(TransactionLimits {
+                    max_writes: Some(2),
+                    ..TransactionLimits::unlimited()
+                }),
+                ..TransactionOptions::default()
+            })
+            .await;
+        for _ in 0..2 {
+            txn.insert_one("users".to_string(), bson::doc! { "name": "John" })
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            txn.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+                .await,
+            Err(DatabaseError::TransactionLimitExceeded(_))
+        ));
+        assert!(matches!(
+            txn.commit().await,
+            Err(DatabaseError::TransactionAborted)
+        ));
+
+        let mut txn = db
+            .begin_transaction_with_options(TransactionOptions {
+                limits: Some(TransactionLimits {
+                    max_bytes: Some(64),
+                    ..TransactionLimits::unlimited()
+                }),
+                ..TransactionOptions::default()
+            })
+            .await;
+        assert!(matches!(
+            txn.insert_one("users".to_string(), bson::doc! { "bio": "x".repeat(100) })
+                .await,
+            Err(DatabaseError::TransactionLimitExceeded(_))
+        ));
+
+        let mut txn = db
+            .begin_transaction_with_options(TransactionOptions {
+                limits: Some(TransactionLimits {
+                    max_duration: Some(Duration::ZERO),
+                    ..TransactionLimits::unlimited()
+                }),
+                ..TransactionOptions::default()
+            })
+            .await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(matches!(
+            txn.insert_one("users".to_string(), bson::doc! { "name": "John" })
+                .await,
+            Err(DatabaseError::TransactionLimitExceeded(_))
+        ));
+
+        assert!(db
+            .find("users".to_string(), bson::doc! {})
+            .await
+            .unwrap_or_default()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_writers_wait_for_transaction() {
+        let db = Database::init_test("data_tests".to_string(), "test_txn_locks".to_string()).await;
+        db.clear().await.unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        txn.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "age": 1 } },
+        )
+        .await
+        .unwrap();
+
+        let writer = {
+            let db = db.clone();
+            let id = id.clone();
+            tokio::spawn(async move {
+                db.update_one("users".to_string(), id, bson::doc! { "$inc": { "age": 1 } })
+                    .await
+            })
+        };
+        tokio::task::yield_now().await;
+        txn.commit().await.unwrap();
+        writer.await.unwrap().unwrap();
+
+        assert_eq!(
+            db.find_one("users".to_string(), id).await.unwrap(),
+            Some(bson::doc! { "age": 32 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rollback_to_savepoint() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_txn_savepoint".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        let kept = txn
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        txn.savepoint("step");
+        let undone = txn
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        txn.update_one(
+            "users".to_string(),
+            kept.clone(),
+            bson::doc! { "$set": { "name": "Johnny" } },
+        )
+        .await
+        .unwrap();
+
+        txn.rollback_to("step").unwrap();
+        assert!(txn.rollback_to("missing").is_err());
+        txn.commit().await.unwrap();
+
+        assert_eq!(
+            db.find_one("users".to_string(), kept).await.unwrap(),
+            Some(bson::doc! { "name": "John" })
+        );
+        assert!(db
+            .find_one("users".to_string(), undone)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rollback_discards_writes() {
+        let db =
+            Database::init_test("data_tests".to_string(), "test_txn_rollback".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut txn = db.begin_transaction().await;
+        let id = txn
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        txn.rollback();
+
+        assert!(db
+            .find_one("users".to_string(), id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_committed_batch_is_replayed() {
+        let db = Database::init_test("data_tests".to_string(), "test_txn_replay".to_string()).await;
+        db.clear().await.unwrap();
+
+        let mut log = WalWriter::create(&db.inner.folder_path, 1, 0)
+            .await
+            .unwrap();
+        log.append(&[WalRecord::Batch(vec![
+            WalRecord::Insert {
+                collection: "users".to_string(),
+                id: "a".to_string(),
+                doc: bson::doc! { "name": "John" },
+            },
+            WalRecord::Insert {
+                collection: "orders".to_string(),
+                id: "b".to_string(),
+                doc: bson::doc! { "user": "a" },
+            },
+        ])])
+        .await
+        .unwrap();
+
+        let db = Database::init(db.inner.folder_path.clone()).await.unwrap();
+
+        assert!(db
+            .find_one("users".to_string(), "a".to_string())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(db
+            .find_one("orders".to_string(), "b".to_string())
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,201 @@
+//! Time-to-live: documents whose date field is older than a collection's
+//! `expire_after` are deleted by a sweep, either `expire_documents` or the
+//! monitor `spawn_ttl_monitor` starts. Such deletes are recorded as
+//! `OperationType::Expire`, and their change events carry the document as
+//! it was, so subscribers can tell them from deletes made by a client.
+//!
+//! Rules live in memory; they're set again each time the database opens.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use bson::Document;
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::{Database, DatabaseError, WriteConcern};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TtlRule {
+    pub collection: String,
+    /// A date field; documents without one never expire.
+    pub field: String,
+    pub expire_after: Duration,
+}
+
+impl TtlRule {
+    pub(crate) fn has_expired(&self, doc: &Document) -> bool {
+        let Ok(date) = doc.get_datetime(&self.field) else {
+            return false;
+        };
+        date.to_system_time()
+            .checked_add(self.expire_after)
+            .is_some_and(|expires| expires <= SystemTime::now())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TtlRules {
+    collections: RwLock<HashMap<String, TtlRule>>,
+}
+
+impl TtlRules {
+    fn all(&self) -> Vec<TtlRule> {
+        let collections = self.collections.read().unwrap_or_else(|e| e.into_inner());
+        collections.values().cloned().collect()
+    }
+}
+
+pub struct TtlHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl TtlHandle {
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("TTL monitor task failed: {}", e);
+        }
+    }
+}
+
+impl Database {
+    /// Expires `collection`'s documents once `field` is `expire_after` in
+    /// the past, replacing any rule set before.
+    pub fn set_ttl(&self, collection: &str, field: &str, expire_after: Duration) {
+        let rule = TtlRule {
+            collection: collection.to_string(),
+            field: field.to_string(),
+            expire_after,
+        };
+        let mut collections = self
+            .inner
+            .ttl
+            .collections
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        collections.insert(collection.to_string(), rule);
+    }
+
+    /// Stops expiring `collection`'s documents. Answers false if nothing
+    /// did.
+    pub fn remove_ttl(&self, collection: &str) -> bool {
+        let mut collections = self
+            .inner
+            .ttl
+            .collections
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        collections.remove(collection).is_some()
+    }
+
+    /// Deletes every document that has expired, once. Answers how many.
+    /// With a write coordinator they go through it, as plain deletes.
+    pub async fn expire_documents(&self) -> Result<usize, DatabaseError> {
+        self.check_writable()?;
+        let mut expired = 0;
+        for rule in self.inner.ttl.all() {
+            let found = match self
+                .find_with_ids(rule.collection.clone(), Document::new())
+                .await
+            {
+                Ok(found) => found,
+                // Nothing written to the collection yet.
+                Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            for (id, doc) in found {
+                if !rule.has_expired(&doc) {
+                    continue;
+                }
+                // Checked again under the lock, in case of an update since.
+                let removed = match &self.inner.coordinator {
+                    Some(coordinator) => {
+                        coordinator
+                            .delete(self, rule.collection.clone(), id)
+                            .await?;
+                        true
+                    }
+                    None => {
+                        self.remove_document(
+                            &rule.collection,
+                            &id,
+                            WriteConcern::default(),
+                            Some(&rule),
+                        )
+                        .await?
+                    }
+                };
+                expired += removed as usize;
+            }
+        }
+        if expired > 0 {
+            info!("Expired {} documents", expired);
+        }
+        Ok(expired)
+    }
+
+    /// Runs `expire_documents` every `interval` in the background.
+    pub fn spawn_ttl_monitor(&self, interval: Duration) -> TtlHandle {
+        let (shutdown, mut shutdown_rx) = watch::channel(false);
+        let db = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.changed() => break,
+                    _ = tokio::time::sleep(interval) => {}
+                }
+                if let Err(e) = db.expire_documents().await {
+                    warn!("Failed to expire documents: {:?}", e);
+                }
+            }
+        });
+        TtlHandle { shutdown, task }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::db::OperationType;
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let db = Database::init_test("data_tests".to_string(), "test_ttl".to_string()).await;
+        db.clear().await.unwrap();
+        db.set_ttl("sessions", "seen", Duration::from_secs(60));
+
+        let stale = bson::DateTime::from_system_time(SystemTime::now() - Duration::from_secs(120));
+        let old = db
+            .insert_one("sessions".to_string(), bson::doc! { "seen": stale })
+            .await
+            .unwrap();
+        let fresh = bson::doc! { "seen": bson::DateTime::now() };
+        db.insert_one("sessions".to_string(), fresh).await.unwrap();
+        db.insert_one("sessions".to_string(), bson::doc! { "user": "ada" })
+            .await
+            .unwrap();
+
+        let mut changes = db.watch("sessions");
+        assert_eq!(db.expire_documents().await.unwrap(), 1);
+        assert_eq!(
+            db.find_one("sessions".to_string(), old.clone())
+                .await
+                .unwrap(),
+            None
+        );
+
+        let event = changes.next().await.unwrap().unwrap();
+        assert_eq!(event.operation, OperationType::Expire);
+        assert_eq!(event.id, old);
+        assert_eq!(event.document.unwrap().get_datetime("seen"), Ok(&stale));
+        assert_eq!(db.expire_documents().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,124 @@
+use bson::{Bson, Document};
+
+use super::DatabaseError;
+
+/// Applies an update document to `doc`.
+///
+/// An update made only of operators (`$set`, `$unset`, `$inc`) modifies the
+/// named fields; anything else replaces the document wholesale.
+pub(crate) fn apply_update(doc: &Document, update: &Document) -> Result<Document, DatabaseError> {
+    let operators = update.keys().filter(|key| key.starts_with('$')).count();
+
+    if operators == 0 {
+        return Ok(update.clone());
+    }
+    if operators != update.len() {
+        return Err(DatabaseError::InvalidUpdate(
+            "update cannot mix operators and fields".to_string(),
+        ));
+    }
+
+    let mut result = doc.clone();
+    for (operator, fields) in update.iter() {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            _ => {
+                return Err(DatabaseError::InvalidUpdate(format!(
+                    "'{}' expects a document",
+                    operator
+                )))
+            }
+        };
+
+        match operator.as_str() {
+            "$set" => {
+                for (field, value) in fields.iter() {
+                    result.insert(field.clone(), value.clone());
+                }
+            }
+            "$unset" => {
+                for (field, _) in fields.iter() {
+                    result.remove(field);
+                }
+            }
+            "$inc" => {
+                for (field, amount) in fields.iter() {
+                    let current = result.get(field).cloned().unwrap_or(Bson::Int32(0));
+                    result.insert(field.clone(), increment(field, &current, amount)?);
+                }
+            }
+            _ => {
+                return Err(DatabaseError::InvalidUpdate(format!(
+                    "unknown update operator '{}'",
+                    operator
+                )))
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn increment(field: &str, current: &Bson, amount: &Bson) -> Result<Bson, DatabaseError> {
+    match (current, amount) {
+        (Bson::Int32(a), Bson::Int32(b)) => Ok(a
+            .checked_add(*b)
+            .map(Bson::Int32)
+            .unwrap_or(Bson::Int64(*a as i64 + *b as i64))),
+        (Bson::Int32(a), Bson::Int64(b)) => Ok(Bson::Int64(*a as i64 + b)),
+        (Bson::Int64(a), Bson::Int32(b)) => Ok(Bson::Int64(a + *b as i64)),
+        (Bson::Int64(a), Bson::Int64(b)) => Ok(Bson::Int64(a + b)),
+        (Bson::Double(a), Bson::Double(b)) => Ok(Bson::Double(a + b)),
+        (Bson::Double(a), Bson::Int32(b)) => Ok(Bson::Double(a + *b as f64)),
+        (Bson::Double(a), Bson::Int64(b)) => Ok(Bson::Double(a + *b as f64)),
+        (Bson::Int32(a), Bson::Double(b)) => Ok(Bson::Double(*a as f64 + b)),
+        (Bson::Int64(a), Bson::Double(b)) => Ok(Bson::Double(*a as f64 + b)),
+        _ => Err(DatabaseError::InvalidUpdate(format!(
+            "cannot increment non-numeric field '{}'",
+            field
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_operators() {
+        let doc = bson::doc! { "name": "John", "age": 30, "email": "john@example.com" };
+
+        let updated = apply_update(
+            &doc,
+            &bson::doc! {
+                "$set": { "name": "Johnny" },
+                "$unset": { "email": "" },
+                "$inc": { "age": 1, "visits": 2 },
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            updated,
+            bson::doc! { "name": "Johnny", "age": 31, "visits": 2 }
+        );
+    }
+
+    #[test]
+    fn test_replacement() {
+        let doc = bson::doc! { "name": "John", "age": 30 };
+
+        let updated = apply_update(&doc, &bson::doc! { "name": "Jane" }).unwrap();
+
+        assert_eq!(updated, bson::doc! { "name": "Jane" });
+    }
+
+    #[test]
+    fn test_invalid_updates() {
+        let doc = bson::doc! { "name": "John" };
+
+        assert!(apply_update(&doc, &bson::doc! { "$set": { "a": 1 }, "b": 2 }).is_err());
+        assert!(apply_update(&doc, &bson::doc! { "$push": { "a": 1 } }).is_err());
+        assert!(apply_update(&doc, &bson::doc! { "$inc": { "name": 1 } }).is_err());
+    }
+}
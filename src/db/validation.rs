@@ -0,0 +1,402 @@
+//! Per-collection validators, checked on every document an insert or update
+//! is about to write, after its pre-hooks. A validator is either a
+//! JSON-Schema-like document or a Rust closure; a document failing it is
+//! refused with `DatabaseError::ValidationError`, naming the field.
+//!
+//! Schemas understand `bsonType` (or `type`) as a name or a list of them,
+//! `required`, `properties`, `additionalProperties: false`, `enum`,
+//! `minimum`/`maximum`, `minLength`/`maxLength`, and `items` with
+//! `minItems`/`maxItems` for arrays. Validators live in memory, so they
+//! have to be set again each time the database is opened.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use bson::{Bson, Document};
+
+use super::{Database, DatabaseError};
+
+/// Why a document was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Dotted path to the offending field; empty for the document itself.
+    pub field: String,
+    pub reason: String,
+}
+
+impl ValidationError {
+    pub fn new(field: &str, reason: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+type Check = dyn Fn(&Document) -> Result<(), ValidationError> + Send + Sync;
+
+/// What a collection's documents have to satisfy.
+#[derive(Clone)]
+pub enum Validator {
+    Schema(Document),
+    Custom(Arc<Check>),
+}
+
+impl Validator {
+    pub fn custom(
+        check: impl Fn(&Document) -> Result<(), ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom(Arc::new(check))
+    }
+}
+
+/// The validator set on each collection, schemas already parsed.
+#[derive(Default)]
+pub(crate) struct Validators {
+    collections: RwLock<HashMap<String, Compiled>>,
+}
+
+#[derive(Clone)]
+enum Compiled {
+    Schema(Arc<Schema>),
+    Custom(Arc<Check>),
+}
+
+impl Validators {
+    pub(crate) fn check(&self, collection: &str, doc: &Document) -> Result<(), DatabaseError> {
+        let validator = {
+            let collections = self.collections.read().unwrap_or_else(|e| e.into_inner());
+            match collections.get(collection) {
+                Some(validator) => validator.clone(),
+                None => return Ok(()),
+            }
+        };
+        let result = match validator {
+            Compiled::Schema(schema) => schema.check_document("", doc),
+            Compiled::Custom(check) => check(doc),
+        };
+        result.map_err(DatabaseError::ValidationError)
+    }
+}
+
+impl Database {
+    /// Checks every document inserted into or updated in `collection` from
+    /// now on against `validator`, replacing any set before. Documents
+    /// already there aren't checked. Fails if a schema is malformed.
+    pub fn set_validator(
+        &self,
+        collection: &str,
+        validator: Validator,
+    ) -> Result<(), DatabaseError> {
+        let compiled = match validator {
+            Validator::Schema(schema) => Compiled::Schema(Arc::new(Schema::parse(&schema)?)),
+            Validator::Custom(check) => Compiled::Custom(check),
+        };
+        let mut collections = self
+            .inner
+            .validators
+            .collections
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        collections.insert(collection.to_string(), compiled);
+        Ok(())
+    }
+
+    /// Stops validating `collection`'s documents. Answers false if nothing
+    /// was.
+    pub fn remove_validator(&self, collection: &str) -> bool {
+        let mut collections = self
+            .inner
+            .validators
+            .collections
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        collections.remove(collection).is_some()
+    }
+}
+
+#[derive(Default)]
+struct Schema {
+    types: Option<Vec<String>>,
+    required: Vec<String>,
+    properties: Vec<(String, Schema)>,
+    additional_properties: bool,
+    allowed: Option<Vec<Bson>>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    items: Option<Box<Schema>>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+}
+
+const TYPES: &[&str] = &[
+    "object", "array", "string", "int", "long", "double", "number", "integer", "bool", "boolean",
+    "null", "objectId", "date", "binData",
+];
+
+impl Schema {
+    fn parse(doc: &Document) -> Result<Self, DatabaseError> {
+        let invalid = |message: String| DatabaseError::InvalidUpdate(message);
+        let mut schema = Schema {
+            additional_properties: true,
+            ..Schema::default()
+        };
+        for (keyword, value) in doc {
+            match (keyword.as_str(), value) {
+                ("bsonType" | "type", Bson::String(name)) => {
+                    schema.types = Some(vec![name.clone()]);
+                }
+                ("bsonType" | "type", Bson::Array(names)) => {
+                    let names = names
+                        .iter()
+                        .map(|name| name.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| invalid(format!("'{}' must list type names", keyword)))?;
+                    schema.types = Some(names);
+                }
+                ("required", Bson::Array(fields)) => {
+                    schema.required = fields
+                        .iter()
+                        .map(|field| field.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| invalid("'required' must list field names".to_string()))?;
+                }
+                ("properties", Bson::Document(properties)) => {
+                    for (field, property) in properties {
+                        let Bson::Document(property) = property else {
+                            return Err(invalid(format!("property '{}' must be a schema", field)));
+                        };
+                        schema
+                            .properties
+                            .push((field.clone(), Schema::parse(property)?));
+                    }
+                }
+                ("additionalProperties", Bson::Boolean(allowed)) => {
+                    schema.additional_properties = *allowed;
+                }
+                ("enum", Bson::Array(values)) => schema.allowed = Some(values.clone()),
+                ("minimum", value) if number(value).is_some() => schema.minimum = number(value),
+                ("maximum", value) if number(value).is_some() => schema.maximum = number(value),
+                ("minLength", value) if count(value).is_some() => schema.min_length = count(value),
+                ("maxLength", value) if count(value).is_some() => schema.max_length = count(value),
+                ("minItems", value) if count(value).is_some() => schema.min_items = count(value),
+                ("maxItems", value) if count(value).is_some() => schema.max_items = count(value),
+                ("items", Bson::Document(items)) => {
+                    schema.items = Some(Box::new(Schema::parse(items)?));
+                }
+                ("title" | "description", _) => {}
+                _ => return Err(invalid(format!("unsupported schema keyword '{}'", keyword))),
+            }
+        }
+        for name in schema.types.iter().flatten() {
+            if !TYPES.contains(&name.as_str()) {
+                return Err(invalid(format!("unknown type '{}'", name)));
+            }
+        }
+        Ok(schema)
+    }
+
+    fn check_document(&self, path: &str, doc: &Document) -> Result<(), ValidationError> {
+        for field in &self.required {
+            if !doc.contains_key(field) {
+                return Err(ValidationError::new(&join(path, field), "is required"));
+            }
+        }
+        for (field, value) in doc {
+            let field_path = join(path, field);
+            match self.properties.iter().find(|(name, _)| name == field) {
+                Some((_, schema)) => schema.check(&field_path, value)?,
+                None if !self.additional_properties => {
+                    return Err(ValidationError::new(&field_path, "is not allowed"));
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, path: &str, value: &Bson) -> Result<(), ValidationError> {
+        if let Some(types) = &self.types {
+            if !types.iter().any(|name| is_type(name, value)) {
+                return Err(ValidationError::new(
+                    path,
+                    format!("must be of type {}", types.join(" or ")),
+                ));
+            }
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(value) {
+                return Err(ValidationError::new(
+                    path,
+                    "is not one of the allowed values",
+                ));
+            }
+        }
+        if let Some(n) = number(value) {
+            if self.minimum.is_some_and(|minimum| n < minimum) {
+                return Err(ValidationError::new(path, "is below the minimum"));
+            }
+            if self.maximum.is_some_and(|maximum| n > maximum) {
+                return Err(ValidationError::new(path, "is above the maximum"));
+            }
+        }
+        match value {
+            Bson::String(s) => {
+                let len = s.chars().count();
+                if self.min_length.is_some_and(|min| len < min) {
+                    return Err(ValidationError::new(path, "is too short"));
+                }
+                if self.max_length.is_some_and(|max| len > max) {
+                    return Err(ValidationError::new(path, "is too long"));
+                }
+            }
+            Bson::Array(items) => {
+                if self.min_items.is_some_and(|min| items.len() < min) {
+                    return Err(ValidationError::new(path, "has too few items"));
+                }
+                if self.max_items.is_some_and(|max| items.len() > max) {
+                    return Err(ValidationError::new(path, "has too many items"));
+                }
+                if let Some(schema) = &self.items {
+                    for (i, item) in items.iter().enumerate() {
+                        schema.check(&join(path, &i.to_string()), item)?;
+                    }
+                }
+            }
+            Bson::Document(doc) => self.check_document(path, doc)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn is_type(name: &str, value: &Bson) -> bool {
+    matches!(
+        (name, value),
+        ("object", Bson::Document(_))
+            | ("array", Bson::Array(_))
+            | ("string", Bson::String(_))
+            | ("int", Bson::Int32(_))
+            | ("long", Bson::Int64(_))
+            | ("double", Bson::Double(_))
+            | ("number", Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_))
+            | ("integer", Bson::Int32(_) | Bson::Int64(_))
+            | ("bool" | "boolean", Bson::Boolean(_))
+            | ("null", Bson::Null)
+            | ("objectId", Bson::ObjectId(_))
+            | ("date", Bson::DateTime(_))
+            | ("binData", Bson::Binary(_))
+    )
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn count(value: &Bson) -> Option<usize> {
+    number(value)
+        .filter(|n| *n >= 0.0 && n.fract() == 0.0)
+        .map(|n| n as usize)
+}
+
+fn join(path: &str, field: &str) -> String {
+    match path {
+        "" => field.to_string(),
+        _ => format!("{}.{}", path, field),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validators() {
+        let db = Database::init_test("data_tests".to_string(), "test_validators".to_string()).await;
+        db.clear().await.unwrap();
+        let schema = bson::doc! {
+            "bsonType": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "bsonType": "string", "minLength": 1 },
+                "age": { "bsonType": ["int", "long"], "minimum": 0 },
+                "tags": { "bsonType": "array", "items": { "enum": ["admin", "staff"] } },
+            },
+        };
+        db.set_validator("users", Validator::Schema(schema))
+            .unwrap();
+        assert!(db
+            .set_validator("users", Validator::Schema(bson::doc! { "pattern": "^a" }))
+            .is_err());
+
+        let refused = |doc: bson::Document| {
+            let db = db.clone();
+            async move {
+                match db.insert_one("users".to_string(), doc).await {
+                    Err(DatabaseError::ValidationError(e)) => e.field,
+                    other => panic!("expected a validation error, got {:?}", other),
+                }
+            }
+        };
+        assert_eq!(refused(bson::doc! { "age": 30 }).await, "name");
+        assert_eq!(
+            refused(bson::doc! { "name": "John", "age": -1 }).await,
+            "age"
+        );
+        assert_eq!(
+            refused(bson::doc! { "name": "John", "tags": ["staff", "guest"] }).await,
+            "tags.1"
+        );
+        let id = db
+            .insert_one(
+                "users".to_string(),
+                bson::doc! { "name": "John", "age": 30, "tags": ["admin"] },
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$set": { "age": "thirty" } },
+            )
+            .await,
+            Err(DatabaseError::ValidationError(_))
+        ));
+
+        db.set_validator(
+            "users",
+            Validator::custom(|doc| match doc.get_i32("age") {
+                Ok(age) if age > 150 => Err(ValidationError::new("age", "is implausible")),
+                _ => Ok(()),
+            }),
+        )
+        .unwrap();
+        let mut txn = db.begin_transaction().await;
+        assert!(txn
+            .update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$set": { "age": 200 } },
+            )
+            .await
+            .is_err());
+        txn.rollback();
+        assert!(db.remove_validator("users"));
+        assert!(db
+            .update_one(
+                "users".to_string(),
+                id,
+                bson::doc! { "$set": { "age": 200 } },
+            )
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,311 @@
+//! Checking a database folder nobody has open, say after a crash or a disk
+//! error: every document file must decode, and nothing an interrupted
+//! write left behind may remain. Repairing moves documents that don't
+//! decode aside to `.quarantine/<collection>/`, applies logged writes and
+//! finishes or removes interrupted directory changes.
+
+use std::path::Path;
+
+use log::{info, warn};
+
+use super::codec::{self, Codec, CREATING_SUFFIX};
+use super::defrag::{self, list_collections, RETIRED_SUFFIX, STAGING_SUFFIX};
+use super::lock_file::LockFile;
+use super::{oplog, wal, DatabaseError};
+
+pub(crate) const QUARANTINE_DIR: &str = ".quarantine";
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Fix what can be fixed instead of only reporting it.
+    pub repair: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A document file that can't be read or doesn't decode; repairing
+    /// quarantines it.
+    CorruptDocument {
+        collection: String,
+        file: String,
+        reason: String,
+    },
+    /// A collection whose `.codec` this build can't read; never repaired.
+    UnreadableCollection { collection: String, reason: String },
+    /// A write-ahead log still holding writes, as a crash leaves it.
+    /// Repairing applies them, as opening the database would.
+    UnappliedLog { log: String, records: usize },
+    /// What an interrupted defragmentation or collection creation left,
+    /// relative to the database folder.
+    Leftover { path: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub collections: usize,
+    pub documents: u64,
+    /// Found and fixed.
+    pub repaired: Vec<Problem>,
+    /// Found and still there.
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.repaired.is_empty() && self.problems.is_empty()
+    }
+}
+
+/// Checks the database folder at `folder_path`, which must not be open
+/// anywhere: fails with `DatabaseError::AlreadyLocked` if it is. Reads
+/// every document, so it costs as much as a scan of each collection.
+pub async fn verify(
+    folder_path: &str,
+    options: VerifyOptions,
+) -> Result<VerifyReport, DatabaseError> {
+    let _lock = LockFile::acquire(folder_path)?;
+    let mut report = VerifyReport::default();
+
+    // Settled first, since that writes document files.
+    let mut unsettled = leftovers(folder_path).await?;
+    unsettled.extend(unapplied_logs(folder_path).await?);
+    if options.repair && !unsettled.is_empty() {
+        defrag::recover(folder_path).await?;
+        for problem in &unsettled {
+            if let Problem::Leftover { path } = problem {
+                if path.ends_with(CREATING_SUFFIX) {
+                    remove_dir_if_exists(&Path::new(folder_path).join(path)).await?;
+                }
+            }
+        }
+        let logged = wal::replay(folder_path).await?;
+        oplog::recover_replayed(folder_path, logged).await?;
+        report.repaired = unsettled;
+    } else {
+        report.problems = unsettled;
+    }
+
+    for collection in list_collections(folder_path).await? {
+        report.collections += 1;
+        match codec::read(folder_path, &collection).await {
+            Ok(_) => check_documents(folder_path, &collection, &options, &mut report).await?,
+            Err(e) => report.problems.push(Problem::UnreadableCollection {
+                collection,
+                reason: format!("{:?}", e),
+            }),
+        }
+    }
+
+    match report.is_clean() {
+        true => info!(
+            "Verified {} documents in '{}'",
+            report.documents, folder_path
+        ),
+        false => warn!(
+            "Verifying '{}' found {} problems, {} of them repaired",
+            folder_path,
+            report.problems.len() + report.repaired.len(),
+            report.repaired.len()
+        ),
+    }
+    Ok(report)
+}
+
+async fn check_documents(
+    folder_path: &str,
+    collection: &str,
+    options: &VerifyOptions,
+    report: &mut VerifyReport,
+) -> Result<(), DatabaseError> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(Path::new(folder_path).join(collection))
+        .await
+        .map_err(DatabaseError::IoError)?;
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        if let Some(codec) = Codec::of_file(&entry.path()) {
+            files.push((entry.path(), codec));
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (path, codec) in files {
+        report.documents += 1;
+        let decoded = match tokio::fs::read(&path).await {
+            Ok(bytes) => codec
+                .decode(bytes)
+                .and_then(|raw| raw.to_document().map_err(DatabaseError::BsonRawError))
+                .map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(e.to_string()),
+        };
+        let Err(reason) = decoded else {
+            continue;
+        };
+
+        let problem = Problem::CorruptDocument {
+            collection: collection.to_string(),
+            file: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            reason,
+        };
+        if options.repair {
+            quarantine(folder_path, collection, &path).await?;
+            report.repaired.push(problem);
+        } else {
+            report.problems.push(problem);
+        }
+    }
+    Ok(())
+}
+
+async fn quarantine(folder_path: &str, collection: &str, path: &Path) -> Result<(), DatabaseError> {
+    let dir = Path::new(folder_path).join(QUARANTINE_DIR).join(collection);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    warn!("Quarantining unreadable document {:?}", path);
+    tokio::fs::rename(path, dir.join(path.file_name().unwrap_or_default()))
+        .await
+        .map_err(DatabaseError::IoError)
+}
+
+async fn leftovers(folder_path: &str) -> Result<Vec<Problem>, DatabaseError> {
+    let mut found = Vec::new();
+    let mut entries = tokio::fs::read_dir(folder_path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(STAGING_SUFFIX)
+            || name.ends_with(RETIRED_SUFFIX)
+            || (name.starts_with('.') && name.ends_with(CREATING_SUFFIX))
+        {
+            found.push(name);
+        }
+    }
+    found.sort();
+    Ok(found
+        .into_iter()
+        .map(|path| Problem::Leftover { path })
+        .collect())
+}
+
+/// Logs still holding records; a database closed cleanly leaves none.
+async fn unapplied_logs(folder_path: &str) -> Result<Vec<Problem>, DatabaseError> {
+    let mut found = Vec::new();
+    for (sequence, path) in wal::list_logs(folder_path).await? {
+        let records = wal::read_log(&path, sequence).await?.len();
+        if records > 0 {
+            found.push(Problem::UnappliedLog {
+                log: path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                records,
+            });
+        }
+    }
+    Ok(found)
+}
+
+async fn remove_dir_if_exists(path: &Path) -> Result<(), DatabaseError> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(DatabaseError::IoError(e)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, DatabaseOptions, WriteBufferOptions};
+
+    #[tokio::test]
+    async fn test_verify_and_repair() {
+        let folder_path = "data_tests/test_verify";
+        let _ = tokio::fs::remove_dir_all(folder_path).await;
+        let options = DatabaseOptions {
+            write_buffer: Some(WriteBufferOptions {
+                flush_interval: std::time::Duration::from_secs(3600),
+                ..WriteBufferOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path.to_string(), options)
+            .await
+            .unwrap();
+        db.put(
+            "users".to_string(),
+            "ada".to_string(),
+            bson::doc! { "name": "Ada" },
+        )
+        .await
+        .unwrap();
+        db.flush().await.unwrap();
+        assert!(matches!(
+            verify(folder_path, VerifyOptions::default()).await,
+            Err(DatabaseError::AlreadyLocked(_))
+        ));
+        // Still in the log when the process goes away.
+        db.insert_one("users".to_string(), bson::doc! { "name": "Bob" })
+            .await
+            .unwrap();
+        std::mem::forget(db);
+
+        tokio::fs::write(format!("{}/users/torn.bson", folder_path), b"\x20\0\0\0")
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(format!("{}/.orders.creating", folder_path))
+            .await
+            .unwrap();
+        // The forgotten handle holds the lock until the process exits.
+        let copy = "data_tests/test_verify_copy";
+        let _ = tokio::fs::remove_dir_all(copy).await;
+        copy_dir(Path::new(folder_path), Path::new(copy)).await;
+
+        let report = verify(copy, VerifyOptions::default()).await.unwrap();
+        assert_eq!(report.documents, 2);
+        assert!(report.repaired.is_empty());
+        assert_eq!(report.problems.len(), 3);
+        assert!(report.problems.contains(&Problem::Leftover {
+            path: ".orders.creating".to_string()
+        }));
+        assert!(matches!(
+            &report.problems[1],
+            Problem::UnappliedLog { records: 1, .. }
+        ));
+        assert!(matches!(
+            &report.problems[2],
+            Problem::CorruptDocument { file, .. } if file == "torn.bson"
+        ));
+
+        let report = verify(copy, VerifyOptions { repair: true }).await.unwrap();
+        assert_eq!(report.repaired.len(), 3);
+        assert!(report.problems.is_empty());
+        assert!(
+            tokio::fs::metadata(format!("{}/.quarantine/users/torn.bson", copy))
+                .await
+                .is_ok()
+        );
+
+        let report = verify(copy, VerifyOptions::default()).await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.documents, 2);
+    }
+
+    async fn copy_dir(from: &Path, to: &Path) {
+        tokio::fs::create_dir_all(to).await.unwrap();
+        let mut entries = tokio::fs::read_dir(from).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let target = to.join(entry.file_name());
+            if entry.file_type().await.unwrap().is_dir() {
+                Box::pin(copy_dir(&entry.path(), &target)).await;
+            } else {
+                tokio::fs::copy(entry.path(), target).await.unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bson::Document;
+use log::{error, info};
+
+use super::{check_name, Database, DatabaseError, FindOptions};
+
+/// Directory, inside the database folder, holding past document versions.
+const VERSIONS_DIR: &str = ".versions";
+
+#[derive(Debug, Clone)]
+pub struct VersioningOptions {
+    /// How far back `find_one_at` can look. Older versions are pruned.
+    pub retention: Duration,
+}
+
+impl Default for VersioningOptions {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// One image a document had, as `Database::history` lists them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revision {
+    /// What `revert_to` takes; later revisions have greater numbers.
+    pub revision: u64,
+    pub written_at: SystemTime,
+    /// `None` for a delete.
+    pub document: Option<Document>,
+}
+
+/// Keeps every image a document had, one file per write, named after the
+/// time of the write: `.versions/<collection>/<id>/<nanos>.bson`. A delete
+/// leaves an empty file.
+pub(crate) struct VersionHistory {
+    folder_path: String,
+    options: VersioningOptions,
+}
+
+impl VersionHistory {
+    pub(crate) fn new(folder_path: &str, options: VersioningOptions) -> Self {
+        Self {
+            folder_path: format!("{}/{}", folder_path, VERSIONS_DIR),
+            options,
+        }
+    }
+
+    fn document_dir(&self, collection: &str, id: &str) -> Result<PathBuf, DatabaseError> {
+        check_name(collection)?;
+        check_name(id)?;
+        Ok(Path::new(&self.folder_path).join(collection).join(id))
+    }
+
+    /// Records the image a write left behind; `None` for a delete.
+    pub(crate) async fn record(
+        &self,
+        collection: &str,
+        id: &str,
+        doc: Option<&Document>,
+    ) -> Result<(), DatabaseError> {
+        let dir = self.document_dir(collection, id)?;
+        tokio::fs::create_dir_all(&dir).await.map_err(|e| {
+            error!("Failed to create version directory: {}", e);
+            DatabaseError::IoError(e)
+        })?;
+
+        let mut buffer = Vec::new();
+        if let Some(doc) = doc {
+            doc.to_writer(&mut buffer)
+                .map_err(DatabaseError::BsonSerError)?;
+        }
+
+        // Writers of a document are serialized, so only two writes landing
+        // in the same nanosecond could collide.
+        let mut timestamp = nanos_since_epoch(SystemTime::now());
+        while tokio::fs::try_exists(version_path(&dir, timestamp))
+            .await
+            .unwrap_or(false)
+        {
+            timestamp += 1;
+        }
+
+        tokio::fs::write(version_path(&dir, timestamp), &buffer)
+            .await
+            .map_err(|e| {
+                error!("Failed to write document version: {}", e);
+                DatabaseError::IoError(e)
+            })?;
+
+        self.prune_document(&dir).await
+    }
+
+    /// The image a document had at `at`: `Some(None)` if it did not exist
+    /// then, `None` if it has no recorded versions at all.
+    pub(crate) async fn document_at(
+        &self,
+        collection: &str,
+        id: &str,
+        at: SystemTime,
+    ) -> Result<Option<Option<Document>>, DatabaseError> {
+        let at = nanos_since_epoch(at);
+        let versions = list_versions(&self.document_dir(collection, id)?).await?;
+        if versions.is_empty() {
+            // Not written since versioning was enabled.
+            return Ok(None);
+        }
+
+        match versions
+            .iter()
+            .rev()
+            .find(|(timestamp, _)| *timestamp <= at)
+        {
+            Some((_, path)) => read_version(path).await.map(Some),
+            None => Ok(Some(None)),
+        }
+    }
+
+    /// Every image a document had that is still kept, oldest first.
+    pub(crate) async fn revisions(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> Result<Vec<Revision>, DatabaseError> {
+        let mut revisions = Vec::new();
+        for (timestamp, path) in list_versions(&self.document_dir(collection, id)?).await? {
+            revisions.push(Revision {
+                revision: timestamp,
+                written_at: UNIX_EPOCH + Duration::from_nanos(timestamp),
+                document: read_version(&path).await?,
+            });
+        }
+        Ok(revisions)
+    }
+
+    /// Drops versions that fell out of the retention window, except the
+    /// newest of them: it still describes the document at the window's start.
+    async fn prune_document(&self, dir: &Path) -> Result<(), DatabaseError> {
+        let cutoff = SystemTime::now()
+            .checked_sub(self.options.retention)
+            .map_or(0, nanos_since_epoch);
+
+        let versions = list_versions(dir).await?;
+        let expired = versions
+            .iter()
+            .filter(|(timestamp, _)| *timestamp <= cutoff)
+            .count();
+
+        for (_, path) in versions.iter().take(expired.saturating_sub(1)) {
+            tokio::fs::remove_file(path)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn prune(&self) -> Result<(), DatabaseError> {
+        let mut collections = match tokio::fs::read_dir(&self.folder_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(DatabaseError::IoError(e)),
+        };
+
+        while let Some(collection) = collections
+            .next_entry()
+            .await
+            .map_err(DatabaseError::IoError)?
+        {
+            let mut documents = tokio::fs::read_dir(collection.path())
+                .await
+                .map_err(DatabaseError::IoError)?;
+            while let Some(document) = documents
+                .next_entry()
+                .await
+                .map_err(DatabaseError::IoError)?
+            {
+                self.prune_document(&document.path()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+fn version_path(dir: &Path, timestamp: u64) -> PathBuf {
+    dir.join(format!("{:020}.bson", timestamp))
+}
+
+/// The image a version file holds; `None` for a delete.
+async fn read_version(path: &Path) -> Result<Option<Document>, DatabaseError> {
+    let buffer = tokio::fs::read(path).await.map_err(|e| {
+        error!("Failed to read document version: {}", e);
+        DatabaseError::IoError(e)
+    })?;
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+
+    let doc = Document::from_reader(&mut buffer.as_slice()).map_err(DatabaseError::BsonDeError)?;
+    Ok(Some(doc))
+}
+
+/// Versions of one document, oldest first.
+async fn list_versions(dir: &Path) -> Result<Vec<(u64, PathBuf)>, DatabaseError> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(DatabaseError::IoError(e)),
+    };
+
+    let mut versions = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let path = entry.path();
+        let timestamp = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        if let Some(timestamp) = timestamp {
+            versions.push((timestamp, path));
+        }
+    }
+    versions.sort();
+
+    Ok(versions)
+}
+
+impl Database {
+    /// Reads a document as it was at `at`. Requires versioning to be enabled
+    /// in `DatabaseOptions`; documents not written since then read as they
+    /// are now.
+    pub async fn find_one_at(
+        &self,
+        collection: String,
+        id: String,
+        at: SystemTime,
+    ) -> Result<Option<Document>, DatabaseError> {
+        let history = self
+            .inner
+            .history
+            .as_ref()
+            .ok_or(DatabaseError::VersioningDisabled)?;
+
+        let retained_since = SystemTime::now()
+            .checked_sub(history.options.retention)
+            .unwrap_or(UNIX_EPOCH);
+        if at < retained_since {
+            return Err(DatabaseError::VersionPruned);
+        }
+
+        let _guard = self.inner.activity.begin().await?;
+
+        match history.document_at(&collection, &id, at).await? {
+            Some(doc) => Ok(doc),
+            None => {
+                self.read_document(&collection, &id, &FindOptions::default())
+                    .await
+            }
+        }
+    }
+
+    /// Lists the revisions of a document still within the retention
+    /// window, oldest first: each image an insert, update or delete left.
+    /// Requires versioning to be enabled in `DatabaseOptions`.
+    pub async fn history(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Vec<Revision>, DatabaseError> {
+        let history = self
+            .inner
+            .history
+            .as_ref()
+            .ok_or(DatabaseError::VersioningDisabled)?;
+        let _guard = self.inner.activity.begin().await?;
+        history.revisions(&collection, &id).await
+    }
+
+    /// Restores a document to one of its revisions from `history`, as a new
+    /// write, so the revisions after it are kept. Reverting to a delete
+    /// deletes the document.
+    pub async fn revert_to(
+        &self,
+        collection: String,
+        id: String,
+        revision: u64,
+    ) -> Result<(), DatabaseError> {
+        let document = self
+            .history(collection.clone(), id.clone())
+            .await?
+            .into_iter()
+            .find(|kept| kept.revision == revision)
+            .ok_or(DatabaseError::RevisionNotFound(revision))?
+            .document;
+        match document {
+            Some(doc) => self.put(collection.clone(), id.clone(), doc).await?,
+            None => {
+                self.delete_one(collection.clone(), id.clone()).await?;
+            }
+        }
+        info!(
+            "Reverted document in '{}' with ID '{}' to revision {}",
+            collection, id, revision
+        );
+        Ok(())
+    }
+
+    /// Removes document versions older than the retention window. Writes
+    /// prune the versions of the document they touch; this sweeps the rest.
+    pub async fn prune_versions(&self) -> Result<(), DatabaseError> {
+        if let Some(history) = &self.inner.history {
+            let _guard = self.inner.activity.begin().await?;
+            history.prune().await?;
+            info!("Successfully pruned document versions");
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn record_version(
+        &self,
+        collection: &str,
+        id: &str,
+        doc: Option<&Document>,
+    ) -> Result<(), DatabaseError> {
+        match &self.inner.history {
+            Some(history) => history.record(collection, id, doc).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DatabaseOptions;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_find_one_at() {
+        let folder_path = "data_tests/test_versioning".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            versioning: Some(VersioningOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let before_insert = SystemTime::now();
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+        let after_insert = SystemTime::now();
+        db.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "age": 1 } },
+        )
+        .await
+        .unwrap();
+        let after_update = SystemTime::now();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let at = |time| db.find_one_at("users".to_string(), id.clone(), time);
+        assert_eq!(at(before_insert).await.unwrap(), None);
+        assert_eq!(
+            at(after_insert).await.unwrap(),
+            Some(bson::doc! { "age": 30 })
+        );
+        assert_eq!(
+            at(after_update).await.unwrap(),
+            Some(bson::doc! { "age": 31 })
+        );
+        assert_eq!(at(SystemTime::now()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_history_and_revert() {
+        let folder_path = "data_tests/test_versioning_history".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            versioning: Some(VersioningOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "age": 30 })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            id.clone(),
+            bson::doc! { "$inc": { "age": 1 } },
+        )
+        .await
+        .unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let history = db.history("users".to_string(), id.clone()).await.unwrap();
+        let documents: Vec<_> = history.iter().map(|kept| kept.document.clone()).collect();
+        assert_eq!(
+            documents,
+            [
+                Some(bson::doc! { "age": 30 }),
+                Some(bson::doc! { "age": 31 }),
+                None
+            ]
+        );
+
+        db.revert_to("users".to_string(), id.clone(), history[0].revision)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.find_one("users".to_string(), id.clone()).await.unwrap(),
+            Some(bson::doc! { "age": 30 })
+        );
+        assert_eq!(
+            db.history("users".to_string(), id.clone())
+                .await
+                .unwrap()
+                .len(),
+            4
+        );
+        assert!(matches!(
+            db.revert_to("users".to_string(), id, 1).await,
+            Err(DatabaseError::RevisionNotFound(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prune_keeps_latest_version() {
+        let dir = "data_tests/test_versioning_prune";
+        let _ = tokio::fs::remove_dir_all(dir).await;
+        let history = VersionHistory::new(
+            dir,
+            VersioningOptions {
+                retention: Duration::ZERO,
+            },
+        );
+
+        history
+            .record("users", "a", Some(&bson::doc! { "age": 30 }))
+            .await
+            .unwrap();
+        history
+            .record("users", "a", Some(&bson::doc! { "age": 31 }))
+            .await
+            .unwrap();
+        history.prune().await.unwrap();
+
+        let versions = list_versions(&history.document_dir("users", "a").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(
+            history
+                .document_at("users", "a", SystemTime::now())
+                .await
+                .unwrap(),
+            Some(Some(bson::doc! { "age": 31 }))
+        );
+    }
+}
@@ -0,0 +1,656 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use bson::Document;
+use log::{error, info, warn};
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use super::{codec, DatabaseError};
+
+/// Directory, inside the database folder, holding the write-ahead log.
+pub(crate) const WAL_DIR: &str = ".wal";
+
+const WAL_EXTENSION: &str = "log";
+
+/// Extension of retired log files kept around for reuse.
+const RECYCLED_EXTENSION: &str = "free";
+
+/// How many retired log files are kept for reuse.
+const MAX_RECYCLED_LOGS: usize = 2;
+
+/// Every record is framed as `[len: u32 LE][crc32: u32 LE][bson payload]`.
+///
+/// The checksum also covers the sequence number of the log file, so stale
+/// records left over in a recycled file read as the end of the log.
+const HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum WalRecord {
+    Insert {
+        collection: String,
+        id: String,
+        doc: Document,
+    },
+    Delete {
+        collection: String,
+        id: String,
+    },
+    /// Operations that must be applied all together or not at all.
+    Batch(Vec<WalRecord>),
+    /// The oplog entry of a write in the same batch; writes nothing itself,
+    /// but replay hands it back for the oplog to append if it hadn't.
+    Logged(Document),
+}
+
+impl WalRecord {
+    /// Adds the collections the record writes to to `into`.
+    pub(crate) fn collections(&self, into: &mut BTreeSet<String>) {
+        match self {
+            WalRecord::Insert { collection, .. } | WalRecord::Delete { collection, .. } => {
+                into.insert(collection.clone());
+            }
+            WalRecord::Batch(records) => {
+                for record in records {
+                    record.collections(into);
+                }
+            }
+            WalRecord::Logged(_) => {}
+        }
+    }
+
+    /// Adds the oplog entries the record carries to `into`.
+    fn logged(self, into: &mut Vec<Document>) {
+        match self {
+            WalRecord::Batch(records) => {
+                for record in records {
+                    record.logged(into);
+                }
+            }
+            WalRecord::Logged(entry) => into.push(entry),
+            WalRecord::Insert { .. } | WalRecord::Delete { .. } => {}
+        }
+    }
+
+    fn to_document(&self) -> Document {
+        match self {
+            WalRecord::Insert {
+                collection,
+                id,
+                doc,
+            } => bson::doc! {
+                "op": "insert",
+                "collection": collection,
+                "id": id,
+                "doc": doc.clone(),
+            },
+            WalRecord::Delete { collection, id } => bson::doc! {
+                "op": "delete",
+                "collection": collection,
+                "id": id,
+            },
+            WalRecord::Batch(records) => bson::doc! {
+                "op": "batch",
+                "ops": records
+                    .iter()
+                    .map(|record| bson::Bson::Document(record.to_document()))
+                    .collect::<Vec<_>>(),
+            },
+            WalRecord::Logged(entry) => bson::doc! {
+                "op": "logged",
+                "entry": entry.clone(),
+            },
+        }
+    }
+
+    fn from_document(doc: &Document) -> Result<Self, DatabaseError> {
+        let field = |key: &str| {
+            doc.get_str(key)
+                .map(|value| value.to_string())
+                .map_err(|_| DatabaseError::WalCorrupted(format!("missing field '{}'", key)))
+        };
+
+        match field("op")?.as_str() {
+            "insert" => Ok(WalRecord::Insert {
+                collection: field("collection")?,
+                id: field("id")?,
+                doc: doc
+                    .get_document("doc")
+                    .map_err(|_| DatabaseError::WalCorrupted("missing field 'doc'".to_string()))?
+                    .clone(),
+            }),
+            "delete" => Ok(WalRecord::Delete {
+                collection: field("collection")?,
+                id: field("id")?,
+            }),
+            "batch" => doc
+                .get_array("ops")
+                .map_err(|_| DatabaseError::WalCorrupted("missing field 'ops'".to_string()))?
+                .iter()
+                .map(|op| match op {
+                    bson::Bson::Document(op) => Self::from_document(op),
+                    _ => Err(DatabaseError::WalCorrupted(
+                        "batch entry is not a document".to_string(),
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(WalRecord::Batch),
+            "logged" => doc
+                .get_document("entry")
+                .map(|entry| WalRecord::Logged(entry.clone()))
+                .map_err(|_| DatabaseError::WalCorrupted("missing field 'entry'".to_string())),
+            op => Err(DatabaseError::WalCorrupted(format!(
+                "unknown operation '{}'",
+                op
+            ))),
+        }
+    }
+
+    fn encode(&self, sequence: u64, buffer: &mut Vec<u8>) -> Result<(), DatabaseError> {
+        let mut payload = Vec::new();
+        self.to_document()
+            .to_writer(&mut payload)
+            .map_err(DatabaseError::BsonSerError)?;
+
+        buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&checksum(sequence, &payload).to_le_bytes());
+        buffer.extend_from_slice(&payload);
+
+        Ok(())
+    }
+}
+
+fn checksum(sequence: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&sequence.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Hands out log file sequence numbers to every writer of the WAL.
+pub(crate) struct LogSequence(AtomicU64);
+
+impl LogSequence {
+    pub(crate) fn new(next: u64) -> Self {
+        Self(AtomicU64::new(next))
+    }
+
+    pub(crate) fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// Handle on one log file, written sequentially from its start.
+pub(crate) struct WalWriter {
+    file: tokio::fs::File,
+    path: PathBuf,
+    sequence: u64,
+    offset: u64,
+}
+
+impl WalWriter {
+    /// Creates the log file for `sequence`, reusing a retired file when one
+    /// is available and otherwise preallocating `preallocate` bytes.
+    pub(crate) async fn create(
+        folder_path: &str,
+        sequence: u64,
+        preallocate: u64,
+    ) -> Result<Self, DatabaseError> {
+        let path = log_path(folder_path, sequence);
+
+        tokio::fs::create_dir_all(wal_dir(folder_path))
+            .await
+            .map_err(DatabaseError::IoError)?;
+
+        if let Some(recycled) = take_recycled(folder_path).await? {
+            tokio::fs::rename(&recycled, &path)
+                .await
+                .map_err(DatabaseError::IoError)?;
+        }
+
+        let file_path = path.clone();
+        let file = tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&file_path)?;
+            if file.metadata()?.len() < preallocate {
+                preallocate_file(&file, preallocate)?;
+            }
+            Ok(file)
+        })
+        .await
+        .map_err(|e| DatabaseError::IoError(std::io::Error::other(e)))?
+        .map_err(|e| {
+            error!("Failed to open write-ahead log: {}", e);
+            DatabaseError::IoError(e)
+        })?;
+        // Otherwise a crash could lose the new log, or bring back the
+        // recycled file under its old name.
+        sync_directory(&wal_dir(folder_path).to_string_lossy()).await?;
+
+        Ok(Self {
+            file: tokio::fs::File::from_std(file),
+            path,
+            sequence,
+            offset: 0,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `records` and waits until they are durable.
+    pub(crate) async fn append(&mut self, records: &[WalRecord]) -> Result<(), DatabaseError> {
+        let mut buffer = Vec::new();
+        for record in records {
+            record.encode(self.sequence, &mut buffer)?;
+        }
+
+        self.file
+            .seek(SeekFrom::Start(self.offset))
+            .await
+            .map_err(DatabaseError::IoError)?;
+        self.file.write_all(&buffer).await.map_err(|e| {
+            error!("Failed to append to write-ahead log: {}", e);
+            DatabaseError::IoError(e)
+        })?;
+        // The file is preallocated, so this rarely has metadata to flush.
+        self.file
+            .sync_data()
+            .await
+            .map_err(DatabaseError::IoError)?;
+
+        self.offset += buffer.len() as u64;
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate_file(file: &std::fs::File, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `posix_fallocate` only operates on a descriptor we own.
+    let ret = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) };
+    match ret {
+        0 => Ok(()),
+        // Not every filesystem can reserve blocks; a zero-filled extent is
+        // the next best thing.
+        libc::EOPNOTSUPP | libc::EINVAL => file.set_len(len),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate_file(file: &std::fs::File, len: u64) -> std::io::Result<()> {
+    file.set_len(len)
+}
+
+pub(crate) fn wal_dir(folder_path: &str) -> PathBuf {
+    Path::new(folder_path).join(WAL_DIR)
+}
+
+pub(crate) fn log_path(folder_path: &str, sequence: u64) -> PathBuf {
+    wal_dir(folder_path).join(format!("{:020}.{}", sequence, WAL_EXTENSION))
+}
+
+/// Reads every intact record of a log file, stopping at a torn tail.
+pub(crate) async fn read_log(path: &Path, sequence: u64) -> Result<Vec<WalRecord>, DatabaseError> {
+    let buffer = tokio::fs::read(path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while offset + HEADER_LEN <= buffer.len() {
+        let len = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buffer[offset + 4..offset + 8].try_into().unwrap());
+        let start = offset + HEADER_LEN;
+
+        if len == 0 || start + len > buffer.len() {
+            break;
+        }
+
+        let payload = &buffer[start..start + len];
+        if checksum(sequence, payload) != crc {
+            if offset > 0 {
+                warn!(
+                    "Ignoring torn write-ahead log record at offset {} in {:?}",
+                    offset, path
+                );
+            }
+            break;
+        }
+
+        let doc = Document::from_reader(payload).map_err(DatabaseError::BsonDeError)?;
+        records.push(WalRecord::from_document(&doc)?);
+        offset = start + len;
+    }
+
+    Ok(records)
+}
+
+/// Lists existing log files ordered by sequence number.
+pub(crate) async fn list_logs(folder_path: &str) -> Result<Vec<(u64, PathBuf)>, DatabaseError> {
+    let mut entries = match tokio::fs::read_dir(wal_dir(folder_path)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(DatabaseError::IoError(e)),
+    };
+
+    let mut logs = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(WAL_EXTENSION) {
+            continue;
+        }
+        if let Some(sequence) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            logs.push((sequence, path));
+        }
+    }
+    logs.sort();
+
+    Ok(logs)
+}
+
+/// Re-applies every logged operation to the document files and removes the
+/// logs afterwards. Applying a record twice is harmless. Answers the oplog
+/// entries the logs carried, in order.
+pub(crate) async fn replay(folder_path: &str) -> Result<Vec<Document>, DatabaseError> {
+    let logs = list_logs(folder_path).await?;
+    if logs.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut applied = 0;
+    let mut collections = BTreeSet::new();
+    let mut logged = Vec::new();
+    for (sequence, path) in &logs {
+        for record in read_log(path, *sequence).await? {
+            apply(folder_path, &record).await?;
+            record.collections(&mut collections);
+            record.logged(&mut logged);
+            applied += 1;
+        }
+    }
+    sync_collections(folder_path, &collections).await?;
+
+    for (_, path) in logs {
+        recycle(folder_path, &path).await?;
+    }
+
+    info!(
+        "Successfully replayed {} write-ahead log records in '{}'",
+        applied, folder_path
+    );
+
+    Ok(logged)
+}
+
+/// Retires a log file whose records are all applied, keeping it for reuse
+/// unless enough retired files are already around.
+pub(crate) async fn recycle(folder_path: &str, path: &Path) -> Result<(), DatabaseError> {
+    if recycled_logs(folder_path).await?.len() < MAX_RECYCLED_LOGS {
+        tokio::fs::rename(path, path.with_extension(RECYCLED_EXTENSION))
+            .await
+            .map_err(DatabaseError::IoError)
+    } else {
+        tokio::fs::remove_file(path)
+            .await
+            .map_err(DatabaseError::IoError)
+    }
+}
+
+/// First sequence number above every log file, live or recycled, so a
+/// reused file can never carry records that look current.
+pub(crate) async fn next_sequence(folder_path: &str) -> Result<u64, DatabaseError> {
+    let logs = list_logs(folder_path).await?;
+    let recycled = recycled_logs(folder_path).await?;
+
+    let highest = logs
+        .into_iter()
+        .map(|(_, path)| path)
+        .chain(recycled)
+        .filter_map(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+        })
+        .max()
+        .unwrap_or(0);
+
+    Ok(highest + 1)
+}
+
+async fn take_recycled(folder_path: &str) -> Result<Option<PathBuf>, DatabaseError> {
+    Ok(recycled_logs(folder_path).await?.into_iter().next())
+}
+
+async fn recycled_logs(folder_path: &str) -> Result<Vec<PathBuf>, DatabaseError> {
+    let mut entries = match tokio::fs::read_dir(wal_dir(folder_path)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(DatabaseError::IoError(e)),
+    };
+
+    let mut recycled = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(DatabaseError::IoError)? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(RECYCLED_EXTENSION) {
+            recycled.push(path);
+        }
+    }
+    recycled.sort();
+
+    Ok(recycled)
+}
+
+pub(crate) async fn apply(folder_path: &str, record: &WalRecord) -> Result<(), DatabaseError> {
+    match record {
+        WalRecord::Insert {
+            collection,
+            id,
+            doc,
+        } => write_document(folder_path, collection, id, doc).await,
+        WalRecord::Batch(records) => {
+            for record in records {
+                Box::pin(apply(folder_path, record)).await?;
+            }
+            Ok(())
+        }
+        WalRecord::Logged(_) => Ok(()),
+        WalRecord::Delete { collection, id } => {
+            let codec = codec::read(folder_path, collection)
+                .await?
+                .unwrap_or_default();
+            let path = format!(
+                "{}/{}/{}.{}",
+                folder_path,
+                collection,
+                id,
+                codec.extension()
+            );
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(DatabaseError::IoError(e))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+pub(crate) async fn write_document(
+    folder_path: &str,
+    collection: &str,
+    id: &str,
+    doc: &Document,
+) -> Result<(), DatabaseError> {
+    let codec = codec::read(folder_path, collection)
+        .await?
+        .unwrap_or_default();
+    let buffer = codec.encode(doc)?;
+
+    let collection_path = format!("{}/{}", folder_path, collection);
+    tokio::fs::create_dir_all(&collection_path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+
+    let path = format!("{}/{}.{}", collection_path, id, codec.extension());
+    write_synced(&path, &buffer).await
+}
+
+/// Writes a file and waits until its contents are durable.
+pub(crate) async fn write_synced(path: &str, buffer: &[u8]) -> Result<(), DatabaseError> {
+    let mut file = tokio::fs::File::create(path).await.map_err(|e| {
+        error!("Failed to write document: {}", e);
+        DatabaseError::IoError(e)
+    })?;
+    file.write_all(buffer)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    file.sync_all().await.map_err(DatabaseError::IoError)
+}
+
+/// Makes the document files written to and removed from `collections`
+/// durable, along with any collection directory created for them; needed
+/// before the log holding those writes goes.
+pub(crate) async fn sync_collections(
+    folder_path: &str,
+    collections: &BTreeSet<String>,
+) -> Result<(), DatabaseError> {
+    for collection in collections {
+        match sync_directory(&format!("{}/{}", folder_path, collection)).await {
+            // Only deletes named it, and there was nothing to delete.
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    sync_directory(folder_path).await
+}
+
+/// Makes creations and removals of entries in a directory durable.
+pub(crate) async fn sync_directory(path: &str) -> Result<(), DatabaseError> {
+    let dir = tokio::fs::File::open(path)
+        .await
+        .map_err(DatabaseError::IoError)?;
+    dir.sync_all().await.map_err(DatabaseError::IoError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_log_stops_at_torn_tail() {
+        let folder_path = "data_tests/test_wal_torn_tail";
+        let _ = tokio::fs::remove_dir_all(folder_path).await;
+        tokio::fs::create_dir_all(wal_dir(folder_path))
+            .await
+            .unwrap();
+
+        let records = vec![
+            WalRecord::Insert {
+                collection: "users".to_string(),
+                id: "a".to_string(),
+                doc: bson::doc! { "name": "John" },
+            },
+            WalRecord::Delete {
+                collection: "users".to_string(),
+                id: "b".to_string(),
+            },
+        ];
+
+        let path = log_path(folder_path, 1);
+        let mut writer = WalWriter::create(folder_path, 1, 0).await.unwrap();
+        writer.append(&records).await.unwrap();
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).await.unwrap();
+
+        assert_eq!(read_log(&path, 1).await.unwrap(), records);
+    }
+
+    #[tokio::test]
+    async fn test_replay_applies_records() {
+        let folder_path = "data_tests/test_wal_replay";
+        let _ = tokio::fs::remove_dir_all(folder_path).await;
+        tokio::fs::create_dir_all(wal_dir(folder_path))
+            .await
+            .unwrap();
+
+        let mut writer = WalWriter::create(folder_path, 1, 4096).await.unwrap();
+        writer
+            .append(&[
+                WalRecord::Insert {
+                    collection: "users".to_string(),
+                    id: "a".to_string(),
+                    doc: bson::doc! { "name": "John" },
+                },
+                WalRecord::Insert {
+                    collection: "users".to_string(),
+                    id: "b".to_string(),
+                    doc: bson::doc! { "name": "Jane" },
+                },
+                WalRecord::Delete {
+                    collection: "users".to_string(),
+                    id: "b".to_string(),
+                },
+            ])
+            .await
+            .unwrap();
+
+        replay(folder_path).await.unwrap();
+
+        assert!(tokio::fs::metadata(format!("{}/users/a.bson", folder_path))
+            .await
+            .is_ok());
+        assert!(tokio::fs::metadata(format!("{}/users/b.bson", folder_path))
+            .await
+            .is_err());
+        assert!(list_logs(folder_path).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recycled_log_hides_stale_records() {
+        let folder_path = "data_tests/test_wal_recycle";
+        let _ = tokio::fs::remove_dir_all(folder_path).await;
+        tokio::fs::create_dir_all(wal_dir(folder_path))
+            .await
+            .unwrap();
+
+        let insert = |id: &str| WalRecord::Insert {
+            collection: "users".to_string(),
+            id: id.to_string(),
+            doc: bson::doc! { "name": "John" },
+        };
+
+        let mut writer = WalWriter::create(folder_path, 1, 4096).await.unwrap();
+        writer.append(&[insert("a"), insert("b")]).await.unwrap();
+        let len = tokio::fs::metadata(writer.path()).await.unwrap().len();
+        assert_eq!(len, 4096);
+
+        recycle(folder_path, &log_path(folder_path, 1))
+            .await
+            .unwrap();
+
+        let mut writer = WalWriter::create(folder_path, 2, 4096).await.unwrap();
+        writer.append(&[insert("c")]).await.unwrap();
+
+        assert_eq!(read_log(writer.path(), 2).await.unwrap(), vec![insert("c")]);
+        assert!(take_recycled(folder_path).await.unwrap().is_none());
+    }
+}
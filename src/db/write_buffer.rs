@@ -0,0 +1,524 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use bson::Document;
+use log::{error, info};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::AbortHandle;
+
+use super::defrag::Activity;
+use super::locks::CollectionLocks;
+use super::wal::{self, LogSequence, WalRecord, WalWriter};
+use super::DatabaseError;
+
+#[derive(Debug, Clone)]
+pub struct WriteBufferOptions {
+    /// How often buffered inserts are written to their document files.
+    pub flush_interval: Duration,
+    /// Flush early once this many inserts are waiting.
+    pub max_pending: usize,
+    /// Bytes reserved up front for each write-ahead log file, so appends
+    /// don't grow the file (and churn its metadata) on every write.
+    pub wal_preallocate: u64,
+}
+
+impl Default for WriteBufferOptions {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_millis(200),
+            max_pending: 1000,
+            wal_preallocate: 4 * 1024 * 1024,
+        }
+    }
+}
+
+type Key = (String, String);
+
+/// Write-behind buffer: inserts are made durable in the WAL and kept in
+/// memory until a background task moves them into document files.
+pub(crate) struct WriteBuffer {
+    folder_path: String,
+    options: WriteBufferOptions,
+    sequence: Arc<LogSequence>,
+    state: Mutex<BufferState>,
+    flush_lock: Mutex<()>,
+    flush_requested: Notify,
+    /// The background flusher, once spawned.
+    flusher: std::sync::Mutex<Option<AbortHandle>>,
+}
+
+struct BufferState {
+    wal: WalWriter,
+    pending: HashMap<Key, Document>,
+    /// Inserts currently being written out; still served to readers.
+    flushing: Arc<HashMap<Key, Document>>,
+    /// Documents deleted while their flush was in progress.
+    cancelled: HashSet<Key>,
+    /// Logs whose flush failed; their records were moved back to `pending`.
+    stale_logs: Vec<PathBuf>,
+}
+
+impl WriteBuffer {
+    pub(crate) async fn start(
+        folder_path: String,
+        options: WriteBufferOptions,
+        sequence: Arc<LogSequence>,
+    ) -> Result<Arc<Self>, DatabaseError> {
+        let state = Self::open_state(&folder_path, &options, &sequence).await?;
+        Ok(Arc::new(Self {
+            folder_path,
+            options,
+            sequence,
+            state: Mutex::new(state),
+            flush_lock: Mutex::new(()),
+            flush_requested: Notify::new(),
+            flusher: std::sync::Mutex::new(None),
+        }))
+    }
+
+    /// Starts the task writing buffered inserts out every `flush_interval`.
+    /// It flushes as foreground writes do: under an `activity` guard, so
+    /// the defragmenter can't swap a directory out from under it, and with
+    /// the collections it writes to locked.
+    pub(crate) fn spawn_flusher(
+        self: &Arc<Self>,
+        activity: Arc<Activity>,
+        locks: Arc<CollectionLocks>,
+    ) {
+        let task = tokio::spawn(Self::run(
+            Arc::downgrade(self),
+            self.options.flush_interval,
+            activity,
+            locks,
+        ));
+        *self.flusher.lock().unwrap_or_else(|e| e.into_inner()) = Some(task.abort_handle());
+    }
+
+    /// Stops the background flusher; later flushes are up to the caller.
+    pub(crate) fn stop(&self) {
+        if let Some(flusher) = self
+            .flusher
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        {
+            flusher.abort();
+        }
+    }
+
+    async fn open_state(
+        folder_path: &str,
+        options: &WriteBufferOptions,
+        sequence: &LogSequence,
+    ) -> Result<BufferState, DatabaseError> {
+        tokio::fs::create_dir_all(wal::wal_dir(folder_path))
+            .await
+            .map_err(DatabaseError::IoError)?;
+
+        let wal = WalWriter::create(folder_path, sequence.next(), options.wal_preallocate).await?;
+
+        Ok(BufferState {
+            wal,
+            pending: HashMap::new(),
+            flushing: Arc::new(HashMap::new()),
+            cancelled: HashSet::new(),
+            stale_logs: Vec::new(),
+        })
+    }
+
+    async fn run(
+        buffer: Weak<WriteBuffer>,
+        interval: Duration,
+        activity: Arc<Activity>,
+        locks: Arc<CollectionLocks>,
+    ) {
+        while let Some(buffer) = buffer.upgrade() {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = buffer.flush_requested.notified() => {}
+            }
+
+            let Ok(_guard) = activity.begin().await else {
+                // Closed; whatever is left was flushed by `close`.
+                break;
+            };
+            if let Err(e) = buffer.flush_locked(&locks).await {
+                error!("Failed to flush write buffer: {:?}", e);
+            }
+        }
+    }
+
+    /// `flush`, with every collection it writes to locked for writing.
+    async fn flush_locked(&self, locks: &CollectionLocks) -> Result<(), DatabaseError> {
+        loop {
+            let collections: BTreeSet<String> = {
+                let state = self.state.lock().await;
+                state.pending.keys().map(|(c, _)| c.clone()).collect()
+            };
+            let _locks = locks
+                .write_all(collections.iter().map(String::as_str))
+                .await;
+            // Inserts into other collections may have come in meanwhile.
+            if self.flush_within(Some(&collections)).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    pub(crate) async fn insert(
+        &self,
+        collection: &str,
+        id: &str,
+        doc: Document,
+    ) -> Result<(), DatabaseError> {
+        let mut state = self.state.lock().await;
+
+        state
+            .wal
+            .append(&[WalRecord::Insert {
+                collection: collection.to_string(),
+                id: id.to_string(),
+                doc: doc.clone(),
+            }])
+            .await?;
+        state
+            .pending
+            .insert((collection.to_string(), id.to_string()), doc);
+
+        if state.pending.len() >= self.options.max_pending {
+            self.flush_requested.notify_one();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn get(&self, collection: &str, id: &str) -> Option<Document> {
+        let key = (collection.to_string(), id.to_string());
+        let state = self.state.lock().await;
+
+        if let Some(doc) = state.pending.get(&key) {
+            return Some(doc.clone());
+        }
+        if state.cancelled.contains(&key) {
+            return None;
+        }
+        state.flushing.get(&key).cloned()
+    }
+
+    /// Every buffered document of `collection` by ID, as `get` would
+    /// answer for each; these supersede what their files hold.
+    pub(crate) async fn documents(&self, collection: &str) -> HashMap<String, Document> {
+        let state = self.state.lock().await;
+
+        let flushing = state
+            .flushing
+            .iter()
+            .filter(|(key, _)| !state.cancelled.contains(*key));
+        flushing
+            .chain(state.pending.iter())
+            .filter(|((c, _), _)| c == collection)
+            .map(|((_, id), doc)| (id.clone(), doc.clone()))
+            .collect()
+    }
+
+    /// Drops a buffered insert. Returns whether the document was buffered;
+    /// the caller still removes any document file.
+    pub(crate) async fn delete(&self, collection: &str, id: &str) -> Result<bool, DatabaseError> {
+        let key = (collection.to_string(), id.to_string());
+        let mut state = self.state.lock().await;
+
+        let buffered = state.pending.remove(&key).is_some()
+            || (state.flushing.contains_key(&key) && state.cancelled.insert(key));
+        if buffered {
+            // The insert is still in the log; record the delete so replay
+            // doesn't bring the document back.
+            state
+                .wal
+                .append(&[WalRecord::Delete {
+                    collection: collection.to_string(),
+                    id: id.to_string(),
+                }])
+                .await?;
+        }
+
+        Ok(buffered)
+    }
+
+    /// Inserts logged but not yet in their document files.
+    pub(crate) async fn pending_count(&self) -> usize {
+        let state = self.state.lock().await;
+        state.pending.len() + state.flushing.len()
+    }
+
+    /// Writes every buffered insert to its document file and retires the
+    /// log that covered them.
+    pub(crate) async fn flush(&self) -> Result<(), DatabaseError> {
+        self.flush_within(None).await.map(|_| ())
+    }
+
+    /// `flush`, unless there are inserts buffered for collections outside
+    /// `locked`. Answers whether it went ahead.
+    async fn flush_within(&self, locked: Option<&BTreeSet<String>>) -> Result<bool, DatabaseError> {
+        let _flush = self.flush_lock.lock().await;
+
+        let (flushing, retired_log) = {
+            let mut state = self.state.lock().await;
+            if state.pending.is_empty() {
+                return Ok(true);
+            }
+            if locked.is_some_and(|locked| {
+                state
+                    .pending
+                    .keys()
+                    .any(|(collection, _)| !locked.contains(collection))
+            }) {
+                return Ok(false);
+            }
+
+            let next = WalWriter::create(
+                &self.folder_path,
+                self.sequence.next(),
+                self.options.wal_preallocate,
+            )
+            .await?;
+            let retired = std::mem::replace(&mut state.wal, next);
+
+            let flushing = Arc::new(std::mem::take(&mut state.pending));
+            state.flushing = flushing.clone();
+
+            (flushing, retired.path().to_path_buf())
+        };
+
+        for ((collection, id), doc) in flushing.iter() {
+            if let Err(e) = wal::write_document(&self.folder_path, collection, id, doc).await {
+                self.restore(retired_log).await;
+                return Err(e);
+            }
+        }
+
+        let stale_logs = {
+            let mut state = self.state.lock().await;
+            for (collection, id) in state.cancelled.drain() {
+                wal::apply(&self.folder_path, &WalRecord::Delete { collection, id }).await?;
+            }
+            state.flushing = Arc::new(HashMap::new());
+            std::mem::take(&mut state.stale_logs)
+        };
+        let collections = flushing
+            .keys()
+            .map(|(collection, _)| collection.clone())
+            .collect();
+        wal::sync_collections(&self.folder_path, &collections).await?;
+
+        for path in stale_logs.iter().chain(std::iter::once(&retired_log)) {
+            wal::recycle(&self.folder_path, path).await?;
+        }
+
+        info!(
+            "Successfully flushed {} buffered documents in '{}'",
+            flushing.len(),
+            self.folder_path
+        );
+
+        Ok(true)
+    }
+
+    /// Puts a failed flush back in line for the next attempt.
+    async fn restore(&self, retired_log: PathBuf) {
+        let mut state = self.state.lock().await;
+
+        let flushing = std::mem::take(&mut state.flushing);
+        let cancelled = std::mem::take(&mut state.cancelled);
+        for (key, doc) in flushing.iter() {
+            if !cancelled.contains(key) {
+                state
+                    .pending
+                    .entry(key.clone())
+                    .or_insert_with(|| doc.clone());
+            }
+        }
+        for (collection, id) in cancelled {
+            // The file may have been written before the failure.
+            if let Err(e) =
+                wal::apply(&self.folder_path, &WalRecord::Delete { collection, id }).await
+            {
+                error!("Failed to remove cancelled document: {:?}", e);
+            }
+        }
+        state.stale_logs.push(retired_log);
+    }
+
+    /// Forgets everything buffered, for when the database folder was wiped.
+    pub(crate) async fn reset(&self) -> Result<(), DatabaseError> {
+        let _flush = self.flush_lock.lock().await;
+        let mut state = self.state.lock().await;
+
+        *state = Self::open_state(&self.folder_path, &self.options, &self.sequence).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Codec, Database, DatabaseOptions};
+    use super::*;
+
+    async fn write_behind_db(id: &str) -> Database {
+        let folder_path = format!("data_tests/{}", id);
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+
+        let options = DatabaseOptions {
+            write_buffer: Some(WriteBufferOptions {
+                flush_interval: Duration::from_secs(3600),
+                ..WriteBufferOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+
+        Database::init_with_options(folder_path, options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_buffered_documents_are_readable() {
+        let db = write_behind_db("test_write_buffer_read").await;
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+
+        let path = db.get_document_path("users", &id, Codec::Bson).unwrap();
+        assert!(tokio::fs::metadata(&path).await.is_err());
+
+        let found_doc = db.find_one("users".to_string(), id.clone()).await.unwrap();
+        assert_eq!(found_doc, Some(bson::doc! { "name": "John" }));
+
+        let found_docs = db
+            .find("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        assert_eq!(found_docs.len(), 1);
+        let found_docs = db
+            .find("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        assert!(found_docs.is_empty());
+        // Found without being flushed to its file.
+        assert!(tokio::fs::metadata(&path).await.is_err());
+
+        db.flush().await.unwrap();
+        let found_docs = db
+            .find("users".to_string(), bson::Document::new())
+            .await
+            .unwrap();
+        assert_eq!(found_docs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unflushed_inserts_survive_restart() {
+        let folder_path = "data_tests/test_write_buffer_restart".to_string();
+        let db = write_behind_db("test_write_buffer_restart").await;
+
+        let kept = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let deleted = db
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        db.delete_one("users".to_string(), deleted.clone())
+            .await
+            .unwrap();
+        drop(db);
+
+        let db = Database::init(folder_path).await.unwrap();
+
+        assert!(db
+            .find_one("users".to_string(), kept)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(db
+            .find_one("users".to_string(), deleted)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flusher_waits_for_collection_lock() {
+        let folder_path = "data_tests/test_write_buffer_flusher".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            write_buffer: Some(WriteBufferOptions {
+                flush_interval: Duration::from_millis(10),
+                ..WriteBufferOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let path = db.get_document_path("users", &id, Codec::Bson).unwrap();
+        let lock = db.inner.locks.write("users").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tokio::fs::metadata(&path).await.is_err());
+
+        drop(lock);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tokio::fs::metadata(&path).await.is_ok());
+
+        db.close().await.unwrap();
+        let write_buffer = db.inner.write_buffer.as_ref().unwrap();
+        assert!(write_buffer.flusher.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_close_flushes_and_releases_folder() {
+        let folder_path = "data_tests/test_write_buffer_close".to_string();
+        let db = write_behind_db("test_write_buffer_close").await;
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let clone = db.clone();
+        db.close().await.unwrap();
+        db.close().await.unwrap();
+
+        assert_eq!(
+            db.inner
+                .write_buffer
+                .as_ref()
+                .unwrap()
+                .pending_count()
+                .await,
+            0
+        );
+        assert!(matches!(
+            clone
+                .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+                .await,
+            Err(DatabaseError::Closed)
+        ));
+
+        // Opens while the closed handles are still around.
+        let reopened = Database::init(folder_path).await.unwrap();
+        assert!(reopened
+            .find_one("users".to_string(), id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -1 +1,5 @@
+#[cfg(feature = "client")]
+pub mod client;
 pub mod db;
+pub mod server;
+pub mod sharding;
@@ -1,58 +1,364 @@
+//! `owldb`: reads and writes a database folder from the command line.
+//! Documents go in and come out as MongoDB Extended JSON, with their ID
+//! under `_id`.
+//!
+//! ```text
+//! owldb --path data insert users '{"name": "John", "age": 30}'
+//! owldb find users '{"age": 30}'
+//! owldb find users --output csv --fields name,age
+//! owldb delete users '{"name": "John"}'
+//! owldb stats --json
+//! owldb dump --out backups/today --gzip
+//! owldb compact --dry-run
+//! owldb bench --workload insert,find --concurrency 8 --duration 5
+//! owldb verify --repair
+//! owldb --path data serve --listen 0.0.0.0:8080 --auth
+//! ```
+
+use clap::{Parser, Subcommand};
+use cli::{format_documents, insert, not_found, parse, Format, Output};
 use env_logger::Builder;
 use log::LevelFilter;
+use owldb::db::{verify, Database, DatabaseError, VerifyOptions};
 
-pub mod db;
+mod cli;
 
-const DB_FOLDER: &str = "data";
+#[derive(Debug, Parser)]
+#[command(name = "owldb", about = "Reads and writes an owldb database folder")]
+struct Cli {
+    /// The database folder, like the server's `data` setting; `data` if
+    /// not given.
+    #[arg(long, env = "OWLDB_DATA")]
+    path: Option<String>,
+    /// Same as `--output ndjson`.
+    #[arg(long, conflicts_with = "output")]
+    raw: bool,
+    /// How `get` and `find` print documents.
+    #[arg(long, global = true, value_enum, default_value_t = Format::Json)]
+    output: Format,
+    /// Only these fields, by dotted path and comma separated; JSON keeps
+    /// `_id` whatever they are.
+    #[arg(long, global = true, value_delimiter = ',')]
+    fields: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
 
-fn test_documents() -> Vec<bson::Document> {
-    vec![
-        bson::doc! {
-            "name": "John",
-            "age": 30
-        },
-        bson::doc! {
-            "name": "Jane",
-            "age": 25
-        },
-        bson::doc! {
-            "name": "John",
-            "age": 25
-        },
-    ]
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Inserts a document, under its `_id` if it has one, failing if that
+    /// ID is taken; prints the ID.
+    Insert {
+        collection: String,
+        document: String,
+    },
+    /// Prints the document under an ID.
+    Get { collection: String, id: String },
+    /// Prints the documents matching a filter such as
+    /// `{"age": {"$gt": 25}}`, or all of them.
+    Find {
+        collection: String,
+        #[arg(default_value = "{}")]
+        filter: String,
+    },
+    /// Applies an update such as `{"$set": {"age": 31}}` to the document
+    /// under an ID.
+    Update {
+        collection: String,
+        id: String,
+        update: String,
+    },
+    /// Deletes the documents matching a filter; prints their IDs.
+    Delete { collection: String, filter: String },
+    /// Opens an interactive prompt taking mongo shell statements such as
+    /// `db.users.find({"name": "John"})`.
+    Shell,
+    /// Prints each collection's documents, size on disk, index size and
+    /// largest documents, biggest collection first.
+    Stats {
+        /// Print JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rewrites collection directories bloated by deleted documents, with
+    /// a progress bar for each.
+    Compact {
+        /// Only these collections; every one if none are given.
+        collections: Vec<String>,
+        /// Only estimate the space that would be reclaimed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Writes collections to a directory of Extended JSON files, or the
+    /// whole database to a backup archive.
+    Dump(cli::dump::DumpArgs),
+    /// Reads back what `dump` wrote.
+    Restore(cli::dump::RestoreArgs),
+    /// Checks every document and what interrupted writes left, in a
+    /// folder nothing has open. Exits 0 if all is well, 2 if every
+    /// problem found was repaired, 3 if some remain and 1 if it couldn't
+    /// check.
+    Verify {
+        /// Quarantine documents that don't decode, apply logged writes and
+        /// clean up after interrupted changes.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Times inserts, finds and updates against a scratch database, with
+    /// the given storage options; leaves `--path` alone.
+    Bench(cli::bench::BenchArgs),
+    /// Serves the database over the network, like `owldb-server`.
+    #[cfg(feature = "http")]
+    Serve(cli::serve::ServeArgs),
+}
+
+impl Command {
+    fn writes(&self) -> bool {
+        matches!(
+            self,
+            Command::Insert { .. }
+                | Command::Update { .. }
+                | Command::Delete { .. }
+                | Command::Shell
+                | Command::Restore(_)
+                | Command::Compact { dry_run: false, .. }
+        )
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Builder::new().filter(None, LevelFilter::Info).init();
+async fn main() {
+    let cli = Cli::parse();
 
-    let mut database = db::Database::init(DB_FOLDER.to_string())
-        .await
-        .expect("Failed to initialize database");
+    // Logs go to stderr; only warnings unless serving or `RUST_LOG` asks
+    // for more.
+    let level = match cli.command {
+        #[cfg(feature = "http")]
+        Command::Serve(_) => LevelFilter::Info,
+        _ => LevelFilter::Warn,
+    };
+    Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+
+    match run(cli).await {
+        Ok(output) => {
+            print!("{}", output.text);
+            if output.status != 0 {
+                std::process::exit(output.status);
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the command, answering what it prints.
+async fn run(cli: Cli) -> Result<Output, String> {
+    #[cfg(feature = "http")]
+    if let Command::Serve(args) = cli.command {
+        return cli::serve::run(cli.path, args)
+            .await
+            .map(|_| Output::from(String::new()));
+    }
 
-    database.clear().await.expect("Failed to clear database");
+    if let Command::Bench(args) = cli.command {
+        return cli::bench::run(args)
+            .await
+            .map(Output::from)
+            .map_err(|e| format!("{:?}", e));
+    }
 
-    let documents = test_documents();
-    for doc in documents.clone() {
-        database
-            .insert_one("users".to_string(), doc)
+    let path = cli.path.unwrap_or_else(|| "data".to_string());
+    if let Command::Verify { repair } = cli.command {
+        let report = verify(&path, VerifyOptions { repair })
             .await
-            .expect("Failed to insert document");
+            .map_err(|e| format!("Failed to verify '{}': {:?}", path, e))?;
+        return Ok(cli::verify::output(report));
     }
 
-    let all = database
-        .find("users".to_string(), bson::doc! {})
+    // Reads don't need the folder to themselves, so they work next to a
+    // running server.
+    let database = match cli.command.writes() {
+        true => Database::init(path.clone()).await,
+        false => Database::open_read_only(path.clone()).await,
+    }
+    .map_err(|e| format!("Failed to open database '{}': {:?}", path, e))?;
+
+    let format = match cli.raw {
+        true => Format::Ndjson,
+        false => cli.output,
+    };
+    let output = match cli.command {
+        Command::Shell => cli::shell::run(&database).await.map(|_| String::new()),
+        command => execute(&database, command, format, &cli.fields)
+            .await
+            .map_err(|e| format!("{:?}", e)),
+    };
+    database
+        .close()
         .await
-        .expect("Failed to find all documents");
+        .map_err(|e| format!("Failed to close database: {:?}", e))?;
+    output.map(Output::from)
+}
+
+async fn execute(
+    database: &Database,
+    command: Command,
+    format: Format,
+    fields: &[String],
+) -> Result<String, DatabaseError> {
+    match command {
+        Command::Insert {
+            collection,
+            document,
+        } => {
+            let id = insert(database, collection, parse(&document)?).await?;
+            Ok(format!("{}\n", id))
+        }
+        Command::Get { collection, id } => match database.find_one(collection, id.clone()).await? {
+            Some(doc) => Ok(format_documents(vec![(id, doc)], format, fields, false)),
+            None => Err(not_found(&id)),
+        },
+        Command::Find { collection, filter } => {
+            let documents = database.find_with_ids(collection, parse(&filter)?).await?;
+            Ok(format_documents(documents, format, fields, true))
+        }
+        Command::Update {
+            collection,
+            id,
+            update,
+        } => match database
+            .update_one(collection, id.clone(), parse(&update)?)
+            .await?
+        {
+            true => Ok(format!("{}\n", id)),
+            false => Err(not_found(&id)),
+        },
+        Command::Delete { collection, filter } => {
+            let deleted = database.delete(collection, parse(&filter)?).await?;
+            Ok(deleted.iter().map(|id| format!("{}\n", id)).collect())
+        }
+        Command::Stats { json } => cli::stats::run(database, json).await,
+        Command::Dump(args) => cli::dump::dump(database, args).await,
+        Command::Restore(args) => cli::dump::restore(database, args).await,
+        Command::Compact {
+            collections,
+            dry_run,
+        } => cli::compact::run(database, collections, dry_run).await,
+        // Handled by `run`, which can't answer a `DatabaseError`.
+        Command::Shell | Command::Verify { .. } | Command::Bench(_) => Ok(String::new()),
+        #[cfg(feature = "http")]
+        Command::Serve(_) => Ok(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
 
-    assert_eq!(all.len(), documents.len());
+    use super::*;
 
-    let found_docs = database
-        .find("users".to_string(), bson::doc! { "name": "John" })
+    async fn owldb(args: &[&str]) -> Result<String, String> {
+        let cli = Cli::try_parse_from(
+            ["owldb", "--path", "data_tests/test_cli"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap();
+        run(cli).await.map(|output| output.text)
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[tokio::test]
+    async fn test_cli_crud() {
+        let _ = tokio::fs::remove_dir_all("data_tests/test_cli").await;
+
+        let id = owldb(&["insert", "users", r#"{"name": "John", "age": 30}"#])
+            .await
+            .unwrap();
+        owldb(&[
+            "insert",
+            "users",
+            r#"{"_id": "jane", "name": "Jane", "age": 25}"#,
+        ])
         .await
-        .expect("Failed to find documents");
+        .unwrap();
 
-    assert_eq!(found_docs.len(), 2);
+        let found = owldb(&["--raw", "find", "users", r#"{"age": 30}"#])
+            .await
+            .unwrap();
+        assert_eq!(
+            found,
+            format!(
+                "{{\"_id\":\"{}\",\"name\":\"John\",\"age\":30}}\n",
+                id.trim()
+            )
+        );
+        assert_eq!(
+            owldb(&[
+                "find",
+                "users",
+                r#"{"age": 30}"#,
+                "--output",
+                "csv",
+                "--fields",
+                "name"
+            ])
+            .await
+            .unwrap(),
+            "name\r\nJohn\r\n"
+        );
+        let jane: serde_json::Value =
+            serde_json::from_str(&owldb(&["get", "users", "jane"]).await.unwrap()).unwrap();
+        assert_eq!(jane["name"], "Jane");
+        let all: serde_json::Value =
+            serde_json::from_str(&owldb(&["find", "users"]).await.unwrap()).unwrap();
+        assert_eq!(all.as_array().unwrap().len(), 2);
+        assert_eq!(
+            owldb(&["--raw", "find", "users", r#"{"age": {"$gt": 25}}"#])
+                .await
+                .unwrap(),
+            found
+        );
+        let unknown = owldb(&["find", "users", r#"{"age": {"$near": 25}}"#])
+            .await
+            .unwrap_err();
+        assert!(
+            unknown.contains("unknown query operator '$near'"),
+            "{}",
+            unknown
+        );
+
+        owldb(&["update", "users", "jane", r#"{"$set": {"age": 26}}"#])
+            .await
+            .unwrap();
+        assert_eq!(
+            owldb(&["delete", "users", r#"{"age": 26}"#]).await.unwrap(),
+            "jane\n"
+        );
+        assert!(owldb(&["get", "users", "jane"]).await.is_err());
+        assert!(owldb(&["insert", "users", "[1, 2]"]).await.is_err());
 
-    Ok(())
+        // IDs are strings or ObjectIds, and taken once.
+        let oid = "64b7f0c2a1b2c3d4e5f60718";
+        let bob = format!(r#"{{"_id": {{"$oid": "{}"}}, "name": "Bob"}}"#, oid);
+        assert_eq!(
+            owldb(&["insert", "users", &bob]).await.unwrap(),
+            format!("{}\n", oid)
+        );
+        let taken = format!(r#"{{"_id": "{}", "name": "Eve"}}"#, oid);
+        assert!(owldb(&["insert", "users", &taken]).await.is_err());
+        let bob: serde_json::Value =
+            serde_json::from_str(&owldb(&["get", "users", oid]).await.unwrap()).unwrap();
+        assert_eq!(bob["name"], "Bob");
+        assert!(owldb(&["insert", "users", r#"{"_id": 42}"#]).await.is_err());
+    }
 }
@@ -0,0 +1,204 @@
+//! An append-only record of the data operations clients make through the
+//! front ends: who did what to which collection, and when.
+//!
+//! Events go either to a file, one relaxed Extended JSON object per line,
+//! or to the `_audit` collection of the tenant they happened in, which no
+//! front end can reach. An operation that can't be recorded isn't carried
+//! out.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use bson::{Bson, Document};
+use log::info;
+use tokio::io::AsyncWriteExt;
+
+use crate::db::{Database, DatabaseError};
+
+pub const AUDIT_COLLECTION: &str = "_audit";
+
+/// Where events are recorded. Cloning is cheap; clones share the sink.
+#[derive(Clone, Default)]
+pub struct Audit {
+    sink: Option<Arc<Sink>>,
+}
+
+enum Sink {
+    File(tokio::sync::Mutex<tokio::fs::File>),
+    Collection,
+}
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sink = match self.sink.as_deref() {
+            None => "disabled",
+            Some(Sink::File(_)) => "file",
+            Some(Sink::Collection) => "collection",
+        };
+        f.debug_struct("Audit").field("sink", &sink).finish()
+    }
+}
+
+/// One operation, as a front end describes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    /// What was done, such as `insert` or `find`.
+    pub operation: String,
+    pub collection: String,
+    /// The document operated on, for operations on a single one.
+    pub id: Option<String>,
+    /// The query selecting the documents operated on.
+    pub filter: Option<Document>,
+}
+
+impl AuditEvent {
+    pub fn new(operation: &str, collection: &str) -> Self {
+        Self {
+            operation: operation.to_string(),
+            collection: collection.to_string(),
+            id: None,
+            filter: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_filter(mut self, filter: Document) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+}
+
+impl Audit {
+    /// Records nothing.
+    pub fn disabled() -> Self {
+        Self { sink: None }
+    }
+
+    /// Appends events to the file at `path`, creating it if needed.
+    pub async fn to_file(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .await
+            .map_err(DatabaseError::IoError)?;
+        info!("Successfully opened audit log {}", path.as_ref().display());
+
+        Ok(Self {
+            sink: Some(Arc::new(Sink::File(tokio::sync::Mutex::new(file)))),
+        })
+    }
+
+    /// Inserts events into the `_audit` collection of their tenant.
+    pub fn to_collection() -> Self {
+        Self {
+            sink: Some(Arc::new(Sink::Collection)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Records that `username` made `event` in the tenant `database`,
+    /// whose data lives in `db`.
+    pub async fn record(
+        &self,
+        db: &Database,
+        database: &str,
+        username: &str,
+        event: AuditEvent,
+    ) -> Result<(), DatabaseError> {
+        let Some(sink) = self.sink.as_deref() else {
+            return Ok(());
+        };
+
+        let mut record = bson::doc! {
+            "timestamp": bson::DateTime::now(),
+            "database": database,
+            "user": username,
+            "operation": event.operation,
+            "collection": event.collection,
+        };
+        if let Some(id) = event.id {
+            record.insert("id", id);
+        }
+        if let Some(filter) = event.filter {
+            record.insert("filter", filter);
+        }
+
+        match sink {
+            Sink::File(file) => {
+                let mut line = Bson::Document(record).into_relaxed_extjson().to_string();
+                line.push('\n');
+                let mut file = file.lock().await;
+                file.write_all(line.as_bytes())
+                    .await
+                    .map_err(DatabaseError::IoError)?;
+                file.flush().await.map_err(DatabaseError::IoError)
+            }
+            Sink::Collection => db
+                .insert_one(AUDIT_COLLECTION.to_string(), record)
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audit_sinks() {
+        let folder_path = "data_tests/test_audit".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path.clone()).await.unwrap();
+        let event = AuditEvent::new("find", "users").with_filter(bson::doc! { "age": 30 });
+
+        Audit::disabled()
+            .record(&db, "default", "alice", event.clone())
+            .await
+            .unwrap();
+
+        Audit::to_collection()
+            .record(&db, "default", "alice", event.clone())
+            .await
+            .unwrap();
+        let recorded = db
+            .find(AUDIT_COLLECTION.to_string(), bson::doc! { "user": "alice" })
+            .await
+            .unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].get_str("operation").unwrap(), "find");
+        assert_eq!(
+            recorded[0].get_document("filter").unwrap(),
+            &bson::doc! { "age": 30 }
+        );
+
+        let path = format!("{}/audit.log", folder_path);
+        let audit = Audit::to_file(&path).await.unwrap();
+        audit.record(&db, "default", "alice", event).await.unwrap();
+        audit
+            .record(
+                &db,
+                "default",
+                "bob",
+                AuditEvent::new("delete", "users").with_id("42"),
+            )
+            .await
+            .unwrap();
+
+        let log = tokio::fs::read_to_string(&path).await.unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""filter":{"age":30}"#), "{}", lines[0]);
+        assert!(lines[1].contains(r#""user":"bob""#), "{}", lines[1]);
+        assert!(lines[1].contains(r#""id":"42""#), "{}", lines[1]);
+    }
+}
@@ -0,0 +1,843 @@
+//! Users and sessions for server mode.
+//!
+//! Users live in the `_users` collection with argon2-hashed passwords.
+//! Logging in hands out a bearer token, kept in memory, so restarting the
+//! server logs everybody out. Collections whose name starts with `_` are
+//! internal and can't be reached through any front end.
+//!
+//! What a user may do is set by the roles they're granted (see `roles`).
+//! A server whose database has no users yet logs a one-time bootstrap token,
+//! which creates the first admin, a `dbAdmin` of the whole database, through
+//! `Auth::bootstrap`.
+//!
+//! Admins can also create API keys: long-lived bearer tokens for devices
+//! and services, granted roles like users but never `dbAdmin`, so a key
+//! embedded in a device can be limited to, say, inserting into one
+//! collection. Keys live in `_api_keys`, hashed; requests made with one run
+//! as `key:<name>`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use blake2::{Blake2s256, Digest};
+use bson::Document;
+use log::{info, warn};
+
+use crate::db::{is_valid_name, Database, DatabaseError};
+use crate::server::roles::{Access, Grant, Role};
+
+pub const USERS_COLLECTION: &str = "_users";
+pub const API_KEYS_COLLECTION: &str = "_api_keys";
+
+/// Tells API keys apart from session tokens.
+const API_KEY_PREFIX: &str = "owk_";
+
+/// How long a bearer token stays valid after login.
+const TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Who a request runs as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub username: String,
+    pub grants: Vec<Grant>,
+}
+
+impl Principal {
+    /// Everyone, when authentication is disabled.
+    fn anonymous() -> Self {
+        Self {
+            username: String::new(),
+            grants: vec![Grant::database(Role::DbAdmin)],
+        }
+    }
+
+    /// May manage users and their grants.
+    pub fn is_admin(&self) -> bool {
+        self.grants.iter().any(Grant::is_admin)
+    }
+
+    fn from_user(user: &Document) -> Result<Self, DatabaseError> {
+        let grants = match user.get_array("roles") {
+            Ok(roles) => roles
+                .iter()
+                .filter_map(|role| role.as_document())
+                .map(Grant::from_document)
+                .collect::<Result<_, _>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Self {
+            username: user.get_str("username").unwrap_or_default().to_string(),
+            grants,
+        })
+    }
+}
+
+/// An API key, as admins see it; the key itself is only shown once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    pub grants: Vec<Grant>,
+}
+
+impl ApiKey {
+    fn principal(&self) -> Principal {
+        Principal {
+            username: format!("key:{}", self.name),
+            grants: self.grants.clone(),
+        }
+    }
+
+    fn from_document(doc: &Document) -> Result<Self, DatabaseError> {
+        let principal = Principal::from_user(doc)?;
+        Ok(Self {
+            name: doc.get_str("name").unwrap_or_default().to_string(),
+            grants: principal.grants,
+        })
+    }
+}
+
+/// Authentication for the front ends. Cloning is cheap; clones share their
+/// sessions.
+#[derive(Clone)]
+pub struct Auth {
+    inner: Option<Arc<AuthInner>>,
+}
+
+struct AuthInner {
+    db: Database,
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Every API key, by the hash of the key.
+    api_keys: Mutex<HashMap<String, ApiKey>>,
+    bootstrap_token: Mutex<Option<String>>,
+    /// Held while users or API keys are added or removed, so their names
+    /// stay unique.
+    users: tokio::sync::Mutex<()>,
+}
+
+struct Session {
+    principal: Principal,
+    expires_at: Instant,
+}
+
+impl Auth {
+    /// Lets every request through, as anonymous.
+    pub fn disabled() -> Self {
+        Self { inner: None }
+    }
+
+    /// Requires every request to authenticate against the users in `db`.
+    pub async fn enable(db: Database) -> Result<Self, DatabaseError> {
+        let inner = Arc::new(AuthInner {
+            db,
+            sessions: Mutex::new(HashMap::new()),
+            api_keys: Mutex::new(HashMap::new()),
+            bootstrap_token: Mutex::new(None),
+            users: tokio::sync::Mutex::new(()),
+        });
+
+        let keys = match inner
+            .db
+            .find(API_KEYS_COLLECTION.to_string(), Document::new())
+            .await
+        {
+            Ok(keys) => keys,
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        };
+        for key in keys {
+            let hash = key.get_str("key_hash").unwrap_or_default().to_string();
+            inner.api_keys().insert(hash, ApiKey::from_document(&key)?);
+        }
+
+        if inner.users(Document::new()).await?.is_empty() {
+            let token = random_token();
+            warn!(
+                "No users yet; create the first admin with bootstrap token {}",
+                token
+            );
+            *inner
+                .bootstrap_token
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()) = Some(token);
+        }
+
+        Ok(Self { inner: Some(inner) })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// The token `bootstrap` accepts, until the first admin is created.
+    pub fn bootstrap_token(&self) -> Option<String> {
+        let inner = self.inner.as_ref()?;
+        inner
+            .bootstrap_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Creates the first admin, using the token logged at startup.
+    pub async fn bootstrap(
+        &self,
+        token: &str,
+        username: String,
+        password: String,
+    ) -> Result<(), DatabaseError> {
+        let inner = self.enabled()?;
+        let _users = inner.users.lock().await;
+
+        if inner
+            .bootstrap_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_deref()
+            != Some(token)
+        {
+            return Err(DatabaseError::Unauthenticated);
+        }
+
+        inner
+            .insert_user(
+                username.clone(),
+                password,
+                vec![Grant::database(Role::DbAdmin)],
+            )
+            .await?;
+        *inner
+            .bootstrap_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = None;
+        info!("Successfully bootstrapped admin '{}'", username);
+        Ok(())
+    }
+
+    /// Checks a password, answering who it belongs to.
+    pub async fn verify_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal, DatabaseError> {
+        let Some(inner) = &self.inner else {
+            return Ok(Principal::anonymous());
+        };
+
+        let user = inner
+            .users(bson::doc! { "username": username })
+            .await?
+            .into_iter()
+            .next();
+        // Hash even for unknown users, so timing doesn't tell them apart.
+        let hash = match &user {
+            Some(user) => user
+                .get_str("password_hash")
+                .unwrap_or_default()
+                .to_string(),
+            None => hash_password(String::new()).await?,
+        };
+
+        let valid = verify_password(password.to_string(), hash).await?;
+
+        match user {
+            Some(user) if valid => Principal::from_user(&user),
+            _ => Err(DatabaseError::Unauthenticated),
+        }
+    }
+
+    /// Checks a password and starts a session, answering its bearer token.
+    pub async fn login(&self, username: &str, password: &str) -> Result<String, DatabaseError> {
+        let inner = self.enabled()?;
+        let principal = self.verify_password(username, password).await?;
+
+        let token = random_token();
+        let now = Instant::now();
+        let mut sessions = inner.sessions();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            token.clone(),
+            Session {
+                principal,
+                expires_at: now + TOKEN_TTL,
+            },
+        );
+        Ok(token)
+    }
+
+    pub fn logout(&self, token: &str) {
+        if let Some(inner) = &self.inner {
+            inner.sessions().remove(token);
+        }
+    }
+
+    /// Resolves a bearer token, a session's or an API key. Without
+    /// authentication everyone is let in, token or not.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Principal, DatabaseError> {
+        let Some(inner) = &self.inner else {
+            return Ok(Principal::anonymous());
+        };
+
+        let token = token.ok_or(DatabaseError::Unauthenticated)?;
+        if token.starts_with(API_KEY_PREFIX) {
+            return match inner.api_keys().get(&hash_api_key(token)) {
+                Some(key) => Ok(key.principal()),
+                None => Err(DatabaseError::Unauthenticated),
+            };
+        }
+        match inner.sessions().get(token) {
+            Some(session) if session.expires_at > Instant::now() => Ok(session.principal.clone()),
+            _ => Err(DatabaseError::Unauthenticated),
+        }
+    }
+
+    /// Checks `principal` may read or write `collection`. Names that
+    /// aren't `db::is_valid_name` are refused outright: with a path
+    /// separator in it, `x/../_users` would name an internal collection.
+    pub fn authorize(
+        &self,
+        principal: &Principal,
+        collection: &str,
+        access: Access,
+    ) -> Result<(), DatabaseError> {
+        if !is_valid_name(collection) {
+            return Err(DatabaseError::InvalidName(collection.to_string()));
+        }
+        if collection.starts_with('_') {
+            return Err(DatabaseError::PermissionDenied(format!(
+                "collection '{}' is internal",
+                collection
+            )));
+        }
+
+        match principal
+            .grants
+            .iter()
+            .any(|grant| grant.allows(collection, access))
+        {
+            true => Ok(()),
+            false => Err(DatabaseError::PermissionDenied(format!(
+                "'{}' may not {} collection '{}'",
+                principal.username,
+                match access {
+                    Access::Read => "read",
+                    Access::Insert => "insert into",
+                    Access::Write => "write",
+                },
+                collection
+            ))),
+        }
+    }
+
+    pub async fn create_user(
+        &self,
+        by: &Principal,
+        username: String,
+        password: String,
+        grants: Vec<Grant>,
+    ) -> Result<(), DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+        let _users = inner.users.lock().await;
+
+        inner
+            .insert_user(username.clone(), password, grants)
+            .await?;
+        info!("Successfully created user '{}'", username);
+        Ok(())
+    }
+
+    /// Every user, with their grants.
+    pub async fn list_users(&self, by: &Principal) -> Result<Vec<Principal>, DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+
+        inner
+            .users(Document::new())
+            .await?
+            .iter()
+            .map(Principal::from_user)
+            .collect()
+    }
+
+    /// Adds a grant to a user, taking effect on their open sessions too.
+    /// Returns whether the user exists.
+    pub async fn grant(
+        &self,
+        by: &Principal,
+        username: &str,
+        grant: Grant,
+    ) -> Result<bool, DatabaseError> {
+        self.update_grants(by, username, |grants| {
+            if !grants.contains(&grant) {
+                grants.push(grant);
+            }
+        })
+        .await
+    }
+
+    /// Takes a grant away from a user, taking effect on their open sessions
+    /// too. Returns whether the user exists.
+    pub async fn revoke(
+        &self,
+        by: &Principal,
+        username: &str,
+        grant: &Grant,
+    ) -> Result<bool, DatabaseError> {
+        self.update_grants(by, username, |grants| {
+            grants.retain(|granted| granted != grant)
+        })
+        .await
+    }
+
+    async fn update_grants(
+        &self,
+        by: &Principal,
+        username: &str,
+        change: impl FnOnce(&mut Vec<Grant>),
+    ) -> Result<bool, DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+        let _users = inner.users.lock().await;
+
+        let Some((id, user)) = inner
+            .db
+            .find_with_ids(
+                USERS_COLLECTION.to_string(),
+                bson::doc! { "username": username },
+            )
+            .await?
+            .into_iter()
+            .next()
+        else {
+            return Ok(false);
+        };
+
+        let mut grants = Principal::from_user(&user)?.grants;
+        change(&mut grants);
+        let roles: Vec<Document> = grants.iter().map(Grant::to_document).collect();
+        inner
+            .db
+            .update_one(
+                USERS_COLLECTION.to_string(),
+                id,
+                bson::doc! { "$set": { "roles": roles } },
+            )
+            .await?;
+
+        for session in inner.sessions().values_mut() {
+            if session.principal.username == username {
+                session.principal.grants = grants.clone();
+            }
+        }
+        info!("Successfully updated the grants of '{}'", username);
+        Ok(true)
+    }
+
+    /// Removes a user and ends their sessions. Returns whether they existed.
+    pub async fn delete_user(&self, by: &Principal, username: &str) -> Result<bool, DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+        let _users = inner.users.lock().await;
+
+        let ids = inner
+            .db
+            .find_with_ids(
+                USERS_COLLECTION.to_string(),
+                bson::doc! { "username": username },
+            )
+            .await?;
+        for (id, _) in &ids {
+            inner
+                .db
+                .delete_one(USERS_COLLECTION.to_string(), id.clone())
+                .await?;
+        }
+
+        inner
+            .sessions()
+            .retain(|_, session| session.principal.username != username);
+        Ok(!ids.is_empty())
+    }
+
+    /// Creates an API key named `name` holding `grants`, answering the key.
+    pub async fn create_api_key(
+        &self,
+        by: &Principal,
+        name: String,
+        grants: Vec<Grant>,
+    ) -> Result<String, DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+        if name.is_empty() {
+            return Err(DatabaseError::InvalidUpdate(
+                "API key name must not be empty".to_string(),
+            ));
+        }
+        if grants.iter().any(|grant| grant.role == Role::DbAdmin) {
+            return Err(DatabaseError::InvalidUpdate(
+                "API keys can't be granted dbAdmin".to_string(),
+            ));
+        }
+        let _users = inner.users.lock().await;
+        if inner.api_keys().values().any(|key| key.name == name) {
+            return Err(DatabaseError::InvalidUpdate(format!(
+                "API key '{}' already exists",
+                name
+            )));
+        }
+
+        let key = format!("{}{}", API_KEY_PREFIX, random_token());
+        let hash = hash_api_key(&key);
+        let roles: Vec<Document> = grants.iter().map(Grant::to_document).collect();
+        inner
+            .db
+            .insert_one(
+                API_KEYS_COLLECTION.to_string(),
+                bson::doc! { "name": &name, "key_hash": &hash, "roles": roles },
+            )
+            .await?;
+        inner.api_keys().insert(
+            hash,
+            ApiKey {
+                name: name.clone(),
+                grants,
+            },
+        );
+        info!("Successfully created API key '{}'", name);
+        Ok(key)
+    }
+
+    /// Every API key, by name.
+    pub fn list_api_keys(&self, by: &Principal) -> Result<Vec<ApiKey>, DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+
+        let mut keys: Vec<ApiKey> = inner.api_keys().values().cloned().collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(keys)
+    }
+
+    /// Revokes an API key. Returns whether it existed.
+    pub async fn delete_api_key(&self, by: &Principal, name: &str) -> Result<bool, DatabaseError> {
+        let inner = self.enabled()?;
+        require_admin(by)?;
+        let _users = inner.users.lock().await;
+
+        let ids = inner
+            .db
+            .find_with_ids(API_KEYS_COLLECTION.to_string(), bson::doc! { "name": name })
+            .await?;
+        for (id, _) in &ids {
+            inner
+                .db
+                .delete_one(API_KEYS_COLLECTION.to_string(), id.clone())
+                .await?;
+        }
+
+        inner.api_keys().retain(|_, key| key.name != name);
+        Ok(!ids.is_empty())
+    }
+
+    fn enabled(&self) -> Result<&AuthInner, DatabaseError> {
+        self.inner.as_deref().ok_or_else(|| {
+            DatabaseError::PermissionDenied("authentication is disabled".to_string())
+        })
+    }
+}
+
+impl AuthInner {
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn api_keys(&self) -> std::sync::MutexGuard<'_, HashMap<String, ApiKey>> {
+        self.api_keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn users(&self, query: Document) -> Result<Vec<Document>, DatabaseError> {
+        match self.db.find(USERS_COLLECTION.to_string(), query).await {
+            Ok(users) => Ok(users),
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Callers hold `users`.
+    async fn insert_user(
+        &self,
+        username: String,
+        password: String,
+        grants: Vec<Grant>,
+    ) -> Result<(), DatabaseError> {
+        if username.is_empty() {
+            return Err(DatabaseError::InvalidUpdate(
+                "username must not be empty".to_string(),
+            ));
+        }
+        if !self
+            .users(bson::doc! { "username": &username })
+            .await?
+            .is_empty()
+        {
+            return Err(DatabaseError::InvalidUpdate(format!(
+                "user '{}' already exists",
+                username
+            )));
+        }
+
+        let password_hash = hash_password(password).await?;
+        let roles: Vec<Document> = grants.iter().map(Grant::to_document).collect();
+        self.db
+            .insert_one(
+                USERS_COLLECTION.to_string(),
+                bson::doc! {
+                    "username": username,
+                    "password_hash": password_hash,
+                    "roles": roles,
+                },
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+fn require_admin(principal: &Principal) -> Result<(), DatabaseError> {
+    match principal.is_admin() {
+        true => Ok(()),
+        false => Err(DatabaseError::PermissionDenied(
+            "only admins may manage users".to_string(),
+        )),
+    }
+}
+
+/// 256 random bits, hex-encoded.
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().fold(String::new(), |mut token, byte| {
+        let _ = write!(token, "{:02x}", byte);
+        token
+    })
+}
+
+/// Keys are random enough that a fast hash keeps them safe at rest, and
+/// checking one needn't cost a password hash on every request.
+fn hash_api_key(key: &str) -> String {
+    Blake2s256::digest(key.as_bytes())
+        .iter()
+        .fold(String::new(), |mut hash, byte| {
+            let _ = write!(hash, "{:02x}", byte);
+            hash
+        })
+}
+
+/// Argon2 is slow on purpose, so it runs off the async workers.
+async fn hash_password(password: String) -> Result<String, DatabaseError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| DatabaseError::InvalidUpdate(format!("failed to hash password: {}", e)))
+    })
+    .await
+    .map_err(|e| DatabaseError::IoError(e.into()))?
+}
+
+async fn verify_password(password: String, hash: String) -> Result<bool, DatabaseError> {
+    tokio::task::spawn_blocking(move || {
+        Ok(PasswordHash::new(&hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false))
+    })
+    .await
+    .map_err(|e| DatabaseError::IoError(e.into()))?
+}
+
+/// The token of an `Authorization: Bearer <token>` header value.
+pub fn bearer(header: &str) -> Option<&str> {
+    header.strip_prefix("Bearer ").map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bootstrap_login_and_users() {
+        let folder_path = "data_tests/test_auth".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let auth = Auth::enable(db.clone()).await.unwrap();
+        let bootstrap_token = auth.bootstrap_token().unwrap();
+        assert!(matches!(
+            auth.bootstrap("guess", "root".to_string(), "secret".to_string())
+                .await,
+            Err(DatabaseError::Unauthenticated)
+        ));
+        auth.bootstrap(&bootstrap_token, "root".to_string(), "secret".to_string())
+            .await
+            .unwrap();
+        assert_eq!(auth.bootstrap_token(), None);
+
+        assert!(auth.login("root", "wrong").await.is_err());
+        let token = auth.login("root", "secret").await.unwrap();
+        let root = auth.authenticate(Some(&token)).unwrap();
+        assert!(root.is_admin());
+        assert!(matches!(
+            auth.authenticate(None),
+            Err(DatabaseError::Unauthenticated)
+        ));
+
+        auth.create_user(&root, "ana".to_string(), "hunter2".to_string(), Vec::new())
+            .await
+            .unwrap();
+        let ana = auth.verify_password("ana", "hunter2").await.unwrap();
+        assert!(!ana.is_admin());
+        assert!(auth
+            .create_user(&ana, "eve".to_string(), "x".to_string(), Vec::new())
+            .await
+            .is_err());
+        assert!(auth
+            .authorize(&root, USERS_COLLECTION, Access::Read)
+            .is_err());
+
+        // Users persist; sessions don't.
+        let reopened = Auth::enable(db.clone()).await.unwrap();
+        assert_eq!(reopened.bootstrap_token(), None);
+        assert!(reopened.authenticate(Some(&token)).is_err());
+
+        assert!(auth.delete_user(&root, "ana").await.unwrap());
+        assert!(auth.verify_password("ana", "hunter2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_grants_apply_to_sessions() {
+        let folder_path = "data_tests/test_auth_grants".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let auth = Auth::enable(db).await.unwrap();
+        let bootstrap_token = auth.bootstrap_token().unwrap();
+        auth.bootstrap(&bootstrap_token, "root".to_string(), "secret".to_string())
+            .await
+            .unwrap();
+        let root = auth.verify_password("root", "secret").await.unwrap();
+
+        let events = Grant::collection(Role::Read, "events".to_string());
+        auth.create_user(
+            &root,
+            "analyst".to_string(),
+            "pw".to_string(),
+            vec![events.clone()],
+        )
+        .await
+        .unwrap();
+        let token = auth.login("analyst", "pw").await.unwrap();
+        let analyst = auth.authenticate(Some(&token)).unwrap();
+        assert!(auth.authorize(&analyst, "events", Access::Read).is_ok());
+        assert!(matches!(
+            auth.authorize(&analyst, "events", Access::Write),
+            Err(DatabaseError::PermissionDenied(_))
+        ));
+        assert!(auth.authorize(&analyst, "users", Access::Read).is_err());
+
+        let writer = Grant::database(Role::ReadWrite);
+        assert!(auth.grant(&root, "analyst", writer.clone()).await.unwrap());
+        let analyst = auth.authenticate(Some(&token)).unwrap();
+        assert!(auth.authorize(&analyst, "users", Access::Write).is_ok());
+        assert!(!analyst.is_admin());
+        // Would resolve to `_users` once used as a path.
+        assert!(matches!(
+            auth.authorize(&analyst, "x/../_users", Access::Write),
+            Err(DatabaseError::InvalidName(_))
+        ));
+        assert!(matches!(
+            auth.authorize(&analyst, "_users", Access::Write),
+            Err(DatabaseError::PermissionDenied(_))
+        ));
+        assert!(auth
+            .grant(&analyst, "analyst", writer.clone())
+            .await
+            .is_err());
+
+        assert!(auth.revoke(&root, "analyst", &writer).await.unwrap());
+        let analyst = auth.authenticate(Some(&token)).unwrap();
+        assert_eq!(analyst.grants, vec![events]);
+        assert!(!auth.revoke(&root, "nobody", &writer).await.unwrap());
+
+        let users = auth.list_users(&root).await.unwrap();
+        assert_eq!(users.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let folder_path = "data_tests/test_auth_api_keys".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let auth = Auth::enable(db.clone()).await.unwrap();
+        let bootstrap_token = auth.bootstrap_token().unwrap();
+        auth.bootstrap(&bootstrap_token, "root".to_string(), "secret".to_string())
+            .await
+            .unwrap();
+        let root = auth.verify_password("root", "secret").await.unwrap();
+
+        let telemetry = Grant::collection(Role::Insert, "telemetry".to_string());
+        let key = auth
+            .create_api_key(&root, "sensors".to_string(), vec![telemetry.clone()])
+            .await
+            .unwrap();
+        assert!(auth
+            .create_api_key(&root, "sensors".to_string(), Vec::new())
+            .await
+            .is_err());
+        assert!(auth
+            .create_api_key(
+                &root,
+                "ops".to_string(),
+                vec![Grant::database(Role::DbAdmin)]
+            )
+            .await
+            .is_err());
+
+        let sensors = auth.authenticate(Some(&key)).unwrap();
+        assert_eq!(sensors.username, "key:sensors");
+        assert!(!sensors.is_admin());
+        assert!(auth
+            .authorize(&sensors, "telemetry", Access::Insert)
+            .is_ok());
+        assert!(auth.authorize(&sensors, "telemetry", Access::Read).is_err());
+        assert!(auth.authorize(&sensors, "users", Access::Insert).is_err());
+        assert!(auth.authenticate(Some("owk_guess")).is_err());
+        assert!(auth.list_api_keys(&sensors).is_err());
+
+        // Keys outlive restarts, unlike sessions.
+        let reopened = Auth::enable(db).await.unwrap();
+        assert_eq!(reopened.authenticate(Some(&key)).unwrap(), sensors);
+        assert_eq!(
+            reopened.list_api_keys(&root).unwrap(),
+            vec![ApiKey {
+                name: "sensors".to_string(),
+                grants: vec![telemetry],
+            }]
+        );
+        assert!(reopened.delete_api_key(&root, "sensors").await.unwrap());
+        assert!(reopened.authenticate(Some(&key)).is_err());
+    }
+}
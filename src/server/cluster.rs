@@ -0,0 +1,280 @@
+//! A server's place among others: standing alone, following a leader (see
+//! `replication`), or one of several primaries (see `multi_primary`).
+//! `Cluster` runs whichever it is, and lets admins change it while the
+//! server runs: add and remove primaries, read the topology and how each
+//! link is faring, and fail a follower over to take writes itself.
+//!
+//! Changes last until the server restarts, which starts again from its
+//! `[replication]` config. Followers pull from their leader, so a leader
+//! doesn't list them.
+
+use std::sync::Arc;
+
+use log::info;
+use tokio::sync::Mutex;
+
+use crate::db::{Database, DatabaseError};
+use crate::server::multi_primary::MultiPrimary;
+use crate::server::replication::{Follower, LinkHealth, ReplicationOptions};
+
+#[derive(Debug, Clone, Default)]
+pub struct ClusterOptions {
+    /// This server's name among primaries; its database's
+    /// `OplogOptions::node` must match.
+    pub node: Option<String>,
+    /// The leader to follow, such as `http://10.0.0.1:50051`.
+    pub leader: Option<String>,
+    /// The other primaries, when it's one of several.
+    pub peers: Vec<String>,
+    pub replication: ReplicationOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Standalone,
+    Follower,
+    Leader,
+    Primary,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Standalone => "standalone",
+            Role::Follower => "follower",
+            Role::Leader => "leader",
+            Role::Primary => "primary",
+        }
+    }
+}
+
+/// Another server this one follows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub uri: String,
+    pub role: Role,
+    pub health: LinkHealth,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    pub node: Option<String>,
+    pub role: Role,
+    pub members: Vec<Member>,
+}
+
+/// Handle to the replication a server runs. Cloning is cheap.
+#[derive(Clone)]
+pub struct Cluster {
+    inner: Arc<ClusterInner>,
+}
+
+struct ClusterInner {
+    db: Database,
+    node: Option<String>,
+    options: ReplicationOptions,
+    state: Mutex<State>,
+}
+
+enum State {
+    Standalone,
+    Following(Follower),
+    Primary(Box<MultiPrimary>),
+}
+
+impl Cluster {
+    /// Starts whichever replication `options` ask of `db`.
+    pub fn start(db: Database, options: ClusterOptions) -> Self {
+        let state = match (&options.leader, options.peers.is_empty()) {
+            (Some(leader), _) => State::Following(Follower::start(
+                db.clone(),
+                leader,
+                options.replication.clone(),
+            )),
+            (None, false) => State::Primary(Box::new(MultiPrimary::start(
+                db.clone(),
+                &options.peers,
+                options.replication.clone(),
+            ))),
+            (None, true) => State::Standalone,
+        };
+
+        Self {
+            inner: Arc::new(ClusterInner {
+                db,
+                node: options.node,
+                options: options.replication,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// Starts following the primary at `uri`, making this server one of
+    /// several if it stood alone. Answers false if it already followed it.
+    pub async fn add_node(&self, uri: &str) -> Result<bool, DatabaseError> {
+        if !(uri.starts_with("http://") || uri.starts_with("https://")) {
+            return Err(DatabaseError::InvalidConfig(format!("node '{}'", uri)));
+        }
+        if self.inner.node.is_none() {
+            return Err(DatabaseError::InvalidConfig(
+                "adding a primary needs this server's node name".to_string(),
+            ));
+        }
+
+        let mut state = self.inner.state.lock().await;
+        match &mut *state {
+            State::Following(_) => Err(DatabaseError::InvalidConfig(
+                "a follower can't have peers; fail it over first".to_string(),
+            )),
+            State::Primary(primary) => Ok(primary.add_peer(uri)),
+            State::Standalone => {
+                let peers = [uri.to_string()];
+                let primary =
+                    MultiPrimary::start(self.inner.db.clone(), &peers, self.inner.options.clone());
+                *state = State::Primary(Box::new(primary));
+                info!("Joined primary {}", uri);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Stops following the primary at `uri`, standing alone once none is
+    /// left. Answers false if it wasn't followed.
+    pub async fn remove_node(&self, uri: &str) -> Result<bool, DatabaseError> {
+        let mut state = self.inner.state.lock().await;
+        let State::Primary(primary) = &mut *state else {
+            return Ok(false);
+        };
+        if !primary.remove_peer(uri).await {
+            return Ok(false);
+        }
+        info!("Left primary {}", uri);
+        if primary.peers().is_empty() {
+            *state = State::Standalone;
+        }
+        Ok(true)
+    }
+
+    /// Stops following the leader and takes writes instead.
+    pub async fn failover(&self) -> Result<(), DatabaseError> {
+        let mut state = self.inner.state.lock().await;
+        match std::mem::replace(&mut *state, State::Standalone) {
+            State::Following(follower) => {
+                follower.promote().await;
+                Ok(())
+            }
+            other => {
+                *state = other;
+                Err(DatabaseError::InvalidConfig(
+                    "only a follower can fail over".to_string(),
+                ))
+            }
+        }
+    }
+
+    pub async fn topology(&self) -> Topology {
+        let state = self.inner.state.lock().await;
+        let (role, members) = match &*state {
+            State::Standalone => (Role::Standalone, Vec::new()),
+            State::Following(follower) => (
+                Role::Follower,
+                vec![Member {
+                    uri: follower.leader().to_string(),
+                    role: Role::Leader,
+                    health: follower.health(),
+                }],
+            ),
+            State::Primary(primary) => (
+                Role::Primary,
+                primary
+                    .peers()
+                    .into_iter()
+                    .map(|(uri, health)| Member {
+                        uri,
+                        role: Role::Primary,
+                        health,
+                    })
+                    .collect(),
+            ),
+        };
+
+        Topology {
+            node: self.inner.node.clone(),
+            role,
+            members,
+        }
+    }
+
+    /// Stops replicating, leaving a follower read-only.
+    pub async fn stop(&self) {
+        let mut state = self.inner.state.lock().await;
+        match std::mem::replace(&mut *state, State::Standalone) {
+            State::Standalone => {}
+            State::Following(follower) => follower.stop().await,
+            State::Primary(primary) => primary.stop().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DatabaseOptions, OplogOptions};
+
+    #[tokio::test]
+    async fn test_cluster() {
+        let folder_path = "data_tests/test_cluster".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions {
+                node: Some("east".to_string()),
+                ..OplogOptions::default()
+            }),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        // Nothing listens there, so the links stay down.
+        let cluster = Cluster::start(
+            db.clone(),
+            ClusterOptions {
+                node: Some("east".to_string()),
+                leader: Some("http://127.0.0.1:1".to_string()),
+                ..ClusterOptions::default()
+            },
+        );
+        let topology = cluster.topology().await;
+        assert_eq!(topology.role, Role::Follower);
+        assert_eq!(topology.members[0].role, Role::Leader);
+        assert!(!topology.members[0].health.connected);
+        assert!(matches!(
+            cluster.add_node("http://127.0.0.1:2").await,
+            Err(DatabaseError::InvalidConfig(_))
+        ));
+
+        cluster.failover().await.unwrap();
+        assert_eq!(cluster.topology().await.role, Role::Standalone);
+        db.insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        assert!(cluster.failover().await.is_err());
+
+        assert!(cluster.add_node("10.0.0.2:50051").await.is_err());
+        assert!(cluster.add_node("http://127.0.0.1:2").await.unwrap());
+        assert!(!cluster.add_node("http://127.0.0.1:2").await.unwrap());
+        assert!(cluster.add_node("http://127.0.0.1:3").await.unwrap());
+        let topology = cluster.topology().await;
+        assert_eq!(topology.node.as_deref(), Some("east"));
+        assert_eq!(topology.role, Role::Primary);
+        let uris: Vec<_> = topology.members.iter().map(|m| m.uri.as_str()).collect();
+        assert_eq!(uris, ["http://127.0.0.1:2", "http://127.0.0.1:3"]);
+
+        assert!(cluster.remove_node("http://127.0.0.1:2").await.unwrap());
+        assert!(!cluster.remove_node("http://127.0.0.1:2").await.unwrap());
+        assert!(cluster.remove_node("http://127.0.0.1:3").await.unwrap());
+        assert_eq!(cluster.topology().await.role, Role::Standalone);
+        cluster.stop().await;
+    }
+}
@@ -0,0 +1,287 @@
+//! Payload compression the front ends can negotiate with their clients.
+//! Each listener offers its own list of compressors, none by default:
+//!
+//! - The MongoDB front end answers a driver's `compression` list in `hello`
+//!   with the ones it offers too, and reads and answers `OP_COMPRESSED`
+//!   messages with them.
+//! - The REST interface reads request bodies sent with a `Content-Encoding`
+//!   it offers, and compresses responses with the first compressor it
+//!   offers that the client's `Accept-Encoding` names.
+//!
+//! Snappy, in its raw block format, is the only compressor built in.
+
+use crate::db::DatabaseError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compressor {
+    Snappy,
+}
+
+/// Payloads smaller than this aren't worth compressing.
+pub const MIN_COMPRESSED_SIZE: usize = 256;
+
+impl Compressor {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "snappy" => Some(Compressor::Snappy),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compressor::Snappy => "snappy",
+        }
+    }
+
+    /// The compressor's ID in MongoDB's `OP_COMPRESSED`.
+    pub fn mongo_id(self) -> u8 {
+        match self {
+            Compressor::Snappy => 1,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compressor::Snappy => snappy::compress(data),
+        }
+    }
+
+    /// Fails on malformed data, and on data that would decompress to more
+    /// than `max_size` bytes.
+    pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            Compressor::Snappy => snappy::decompress(data, max_size),
+        }
+    }
+}
+
+/// The first of `offered` that's named in `names`, a comma-separated list
+/// such as an `Accept-Encoding` header, where names may carry parameters.
+pub fn negotiate(offered: &[Compressor], names: &str) -> Option<Compressor> {
+    let accepted: Vec<&str> = names
+        .split(',')
+        .filter_map(|name| {
+            let mut parts = name.split(';').map(str::trim);
+            let name = parts.next()?;
+            // `q=0` means not acceptable.
+            let refused =
+                parts.any(|param| matches!(param, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            (!refused).then_some(name)
+        })
+        .collect();
+
+    offered
+        .iter()
+        .copied()
+        .find(|compressor| accepted.contains(&compressor.name()))
+}
+
+/// The raw (unframed) Snappy format: the uncompressed length as a varint,
+/// then literals and back-references.
+mod snappy {
+    use super::DatabaseError;
+
+    const HASH_BITS: u32 = 14;
+
+    pub fn compress(input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len() / 2 + 16);
+        let mut length = input.len() as u64;
+        while length >= 0x80 {
+            output.push(length as u8 | 0x80);
+            length >>= 7;
+        }
+        output.push(length as u8);
+
+        // Positions, plus one, of the last four bytes hashing to each slot.
+        let mut table = vec![0usize; 1 << HASH_BITS];
+        let mut literal_start = 0;
+        let mut at = 0;
+        while at + 4 <= input.len() {
+            let key = u32::from_le_bytes([input[at], input[at + 1], input[at + 2], input[at + 3]]);
+            let slot = (key.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize;
+            let candidate = table[slot];
+            table[slot] = at + 1;
+
+            if candidate > 0 {
+                let from = candidate - 1;
+                if at - from <= u32::MAX as usize && input[from..from + 4] == input[at..at + 4] {
+                    let mut length = 4;
+                    while at + length < input.len() && input[from + length] == input[at + length] {
+                        length += 1;
+                    }
+                    literal(&mut output, &input[literal_start..at]);
+                    copy(&mut output, at - from, length);
+                    at += length;
+                    literal_start = at;
+                    continue;
+                }
+            }
+            at += 1;
+        }
+        literal(&mut output, &input[literal_start..]);
+        output
+    }
+
+    fn literal(output: &mut Vec<u8>, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let n = bytes.len() - 1;
+        if n < 60 {
+            output.push((n as u8) << 2);
+        } else {
+            let width = (64 - (n as u64).leading_zeros()).div_ceil(8) as usize;
+            output.push(((59 + width) as u8) << 2);
+            output.extend_from_slice(&n.to_le_bytes()[..width]);
+        }
+        output.extend_from_slice(bytes);
+    }
+
+    fn copy(output: &mut Vec<u8>, offset: usize, mut length: usize) {
+        while length > 0 {
+            // Keep at least 4 for the last piece, so it can use the short form.
+            let n = match length {
+                65..=67 => 60,
+                _ => length.min(64),
+            };
+            if (4..=11).contains(&n) && offset < 2048 {
+                output.push(1 | ((n as u8 - 4) << 2) | (((offset >> 8) as u8) << 5));
+                output.push(offset as u8);
+            } else if offset < 65536 {
+                output.push(2 | ((n as u8 - 1) << 2));
+                output.extend_from_slice(&(offset as u16).to_le_bytes());
+            } else {
+                output.push(3 | ((n as u8 - 1) << 2));
+                output.extend_from_slice(&(offset as u32).to_le_bytes());
+            }
+            length -= n;
+        }
+    }
+
+    pub fn decompress(input: &[u8], max_size: usize) -> Result<Vec<u8>, DatabaseError> {
+        let corrupt = |message: &str| DatabaseError::InvalidCompression(message.to_string());
+
+        let mut length: u64 = 0;
+        let mut at = 0;
+        loop {
+            let byte = *input.get(at).ok_or_else(|| corrupt("truncated length"))?;
+            if at == 5 {
+                return Err(corrupt("length too long"));
+            }
+            length |= ((byte & 0x7f) as u64) << (7 * at);
+            at += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if length > max_size as u64 {
+            return Err(DatabaseError::InvalidCompression(format!(
+                "decompresses to {} bytes, over the limit of {}",
+                length, max_size
+            )));
+        }
+
+        let length = length as usize;
+        let mut output = Vec::with_capacity(length);
+        let take = |at: &mut usize, n: usize| -> Result<u64, DatabaseError> {
+            let bytes = input
+                .get(*at..*at + n)
+                .ok_or_else(|| corrupt("truncated"))?;
+            *at += n;
+            Ok(bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| (value << 8) | *byte as u64))
+        };
+
+        while at < input.len() {
+            let tag = input[at];
+            at += 1;
+            let (offset, n) = match tag & 3 {
+                0 => {
+                    let n = match (tag >> 2) as usize {
+                        n @ 0..=59 => n + 1,
+                        width => take(&mut at, width - 59)? as usize + 1,
+                    };
+                    let bytes = input.get(at..at + n).ok_or_else(|| corrupt("truncated"))?;
+                    if output.len() + n > length {
+                        return Err(corrupt("longer than its length"));
+                    }
+                    output.extend_from_slice(bytes);
+                    at += n;
+                    continue;
+                }
+                1 => {
+                    let offset = ((tag as usize >> 5) << 8) | take(&mut at, 1)? as usize;
+                    (offset, ((tag >> 2) & 7) as usize + 4)
+                }
+                2 => (take(&mut at, 2)? as usize, (tag >> 2) as usize + 1),
+                _ => (take(&mut at, 4)? as usize, (tag >> 2) as usize + 1),
+            };
+
+            if offset == 0 || offset > output.len() {
+                return Err(corrupt("copy from before the start"));
+            }
+            if output.len() + n > length {
+                return Err(corrupt("longer than its length"));
+            }
+            // Copies may overlap what they produce.
+            let from = output.len() - offset;
+            for i in 0..n {
+                output.push(output[from + i]);
+            }
+        }
+
+        match output.len() == length {
+            true => Ok(output),
+            false => Err(corrupt("shorter than its length")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snappy_roundtrip() {
+        let mut inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabc".to_vec(),
+            vec![7; 100_000],
+        ];
+        let document = br#"{"name": "John", "tags": ["a", "b"], "age": 30}"#;
+        inputs.push(document.repeat(500));
+        // Bytes that don't repeat, to exercise long literals.
+        inputs.push(
+            (0..70_000u32)
+                .map(|n| (n.wrapping_mul(2_654_435_761) >> 13) as u8)
+                .collect(),
+        );
+
+        for input in inputs {
+            let compressed = Compressor::Snappy.compress(&input);
+            let output = Compressor::Snappy
+                .decompress(&compressed, usize::MAX)
+                .unwrap();
+            assert_eq!(output, input);
+        }
+        assert!(Compressor::Snappy.compress(&vec![0; 10_000]).len() < 1_000);
+
+        let compressed = Compressor::Snappy.compress(&vec![0; 10_000]);
+        assert!(matches!(
+            Compressor::Snappy.decompress(&compressed, 9_999),
+            Err(DatabaseError::InvalidCompression(_))
+        ));
+        assert!(Compressor::Snappy.decompress(&[5, 0x0a, 1], 100).is_err());
+
+        assert_eq!(
+            negotiate(&[Compressor::Snappy], "gzip, snappy;q=0.5"),
+            Some(Compressor::Snappy)
+        );
+        assert_eq!(negotiate(&[Compressor::Snappy], "snappy;q=0"), None);
+        assert_eq!(negotiate(&[], "snappy"), None);
+    }
+}
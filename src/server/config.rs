@@ -0,0 +1,750 @@
+//! The server's configuration file, in TOML. Every setting has a default,
+//! so the file only needs the ones that differ:
+//!
+//! ```toml
+//! data = "/var/lib/owldb"
+//! quota = 10737418240
+//!
+//! [listen]
+//! http = "0.0.0.0:8080"
+//! grpc = "0.0.0.0:50051"
+//! mongo = "0.0.0.0:27017"
+//!
+//! [listen.compression]
+//! mongo = ["snappy"]
+//!
+//! [http]
+//! json = "plain"
+//! cors_origins = ["http://localhost:5173"]
+//!
+//! [storage]
+//! durability = "buffered"
+//! flush_interval_ms = 100
+//! cache_capacity = 4096
+//! oplog_entries = 100000
+//! handoff_entries = 1000000
+//!
+//! [auth]
+//! enabled = true
+//!
+//! [replication]
+//! leader = "http://10.0.0.1:50051"
+//! delay_secs = 3600
+//! # or, for multi-primary:
+//! # node = "east"
+//! # peers = ["http://10.0.0.2:50051", "http://10.0.0.3:50051"]
+//! username = "replicator"
+//! password = "secret"
+//!
+//! [nats]
+//! url = "nats://10.0.0.7:4222"
+//! subject_prefix = "owldb"
+//! format = "bson"
+//!
+//! [tls]
+//! cert = "/etc/owldb/cert.pem"
+//! key = "/etc/owldb/key.pem"
+//!
+//! [[databases]]
+//! name = "acme"
+//! data = "/var/lib/owldb-acme"
+//!
+//! [[webhooks]]
+//! url = "http://10.0.0.5:9000/orders"
+//! database = "acme"
+//! collections = ["orders"]
+//! operations = ["insert", "update"]
+//! filter = { status = "paid" }
+//! ```
+//!
+//! Environment variables take precedence over the file, and the server's
+//! command-line flags over both: `OWLDB_DATA`, `OWLDB_LISTEN` (the HTTP
+//! address), `OWLDB_GRPC`, `OWLDB_MONGO`, `OWLDB_RESP`, `OWLDB_DURABILITY`,
+//! `OWLDB_CACHE_CAPACITY`, `OWLDB_AUTH`, `OWLDB_LEADER`, `OWLDB_TLS_CERT`,
+//! `OWLDB_TLS_KEY` and `OWLDB_TLS_CLIENT_CA`.
+
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::db::{
+    DatabaseError, DatabaseOptions, HandoffOptions, OperationType, OplogOptions, WriteBufferOptions,
+};
+use crate::server::compression::Compressor;
+use crate::server::http::{HttpOptions, JsonMode};
+use crate::server::limits::{LimitOptions, RateLimitOptions};
+use crate::server::tenants::{is_valid_name, TenantOptions, DEFAULT_TENANT};
+use crate::server::webhooks::WebhookOptions;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Folder of the default database.
+    pub data: String,
+    /// Bytes the default database may grow to; unlimited when unset.
+    pub quota: Option<u64>,
+    pub listen: ListenConfig,
+    pub http: HttpConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub replication: ReplicationConfig,
+    pub nats: NatsConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    /// Databases served next to the default one.
+    pub databases: Vec<DatabaseConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            data: "data".to_string(),
+            quota: None,
+            listen: ListenConfig::default(),
+            http: HttpConfig::default(),
+            storage: StorageConfig::default(),
+            auth: AuthConfig::default(),
+            audit: AuditConfig::default(),
+            replication: ReplicationConfig::default(),
+            nats: NatsConfig::default(),
+            tls: TlsConfig::default(),
+            limits: LimitsConfig::default(),
+            databases: Vec::new(),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+/// Addresses of the front ends. Only HTTP is always served.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub http: String,
+    pub grpc: Option<String>,
+    pub mongo: Option<String>,
+    pub resp: Option<String>,
+    pub compression: CompressionConfig,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            http: "127.0.0.1:8080".to_string(),
+            grpc: None,
+            mongo: None,
+            resp: None,
+            compression: CompressionConfig::default(),
+        }
+    }
+}
+
+/// The compressors each front end offers its clients, by name, in order of
+/// preference; none by default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub http: Vec<String>,
+    pub mongo: Vec<String>,
+}
+
+impl CompressionConfig {
+    pub fn http_compressors(&self) -> Vec<Compressor> {
+        compressors(&self.http)
+    }
+
+    pub fn mongo_compressors(&self) -> Vec<Compressor> {
+        compressors(&self.mongo)
+    }
+}
+
+/// Names `validate` has checked.
+fn compressors(names: &[String]) -> Vec<Compressor> {
+    names
+        .iter()
+        .filter_map(|name| Compressor::from_name(name))
+        .collect()
+}
+
+/// How the REST interface talks to browsers.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// `extended` or `plain`.
+    pub json: JsonMode,
+    /// Origins allowed to make cross-origin requests, or `*` for any.
+    pub cors_origins: Vec<String>,
+}
+
+/// When a write reaches its document file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Before the write returns.
+    #[default]
+    Sync,
+    /// In the background, every `flush_interval_ms`; until then inserts
+    /// are kept durable by the write-ahead log.
+    Buffered,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub durability: Durability,
+    /// How often buffered writes are flushed, with `Durability::Buffered`.
+    pub flush_interval_ms: u64,
+    /// Recently read documents kept in memory by each database.
+    pub cache_capacity: usize,
+    /// Keep about this many of each database's latest writes in its oplog,
+    /// for gRPC `Tail` consumers; no oplog when unset.
+    pub oplog_entries: Option<u64>,
+    /// Hold up to this many entries the oplog drops for each follower gone
+    /// away, to hand off once it's back; none held when unset.
+    pub handoff_entries: Option<u64>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            flush_interval_ms: WriteBufferOptions::default().flush_interval.as_millis() as u64,
+            cache_capacity: DatabaseOptions::default().cache_capacity,
+            oplog_entries: None,
+            handoff_entries: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require clients to log in as one of each database's users.
+    pub enabled: bool,
+}
+
+/// Where data operations are recorded; nowhere when neither is set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub file: Option<String>,
+    /// Record them in each database's `_audit` collection.
+    pub collection: bool,
+}
+
+/// Makes the default database a follower of another server's, which must
+/// keep an oplog; see `owldb::server::replication`. Or, with `peers`, one
+/// of several primaries; see `owldb::server::multi_primary`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// The leader's gRPC address, such as `http://10.0.0.1:50051`.
+    pub leader: Option<String>,
+    /// An admin of the leader's, when it authenticates.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The leader's database to follow; its default one when unset.
+    pub database: Option<String>,
+    /// This server's name among the primaries, stamped on its writes; a
+    /// follower's name to its leader, for handoff.
+    pub node: Option<String>,
+    /// The other primaries' gRPC addresses.
+    pub peers: Vec<String>,
+    /// Apply the leader's writes only once they're this old, for a delayed
+    /// follower.
+    pub delay_secs: u64,
+}
+
+/// Publishes the default database's writes to a NATS server, with the
+/// `nats` feature; see `owldb::server::nats`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NatsConfig {
+    /// Such as `nats://10.0.0.7:4222`; nothing is published when unset.
+    pub url: Option<String>,
+    pub subject_prefix: String,
+    /// `json` or `bson`.
+    pub format: String,
+    /// Every collection but the internal ones when empty.
+    pub collections: Vec<String>,
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            subject_prefix: "owldb".to_string(),
+            format: "json".to_string(),
+            collections: Vec::new(),
+        }
+    }
+}
+
+/// TLS for every front end; off unless both `cert` and `key` are set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub client_ca: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: usize,
+    pub max_in_flight: usize,
+    pub max_request_size: usize,
+    /// Requests per second each client may make; unlimited when unset.
+    pub rate_limit: Option<f64>,
+    /// Defaults to a second's worth of `rate_limit`.
+    pub rate_burst: Option<u32>,
+    /// Seconds a cursor may sit unused before it's dropped.
+    pub cursor_timeout_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let limits = LimitOptions::default();
+        Self {
+            max_connections: limits.max_connections,
+            max_in_flight: limits.max_in_flight,
+            max_request_size: limits.max_request_size,
+            rate_limit: None,
+            rate_burst: None,
+            cursor_timeout_secs: TenantOptions::default().cursor_timeout.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub name: String,
+    pub data: String,
+    pub quota: Option<u64>,
+}
+
+/// Where to POST a database's writes; see `owldb::server::webhooks`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// The database whose writes are sent; the default one when unset.
+    pub database: Option<String>,
+    /// Every collection but the internal ones when empty.
+    #[serde(default)]
+    pub collections: Vec<String>,
+    /// `insert`, `update` or `delete`; all of them when empty.
+    #[serde(default)]
+    pub operations: Vec<String>,
+    /// Fields a written document must have, with these values.
+    #[serde(default)]
+    pub filter: toml::Table,
+    pub max_attempts: Option<u32>,
+}
+
+impl ServerConfig {
+    /// Reads the file at `path`; settings it leaves out keep their defaults.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        let path = path.as_ref();
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(DatabaseError::IoError)?;
+        toml::from_str(&text)
+            .map_err(|e| DatabaseError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, DatabaseError> {
+        toml::from_str(text).map_err(|e| DatabaseError::InvalidConfig(e.to_string()))
+    }
+
+    /// Overrides settings with the `OWLDB_*` variables in `vars`, as taken
+    /// from `std::env::vars()`.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), DatabaseError> {
+        for (name, value) in vars {
+            let invalid = || DatabaseError::InvalidConfig(format!("{}={}", name, value));
+            match name.as_str() {
+                "OWLDB_DATA" => self.data = value,
+                "OWLDB_LISTEN" => self.listen.http = value,
+                "OWLDB_GRPC" => self.listen.grpc = Some(value),
+                "OWLDB_MONGO" => self.listen.mongo = Some(value),
+                "OWLDB_RESP" => self.listen.resp = Some(value),
+                "OWLDB_DURABILITY" => {
+                    self.storage.durability = match value.as_str() {
+                        "sync" => Durability::Sync,
+                        "buffered" => Durability::Buffered,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "OWLDB_CACHE_CAPACITY" => {
+                    self.storage.cache_capacity = value.parse().map_err(|_| invalid())?
+                }
+                "OWLDB_AUTH" => {
+                    self.auth.enabled = match value.as_str() {
+                        "true" | "1" => true,
+                        "false" | "0" => false,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "OWLDB_LEADER" => self.replication.leader = Some(value),
+                "OWLDB_TLS_CERT" => self.tls.cert = Some(value),
+                "OWLDB_TLS_KEY" => self.tls.key = Some(value),
+                "OWLDB_TLS_CLIENT_CA" => self.tls.client_ca = Some(value),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the settings make sense together, once every source has been
+    /// applied.
+    pub fn validate(&self) -> Result<(), DatabaseError> {
+        let invalid = |message: String| Err(DatabaseError::InvalidConfig(message));
+
+        let mut names = vec![DEFAULT_TENANT];
+        for database in &self.databases {
+            if !is_valid_name(&database.name) || names.contains(&database.name.as_str()) {
+                return invalid(format!("database name '{}'", database.name));
+            }
+            names.push(&database.name);
+        }
+
+        let compression = &self.listen.compression;
+        for name in compression.http.iter().chain(&compression.mongo) {
+            if Compressor::from_name(name).is_none() {
+                return invalid(format!("unsupported compressor '{}'", name));
+            }
+        }
+
+        for origin in &self.http.cors_origins {
+            let valid = origin == "*"
+                || ((origin.starts_with("http://") || origin.starts_with("https://"))
+                    && !origin.ends_with('/')
+                    && origin.bytes().all(|b| b.is_ascii_graphic()));
+            if !valid {
+                return invalid(format!("CORS origin '{}'", origin));
+            }
+        }
+
+        if self.storage.oplog_entries == Some(0) {
+            return invalid("the oplog must keep some entries".to_string());
+        }
+        if let Some(entries) = self.storage.handoff_entries {
+            if entries == 0 || self.storage.oplog_entries.is_none() {
+                return invalid("handoff needs an oplog and some entries".to_string());
+            }
+        }
+        if self.audit.file.is_some() && self.audit.collection {
+            return invalid("audit goes to a file or a collection, not both".to_string());
+        }
+        let replication = &self.replication;
+        if let Some(leader) = &replication.leader {
+            if !(leader.starts_with("http://") || leader.starts_with("https://")) {
+                return invalid(format!("leader '{}'", leader));
+            }
+            // Reads would be recorded there too, and a follower can't write.
+            if self.audit.collection {
+                return invalid("a follower can't audit to a collection".to_string());
+            }
+        }
+        if !replication.peers.is_empty() {
+            if replication.leader.is_some() {
+                return invalid("a server has either a leader or peers".to_string());
+            }
+            if replication.delay_secs > 0 {
+                return invalid("only a follower can be delayed".to_string());
+            }
+            if replication.node.as_deref().is_none_or(str::is_empty)
+                || self.storage.oplog_entries.is_none()
+            {
+                return invalid("peers need a node name and an oplog".to_string());
+            }
+            for peer in &replication.peers {
+                if !(peer.starts_with("http://") || peer.starts_with("https://")) {
+                    return invalid(format!("peer '{}'", peer));
+                }
+            }
+        }
+        if replication.username.is_some() != replication.password.is_some() {
+            return invalid("replication needs both a username and a password".to_string());
+        }
+        for webhook in &self.webhooks {
+            if !webhook.url.starts_with("http://") {
+                return invalid(format!("webhook '{}' must be an http:// URL", webhook.url));
+            }
+            if let Some(database) = &webhook.database {
+                if !names.contains(&database.as_str()) {
+                    return invalid(format!("webhook for unknown database '{}'", database));
+                }
+            }
+            for operation in &webhook.operations {
+                if operation_type(operation).is_none() {
+                    return invalid(format!("webhook operation '{}'", operation));
+                }
+            }
+            if bson::to_document(&webhook.filter).is_err() || webhook.max_attempts == Some(0) {
+                return invalid(format!("webhook '{}'", webhook.url));
+            }
+        }
+        let nats = &self.nats;
+        if nats
+            .url
+            .as_ref()
+            .is_some_and(|url| url.contains("://") && !url.starts_with("nats://"))
+            || nats.subject_prefix.is_empty()
+            || !["json", "bson"].contains(&nats.format.as_str())
+        {
+            return invalid("NATS needs a nats:// URL, a subject prefix and a format".to_string());
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some()
+            || (self.tls.client_ca.is_some() && self.tls.cert.is_none())
+        {
+            return invalid("TLS needs both a certificate and a key".to_string());
+        }
+
+        let limits = &self.limits;
+        if limits.max_connections == 0
+            || limits.max_in_flight == 0
+            || limits.max_request_size == 0
+            || limits.cursor_timeout_secs == 0
+        {
+            return invalid("limits must be positive".to_string());
+        }
+        match (limits.rate_limit, limits.rate_burst) {
+            (Some(rate), _) if !(rate > 0.0 && rate.is_finite()) => {
+                invalid(format!("rate limit {}", rate))
+            }
+            (None, Some(_)) => invalid("a rate burst needs a rate limit".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// How every database is opened.
+    pub fn database_options(&self) -> DatabaseOptions {
+        let write_buffer = match self.storage.durability {
+            Durability::Sync => None,
+            Durability::Buffered => Some(WriteBufferOptions {
+                flush_interval: Duration::from_millis(self.storage.flush_interval_ms),
+                ..WriteBufferOptions::default()
+            }),
+        };
+
+        DatabaseOptions {
+            cache_capacity: self.storage.cache_capacity,
+            write_buffer,
+            oplog: self
+                .storage
+                .oplog_entries
+                .map(|retained_entries| OplogOptions {
+                    retained_entries,
+                    node: self.replication.node.clone(),
+                    handoff: self
+                        .storage
+                        .handoff_entries
+                        .map(|max_entries| HandoffOptions {
+                            max_entries,
+                            ..HandoffOptions::default()
+                        }),
+                }),
+            ..DatabaseOptions::default()
+        }
+    }
+
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            compressors: self.listen.compression.http_compressors(),
+            json: self.http.json,
+            cors_origins: self.http.cors_origins.clone(),
+        }
+    }
+
+    /// Each webhook, with the name of the database it watches.
+    pub fn webhook_options(&self) -> Vec<(String, WebhookOptions)> {
+        self.webhooks
+            .iter()
+            .map(|webhook| {
+                let database = webhook.database.as_deref().unwrap_or(DEFAULT_TENANT);
+                let options = WebhookOptions {
+                    url: webhook.url.clone(),
+                    collections: webhook.collections.clone(),
+                    operations: webhook
+                        .operations
+                        .iter()
+                        .filter_map(|operation| operation_type(operation))
+                        .collect(),
+                    filter: bson::to_document(&webhook.filter).unwrap_or_default(),
+                    json: self.http.json,
+                    max_attempts: webhook
+                        .max_attempts
+                        .unwrap_or(WebhookOptions::default().max_attempts),
+                    ..WebhookOptions::default()
+                };
+                (database.to_string(), options)
+            })
+            .collect()
+    }
+
+    pub fn limit_options(&self) -> LimitOptions {
+        let limits = &self.limits;
+        // A second's worth of requests at once, unless told otherwise.
+        let rate_limit = limits
+            .rate_limit
+            .map(|requests_per_second| RateLimitOptions {
+                requests_per_second,
+                burst: limits
+                    .rate_burst
+                    .unwrap_or(requests_per_second.ceil() as u32),
+            });
+
+        LimitOptions {
+            max_connections: limits.max_connections,
+            max_in_flight: limits.max_in_flight,
+            max_request_size: limits.max_request_size,
+            rate_limit,
+        }
+    }
+}
+
+/// Names `validate` has checked.
+fn operation_type(name: &str) -> Option<OperationType> {
+    match name {
+        "insert" => Some(OperationType::Insert),
+        "update" => Some(OperationType::Update),
+        "delete" => Some(OperationType::Delete),
+        "expired" => Some(OperationType::Expire),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config() {
+        let mut config = ServerConfig::parse(
+            r#"
+            data = "/var/lib/owldb"
+
+            [listen]
+            grpc = "0.0.0.0:50051"
+
+            [listen.compression]
+            mongo = ["snappy"]
+
+            [http]
+            json = "plain"
+            cors_origins = ["http://localhost:5173"]
+
+            [storage]
+            durability = "buffered"
+            flush_interval_ms = 50
+            oplog_entries = 500
+            handoff_entries = 5000
+
+            [limits]
+            rate_limit = 2.5
+
+            [[databases]]
+            name = "acme"
+            data = "/var/lib/acme"
+            quota = 1024
+
+            [[webhooks]]
+            url = "http://10.0.0.5:9000/orders"
+            database = "acme"
+            operations = ["insert"]
+            filter = { status = "paid" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.data, "/var/lib/owldb");
+        assert_eq!(config.listen.http, ListenConfig::default().http);
+        assert_eq!(config.listen.grpc.as_deref(), Some("0.0.0.0:50051"));
+        assert_eq!(config.databases[0].quota, Some(1024));
+        assert_eq!(config.limit_options().rate_limit.unwrap().burst, 3);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.listen.compression.mongo_compressors(),
+            vec![Compressor::Snappy]
+        );
+        assert!(config.listen.compression.http_compressors().is_empty());
+        assert_eq!(config.http_options().json, JsonMode::Plain);
+        let (database, webhook) = config.webhook_options().remove(0);
+        assert_eq!(database, "acme");
+        assert_eq!(webhook.operations, [OperationType::Insert]);
+        assert_eq!(webhook.filter, bson::doc! { "status": "paid" });
+
+        let options = config.database_options();
+        let oplog = options.oplog.unwrap();
+        assert_eq!(oplog.retained_entries, 500);
+        assert_eq!(oplog.handoff.unwrap().max_entries, 5000);
+        assert_eq!(
+            options.write_buffer.unwrap().flush_interval,
+            Duration::from_millis(50)
+        );
+
+        config
+            .apply_env([
+                ("OWLDB_DATA".to_string(), "/srv/owldb".to_string()),
+                ("OWLDB_DURABILITY".to_string(), "sync".to_string()),
+                ("OWLDB_AUTH".to_string(), "1".to_string()),
+                ("HOME".to_string(), "/root".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(config.data, "/srv/owldb");
+        assert!(config.auth.enabled);
+        assert!(config.database_options().write_buffer.is_none());
+        assert!(matches!(
+            config.apply_env([("OWLDB_AUTH".to_string(), "yes".to_string())]),
+            Err(DatabaseError::InvalidConfig(_))
+        ));
+
+        config.listen.compression.http = vec!["zstd".to_string()];
+        assert!(config.validate().is_err());
+        config.listen.compression.http.clear();
+        config.http.cors_origins.push("localhost:5173/".to_string());
+        assert!(config.validate().is_err());
+        config.http.cors_origins.pop();
+        config.webhooks[0].operations.push("upsert".to_string());
+        assert!(config.validate().is_err());
+        config.webhooks.clear();
+        config.nats.url = Some("nats://10.0.0.7:4222".to_string());
+        config.nats.format = "avro".to_string();
+        assert!(config.validate().is_err());
+        config.nats = NatsConfig::default();
+        config.storage.handoff_entries = Some(0);
+        assert!(config.validate().is_err());
+        config.storage.handoff_entries = None;
+        config.replication.leader = Some("http://10.0.0.1:50051".to_string());
+        assert!(config.validate().is_ok());
+        config.audit.collection = true;
+        assert!(config.validate().is_err());
+        config.audit.collection = false;
+        config.replication.username = Some("replicator".to_string());
+        assert!(config.validate().is_err());
+        config.replication.username = None;
+        config.replication.peers = vec!["http://10.0.0.2:50051".to_string()];
+        assert!(config.validate().is_err());
+        config.replication.leader = None;
+        assert!(config.validate().is_err());
+        config.replication.node = Some("east".to_string());
+        assert!(config.validate().is_ok());
+        config.replication.delay_secs = 3600;
+        assert!(config.validate().is_err());
+        config.replication.delay_secs = 0;
+        let oplog = config.database_options().oplog.unwrap();
+        assert_eq!(oplog.node.as_deref(), Some("east"));
+        config.databases[0].name = DEFAULT_TENANT.to_string();
+        assert!(config.validate().is_err());
+        assert!(ServerConfig::parse("listen = 8080").is_err());
+        assert!(ServerConfig::parse("[storage]\ncache = 1").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Server-side cursors, for finds matching too many documents to answer at
+//! once. The find answers a first batch and a cursor ID, and the client
+//! fetches the rest by that ID a batch at a time, or kills the cursor when
+//! it has seen enough.
+//!
+//! The matches are taken when the find runs, so later writes don't show up
+//! in its batches. A cursor only serves the user who opened it, and is
+//! dropped once it's been idle for its tenant's `cursor_timeout`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bson::Document;
+
+use crate::db::DatabaseError;
+
+/// Documents in a batch when the client doesn't ask for a size.
+pub const DEFAULT_BATCH_SIZE: usize = 101;
+
+/// A tenant's open cursors. Cloning is cheap; clones share the cursors.
+#[derive(Clone)]
+pub struct Cursors {
+    inner: Arc<Mutex<CursorsInner>>,
+    idle_timeout: Duration,
+}
+
+struct CursorsInner {
+    next_id: i64,
+    open: HashMap<i64, Cursor>,
+}
+
+struct Cursor {
+    owner: String,
+    collection: String,
+    documents: VecDeque<Document>,
+    last_used: Instant,
+}
+
+/// One batch of a find's matches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub documents: Vec<Document>,
+    /// Where the next batch comes from, or 0 once there are no more.
+    pub cursor_id: i64,
+}
+
+impl Cursors {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(CursorsInner {
+                next_id: 1,
+                open: HashMap::new(),
+            })),
+            idle_timeout,
+        }
+    }
+
+    /// Answers the first `batch_size` of `documents`, keeping the rest for
+    /// `owner` behind a new cursor if there are any.
+    pub fn open(
+        &self,
+        owner: &str,
+        collection: &str,
+        documents: Vec<Document>,
+        batch_size: usize,
+    ) -> Batch {
+        let mut documents = VecDeque::from(documents);
+        let first: Vec<Document> = documents.drain(..batch_size.min(documents.len())).collect();
+        if documents.is_empty() && batch_size > 0 {
+            return Batch {
+                documents: first,
+                cursor_id: 0,
+            };
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        self.drop_idle(&mut inner);
+        let cursor_id = inner.next_id;
+        inner.next_id += 1;
+        inner.open.insert(
+            cursor_id,
+            Cursor {
+                owner: owner.to_string(),
+                collection: collection.to_string(),
+                documents,
+                last_used: Instant::now(),
+            },
+        );
+
+        Batch {
+            documents: first,
+            cursor_id,
+        }
+    }
+
+    /// The collection `owner`'s cursor `cursor_id` reads from.
+    pub fn collection(&self, cursor_id: i64, owner: &str) -> Result<String, DatabaseError> {
+        let mut inner = self.inner.lock().unwrap();
+        self.drop_idle(&mut inner);
+        match inner.open.get(&cursor_id) {
+            Some(cursor) if cursor.owner == owner => Ok(cursor.collection.clone()),
+            _ => Err(DatabaseError::CursorNotFound(cursor_id)),
+        }
+    }
+
+    /// Answers the next `batch_size` documents of `owner`'s cursor
+    /// `cursor_id`, closing it once they run out.
+    pub fn next(
+        &self,
+        cursor_id: i64,
+        owner: &str,
+        batch_size: usize,
+    ) -> Result<Batch, DatabaseError> {
+        let mut inner = self.inner.lock().unwrap();
+        self.drop_idle(&mut inner);
+        let cursor = match inner.open.get_mut(&cursor_id) {
+            Some(cursor) if cursor.owner == owner => cursor,
+            _ => return Err(DatabaseError::CursorNotFound(cursor_id)),
+        };
+
+        let size = batch_size.min(cursor.documents.len());
+        let documents: Vec<Document> = cursor.documents.drain(..size).collect();
+        cursor.last_used = Instant::now();
+        if cursor.documents.is_empty() {
+            inner.open.remove(&cursor_id);
+            return Ok(Batch {
+                documents,
+                cursor_id: 0,
+            });
+        }
+
+        Ok(Batch {
+            documents,
+            cursor_id,
+        })
+    }
+
+    /// Closes `owner`'s cursor `cursor_id`, answering whether it was open.
+    pub fn kill(&self, cursor_id: i64, owner: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.open.get(&cursor_id) {
+            Some(cursor) if cursor.owner == owner => inner.open.remove(&cursor_id).is_some(),
+            _ => false,
+        }
+    }
+
+    fn drop_idle(&self, inner: &mut CursorsInner) {
+        let idle_timeout = self.idle_timeout;
+        inner
+            .open
+            .retain(|_, cursor| cursor.last_used.elapsed() < idle_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_batches() {
+        let cursors = Cursors::new(Duration::from_secs(60));
+        let documents: Vec<Document> = (0..5).map(|n| bson::doc! { "n": n }).collect();
+
+        let batch = cursors.open("alice", "users", documents.clone(), 10);
+        assert_eq!((batch.documents.len(), batch.cursor_id), (5, 0));
+
+        let batch = cursors.open("alice", "users", documents.clone(), 2);
+        assert_eq!(batch.documents, documents[..2]);
+        let id = batch.cursor_id;
+        assert_eq!(cursors.collection(id, "alice").unwrap(), "users");
+        assert!(matches!(
+            cursors.next(id, "bob", 2),
+            Err(DatabaseError::CursorNotFound(_))
+        ));
+        assert_eq!(
+            cursors.next(id, "alice", 2).unwrap().documents,
+            documents[2..4]
+        );
+        let last = cursors.next(id, "alice", 2).unwrap();
+        assert_eq!(
+            (last.documents, last.cursor_id),
+            (documents[4..].to_vec(), 0)
+        );
+        assert!(cursors.next(id, "alice", 2).is_err());
+
+        let id = cursors
+            .open("alice", "users", documents.clone(), 0)
+            .cursor_id;
+        assert!(!cursors.kill(id, "bob"));
+        assert!(cursors.kill(id, "alice"));
+        assert!(!cursors.kill(id, "alice"));
+
+        let idle = Cursors::new(Duration::ZERO);
+        let id = idle.open("alice", "users", documents, 1).cursor_id;
+        assert!(idle.next(id, "alice", 1).is_err());
+    }
+}
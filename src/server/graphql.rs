@@ -0,0 +1,346 @@
+//! GraphQL interface over a database, served by the REST server at
+//! `POST /graphql`. Documents have no schema, so every collection is
+//! exposed through the same `Document` type, whose fields are read as JSON
+//! (relaxed Extended JSON, as in the REST interface):
+//!
+//! ```graphql
+//! query {
+//!   documents(collection: "users", filter: { age: 30 }, limit: 10) {
+//!     id
+//!     name: field(name: "name")
+//!   }
+//! }
+//!
+//! mutation {
+//!   insert(collection: "users", document: { name: "John", age: 30 })
+//! }
+//! ```
+//!
+//! Requests authenticate like REST ones, with `Authorization: Bearer
+//! <token>`, and each tenant has its own endpoint next to its REST routes.
+//! Errors carry the request's ID in their `requestId` extension.
+
+use async_graphql::{Context, EmptySubscription, ErrorExtensions, Object, Schema, ServerError, ID};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use bson::{Bson, Document};
+use log::error;
+use serde_json::Value;
+
+use crate::db::DatabaseError;
+use crate::server::audit::AuditEvent;
+use crate::server::auth::{self, Principal};
+use crate::server::roles::Access;
+use crate::server::tenants::Tenant;
+use crate::server::trace;
+
+pub type OwlDbSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// The schema of one tenant, for serving it some other way than `router`.
+pub fn schema(tenant: Tenant) -> OwlDbSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(tenant)
+        .finish()
+}
+
+pub fn router(tenant: Tenant) -> Router {
+    Router::new()
+        .route("/graphql", post(execute))
+        .with_state(schema(tenant))
+}
+
+async fn execute(State(schema): State<OwlDbSchema>, headers: HeaderMap, body: Bytes) -> Response {
+    let request: async_graphql::Request = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = ServerError::new(format!("invalid request: {}", e), None);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(async_graphql::Response::from_errors(vec![error])),
+            )
+                .into_response();
+        }
+    };
+
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(auth::bearer);
+    let principal = match schema
+        .data::<Tenant>()
+        .map(|tenant| tenant.auth().authenticate(token))
+    {
+        Some(Ok(principal)) => principal,
+        _ => {
+            let error = ServerError::new("authentication required", None);
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, "Bearer")],
+                Json(async_graphql::Response::from_errors(vec![error])),
+            )
+                .into_response();
+        }
+    };
+
+    Json(schema.execute(request.data(principal)).await).into_response()
+}
+
+fn to_error(e: DatabaseError) -> async_graphql::Error {
+    let code = match &e {
+        DatabaseError::InvalidUpdate(_)
+        | DatabaseError::InvalidQuery(_)
+        | DatabaseError::InvalidName(_)
+        | DatabaseError::ValidationError(_) => "BAD_USER_INPUT",
+        DatabaseError::ReadOnly | DatabaseError::PermissionDenied(_) => "FORBIDDEN",
+        DatabaseError::Unauthenticated => "UNAUTHENTICATED",
+        DatabaseError::RateLimited(_) => "RATE_LIMITED",
+        DatabaseError::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+        DatabaseError::DocumentExists(_) => "CONFLICT",
+        e if e.is_transient() => "CONFLICT",
+        _ => {
+            let id = trace::current_id().unwrap_or_default();
+            error!("[{}] Failed to serve request: {:?}", id, e);
+            "INTERNAL_SERVER_ERROR"
+        }
+    };
+    async_graphql::Error::new(format!("{:?}", e)).extend_with(|_, extensions| {
+        extensions.set("code", code);
+        if let Some(id) = trace::current_id() {
+            extensions.set("requestId", id);
+        }
+    })
+}
+
+/// Checks the request's user may make `event`, records it, and hands out
+/// the tenant to make it with.
+async fn authorize<'a>(
+    ctx: &Context<'a>,
+    access: Access,
+    event: AuditEvent,
+) -> async_graphql::Result<&'a Tenant> {
+    let principal = ctx.data::<Principal>()?;
+    let tenant = ctx.data::<Tenant>()?;
+    trace::stage_sync("auth", || {
+        tenant
+            .auth()
+            .authorize(principal, &event.collection, access)
+    })
+    .map_err(to_error)?;
+    tenant.audit(principal, event).await.map_err(to_error)?;
+    Ok(tenant)
+}
+
+fn to_document(value: Value) -> async_graphql::Result<Document> {
+    match Bson::try_from(value) {
+        Ok(Bson::Document(doc)) => Ok(doc),
+        Ok(_) => Err("expected an object".into()),
+        Err(e) => Err(format!("invalid Extended JSON: {}", e).into()),
+    }
+}
+
+/// A stored document.
+pub struct Record {
+    id: String,
+    doc: Document,
+}
+
+#[Object(name = "Document")]
+impl Record {
+    async fn id(&self) -> ID {
+        ID(self.id.clone())
+    }
+
+    /// The whole document.
+    async fn data(&self) -> async_graphql::Json<Value> {
+        async_graphql::Json(Bson::Document(self.doc.clone()).into_relaxed_extjson())
+    }
+
+    /// One top-level field, or null if the document has none by that name.
+    async fn field(&self, name: String) -> Option<async_graphql::Json<Value>> {
+        self.doc
+            .get(&name)
+            .map(|value| async_graphql::Json(value.clone().into_relaxed_extjson()))
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn document(
+        &self,
+        ctx: &Context<'_>,
+        collection: String,
+        id: ID,
+    ) -> async_graphql::Result<Option<Record>> {
+        let event = AuditEvent::new("find", &collection).with_id(&id.0);
+        let db = authorize(ctx, Access::Read, event).await?.db();
+        let doc = trace::stage("db", db.find_one(collection, id.0.clone()))
+            .await
+            .map_err(to_error)?;
+
+        Ok(doc.map(|doc| Record { id: id.0, doc }))
+    }
+
+    /// The documents whose fields equal those of `filter`, as with
+    /// `Database::find`.
+    async fn documents(
+        &self,
+        ctx: &Context<'_>,
+        collection: String,
+        filter: Option<async_graphql::Json<Value>>,
+        #[graphql(default = 0)] skip: usize,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<Record>> {
+        let query = match filter {
+            Some(filter) => to_document(filter.0)?,
+            None => Document::new(),
+        };
+        let event = AuditEvent::new("find", &collection).with_filter(query.clone());
+        let db = authorize(ctx, Access::Read, event).await?.db();
+
+        let docs = match trace::stage("db", db.find_with_ids(collection, query)).await {
+            Ok(docs) => docs,
+            // A collection nobody wrote to yet has no directory to scan.
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Vec::new()
+            }
+            Err(e) => return Err(to_error(e)),
+        };
+
+        Ok(docs
+            .into_iter()
+            .skip(skip)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(id, doc)| Record { id, doc })
+            .collect())
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Inserts `document`, returning its id.
+    async fn insert(
+        &self,
+        ctx: &Context<'_>,
+        collection: String,
+        document: async_graphql::Json<Value>,
+    ) -> async_graphql::Result<ID> {
+        let doc = to_document(document.0)?;
+        let event = AuditEvent::new("insert", &collection);
+        let tenant = authorize(ctx, Access::Insert, event).await?;
+        tenant.check_quota().await.map_err(to_error)?;
+        let id = trace::stage("db", tenant.db().insert_one(collection, doc))
+            .await
+            .map_err(to_error)?;
+
+        Ok(ID(id))
+    }
+
+    /// Applies `update`, in the forms `Database::update_one` takes. False
+    /// if there is no such document.
+    async fn update(
+        &self,
+        ctx: &Context<'_>,
+        collection: String,
+        id: ID,
+        update: async_graphql::Json<Value>,
+    ) -> async_graphql::Result<bool> {
+        let update = to_document(update.0)?;
+        let event = AuditEvent::new("update", &collection).with_id(&id.0);
+        let tenant = authorize(ctx, Access::Write, event).await?;
+        tenant.check_quota().await.map_err(to_error)?;
+
+        trace::stage("db", tenant.db().update_one(collection, id.0, update))
+            .await
+            .map_err(to_error)
+    }
+
+    /// Deletes the document, if there is one, returning its id.
+    async fn delete(
+        &self,
+        ctx: &Context<'_>,
+        collection: String,
+        id: ID,
+    ) -> async_graphql::Result<ID> {
+        let event = AuditEvent::new("delete", &collection).with_id(&id.0);
+        let db = authorize(ctx, Access::Write, event).await?.db();
+        trace::stage("db", db.delete_one(collection, id.0.clone()))
+            .await
+            .map_err(to_error)?;
+
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::Database;
+    use crate::server::auth::Auth;
+    use crate::server::tenants::{TenantOptions, DEFAULT_TENANT};
+
+    #[tokio::test]
+    async fn test_queries_and_mutations() {
+        let folder_path = "data_tests/test_graphql".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let schema = schema(Tenant::new(
+            DEFAULT_TENANT,
+            db,
+            Auth::disabled(),
+            TenantOptions::default(),
+        ));
+        let principal = Auth::disabled().authenticate(None).unwrap();
+
+        let run = |query: &str, variables: Value| {
+            let request = async_graphql::Request::new(query)
+                .variables(async_graphql::Variables::from_json(variables))
+                .data(principal.clone());
+            let schema = schema.clone();
+            async move {
+                let response = schema.execute(request).await;
+                assert!(response.errors.is_empty(), "{:?}", response.errors);
+                response.data.into_json().unwrap()
+            }
+        };
+        let insert = r#"mutation($doc: JSON!) { insert(collection: "users", document: $doc) }"#;
+
+        let inserted = run(insert, json!({ "doc": { "name": "John", "age": 30 } })).await;
+        let id = inserted["insert"].as_str().unwrap().to_string();
+        run(insert, json!({ "doc": { "name": "Jane", "age": 25 } })).await;
+        // Operators start with `$`, so updates come in variables.
+        run(
+            r#"mutation($id: ID!, $update: JSON!) { update(collection: "users", id: $id, update: $update) }"#,
+            json!({ "id": id, "update": { "$set": { "age": 31 } } }),
+        )
+        .await;
+
+        let found = run(
+            r#"{ documents(collection: "users", filter: { name: "John" }) { id age: field(name: "age") } }"#,
+            json!({}),
+        )
+        .await;
+        assert_eq!(found, json!({ "documents": [{ "id": id, "age": 31 }] }));
+
+        run(
+            r#"mutation($id: ID!) { delete(collection: "users", id: $id) }"#,
+            json!({ "id": id }),
+        )
+        .await;
+        let remaining = run(r#"{ documents(collection: "users") { data } }"#, json!({})).await;
+        assert_eq!(
+            remaining,
+            json!({ "documents": [{ "data": { "name": "Jane", "age": 25 } }] })
+        );
+    }
+}
@@ -0,0 +1,1298 @@
+//! gRPC interface over a database, as described by `proto/owldb.proto`.
+//! Documents, queries and updates travel BSON-encoded. With authentication
+//! enabled, calls carry `authorization: Bearer <token>` metadata, the token
+//! coming from `Login`.
+//!
+//! `Tail` streams a database's oplog (see `owldb::db::Database::read_oplog`)
+//! to followers and other consumers that can't afford to miss a write, and
+//! `Snapshot` copies every document for them to start from. `Replicate`
+//! streams it too, in batches a follower acknowledges as it applies them.
+//!
+//! Calls may carry an `x-request-id` metadata key; responses carry it back,
+//! or the one the server made up, along with `server-timing`. Errors carry
+//! it in their metadata too. See `owldb::server::trace`.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use log::{error, info, warn};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::codegen::http;
+use tonic::metadata::MetadataValue;
+use tonic::{Request, Response, Status, Streaming};
+use tower::Service;
+
+use crate::db::{self, Database, DatabaseError, OperationType, WriteOp};
+use crate::server::audit::AuditEvent;
+use crate::server::auth::{self, Principal};
+use crate::server::limits::{client_key, Limits};
+use crate::server::roles::Access;
+use crate::server::tenants::{Tenant, Tenants};
+use crate::server::trace::{self, RequestTrace, REQUEST_ID_HEADER, SERVER_TIMING_HEADER};
+
+/// Messages of `proto/owldb.proto`, kept in sync with it by hand, and the
+/// service stubs generated from them.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Document {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub bson: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InsertRequest {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub document: Vec<u8>,
+        #[prost(string, tag = "3")]
+        pub id: String,
+        #[prost(bool, tag = "4")]
+        pub if_absent: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InsertResponse {
+        #[prost(string, tag = "1")]
+        pub id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetRequest {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(string, tag = "2")]
+        pub id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetResponse {
+        #[prost(message, optional, tag = "1")]
+        pub document: Option<Document>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FindRequest {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub query: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FindResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub documents: Vec<Document>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UpdateRequest {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(string, tag = "2")]
+        pub id: String,
+        #[prost(bytes = "vec", tag = "3")]
+        pub update: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UpdateResponse {
+        #[prost(bool, tag = "1")]
+        pub updated: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeleteRequest {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(string, tag = "2")]
+        pub id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeleteResponse {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WatchRequest {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(bool, tag = "2")]
+        pub unsubscribe: bool,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Operation {
+        Insert = 0,
+        Update = 1,
+        Delete = 2,
+        Expire = 3,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LoginRequest {
+        #[prost(string, tag = "1")]
+        pub username: String,
+        #[prost(string, tag = "2")]
+        pub password: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LoginResponse {
+        #[prost(string, tag = "1")]
+        pub token: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Write {
+        #[prost(enumeration = "Operation", tag = "1")]
+        pub operation: i32,
+        #[prost(string, tag = "2")]
+        pub collection: String,
+        #[prost(string, tag = "3")]
+        pub id: String,
+        #[prost(bytes = "vec", tag = "4")]
+        pub document: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommitRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub writes: Vec<Write>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CommitResponse {
+        #[prost(string, repeated, tag = "1")]
+        pub inserted_ids: Vec<String>,
+        #[prost(uint64, tag = "2")]
+        pub updated: u64,
+        #[prost(uint64, tag = "3")]
+        pub deleted: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ChangeEvent {
+        #[prost(enumeration = "Operation", tag = "1")]
+        pub operation: i32,
+        #[prost(string, tag = "2")]
+        pub collection: String,
+        #[prost(string, tag = "3")]
+        pub id: String,
+        #[prost(bytes = "vec", tag = "4")]
+        pub document: Vec<u8>,
+        #[prost(uint64, tag = "5")]
+        pub token: u64,
+        #[prost(message, repeated, tag = "6")]
+        pub changes: Vec<FieldChange>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FieldChange {
+        #[prost(string, tag = "1")]
+        pub path: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub old_value: Vec<u8>,
+        #[prost(bytes = "vec", tag = "3")]
+        pub new_value: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TailRequest {
+        #[prost(uint64, tag = "1")]
+        pub from: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct OplogEntry {
+        #[prost(uint64, tag = "1")]
+        pub position: u64,
+        #[prost(int64, tag = "2")]
+        pub timestamp: i64,
+        #[prost(enumeration = "Operation", tag = "3")]
+        pub operation: i32,
+        #[prost(string, tag = "4")]
+        pub collection: String,
+        #[prost(string, tag = "5")]
+        pub id: String,
+        #[prost(bytes = "vec", tag = "6")]
+        pub document: Vec<u8>,
+        #[prost(bytes = "vec", tag = "7")]
+        pub previous: Vec<u8>,
+        #[prost(string, tag = "8")]
+        pub origin_node: String,
+        #[prost(int64, tag = "9")]
+        pub origin_timestamp: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SnapshotRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SnapshotDocument {
+        #[prost(string, tag = "1")]
+        pub collection: String,
+        #[prost(string, tag = "2")]
+        pub id: String,
+        #[prost(bytes = "vec", tag = "3")]
+        pub document: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReplicateRequest {
+        #[prost(uint64, tag = "1")]
+        pub from: u64,
+        #[prost(uint32, tag = "2")]
+        pub batch_size: u32,
+        #[prost(uint32, tag = "3")]
+        pub window: u32,
+        #[prost(uint64, tag = "4")]
+        pub acknowledged: u64,
+        #[prost(string, tag = "5")]
+        pub follower: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct OplogBatch {
+        #[prost(message, repeated, tag = "1")]
+        pub entries: Vec<OplogEntry>,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/owldb.OwlDb.rs"));
+}
+
+use proto::owl_db_server::{OwlDb, OwlDbServer};
+
+/// Messages a response stream may run ahead of the client.
+const STREAM_BUFFER: usize = 64;
+
+/// Oplog entries per `Replicate` batch, and sent but unacknowledged, when
+/// the follower leaves it to the leader, and the most it may ask for.
+const REPLICATE_BATCH: usize = 256;
+const MAX_REPLICATE_BATCH: usize = 4096;
+const REPLICATE_WINDOW: u64 = 4096;
+const MAX_REPLICATE_WINDOW: u64 = 65536;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// The metadata key naming the tenant a call is for; calls without it go
+/// to the default one.
+pub const DATABASE_METADATA_KEY: &str = "x-owldb-database";
+
+/// The response metadata key of a `Snapshot`, holding the oplog position to
+/// tail from once it's been copied.
+pub const OPLOG_POSITION_METADATA_KEY: &str = "x-owldb-oplog-position";
+
+pub struct OwlDbService {
+    tenants: Tenants,
+    limits: Limits,
+}
+
+/// The service, ready to be added to a `tonic` server next to others.
+pub fn service(tenants: Tenants) -> OwlDbServer<OwlDbService> {
+    service_with_limits(tenants, Limits::default())
+}
+
+/// Like `service`, rate limiting calls under `limits`.
+pub fn service_with_limits(tenants: Tenants, limits: Limits) -> OwlDbServer<OwlDbService> {
+    let max_request_size = limits.options().max_request_size;
+    OwlDbServer::new(OwlDbService { tenants, limits }).max_decoding_message_size(max_request_size)
+}
+
+/// Serves the tenants on `addr` until `limits` are shut down.
+pub async fn serve(tenants: Tenants, addr: SocketAddr, limits: Limits) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Listening for gRPC on {}", listener.local_addr()?);
+
+    let admitted = limits.clone();
+    let incoming = TcpListenerStream::new(listener).filter_map(move |stream| match stream {
+        Ok(stream) => {
+            let peer = stream.peer_addr().ok()?;
+            // As tonic does for the listeners it binds itself.
+            let _ = stream.set_nodelay(true);
+            admitted.admit(stream, peer).map(Ok)
+        }
+        Err(e) => Some(Err(e)),
+    });
+    serve_incoming(tenants, incoming, &limits).await
+}
+
+/// Like `serve`, for clients connecting over TLS.
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    tenants: Tenants,
+    listener: crate::server::tls::TlsListener,
+    limits: Limits,
+) -> std::io::Result<()> {
+    info!("Listening for gRPC over TLS on {}", listener.local_addr());
+
+    let admitted = limits.clone();
+    let incoming = listener.filter_map(move |stream| match stream {
+        Ok(stream) => {
+            let peer = stream.get_ref().0.peer_addr().ok()?;
+            admitted.admit(stream, peer).map(Ok)
+        }
+        Err(e) => Some(Err(e)),
+    });
+    serve_incoming(tenants, incoming, &limits).await
+}
+
+async fn serve_incoming<S, IO>(
+    tenants: Tenants,
+    incoming: S,
+    limits: &Limits,
+) -> std::io::Result<()>
+where
+    S: Stream<Item = std::io::Result<IO>> + Send + 'static,
+    IO: tonic::transport::server::Connected
+        + tokio::io::AsyncRead
+        + tokio::io::AsyncWrite
+        + Unpin
+        + Send
+        + 'static,
+{
+    let options = limits.options();
+    tonic::transport::Server::builder()
+        .concurrency_limit_per_connection(options.max_in_flight)
+        .layer(tower::layer::layer_fn(|inner| Traced { inner }))
+        .add_service(service_with_limits(tenants, limits.clone()))
+        .serve_with_incoming_shutdown(incoming, limits.shutting_down())
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Serves each call as the current request of its task, and hands back
+/// its ID and timings as response metadata.
+#[derive(Clone)]
+struct Traced<S> {
+    inner: S,
+}
+
+impl<S, B, R> Service<http::Request<B>> for Traced<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let trace = RequestTrace::new(id);
+        let operation = request.uri().path().to_string();
+        let call = self.inner.call(request);
+
+        Box::pin(async move {
+            let mut response = trace.clone().scope(call).await?;
+
+            let headers = response.headers_mut();
+            for (name, value) in [
+                (REQUEST_ID_HEADER, trace.id().to_string()),
+                (SERVER_TIMING_HEADER, trace.server_timing()),
+            ] {
+                if let Ok(value) = http::HeaderValue::from_str(&value) {
+                    headers.insert(name, value);
+                }
+            }
+            // Errors before the first message come with the headers;
+            // streamed ones only at the end, so they show as OK here.
+            let outcome = match headers.get("grpc-status") {
+                Some(code) => format!("status {}", code.to_str().unwrap_or("?")),
+                None => "OK".to_string(),
+            };
+            trace.finish(&operation, outcome);
+            Ok(response)
+        })
+    }
+}
+
+fn to_status(e: DatabaseError) -> Status {
+    let mut status = status_for(e);
+    if let Some(id) = trace::current_id().and_then(|id| MetadataValue::try_from(id).ok()) {
+        status.metadata_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    status
+}
+
+fn status_for(e: DatabaseError) -> Status {
+    match &e {
+        DatabaseError::InvalidUpdate(_)
+        | DatabaseError::InvalidQuery(_)
+        | DatabaseError::InvalidName(_)
+        | DatabaseError::ValidationError(_) => Status::invalid_argument(format!("{:?}", e)),
+        DatabaseError::ReadOnly | DatabaseError::PermissionDenied(_) => {
+            Status::permission_denied(format!("{:?}", e))
+        }
+        DatabaseError::Unauthenticated => Status::unauthenticated(format!("{:?}", e)),
+        DatabaseError::RateLimited(_) | DatabaseError::QuotaExceeded(_) => {
+            Status::resource_exhausted(format!("{:?}", e))
+        }
+        DatabaseError::DatabaseNotFound(_) => Status::not_found(format!("{:?}", e)),
+        DatabaseError::DocumentExists(_) => Status::already_exists(format!("{:?}", e)),
+        DatabaseError::OplogTruncated(_) => Status::out_of_range(format!("{:?}", e)),
+        DatabaseError::OplogDisabled => Status::failed_precondition(format!("{:?}", e)),
+        e if e.is_transient() => Status::aborted(format!("{:?}", e)),
+        _ => {
+            let id = trace::current_id().unwrap_or_default();
+            error!("[{}] Failed to serve request: {:?}", id, e);
+            Status::internal(format!("{:?}", e))
+        }
+    }
+}
+
+fn decode(bytes: &[u8]) -> Result<bson::Document, Status> {
+    bson::Document::from_reader(&mut &bytes[..])
+        .map_err(|e| Status::invalid_argument(format!("invalid BSON: {}", e)))
+}
+
+fn encode(doc: &bson::Document) -> Result<Vec<u8>, Status> {
+    let mut buffer = Vec::new();
+    doc.to_writer(&mut buffer)
+        .map_err(|e| Status::internal(format!("failed to encode document: {}", e)))?;
+    Ok(buffer)
+}
+
+/// A collection nobody wrote to yet has no directory to scan.
+async fn find_with_ids(
+    db: &Database,
+    collection: String,
+    query: bson::Document,
+) -> Result<Vec<(String, bson::Document)>, Status> {
+    match trace::stage("db", db.find_with_ids(collection, query)).await {
+        Ok(docs) => Ok(docs),
+        Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Vec::new())
+        }
+        Err(e) => Err(to_status(e)),
+    }
+}
+
+fn to_operation(operation: OperationType) -> proto::Operation {
+    match operation {
+        OperationType::Insert => proto::Operation::Insert,
+        OperationType::Update => proto::Operation::Update,
+        OperationType::Delete => proto::Operation::Delete,
+        OperationType::Expire => proto::Operation::Expire,
+    }
+}
+
+fn to_event(event: db::ChangeEvent) -> Result<proto::ChangeEvent, Status> {
+    Ok(proto::ChangeEvent {
+        operation: to_operation(event.operation) as i32,
+        collection: event.collection,
+        id: event.id,
+        document: match &event.document {
+            Some(doc) => encode(doc)?,
+            None => Vec::new(),
+        },
+        token: event.token.unwrap_or_default(),
+        changes: event
+            .changes
+            .into_iter()
+            .map(|change| {
+                let encode_value = |value: Option<bson::Bson>| match value {
+                    Some(value) => encode(&bson::doc! { "value": value }),
+                    None => Ok(Vec::new()),
+                };
+                Ok(proto::FieldChange {
+                    path: change.path,
+                    old_value: encode_value(change.old)?,
+                    new_value: encode_value(change.new)?,
+                })
+            })
+            .collect::<Result<_, Status>>()?,
+    })
+}
+
+fn to_oplog_entry(entry: db::OplogEntry) -> Result<proto::OplogEntry, Status> {
+    Ok(proto::OplogEntry {
+        position: entry.position,
+        timestamp: entry.timestamp.timestamp_millis(),
+        operation: to_operation(entry.operation) as i32,
+        collection: entry.collection,
+        id: entry.id,
+        previous: match &entry.previous {
+            Some(doc) => encode(doc)?,
+            None => Vec::new(),
+        },
+        document: match &entry.document {
+            Some(doc) => encode(doc)?,
+            None => Vec::new(),
+        },
+        origin_node: entry
+            .origin
+            .as_ref()
+            .map(|origin| origin.node.clone())
+            .unwrap_or_default(),
+        origin_timestamp: entry
+            .origin
+            .map(|origin| origin.timestamp.timestamp_millis())
+            .unwrap_or_default(),
+    })
+}
+
+impl OwlDbService {
+    /// The tenant the call names in its metadata.
+    fn tenant<T>(&self, request: &Request<T>) -> Result<&Tenant, Status> {
+        let name = request
+            .metadata()
+            .get(DATABASE_METADATA_KEY)
+            .map(|value| value.to_str())
+            .transpose()
+            .map_err(|_| Status::invalid_argument("invalid database name"))?;
+        self.tenants.resolve(name).map_err(to_status)
+    }
+
+    /// Authenticates the call with its tenant and counts it against its
+    /// client's rate.
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<(&Tenant, Principal), Status> {
+        let tenant = self.tenant(request)?;
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(auth::bearer);
+        let principal =
+            trace::stage_sync("auth", || tenant.auth().authenticate(token)).map_err(to_status)?;
+        self.check_rate(request, tenant, Some(&principal.username))?;
+        Ok((tenant, principal))
+    }
+
+    fn check_rate<T>(
+        &self,
+        request: &Request<T>,
+        tenant: &Tenant,
+        username: Option<&str>,
+    ) -> Result<(), Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        self.limits
+            .check_rate(&client_key(tenant.name(), username, peer))
+            .map_err(to_status)
+    }
+
+    /// Authenticates the call, checks it may make `event` and records it,
+    /// handing out the tenant to make it with.
+    async fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        access: Access,
+        event: AuditEvent,
+    ) -> Result<&Tenant, Status> {
+        let (tenant, principal) = self.authenticate(request)?;
+        trace::stage_sync("auth", || {
+            tenant
+                .auth()
+                .authorize(&principal, &event.collection, access)
+        })
+        .map_err(to_status)?;
+        tenant.audit(&principal, event).await.map_err(to_status)?;
+        Ok(tenant)
+    }
+
+    /// Like `authorize`, for the calls reading the whole database that only
+    /// admins may make.
+    async fn authenticate_admin<T>(
+        &self,
+        request: &Request<T>,
+        action: &str,
+    ) -> Result<&Tenant, Status> {
+        let (tenant, principal) = self.authenticate(request)?;
+        if !principal.is_admin() {
+            return Err(to_status(DatabaseError::PermissionDenied(format!(
+                "only admins may {}",
+                action
+            ))));
+        }
+        tenant
+            .audit(&principal, AuditEvent::new(action, ""))
+            .await
+            .map_err(to_status)?;
+        Ok(tenant)
+    }
+}
+
+#[tonic::async_trait]
+impl OwlDb for OwlDbService {
+    type FindStreamStream = ResponseStream<proto::Document>;
+    type WatchStream = ResponseStream<proto::ChangeEvent>;
+    type TailStream = ResponseStream<proto::OplogEntry>;
+    type SnapshotStream = ResponseStream<proto::SnapshotDocument>;
+    type ReplicateStream = ResponseStream<proto::OplogBatch>;
+
+    async fn insert(
+        &self,
+        request: Request<proto::InsertRequest>,
+    ) -> Result<Response<proto::InsertResponse>, Status> {
+        // Replacing whatever is under the ID takes more than inserting.
+        let (access, event) = match request.get_ref().id.as_str() {
+            "" => (
+                Access::Insert,
+                AuditEvent::new("insert", &request.get_ref().collection),
+            ),
+            id if request.get_ref().if_absent => (
+                Access::Insert,
+                AuditEvent::new("insert", &request.get_ref().collection).with_id(id),
+            ),
+            id => (
+                Access::Write,
+                AuditEvent::new("put", &request.get_ref().collection).with_id(id),
+            ),
+        };
+        let tenant = self.authorize(&request, access, event).await?;
+        tenant.check_quota().await.map_err(to_status)?;
+        let request = request.into_inner();
+        let doc = decode(&request.document)?;
+        let id = if request.id.is_empty() {
+            trace::stage("db", tenant.db().insert_one(request.collection, doc)).await
+        } else if request.if_absent {
+            let insert =
+                tenant
+                    .db()
+                    .insert_one_with_id(request.collection, request.id.clone(), doc);
+            trace::stage("db", insert).await.map(|()| request.id)
+        } else {
+            let put = tenant.db().put(request.collection, request.id.clone(), doc);
+            trace::stage("db", put).await.map(|()| request.id)
+        }
+        .map_err(to_status)?;
+
+        Ok(Response::new(proto::InsertResponse { id }))
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::GetResponse>, Status> {
+        let event =
+            AuditEvent::new("find", &request.get_ref().collection).with_id(&request.get_ref().id);
+        let tenant = self.authorize(&request, Access::Read, event).await?;
+        let request = request.into_inner();
+        let find = tenant.db().find_one(request.collection, request.id.clone());
+        let doc = trace::stage("db", find).await.map_err(to_status)?;
+
+        let document = match doc {
+            Some(doc) => Some(proto::Document {
+                id: request.id,
+                bson: encode(&doc)?,
+            }),
+            None => None,
+        };
+        Ok(Response::new(proto::GetResponse { document }))
+    }
+
+    async fn find(
+        &self,
+        request: Request<proto::FindRequest>,
+    ) -> Result<Response<proto::FindResponse>, Status> {
+        let query = decode(&request.get_ref().query)?;
+        let event =
+            AuditEvent::new("find", &request.get_ref().collection).with_filter(query.clone());
+        let tenant = self.authorize(&request, Access::Read, event).await?;
+        let request = request.into_inner();
+
+        let documents = find_with_ids(tenant.db(), request.collection, query)
+            .await?
+            .into_iter()
+            .map(|(id, doc)| {
+                Ok(proto::Document {
+                    id,
+                    bson: encode(&doc)?,
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+
+        Ok(Response::new(proto::FindResponse { documents }))
+    }
+
+    /// Matches are encoded as the client takes them, so a slow client holds
+    /// up the scan's results rather than piling up encoded messages.
+    async fn find_stream(
+        &self,
+        request: Request<proto::FindRequest>,
+    ) -> Result<Response<Self::FindStreamStream>, Status> {
+        let query = decode(&request.get_ref().query)?;
+        let event =
+            AuditEvent::new("find", &request.get_ref().collection).with_filter(query.clone());
+        let tenant = self.authorize(&request, Access::Read, event).await?;
+        let request = request.into_inner();
+        let docs = find_with_ids(tenant.db(), request.collection, query).await?;
+
+        let stream = tokio_stream::iter(docs).map(|(id, doc)| {
+            Ok(proto::Document {
+                id,
+                bson: encode(&doc)?,
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn update(
+        &self,
+        request: Request<proto::UpdateRequest>,
+    ) -> Result<Response<proto::UpdateResponse>, Status> {
+        let event =
+            AuditEvent::new("update", &request.get_ref().collection).with_id(&request.get_ref().id);
+        let tenant = self.authorize(&request, Access::Write, event).await?;
+        tenant.check_quota().await.map_err(to_status)?;
+        let request = request.into_inner();
+        let update = decode(&request.update)?;
+        let update = tenant
+            .db()
+            .update_one(request.collection, request.id, update);
+        let updated = trace::stage("db", update).await.map_err(to_status)?;
+
+        Ok(Response::new(proto::UpdateResponse { updated }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<proto::DeleteResponse>, Status> {
+        let event =
+            AuditEvent::new("delete", &request.get_ref().collection).with_id(&request.get_ref().id);
+        let tenant = self.authorize(&request, Access::Write, event).await?;
+        let request = request.into_inner();
+        trace::stage("db", tenant.db().delete_one(request.collection, request.id))
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::DeleteResponse {}))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<Streaming<proto::WatchRequest>>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let (tenant, principal) = self.authenticate(&request)?;
+        let tenant = tenant.clone();
+        let mut changes = tenant.db().subscribe_changes();
+        let mut requests = request.into_inner();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            // An empty name stands for every collection.
+            let mut subscriptions: HashSet<String> = HashSet::new();
+
+            loop {
+                tokio::select! {
+                    request = requests.next() => match request {
+                        Some(Ok(request)) => {
+                            if request.unsubscribe {
+                                subscriptions.remove(&request.collection);
+                                continue;
+                            }
+                            let event = AuditEvent::new("watch", &request.collection);
+                            if let Err(e) = tenant.audit(&principal, event).await {
+                                let _ = sender.send(Err(to_status(e))).await;
+                                break;
+                            }
+                            subscriptions.insert(request.collection);
+                        }
+                        Some(Err(e)) => {
+                            warn!("Watch request stream failed: {}", e);
+                            break;
+                        }
+                        None => break,
+                    },
+                    event = changes.recv() => {
+                        let event = match event {
+                            Ok(event) => event,
+                            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                let _ = sender
+                                    .send(Err(Status::data_loss(format!(
+                                        "fell behind and missed {} changes",
+                                        missed
+                                    ))))
+                                    .await;
+                                break;
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        };
+                        if !subscriptions.contains("")
+                            && !subscriptions.contains(&event.collection)
+                        {
+                            continue;
+                        }
+                        if tenant
+                            .auth()
+                            .authorize(&principal, &event.collection, Access::Read)
+                            .is_err()
+                        {
+                            continue;
+                        }
+                        if sender.send(to_event(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    async fn login(
+        &self,
+        request: Request<proto::LoginRequest>,
+    ) -> Result<Response<proto::LoginResponse>, Status> {
+        let tenant = self.tenant(&request)?;
+        self.check_rate(&request, tenant, None)?;
+        let request = request.into_inner();
+        let token = tenant
+            .auth()
+            .login(&request.username, &request.password)
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::LoginResponse { token }))
+    }
+
+    async fn commit(
+        &self,
+        request: Request<proto::CommitRequest>,
+    ) -> Result<Response<proto::CommitResponse>, Status> {
+        let (tenant, principal) = self.authenticate(&request)?;
+        let mut ops = Vec::new();
+        let mut events = Vec::new();
+
+        for write in request.into_inner().writes {
+            let access = match proto::Operation::try_from(write.operation) {
+                Ok(proto::Operation::Insert) => Access::Insert,
+                _ => Access::Write,
+            };
+            tenant
+                .auth()
+                .authorize(&principal, &write.collection, access)
+                .map_err(to_status)?;
+
+            let (op, event) = match proto::Operation::try_from(write.operation) {
+                Ok(proto::Operation::Insert) => (
+                    WriteOp::Insert(decode(&write.document)?),
+                    AuditEvent::new("insert", &write.collection),
+                ),
+                Ok(proto::Operation::Update) => (
+                    WriteOp::Update {
+                        id: write.id.clone(),
+                        update: decode(&write.document)?,
+                    },
+                    AuditEvent::new("update", &write.collection).with_id(write.id),
+                ),
+                Ok(proto::Operation::Delete) => (
+                    WriteOp::Delete {
+                        id: write.id.clone(),
+                    },
+                    AuditEvent::new("delete", &write.collection).with_id(write.id),
+                ),
+                Ok(proto::Operation::Expire) | Err(_) => {
+                    return Err(Status::invalid_argument("unknown write operation"))
+                }
+            };
+            ops.push((write.collection, op));
+            events.push(event);
+        }
+        // Only once every write is known to be allowed.
+        for event in events {
+            tenant.audit(&principal, event).await.map_err(to_status)?;
+        }
+
+        // Deletes alone are let through a full quota, since they free space.
+        if ops
+            .iter()
+            .any(|(_, op)| !matches!(op, WriteOp::Delete { .. }))
+        {
+            tenant.check_quota().await.map_err(to_status)?;
+        }
+        let result = trace::stage("db", tenant.db().apply_batch(ops))
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::CommitResponse {
+            inserted_ids: result.inserted_ids,
+            updated: result.updated as u64,
+            deleted: result.deleted as u64,
+        }))
+    }
+
+    /// Reads the oplog a batch at a time, waiting for writes once caught
+    /// up, until the client goes away.
+    async fn tail(
+        &self,
+        request: Request<proto::TailRequest>,
+    ) -> Result<Response<Self::TailStream>, Status> {
+        let tenant = self.authenticate_admin(&request, "tail").await?;
+        let db = tenant.db().clone();
+        let from = match request.into_inner().from {
+            0 => db.oplog_range().await.map_err(to_status)?.start,
+            from => from,
+        };
+        // Fails the call itself when the position isn't held.
+        let mut cursor = db.oplog_since(from - 1).await.map_err(to_status)?;
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let entry = tokio::select! {
+                    entry = cursor.next() => entry,
+                    _ = sender.closed() => return,
+                };
+                let message = entry.map_err(to_status).and_then(to_oplog_entry);
+                let failed = message.is_err();
+                if sender.send(message).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    /// The position is taken before reading, so writes made while copying
+    /// are tailed again afterwards; replaying them is harmless.
+    async fn snapshot(
+        &self,
+        request: Request<proto::SnapshotRequest>,
+    ) -> Result<Response<Self::SnapshotStream>, Status> {
+        let tenant = self.authenticate_admin(&request, "snapshot").await?;
+        let db = tenant.db().clone();
+        let position = db.oplog_range().await.map_err(to_status)?.end;
+        // Buffered writes to new collections aren't listed until flushed.
+        db.flush().await.map_err(to_status)?;
+        let collections = db.collections().await.map_err(to_status)?;
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            for collection in collections {
+                let docs = match find_with_ids(&db, collection.clone(), bson::Document::new()).await
+                {
+                    Ok(docs) => docs,
+                    Err(status) => {
+                        let _ = sender.send(Err(status)).await;
+                        return;
+                    }
+                };
+                for (id, doc) in docs {
+                    let message = encode(&doc).map(|document| proto::SnapshotDocument {
+                        collection: collection.clone(),
+                        id,
+                        document,
+                    });
+                    if sender.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        let mut response =
+            Response::new(Box::pin(ReceiverStream::new(receiver)) as Self::SnapshotStream);
+        response
+            .metadata_mut()
+            .insert(OPLOG_POSITION_METADATA_KEY, MetadataValue::from(position));
+        Ok(response)
+    }
+
+    /// Memory held for a follower is bounded by its window: entries are
+    /// read from the oplog only as the follower makes room for them.
+    async fn replicate(
+        &self,
+        request: Request<Streaming<proto::ReplicateRequest>>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        let tenant = self.authenticate_admin(&request, "replicate").await?;
+        let db = tenant.db().clone();
+        let remote = request.remote_addr();
+        let mut requests = request.into_inner();
+        let first = requests
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("no replicate request"))?;
+        let from = match first.from {
+            0 => db.oplog_range().await.map_err(to_status)?.start,
+            from => from,
+        };
+        let batch_size = match first.batch_size {
+            0 => REPLICATE_BATCH,
+            batch_size => (batch_size as usize).min(MAX_REPLICATE_BATCH),
+        };
+        let window = match first.window {
+            0 => REPLICATE_WINDOW,
+            window => (window as u64).min(MAX_REPLICATE_WINDOW),
+        };
+        // Fails the call itself when the position isn't held.
+        let mut cursor = match first.follower.as_str() {
+            "" => db.oplog_since(from - 1).await,
+            follower => db.handoff(follower, from - 1).await,
+        }
+        .map_err(to_status)?;
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let name = match (first.follower.as_str(), remote) {
+            ("", Some(remote)) => remote.to_string(),
+            ("", None) => "unknown".to_string(),
+            (follower, _) => follower.to_string(),
+        };
+
+        tokio::spawn(async move {
+            let mut acknowledged = first.acknowledged.max(from - 1);
+            db.track_follower(&name, acknowledged);
+            let replicated = async {
+                loop {
+                    let room = window.saturating_sub(cursor.position() - acknowledged);
+                    let batch = tokio::select! {
+                        batch = cursor.next_batch(batch_size.min(room as usize)), if room > 0 => batch,
+                        request = requests.next() => match request {
+                            Some(Ok(request)) => {
+                                acknowledged = acknowledged.max(request.acknowledged);
+                                db.track_follower(&name, acknowledged);
+                                continue;
+                            }
+                            Some(Err(e)) => {
+                                warn!("Replicate request stream failed: {}", e);
+                                return;
+                            }
+                            None => return,
+                        },
+                        _ = sender.closed() => return,
+                    };
+                    let message = batch.map_err(to_status).and_then(|entries| {
+                        Ok(proto::OplogBatch {
+                            entries: entries
+                                .into_iter()
+                                .map(to_oplog_entry)
+                                .collect::<Result<_, _>>()?,
+                        })
+                    });
+                    let failed = message.is_err();
+                    if sender.send(message).await.is_err() || failed {
+                        return;
+                    }
+                }
+            };
+            replicated.await;
+            db.untrack_follower(&name);
+
+            // The follower went away; hold what it misses until it's back.
+            if !first.follower.is_empty() {
+                if let Err(e) = db.hold_for(&first.follower, acknowledged).await {
+                    warn!("Can't hold oplog entries for {}: {:?}", first.follower, e);
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::proto::owl_db_client::OwlDbClient;
+    use super::*;
+    use crate::server::auth::Auth;
+
+    #[tokio::test]
+    async fn test_grpc_roundtrip_and_watch() {
+        let folder_path = "data_tests/test_grpc".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .layer(tower::layer::layer_fn(|inner| Traced { inner }))
+                .add_service(service(Tenants::single(db.clone(), Auth::disabled())))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = OwlDbClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let id = client
+            .insert(proto::InsertRequest {
+                collection: "users".to_string(),
+                document: encode(&bson::doc! { "name": "John" }).unwrap(),
+                id: String::new(),
+                if_absent: false,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .id;
+
+        let found = client
+            .get(proto::GetRequest {
+                collection: "users".to_string(),
+                id: id.clone(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .document
+            .unwrap();
+        assert_eq!(decode(&found.bson).unwrap(), bson::doc! { "name": "John" });
+
+        let mut request = Request::new(proto::GetRequest {
+            collection: "users".to_string(),
+            id: id.clone(),
+        });
+        let request_id = MetadataValue::from_static("trace-7");
+        request.metadata_mut().insert(REQUEST_ID_HEADER, request_id);
+        let response = client.get(request).await.unwrap();
+        assert_eq!(
+            response.metadata().get(REQUEST_ID_HEADER).unwrap(),
+            "trace-7"
+        );
+        let timing = response
+            .metadata()
+            .get(SERVER_TIMING_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(timing.contains("db;dur="), "{}", timing);
+
+        let mut stream = client
+            .find_stream(proto::FindRequest {
+                collection: "users".to_string(),
+                query: encode(&bson::doc! {}).unwrap(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut streamed = Vec::new();
+        while let Some(doc) = stream.message().await.unwrap() {
+            streamed.push(doc.id);
+        }
+        assert_eq!(streamed, vec![id.clone()]);
+
+        let outside = client
+            .get(proto::GetRequest {
+                collection: "../users".to_string(),
+                id: id.clone(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(outside.code(), tonic::Code::InvalidArgument);
+        let outside = client
+            .insert(proto::InsertRequest {
+                collection: "users".to_string(),
+                document: encode(&bson::doc! { "name": "Eve" }).unwrap(),
+                id: "../../escaped".to_string(),
+                if_absent: false,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(outside.code(), tonic::Code::InvalidArgument);
+
+        let taken = client
+            .insert(proto::InsertRequest {
+                collection: "users".to_string(),
+                document: encode(&bson::doc! { "name": "Eve" }).unwrap(),
+                id: id.clone(),
+                if_absent: true,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(taken.code(), tonic::Code::AlreadyExists);
+        let found = db.find_one("users".to_string(), id.clone()).await.unwrap();
+        assert_eq!(found, Some(bson::doc! { "name": "John" }));
+
+        let subscribe = proto::WatchRequest {
+            collection: "users".to_string(),
+            unsubscribe: false,
+        };
+        let mut events = client
+            .watch(tokio_stream::once(subscribe).chain(tokio_stream::pending()))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // The subscription races the write, so write until it is seen.
+        let event = loop {
+            db.update_one(
+                "users".to_string(),
+                id.clone(),
+                bson::doc! { "$set": { "name": "Johnny" } },
+            )
+            .await
+            .unwrap();
+            if let Ok(event) =
+                tokio::time::timeout(Duration::from_millis(100), events.message()).await
+            {
+                break event.unwrap().unwrap();
+            }
+        };
+        assert_eq!(event.operation, proto::Operation::Update as i32);
+        assert_eq!(event.id, id);
+        assert_eq!(
+            decode(&event.document).unwrap(),
+            bson::doc! { "name": "Johnny" }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tail_oplog() {
+        let folder_path = "data_tests/test_grpc_tail".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = db::DatabaseOptions {
+            oplog: Some(db::OplogOptions::default()),
+            ..db::DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service(Tenants::single(db.clone(), Auth::disabled())))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = OwlDbClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let mut entries = client
+            .tail(proto::TailRequest { from: 0 })
+            .await
+            .unwrap()
+            .into_inner();
+        let entry = entries.message().await.unwrap().unwrap();
+        assert_eq!((entry.position, entry.id.as_str()), (1, id.as_str()));
+        assert_eq!(entry.operation, proto::Operation::Insert as i32);
+
+        // Writes made after catching up are streamed as they happen.
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+        let entry = entries.message().await.unwrap().unwrap();
+        assert_eq!(entry.position, 2);
+        assert_eq!(entry.operation, proto::Operation::Delete as i32);
+        assert!(entry.document.is_empty());
+
+        let status = client
+            .tail(proto::TailRequest { from: 10 })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+    }
+}
@@ -0,0 +1,2251 @@
+//! REST interface over a database. Documents travel as (relaxed) Extended
+//! JSON, so types JSON lacks, like dates and ObjectIds, survive the trip:
+//!
+//! - `POST /db/{collection}` inserts the body, answering `{"_id": ...}`.
+//! - `GET /db/{collection}/{id}` reads a document.
+//! - `PATCH /db/{collection}/{id}` applies the body as an update.
+//! - `DELETE /db/{collection}/{id}` deletes a document.
+//! - `POST /db/{collection}/_find` answers the documents matching the body.
+//!   With a `batch_size` query parameter, it answers `{"documents",
+//!   "cursor"}` instead: the first batch, and while more are left, the ID
+//!   of a cursor to fetch them from (see `owldb::server::cursors`).
+//! - `GET /_cursors/{id}` answers the cursor's next batch the same way,
+//!   also taking a `batch_size`, and `DELETE /_cursors/{id}` closes it.
+//! - `POST /db/{collection}/_bulk` carries out the operations in the body,
+//!   one JSON object per line: `{"op": "insert", "doc": {...}}`, `{"op":
+//!   "update", "_id": ..., "update": {...}}` or `{"op": "delete", "_id":
+//!   ...}`. Each is carried out on its own, in order; the answer has a
+//!   `status` for each in `items`, and `errors` tells whether any failed.
+//! - `GET /db/{collection}/_changes` upgrades to a WebSocket pushing the
+//!   collection's changes, optionally only those matching the `filter`
+//!   query parameter. Updates list the fields they changed in `changes`,
+//!   each with its `path` and `old` and `new` values.
+//!
+//! Documents read back carry their id in `_id`. With the `graphql` feature,
+//! `POST /graphql` answers GraphQL queries; see `owldb::server::graphql`.
+//!
+//! A server with several tenants (see `owldb::server::tenants`) serves the
+//! default one at these paths, and each one under `/databases/{name}` too,
+//! as in `POST /databases/acme/db/users`.
+//!
+//! Every response carries the request's `x-request-id`, the client's own or
+//! a new one, and how long each stage of serving it took in
+//! `Server-Timing`; errors carry the ID in `request_id` too. See
+//! `owldb::server::trace`.
+//!
+//! Request and response bodies may be compressed with the compressors the
+//! listener offers, named in `Content-Encoding` and `Accept-Encoding` (see
+//! `owldb::server::compression`).
+//!
+//! For single-page apps talking to the server directly, the listener can
+//! answer plain JSON instead (see `JsonMode`), and allow cross-origin
+//! requests from a list of origins.
+//!
+//! For orchestrators and dashboards:
+//!
+//! - `GET /healthz` answers 200 while the process serves requests.
+//! - `GET /readyz` answers 200 while the database folder is reachable, 503
+//!   otherwise.
+//! - `GET /stats` reports the collections' document counts and sizes, disk
+//!   usage, write-ahead log backlog and, with an oplog, how far back it
+//!   reaches and how far behind each follower is. It needs an admin when
+//!   authentication is enabled.
+//!
+//! A server running a `Cluster` (see `owldb::server::cluster`) lets admins
+//! manage its default tenant's replication:
+//!
+//! - `GET /_cluster` answers this server's `node` and `role`, and the
+//!   `members` it follows with how each link is faring, including a
+//!   follower's initial copy progress.
+//! - `POST /_cluster/nodes` starts following the primary at `{"uri"}`, and
+//!   `DELETE /_cluster/nodes` stops.
+//! - `POST /_cluster/failover` has a follower take writes itself.
+//!
+//! With authentication enabled, requests carry `Authorization: Bearer
+//! <token>` (or, for WebSockets, an `access_token` query parameter), and:
+//!
+//! - `POST /_auth/login` trades `{"username", "password"}` for `{"token"}`.
+//! - `POST /_auth/logout` ends the session of the request's token.
+//! - `POST /_auth/bootstrap` creates the first admin from `{"username",
+//!   "password"}`, authorized by the bootstrap token instead of a session.
+//! - `GET /_auth/users` lists the users and their roles.
+//! - `POST /_auth/users` creates a user from `{"username", "password",
+//!   "roles"}`, and `DELETE /_auth/users/{username}` removes one.
+//! - `POST /_auth/users/{username}/grant` and `.../revoke` add or remove a
+//!   `{"role", "collection"}` grant; leave out `collection` for the whole
+//!   database.
+//! - `GET /_auth/keys` lists the API keys and their roles.
+//! - `POST /_auth/keys` creates an API key from `{"name", "roles"}`,
+//!   answering `{"name", "key"}`; the key can't be read back later. `DELETE
+//!   /_auth/keys/{name}` revokes one.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, Query, Request, State};
+use axum::http::header::{
+    ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION, CONTENT_ENCODING,
+    CONTENT_LENGTH, ORIGIN, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::serve::{IncomingStream, Listener};
+use axum::{Json, Router};
+use base64::Engine;
+use bson::{Bson, Document};
+use futures_util::StreamExt;
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tower::limit::ConcurrencyLimit;
+use tower::Service;
+
+use crate::db::{ChangeEvent, ChangeStream, Database, DatabaseError, OperationType};
+use crate::server::audit::AuditEvent;
+use crate::server::auth::{self, Auth, Principal};
+#[cfg(feature = "client")]
+use crate::server::cluster::Cluster;
+use crate::server::compression::{self, Compressor, MIN_COMPRESSED_SIZE};
+use crate::server::cursors::{Batch, DEFAULT_BATCH_SIZE};
+use crate::server::limits::{client_key, LimitedListener, Limits};
+use crate::server::roles::{Access, Grant};
+use crate::server::tenants::{Tenant, TenantOptions, Tenants, DEFAULT_TENANT};
+#[cfg(feature = "tls")]
+use crate::server::tls::TlsListener;
+use crate::server::trace::{self, RequestTrace, REQUEST_ID_HEADER, SERVER_TIMING_HEADER};
+
+/// How documents are written in responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonMode {
+    /// Relaxed Extended JSON, which keeps every BSON type.
+    #[default]
+    Extended,
+    /// Plain JSON, easier on browsers: ObjectIds as their hex, dates as RFC
+    /// 3339 strings, decimals as strings and binary data as base64.
+    /// Clients can't tell these from strings.
+    Plain,
+}
+
+impl JsonMode {
+    pub fn render(self, value: Bson) -> Value {
+        match self {
+            JsonMode::Extended => value.into_relaxed_extjson(),
+            JsonMode::Plain => plain_json(value),
+        }
+    }
+}
+
+fn plain_json(value: Bson) -> Value {
+    match value {
+        Bson::Document(doc) => Value::Object(
+            doc.into_iter()
+                .map(|(key, value)| (key, plain_json(value)))
+                .collect(),
+        ),
+        Bson::Array(values) => Value::Array(values.into_iter().map(plain_json).collect()),
+        Bson::ObjectId(id) => Value::String(id.to_hex()),
+        Bson::DateTime(date) => match date.try_to_rfc3339_string() {
+            Ok(date) => Value::String(date),
+            Err(_) => json!(date.timestamp_millis()),
+        },
+        Bson::Decimal128(decimal) => Value::String(decimal.to_string()),
+        Bson::Binary(binary) => {
+            Value::String(base64::engine::general_purpose::STANDARD.encode(binary.bytes))
+        }
+        Bson::Symbol(text) | Bson::JavaScriptCode(text) => Value::String(text),
+        value => value.into_relaxed_extjson(),
+    }
+}
+
+/// How a listener talks to its clients, past what its `Limits` cover.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// Offered for request and response bodies, in order of preference.
+    pub compressors: Vec<Compressor>,
+    pub json: JsonMode,
+    /// Origins browsers may make cross-origin requests from, such as
+    /// `http://localhost:5173`, or `*` for any; none when empty.
+    pub cors_origins: Vec<String>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    tenant: Tenant,
+    json: JsonMode,
+}
+
+impl AppState {
+    fn db(&self) -> &Database {
+        self.tenant.db()
+    }
+
+    fn auth(&self) -> &Auth {
+        self.tenant.auth()
+    }
+}
+
+/// Serves one database, without tenants.
+pub fn router(db: Database, auth: Auth) -> Router {
+    traced(tenant_router(
+        Tenant::new(DEFAULT_TENANT, db, auth, TenantOptions::default()),
+        JsonMode::default(),
+    ))
+}
+
+/// Serves the default tenant at the root, and every tenant, the default
+/// included, under `/databases/{name}`.
+pub fn tenants_router(tenants: &Tenants) -> Router {
+    traced(with_tenants(tenants, |tenant| {
+        tenant_router(tenant.clone(), JsonMode::default())
+    }))
+}
+
+fn with_tenants(tenants: &Tenants, route: impl Fn(&Tenant) -> Router) -> Router {
+    let mut router = route(tenants.default_tenant());
+    for tenant in tenants.iter() {
+        router = router.nest(&format!("/databases/{}", tenant.name()), route(tenant));
+    }
+    #[cfg(feature = "client")]
+    if let Some(cluster) = tenants.cluster() {
+        router = router.merge(cluster_router(
+            tenants.default_tenant().clone(),
+            cluster.clone(),
+        ));
+    }
+    router
+}
+
+/// Admins of the default tenant manage its replication under `/_cluster`.
+#[cfg(feature = "client")]
+fn cluster_router(tenant: Tenant, cluster: Cluster) -> Router {
+    Router::new()
+        .route("/_cluster", get(topology))
+        .route("/_cluster/nodes", post(add_node).delete(remove_node))
+        .route("/_cluster/failover", post(failover))
+        .with_state(ClusterState { tenant, cluster })
+}
+
+fn tenant_router(tenant: Tenant, json: JsonMode) -> Router {
+    #[cfg(feature = "graphql")]
+    let graphql = crate::server::graphql::router(tenant.clone());
+
+    let router = Router::new()
+        .route("/db/{collection}", post(insert))
+        .route("/db/{collection}/_find", post(find))
+        .route("/db/{collection}/_bulk", post(bulk))
+        .route("/_cursors/{id}", get(next_batch).delete(kill_cursor))
+        .route("/db/{collection}/_changes", get(changes))
+        .route(
+            "/db/{collection}/{id}",
+            get(find_one).patch(update).delete(delete_one),
+        )
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/stats", get(stats))
+        .route("/_auth/login", post(login))
+        .route("/_auth/logout", post(logout))
+        .route("/_auth/bootstrap", post(bootstrap))
+        .route("/_auth/users", get(list_users).post(create_user))
+        .route("/_auth/users/{username}", delete(delete_user))
+        .route("/_auth/users/{username}/grant", post(grant))
+        .route("/_auth/users/{username}/revoke", post(revoke))
+        .route("/_auth/keys", get(list_api_keys).post(create_api_key))
+        .route("/_auth/keys/{name}", delete(delete_api_key))
+        .with_state(AppState { tenant, json });
+
+    #[cfg(feature = "graphql")]
+    let router = router.merge(graphql);
+    router
+}
+
+/// Serves the tenants on `addr` until `limits` are shut down.
+pub async fn serve(
+    tenants: Tenants,
+    addr: SocketAddr,
+    limits: Limits,
+    options: HttpOptions,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Listening on http://{}", listener.local_addr()?);
+
+    serve_with_limits(listener, tenants, limits, options).await
+}
+
+/// Like `serve`, for clients connecting over TLS.
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    tenants: Tenants,
+    listener: TlsListener,
+    limits: Limits,
+    options: HttpOptions,
+) -> std::io::Result<()> {
+    info!("Listening on https://{}", listener.local_addr());
+
+    serve_with_limits(listener, tenants, limits, options).await
+}
+
+async fn serve_with_limits<L>(
+    listener: L,
+    tenants: Tenants,
+    limits: Limits,
+    options: HttpOptions,
+) -> std::io::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+    L::Io: Unpin,
+{
+    let max_request_size = limits.options().max_request_size;
+    let router = with_tenants(&tenants, |tenant| {
+        tenant_router(tenant.clone(), options.json).layer(middleware::from_fn_with_state(
+            (tenant.clone(), limits.clone()),
+            rate_limit,
+        ))
+    });
+    let router = compressed(router, options.compressors, max_request_size);
+    let router =
+        cors(traced(router), options.cors_origins).layer(DefaultBodyLimit::max(max_request_size));
+    let connections = PerConnection {
+        router,
+        max_in_flight: limits.options().max_in_flight,
+    };
+
+    let shutdown = limits.clone();
+    axum::serve(LimitedListener::new(listener, limits), connections)
+        .with_graceful_shutdown(async move { shutdown.shutting_down().await })
+        .await
+}
+
+fn traced(router: Router) -> Router {
+    router.layer(middleware::from_fn(trace_request))
+}
+
+/// Serves the request as the current one of its task, then gives the
+/// response its ID and timings and logs it. Probes aren't logged.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let trace = RequestTrace::new(id);
+    let operation = format!("{} {}", request.method(), request.uri().path());
+
+    let mut response = trace.clone().scope(next.run(request)).await;
+
+    let headers = response.headers_mut();
+    for (name, value) in [
+        (REQUEST_ID_HEADER, trace.id().to_string()),
+        (SERVER_TIMING_HEADER, trace.server_timing()),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    if !matches!(operation.as_str(), "GET /healthz" | "GET /readyz") {
+        trace.finish(&operation, response.status());
+    }
+    response
+}
+
+/// Lets browsers on `origins` make cross-origin requests, answering their
+/// preflight requests before they reach the routes.
+fn cors(router: Router, origins: Vec<String>) -> Router {
+    if origins.is_empty() {
+        return router;
+    }
+    router.layer(middleware::from_fn_with_state(
+        Arc::<[String]>::from(origins),
+        allow_origin,
+    ))
+}
+
+/// Requests from other origins go through unchanged, and the browser keeps
+/// their responses from the page.
+async fn allow_origin(
+    State(origins): State<Arc<[String]>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(origin) = request.headers().get(ORIGIN).cloned() else {
+        return next.run(request).await;
+    };
+    let allowed = origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes());
+    if !allowed {
+        return next.run(request).await;
+    }
+
+    let preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = match preflight {
+        true => {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            let headers = response.headers_mut();
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, POST, PATCH, DELETE"),
+            );
+            if let Some(requested) = request.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+            response
+        }
+        false => next.run(request).await,
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(VARY, HeaderValue::from_static("origin"));
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("x-request-id, server-timing"),
+    );
+    response
+}
+
+/// Decodes request bodies sent with one of `compressors`, up to `max_size`
+/// bytes, and encodes responses with the first the client accepts.
+fn compressed(router: Router, compressors: Vec<Compressor>, max_size: usize) -> Router {
+    if compressors.is_empty() {
+        return router;
+    }
+    router.layer(middleware::from_fn_with_state(
+        (Arc::<[Compressor]>::from(compressors), max_size),
+        negotiate_compression,
+    ))
+}
+
+async fn negotiate_compression(
+    State((compressors, max_size)): State<(Arc<[Compressor]>, usize)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request = match decompress_body(request, &compressors, max_size).await {
+        Ok(request) => request,
+        Err(e) => return e.into_response(),
+    };
+    let accepted = request
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|names| compression::negotiate(&compressors, names));
+
+    let response = next.run(request).await;
+    match accepted {
+        Some(compressor) => compress_body(response, compressor).await,
+        None => response,
+    }
+}
+
+async fn decompress_body(
+    request: Request,
+    compressors: &[Compressor],
+    max_size: usize,
+) -> Result<Request, ApiError> {
+    let Some(encoding) = request.headers().get(CONTENT_ENCODING) else {
+        return Ok(request);
+    };
+    let compressor = encoding
+        .to_str()
+        .ok()
+        .and_then(Compressor::from_name)
+        .filter(|compressor| compressors.contains(compressor))
+        .ok_or_else(|| ApiError {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            message: format!("unsupported content encoding {:?}", encoding),
+            retry_after: None,
+        })?;
+
+    let (mut parts, body) = request.into_parts();
+    let body = to_bytes(body, max_size).await.map_err(|_| ApiError {
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        message: format!("request bodies are limited to {} bytes", max_size),
+        retry_after: None,
+    })?;
+    let body = compressor.decompress(&body, max_size)?;
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+/// WebSocket upgrades and small bodies are left as they are.
+async fn compress_body(response: Response, compressor: Compressor) -> Response {
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS
+        || response.headers().contains_key(CONTENT_ENCODING)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to read response body: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if body.len() < MIN_COMPRESSED_SIZE {
+        return Response::from_parts(parts, Body::from(body));
+    }
+
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(compressor.name()),
+    );
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(compressor.compress(&body)))
+}
+
+/// Counts the request against its client's rate. Probes are exempt, so
+/// an orchestrator polling them can't get the server marked down.
+async fn rate_limit(
+    State((tenant, limits)): State<(Tenant, Limits)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(request.uri().path(), "/healthz" | "/readyz") {
+        return next.run(request).await;
+    }
+
+    let username = bearer(request.headers())
+        .and_then(|token| tenant.auth().authenticate(Some(token)).ok())
+        .map(|principal| principal.username);
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+
+    match limits.check_rate(&client_key(tenant.name(), username.as_deref(), peer)) {
+        Ok(()) => next.run(request).await,
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
+
+/// Gives every connection its own in-flight limit; requests over it wait
+/// for earlier ones to finish.
+#[derive(Clone)]
+struct PerConnection {
+    router: Router,
+    max_in_flight: usize,
+}
+
+impl<L: Listener<Addr = SocketAddr>> Service<IncomingStream<'_, L>> for PerConnection {
+    type Response = ConcurrencyLimit<WithPeer>;
+    type Error = Infallible;
+    type Future = std::future::Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, incoming: IncomingStream<'_, L>) -> Self::Future {
+        let service = WithPeer {
+            router: self.router.clone(),
+            peer: *incoming.remote_addr(),
+        };
+        std::future::ready(Ok(ConcurrencyLimit::new(service, self.max_in_flight)))
+    }
+}
+
+/// Hands requests the connection's peer address as `ConnectInfo`.
+#[derive(Clone)]
+struct WithPeer {
+    router: Router,
+    peer: SocketAddr,
+}
+
+impl Service<Request> for WithPeer {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = <Router as Service<Request>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        <Router as Service<Request>>::poll_ready(&mut self.router, cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        request.extensions_mut().insert(ConnectInfo(self.peer));
+        self.router.call(request)
+    }
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    /// Seconds a rate-limited client should wait.
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    fn bad_request(message: String) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn not_found(message: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(e: DatabaseError) -> Self {
+        let status = match &e {
+            DatabaseError::InvalidUpdate(_)
+            | DatabaseError::InvalidQuery(_)
+            | DatabaseError::InvalidCompression(_)
+            | DatabaseError::InvalidConfig(_)
+            | DatabaseError::InvalidName(_)
+            | DatabaseError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            DatabaseError::ReadOnly | DatabaseError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            DatabaseError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            DatabaseError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            DatabaseError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            DatabaseError::CursorNotFound(_) => StatusCode::NOT_FOUND,
+            DatabaseError::DocumentExists(_) => StatusCode::CONFLICT,
+            e if e.is_transient() => StatusCode::CONFLICT,
+            _ => {
+                let id = trace::current_id().unwrap_or_default();
+                error!("[{}] Failed to serve request: {:?}", id, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        let retry_after = match &e {
+            DatabaseError::RateLimited(wait) => Some(wait.as_secs_f64().ceil() as u64),
+            _ => None,
+        };
+
+        Self {
+            status,
+            message: format!("{:?}", e),
+            retry_after,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = json!({ "error": self.message });
+        if let Some(id) = trace::current_id() {
+            body["request_id"] = Value::String(id);
+        }
+        let body = Json(body);
+        match self.status {
+            StatusCode::UNAUTHORIZED => {
+                (self.status, [(WWW_AUTHENTICATE, "Bearer")], body).into_response()
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = self.retry_after.unwrap_or(1).to_string();
+                (self.status, [(RETRY_AFTER, retry_after)], body).into_response()
+            }
+            status => (status, body).into_response(),
+        }
+    }
+}
+
+/// Parses a request body holding one Extended JSON document.
+fn parse_document(body: &[u8]) -> Result<Document, ApiError> {
+    let value: Value = serde_json::from_slice(body)
+        .map_err(|e| ApiError::bad_request(format!("invalid JSON: {}", e)))?;
+
+    match Bson::try_from(value) {
+        Ok(Bson::Document(doc)) => Ok(doc),
+        Ok(_) => Err(ApiError::bad_request(
+            "body must be a JSON object".to_string(),
+        )),
+        Err(e) => Err(ApiError::bad_request(format!(
+            "invalid Extended JSON: {}",
+            e
+        ))),
+    }
+}
+
+fn to_json(json: JsonMode, id: String, doc: Document) -> Value {
+    json.render(Bson::Document(with_id(id, doc)))
+}
+
+fn with_id(id: String, doc: Document) -> Document {
+    let mut with_id = bson::doc! { "_id": id };
+    with_id.extend(doc);
+    with_id
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(auth::bearer)
+}
+
+/// Authenticates the request and checks it may use `collection`.
+fn authorize(
+    state: &AppState,
+    headers: &HeaderMap,
+    collection: &str,
+    access: Access,
+) -> Result<Principal, ApiError> {
+    trace::stage_sync("auth", || {
+        let principal = state.auth().authenticate(bearer(headers))?;
+        state.auth().authorize(&principal, collection, access)?;
+        Ok(principal)
+    })
+}
+
+fn credentials(body: &[u8]) -> Result<(String, String), ApiError> {
+    let doc = parse_document(body)?;
+    match (doc.get_str("username"), doc.get_str("password")) {
+        (Ok(username), Ok(password)) => Ok((username.to_string(), password.to_string())),
+        _ => Err(ApiError::bad_request(
+            "username and password must be strings".to_string(),
+        )),
+    }
+}
+
+async fn insert(
+    State(state): State<AppState>,
+    Path(collection): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let principal = authorize(&state, &headers, &collection, Access::Insert)?;
+    state.tenant.check_quota().await?;
+    let doc = parse_document(&body)?;
+    state
+        .tenant
+        .audit(&principal, AuditEvent::new("insert", &collection))
+        .await?;
+    let id = trace::stage("db", state.db().insert_one(collection, doc)).await?;
+
+    Ok((StatusCode::CREATED, Json(json!({ "_id": id }))))
+}
+
+async fn find_one(
+    State(state): State<AppState>,
+    Path((collection, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let principal = authorize(&state, &headers, &collection, Access::Read)?;
+    state
+        .tenant
+        .audit(
+            &principal,
+            AuditEvent::new("find", &collection).with_id(&id),
+        )
+        .await?;
+
+    match trace::stage("db", state.db().find_one(collection, id.clone())).await? {
+        Some(doc) => Ok(Json(to_json(state.json, id, doc))),
+        None => Err(ApiError::not_found("document not found")),
+    }
+}
+
+async fn update(
+    State(state): State<AppState>,
+    Path((collection, id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&state, &headers, &collection, Access::Write)?;
+    state.tenant.check_quota().await?;
+    let update = parse_document(&body)?;
+    state
+        .tenant
+        .audit(
+            &principal,
+            AuditEvent::new("update", &collection).with_id(&id),
+        )
+        .await?;
+
+    match trace::stage("db", state.db().update_one(collection, id, update)).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found("document not found")),
+    }
+}
+
+/// Deleting a document that doesn't exist succeeds too.
+async fn delete_one(
+    State(state): State<AppState>,
+    Path((collection, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&state, &headers, &collection, Access::Write)?;
+    state
+        .tenant
+        .audit(
+            &principal,
+            AuditEvent::new("delete", &collection).with_id(&id),
+        )
+        .await?;
+    trace::stage("db", state.db().delete_one(collection, id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find(
+    State(state): State<AppState>,
+    Path(collection): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let principal = authorize(&state, &headers, &collection, Access::Read)?;
+    let query = parse_document(&body)?;
+    state
+        .tenant
+        .audit(
+            &principal,
+            AuditEvent::new("find", &collection).with_filter(query.clone()),
+        )
+        .await?;
+
+    // A collection nobody wrote to yet has no directory to scan.
+    let docs = match trace::stage("db", state.db().find_with_ids(collection.clone(), query)).await {
+        Ok(docs) => docs,
+        Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let Some(batch_size) = batch_size(&params)? else {
+        return Ok(Json(Value::Array(
+            docs.into_iter()
+                .map(|(id, doc)| to_json(state.json, id, doc))
+                .collect(),
+        )));
+    };
+    let docs = docs.into_iter().map(|(id, doc)| with_id(id, doc)).collect();
+    let batch = state
+        .tenant
+        .cursors()
+        .open(&principal.username, &collection, docs, batch_size);
+    Ok(Json(batch_json(state.json, batch)))
+}
+
+async fn next_batch(
+    State(state): State<AppState>,
+    Path(cursor_id): Path<i64>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let principal = trace::stage_sync("auth", || state.auth().authenticate(bearer(&headers)))?;
+    let cursors = state.tenant.cursors();
+    let collection = cursors.collection(cursor_id, &principal.username)?;
+    trace::stage_sync("auth", || {
+        state
+            .auth()
+            .authorize(&principal, &collection, Access::Read)
+    })?;
+
+    let batch_size = batch_size(&params)?.unwrap_or(DEFAULT_BATCH_SIZE);
+    let batch = cursors.next(cursor_id, &principal.username, batch_size)?;
+    Ok(Json(batch_json(state.json, batch)))
+}
+
+async fn kill_cursor(
+    State(state): State<AppState>,
+    Path(cursor_id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let principal = trace::stage_sync("auth", || state.auth().authenticate(bearer(&headers)))?;
+    match state.tenant.cursors().kill(cursor_id, &principal.username) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(DatabaseError::CursorNotFound(cursor_id).into()),
+    }
+}
+
+fn batch_size(params: &HashMap<String, String>) -> Result<Option<usize>, ApiError> {
+    match params.get("batch_size") {
+        Some(size) => match size.parse() {
+            Ok(size) => Ok(Some(size)),
+            Err(_) => Err(ApiError::bad_request(format!(
+                "invalid batch_size '{}'",
+                size
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// A batch of documents holding their id in `_id`, with the cursor to
+/// fetch the next from, or `null` once there are no more.
+fn batch_json(json: JsonMode, batch: Batch) -> Value {
+    let documents: Vec<Value> = batch
+        .documents
+        .into_iter()
+        .map(|doc| json.render(Bson::Document(doc)))
+        .collect();
+    let cursor = match batch.cursor_id {
+        0 => Value::Null,
+        id => json!(id),
+    };
+    json!({ "documents": documents, "cursor": cursor })
+}
+
+async fn bulk(
+    State(state): State<AppState>,
+    Path(collection): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    // Updates and deletes need more, checked as each comes.
+    let principal = authorize(&state, &headers, &collection, Access::Insert)?;
+    state.tenant.check_quota().await?;
+
+    let mut items = Vec::new();
+    for line in body.split(|b| *b == b'\n') {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let item = match bulk_item(&state, &principal, &collection, line).await {
+            Ok((status, Some(id))) => json!({ "status": status.as_u16(), "_id": id }),
+            Ok((status, None)) => json!({ "status": status.as_u16() }),
+            Err(e) => json!({ "status": e.status.as_u16(), "error": e.message }),
+        };
+        items.push(item);
+    }
+
+    let errors = items.iter().any(|item| item.get("error").is_some());
+    Ok(Json(json!({ "errors": errors, "items": items })))
+}
+
+/// Carries out one line of a bulk request, answering the status it would
+/// have had on its own and the id of an inserted document.
+async fn bulk_item(
+    state: &AppState,
+    principal: &Principal,
+    collection: &str,
+    line: &[u8],
+) -> Result<(StatusCode, Option<String>), ApiError> {
+    let mut item = parse_document(line)?;
+    let op = item.get_str("op").unwrap_or_default().to_string();
+    let id = match item.get("_id") {
+        Some(Bson::String(id)) => Some(id.clone()),
+        Some(_) => return Err(ApiError::bad_request("_id must be a string".to_string())),
+        None => None,
+    };
+    let collection = collection.to_string();
+    if op != "insert" {
+        trace::stage_sync("auth", || {
+            state
+                .auth()
+                .authorize(principal, &collection, Access::Write)
+        })?;
+    }
+
+    match (op.as_str(), id) {
+        ("insert", _) => {
+            let Some(Bson::Document(doc)) = item.remove("doc") else {
+                return Err(ApiError::bad_request("doc must be an object".to_string()));
+            };
+            state
+                .tenant
+                .audit(principal, AuditEvent::new("insert", &collection))
+                .await?;
+            let id = trace::stage("db", state.db().insert_one(collection, doc)).await?;
+            Ok((StatusCode::CREATED, Some(id)))
+        }
+        ("update", Some(id)) => {
+            let Some(Bson::Document(update)) = item.remove("update") else {
+                return Err(ApiError::bad_request(
+                    "update must be an object".to_string(),
+                ));
+            };
+            state
+                .tenant
+                .audit(
+                    principal,
+                    AuditEvent::new("update", &collection).with_id(&id),
+                )
+                .await?;
+            match trace::stage("db", state.db().update_one(collection, id, update)).await? {
+                true => Ok((StatusCode::NO_CONTENT, None)),
+                false => Err(ApiError::not_found("document not found")),
+            }
+        }
+        ("delete", Some(id)) => {
+            state
+                .tenant
+                .audit(
+                    principal,
+                    AuditEvent::new("delete", &collection).with_id(&id),
+                )
+                .await?;
+            trace::stage("db", state.db().delete_one(collection, id)).await?;
+            Ok((StatusCode::NO_CONTENT, None))
+        }
+        ("update" | "delete", None) => Err(ApiError::bad_request(format!("{} needs an _id", op))),
+        _ => Err(ApiError::bad_request(format!("unknown operation '{}'", op))),
+    }
+}
+
+/// Subscribes before upgrading, so every write made once the client sees
+/// the handshake completed reaches it. Browsers can't set headers on a
+/// WebSocket, so the token may come as the `access_token` parameter.
+async fn changes(
+    State(state): State<AppState>,
+    Path(collection): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let token = bearer(&headers).or(params.get("access_token").map(String::as_str));
+    let principal = trace::stage_sync("auth", || {
+        let principal = state.auth().authenticate(token)?;
+        state
+            .auth()
+            .authorize(&principal, &collection, Access::Read)?;
+        Ok::<_, DatabaseError>(principal)
+    })?;
+
+    let filter = match params.get("filter") {
+        Some(filter) => parse_document(filter.as_bytes())?,
+        None => Document::new(),
+    };
+    let changes = state.db().watch_filtered(&collection, filter.clone())?;
+    state
+        .tenant
+        .audit(
+            &principal,
+            AuditEvent::new("watch", &collection).with_filter(filter),
+        )
+        .await?;
+    let json = state.json;
+
+    Ok(upgrade.on_upgrade(move |socket| push_changes(socket, changes, json)))
+}
+
+/// Sends each matching change as a JSON text message until the client
+/// leaves. Deletes carry no document to match, so all of them are sent. A
+/// client that falls too far behind is disconnected with code 1013 and
+/// should subscribe again.
+async fn push_changes(mut socket: WebSocket, mut changes: ChangeStream, json: JsonMode) {
+    loop {
+        tokio::select! {
+            event = changes.next() => {
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    Some(Err(DatabaseError::ChangesMissed(missed))) => {
+                        let close = CloseFrame {
+                            code: close_code::AGAIN,
+                            reason: format!("missed {} changes", missed).into(),
+                        };
+                        let _ = socket.send(Message::Close(Some(close))).await;
+                        return;
+                    }
+                    Some(Err(_)) | None => return,
+                };
+
+                let message = change_to_json(json, event).to_string();
+                if socket.send(Message::Text(message.into())).await.is_err() {
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                // Pings are answered for us; anything else is ignored.
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+pub(crate) fn change_to_json(json: JsonMode, event: ChangeEvent) -> Value {
+    let operation = match event.operation {
+        OperationType::Insert => "insert",
+        OperationType::Update => "update",
+        OperationType::Delete => "delete",
+        OperationType::Expire => "expired",
+    };
+
+    let mut value = json!({
+        "operation": operation,
+        "collection": event.collection,
+        "_id": event.id.clone(),
+        "document": event.document.map(|doc| to_json(json, event.id, doc)),
+    });
+    if event.operation == OperationType::Update {
+        let changes: Vec<_> = event
+            .changes
+            .into_iter()
+            .map(|change| {
+                json!({
+                    "path": change.path,
+                    "old": change.old.map(|old| json.render(old)),
+                    "new": change.new.map(|new| json.render(new)),
+                })
+            })
+            .collect();
+        value["changes"] = Value::Array(changes);
+    }
+    value
+}
+
+async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    match state.db().ping().await {
+        Ok(()) => (StatusCode::OK, Json(json!({ "status": "ready" }))),
+        Err(e) => {
+            error!("Failed readiness check: {:?}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "unavailable", "error": format!("{:?}", e) })),
+            )
+        }
+    }
+}
+
+async fn stats(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<Value>, ApiError> {
+    let principal = trace::stage_sync("auth", || state.auth().authenticate(bearer(&headers)))?;
+    if !principal.is_admin() {
+        return Err(DatabaseError::PermissionDenied(
+            "only admins may read server stats".to_string(),
+        )
+        .into());
+    }
+
+    let stats = trace::stage("db", state.db().stats()).await?;
+    let collections: serde_json::Map<String, Value> = stats
+        .collections
+        .into_iter()
+        .map(|collection| {
+            (
+                collection.name,
+                json!({
+                    "documents": collection.documents,
+                    "size_bytes": collection.size_bytes,
+                    "index_bytes": collection.index_bytes,
+                }),
+            )
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "read_only": stats.read_only,
+        "collections": collections,
+        "disk_usage_bytes": stats.disk_usage_bytes,
+        "wal": {
+            "pending_writes": stats.wal_pending_writes,
+            "files": stats.wal_files,
+        },
+        "oplog": stats.oplog.map(|oplog| {
+            let followers: serde_json::Map<String, Value> = oplog
+                .followers
+                .into_iter()
+                .map(|follower| {
+                    (
+                        follower.name,
+                        json!({
+                            "acknowledged": follower.acknowledged,
+                            "lag_operations": follower.operations,
+                            "lag_secs": follower.behind.as_secs_f64(),
+                        }),
+                    )
+                })
+                .collect();
+            json!({
+                "first": oplog.first,
+                "next": oplog.next,
+                "window_secs": oplog.window.as_secs_f64(),
+                "followers": followers,
+            })
+        }),
+        "backups": stats.backups.map(|backups| {
+            json!({
+                "succeeded": backups.succeeded,
+                "failed": backups.failed,
+                "archives": backups.archives,
+                "pruned": backups.pruned,
+                "last_finished_at": backups
+                    .last_backup
+                    .and_then(|info| info.finished_at.try_to_rfc3339_string().ok()),
+                "last_failure": backups.last_failure.map(|(at, error)| {
+                    json!({
+                        "at": at.try_to_rfc3339_string().ok(),
+                        "error": error,
+                    })
+                }),
+            })
+        }),
+    })))
+}
+
+#[cfg(feature = "client")]
+#[derive(Clone)]
+struct ClusterState {
+    tenant: Tenant,
+    cluster: Cluster,
+}
+
+#[cfg(feature = "client")]
+impl ClusterState {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let principal = self.tenant.auth().authenticate(bearer(headers))?;
+        match principal.is_admin() {
+            true => Ok(()),
+            false => Err(DatabaseError::PermissionDenied(
+                "only admins may manage the cluster".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+fn node_uri(body: &[u8]) -> Result<String, ApiError> {
+    match parse_document(body)?.get_str("uri") {
+        Ok(uri) => Ok(uri.to_string()),
+        Err(_) => Err(ApiError::bad_request("uri must be a string".to_string())),
+    }
+}
+
+#[cfg(feature = "client")]
+async fn topology(
+    State(state): State<ClusterState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    state.authorize(&headers)?;
+    let topology = state.cluster.topology().await;
+    let members: Vec<Value> = topology
+        .members
+        .into_iter()
+        .map(|member| {
+            json!({
+                "uri": member.uri,
+                "role": member.role.as_str(),
+                "connected": member.health.connected,
+                "position": member.health.position,
+                "last_error": member.health.last_error,
+                "copied": member.health.copied,
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "node": topology.node,
+        "role": topology.role.as_str(),
+        "members": members,
+    })))
+}
+
+#[cfg(feature = "client")]
+async fn add_node(
+    State(state): State<ClusterState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    state.authorize(&headers)?;
+    match state.cluster.add_node(&node_uri(&body)?).await? {
+        true => Ok(StatusCode::CREATED),
+        false => Ok(StatusCode::NO_CONTENT),
+    }
+}
+
+#[cfg(feature = "client")]
+async fn remove_node(
+    State(state): State<ClusterState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    state.authorize(&headers)?;
+    match state.cluster.remove_node(&node_uri(&body)?).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Ok(StatusCode::NOT_FOUND),
+    }
+}
+
+#[cfg(feature = "client")]
+async fn failover(
+    State(state): State<ClusterState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    state.authorize(&headers)?;
+    state.cluster.failover().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn login(State(state): State<AppState>, body: Bytes) -> Result<Json<Value>, ApiError> {
+    let (username, password) = credentials(&body)?;
+    let token = state.auth().login(&username, &password).await?;
+
+    Ok(Json(json!({ "token": token })))
+}
+
+async fn logout(State(state): State<AppState>, headers: HeaderMap) -> StatusCode {
+    if let Some(token) = bearer(&headers) {
+        state.auth().logout(token);
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn bootstrap(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let (username, password) = credentials(&body)?;
+    let token = bearer(&headers).ok_or(DatabaseError::Unauthenticated)?;
+    state.auth().bootstrap(token, username, password).await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+async fn create_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+    let (username, password) = credentials(&body)?;
+    let grants = grants(&parse_document(&body)?)?;
+    state
+        .auth()
+        .create_user(&principal, username, password, grants)
+        .await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// The grants in a body's `roles`, if any.
+fn grants(body: &Document) -> Result<Vec<Grant>, ApiError> {
+    match body.get_array("roles") {
+        Ok(roles) => roles
+            .iter()
+            .map(|role| match role.as_document() {
+                Some(role) => Ok(Grant::from_document(role)?),
+                None => Err(ApiError::bad_request("roles must be objects".to_string())),
+            })
+            .collect(),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn grant_to_json(grant: &Grant) -> Value {
+    Bson::Document(grant.to_document()).into_relaxed_extjson()
+}
+
+async fn list_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+    let users = state.auth().list_users(&principal).await?;
+
+    Ok(Json(Value::Array(
+        users
+            .iter()
+            .map(|user| {
+                json!({
+                    "username": user.username,
+                    "roles": user.grants.iter().map(grant_to_json).collect::<Vec<_>>(),
+                })
+            })
+            .collect(),
+    )))
+}
+
+async fn grant(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+    let grant = Grant::from_document(&parse_document(&body)?)?;
+
+    match state.auth().grant(&principal, &username, grant).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found("user not found")),
+    }
+}
+
+async fn revoke(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+    let grant = Grant::from_document(&parse_document(&body)?)?;
+
+    match state.auth().revoke(&principal, &username, &grant).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found("user not found")),
+    }
+}
+
+async fn delete_user(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+
+    match state.auth().delete_user(&principal, &username).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found("user not found")),
+    }
+}
+
+async fn create_api_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+    let body = parse_document(&body)?;
+    let Ok(name) = body.get_str("name") else {
+        return Err(ApiError::bad_request("name must be a string".to_string()));
+    };
+    let key = state
+        .auth()
+        .create_api_key(&principal, name.to_string(), grants(&body)?)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "name": name, "key": key })),
+    ))
+}
+
+async fn list_api_keys(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+    let keys = state.auth().list_api_keys(&principal)?;
+
+    Ok(Json(Value::Array(
+        keys.iter()
+            .map(|key| {
+                json!({
+                    "name": key.name,
+                    "roles": key.grants.iter().map(grant_to_json).collect::<Vec<_>>(),
+                })
+            })
+            .collect(),
+    )))
+}
+
+async fn delete_api_key(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let principal = state.auth().authenticate(bearer(&headers))?;
+
+    match state.auth().delete_api_key(&principal, &name).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::not_found("API key not found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+    use futures_util::StreamExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn call(router: &Router, method: &str, uri: &str, body: Value) -> (StatusCode, Value) {
+        call_as(router, None, method, uri, body).await
+    }
+
+    async fn call_as(
+        router: &Router,
+        token: Option<&str>,
+        method: &str,
+        uri: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn test_rest_roundtrip() {
+        let folder_path = "data_tests/test_http".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let router = router(db, Auth::disabled());
+
+        let (status, body) = call(
+            &router,
+            "POST",
+            "/db/users",
+            json!({ "name": "John", "born": { "$date": "1990-01-01T00:00:00Z" } }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = body["_id"].as_str().unwrap().to_string();
+
+        let (status, body) = call(&router, "GET", &format!("/db/users/{}", id), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "John");
+        assert_eq!(body["born"]["$date"], "1990-01-01T00:00:00Z");
+
+        let (status, _) = call(
+            &router,
+            "PATCH",
+            &format!("/db/users/{}", id),
+            json!({ "$set": { "name": "Johnny" } }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, body) = call(
+            &router,
+            "POST",
+            "/db/users/_find",
+            json!({ "name": "Johnny" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["_id"], id.as_str());
+
+        let (status, _) = call(&router, "GET", "/healthz", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&router, "GET", "/readyz", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call(&router, "GET", "/stats", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["collections"]["users"]["documents"], 1);
+        assert_eq!(body["wal"]["pending_writes"], 0);
+
+        let (status, _) = call(&router, "DELETE", &format!("/db/users/{}", id), Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&router, "GET", &format!("/db/users/{}", id), Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/db/users/_bulk")
+            .body(Body::from(format!(
+                "{}\n{}\n\n{}\n{}\n",
+                json!({ "op": "insert", "doc": { "name": "Jane" } }),
+                json!({ "op": "update", "_id": id, "update": { "$set": { "age": 40 } } }),
+                json!({ "op": "delete", "_id": "missing" }),
+                json!({ "op": "replace", "_id": id }),
+            )))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["errors"], true);
+        let statuses: Vec<_> = body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["status"].as_u64().unwrap())
+            .collect();
+        assert_eq!(statuses, vec![201, 404, 204, 400]);
+        let jane = body["items"][0]["_id"].as_str().unwrap().to_string();
+        let (_, body) = call(&router, "GET", &format!("/db/users/{}", jane), Value::Null).await;
+        assert_eq!(body["name"], "Jane");
+
+        let (_, body) = call(&router, "POST", "/db/users/_find?batch_size=0", json!({})).await;
+        assert_eq!(body["documents"], json!([]));
+        let cursor = format!("/_cursors/{}", body["cursor"].as_i64().unwrap());
+        let (status, body) = call(
+            &router,
+            "GET",
+            &format!("{}?batch_size=5", cursor),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["documents"][0]["_id"], jane.as_str());
+        assert_eq!(body["cursor"], Value::Null);
+        let (status, _) = call(&router, "GET", &cursor, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&router, "DELETE", &cursor, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = call(&router, "POST", "/db/users", json!([1, 2])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["request_id"].as_str().unwrap().len(), 24);
+
+        let request = Request::builder()
+            .uri(format!("/db/users/{}", id))
+            .header(REQUEST_ID_HEADER, "trace-42")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "trace-42");
+        let timing = response.headers()[SERVER_TIMING_HEADER].to_str().unwrap();
+        assert!(timing.contains("auth;dur="), "{}", timing);
+        assert!(timing.contains("db;dur="), "{}", timing);
+    }
+
+    #[tokio::test]
+    async fn test_names_cannot_leave_the_folder() {
+        let folder_path = "data_tests/test_http_traversal".to_string();
+        let _ = tokio::fs::remove_dir_all("data_tests/escaped").await;
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let router = router(db, Auth::disabled());
+
+        // Axum decodes `%2F` after routing, so these reach the handlers.
+        for (method, uri) in [
+            ("POST", "/db/..%2Fescaped"),
+            ("GET", "/db/..%2Fescaped/x"),
+            ("GET", "/db/users/..%2F..%2Fescaped"),
+            ("PATCH", "/db/users/..%2F..%2Fescaped"),
+            ("DELETE", "/db/.wal/00000000000000000001"),
+            ("POST", "/db/%2E%2E/_find"),
+        ] {
+            let (status, _) = call(&router, method, uri, json!({ "name": "Eve" })).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", method, uri);
+        }
+        assert!(tokio::fs::metadata("data_tests/escaped").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_bodies() {
+        let folder_path = "data_tests/test_http_compression".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let router = compressed(
+            router(db, Auth::disabled()),
+            vec![Compressor::Snappy],
+            1 << 20,
+        );
+
+        let text = "owl ".repeat(200);
+        let body = json!({ "text": text }).to_string();
+        let request = Request::builder()
+            .method("POST")
+            .uri("/db/notes")
+            .header("content-encoding", "snappy")
+            .body(Body::from(Compressor::Snappy.compress(body.as_bytes())))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let id: Value = serde_json::from_slice(&bytes).unwrap();
+
+        let request = Request::builder()
+            .uri(format!("/db/notes/{}", id["_id"].as_str().unwrap()))
+            .header("accept-encoding", "gzip, snappy")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "snappy");
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let bytes = Compressor::Snappy.decompress(&bytes, usize::MAX).unwrap();
+        let note: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(note["text"], text.as_str());
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/db/notes")
+            .header("content-encoding", "zstd")
+            .body(Body::from(body))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_plain_json_and_cors() {
+        let folder_path = "data_tests/test_http_plain_json".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let tenant = Tenant::new(
+            DEFAULT_TENANT,
+            db,
+            Auth::disabled(),
+            TenantOptions::default(),
+        );
+        let router = cors(
+            tenant_router(tenant, JsonMode::Plain),
+            vec!["http://localhost:5173".to_string()],
+        );
+
+        let body = json!({
+            "owner": { "$oid": "64b7f2a1c3d4e5f601234567" },
+            "at": { "$date": "2023-07-19T12:00:00Z" },
+            "tags": [{ "$numberDecimal": "1.50" }],
+            "count": 3,
+        });
+        let request = Request::builder()
+            .method("POST")
+            .uri("/db/events")
+            .header("origin", "http://localhost:5173")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "http://localhost:5173"
+        );
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let id: Value = serde_json::from_slice(&bytes).unwrap();
+
+        let request = Request::builder()
+            .uri(format!("/db/events/{}", id["_id"].as_str().unwrap()))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let event: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(event["owner"], "64b7f2a1c3d4e5f601234567");
+        assert_eq!(event["at"], "2023-07-19T12:00:00Z");
+        assert_eq!(event["tags"], json!(["1.50"]));
+        assert_eq!(event["count"], 3);
+
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method("OPTIONS")
+                .uri("/db/events")
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header(
+                    "access-control-request-headers",
+                    "authorization, content-type",
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = router
+            .clone()
+            .oneshot(preflight("http://localhost:5173"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()["access-control-allow-headers"],
+            "authorization, content-type"
+        );
+        let response = router
+            .oneshot(preflight("http://evil.example"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_tenant_routes() {
+        let mut opened = Vec::new();
+        for name in ["default", "acme"] {
+            let folder_path = format!("data_tests/test_http_tenants_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            opened.push(Database::init(folder_path).await.unwrap());
+        }
+        let acme = opened.pop().unwrap();
+        let id = acme
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let tenants = Tenants::new(
+            Tenant::new(
+                DEFAULT_TENANT,
+                opened.pop().unwrap(),
+                Auth::disabled(),
+                TenantOptions::default(),
+            ),
+            vec![Tenant::new(
+                "acme",
+                acme,
+                Auth::disabled(),
+                TenantOptions {
+                    quota_bytes: Some(1),
+                    ..TenantOptions::default()
+                },
+            )],
+        );
+        let router = tenants_router(&tenants);
+
+        let (status, body) =
+            call(&router, "POST", "/databases/acme/db/users/_find", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let (_, body) = call(&router, "POST", "/db/users/_find", json!({})).await;
+        assert_eq!(body, json!([]));
+        let (status, _) = call(&router, "POST", "/databases/default/db/users", json!({})).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _) = call(&router, "POST", "/databases/acme/db/users", json!({})).await;
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+        let uri = format!("/databases/acme/db/users/{}", id);
+        let (status, _) = call(&router, "DELETE", &uri, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&router, "GET", "/databases/initech/healthz", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        #[cfg(feature = "client")]
+        {
+            use crate::server::cluster::ClusterOptions;
+
+            let db = tenants.default_tenant().db().clone();
+            tenants.set_cluster(Cluster::start(db, ClusterOptions::default()));
+            let router = tenants_router(&tenants);
+            let (_, body) = call(&router, "GET", "/_cluster", Value::Null).await;
+            assert_eq!(body["role"], "standalone");
+            let node = json!({ "uri": "http://127.0.0.1:1" });
+            let (status, _) = call(&router, "POST", "/_cluster/nodes", node).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (status, _) = call(&router, "POST", "/_cluster/failover", Value::Null).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_stream() {
+        let folder_path = "data_tests/test_http_changes".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(db.clone(), Auth::disabled());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // filter={"name":"John"}
+        let url = format!(
+            "ws://{}/db/users/_changes?filter=%7B%22name%22%3A%22John%22%7D",
+            addr
+        );
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let john = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        db.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        db.insert_one("teams".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        db.update_one(
+            "users".to_string(),
+            john.clone(),
+            bson::doc! { "$set": { "age": 30 } },
+        )
+        .await
+        .unwrap();
+        db.delete_one("users".to_string(), john.clone())
+            .await
+            .unwrap();
+
+        let expected = [
+            json!({
+                "operation": "insert",
+                "collection": "users",
+                "_id": john,
+                "document": { "_id": john, "name": "John" },
+            }),
+            json!({
+                "operation": "update",
+                "collection": "users",
+                "_id": john,
+                "document": { "_id": john, "name": "John", "age": 30 },
+                "changes": [{ "path": "age", "old": null, "new": 30 }],
+            }),
+            json!({
+                "operation": "delete",
+                "collection": "users",
+                "_id": john,
+                "document": null,
+            }),
+        ];
+        for expected in expected {
+            let message = socket.next().await.unwrap().unwrap();
+            let event: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            assert_eq!(event, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_authentication() {
+        let folder_path = "data_tests/test_http_auth".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let auth = Auth::enable(db.clone()).await.unwrap();
+        let bootstrap_token = auth.bootstrap_token().unwrap();
+        let router = router(db, auth);
+
+        let credentials = json!({ "username": "root", "password": "secret" });
+        let (status, _) = call(&router, "POST", "/_auth/bootstrap", credentials.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(
+            &router,
+            Some(&bootstrap_token),
+            "POST",
+            "/_auth/bootstrap",
+            credentials.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = call(&router, "POST", "/_auth/login", credentials).await;
+        assert_eq!(status, StatusCode::OK);
+        let token = body["token"].as_str().unwrap().to_string();
+
+        let doc = json!({ "name": "John" });
+        let (status, _) = call(&router, "POST", "/db/users", doc.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&router, Some("forged"), "POST", "/db/users", doc.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&router, Some(&token), "POST", "/db/users", doc).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) =
+            call_as(&router, Some(&token), "POST", "/db/_users/_find", json!({})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = call_as(
+            &router,
+            Some(&token),
+            "POST",
+            "/_auth/keys",
+            json!({ "name": "sensors", "roles": [{ "role": "insert", "collection": "telemetry" }] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let key = body["key"].as_str().unwrap().to_string();
+        let reading = json!({ "celsius": 21.5 });
+        let (status, _) = call_as(
+            &router,
+            Some(&key),
+            "POST",
+            "/db/telemetry",
+            reading.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = call_as(
+            &router,
+            Some(&key),
+            "POST",
+            "/db/telemetry/_find",
+            json!({}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_as(&router, Some(&key), "POST", "/db/users", reading).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // A database-wide writer still can't reach the users collection
+        // through a name that climbs back up into it.
+        let (_, body) = call_as(
+            &router,
+            Some(&token),
+            "POST",
+            "/_auth/keys",
+            json!({ "name": "writer", "roles": [{ "role": "readWrite" }] }),
+        )
+        .await;
+        let writer = body["key"].as_str().unwrap().to_string();
+        let admin = json!({ "username": "eve", "password": "pw", "roles": [{ "role": "admin" }] });
+        let (status, _) =
+            call_as(&router, Some(&writer), "POST", "/db/x%2F..%2F_users", admin).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, body) = call_as(&router, Some(&token), "GET", "/_auth/users", Value::Null).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let (_, body) = call_as(&router, Some(&token), "GET", "/_auth/keys", Value::Null).await;
+        assert_eq!(body[0]["roles"][0]["role"], "insert");
+        let (status, _) = call_as(
+            &router,
+            Some(&token),
+            "DELETE",
+            "/_auth/keys/sensors",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) =
+            call_as(&router, Some(&token), "POST", "/_auth/logout", Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) =
+            call_as(&router, Some(&token), "POST", "/db/users/_find", json!({})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    async fn call_bulk(router: &Router, token: &str, uri: &str, lines: &[String]) -> Value {
+        let request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::from(lines.join("\n")))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn statuses(body: &Value) -> Vec<u64> {
+        body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["status"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_bulk_answers_each_line() {
+        let folder_path = "data_tests/test_http_bulk".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let auth = Auth::enable(db.clone()).await.unwrap();
+        let bootstrap_token = auth.bootstrap_token().unwrap();
+        let router = router(db, auth);
+
+        let credentials = json!({ "username": "root", "password": "secret" });
+        call_as(
+            &router,
+            Some(&bootstrap_token),
+            "POST",
+            "/_auth/bootstrap",
+            credentials.clone(),
+        )
+        .await;
+        let (_, body) = call(&router, "POST", "/_auth/login", credentials).await;
+        let token = body["token"].as_str().unwrap().to_string();
+        let (_, body) = call_as(
+            &router,
+            Some(&token),
+            "POST",
+            "/_auth/keys",
+            json!({ "name": "sensors", "roles": [{ "role": "insert", "collection": "telemetry" }] }),
+        )
+        .await;
+        let key = body["key"].as_str().unwrap().to_string();
+
+        let body = call_bulk(
+            &router,
+            &key,
+            "/db/telemetry/_bulk",
+            &[
+                json!({ "op": "insert", "doc": { "celsius": 21.5 } }).to_string(),
+                json!({ "op": "insert", "doc": { "celsius": 22.0 } }).to_string(),
+            ],
+        )
+        .await;
+        assert_eq!(body["errors"], false);
+        assert_eq!(statuses(&body), vec![201, 201]);
+        let reading = body["items"][0]["_id"].as_str().unwrap().to_string();
+
+        let body = call_bulk(
+            &router,
+            &token,
+            "/db/telemetry/_bulk",
+            &[
+                json!({ "op": "update", "update": { "$set": { "celsius": 0 } } }).to_string(),
+                "{\"op\": \"insert\", \"doc\":".to_string(),
+                json!({ "op": "delete" }).to_string(),
+                json!({ "op": "update", "_id": reading, "update": { "$set": { "checked": true } } })
+                    .to_string(),
+            ],
+        )
+        .await;
+        assert_eq!(body["errors"], true);
+        assert_eq!(statuses(&body), vec![400, 400, 400, 204]);
+        assert_eq!(body["items"][0]["error"], "update needs an _id");
+        assert_eq!(body["items"][2]["error"], "delete needs an _id");
+        assert!(body["items"][3].get("error").is_none());
+
+        // The insert-only key gets its inserts through but not the delete.
+        let body = call_bulk(
+            &router,
+            &key,
+            "/db/telemetry/_bulk",
+            &[
+                json!({ "op": "delete", "_id": reading }).to_string(),
+                json!({ "op": "insert", "doc": { "celsius": 23.0 } }).to_string(),
+            ],
+        )
+        .await;
+        assert_eq!(body["errors"], true);
+        assert_eq!(statuses(&body), vec![403, 201]);
+        let (status, body) = call_as(
+            &router,
+            Some(&token),
+            "GET",
+            &format!("/db/telemetry/{}", reading),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["checked"], true);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_stats_report_replication_progress() {
+        use std::time::Duration;
+
+        use tokio_stream::wrappers::TcpListenerStream;
+
+        use crate::client::Client;
+        use crate::db::{DatabaseOptions, OplogOptions};
+        use crate::server::cluster::ClusterOptions;
+        use crate::server::grpc;
+
+        let mut opened = Vec::new();
+        for name in ["leader", "follower"] {
+            let folder_path = format!("data_tests/test_http_replication_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions::default()),
+                ..DatabaseOptions::default()
+            };
+            opened.push(
+                Database::init_with_options(folder_path, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let follower_db = opened.pop().unwrap();
+        let leader = opened.pop().unwrap();
+        for n in 0..50 {
+            leader
+                .insert_one("users".to_string(), bson::doc! { "n": n })
+                .await
+                .unwrap();
+        }
+
+        // Holds the leader's snapshots after their first frame, keeping the
+        // follower in the middle of its initial copy.
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let stall = {
+            let release = release.clone();
+            tower::util::MapResponseLayer::new(move |response: Response<tonic::body::Body>| {
+                if !response
+                    .headers()
+                    .contains_key(grpc::OPLOG_POSITION_METADATA_KEY)
+                {
+                    return response;
+                }
+                let release = release.clone();
+                response.map(|body| {
+                    let frames = http_body_util::BodyStream::new(body);
+                    let frames = futures_util::stream::unfold(
+                        (frames, 0, release),
+                        |(mut frames, sent, release)| async move {
+                            if sent == 1 {
+                                let _ = release.acquire().await.unwrap();
+                            }
+                            let frame = frames.next().await?;
+                            Some((frame, (frames, sent + 1, release)))
+                        },
+                    );
+                    tonic::body::Body::new(http_body_util::StreamBody::new(frames))
+                })
+            })
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .layer(stall)
+                .add_service(grpc::service(Tenants::single(
+                    leader.clone(),
+                    Auth::disabled(),
+                )))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let leader_uri = format!("http://{}", addr);
+
+        let tenants = Tenants::single(follower_db.clone(), Auth::disabled());
+        tenants.set_cluster(Cluster::start(
+            follower_db,
+            ClusterOptions {
+                leader: Some(leader_uri.clone()),
+                ..ClusterOptions::default()
+            },
+        ));
+        let follower = tenants_router(&tenants);
+        let mut body = Value::Null;
+        for _ in 0..100 {
+            (_, body) = call(&follower, "GET", "/_cluster", Value::Null).await;
+            if body["members"][0]["copied"].as_u64().unwrap_or(0) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(body["role"], "follower");
+        let copied = body["members"][0]["copied"].as_u64().unwrap();
+        assert!(copied > 0 && copied <= 50, "{}", copied);
+        release.add_permits(1);
+        for _ in 0..100 {
+            (_, body) = call(&follower, "GET", "/_cluster", Value::Null).await;
+            if body["members"][0]["position"].as_u64().unwrap() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(body["members"][0]["copied"], Value::Null);
+
+        // A second follower applies two entries and then falls behind.
+        let client = Client::connect(&leader_uri).await.unwrap();
+        let mut oplog = client.replicate(1, 2, 0, Some("standby")).await.unwrap();
+        oplog.next_batch().await.unwrap().unwrap();
+        oplog.acknowledge(2).await.unwrap();
+        leader
+            .insert_one("users".to_string(), bson::doc! { "n": 50 })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let router = router(leader, Auth::disabled());
+        let (status, stats) = call(&router, "GET", "/stats", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let oplog = &stats["oplog"];
+        assert_eq!(oplog["first"], 1);
+        assert_eq!(oplog["next"], 52);
+        let standby = &oplog["followers"]["standby"];
+        assert_eq!(standby["acknowledged"], 2);
+        assert_eq!(standby["lag_operations"], 49);
+        let lag = standby["lag_secs"].as_f64().unwrap();
+        assert!(lag >= 0.1, "{}", lag);
+        assert!(oplog["window_secs"].as_f64().unwrap() >= lag);
+    }
+}
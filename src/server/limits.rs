@@ -0,0 +1,354 @@
+//! Bounds on what clients may take from the server: how many connections
+//! are open at once across every front end, how many requests one
+//! connection may have in flight, how large a request may be, and how many
+//! requests a client may make per second.
+//!
+//! Connections over the limit are closed as soon as they are accepted.
+//! Requests over a connection's in-flight limit wait for an earlier one to
+//! finish; only HTTP/2 and gRPC clients can have more than one in flight,
+//! since the MongoDB and Redis front ends answer one request at a time.
+//!
+//! Request rates are limited with a token bucket per client: per user (of
+//! each tenant) once logged in, per IP address otherwise. Requests over
+//! the rate are refused with `DatabaseError::RateLimited`, which the front
+//! ends answer like HTTP 429.
+//!
+//! The limits also shut the front ends down: after `Limits::shut_down`, they
+//! stop accepting connections and close each open one once the request in
+//! progress on it is answered, and `Limits::drained` tells when the last
+//! one is gone.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+use crate::db::DatabaseError;
+
+/// Clients whose buckets are kept before idle ones are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct LimitOptions {
+    /// Connections open at once, over every front end.
+    pub max_connections: usize,
+    /// Requests one connection may have in flight at once.
+    pub max_in_flight: usize,
+    /// Largest request accepted, in bytes.
+    pub max_request_size: usize,
+    /// Requests each client may make; unlimited when unset.
+    pub rate_limit: Option<RateLimitOptions>,
+}
+
+impl Default for LimitOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_in_flight: 64,
+            max_request_size: 16 * 1024 * 1024,
+            rate_limit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitOptions {
+    /// Requests per second a client may keep up.
+    pub requests_per_second: f64,
+    /// Requests a client may make at once after being idle.
+    pub burst: u32,
+}
+
+/// Limits shared by the front ends given clones of it.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    options: LimitOptions,
+    connections: Arc<Semaphore>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: &RateLimitOptions) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.requests_per_second).min(rate.burst as f64);
+        self.updated = now;
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new(LimitOptions::default())
+    }
+}
+
+impl Limits {
+    pub fn new(options: LimitOptions) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(options.max_connections)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: Arc::new(watch::Sender::new(false)),
+            options,
+        }
+    }
+
+    pub fn options(&self) -> &LimitOptions {
+        &self.options
+    }
+
+    /// Takes up a connection slot for `stream` until it is dropped, or
+    /// drops it right away if none is free.
+    pub fn admit<S>(&self, stream: S, peer: impl Debug) -> Option<LimitedStream<S>> {
+        match self.connections.clone().try_acquire_owned() {
+            Ok(permit) => Some(LimitedStream {
+                stream,
+                _permit: permit,
+            }),
+            Err(_) => {
+                warn!(
+                    "Refusing connection from {:?}: {} connections are open",
+                    peer, self.options.max_connections
+                );
+                None
+            }
+        }
+    }
+
+    /// Has every front end sharing these limits stop accepting connections
+    /// and close the open ones as they finish their requests.
+    pub fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once `shut_down` is called.
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Resolves once no connection is open.
+    pub async fn drained(&self) {
+        let slots = u32::try_from(self.options.max_connections).unwrap_or(u32::MAX);
+        let _ = self.connections.acquire_many(slots).await;
+    }
+}
+
+impl Limits {
+    /// Counts a request against `client`'s rate, as named by `client_key`.
+    pub fn check_rate(&self, client: &str) -> Result<(), DatabaseError> {
+        let Some(rate) = &self.options.rate_limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(client) {
+            // A full bucket is the same as a fresh one.
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, rate);
+                bucket.tokens < rate.burst as f64
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: rate.burst as f64,
+            updated: now,
+        });
+        bucket.refill(now, rate);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / rate.requests_per_second;
+            Err(DatabaseError::RateLimited(Duration::from_secs_f64(wait)))
+        }
+    }
+}
+
+/// Names the client a request counts against: its user when logged in to
+/// `tenant`, otherwise its address.
+pub fn client_key(tenant: &str, username: Option<&str>, peer: Option<IpAddr>) -> String {
+    match (username, peer) {
+        (Some(username), _) if !username.is_empty() => {
+            format!("user:{}/{}", tenant, username)
+        }
+        (_, Some(peer)) => format!("ip:{}", peer),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// A stream holding one of the connection slots.
+pub struct LimitedStream<S> {
+    stream: S,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LimitedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LimitedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl<S: tonic::transport::server::Connected> tonic::transport::server::Connected
+    for LimitedStream<S>
+{
+    type ConnectInfo = S::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.stream.connect_info()
+    }
+}
+
+/// Admits the connections of another listener under the limits.
+#[cfg(feature = "http")]
+pub struct LimitedListener<L> {
+    listener: L,
+    limits: Limits,
+}
+
+#[cfg(feature = "http")]
+impl<L> LimitedListener<L> {
+    pub fn new(listener: L, limits: Limits) -> Self {
+        Self { listener, limits }
+    }
+}
+
+#[cfg(feature = "http")]
+impl<L> axum::serve::Listener for LimitedListener<L>
+where
+    L: axum::serve::Listener,
+    L::Io: Unpin,
+    L::Addr: Debug,
+{
+    type Io = LimitedStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, peer) = self.listener.accept().await;
+            if let Some(stream) = self.limits.admit(stream, &peer) {
+                return (stream, peer);
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Connects a client and offers the server's end of it to `limits`.
+    async fn connect(
+        listener: &TcpListener,
+        limits: &Limits,
+    ) -> (TcpStream, Option<LimitedStream<TcpStream>>) {
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        (client, limits.admit(stream, peer))
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limits = Limits::new(LimitOptions {
+            rate_limit: Some(RateLimitOptions {
+                requests_per_second: 0.001,
+                burst: 2,
+            }),
+            ..LimitOptions::default()
+        });
+        let alice = client_key("default", Some("alice"), None);
+        let bob = client_key("default", None, Some("10.0.0.1".parse().unwrap()));
+
+        assert!(limits.check_rate(&alice).is_ok());
+        assert!(limits.check_rate(&alice).is_ok());
+        assert!(matches!(
+            limits.check_rate(&alice),
+            Err(DatabaseError::RateLimited(wait)) if wait > Duration::from_secs(900)
+        ));
+        assert!(limits.check_rate(&bob).is_ok());
+        assert!(Limits::default().check_rate(&alice).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let limits = Limits::new(LimitOptions {
+            max_connections: 1,
+            ..LimitOptions::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (_first_client, first) = connect(&listener, &limits).await;
+        assert!(first.is_some());
+        let (_second_client, second) = connect(&listener, &limits).await;
+        assert!(second.is_none());
+
+        drop(first);
+        let (_third_client, third) = connect(&listener, &limits).await;
+        assert!(third.is_some());
+
+        limits.shut_down();
+        limits.shutting_down().await;
+        let wait = Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, limits.drained()).await.is_err());
+        drop(third);
+        assert!(tokio::time::timeout(wait, limits.drained()).await.is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+//! Network front ends exposing a `Database` to other processes. Each one is
+//! behind a cargo feature of the same name.
+
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod audit;
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod auth;
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod cursors;
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod limits;
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod roles;
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod tenants;
+#[cfg(any(
+    feature = "http",
+    feature = "grpc",
+    feature = "mongo",
+    feature = "resp"
+))]
+pub mod trace;
+
+#[cfg(feature = "client")]
+pub mod cluster;
+
+#[cfg(any(feature = "http", feature = "mongo"))]
+pub mod compression;
+
+#[cfg(feature = "http")]
+pub mod config;
+
+#[cfg(feature = "http")]
+pub mod http;
+
+#[cfg(feature = "graphql")]
+pub mod graphql;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "mongo")]
+pub mod mongo;
+
+#[cfg(feature = "nats")]
+pub mod nats;
+
+#[cfg(feature = "client")]
+pub mod multi_primary;
+
+#[cfg(feature = "client")]
+pub mod replication;
+
+#[cfg(feature = "resp")]
+pub mod resp;
+
+#[cfg(feature = "http")]
+pub mod standalone;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(feature = "http")]
+pub mod webhooks;
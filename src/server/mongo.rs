@@ -0,0 +1,1278 @@
+//! A subset of the MongoDB wire protocol, enough for drivers and `mongosh`
+//! to connect and run `insert`, `find`, `update` and `delete`.
+//!
+//! A command's `$db` picks the tenant of that name (see
+//! `owldb::server::tenants`), or the default tenant when there is none, so
+//! a server with a single database serves it under every name. Collections
+//! map one to one. Documents keep the `_id` the driver gives them;
+//! documents written through the Rust API, which have none, are shown with
+//! their owldb id as `_id`. Filters support equality and the comparison
+//! operators `Database::find` does, and `find` ignores `sort` and
+//! `projection`.
+//!
+//! Messages may be compressed with the compressors the listener offers,
+//! once the driver has negotiated one in `hello` (see
+//! `owldb::server::compression`); replies to a compressed message are
+//! compressed the same way.
+//!
+//! `find` answers its first `batchSize` matches, and a cursor the driver
+//! fetches the rest from with `getMore` and closes with `killCursors`
+//! (see `owldb::server::cursors`).
+//!
+//! With authentication enabled, clients log in with the PLAIN mechanism
+//! (`authMechanism=PLAIN`), which sends the password as is, so it should
+//! only be used over TLS. A login holds for the tenant it was made with,
+//! the driver's `authSource`.
+//!
+//! Failed commands carry the ID their request was logged under in
+//! `requestId` (see `owldb::server::trace`).
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+use bson::oid::ObjectId;
+use bson::{Bson, Document};
+use log::{error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::db::{filter, Database, DatabaseError};
+use crate::server::audit::AuditEvent;
+use crate::server::auth::Principal;
+use crate::server::compression::{Compressor, MIN_COMPRESSED_SIZE};
+use crate::server::cursors::DEFAULT_BATCH_SIZE;
+use crate::server::limits::{client_key, Limits};
+use crate::server::roles::Access;
+use crate::server::tenants::{Tenant, Tenants};
+#[cfg(feature = "tls")]
+use crate::server::tls::TlsListener;
+use crate::server::trace::{self, RequestTrace};
+
+const OP_REPLY: i32 = 1;
+const OP_QUERY: i32 = 2004;
+const OP_COMPRESSED: i32 = 2012;
+const OP_MSG: i32 = 2013;
+
+const HEADER_SIZE: usize = 16;
+/// Largest message the protocol allows; `Limits` may lower it.
+const MAX_MESSAGE_SIZE: usize = 48_000_000;
+const MAX_BSON_OBJECT_SIZE: i32 = 16 * 1024 * 1024;
+/// MongoDB 6.0, recent enough for current drivers.
+const MAX_WIRE_VERSION: i32 = 17;
+
+/// Set when an OP_MSG ends with a CRC-32C checksum.
+const CHECKSUM_PRESENT: u32 = 1;
+
+/// Serves the tenants on `addr` until `limits` are shut down, offering
+/// drivers `compressors`.
+pub async fn serve(
+    tenants: Tenants,
+    addr: SocketAddr,
+    limits: Limits,
+    compressors: Vec<Compressor>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    serve_with_listener(tenants, listener, limits, compressors).await
+}
+
+pub async fn serve_with_listener(
+    tenants: Tenants,
+    listener: TcpListener,
+    limits: Limits,
+    compressors: Vec<Compressor>,
+) -> std::io::Result<()> {
+    info!(
+        "Listening for MongoDB clients on {}",
+        listener.local_addr()?
+    );
+
+    let server = Server::new(tenants, &limits, compressors);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = limits.shutting_down() => return Ok(()),
+        };
+        if let Some(stream) = limits.admit(stream, peer) {
+            server.spawn(stream, peer);
+        }
+    }
+}
+
+/// Like `serve_with_listener`, for clients connecting over TLS.
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    tenants: Tenants,
+    mut listener: TlsListener,
+    limits: Limits,
+    compressors: Vec<Compressor>,
+) -> std::io::Result<()> {
+    info!(
+        "Listening for MongoDB clients over TLS on {}",
+        listener.local_addr()
+    );
+
+    let server = Server::new(tenants, &limits, compressors);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = limits.shutting_down() => return Ok(()),
+        };
+        if let Some(stream) = limits.admit(stream, peer) {
+            server.spawn(stream, peer);
+        }
+    }
+}
+
+struct Server {
+    tenants: Tenants,
+    limits: Limits,
+    compressors: Vec<Compressor>,
+    max_message_size: usize,
+    next_request_id: AtomicI32,
+    next_connection_id: AtomicI32,
+}
+
+struct Header {
+    request_id: i32,
+    op_code: i32,
+}
+
+/// A connection's login, which holds for the tenant it was made with.
+struct Login {
+    tenant: String,
+    principal: Principal,
+}
+
+impl Server {
+    fn new(tenants: Tenants, limits: &Limits, compressors: Vec<Compressor>) -> Arc<Self> {
+        Arc::new(Server {
+            tenants,
+            max_message_size: limits.options().max_request_size.min(MAX_MESSAGE_SIZE),
+            limits: limits.clone(),
+            compressors,
+            next_request_id: AtomicI32::new(1),
+            next_connection_id: AtomicI32::new(1),
+        })
+    }
+
+    fn spawn<S>(self: &Arc<Self>, stream: S, peer: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle_connection(stream, connection_id, peer).await {
+                warn!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    async fn handle_connection<S>(
+        &self,
+        mut stream: S,
+        connection_id: i32,
+        peer: SocketAddr,
+    ) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut login = None;
+
+        // Shutting down closes the connection between requests.
+        while let Some((header, body)) = tokio::select! {
+            message = read_message(&mut stream, self.max_message_size) => message?,
+            _ = self.limits.shutting_down() => None,
+        } {
+            let reply_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+
+            let (op_code, body, compressor) = match header.op_code {
+                OP_COMPRESSED => {
+                    match decompress(&body, &self.compressors, self.max_message_size) {
+                        Ok((op_code, body, compressor)) => (op_code, body, Some(compressor)),
+                        Err(e) => {
+                            warn!("Closing connection on bad compressed message: {:?}", e);
+                            return Ok(());
+                        }
+                    }
+                }
+                op_code => (op_code, body, None),
+            };
+
+            let reply = match op_code {
+                OP_MSG => {
+                    let response = match parse_op_msg(&body) {
+                        Ok(command) => self.execute(command, connection_id, peer, &mut login).await,
+                        Err(e) => error_response(2, "BadValue", e),
+                    };
+                    encode_op_msg(reply_id, header.request_id, &response)
+                }
+                // Drivers still open connections with a legacy handshake.
+                OP_QUERY => {
+                    let response = match parse_op_query(&body) {
+                        Ok(command) => self.execute(command, connection_id, peer, &mut login).await,
+                        Err(e) => error_response(2, "BadValue", e),
+                    };
+                    encode_op_reply(reply_id, header.request_id, &response)
+                }
+                op_code => {
+                    warn!("Closing connection on unsupported opcode {}", op_code);
+                    return Ok(());
+                }
+            };
+            let reply = match compressor {
+                Some(compressor) => compress_message(&reply, compressor),
+                None => reply,
+            };
+
+            stream.write_all(&reply).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The compressors the driver asked for in `hello` that are offered
+    /// too, in the driver's order.
+    fn negotiate(&self, hello: &Document) -> Vec<&'static str> {
+        let Ok(requested) = hello.get_array("compression") else {
+            return Vec::new();
+        };
+        requested
+            .iter()
+            .filter_map(|name| Compressor::from_name(name.as_str()?))
+            .filter(|compressor| self.compressors.contains(compressor))
+            .map(Compressor::name)
+            .collect()
+    }
+
+    /// Runs `command` as a traced request. Failures carry the request's ID
+    /// in `requestId`.
+    async fn execute(
+        &self,
+        command: Document,
+        connection_id: i32,
+        peer: SocketAddr,
+        login: &mut Option<Login>,
+    ) -> Document {
+        let trace = RequestTrace::new(None);
+        let name = command.keys().next().cloned().unwrap_or_default();
+        let run = self.run_command(command, connection_id, peer, login);
+        let mut response = trace.clone().scope(run).await;
+
+        let outcome = match response.get_str("codeName") {
+            Ok(code_name) => code_name.to_string(),
+            Err(_) => "ok".to_string(),
+        };
+        if response.get_f64("ok") != Ok(1.0) {
+            response.insert("requestId", trace.id());
+        }
+        // Drivers check on the server every few seconds.
+        if !matches!(name.as_str(), "hello" | "isMaster" | "ismaster" | "ping") {
+            trace.finish(&format!("mongo {}", name), outcome);
+        }
+        response
+    }
+
+    /// Runs `command` against the tenant its `$db` names, or the default
+    /// one if there is no tenant by that name.
+    async fn run_command(
+        &self,
+        command: Document,
+        connection_id: i32,
+        peer: SocketAddr,
+        login: &mut Option<Login>,
+    ) -> Document {
+        let name = match command.keys().next() {
+            Some(name) => name.clone(),
+            None => return error_response(2, "BadValue", "empty command".to_string()),
+        };
+        let tenant = command
+            .get_str("$db")
+            .ok()
+            .and_then(|database| self.tenants.get(database).ok())
+            .unwrap_or_else(|| self.tenants.default_tenant());
+
+        // Logged in from the start when authentication is disabled.
+        let principal = match login {
+            Some(login) if login.tenant == tenant.name() => Some(login.principal.clone()),
+            _ => tenant.auth().authenticate(None).ok(),
+        };
+        let username = principal.as_ref().map(|p| p.username.as_str());
+        if let Err(e) =
+            self.limits
+                .check_rate(&client_key(tenant.name(), username, Some(peer.ip())))
+        {
+            return rate_limited_response(e);
+        }
+
+        let result = match name.as_str() {
+            "hello" | "isMaster" | "ismaster" => {
+                let mut response = hello(connection_id, self.max_message_size);
+                if command.contains_key("saslSupportedMechs") {
+                    response.insert("saslSupportedMechs", vec!["PLAIN"]);
+                }
+                let compression = self.negotiate(&command);
+                if !compression.is_empty() {
+                    response.insert("compression", compression);
+                }
+                Ok(response)
+            }
+            "saslStart" => match trace::stage("auth", sasl_start(tenant, &command)).await {
+                Ok((principal, response)) => {
+                    *login = Some(Login {
+                        tenant: tenant.name().to_string(),
+                        principal,
+                    });
+                    Ok(response)
+                }
+                Err(e) => Err(e),
+            },
+            "logout" => {
+                *login = None;
+                Ok(bson::doc! {})
+            }
+            "ping" | "endSessions" => Ok(bson::doc! {}),
+            "buildInfo" | "buildinfo" => Ok(bson::doc! {
+                "version": "6.0.0",
+                "versionArray": [6, 0, 0, 0],
+                "maxBsonObjectSize": max_bson_object_size(self.max_message_size),
+            }),
+            "insert" | "find" | "update" | "delete" => {
+                match authorize(tenant, &command, &name, principal.as_ref()).await {
+                    Ok(principal) => {
+                        let crud = run_crud(tenant, &command, &name, principal);
+                        trace::stage("db", crud).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            "getMore" => get_more(tenant, &command, principal.as_ref()),
+            "killCursors" => kill_cursors(tenant, &command, principal.as_ref()),
+            _ => {
+                return error_response(
+                    59,
+                    "CommandNotFound",
+                    format!("no such command: '{}'", name),
+                )
+            }
+        };
+
+        match result {
+            Ok(mut response) => {
+                response.insert("ok", 1.0);
+                response
+            }
+            Err(DatabaseError::Unauthenticated) => error_response(
+                18,
+                "AuthenticationFailed",
+                format!("'{}' requires authentication", name),
+            ),
+            Err(DatabaseError::PermissionDenied(message)) => {
+                error_response(13, "Unauthorized", message)
+            }
+            Err(DatabaseError::QuotaExceeded(message)) => {
+                error_response(14031, "OutOfDiskSpace", message)
+            }
+            Err(DatabaseError::ValidationError(e)) => error_response(
+                121,
+                "DocumentValidationFailure",
+                format!("'{}' {}", e.field, e.reason),
+            ),
+            Err(DatabaseError::CursorNotFound(id)) => {
+                error_response(43, "CursorNotFound", format!("cursor id {} not found", id))
+            }
+            Err(DatabaseError::InvalidName(invalid)) => {
+                error_response(2, "BadValue", format!("invalid name '{}'", invalid))
+            }
+            Err(DatabaseError::InvalidQuery(message)) => error_response(2, "BadValue", message),
+            Err(e) => {
+                let id = trace::current_id().unwrap_or_default();
+                error!("[{}] Failed to run '{}': {:?}", id, name, e);
+                error_response(8, "UnknownError", format!("{:?}", e))
+            }
+        }
+    }
+}
+
+async fn run_crud(
+    tenant: &Tenant,
+    command: &Document,
+    name: &str,
+    principal: &Principal,
+) -> Result<Document, DatabaseError> {
+    let db = tenant.db();
+    match name {
+        "insert" => {
+            tenant.check_quota().await?;
+            insert(db, command).await
+        }
+        "find" => find(tenant, command, principal).await,
+        "update" => {
+            tenant.check_quota().await?;
+            update(db, command).await
+        }
+        _ => delete(db, command).await,
+    }
+}
+
+/// Checks `principal` may run the CRUD command `name`, and records it: once
+/// per statement for updates and deletes, with the filters they select by.
+async fn authorize<'a>(
+    tenant: &Tenant,
+    command: &Document,
+    name: &str,
+    principal: Option<&'a Principal>,
+) -> Result<&'a Principal, DatabaseError> {
+    let principal = principal.ok_or(DatabaseError::Unauthenticated)?;
+    let access = match name {
+        "find" => Access::Read,
+        "insert" => Access::Insert,
+        _ => Access::Write,
+    };
+    let collection = collection_name(command, name)?;
+    trace::stage_sync("auth", || {
+        tenant.auth().authorize(principal, &collection, access)
+    })?;
+
+    let events = match name {
+        "find" => {
+            let filter = command.get_document("filter").cloned().unwrap_or_default();
+            vec![AuditEvent::new(name, &collection).with_filter(filter)]
+        }
+        "update" | "delete" => {
+            let field = format!("{}s", name);
+            documents(command, &field)?
+                .into_iter()
+                .map(|statement| {
+                    let filter = statement.get_document("q").cloned().unwrap_or_default();
+                    AuditEvent::new(name, &collection).with_filter(filter)
+                })
+                .collect()
+        }
+        _ => vec![AuditEvent::new(name, &collection)],
+    };
+    for event in events {
+        tenant.audit(principal, event).await?;
+    }
+    Ok(principal)
+}
+
+/// PLAIN finishes in one step: the payload is `authzid\0user\0password`.
+/// Answers who logged in, and the reply.
+async fn sasl_start(
+    tenant: &Tenant,
+    command: &Document,
+) -> Result<(Principal, Document), DatabaseError> {
+    if command.get_str("mechanism") != Ok("PLAIN") {
+        return Err(DatabaseError::Unauthenticated);
+    }
+    let payload = match command.get("payload") {
+        Some(Bson::Binary(binary)) => binary.bytes.clone(),
+        _ => return Err(DatabaseError::Unauthenticated),
+    };
+
+    let mut parts = payload.split(|b| *b == 0).skip(1);
+    let (Some(username), Some(password)) = (parts.next(), parts.next()) else {
+        return Err(DatabaseError::Unauthenticated);
+    };
+    let username = String::from_utf8_lossy(username);
+    let password = String::from_utf8_lossy(password);
+    let principal = tenant.auth().verify_password(&username, &password).await?;
+
+    let response = bson::doc! {
+        "conversationId": 1,
+        "done": true,
+        "payload": bson::Binary {
+            subtype: bson::spec::BinarySubtype::Generic,
+            bytes: Vec::new(),
+        },
+    };
+    Ok((principal, response))
+}
+
+async fn insert(db: &Database, command: &Document) -> Result<Document, DatabaseError> {
+    let collection = collection_name(command, "insert")?;
+
+    let mut n = 0;
+    for doc in documents(command, "documents")? {
+        let mut doc = doc.clone();
+        if !doc.contains_key("_id") {
+            let mut with_id = bson::doc! { "_id": ObjectId::new() };
+            with_id.extend(doc);
+            doc = with_id;
+        }
+
+        db.insert_one(collection.clone(), doc).await?;
+        n += 1;
+    }
+
+    Ok(bson::doc! { "n": n })
+}
+
+async fn find(
+    tenant: &Tenant,
+    command: &Document,
+    principal: &Principal,
+) -> Result<Document, DatabaseError> {
+    let collection = collection_name(command, "find")?;
+    let filter = command.get_document("filter").cloned().unwrap_or_default();
+    let skip = number(command, "skip").unwrap_or(0).max(0) as usize;
+    let limit = number(command, "limit").unwrap_or(0);
+    let take = match limit.unsigned_abs() {
+        0 => usize::MAX,
+        limit => limit as usize,
+    };
+
+    let matches: Vec<Document> = matching(tenant.db(), &collection, &filter)
+        .await?
+        .into_iter()
+        .skip(skip)
+        .take(take)
+        .map(|(_, doc)| doc)
+        .collect();
+
+    // A negative limit asks for a single batch.
+    let batch_size = match number(command, "batchSize") {
+        _ if limit < 0 || command.get_bool("singleBatch") == Ok(true) => usize::MAX,
+        Some(size) => size.max(0) as usize,
+        None => DEFAULT_BATCH_SIZE,
+    };
+    let batch = tenant
+        .cursors()
+        .open(&principal.username, &collection, matches, batch_size);
+
+    Ok(bson::doc! {
+        "cursor": {
+            "firstBatch": batch.documents,
+            "id": batch.cursor_id,
+            "ns": namespace(command, &collection),
+        },
+    })
+}
+
+fn get_more(
+    tenant: &Tenant,
+    command: &Document,
+    principal: Option<&Principal>,
+) -> Result<Document, DatabaseError> {
+    let principal = principal.ok_or(DatabaseError::Unauthenticated)?;
+    let Some(cursor_id) = number(command, "getMore") else {
+        return Err(DatabaseError::InvalidUpdate(
+            "'getMore' must be a cursor id".to_string(),
+        ));
+    };
+    let cursors = tenant.cursors();
+    let collection = cursors.collection(cursor_id, &principal.username)?;
+    trace::stage_sync("auth", || {
+        tenant
+            .auth()
+            .authorize(principal, &collection, Access::Read)
+    })?;
+
+    let batch_size = match number(command, "batchSize") {
+        Some(size) if size > 0 => size as usize,
+        _ => DEFAULT_BATCH_SIZE,
+    };
+    let batch = cursors.next(cursor_id, &principal.username, batch_size)?;
+
+    Ok(bson::doc! {
+        "cursor": {
+            "nextBatch": batch.documents,
+            "id": batch.cursor_id,
+            "ns": namespace(command, &collection),
+        },
+    })
+}
+
+fn kill_cursors(
+    tenant: &Tenant,
+    command: &Document,
+    principal: Option<&Principal>,
+) -> Result<Document, DatabaseError> {
+    let principal = principal.ok_or(DatabaseError::Unauthenticated)?;
+    let ids = command
+        .get_array("cursors")
+        .map_err(|_| DatabaseError::InvalidUpdate("'cursors' must be an array".to_string()))?;
+
+    let mut killed = Vec::new();
+    let mut not_found = Vec::new();
+    for id in ids {
+        let id = match id {
+            Bson::Int64(id) => *id,
+            Bson::Int32(id) => *id as i64,
+            _ => continue,
+        };
+        match tenant.cursors().kill(id, &principal.username) {
+            true => killed.push(id),
+            false => not_found.push(id),
+        }
+    }
+
+    Ok(bson::doc! {
+        "cursorsKilled": killed,
+        "cursorsNotFound": not_found,
+        "cursorsAlive": [],
+        "cursorsUnknown": [],
+    })
+}
+
+fn namespace(command: &Document, collection: &str) -> String {
+    let database = command.get_str("$db").unwrap_or("test");
+    format!("{}.{}", database, collection)
+}
+
+async fn update(db: &Database, command: &Document) -> Result<Document, DatabaseError> {
+    let collection = collection_name(command, "update")?;
+
+    let mut matched = 0;
+    let mut modified = 0;
+    let mut upserted = Vec::new();
+    for (index, statement) in documents(command, "updates")?.iter().enumerate() {
+        let query = statement.get_document("q").cloned().unwrap_or_default();
+        let update = statement
+            .get_document("u")
+            .map_err(|_| DatabaseError::InvalidUpdate("'u' must be a document".to_string()))?;
+        let multi = statement.get_bool("multi").unwrap_or(false);
+        let replacement = !update.keys().any(|key| key.starts_with('$'));
+
+        let mut targets = matching(db, &collection, &query).await?;
+        if !multi {
+            targets.truncate(1);
+        }
+
+        if targets.is_empty() && statement.get_bool("upsert").unwrap_or(false) {
+            let id = ObjectId::new();
+            let mut doc = bson::doc! { "_id": id };
+            if !replacement {
+                // Start from the fields the query pinned down.
+                for (key, value) in query.iter().filter(|(key, _)| !key.starts_with('$')) {
+                    doc.insert(key.clone(), value.clone());
+                }
+            }
+            let owl_id = db.insert_one(collection.clone(), doc.clone()).await?;
+            db.update_one(
+                collection.clone(),
+                owl_id,
+                with_id(&doc, update, replacement),
+            )
+            .await?;
+            upserted.push(bson::doc! { "index": index as i32, "_id": id });
+            continue;
+        }
+
+        for (id, doc) in targets {
+            matched += 1;
+            let update = with_id(&doc, update, replacement);
+            if db.update_one(collection.clone(), id, update).await? {
+                modified += 1;
+            }
+        }
+    }
+
+    let mut response = bson::doc! {
+        "n": matched + upserted.len() as i32,
+        "nModified": modified,
+    };
+    if !upserted.is_empty() {
+        response.insert("upserted", upserted);
+    }
+    Ok(response)
+}
+
+async fn delete(db: &Database, command: &Document) -> Result<Document, DatabaseError> {
+    let collection = collection_name(command, "delete")?;
+
+    let mut n = 0;
+    for statement in documents(command, "deletes")? {
+        let query = statement.get_document("q").cloned().unwrap_or_default();
+
+        let mut targets = matching(db, &collection, &query).await?;
+        if number(statement, "limit") == Some(1) {
+            targets.truncate(1);
+        }
+
+        for (id, _) in targets {
+            db.delete_one(collection.clone(), id).await?;
+            n += 1;
+        }
+    }
+
+    Ok(bson::doc! { "n": n })
+}
+
+/// Documents matching `filter`, with their owldb ids, as clients see
+/// them. `_id` is matched after the fact since it may be synthesized.
+async fn matching(
+    db: &Database,
+    collection: &str,
+    filter: &Document,
+) -> Result<Vec<(String, Document)>, DatabaseError> {
+    let mut query = filter.clone();
+    let id_filter = match query.remove("_id") {
+        Some(condition) => bson::doc! { "_id": condition },
+        None => Document::new(),
+    };
+    filter::validate(&id_filter)?;
+
+    let docs = match db.find_with_ids(collection.to_string(), query).await {
+        Ok(docs) => docs,
+        // A collection nobody wrote to yet has no directory to scan.
+        Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    Ok(docs
+        .into_iter()
+        .map(|(id, doc)| {
+            let doc = with_mongo_id(&id, doc);
+            (id, doc)
+        })
+        .filter(|(_, doc)| filter::matches(doc, &id_filter))
+        .collect())
+}
+
+fn hello(connection_id: i32, max_message_size: usize) -> Document {
+    bson::doc! {
+        "helloOk": true,
+        "isWritablePrimary": true,
+        "ismaster": true,
+        "maxBsonObjectSize": max_bson_object_size(max_message_size),
+        "maxMessageSizeBytes": max_message_size as i32,
+        "maxWriteBatchSize": 100_000,
+        "localTime": bson::DateTime::now(),
+        "connectionId": connection_id,
+        "minWireVersion": 0,
+        "maxWireVersion": MAX_WIRE_VERSION,
+        "readOnly": false,
+    }
+}
+
+fn error_response(code: i32, code_name: &str, message: String) -> Document {
+    bson::doc! {
+        "ok": 0.0,
+        "errmsg": message,
+        "code": code,
+        "codeName": code_name,
+    }
+}
+
+/// The reply to a request over its client's rate, with the code MongoDB
+/// Atlas uses for the same.
+fn rate_limited_response(e: DatabaseError) -> Document {
+    error_response(462, "IngressRequestRateLimitExceeded", format!("{:?}", e))
+}
+
+fn collection_name(command: &Document, name: &str) -> Result<String, DatabaseError> {
+    command
+        .get_str(name)
+        .map(str::to_string)
+        .map_err(|_| DatabaseError::InvalidUpdate(format!("'{}' must name a collection", name)))
+}
+
+fn documents<'a>(command: &'a Document, field: &str) -> Result<Vec<&'a Document>, DatabaseError> {
+    let array = command
+        .get_array(field)
+        .map_err(|_| DatabaseError::InvalidUpdate(format!("'{}' must be an array", field)))?;
+
+    array
+        .iter()
+        .map(|value| match value {
+            Bson::Document(doc) => Ok(doc),
+            _ => Err(DatabaseError::InvalidUpdate(format!(
+                "'{}' must hold documents",
+                field
+            ))),
+        })
+        .collect()
+}
+
+/// Drivers send numbers as whichever integer or double type they like.
+fn number(doc: &Document, field: &str) -> Option<i64> {
+    match doc.get(field)? {
+        Bson::Int32(n) => Some(*n as i64),
+        Bson::Int64(n) => Some(*n),
+        Bson::Double(n) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+fn with_mongo_id(id: &str, doc: Document) -> Document {
+    if doc.contains_key("_id") {
+        return doc;
+    }
+
+    let mongo_id = match ObjectId::parse_str(id) {
+        Ok(oid) => Bson::ObjectId(oid),
+        Err(_) => Bson::String(id.to_string()),
+    };
+    let mut with_id = bson::doc! { "_id": mongo_id };
+    with_id.extend(doc);
+    with_id
+}
+
+/// A replacement keeps the document's `_id`, as in MongoDB.
+fn with_id(current: &Document, update: &Document, replacement: bool) -> Document {
+    match (replacement, current.get("_id")) {
+        (true, Some(id)) => {
+            let mut doc = bson::doc! { "_id": id.clone() };
+            doc.extend(update.clone());
+            doc
+        }
+        _ => update.clone(),
+    }
+}
+
+/// Documents must fit in a message, with room for its header.
+fn max_bson_object_size(max_message_size: usize) -> i32 {
+    MAX_BSON_OBJECT_SIZE.min(max_message_size.saturating_sub(HEADER_SIZE + 5) as i32)
+}
+
+async fn read_message<S>(
+    stream: &mut S,
+    max_size: usize,
+) -> std::io::Result<Option<(Header, Vec<u8>)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_SIZE];
+    match stream.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = read_i32(&header, 0) as usize;
+    if !(HEADER_SIZE..=max_size).contains(&length) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid message length {}", length),
+        ));
+    }
+
+    let mut body = vec![0u8; length - HEADER_SIZE];
+    stream.read_exact(&mut body).await?;
+
+    Ok(Some((
+        Header {
+            request_id: read_i32(&header, 4),
+            op_code: read_i32(&header, 12),
+        },
+        body,
+    )))
+}
+
+fn read_i32(bytes: &[u8], at: usize) -> i32 {
+    i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn read_document(bytes: &[u8], at: usize) -> Result<(Document, usize), String> {
+    if at + 4 > bytes.len() {
+        return Err("truncated document".to_string());
+    }
+    let length = read_i32(bytes, at) as usize;
+    let end = at
+        .checked_add(length)
+        .filter(|end| *end <= bytes.len())
+        .ok_or("truncated document")?;
+
+    let doc = Document::from_reader(&mut &bytes[at..end]).map_err(|e| e.to_string())?;
+    Ok((doc, end))
+}
+
+fn read_cstring(bytes: &[u8], at: usize) -> Result<(String, usize), String> {
+    let length = bytes[at..]
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or("unterminated string")?;
+    let string = std::str::from_utf8(&bytes[at..at + length]).map_err(|e| e.to_string())?;
+    Ok((string.to_string(), at + length + 1))
+}
+
+/// Merges the sections of an OP_MSG into one command: document sequences
+/// (e.g. the `documents` of an insert) become arrays of the body.
+fn parse_op_msg(body: &[u8]) -> Result<Document, String> {
+    if body.len() < 4 {
+        return Err("truncated message".to_string());
+    }
+    let flags = read_i32(body, 0) as u32;
+    let end = match flags & CHECKSUM_PRESENT {
+        0 => body.len(),
+        _ => body.len().checked_sub(4).ok_or("truncated message")?,
+    };
+
+    let mut command = None;
+    let mut sequences = Vec::new();
+    let mut at = 4;
+    while at < end {
+        let kind = body[at];
+        at += 1;
+        match kind {
+            0 => {
+                let (doc, next) = read_document(&body[..end], at)?;
+                command = Some(doc);
+                at = next;
+            }
+            1 => {
+                if at + 4 > end {
+                    return Err("truncated document sequence".to_string());
+                }
+                let section_end = at + read_i32(body, at) as usize;
+                let (identifier, mut next) = read_cstring(&body[..end], at + 4)?;
+                let mut docs = Vec::new();
+                while next < section_end {
+                    let (doc, after) = read_document(&body[..section_end], next)?;
+                    docs.push(Bson::Document(doc));
+                    next = after;
+                }
+                sequences.push((identifier, docs));
+                at = section_end;
+            }
+            kind => return Err(format!("unknown section kind {}", kind)),
+        }
+    }
+
+    let mut command = command.ok_or("message has no body")?;
+    for (identifier, docs) in sequences {
+        command.insert(identifier, docs);
+    }
+    Ok(command)
+}
+
+fn parse_op_query(body: &[u8]) -> Result<Document, String> {
+    // flags, then the namespace, then how many documents to skip and return.
+    let (_, at) = read_cstring(body, 4)?;
+    let (query, _) = read_document(body, at + 8)?;
+
+    match query.get_document("$query") {
+        Ok(inner) => Ok(inner.clone()),
+        Err(_) => Ok(query),
+    }
+}
+
+fn encode_message(request_id: i32, response_to: i32, op_code: i32, payload: &[u8]) -> Vec<u8> {
+    let length = (HEADER_SIZE + payload.len()) as i32;
+
+    let mut message = Vec::with_capacity(length as usize);
+    message.extend_from_slice(&length.to_le_bytes());
+    message.extend_from_slice(&request_id.to_le_bytes());
+    message.extend_from_slice(&response_to.to_le_bytes());
+    message.extend_from_slice(&op_code.to_le_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Unwraps an OP_COMPRESSED body into the message it holds: its opcode,
+/// body and how it was compressed, which must be one of `compressors`.
+fn decompress(
+    body: &[u8],
+    compressors: &[Compressor],
+    max_size: usize,
+) -> Result<(i32, Vec<u8>, Compressor), DatabaseError> {
+    let invalid = |message: &str| DatabaseError::InvalidCompression(message.to_string());
+    if body.len() < 9 {
+        return Err(invalid("truncated message"));
+    }
+    let op_code = read_i32(body, 0);
+    let size = read_i32(body, 4) as usize;
+    let compressor = compressors
+        .iter()
+        .copied()
+        .find(|compressor| compressor.mongo_id() == body[8])
+        .ok_or_else(|| invalid("compressor not offered"))?;
+
+    let body = compressor.decompress(&body[9..], max_size)?;
+    match body.len() == size {
+        true => Ok((op_code, body, compressor)),
+        false => Err(invalid("wrong uncompressed size")),
+    }
+}
+
+/// Wraps an encoded `message` in an OP_COMPRESSED, unless it's too small
+/// to be worth it.
+fn compress_message(message: &[u8], compressor: Compressor) -> Vec<u8> {
+    if message.len() < HEADER_SIZE + MIN_COMPRESSED_SIZE {
+        return message.to_vec();
+    }
+
+    let original = &message[HEADER_SIZE..];
+    let mut payload = Vec::with_capacity(original.len() / 2 + 9);
+    payload.extend_from_slice(&message[12..16]); // original opcode
+    payload.extend_from_slice(&(original.len() as i32).to_le_bytes());
+    payload.push(compressor.mongo_id());
+    payload.extend_from_slice(&compressor.compress(original));
+    encode_message(
+        read_i32(message, 4),
+        read_i32(message, 8),
+        OP_COMPRESSED,
+        &payload,
+    )
+}
+
+fn encode_document(doc: &Document) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    // Writing to a Vec only fails for documents BSON can't represent, which
+    // responses built here never are.
+    doc.to_writer(&mut buffer).expect("response is valid BSON");
+    buffer
+}
+
+fn encode_op_msg(request_id: i32, response_to: i32, doc: &Document) -> Vec<u8> {
+    let mut payload = vec![0, 0, 0, 0, 0];
+    payload.extend_from_slice(&encode_document(doc));
+    encode_message(request_id, response_to, OP_MSG, &payload)
+}
+
+fn encode_op_reply(request_id: i32, response_to: i32, doc: &Document) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&0_i32.to_le_bytes()); // response flags
+    payload.extend_from_slice(&0_i64.to_le_bytes()); // cursor id
+    payload.extend_from_slice(&0_i32.to_le_bytes()); // starting from
+    payload.extend_from_slice(&1_i32.to_le_bytes()); // number returned
+    payload.extend_from_slice(&encode_document(doc));
+    encode_message(request_id, response_to, OP_REPLY, &payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::server::auth::Auth;
+    use crate::server::tenants::TenantOptions;
+
+    struct Client {
+        stream: TcpStream,
+        next_request_id: i32,
+    }
+
+    impl Client {
+        async fn run(&mut self, command: Document) -> Document {
+            let (op_code, body) = self.exchange(command, None).await;
+            assert_eq!(op_code, OP_MSG);
+            parse_op_msg(&body).unwrap()
+        }
+
+        /// Sends `command`, compressed with `compressor` if given; answers
+        /// the reply's opcode and body.
+        async fn exchange(
+            &mut self,
+            command: Document,
+            compressor: Option<Compressor>,
+        ) -> (i32, Vec<u8>) {
+            self.next_request_id += 1;
+            let mut message = encode_op_msg(self.next_request_id, 0, &command);
+            if let Some(compressor) = compressor {
+                message = compress_message(&message, compressor);
+            }
+            self.stream.write_all(&message).await.unwrap();
+
+            let (header, body) = read_message(&mut self.stream, MAX_MESSAGE_SIZE)
+                .await
+                .unwrap()
+                .unwrap();
+            (header.op_code, body)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crud_over_op_msg() {
+        let folder_path = "data_tests/test_mongo".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+        let folder_path = "data_tests/test_mongo_acme".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let acme = Database::init(folder_path).await.unwrap();
+        let tenants = Tenants::new(
+            Tenant::new("app", db, Auth::disabled(), TenantOptions::default()),
+            vec![Tenant::new(
+                "acme",
+                acme,
+                Auth::disabled(),
+                TenantOptions::default(),
+            )],
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_with_listener(
+            tenants,
+            listener,
+            Limits::default(),
+            vec![Compressor::Snappy],
+        ));
+        let mut client = Client {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            next_request_id: 0,
+        };
+
+        let hello = client.run(bson::doc! { "hello": 1, "$db": "admin" }).await;
+        assert_eq!(hello.get_f64("ok").unwrap(), 1.0);
+        assert_eq!(hello.get_i32("maxWireVersion").unwrap(), MAX_WIRE_VERSION);
+
+        let id = ObjectId::new();
+        let inserted = client
+            .run(bson::doc! {
+                "insert": "users",
+                "documents": [
+                    { "_id": id, "name": "John", "age": 30 },
+                    { "name": "Jane", "age": 25 },
+                ],
+                "$db": "app",
+            })
+            .await;
+        assert_eq!(inserted.get_i32("n").unwrap(), 2);
+
+        let updated = client
+            .run(bson::doc! {
+                "update": "users",
+                "updates": [{ "q": { "_id": id }, "u": { "$set": { "age": 31 } } }],
+                "$db": "app",
+            })
+            .await;
+        assert_eq!(updated.get_i32("nModified").unwrap(), 1);
+
+        let found = client
+            .run(bson::doc! { "find": "users", "filter": { "name": "John" }, "$db": "app" })
+            .await;
+        let batch = found
+            .get_document("cursor")
+            .unwrap()
+            .get_array("firstBatch")
+            .unwrap();
+        assert_eq!(
+            batch,
+            &vec![Bson::Document(
+                bson::doc! { "_id": id, "name": "John", "age": 31 }
+            )]
+        );
+        let found = client
+            .run(bson::doc! {
+                "find": "users",
+                "filter": { "_id": { "$in": [id, ObjectId::new()] } },
+                "$db": "app",
+            })
+            .await;
+        let batch = found
+            .get_document("cursor")
+            .unwrap()
+            .get_array("firstBatch")
+            .unwrap();
+        assert_eq!(batch.len(), 1);
+        let refused = client
+            .run(bson::doc! {
+                "find": "users",
+                "filter": { "_id": { "$near": id } },
+                "$db": "app",
+            })
+            .await;
+        assert_eq!(refused.get_str("codeName").unwrap(), "BadValue");
+
+        let deleted = client
+            .run(bson::doc! {
+                "delete": "users",
+                "deletes": [{ "q": {}, "limit": 0 }],
+                "$db": "app",
+            })
+            .await;
+        assert_eq!(deleted.get_i32("n").unwrap(), 2);
+
+        client
+            .run(bson::doc! {
+                "insert": "users",
+                "documents": [{ "name": "Joe" }],
+                "$db": "acme",
+            })
+            .await;
+        for (database, expected) in [("acme", 1), ("app", 0), ("test", 0)] {
+            let found = client
+                .run(bson::doc! { "find": "users", "$db": database })
+                .await;
+            let batch = found
+                .get_document("cursor")
+                .unwrap()
+                .get_array("firstBatch")
+                .unwrap();
+            assert_eq!(batch.len(), expected, "{}", database);
+        }
+
+        client
+            .run(bson::doc! {
+                "insert": "events",
+                "documents": (0..5).map(|n| bson::doc! { "n": n }).collect::<Vec<_>>(),
+                "$db": "app",
+            })
+            .await;
+        let found = client
+            .run(bson::doc! { "find": "events", "batchSize": 2, "$db": "app" })
+            .await;
+        let cursor = found.get_document("cursor").unwrap();
+        assert_eq!(cursor.get_array("firstBatch").unwrap().len(), 2);
+        let cursor_id = cursor.get_i64("id").unwrap();
+        assert_ne!(cursor_id, 0);
+        let more = client
+            .run(bson::doc! {
+                "getMore": cursor_id,
+                "collection": "events",
+                "batchSize": 2,
+                "$db": "app",
+            })
+            .await;
+        let cursor = more.get_document("cursor").unwrap();
+        assert_eq!(cursor.get_array("nextBatch").unwrap().len(), 2);
+        assert_eq!(cursor.get_i64("id").unwrap(), cursor_id);
+        let killed = client
+            .run(bson::doc! {
+                "killCursors": "events",
+                "cursors": [cursor_id],
+                "$db": "app",
+            })
+            .await;
+        assert_eq!(killed.get_array("cursorsKilled").unwrap().len(), 1);
+        let gone = client
+            .run(bson::doc! { "getMore": cursor_id, "collection": "events", "$db": "app" })
+            .await;
+        assert_eq!(gone.get_str("codeName").unwrap(), "CursorNotFound");
+
+        let hello = client
+            .run(bson::doc! { "hello": 1, "compression": ["zstd", "snappy"], "$db": "admin" })
+            .await;
+        assert_eq!(
+            hello.get_array("compression").unwrap(),
+            &vec![Bson::from("snappy")]
+        );
+        let padding = "x".repeat(MIN_COMPRESSED_SIZE);
+        let (op_code, _) = client
+            .exchange(
+                bson::doc! {
+                    "insert": "notes",
+                    "documents": [{ "text": &padding }, { "text": &padding }],
+                    "$db": "app",
+                },
+                Some(Compressor::Snappy),
+            )
+            .await;
+        assert_eq!(op_code, OP_MSG, "small replies stay uncompressed");
+        let (op_code, body) = client
+            .exchange(
+                bson::doc! { "find": "notes", "comment": &padding, "$db": "app" },
+                Some(Compressor::Snappy),
+            )
+            .await;
+        assert_eq!(op_code, OP_COMPRESSED);
+        let (op_code, body, _) =
+            decompress(&body[..], &[Compressor::Snappy], MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(op_code, OP_MSG);
+        let found = parse_op_msg(&body).unwrap();
+        let batch = found
+            .get_document("cursor")
+            .unwrap()
+            .get_array("firstBatch")
+            .unwrap();
+        assert_eq!(batch.len(), 2);
+
+        let unknown = client.run(bson::doc! { "frobnicate": 1 }).await;
+        assert_eq!(unknown.get_i32("code").unwrap(), 59);
+        assert_eq!(unknown.get_str("requestId").unwrap().len(), 24);
+    }
+}
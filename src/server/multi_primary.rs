@@ -0,0 +1,361 @@
+//! Multi-primary replication: several servers take writes to the same
+//! database, each following every other's oplog with the gRPC `Replicate`
+//! call and applying what it reads with `Database::apply_from_primary`.
+//! Every primary needs a node name (`OplogOptions::node`) so its writes are
+//! stamped; a write to a document reaching a primary that has a later
+//! stamped one is dropped, so all of them end up holding the same version.
+//!
+//! Primaries pass on the writes they apply along with their original
+//! stamps, so they needn't all follow each other directly. Where each one
+//! stopped reading a peer is kept in the `_primaries` collection. One that
+//! fell further behind than a peer's oplog reaches can't catch up by
+//! reading it, and needs a copy of another primary's data to rejoin.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::client::Client;
+use crate::db::{Database, DatabaseError};
+use crate::server::replication::{or_stopped, Link, LinkHealth, ReplicationOptions};
+
+/// Where the position read up to in each peer's oplog is kept.
+pub const PRIMARIES_COLLECTION: &str = "_primaries";
+
+/// Handle to a primary following its peers. Dropping it stops following.
+pub struct MultiPrimary {
+    db: Database,
+    options: ReplicationOptions,
+    peers: BTreeMap<String, Peer>,
+}
+
+struct Peer {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+    link: Arc<Link>,
+}
+
+impl Peer {
+    async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Multi-primary replication task failed: {}", e);
+        }
+    }
+}
+
+impl MultiPrimary {
+    /// Starts applying the writes of the primaries at `peers`, such as
+    /// `http://10.0.0.2:50051`, to `db`, in the background.
+    pub fn start(db: Database, peers: &[String], options: ReplicationOptions) -> Self {
+        let mut primary = Self {
+            db,
+            options,
+            peers: BTreeMap::new(),
+        };
+        for peer in peers {
+            primary.add_peer(peer);
+        }
+        primary
+    }
+
+    /// Starts following another primary. Answers false if it already was.
+    pub fn add_peer(&mut self, peer: &str) -> bool {
+        if self.peers.contains_key(peer) {
+            return false;
+        }
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let link = Arc::new(Link::default());
+        let follower = PeerFollower {
+            db: self.db.clone(),
+            peer: peer.to_string(),
+            options: self.options.clone(),
+            link: link.clone(),
+            shutdown: shutdown_rx,
+        };
+        let task = tokio::spawn(follower.run());
+        self.peers.insert(
+            peer.to_string(),
+            Peer {
+                shutdown,
+                task,
+                link,
+            },
+        );
+        true
+    }
+
+    /// Stops following a primary, keeping where it was read up to should
+    /// it be added again. Answers false if it wasn't followed.
+    pub async fn remove_peer(&mut self, peer: &str) -> bool {
+        match self.peers.remove(peer) {
+            Some(peer) => {
+                peer.stop().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The primaries followed, in order, and how each link is faring.
+    pub fn peers(&self) -> Vec<(String, LinkHealth)> {
+        self.peers
+            .iter()
+            .map(|(uri, peer)| (uri.clone(), peer.link.health()))
+            .collect()
+    }
+
+    pub async fn stop(self) {
+        for (_, peer) in self.peers {
+            peer.stop().await;
+        }
+    }
+}
+
+struct PeerFollower {
+    db: Database,
+    peer: String,
+    options: ReplicationOptions,
+    link: Arc<Link>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl PeerFollower {
+    async fn run(mut self) {
+        loop {
+            let result = self.follow().await;
+            if let Err(e) = &result {
+                self.link.lost(e);
+            }
+            match result {
+                Ok(()) => return,
+                Err(DatabaseError::OplogTruncated(position)) => error!(
+                    "{}'s oplog no longer holds position {}; copy a primary's data to rejoin",
+                    self.peer, position
+                ),
+                Err(e) => warn!("Lost primary {}: {:?}", self.peer, e),
+            }
+
+            let delay = tokio::time::sleep(self.options.retry_delay);
+            if or_stopped(&mut self.shutdown, delay).await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Applies the peer's writes until stopped, or until something fails.
+    async fn follow(&mut self) -> Result<(), DatabaseError> {
+        let connect = Client::connect_with_options(&self.peer, self.options.client.clone());
+        let Some(client) = or_stopped(&mut self.shutdown, connect).await.transpose()? else {
+            return Ok(());
+        };
+        if let Some((username, password)) = self.options.credentials.clone() {
+            client.login(&username, &password).await?;
+        }
+
+        let checkpoint_id = checkpoint_id(&self.peer);
+        let from = self
+            .db
+            .find_one(PRIMARIES_COLLECTION.to_string(), checkpoint_id.clone())
+            .await?
+            .and_then(|checkpoint| checkpoint.get_i64("position").ok())
+            .unwrap_or(0) as u64;
+        let mut oplog = client
+            .replicate(
+                from,
+                self.options.batch_size,
+                self.options.window,
+                self.options.name.as_deref(),
+            )
+            .await?;
+        self.link.connected();
+        self.link.position.store(from, Ordering::Release);
+        info!(
+            "Following primary {} from oplog position {}",
+            self.peer, from
+        );
+
+        loop {
+            let Some(batch) = or_stopped(&mut self.shutdown, oplog.next_batch())
+                .await
+                .transpose()?
+            else {
+                return Ok(());
+            };
+            let Some(batch) = batch else {
+                return Err(DatabaseError::Unavailable(
+                    "the primary ended the oplog".to_string(),
+                ));
+            };
+            let Some(last) = batch.last().map(|entry| entry.position) else {
+                continue;
+            };
+
+            let mut applied = 0;
+            for entry in batch {
+                // Each primary keeps its own.
+                if entry.collection.starts_with('_') {
+                    continue;
+                }
+                if self.db.apply_from_primary(&entry).await? {
+                    applied += 1;
+                }
+            }
+            if applied > 0 {
+                info!("Applied {} writes from primary {}", applied, self.peer);
+            }
+
+            self.db
+                .apply_replicated(
+                    PRIMARIES_COLLECTION.to_string(),
+                    checkpoint_id.clone(),
+                    Some(bson::doc! { "position": (last + 1) as i64 }),
+                )
+                .await?;
+            self.link.position.store(last + 1, Ordering::Release);
+            oplog.acknowledge(last).await?;
+        }
+    }
+}
+
+/// The peer's address made fit to name a file.
+fn checkpoint_id(peer: &str) -> String {
+    peer.replace(
+        |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_',
+        "_",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+    use crate::db::{DatabaseOptions, OplogOptions, CONFLICTS_COLLECTION};
+    use crate::server::auth::Auth;
+    use crate::server::grpc;
+    use crate::server::tenants::Tenants;
+
+    #[tokio::test]
+    async fn test_multi_primary() {
+        let mut primaries = Vec::new();
+        let mut addresses = Vec::new();
+        for node in ["a", "b"] {
+            let folder_path = format!("data_tests/test_multi_primary_server_{}", node);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions {
+                    node: Some(node.to_string()),
+                    ..OplogOptions::default()
+                }),
+                ..DatabaseOptions::default()
+            };
+            let db = Database::init_with_options(folder_path, options)
+                .await
+                .unwrap();
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addresses.push(format!("http://{}", listener.local_addr().unwrap()));
+            tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(grpc::service(Tenants::single(db.clone(), Auth::disabled())))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            primaries.push(db);
+        }
+        let (a, b) = (primaries[0].clone(), primaries[1].clone());
+
+        let john = a
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        b.insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        let options = ReplicationOptions {
+            retry_delay: Duration::from_millis(50),
+            ..ReplicationOptions::default()
+        };
+        let following = [
+            MultiPrimary::start(a.clone(), &addresses[1..], options.clone()),
+            MultiPrimary::start(b.clone(), &addresses[..1], options),
+        ];
+
+        // Both write John once b has him; both keep the later write.
+        let copied = async {
+            while b
+                .find_one("users".to_string(), john.clone())
+                .await
+                .unwrap()
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), copied)
+            .await
+            .unwrap();
+        a.update_one(
+            "users".to_string(),
+            john.clone(),
+            bson::doc! { "$set": { "age": 30 } },
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(b
+            .update_one(
+                "users".to_string(),
+                john.clone(),
+                bson::doc! { "$set": { "age": 40 } },
+            )
+            .await
+            .unwrap());
+
+        let converged = async {
+            loop {
+                let mut docs = Vec::new();
+                for db in [&a, &b] {
+                    docs.push(
+                        db.find_with_ids("users".to_string(), bson::doc! {})
+                            .await
+                            .unwrap_or_default(),
+                    );
+                }
+                if docs[0].len() == 2 && docs[0] == docs[1] {
+                    return docs.remove(0);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let docs = tokio::time::timeout(Duration::from_secs(2), converged)
+            .await
+            .unwrap();
+        let john_doc = docs.into_iter().find(|(id, _)| *id == john).unwrap().1;
+        assert_eq!(john_doc.get_i32("age"), Ok(40));
+
+        for primary in following {
+            primary.stop().await;
+        }
+        // Whether each saw the other's write as concurrent depends on
+        // timing, but a conflict is only ever noted against a's write.
+        for db in [&a, &b] {
+            let conflicts = db
+                .find(CONFLICTS_COLLECTION.to_string(), bson::doc! {})
+                .await
+                .unwrap_or_default();
+            for conflict in conflicts {
+                assert_eq!(
+                    conflict.get_document("lost").unwrap().get_str("node"),
+                    Ok("a")
+                );
+            }
+        }
+    }
+}
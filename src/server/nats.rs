@@ -0,0 +1,461 @@
+//! Publishing a database's writes to NATS, for feeding data lakes, search
+//! indexes and the like. Each write goes to the subject
+//! `{prefix}.{collection}.{operation}`, as relaxed Extended JSON or as
+//! BSON, holding the operation, collection, `_id`, document, for updates
+//! the fields they changed and, with an oplog, its resume token.
+//!
+//! With an oplog, the token of the last write the server acknowledged is
+//! kept in the `_publishers` collection, and a publisher that lost its
+//! connection or was restarted carries on from there, so every write is
+//! published at least once while the oplog still holds it. Without one,
+//! writes made while disconnected are lost. Only the core NATS protocol
+//! is spoken, without TLS or authentication.
+
+use std::time::{Duration, Instant};
+
+use bson::Bson;
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::db::{ChangeEvent, ChangeStream, Database, DatabaseError, OperationType};
+
+/// Where each publisher's last acknowledged resume token is kept.
+pub const PUBLISHERS_COLLECTION: &str = "_publishers";
+
+/// How events are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventFormat {
+    /// Relaxed Extended JSON.
+    #[default]
+    Json,
+    Bson,
+}
+
+#[derive(Debug, Clone)]
+pub struct NatsOptions {
+    /// The server's address, such as `nats://10.0.0.7:4222`.
+    pub url: String,
+    pub subject_prefix: String,
+    pub format: EventFormat,
+    /// Empty for every collection but the internal ones.
+    pub collections: Vec<String>,
+    /// Names the publisher's entry in `_publishers`.
+    pub name: String,
+    /// How often the server is asked to acknowledge what was sent, and the
+    /// resume token saved.
+    pub checkpoint_interval: Duration,
+    pub retry_delay: Duration,
+}
+
+impl Default for NatsOptions {
+    fn default() -> Self {
+        Self {
+            url: "nats://127.0.0.1:4222".to_string(),
+            subject_prefix: "owldb".to_string(),
+            format: EventFormat::default(),
+            collections: Vec::new(),
+            name: "nats".to_string(),
+            checkpoint_interval: Duration::from_secs(1),
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Handle to a publisher running in the background.
+pub struct NatsPublisher {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl NatsPublisher {
+    /// Starts publishing `db`'s writes in the background, from where the
+    /// publisher of this name stopped, or else from now on.
+    pub async fn start(db: Database, options: NatsOptions) -> Result<Self, DatabaseError> {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let publisher = Publisher {
+            db,
+            options,
+            shutdown: shutdown_rx,
+        };
+        let changes = match publisher.db.oplog_range().await {
+            Ok(range) => {
+                if publisher.checkpoint().await?.is_none() {
+                    publisher.save_checkpoint(range.end - 1).await?;
+                }
+                None
+            }
+            Err(DatabaseError::OplogDisabled) => Some(publisher.db.watch_all()),
+            Err(e) => return Err(e),
+        };
+        let task = tokio::spawn(publisher.run(changes));
+        Ok(Self { shutdown, task })
+    }
+
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("NATS publisher task failed: {}", e);
+        }
+    }
+}
+
+struct Publisher {
+    db: Database,
+    options: NatsOptions,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Publisher {
+    async fn run(mut self, mut changes: Option<ChangeStream>) {
+        loop {
+            match self.publish(changes.take()).await {
+                Ok(()) => return,
+                Err(e) => warn!("Lost NATS server {}: {:?}", self.options.url, e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.options.retry_delay) => {}
+                _ = self.shutdown.wait_for(|stopped| *stopped) => return,
+            }
+        }
+    }
+
+    /// Publishes writes until stopped, or until something fails.
+    async fn publish(&mut self, changes: Option<ChangeStream>) -> Result<(), DatabaseError> {
+        let mut connection = Connection::open(&self.options.url).await?;
+        let mut changes = match (self.checkpoint().await?, changes) {
+            (Some(token), _) => self.db.watch_all_after(token).await?,
+            (None, Some(changes)) => changes,
+            (None, None) => self.db.watch_all(),
+        };
+        info!("Publishing writes to NATS server {}", self.options.url);
+        let mut shutdown = self.shutdown.clone();
+
+        let mut unacknowledged = None;
+        let mut checkpointed = Instant::now();
+        loop {
+            tokio::select! {
+                event = changes.next() => {
+                    let event = match event {
+                        Some(Ok(event)) => event,
+                        Some(Err(e)) => return Err(e),
+                        None => return Ok(()),
+                    };
+                    if self.includes(&event.collection) {
+                        let subject = self.subject(&event);
+                        connection.publish(&subject, &self.encode(&event)?).await?;
+                    }
+                    // Saving a checkpoint mustn't call for another.
+                    if event.collection != PUBLISHERS_COLLECTION {
+                        unacknowledged = event.token.or(unacknowledged);
+                    }
+                }
+                line = connection.read_line() => connection.answer(&line?).await?,
+                _ = shutdown.changed() => {
+                    if let Some(token) = unacknowledged {
+                        connection.flush().await?;
+                        self.save_checkpoint(token).await?;
+                    }
+                    return Ok(());
+                }
+            }
+
+            if checkpointed.elapsed() >= self.options.checkpoint_interval {
+                if let Some(token) = unacknowledged.take() {
+                    connection.flush().await?;
+                    self.save_checkpoint(token).await?;
+                }
+                checkpointed = Instant::now();
+            }
+        }
+    }
+
+    fn includes(&self, collection: &str) -> bool {
+        match self.options.collections.is_empty() {
+            true => !collection.starts_with('_'),
+            false => self.options.collections.iter().any(|c| c == collection),
+        }
+    }
+
+    fn subject(&self, event: &ChangeEvent) -> String {
+        format!(
+            "{}.{}.{}",
+            self.options.subject_prefix,
+            event.collection,
+            operation_name(event.operation)
+        )
+    }
+
+    fn encode(&self, event: &ChangeEvent) -> Result<Vec<u8>, DatabaseError> {
+        let mut doc = bson::doc! {
+            "operation": operation_name(event.operation),
+            "collection": &event.collection,
+            "_id": &event.id,
+            "document": event.document.clone().map(Bson::Document).unwrap_or(Bson::Null),
+        };
+        if event.operation == OperationType::Update {
+            let changes: Vec<_> = event
+                .changes
+                .iter()
+                .map(|change| {
+                    Bson::Document(bson::doc! {
+                        "path": &change.path,
+                        "old": change.old.clone().unwrap_or(Bson::Null),
+                        "new": change.new.clone().unwrap_or(Bson::Null),
+                    })
+                })
+                .collect();
+            doc.insert("changes", changes);
+        }
+        if let Some(token) = event.token {
+            doc.insert("token", token as i64);
+        }
+        match self.options.format {
+            EventFormat::Json => Ok(Bson::Document(doc)
+                .into_relaxed_extjson()
+                .to_string()
+                .into_bytes()),
+            EventFormat::Bson => {
+                let mut bytes = Vec::new();
+                doc.to_writer(&mut bytes)
+                    .map_err(DatabaseError::BsonSerError)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// The token to resume from; none without an oplog.
+    async fn checkpoint(&self) -> Result<Option<u64>, DatabaseError> {
+        if self.db.oplog_range().await.is_err() {
+            return Ok(None);
+        }
+        let checkpoint = self
+            .db
+            .find_one(PUBLISHERS_COLLECTION.to_string(), self.options.name.clone())
+            .await?;
+        Ok(checkpoint
+            .and_then(|checkpoint| checkpoint.get_i64("token").ok())
+            .map(|token| token as u64))
+    }
+
+    async fn save_checkpoint(&self, token: u64) -> Result<(), DatabaseError> {
+        self.db
+            .put(
+                PUBLISHERS_COLLECTION.to_string(),
+                self.options.name.clone(),
+                bson::doc! { "token": token as i64 },
+            )
+            .await
+    }
+}
+
+fn operation_name(operation: OperationType) -> &'static str {
+    match operation {
+        OperationType::Insert => "insert",
+        OperationType::Update => "update",
+        OperationType::Delete => "delete",
+        OperationType::Expire => "expired",
+    }
+}
+
+/// A connection speaking the core NATS protocol.
+struct Connection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    async fn open(url: &str) -> Result<Self, DatabaseError> {
+        let address = url.strip_prefix("nats://").unwrap_or(url);
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| DatabaseError::Unavailable(format!("{}: {}", url, e)))?;
+        let (reader, writer) = stream.into_split();
+        let mut connection = Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        };
+
+        let info = connection.read_line().await?;
+        if !info.starts_with("INFO ") {
+            return Err(DatabaseError::Unavailable(format!(
+                "{} isn't a NATS server",
+                url
+            )));
+        }
+        let connect = bson::doc! {
+            "verbose": false,
+            "pedantic": false,
+            "name": "owldb",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+        };
+        let connect = format!(
+            "CONNECT {}\r\n",
+            Bson::Document(connect).into_relaxed_extjson()
+        );
+        connection.write(connect.as_bytes()).await?;
+        connection.flush().await?;
+        Ok(connection)
+    }
+
+    async fn publish(&mut self, subject: &str, payload: &[u8]) -> Result<(), DatabaseError> {
+        let mut message = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+        self.write(&message).await
+    }
+
+    /// Waits until the server has processed everything sent so far.
+    async fn flush(&mut self) -> Result<(), DatabaseError> {
+        self.write(b"PING\r\n").await?;
+        loop {
+            let line = self.read_line().await?;
+            if line == "PONG" {
+                return Ok(());
+            }
+            self.answer(&line).await?;
+        }
+    }
+
+    /// Handles a line the server sent on its own.
+    async fn answer(&mut self, line: &str) -> Result<(), DatabaseError> {
+        match line {
+            "PING" => self.write(b"PONG\r\n").await,
+            line if line.starts_with("-ERR") => Err(DatabaseError::ServerError(line.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// The next line from the server. Safe to cancel.
+    async fn read_line(&mut self) -> Result<String, DatabaseError> {
+        match self
+            .lines
+            .next_line()
+            .await
+            .map_err(DatabaseError::IoError)?
+        {
+            Some(line) => Ok(line.trim_end().to_string()),
+            None => Err(DatabaseError::Unavailable(
+                "the NATS server closed the connection".to_string(),
+            )),
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), DatabaseError> {
+        self.writer
+            .write_all(bytes)
+            .await
+            .map_err(DatabaseError::IoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Document;
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::db::{DatabaseOptions, OplogOptions};
+
+    /// Accepts connections like a NATS server, sending on each `PUB`'s
+    /// subject and payload; drops the first connection after one.
+    async fn serve(
+        listener: tokio::net::TcpListener,
+        published: mpsc::UnboundedSender<(String, Vec<u8>)>,
+    ) {
+        let mut connections = 0;
+        while let Ok((stream, _)) = listener.accept().await {
+            connections += 1;
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"INFO {}\r\n").await.unwrap();
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    break;
+                }
+                let mut parts = line.split_whitespace();
+                match parts.next() {
+                    Some("PING") => stream.get_mut().write_all(b"PONG\r\n").await.unwrap(),
+                    Some("PUB") => {
+                        let subject = parts.next().unwrap().to_string();
+                        let len: usize = parts.next().unwrap().parse().unwrap();
+                        let mut payload = vec![0; len + 2];
+                        stream.read_exact(&mut payload).await.unwrap();
+                        payload.truncate(len);
+                        published.send((subject, payload)).unwrap();
+                        if connections == 1 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nats_publisher() {
+        let folder_path = "data_tests/test_nats_publisher".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let db = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let (published, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(serve(listener, published));
+        let publisher = NatsPublisher::start(
+            db.clone(),
+            NatsOptions {
+                url,
+                format: EventFormat::Bson,
+                collections: vec!["users".to_string()],
+                checkpoint_interval: Duration::ZERO,
+                retry_delay: Duration::from_millis(20),
+                ..NatsOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        db.insert_one("notes".to_string(), bson::doc! { "text": "hi" })
+            .await
+            .unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        // The first connection drops after the insert, which may be sent
+        // again before the delete.
+        let mut subjects = Vec::new();
+        while subjects.last().map(String::as_str) != Some("owldb.users.delete") {
+            let (subject, payload) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let event = Document::from_reader(&mut &payload[..]).unwrap();
+            assert_eq!(event.get_str("_id"), Ok(id.as_str()));
+            subjects.push(subject);
+        }
+        assert_eq!(subjects[0], "owldb.users.insert");
+        assert!(subjects[1..subjects.len() - 1]
+            .iter()
+            .all(|subject| subject == "owldb.users.insert"));
+        publisher.stop().await;
+    }
+}
@@ -0,0 +1,575 @@
+//! Leader–follower replication, for a warm standby. A follower copies a
+//! leader's documents with the gRPC `Snapshot` call, then applies the
+//! leader's oplog with `Replicate` from where the copy was taken, and keeps
+//! doing so as the leader writes. Entries come in batches, acknowledged as
+//! they're applied, and a follower that falls behind holds the leader back
+//! from reading further ahead for it. Meanwhile its database refuses any other
+//! writes, until it's promoted.
+//!
+//! A follower that loses the leader reconnects and tails on from where it
+//! stopped; one that fell further behind than the leader's oplog reaches
+//! back copies everything again. Only the leader's default database is
+//! followed, unless `ClientOptions::database` names another.
+//!
+//! A delayed follower, with `ReplicationOptions::delay`, applies each entry
+//! only once it's that old, so a mistake on the leader can be caught before
+//! it reaches the copy. The leader's oplog has to reach back that far, and
+//! the initial copy isn't delayed.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::client::{Client, ClientOptions};
+use crate::db::{Database, DatabaseError};
+
+#[derive(Debug, Clone)]
+pub struct ReplicationOptions {
+    /// An admin's username and password on the leader, when it
+    /// authenticates.
+    pub credentials: Option<(String, String)>,
+    pub client: ClientOptions,
+    /// How long to wait before reconnecting to a lost leader.
+    pub retry_delay: Duration,
+    /// The most oplog entries the leader sends at once.
+    pub batch_size: u32,
+    /// The most entries the leader sends before the follower acknowledges
+    /// applying them; past that, it waits rather than read further ahead.
+    pub window: u32,
+    /// Apply each entry only once it's this old, by the leader's clock.
+    pub delay: Duration,
+    /// Names this server to the leader, which then holds the entries it
+    /// misses while away; unique among the leader's followers.
+    pub name: Option<String>,
+}
+
+impl Default for ReplicationOptions {
+    fn default() -> Self {
+        Self {
+            credentials: None,
+            client: ClientOptions {
+                connections: 1,
+                ..ClientOptions::default()
+            },
+            retry_delay: Duration::from_secs(1),
+            batch_size: 256,
+            window: 4096,
+            delay: Duration::ZERO,
+            name: None,
+        }
+    }
+}
+
+/// How a link to another server is faring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkHealth {
+    pub connected: bool,
+    /// The other server's oplog position to apply next.
+    pub position: u64,
+    /// Why the link was last lost.
+    pub last_error: Option<String>,
+    /// Documents copied so far, while making the initial copy.
+    pub copied: Option<u64>,
+}
+
+/// A link's health, kept up to date by the task following the other server.
+#[derive(Debug, Default)]
+pub(super) struct Link {
+    pub(super) position: AtomicU64,
+    connected: AtomicBool,
+    last_error: Mutex<Option<String>>,
+    copied: Mutex<Option<u64>>,
+}
+
+impl Link {
+    pub(super) fn connected(&self) {
+        self.connected.store(true, Ordering::Release);
+    }
+
+    pub(super) fn lost(&self, error: &DatabaseError) {
+        self.connected.store(false, Ordering::Release);
+        *self.last_error.lock().unwrap() = Some(format!("{:?}", error));
+    }
+
+    pub(super) fn health(&self) -> LinkHealth {
+        LinkHealth {
+            connected: self.connected.load(Ordering::Acquire),
+            position: self.position.load(Ordering::Acquire),
+            last_error: self.last_error.lock().unwrap().clone(),
+            copied: *self.copied.lock().unwrap(),
+        }
+    }
+}
+
+/// Handle to a database following a leader. Dropping it stops following,
+/// leaving the database a follower.
+pub struct Follower {
+    leader: String,
+    link: Arc<Link>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+    db: Database,
+}
+
+impl Follower {
+    /// Makes `db` a follower of the leader at `leader`, such as
+    /// `http://10.0.0.1:50051`, and starts copying it in the background.
+    pub fn start(db: Database, leader: &str, options: ReplicationOptions) -> Self {
+        db.set_follower(true);
+        let link = Arc::new(Link::default());
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let replicator = Replicator {
+            db: db.clone(),
+            leader: leader.to_string(),
+            options,
+            link: link.clone(),
+            shutdown: shutdown_rx,
+        };
+
+        Self {
+            leader: leader.to_string(),
+            link,
+            shutdown,
+            task: tokio::spawn(replicator.run()),
+            db,
+        }
+    }
+
+    /// The leader's oplog position the follower will apply next; 0 until
+    /// the initial copy is done.
+    pub fn position(&self) -> u64 {
+        self.link.position.load(Ordering::Acquire)
+    }
+
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    pub fn health(&self) -> LinkHealth {
+        self.link.health()
+    }
+
+    /// Stops following, leaving the database a read-only follower.
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Replication task failed: {}", e);
+        }
+    }
+
+    /// Stops following and lets the database take writes of its own.
+    pub async fn promote(self) {
+        let db = self.db.clone();
+        self.stop().await;
+        db.set_follower(false);
+        info!("Promoted follower to leader");
+    }
+}
+
+struct Replicator {
+    db: Database,
+    leader: String,
+    options: ReplicationOptions,
+    link: Arc<Link>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Replicator {
+    async fn run(mut self) {
+        loop {
+            let result = self.follow().await;
+            if let Err(e) = &result {
+                self.link.lost(e);
+            }
+            match result {
+                Ok(()) => return,
+                Err(DatabaseError::OplogTruncated(position)) => {
+                    warn!(
+                        "Leader's oplog no longer holds position {}, copying it again",
+                        position
+                    );
+                    self.link.position.store(0, Ordering::Release);
+                    continue;
+                }
+                Err(e) => warn!("Lost leader at {}: {:?}", self.leader, e),
+            }
+
+            let delay = tokio::time::sleep(self.options.retry_delay);
+            if or_stopped(&mut self.shutdown, delay).await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Follows the leader until stopped, or until something fails.
+    async fn follow(&mut self) -> Result<(), DatabaseError> {
+        let connect = Client::connect_with_options(&self.leader, self.options.client.clone());
+        let Some(client) = or_stopped(&mut self.shutdown, connect).await.transpose()? else {
+            return Ok(());
+        };
+        if let Some((username, password)) = self.options.credentials.clone() {
+            client.login(&username, &password).await?;
+        }
+
+        self.link.connected();
+
+        if self.link.position.load(Ordering::Acquire) == 0 {
+            let Some(position) = self.copy(&client).await? else {
+                return Ok(());
+            };
+            self.link.position.store(position, Ordering::Release);
+        }
+
+        let from = self.link.position.load(Ordering::Acquire);
+        let mut oplog = client
+            .replicate(
+                from,
+                self.options.batch_size,
+                self.options.window,
+                self.options.name.as_deref(),
+            )
+            .await?;
+        info!("Following {} from oplog position {}", self.leader, from);
+        loop {
+            let Some(batch) = or_stopped(&mut self.shutdown, oplog.next_batch())
+                .await
+                .transpose()?
+            else {
+                return Ok(());
+            };
+            let Some(batch) = batch else {
+                return Err(DatabaseError::Unavailable(
+                    "the leader ended the oplog".to_string(),
+                ));
+            };
+            for entry in batch {
+                let due = entry.timestamp.to_system_time() + self.options.delay;
+                if let Ok(wait) = due.duration_since(SystemTime::now()) {
+                    let delay = tokio::time::sleep(wait);
+                    if or_stopped(&mut self.shutdown, delay).await.is_none() {
+                        return Ok(());
+                    }
+                }
+                self.db
+                    .apply_replicated(entry.collection, entry.id, entry.document)
+                    .await?;
+                self.link
+                    .position
+                    .store(entry.position + 1, Ordering::Release);
+            }
+            oplog
+                .acknowledge(self.link.position.load(Ordering::Acquire) - 1)
+                .await?;
+        }
+    }
+
+    /// Makes the database a copy of the leader's snapshot, answering the
+    /// position to tail from, or `None` if stopped first.
+    async fn copy(&mut self, client: &Client) -> Result<Option<u64>, DatabaseError> {
+        let mut snapshot = client.snapshot().await?;
+        info!(
+            "Copying {} as of oplog position {}",
+            self.leader,
+            snapshot.position()
+        );
+
+        *self.link.copied.lock().unwrap() = Some(0);
+        let mut copied = HashSet::new();
+        loop {
+            let Some(next) = or_stopped(&mut self.shutdown, snapshot.next())
+                .await
+                .transpose()?
+            else {
+                return Ok(None);
+            };
+            let Some((collection, id, doc)) = next else {
+                break;
+            };
+            self.db
+                .apply_replicated(collection.clone(), id.clone(), Some(doc))
+                .await?;
+            copied.insert((collection, id));
+            *self.link.copied.lock().unwrap() = Some(copied.len() as u64);
+        }
+
+        // Left from an earlier copy, or written before becoming a follower.
+        let mut deleted = 0;
+        for collection in self.db.collections().await? {
+            let ids = self
+                .db
+                .find_with_ids(collection.clone(), bson::Document::new())
+                .await?;
+            for (id, _) in ids {
+                if !copied.contains(&(collection.clone(), id.clone())) {
+                    self.db
+                        .apply_replicated(collection.clone(), id, None)
+                        .await?;
+                    deleted += 1;
+                }
+            }
+        }
+
+        info!(
+            "Copied {} documents from {}, deleting {} it doesn't have",
+            copied.len(),
+            self.leader,
+            deleted
+        );
+        *self.link.copied.lock().unwrap() = None;
+        Ok(Some(snapshot.position()))
+    }
+}
+
+/// Waits for `future`, or gives `None` if the follower is stopped first.
+/// Writes aren't raced against this, so none is left half made.
+pub(super) async fn or_stopped<T>(
+    shutdown: &mut watch::Receiver<bool>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = shutdown.wait_for(|stopped| *stopped) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+    use crate::db::{DatabaseOptions, OplogOptions};
+    use crate::server::auth::Auth;
+    use crate::server::grpc;
+    use crate::server::tenants::Tenants;
+
+    async fn converged(leader: &Database, follower: &Database) -> bool {
+        for _ in 0..100 {
+            let leader_docs = leader
+                .find_with_ids("users".to_string(), bson::doc! {})
+                .await
+                .unwrap();
+            let follower_docs = follower
+                .find_with_ids("users".to_string(), bson::doc! {})
+                .await
+                .unwrap_or_default();
+            if leader_docs == follower_docs {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_follow_leader() {
+        let mut opened = Vec::new();
+        for name in ["leader", "follower"] {
+            let folder_path = format!("data_tests/test_replication_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions::default()),
+                ..DatabaseOptions::default()
+            };
+            opened.push(
+                Database::init_with_options(folder_path, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let follower_db = opened.pop().unwrap();
+        let leader = opened.pop().unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(grpc::service(Tenants::single(
+                    leader.clone(),
+                    Auth::disabled(),
+                )))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let john = leader
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        // Not on the leader, so dropped by the initial copy.
+        follower_db
+            .insert_one("users".to_string(), bson::doc! { "name": "Stale" })
+            .await
+            .unwrap();
+
+        let follower = Follower::start(
+            follower_db.clone(),
+            &format!("http://{}", addr),
+            ReplicationOptions::default(),
+        );
+        assert!(converged(&leader, &follower_db).await);
+        assert!(matches!(
+            follower_db
+                .insert_one("users".to_string(), bson::doc! { "name": "Bob" })
+                .await,
+            Err(DatabaseError::ReadOnly)
+        ));
+
+        leader
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        leader
+            .update_one(
+                "users".to_string(),
+                john.clone(),
+                bson::doc! { "$set": { "age": 30 } },
+            )
+            .await
+            .unwrap();
+        assert!(converged(&leader, &follower_db).await);
+        leader
+            .delete_one("users".to_string(), john.clone())
+            .await
+            .unwrap();
+        assert!(converged(&leader, &follower_db).await);
+        assert_eq!(follower.position(), leader.oplog_range().await.unwrap().end);
+        assert_eq!(follower.health().copied, None);
+
+        // The leader hears of the follower's progress as it acknowledges.
+        let mut oplog = leader.stats().await.unwrap().oplog.unwrap();
+        while oplog.followers[0].operations > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            oplog = leader.stats().await.unwrap().oplog.unwrap();
+        }
+        assert_eq!(oplog.followers.len(), 1);
+        assert_eq!(oplog.followers[0].acknowledged, oplog.next - 1);
+        assert_eq!(oplog.followers[0].behind, Duration::ZERO);
+        assert_eq!(oplog.first, 1);
+
+        follower.promote().await;
+        follower_db
+            .insert_one("users".to_string(), bson::doc! { "name": "Bob" })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delayed_follower() {
+        let mut opened = Vec::new();
+        for name in ["leader", "follower"] {
+            let folder_path = format!("data_tests/test_replication_delayed_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            let options = DatabaseOptions {
+                oplog: Some(OplogOptions::default()),
+                ..DatabaseOptions::default()
+            };
+            opened.push(
+                Database::init_with_options(folder_path, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let follower_db = opened.pop().unwrap();
+        let leader = opened.pop().unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(grpc::service(Tenants::single(
+                    leader.clone(),
+                    Auth::disabled(),
+                )))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let follower = Follower::start(
+            follower_db.clone(),
+            &format!("http://{}", addr),
+            ReplicationOptions {
+                delay: Duration::from_millis(500),
+                ..ReplicationOptions::default()
+            },
+        );
+        // The copy isn't delayed.
+        while follower.position() == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        leader
+            .insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(follower_db
+            .find_with_ids("users".to_string(), bson::doc! {})
+            .await
+            .unwrap_or_default()
+            .is_empty());
+        assert!(converged(&leader, &follower_db).await);
+        follower.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_stream_stalls_at_window() {
+        let folder_path = "data_tests/test_replication_window".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let options = DatabaseOptions {
+            oplog: Some(OplogOptions::default()),
+            ..DatabaseOptions::default()
+        };
+        let leader = Database::init_with_options(folder_path, options)
+            .await
+            .unwrap();
+        for n in 0..20 {
+            leader
+                .insert_one("users".to_string(), bson::doc! { "n": n })
+                .await
+                .unwrap();
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(grpc::service(Tenants::single(
+                    leader.clone(),
+                    Auth::disabled(),
+                )))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let client = Client::connect(&format!("http://{}", addr)).await.unwrap();
+        let mut oplog = client.replicate(1, 4, 10, None).await.unwrap();
+        let stalled = Duration::from_millis(300);
+
+        // Without acknowledgements, the leader sends the window and no more.
+        let mut received = Vec::new();
+        while received.len() < 10 {
+            let batch = oplog.next_batch().await.unwrap().unwrap();
+            assert!(batch.len() <= 4);
+            received.extend(batch.into_iter().map(|entry| entry.position));
+        }
+        assert_eq!(received, (1..=10).collect::<Vec<_>>());
+        assert!(tokio::time::timeout(stalled, oplog.next_batch())
+            .await
+            .is_err());
+
+        // Acknowledging part of it makes just that much room.
+        oplog.acknowledge(6).await.unwrap();
+        let mut received = Vec::new();
+        while received.len() < 6 {
+            let batch = oplog.next_batch().await.unwrap().unwrap();
+            received.extend(batch.into_iter().map(|entry| entry.position));
+        }
+        assert_eq!(received, (11..=16).collect::<Vec<_>>());
+        assert!(tokio::time::timeout(stalled, oplog.next_batch())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,693 @@
+//! A Redis (RESP2) front end that stores string keys as documents, so Redis
+//! clients can use owldb as a persistent key-value store.
+//!
+//! Each key is a `{ key, value }` document in the `kv` collection. Values
+//! that are valid UTF-8 are stored as strings, anything else as binary.
+//! Supported commands: PING, ECHO, GET, SET (with NX/XX), DEL, EXISTS, KEYS,
+//! SCAN, DBSIZE, SELECT and QUIT. Expiry is not.
+//!
+//! Connections start on the default tenant (see `owldb::server::tenants`);
+//! `SELECT <name>` switches to another one and logs the connection out,
+//! while `SELECT 0` keeps the current one. With authentication enabled,
+//! clients then send `AUTH <username> <password>`, which logs in to the
+//! selected tenant.
+//!
+//! Commands that fail inside the server answer with the ID their request
+//! was logged under (see `owldb::server::trace`).
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson, Document};
+use log::{error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+use crate::db::{Database, DatabaseError};
+use crate::server::audit::AuditEvent;
+use crate::server::auth::Principal;
+use crate::server::limits::{client_key, Limits};
+use crate::server::roles::Access;
+use crate::server::tenants::{Tenant, Tenants};
+#[cfg(feature = "tls")]
+use crate::server::tls::TlsListener;
+use crate::server::trace::{self, RequestTrace};
+
+/// The collection holding every key.
+pub const COLLECTION: &str = "kv";
+
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serves the tenants on `addr` until `limits` are shut down.
+pub async fn serve(tenants: Tenants, addr: SocketAddr, limits: Limits) -> std::io::Result<()> {
+    serve_with_listener(tenants, TcpListener::bind(addr).await?, limits).await
+}
+
+pub async fn serve_with_listener(
+    tenants: Tenants,
+    listener: TcpListener,
+    limits: Limits,
+) -> std::io::Result<()> {
+    info!("Listening for Redis clients on {}", listener.local_addr()?);
+
+    let server = Server::new(tenants, &limits);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = limits.shutting_down() => return Ok(()),
+        };
+        if let Some(stream) = limits.admit(stream, peer) {
+            server.spawn(stream, peer);
+        }
+    }
+}
+
+/// Like `serve_with_listener`, for clients connecting over TLS.
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    tenants: Tenants,
+    mut listener: TlsListener,
+    limits: Limits,
+) -> std::io::Result<()> {
+    info!(
+        "Listening for Redis clients over TLS on {}",
+        listener.local_addr()
+    );
+
+    let server = Server::new(tenants, &limits);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = limits.shutting_down() => return Ok(()),
+        };
+        if let Some(stream) = limits.admit(stream, peer) {
+            server.spawn(stream, peer);
+        }
+    }
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+struct Server {
+    tenants: Tenants,
+    limits: Limits,
+    /// Longest command accepted, counting its framing.
+    max_command_size: usize,
+    /// Serializes SET and DEL, which look a key up before writing it.
+    writes: Mutex<()>,
+}
+
+impl Server {
+    fn new(tenants: Tenants, limits: &Limits) -> Arc<Self> {
+        Arc::new(Server {
+            tenants,
+            max_command_size: limits.options().max_request_size,
+            limits: limits.clone(),
+            writes: Mutex::new(()),
+        })
+    }
+
+    fn spawn<S>(self: &Arc<Self>, stream: S, peer: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle_connection(stream, peer).await {
+                warn!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    async fn handle_connection<S>(&self, stream: S, peer: SocketAddr) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let mut tenant = self.tenants.default_tenant().clone();
+        // Logged in from the start when authentication is disabled.
+        let mut principal = tenant.auth().authenticate(None).ok();
+
+        // Shutting down closes the connection between commands.
+        while let Some(command) = tokio::select! {
+            command = read_command(&mut stream, self.max_command_size) => command?,
+            _ = self.limits.shutting_down() => None,
+        } {
+            if command.is_empty() {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&command[0]).to_ascii_uppercase();
+            let trace = RequestTrace::new(None);
+
+            let username = principal.as_ref().map(|p| p.username.as_str());
+            let result =
+                match self
+                    .limits
+                    .check_rate(&client_key(tenant.name(), username, Some(peer.ip())))
+                {
+                    Ok(()) => {
+                        let run = self.run(&name, &command[1..], &mut tenant, &mut principal);
+                        trace.clone().scope(run).await
+                    }
+                    Err(e) => Err(e),
+                };
+
+            let reply = match result {
+                Ok(reply) => reply,
+                Err(DatabaseError::Unauthenticated) => Reply::Error(
+                    "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                ),
+                Err(DatabaseError::PermissionDenied(message)) => {
+                    Reply::Error(format!("NOPERM {}", message))
+                }
+                Err(DatabaseError::RateLimited(wait)) => Reply::Error(format!(
+                    "ERR rate limit exceeded, retry in {} ms",
+                    wait.as_millis().max(1)
+                )),
+                Err(DatabaseError::DatabaseNotFound(name)) => {
+                    Reply::Error(format!("ERR no database named '{}'", name))
+                }
+                Err(DatabaseError::QuotaExceeded(message)) => {
+                    Reply::Error(format!("OOM {}", message))
+                }
+                Err(DatabaseError::InvalidName(invalid)) => {
+                    Reply::Error(format!("ERR invalid name '{}'", invalid))
+                }
+                Err(e) => {
+                    error!("[{}] Failed to run {}: {:?}", trace.id(), name, e);
+                    Reply::Error(format!("ERR {:?} (request {})", e, trace.id()))
+                }
+            };
+            if name != "PING" {
+                let outcome = match &reply {
+                    Reply::Error(message) => message.split(' ').next().unwrap_or_default(),
+                    _ => "OK",
+                };
+                trace.finish(&format!("redis {}", name), outcome);
+            }
+
+            let mut out = Vec::new();
+            reply.encode(&mut out);
+            stream.get_mut().write_all(&out).await?;
+
+            if name == "QUIT" {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a command once the connection is logged in and allowed to use
+    /// the key-value collection of its tenant.
+    async fn run(
+        &self,
+        name: &str,
+        args: &[Vec<u8>],
+        tenant: &mut Tenant,
+        principal: &mut Option<Principal>,
+    ) -> Result<Reply, DatabaseError> {
+        match (name, args) {
+            ("AUTH", [username, password]) => {
+                let username = String::from_utf8_lossy(username);
+                let password = String::from_utf8_lossy(password);
+                let verify = tenant.auth().verify_password(&username, &password);
+                *principal = Some(trace::stage("auth", verify).await?);
+                return Ok(Reply::ok());
+            }
+            // Clients select database 0 while connecting, which keeps the
+            // current tenant.
+            ("SELECT", [index]) if index.as_slice() == b"0" => return Ok(Reply::ok()),
+            ("SELECT", [database]) => {
+                let database = String::from_utf8_lossy(database);
+                if database != tenant.name() {
+                    *tenant = self.tenants.get(&database)?.clone();
+                    *principal = tenant.auth().authenticate(None).ok();
+                }
+                return Ok(Reply::ok());
+            }
+            ("AUTH", _) => {
+                return Ok(Reply::Error(
+                    "ERR AUTH takes a username and a password".to_string(),
+                ))
+            }
+            ("QUIT", _) => return self.execute(tenant.db(), name, args).await,
+            _ => {}
+        }
+
+        let Some(principal) = principal else {
+            return Ok(Reply::Error("NOAUTH Authentication required.".to_string()));
+        };
+        let access = match name {
+            "SET" | "DEL" | "UNLINK" => Access::Write,
+            _ => Access::Read,
+        };
+        trace::stage_sync("auth", || {
+            tenant.auth().authorize(principal, COLLECTION, access)
+        })?;
+        if let Some(event) = audit_event(name, args) {
+            tenant.audit(principal, event).await?;
+        }
+        if name == "SET" {
+            tenant.check_quota().await?;
+        }
+
+        trace::stage("db", self.execute(tenant.db(), name, args)).await
+    }
+
+    async fn execute(
+        &self,
+        db: &Database,
+        name: &str,
+        args: &[Vec<u8>],
+    ) -> Result<Reply, DatabaseError> {
+        let reply = match (name, args) {
+            ("PING", []) => Reply::Simple("PONG"),
+            ("PING", [message]) | ("ECHO", [message]) => Reply::Bulk(Some(message.clone())),
+            ("GET", [key]) => Reply::Bulk(self.get(db, key).await?.map(|(_, value)| value)),
+            ("SET", [key, value, options @ ..]) => self.set(db, key, value, options).await?,
+            ("DEL" | "UNLINK", keys) if !keys.is_empty() => {
+                let _writes = self.writes.lock().await;
+                let mut deleted = 0;
+                for key in keys {
+                    if let Some((id, _)) = self.get(db, key).await? {
+                        db.delete_one(COLLECTION.to_string(), id).await?;
+                        deleted += 1;
+                    }
+                }
+                Reply::Integer(deleted)
+            }
+            ("EXISTS", keys) if !keys.is_empty() => {
+                let mut found = 0;
+                for key in keys {
+                    if self.get(db, key).await?.is_some() {
+                        found += 1;
+                    }
+                }
+                Reply::Integer(found)
+            }
+            ("KEYS", [pattern]) => Reply::Array(
+                self.keys(db)
+                    .await?
+                    .into_iter()
+                    .filter(|key| glob_match(pattern, key))
+                    .map(|key| Reply::Bulk(Some(key)))
+                    .collect(),
+            ),
+            ("SCAN", [cursor, options @ ..]) => self.scan(db, cursor, options).await?,
+            ("DBSIZE", []) => Reply::Integer(self.keys(db).await?.len() as i64),
+            ("QUIT", []) => Reply::ok(),
+            // Clients send these while connecting; there is nothing to set.
+            ("CLIENT", [_, ..]) => Reply::ok(),
+            ("COMMAND", _) => Reply::Array(Vec::new()),
+            (
+                "PING" | "ECHO" | "GET" | "SET" | "DEL" | "UNLINK" | "EXISTS" | "KEYS" | "SCAN"
+                | "DBSIZE" | "SELECT" | "QUIT",
+                _,
+            ) => Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )),
+            _ => Reply::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )),
+        };
+
+        Ok(reply)
+    }
+
+    async fn set(
+        &self,
+        db: &Database,
+        key: &[u8],
+        value: &[u8],
+        options: &[Vec<u8>],
+    ) -> Result<Reply, DatabaseError> {
+        let mut only_new = false;
+        let mut only_existing = false;
+        for option in options {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" => only_new = true,
+                b"XX" => only_existing = true,
+                _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+            }
+        }
+        if only_new && only_existing {
+            return Ok(Reply::Error("ERR syntax error".to_string()));
+        }
+
+        let _writes = self.writes.lock().await;
+        let doc = bson::doc! { "key": to_bson(key), "value": to_bson(value) };
+        match self.get(db, key).await? {
+            Some(_) if only_new => return Ok(Reply::Bulk(None)),
+            None if only_existing => return Ok(Reply::Bulk(None)),
+            Some((id, _)) => {
+                db.update_one(COLLECTION.to_string(), id, doc).await?;
+            }
+            None => {
+                db.insert_one(COLLECTION.to_string(), doc).await?;
+            }
+        }
+
+        Ok(Reply::ok())
+    }
+
+    /// Walks the keys in sorted order; the cursor is the position to resume
+    /// from, so keys written mid-scan may be skipped or returned twice, as
+    /// Redis allows.
+    async fn scan(
+        &self,
+        db: &Database,
+        cursor: &[u8],
+        options: &[Vec<u8>],
+    ) -> Result<Reply, DatabaseError> {
+        let Some(cursor) = parse_number(cursor) else {
+            return Ok(Reply::Error("ERR invalid cursor".to_string()));
+        };
+
+        let mut pattern: &[u8] = b"*";
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_uppercase().as_slice(), options.next()) {
+                (b"MATCH", Some(value)) => pattern = value,
+                (b"COUNT", Some(value)) => match parse_number(value) {
+                    Some(n) if n > 0 => count = n,
+                    _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+                },
+                _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+            }
+        }
+
+        let mut keys = self.keys(db).await?;
+        keys.sort();
+        let end = cursor.saturating_add(count).min(keys.len());
+        let next = if end == keys.len() { 0 } else { end };
+
+        let batch = keys
+            .get(cursor..end)
+            .unwrap_or_default()
+            .iter()
+            .filter(|key| glob_match(pattern, key))
+            .map(|key| Reply::Bulk(Some(key.clone())))
+            .collect();
+
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(batch),
+        ]))
+    }
+
+    /// The id and value stored under `key`, if any.
+    async fn get(
+        &self,
+        db: &Database,
+        key: &[u8],
+    ) -> Result<Option<(String, Vec<u8>)>, DatabaseError> {
+        let query = bson::doc! { "key": to_bson(key) };
+        Ok(self
+            .entries(db, query)
+            .await?
+            .into_iter()
+            .next()
+            .and_then(|(id, doc)| Some((id, from_bson(doc.get("value")?)?))))
+    }
+
+    async fn keys(&self, db: &Database) -> Result<Vec<Vec<u8>>, DatabaseError> {
+        Ok(self
+            .entries(db, Document::new())
+            .await?
+            .iter()
+            .filter_map(|(_, doc)| from_bson(doc.get("key")?))
+            .collect())
+    }
+
+    async fn entries(
+        &self,
+        db: &Database,
+        query: Document,
+    ) -> Result<Vec<(String, Document)>, DatabaseError> {
+        match db.find_with_ids(COLLECTION.to_string(), query).await {
+            Ok(entries) => Ok(entries),
+            // Nothing was ever set, so there is no directory to scan.
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn to_bson(bytes: &[u8]) -> Bson {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Bson::String(s.to_string()),
+        Err(_) => Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: bytes.to_vec(),
+        }),
+    }
+}
+
+fn from_bson(value: &Bson) -> Option<Vec<u8>> {
+    match value {
+        Bson::String(s) => Some(s.clone().into_bytes()),
+        Bson::Binary(binary) => Some(binary.bytes.clone()),
+        _ => None,
+    }
+}
+
+fn parse_number(bytes: &[u8]) -> Option<usize> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// How a data command is audited: by name, with the keys it names.
+fn audit_event(name: &str, args: &[Vec<u8>]) -> Option<AuditEvent> {
+    let keys: &[Vec<u8>] = match name {
+        "GET" | "DEL" | "UNLINK" | "EXISTS" => args,
+        "SET" => args.get(..1).unwrap_or_default(),
+        "KEYS" | "SCAN" | "DBSIZE" => &[],
+        _ => return None,
+    };
+    let event = AuditEvent::new(&name.to_ascii_lowercase(), COLLECTION);
+    if keys.is_empty() {
+        return Some(event);
+    }
+
+    let keys: Vec<String> = keys
+        .iter()
+        .map(|key| String::from_utf8_lossy(key).into_owned())
+        .collect();
+    Some(event.with_filter(bson::doc! { "key": { "$in": keys } }))
+}
+
+/// Redis-style glob: `*`, `?` and `\` escapes. Character classes are
+/// matched literally.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+fn protocol_error(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads one command: a RESP array of bulk strings, or an inline command
+/// line as typed into telnet. `None` once the client hangs up. Commands
+/// longer than `max_size` bytes fail the connection.
+async fn read_command<S>(
+    stream: &mut BufReader<S>,
+    max_size: usize,
+) -> std::io::Result<Option<Vec<Vec<u8>>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut remaining = max_size;
+    let Some(line) = read_line(stream, &mut remaining).await? else {
+        return Ok(None);
+    };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+
+    let count = parse_number(count)
+        // Each argument takes at least six bytes, as `$0\r\n\r\n`.
+        .filter(|count| *count <= MAX_ARGUMENTS && count * 6 <= remaining)
+        .ok_or_else(|| protocol_error("invalid multibulk length"))?;
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(stream, &mut remaining)
+            .await?
+            .ok_or_else(|| protocol_error("unexpected end of command"))?;
+        let length = line
+            .strip_prefix(b"$")
+            .and_then(parse_number)
+            .ok_or_else(|| protocol_error("invalid bulk length"))?;
+        remaining = remaining
+            .checked_sub(length + 2)
+            .ok_or_else(|| protocol_error("command too large"))?;
+
+        let mut arg = vec![0u8; length + 2];
+        stream.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated"));
+        }
+        arg.truncate(length);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// Reads a line of at most `remaining` bytes, and takes them off it.
+async fn read_line<S>(
+    stream: &mut BufReader<S>,
+    remaining: &mut usize,
+) -> std::io::Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    let read = (&mut *stream)
+        .take(*remaining as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') && read == *remaining {
+        return Err(protocol_error("command too large"));
+    }
+    *remaining -= read;
+    while matches!(line.last(), Some(b'\n' | b'\r')) {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::server::auth::Auth;
+
+    async fn exchange(stream: &mut TcpStream, args: &[&str], expected: &str) {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        stream.write_all(command.as_bytes()).await.unwrap();
+
+        let mut reply = vec![0u8; expected.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected, "{:?}", args);
+    }
+
+    #[tokio::test]
+    async fn test_key_value_commands() {
+        let folder_path = "data_tests/test_resp".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_with_listener(
+            Tenants::single(db.clone(), Auth::disabled()),
+            listener,
+            Limits::default(),
+        ));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        exchange(&mut stream, &["PING"], "+PONG\r\n").await;
+        exchange(&mut stream, &["GET", "user:1"], "$-1\r\n").await;
+        exchange(&mut stream, &["SET", "user:1", "John"], "+OK\r\n").await;
+        exchange(&mut stream, &["SET", "user:1", "Jane", "NX"], "$-1\r\n").await;
+        exchange(&mut stream, &["set", "user:1", "Jane"], "+OK\r\n").await;
+        exchange(&mut stream, &["SET", "user:2", "Bob"], "+OK\r\n").await;
+        exchange(&mut stream, &["GET", "user:1"], "$4\r\nJane\r\n").await;
+        exchange(&mut stream, &["EXISTS", "user:1", "user:3"], ":1\r\n").await;
+        exchange(
+            &mut stream,
+            &["SCAN", "0", "MATCH", "user:*", "COUNT", "1"],
+            "*2\r\n$1\r\n1\r\n*1\r\n$6\r\nuser:1\r\n",
+        )
+        .await;
+        exchange(
+            &mut stream,
+            &["SCAN", "1", "MATCH", "user:*"],
+            "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:2\r\n",
+        )
+        .await;
+        exchange(&mut stream, &["DEL", "user:1", "user:3"], ":1\r\n").await;
+        exchange(&mut stream, &["DBSIZE"], ":1\r\n").await;
+        exchange(&mut stream, &["SELECT", "0"], "+OK\r\n").await;
+        exchange(
+            &mut stream,
+            &["SELECT", "acme"],
+            "-ERR no database named 'acme'\r\n",
+        )
+        .await;
+        exchange(
+            &mut stream,
+            &["FLUSHALL"],
+            "-ERR unknown command 'flushall'\r\n",
+        )
+        .await;
+
+        let stored = db
+            .find(COLLECTION.to_string(), bson::doc! {})
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![bson::doc! { "key": "user:2", "value": "Bob" }]);
+    }
+}
@@ -0,0 +1,155 @@
+//! Roles granted to users, on the whole database or on one collection.
+//!
+//! - `read` may read documents and watch changes.
+//! - `insert` may only insert documents, as a device pushing telemetry
+//!   needs.
+//! - `readWrite` may read, insert, update and delete.
+//! - `dbAdmin` may do anything; on the whole database it also manages users
+//!   and their grants.
+
+use bson::Document;
+
+use crate::db::DatabaseError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Read,
+    Insert,
+    ReadWrite,
+    DbAdmin,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Insert => "insert",
+            Role::ReadWrite => "readWrite",
+            Role::DbAdmin => "dbAdmin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Role::Read),
+            "insert" => Some(Role::Insert),
+            "readWrite" => Some(Role::ReadWrite),
+            "dbAdmin" => Some(Role::DbAdmin),
+            _ => None,
+        }
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        match self {
+            Role::Read => access == Access::Read,
+            Role::Insert => access == Access::Insert,
+            Role::ReadWrite | Role::DbAdmin => true,
+        }
+    }
+}
+
+/// What a request does to a collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Adds new documents, without touching existing ones.
+    Insert,
+    /// Updates or deletes documents, or may overwrite them.
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub role: Role,
+    /// The collection the role applies to; `None` for all of them.
+    pub collection: Option<String>,
+}
+
+impl Grant {
+    pub fn database(role: Role) -> Self {
+        Self {
+            role,
+            collection: None,
+        }
+    }
+
+    pub fn collection(role: Role, collection: String) -> Self {
+        Self {
+            role,
+            collection: Some(collection),
+        }
+    }
+
+    pub(crate) fn allows(&self, collection: &str, access: Access) -> bool {
+        let covered = match &self.collection {
+            Some(granted) => granted == collection,
+            None => true,
+        };
+        covered && self.role.allows(access)
+    }
+
+    /// Whether this grant lets its holder manage users.
+    pub(crate) fn is_admin(&self) -> bool {
+        self.role == Role::DbAdmin && self.collection.is_none()
+    }
+
+    pub(crate) fn to_document(&self) -> Document {
+        let mut doc = bson::doc! { "role": self.role.name() };
+        if let Some(collection) = &self.collection {
+            doc.insert("collection", collection);
+        }
+        doc
+    }
+
+    pub(crate) fn from_document(doc: &Document) -> Result<Self, DatabaseError> {
+        let role = doc
+            .get_str("role")
+            .ok()
+            .and_then(Role::from_name)
+            .ok_or_else(|| {
+                DatabaseError::InvalidUpdate(
+                    "role must be one of read, insert, readWrite or dbAdmin".to_string(),
+                )
+            })?;
+
+        match doc.get("collection") {
+            None | Some(bson::Bson::Null) => Ok(Grant::database(role)),
+            Some(bson::Bson::String(collection)) => Ok(Grant::collection(role, collection.clone())),
+            Some(_) => Err(DatabaseError::InvalidUpdate(
+                "collection must be a string".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grants() {
+        let analyst = Grant::collection(Role::Read, "events".to_string());
+        assert!(analyst.allows("events", Access::Read));
+        assert!(!analyst.allows("events", Access::Write));
+        assert!(!analyst.allows("users", Access::Read));
+        assert!(!analyst.is_admin());
+
+        let sensor = Grant::collection(Role::Insert, "telemetry".to_string());
+        assert!(sensor.allows("telemetry", Access::Insert));
+        assert!(!sensor.allows("telemetry", Access::Read));
+        assert!(!sensor.allows("telemetry", Access::Write));
+
+        let writer = Grant::database(Role::ReadWrite);
+        assert!(writer.allows("users", Access::Write));
+        assert!(writer.allows("users", Access::Insert));
+        assert!(!writer.is_admin());
+        assert!(Grant::database(Role::DbAdmin).is_admin());
+        assert!(!Grant::collection(Role::DbAdmin, "users".to_string()).is_admin());
+
+        assert_eq!(
+            Grant::from_document(&analyst.to_document()).unwrap(),
+            analyst
+        );
+        assert!(Grant::from_document(&bson::doc! { "role": "root" }).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Running the network front ends as a standalone server, as both the
+//! `owldb-server` binary and `owldb serve` do: the databases `config`
+//! names are opened and served until ctrl-c or SIGTERM, then drained and
+//! closed.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use tokio::task::JoinSet;
+
+use super::audit::Audit;
+use super::auth::Auth;
+use super::config::ServerConfig;
+use super::limits::Limits;
+use super::tenants::{Tenant, TenantOptions, Tenants, DEFAULT_TENANT};
+use super::webhooks::Webhooks;
+use crate::db::{Database, DatabaseOptions};
+
+/// How long open connections get to finish once shutting down.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[cfg(feature = "tls")]
+type Tls = super::tls::TlsOptions;
+#[cfg(not(feature = "tls"))]
+type Tls = std::convert::Infallible;
+
+/// Opens a tenant's database, with its own users when authentication is on.
+async fn open_tenant(
+    name: String,
+    folder: String,
+    options: DatabaseOptions,
+    auth: bool,
+    tenant_options: TenantOptions,
+) -> Result<Tenant, String> {
+    let database = Database::init_with_options(folder, options)
+        .await
+        .map_err(|e| format!("Failed to open database '{}': {:?}", name, e))?;
+    let auth = match auth {
+        true => Auth::enable(database.clone())
+            .await
+            .map_err(|e| format!("Failed to load users of '{}': {:?}", name, e))?,
+        false => Auth::disabled(),
+    };
+
+    Ok(Tenant::new(name, database, auth, tenant_options))
+}
+
+/// Resolves on ctrl-c, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Starts a front end on `addr`, over TLS when it's configured, passing
+/// any further arguments on to its `serve`.
+macro_rules! spawn_server {
+    ($servers:expr, $module:ident, $tenants:expr, $addr:expr, $tls:expr, $limits:expr $(, $extra:expr)*) => {{
+        let tenants = $tenants.clone();
+        let limits = $limits.clone();
+        let addr: SocketAddr = $addr;
+        match $tls {
+            #[cfg(feature = "tls")]
+            Some(options) => {
+                let listener = super::tls::TlsListener::bind(addr, options).await?;
+                $servers.spawn(async move {
+                    super::$module::serve_tls(tenants, listener, limits $(, $extra)*)
+                        .await
+                        .map_err(|e| e.to_string())
+                });
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(never) => match *never {},
+            None => {
+                $servers.spawn(async move {
+                    super::$module::serve(tenants, addr, limits $(, $extra)*)
+                        .await
+                        .map_err(|e| e.to_string())
+                });
+            }
+        }
+    }};
+}
+
+/// Serves the databases `config` names with the front ends it enables,
+/// until a signal comes or a front end fails.
+pub async fn run(config: ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    config
+        .validate()
+        .map_err(|e| format!("Invalid configuration: {:?}", e))?;
+
+    let listen = &config.listen;
+    let unsupported = [
+        (listen.grpc.is_some() && !cfg!(feature = "grpc"), "grpc"),
+        (listen.mongo.is_some() && !cfg!(feature = "mongo"), "mongo"),
+        (listen.resp.is_some() && !cfg!(feature = "resp"), "resp"),
+        (config.tls.cert.is_some() && !cfg!(feature = "tls"), "tls"),
+        (config.nats.url.is_some() && !cfg!(feature = "nats"), "nats"),
+        (
+            (config.replication.leader.is_some() || !config.replication.peers.is_empty())
+                && !cfg!(feature = "client"),
+            "client",
+        ),
+    ];
+    if let Some((_, feature)) = unsupported.iter().find(|(unsupported, _)| *unsupported) {
+        return Err(format!("Built without the '{}' feature", feature).into());
+    }
+
+    #[cfg(feature = "tls")]
+    let tls: Option<Tls> = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert_path), Some(key_path)) => Some(Tls {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: config.tls.client_ca.as_ref().map(Into::into),
+        }),
+        _ => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    let tls: Option<Tls> = None;
+
+    let addr: SocketAddr = config.listen.http.parse()?;
+    let limits = Limits::new(config.limit_options());
+    let options = config.database_options();
+
+    let audit = match (&config.audit.file, config.audit.collection) {
+        (Some(path), _) => Audit::to_file(path)
+            .await
+            .map_err(|e| format!("Failed to open audit log: {:?}", e))?,
+        (None, true) => Audit::to_collection(),
+        (None, false) => Audit::disabled(),
+    };
+    let tenant_options = TenantOptions {
+        quota_bytes: None,
+        audit,
+        cursor_timeout: Duration::from_secs(config.limits.cursor_timeout_secs),
+    };
+
+    let auth = config.auth.enabled;
+    let default = open_tenant(
+        DEFAULT_TENANT.to_string(),
+        config.data.clone(),
+        options.clone(),
+        auth,
+        TenantOptions {
+            quota_bytes: config.quota,
+            ..tenant_options.clone()
+        },
+    )
+    .await?;
+    let mut others = Vec::new();
+    for database in config.databases.clone() {
+        others.push(
+            open_tenant(
+                database.name,
+                database.data,
+                options.clone(),
+                auth,
+                TenantOptions {
+                    quota_bytes: database.quota,
+                    ..tenant_options.clone()
+                },
+            )
+            .await?,
+        );
+    }
+    let tenants = Tenants::new(default, others);
+
+    let webhooks: Vec<Webhooks> = tenants
+        .iter()
+        .filter_map(|tenant| {
+            let options: Vec<_> = config
+                .webhook_options()
+                .into_iter()
+                .filter(|(database, _)| database == tenant.name())
+                .map(|(_, options)| options)
+                .collect();
+            (!options.is_empty()).then(|| Webhooks::start(tenant.db().clone(), options))
+        })
+        .collect();
+
+    #[cfg(feature = "client")]
+    let cluster = {
+        use super::cluster::{Cluster, ClusterOptions};
+        use super::replication::ReplicationOptions;
+        use crate::client::ClientOptions;
+
+        let replication = &config.replication;
+        let options = ReplicationOptions {
+            credentials: replication
+                .username
+                .clone()
+                .zip(replication.password.clone()),
+            client: ClientOptions {
+                database: replication.database.clone(),
+                ..ReplicationOptions::default().client
+            },
+            delay: Duration::from_secs(replication.delay_secs),
+            name: replication.node.clone(),
+            ..ReplicationOptions::default()
+        };
+        let options = ClusterOptions {
+            node: replication.node.clone(),
+            leader: replication.leader.clone(),
+            peers: replication.peers.clone(),
+            replication: options,
+        };
+        let cluster = Cluster::start(tenants.default_tenant().db().clone(), options);
+        tenants.set_cluster(cluster.clone());
+        cluster
+    };
+
+    #[cfg(feature = "nats")]
+    let nats = match &config.nats.url {
+        Some(url) => {
+            use super::nats::{EventFormat, NatsOptions, NatsPublisher};
+
+            let options = NatsOptions {
+                url: url.clone(),
+                subject_prefix: config.nats.subject_prefix.clone(),
+                format: match config.nats.format.as_str() {
+                    "bson" => EventFormat::Bson,
+                    _ => EventFormat::Json,
+                },
+                collections: config.nats.collections.clone(),
+                ..NatsOptions::default()
+            };
+            let db = tenants.default_tenant().db().clone();
+            let publisher = NatsPublisher::start(db, options)
+                .await
+                .map_err(|e| format!("Failed to start publishing to NATS: {:?}", e))?;
+            Some(publisher)
+        }
+        None => None,
+    };
+
+    let mut servers: JoinSet<Result<(), String>> = JoinSet::new();
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = &config.listen.grpc {
+        spawn_server!(servers, grpc, tenants, grpc.parse()?, &tls, limits);
+    }
+
+    #[cfg(feature = "mongo")]
+    if let Some(mongo) = &config.listen.mongo {
+        let compressors = config.listen.compression.mongo_compressors();
+        spawn_server!(
+            servers,
+            mongo,
+            tenants,
+            mongo.parse()?,
+            &tls,
+            limits,
+            compressors
+        );
+    }
+
+    #[cfg(feature = "resp")]
+    if let Some(resp) = &config.listen.resp {
+        spawn_server!(servers, resp, tenants, resp.parse()?, &tls, limits);
+    }
+
+    let options = config.http_options();
+    spawn_server!(servers, http, tenants, addr, &tls, limits, options);
+
+    // Every front end runs until the first one fails or a signal comes.
+    let failure = tokio::select! {
+        result = servers.join_next() => match result {
+            Some(Ok(Ok(()))) | None => None,
+            Some(Ok(Err(e))) => Some(e),
+            Some(Err(e)) => Some(e.to_string()),
+        },
+        _ = shutdown_signal() => None,
+    };
+
+    info!("Shutting down");
+    limits.shut_down();
+    let drain = async {
+        while servers.join_next().await.is_some() {}
+        limits.drained().await;
+    };
+    if tokio::time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+        warn!("Closing connections still open after {:?}", DRAIN_TIMEOUT);
+        servers.abort_all();
+    }
+
+    drop(webhooks);
+    #[cfg(feature = "nats")]
+    if let Some(publisher) = nats {
+        publisher.stop().await;
+    }
+    #[cfg(feature = "client")]
+    cluster.stop().await;
+    for tenant in tenants.iter() {
+        if let Err(e) = tenant.db().close().await {
+            error!("Failed to close database '{}': {:?}", tenant.name(), e);
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,305 @@
+//! Several databases served by one server. Each tenant is a database in its
+//! own folder, with its own users (see `auth`) and an optional disk quota.
+//!
+//! One tenant is the default, serving clients that don't name a database:
+//! the REST interface serves it at the root and every tenant under
+//! `/databases/{name}`, gRPC calls pick theirs with the `x-owldb-database`
+//! metadata key, MongoDB commands with their `$db` field, and Redis
+//! connections with `SELECT`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::db::{Database, DatabaseError};
+use crate::server::audit::{Audit, AuditEvent};
+use crate::server::auth::{Auth, Principal};
+#[cfg(feature = "client")]
+use crate::server::cluster::Cluster;
+use crate::server::cursors::Cursors;
+use crate::server::trace;
+
+/// The name of the tenant built by `Tenants::single`.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// How long a measured disk usage is trusted before measuring again.
+const QUOTA_RECHECK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct TenantOptions {
+    /// Bytes the database folder may grow to; unlimited when unset.
+    pub quota_bytes: Option<u64>,
+    /// Where the tenant's data operations are recorded.
+    pub audit: Audit,
+    /// How long a cursor may sit unused before it's dropped.
+    pub cursor_timeout: Duration,
+}
+
+impl Default for TenantOptions {
+    fn default() -> Self {
+        Self {
+            quota_bytes: None,
+            audit: Audit::default(),
+            cursor_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+/// One database and who may use it. Cloning is cheap.
+#[derive(Clone)]
+pub struct Tenant {
+    inner: Arc<TenantInner>,
+}
+
+struct TenantInner {
+    name: String,
+    db: Database,
+    auth: Auth,
+    options: TenantOptions,
+    cursors: Cursors,
+    /// The last measured disk usage, and when it was measured.
+    usage: tokio::sync::Mutex<Option<(u64, Instant)>>,
+}
+
+impl Tenant {
+    pub fn new(name: impl Into<String>, db: Database, auth: Auth, options: TenantOptions) -> Self {
+        Self {
+            inner: Arc::new(TenantInner {
+                name: name.into(),
+                db,
+                auth,
+                cursors: Cursors::new(options.cursor_timeout),
+                options,
+                usage: tokio::sync::Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn db(&self) -> &Database {
+        &self.inner.db
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.inner.auth
+    }
+
+    pub fn cursors(&self) -> &Cursors {
+        &self.inner.cursors
+    }
+
+    /// Records that `principal` made `event`, before it's carried out.
+    pub async fn audit(
+        &self,
+        principal: &Principal,
+        event: AuditEvent,
+    ) -> Result<(), DatabaseError> {
+        let audit = &self.inner.options.audit;
+        let record = audit.record(&self.inner.db, &self.inner.name, &principal.username, event);
+        trace::stage("audit", record).await
+    }
+
+    /// Checks the database is under its quota, before a write that may
+    /// grow it. Usage is measured at most every few seconds, so a burst of
+    /// writes can go somewhat over.
+    pub async fn check_quota(&self) -> Result<(), DatabaseError> {
+        let Some(quota) = self.inner.options.quota_bytes else {
+            return Ok(());
+        };
+
+        trace::stage("quota", self.check_usage(quota)).await
+    }
+
+    async fn check_usage(&self, quota: u64) -> Result<(), DatabaseError> {
+        let mut usage = self.inner.usage.lock().await;
+        let bytes = match *usage {
+            Some((bytes, measured_at)) if measured_at.elapsed() < QUOTA_RECHECK => bytes,
+            _ => {
+                let bytes = self.inner.db.disk_usage().await?;
+                *usage = Some((bytes, Instant::now()));
+                bytes
+            }
+        };
+
+        match bytes < quota {
+            true => Ok(()),
+            false => Err(DatabaseError::QuotaExceeded(format!(
+                "database '{}' uses {} of its {} bytes",
+                self.inner.name, bytes, quota
+            ))),
+        }
+    }
+}
+
+/// The tenants of a server, fixed once it starts. Cloning is cheap.
+#[derive(Clone)]
+pub struct Tenants {
+    inner: Arc<TenantsInner>,
+}
+
+struct TenantsInner {
+    tenants: HashMap<String, Tenant>,
+    default: String,
+    /// The default tenant's replication, administered through the front
+    /// ends.
+    #[cfg(feature = "client")]
+    cluster: std::sync::OnceLock<Cluster>,
+}
+
+impl Tenants {
+    /// Serves `default` to clients that don't name a database, next to
+    /// `others`.
+    ///
+    /// # Panics
+    ///
+    /// If a name is used twice or isn't `is_valid_name`.
+    pub fn new(default: Tenant, others: Vec<Tenant>) -> Self {
+        let default_name = default.name().to_string();
+        let mut tenants = HashMap::new();
+
+        for tenant in std::iter::once(default).chain(others) {
+            assert!(
+                is_valid_name(tenant.name()),
+                "invalid database name '{}'",
+                tenant.name()
+            );
+            let name = tenant.name().to_string();
+            assert!(
+                tenants.insert(name.clone(), tenant).is_none(),
+                "database '{}' is served twice",
+                name
+            );
+        }
+
+        Self {
+            inner: Arc::new(TenantsInner {
+                tenants,
+                default: default_name,
+                #[cfg(feature = "client")]
+                cluster: std::sync::OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Serves one database, as `DEFAULT_TENANT`.
+    pub fn single(db: Database, auth: Auth) -> Self {
+        Self::new(
+            Tenant::new(DEFAULT_TENANT, db, auth, TenantOptions::default()),
+            Vec::new(),
+        )
+    }
+
+    pub fn default_tenant(&self) -> &Tenant {
+        &self.inner.tenants[&self.inner.default]
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Tenant, DatabaseError> {
+        self.inner
+            .tenants
+            .get(name)
+            .ok_or_else(|| DatabaseError::DatabaseNotFound(name.to_string()))
+    }
+
+    /// The tenant named `name`, or the default one when there's no name.
+    pub fn resolve(&self, name: Option<&str>) -> Result<&Tenant, DatabaseError> {
+        match name {
+            Some(name) => self.get(name),
+            None => Ok(self.default_tenant()),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        self.inner.tenants.values()
+    }
+
+    /// Lets admins manage `cluster`, the default tenant's replication.
+    ///
+    /// # Panics
+    ///
+    /// If a cluster was already set.
+    #[cfg(feature = "client")]
+    pub fn set_cluster(&self, cluster: Cluster) {
+        assert!(
+            self.inner.cluster.set(cluster).is_ok(),
+            "the cluster is already set"
+        );
+    }
+
+    #[cfg(feature = "client")]
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.inner.cluster.get()
+    }
+}
+
+/// Names may be used in URLs and folder names: ASCII letters, digits, `-`
+/// and `_`, not starting with `_`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let mut opened = Vec::new();
+        for name in ["acme", "globex"] {
+            let folder_path = format!("data_tests/test_tenants_{}", name);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            opened.push(Database::init(folder_path).await.unwrap());
+        }
+        let globex = opened.pop().unwrap();
+        let acme = opened.pop().unwrap();
+
+        acme.insert_one("users".to_string(), bson::doc! { "name": "John" })
+            .await
+            .unwrap();
+        let tenants = Tenants::new(
+            Tenant::new("acme", acme, Auth::disabled(), TenantOptions::default()),
+            vec![Tenant::new(
+                "globex",
+                globex,
+                Auth::disabled(),
+                TenantOptions {
+                    quota_bytes: Some(1),
+                    ..TenantOptions::default()
+                },
+            )],
+        );
+
+        assert_eq!(tenants.resolve(None).unwrap().name(), "acme");
+        assert!(tenants
+            .resolve(Some("acme"))
+            .unwrap()
+            .check_quota()
+            .await
+            .is_ok());
+        assert!(matches!(
+            tenants.get("initech"),
+            Err(DatabaseError::DatabaseNotFound(_))
+        ));
+
+        let globex = tenants.get("globex").unwrap();
+        globex
+            .db()
+            .insert_one("users".to_string(), bson::doc! { "name": "Jane" })
+            .await
+            .unwrap();
+        assert!(matches!(
+            globex.check_quota().await,
+            Err(DatabaseError::QuotaExceeded(_))
+        ));
+
+        assert!(!is_valid_name("_users"));
+        assert!(!is_valid_name("a/b"));
+        assert!(is_valid_name("tenant-1"));
+    }
+}
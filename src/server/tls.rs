@@ -0,0 +1,259 @@
+//! TLS termination for the network front ends, using rustls with the ring
+//! provider. A `TlsListener` accepts TCP connections and completes their
+//! handshakes in the background, handing out only the streams that made it.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Handshakes taking longer than this are dropped, so stalled clients
+/// don't pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Finished handshakes waiting for the server to pick them up.
+const ACCEPT_BACKLOG: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// PEM file with the server certificate, followed by any intermediates.
+    pub cert_path: PathBuf,
+    /// PEM file with the certificate's private key.
+    pub key_path: PathBuf,
+    /// PEM file with the CAs client certificates must be signed by. When
+    /// unset, clients are not asked for a certificate.
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// Loads the certificates and key named by `options`.
+pub fn server_config(options: &TlsOptions) -> std::io::Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let certs = read_certs(&options.cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&options.key_path)
+        .map_err(|e| pem_error(&options.key_path, e))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+
+    let builder = match &options.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(invalid)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(certs, key).map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+fn read_certs(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in '{}'", path.display())));
+    }
+    Ok(certs)
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> std::io::Error {
+    invalid(format!("failed to read '{}': {:?}", path.display(), e))
+}
+
+fn invalid(e: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+}
+
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TlsListener {
+    pub async fn bind(addr: SocketAddr, options: &TlsOptions) -> std::io::Result<Self> {
+        let config = server_config(options)?;
+        Self::new(TcpListener::bind(addr).await?, config)
+    }
+
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (sender, connections) = mpsc::channel(ACCEPT_BACKLOG);
+
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        // Usually out of file descriptors; give some back.
+                        warn!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            connections,
+            local_addr,
+            task,
+        })
+    }
+
+    /// Waits for the next client to complete its handshake.
+    pub async fn accept(&mut self) -> (TlsStream<TcpStream>, SocketAddr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept task only stops when this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(feature = "http")]
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        TlsListener::accept(self).await
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Lets tonic serve the accepted streams.
+#[cfg(feature = "grpc")]
+impl tokio_stream::Stream for TlsListener {
+    type Item = std::io::Result<TlsStream<TcpStream>>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.connections
+            .poll_recv(cx)
+            .map(|connection| connection.map(|(stream, _)| Ok(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    const FIXTURES: &str = "tests/fixtures/tls";
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(FIXTURES).join(name)
+    }
+
+    fn connector(with_client_cert: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&fixture("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+
+        let config = match with_client_cert {
+            true => builder
+                .with_client_auth_cert(
+                    read_certs(&fixture("client.pem")).unwrap(),
+                    PrivateKeyDer::from_pem_file(fixture("client.key")).unwrap(),
+                )
+                .unwrap(),
+            false => builder.with_no_client_auth(),
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    async fn greet(addr: SocketAddr, connector: TlsConnector) -> std::io::Result<String> {
+        let stream = TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, stream).await?;
+
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).await?;
+        Ok(greeting)
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_are_verified() {
+        let options = TlsOptions {
+            cert_path: fixture("server.pem"),
+            key_path: fixture("server.key"),
+            client_ca_path: Some(fixture("ca.pem")),
+        };
+        let mut listener = TlsListener::bind("127.0.0.1:0".parse().unwrap(), &options)
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await;
+                stream.write_all(b"hello").await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        assert_eq!(greet(addr, connector(true)).await.unwrap(), "hello");
+        // With TLS 1.3 the client learns it was refused on its first read.
+        assert!(greet(addr, connector(false)).await.is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Request IDs and timings. Every request the front ends serve gets an ID,
+//! the client's own when it sends a usable one, which goes into the log
+//! line about the request and into its error responses. The time spent in
+//! each stage of serving it (`auth`, `quota`, `audit`, `db`) is measured
+//! and logged with it.
+//!
+//! HTTP clients send and get back the ID in the `x-request-id` header, and
+//! get the stage timings in `Server-Timing`; gRPC clients use metadata
+//! keys of the same names. MongoDB and Redis clients can't send one, so
+//! their requests always get a new ID.
+
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// Longest ID taken from a client.
+const MAX_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestTrace;
+}
+
+/// One request being served. Cloning is cheap; clones share the timings.
+#[derive(Clone)]
+pub struct RequestTrace {
+    inner: Arc<TraceInner>,
+}
+
+struct TraceInner {
+    id: String,
+    started: Instant,
+    stages: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl RequestTrace {
+    /// Traces a request under `id`, or a new one if it's missing or isn't
+    /// printable ASCII of a sensible length.
+    pub fn new(id: Option<&str>) -> Self {
+        let id = match id {
+            Some(id) if is_valid_id(id) => id.to_string(),
+            _ => bson::oid::ObjectId::new().to_hex(),
+        };
+
+        Self {
+            inner: Arc::new(TraceInner {
+                id,
+                started: Instant::now(),
+                stages: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// The request being served by the current task, if it's traced.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|trace| trace.clone()).ok()
+    }
+
+    /// Runs `future` as the current request.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    pub fn id(&self) -> &str {
+        &self.inner.id
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.inner.started.elapsed()
+    }
+
+    /// Time spent in each stage so far, in the order they were first
+    /// entered; a stage entered several times is summed.
+    pub fn stages(&self) -> Vec<(&'static str, Duration)> {
+        let mut stages: Vec<(&'static str, Duration)> = Vec::new();
+        for (name, duration) in self.inner.stages.lock().unwrap().iter() {
+            match stages.iter_mut().find(|(stage, _)| stage == name) {
+                Some((_, total)) => *total += *duration,
+                None => stages.push((name, *duration)),
+            }
+        }
+        stages
+    }
+
+    fn record(&self, name: &'static str, duration: Duration) {
+        self.inner.stages.lock().unwrap().push((name, duration));
+    }
+
+    /// The stage timings and the total so far, as a `Server-Timing` value.
+    pub fn server_timing(&self) -> String {
+        self.stages()
+            .into_iter()
+            .chain(std::iter::once(("total", self.elapsed())))
+            .map(|(name, duration)| format!("{};dur={:.3}", name, millis(duration)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Logs that `operation` finished with `outcome`.
+    pub fn finish(&self, operation: &str, outcome: impl Display) {
+        info!(
+            "[{}] {} {} in {:.3}ms{}",
+            self.id(),
+            operation,
+            outcome,
+            millis(self.elapsed()),
+            StageList(self.stages())
+        );
+    }
+}
+
+impl fmt::Debug for RequestTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestTrace")
+            .field("id", &self.inner.id)
+            .finish()
+    }
+}
+
+/// Formats stages as ` (auth 0.120ms, db 3.400ms)`, or nothing.
+struct StageList(Vec<(&'static str, Duration)>);
+
+impl Display for StageList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, duration)) in self.0.iter().enumerate() {
+            let separator = if i == 0 { " (" } else { ", " };
+            write!(f, "{}{} {:.3}ms", separator, name, millis(*duration))?;
+        }
+        if !self.0.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// The ID of the request being served by the current task, if it's traced.
+pub fn current_id() -> Option<String> {
+    CURRENT.try_with(|trace| trace.id().to_string()).ok()
+}
+
+/// Runs `future` as the stage `name` of the current request, if any.
+pub async fn stage<F: Future>(name: &'static str, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    if let Ok(trace) = CURRENT.try_with(|trace| trace.clone()) {
+        trace.record(name, started.elapsed());
+    }
+    output
+}
+
+/// Like `stage`, for work that doesn't wait.
+pub fn stage_sync<T>(name: &'static str, work: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let output = work();
+    let _ = CURRENT.try_with(|trace| trace.record(name, started.elapsed()));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_trace() {
+        assert_eq!(RequestTrace::new(Some("abc-123")).id(), "abc-123");
+        assert_eq!(RequestTrace::new(Some("has space")).id().len(), 24);
+        assert_ne!(RequestTrace::new(None).id(), RequestTrace::new(None).id());
+        assert!(current_id().is_none());
+        assert_eq!(stage("db", async { 1 }).await, 1);
+
+        let trace = RequestTrace::new(Some("req-1"));
+        trace
+            .clone()
+            .scope(async {
+                assert_eq!(current_id().as_deref(), Some("req-1"));
+                stage_sync("auth", || ());
+                stage("db", tokio::time::sleep(Duration::from_millis(5))).await;
+                stage("db", async {}).await;
+            })
+            .await;
+
+        let stages = trace.stages();
+        assert_eq!(
+            stages.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            vec!["auth", "db"]
+        );
+        assert!(stages[1].1 >= Duration::from_millis(5));
+        let timing = trace.server_timing();
+        assert!(timing.starts_with("auth;dur="), "{}", timing);
+        assert!(timing.contains(", total;dur="), "{}", timing);
+    }
+}
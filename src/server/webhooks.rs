@@ -0,0 +1,299 @@
+//! Webhooks: POSTing a database's writes to URLs as they're made. Each
+//! webhook watches the database (see `Database::watch_all`) and sends the
+//! writes its filter passes one at a time, in order, as the same JSON the
+//! `/db/{collection}/_changes` WebSocket pushes.
+//!
+//! A delivery the receiver doesn't answer with a 2xx status is retried,
+//! waiting twice as long each time, up to `max_attempts`; after that the
+//! event goes to the `_webhook_dead_letters` collection, with the URL and
+//! the last error, and the webhook moves on. Only plain `http://` URLs are
+//! supported. Writes made while no dispatcher runs aren't sent.
+
+use std::time::{Duration, SystemTime};
+
+use axum::body::Bytes;
+use axum::http::{header, Method, Request};
+use bson::Document;
+use futures_util::StreamExt;
+use http_body_util::Full;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::db::{ChangeEvent, ChangeStream, Database, DatabaseError, OperationType};
+use crate::server::http::{change_to_json, JsonMode};
+
+/// Where events no attempt could deliver are kept.
+pub const DEAD_LETTERS_COLLECTION: &str = "_webhook_dead_letters";
+
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    /// Such as `http://10.0.0.5:9000/owldb`.
+    pub url: String,
+    /// Empty for every collection but the internal ones.
+    pub collections: Vec<String>,
+    /// Empty for every kind of write.
+    pub operations: Vec<OperationType>,
+    /// Fields the written document must have, with these values. Deletes,
+    /// which leave no document, always pass.
+    pub filter: Document,
+    pub json: JsonMode,
+    pub max_attempts: u32,
+    /// How long to wait before the first retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long one attempt may take.
+    pub timeout: Duration,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            collections: Vec::new(),
+            operations: Vec::new(),
+            filter: Document::new(),
+            json: JsonMode::default(),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl WebhookOptions {
+    fn passes(&self, event: &ChangeEvent) -> bool {
+        let collection = match self.collections.is_empty() {
+            true => !event.collection.starts_with('_'),
+            false => self.collections.contains(&event.collection),
+        };
+        let operation = self.operations.is_empty() || self.operations.contains(&event.operation);
+        let document = event.document.as_ref().is_none_or(|doc| {
+            self.filter
+                .iter()
+                .all(|(key, value)| doc.get(key) == Some(value))
+        });
+        collection && operation && document
+    }
+}
+
+/// Handle to the webhooks of one database. Dropping it stops them, along
+/// with any delivery under way.
+pub struct Webhooks {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    /// Starts sending `db`'s writes to each of `webhooks`, in the background.
+    pub fn start(db: Database, webhooks: Vec<WebhookOptions>) -> Self {
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let tasks = webhooks
+            .into_iter()
+            .map(|options| {
+                info!("Sending writes to {}", options.url);
+                let webhook = Webhook {
+                    db: db.clone(),
+                    client: client.clone(),
+                    options,
+                };
+                tokio::spawn(webhook.run(db.watch_all()))
+            })
+            .collect();
+        Self { tasks }
+    }
+}
+
+impl Drop for Webhooks {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+struct Webhook {
+    db: Database,
+    client: Client<HttpConnector, Full<Bytes>>,
+    options: WebhookOptions,
+}
+
+impl Webhook {
+    async fn run(self, mut changes: ChangeStream) {
+        while let Some(event) = changes.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Webhook {} skipped writes: {:?}", self.options.url, e);
+                    continue;
+                }
+            };
+            if !self.options.passes(&event) {
+                continue;
+            }
+            if let Err(e) = self.deliver(event).await {
+                error!(
+                    "Failed to keep an undelivered event for {}: {:?}",
+                    self.options.url, e
+                );
+            }
+        }
+    }
+
+    /// Sends `event`, retrying until it's received or the attempts run
+    /// out; then keeps it as a dead letter.
+    async fn deliver(&self, event: ChangeEvent) -> Result<(), DatabaseError> {
+        let body = change_to_json(self.options.json, event.clone()).to_string();
+        let mut backoff = self.options.initial_backoff;
+        let mut attempts = 0;
+        let failure = loop {
+            attempts += 1;
+            let failure = match self.send(body.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+            if attempts >= self.options.max_attempts {
+                break failure;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.options.max_backoff);
+        };
+
+        warn!(
+            "Gave up sending a write to {} after {} attempts: {}",
+            self.options.url, attempts, failure
+        );
+        let failed_at = bson::DateTime::from_system_time(SystemTime::now());
+        let dead_letter = bson::doc! {
+            "url": &self.options.url,
+            "collection": event.collection,
+            "id": event.id,
+            "operation": format!("{:?}", event.operation).to_lowercase(),
+            "document": event.document,
+            "error": failure,
+            "attempts": attempts as i64,
+            "failed_at": failed_at,
+        };
+        self.db
+            .insert_one(DEAD_LETTERS_COLLECTION.to_string(), dead_letter)
+            .await
+            .map(|_| ())
+    }
+
+    async fn send(&self, body: String) -> Result<(), String> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.options.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| e.to_string())?;
+        let response = tokio::time::timeout(self.options.timeout, self.client.request(request))
+            .await
+            .map_err(|_| "timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("answered {}", response.status())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let folder_path = "data_tests/test_webhooks".to_string();
+        let _ = tokio::fs::remove_dir_all(&folder_path).await;
+        let db = Database::init(folder_path).await.unwrap();
+
+        // Fails every other request.
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let receiver = received.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move |body: String| async move {
+                let mut received = receiver.lock().unwrap();
+                received.push(body);
+                match received.len() % 2 {
+                    1 => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::NO_CONTENT,
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhooks = Webhooks::start(
+            db.clone(),
+            vec![
+                WebhookOptions {
+                    url,
+                    collections: vec!["users".to_string()],
+                    filter: bson::doc! { "role": "admin" },
+                    initial_backoff: Duration::from_millis(10),
+                    ..WebhookOptions::default()
+                },
+                WebhookOptions {
+                    url: "http://127.0.0.1:1/unreachable".to_string(),
+                    operations: vec![OperationType::Delete],
+                    max_attempts: 2,
+                    initial_backoff: Duration::from_millis(10),
+                    ..WebhookOptions::default()
+                },
+            ],
+        );
+
+        db.insert_one("users".to_string(), bson::doc! { "role": "guest" })
+            .await
+            .unwrap();
+        let id = db
+            .insert_one("users".to_string(), bson::doc! { "role": "admin" })
+            .await
+            .unwrap();
+        db.delete_one("users".to_string(), id.clone())
+            .await
+            .unwrap();
+
+        let dead_letter = async {
+            loop {
+                let dead_letters = db
+                    .find(DEAD_LETTERS_COLLECTION.to_string(), bson::doc! {})
+                    .await
+                    .unwrap_or_default();
+                if received.lock().unwrap().len() == 4 && !dead_letters.is_empty() {
+                    return dead_letters.into_iter().next().unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let dead_letter = tokio::time::timeout(Duration::from_secs(5), dead_letter)
+            .await
+            .unwrap();
+        assert_eq!(dead_letter.get_str("id"), Ok(id.as_str()));
+        assert_eq!(dead_letter.get_i64("attempts"), Ok(2));
+
+        // The admin's insert, then its delete, each sent twice.
+        let received = received.lock().unwrap().clone();
+        let operations: Vec<_> = received
+            .iter()
+            .map(|body| {
+                let event: serde_json::Value = serde_json::from_str(body).unwrap();
+                assert_eq!(event["_id"], id.as_str());
+                event["operation"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(operations, ["insert", "insert", "delete", "delete"]);
+        drop(webhooks);
+    }
+}
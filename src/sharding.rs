@@ -0,0 +1,914 @@
+//! Hash-based sharding: a collection's documents are spread over several
+//! databases by the hash of a shard key field, each shard a local
+//! `Database` in its own folder or, with the `client` feature, a server
+//! reached over gRPC.
+//!
+//! Inserts, and finds whose query names the shard key, go to the one shard
+//! owning its value; other finds ask every shard at once and gather the
+//! answers. Writes by ID first find the shard holding the document.
+//! Collections without a shard key live on the first shard.
+//!
+//! The number of shards is fixed: adding one moves documents to shards
+//! that don't hold them yet, so it takes copying the data over.
+//!
+//! Shard key values hash into `CHUNKS` chunks, each owned by one shard. A
+//! `Balancer` evens out shards that grew apart by moving whole chunks,
+//! every sharded collection's documents in them at once, recording where
+//! they went in the first shard's `_chunks` collection; `open` picks that
+//! up. Writes and reads wait while a chunk moves.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bson::{Bson, Document};
+use log::{error, info};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+
+#[cfg(feature = "client")]
+use crate::client::Client;
+use crate::db::{filter, Database, DatabaseError};
+
+/// How many chunks shard key values hash into.
+pub const CHUNKS: u32 = 1024;
+
+/// Where moved chunks are recorded, on the first shard.
+pub const CHUNKS_COLLECTION: &str = "_chunks";
+
+#[derive(Debug, Clone, Default)]
+pub struct ShardingOptions {
+    /// The top-level field each sharded collection is partitioned by.
+    pub shard_keys: HashMap<String, String>,
+}
+
+/// Where one shard's documents are kept. Cloning is cheap.
+#[derive(Clone)]
+pub enum Shard {
+    Local(Database),
+    #[cfg(feature = "client")]
+    Remote(Client),
+}
+
+impl Shard {
+    async fn insert_one(&self, collection: String, doc: Document) -> Result<String, DatabaseError> {
+        match self {
+            Shard::Local(db) => db.insert_one(collection, doc).await,
+            #[cfg(feature = "client")]
+            Shard::Remote(client) => client.insert_one(collection, doc).await,
+        }
+    }
+
+    async fn find_one(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Document>, DatabaseError> {
+        match self {
+            Shard::Local(db) => db.find_one(collection, id).await,
+            #[cfg(feature = "client")]
+            Shard::Remote(client) => client.find_one(collection, id).await,
+        }
+    }
+
+    async fn find_with_ids(
+        &self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<(String, Document)>, DatabaseError> {
+        let found = match self {
+            Shard::Local(db) => db.find_with_ids(collection, query).await,
+            #[cfg(feature = "client")]
+            Shard::Remote(client) => client.find_with_ids(collection, query).await,
+        };
+        match found {
+            // A shard that never got a document of the collection.
+            Err(DatabaseError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Vec::new())
+            }
+            found => found,
+        }
+    }
+
+    async fn update_one(
+        &self,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<bool, DatabaseError> {
+        match self {
+            Shard::Local(db) => db.update_one(collection, id, update).await,
+            #[cfg(feature = "client")]
+            Shard::Remote(client) => client.update_one(collection, id, update).await,
+        }
+    }
+
+    async fn delete_one(&self, collection: String, id: String) -> Result<(), DatabaseError> {
+        match self {
+            Shard::Local(db) => db.delete_one(collection, id).await.map(|_| ()),
+            #[cfg(feature = "client")]
+            Shard::Remote(client) => client.delete_one(collection, id).await,
+        }
+    }
+
+    async fn put(
+        &self,
+        collection: String,
+        id: String,
+        doc: Document,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Shard::Local(db) => db.put(collection, id, doc).await,
+            #[cfg(feature = "client")]
+            Shard::Remote(client) => client.put(collection, id, doc).await,
+        }
+    }
+}
+
+/// Several shards used as one database. Cloning is cheap.
+#[derive(Clone)]
+pub struct ShardedDatabase {
+    shards: Vec<Shard>,
+    options: ShardingOptions,
+    /// The chunks owned by another shard than their hash picks.
+    moved: Arc<RwLock<HashMap<u32, usize>>>,
+    /// Held exclusively while a chunk moves.
+    migration: Arc<tokio::sync::RwLock<()>>,
+}
+
+impl ShardedDatabase {
+    /// Shards as their hash picks; see `open` for a database a balancer
+    /// ran on.
+    ///
+    /// # Panics
+    ///
+    /// If there are no shards.
+    pub fn new(shards: Vec<Shard>, options: ShardingOptions) -> Self {
+        assert!(!shards.is_empty(), "a sharded database needs a shard");
+        Self {
+            shards,
+            options,
+            moved: Arc::default(),
+            migration: Arc::default(),
+        }
+    }
+
+    /// Like `new`, with the chunks a balancer moved.
+    pub async fn open(shards: Vec<Shard>, options: ShardingOptions) -> Result<Self, DatabaseError> {
+        let sharded = Self::new(shards, options);
+        let moved = sharded.shards[0]
+            .find_with_ids(CHUNKS_COLLECTION.to_string(), Document::new())
+            .await?;
+        let mut chunks = sharded.moved.write().unwrap();
+        for (id, doc) in moved {
+            let chunk = id.parse::<u32>().ok().filter(|chunk| *chunk < CHUNKS);
+            let shard = doc.get_i64("shard").ok().map(|shard| shard as usize);
+            match (chunk, shard) {
+                (Some(chunk), Some(shard)) if shard < sharded.shards.len() => {
+                    chunks.insert(chunk, shard);
+                }
+                _ => {
+                    return Err(DatabaseError::InvalidConfig(format!(
+                        "chunk '{}' is recorded on a shard that doesn't exist",
+                        id
+                    )))
+                }
+            }
+        }
+        drop(chunks);
+        Ok(sharded)
+    }
+
+    pub fn shards(&self) -> &[Shard] {
+        &self.shards
+    }
+
+    /// The index of the shard owning the document `doc` of `collection`,
+    /// or would own it once inserted.
+    pub fn shard_for(&self, collection: &str, doc: &Document) -> usize {
+        match self.options.shard_keys.get(collection) {
+            Some(key) => self.shard_for_value(doc.get(key).unwrap_or(&Bson::Null)),
+            None => 0,
+        }
+    }
+
+    fn shard_for_value(&self, value: &Bson) -> usize {
+        let hash = hash(value);
+        match self.moved.read().unwrap().get(&(hash % CHUNKS)) {
+            Some(shard) => *shard,
+            None => hash as usize % self.shards.len(),
+        }
+    }
+
+    /// The chunk a document of `collection` falls in, if it's sharded.
+    fn chunk_for(&self, collection: &str, doc: &Document) -> Option<u32> {
+        let key = self.options.shard_keys.get(collection)?;
+        Some(hash(doc.get(key).unwrap_or(&Bson::Null)) % CHUNKS)
+    }
+
+    /// The shards a find with `query` has to ask: the one owning the shard
+    /// key's value when the query asks for it to equal one, all of them
+    /// otherwise, as for a range like `{"$gt": 10}`.
+    fn shards_for_query(&self, collection: &str, query: &Document) -> Vec<usize> {
+        let Some(key) = self.options.shard_keys.get(collection) else {
+            return vec![0];
+        };
+        match query.get(key) {
+            Some(value) if !filter::is_operators(value) => vec![self.shard_for_value(value)],
+            _ => (0..self.shards.len()).collect(),
+        }
+    }
+
+    pub async fn insert_one(
+        &self,
+        collection: String,
+        doc: Document,
+    ) -> Result<String, DatabaseError> {
+        let _migration = self.migration.read().await;
+        let shard = self.shard_for(&collection, &doc);
+        self.shards[shard].insert_one(collection, doc).await
+    }
+
+    pub async fn find_one(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<Document>, DatabaseError> {
+        let _migration = self.migration.read().await;
+        Ok(self.locate(collection, id).await?.map(|(_, doc)| doc))
+    }
+
+    pub async fn find(
+        &self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<Document>, DatabaseError> {
+        Ok(self
+            .find_with_ids(collection, query)
+            .await?
+            .into_iter()
+            .map(|(_, doc)| doc)
+            .collect())
+    }
+
+    /// Like `find`, but pairs every document with its id. Documents come
+    /// shard by shard.
+    pub async fn find_with_ids(
+        &self,
+        collection: String,
+        query: Document,
+    ) -> Result<Vec<(String, Document)>, DatabaseError> {
+        let _migration = self.migration.read().await;
+        let shards = self.shards_for_query(&collection, &query);
+        let found = self
+            .scatter(shards, |shard| {
+                let (collection, query) = (collection.clone(), query.clone());
+                async move { shard.find_with_ids(collection, query).await }
+            })
+            .await?;
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    /// Same update forms as `Database::update_one`, except that they may
+    /// not change the shard key, which would leave the document on the
+    /// wrong shard.
+    pub async fn update_one(
+        &self,
+        collection: String,
+        id: String,
+        update: Document,
+    ) -> Result<bool, DatabaseError> {
+        let _migration = self.migration.read().await;
+        let Some((shard, doc)) = self.locate(collection.clone(), id.clone()).await? else {
+            return Ok(false);
+        };
+        if let Some(key) = self.options.shard_keys.get(&collection) {
+            if changes_field(&update, key, &doc) {
+                return Err(DatabaseError::InvalidUpdate(format!(
+                    "the shard key '{}' can't change",
+                    key
+                )));
+            }
+        }
+        self.shards[shard].update_one(collection, id, update).await
+    }
+
+    pub async fn delete_one(&self, collection: String, id: String) -> Result<(), DatabaseError> {
+        let _migration = self.migration.read().await;
+        match self.locate(collection.clone(), id.clone()).await? {
+            Some((shard, _)) => self.shards[shard].delete_one(collection, id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The shard holding document `id`, and the document, asking every
+    /// shard that may hold it.
+    async fn locate(
+        &self,
+        collection: String,
+        id: String,
+    ) -> Result<Option<(usize, Document)>, DatabaseError> {
+        let shards = match self.options.shard_keys.contains_key(&collection) {
+            true => (0..self.shards.len()).collect(),
+            false => vec![0],
+        };
+        let found = self
+            .scatter(shards.clone(), |shard| {
+                let (collection, id) = (collection.clone(), id.clone());
+                async move { shard.find_one(collection, id).await }
+            })
+            .await?;
+        Ok(shards
+            .into_iter()
+            .zip(found)
+            .find_map(|(shard, doc)| Some((shard, doc?))))
+    }
+
+    /// Calls each of `shards` at once, answering in the order given.
+    async fn scatter<T, F, Fut>(&self, shards: Vec<usize>, call: F) -> Result<Vec<T>, DatabaseError>
+    where
+        F: Fn(Shard) -> Fut,
+        Fut: Future<Output = Result<T, DatabaseError>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut calls = JoinSet::new();
+        for (order, shard) in shards.iter().enumerate() {
+            let call = call(self.shards[*shard].clone());
+            calls.spawn(async move { (order, call.await) });
+        }
+
+        let mut answers: Vec<Option<T>> = (0..shards.len()).map(|_| None).collect();
+        while let Some(joined) = calls.join_next().await {
+            let (order, answer) = match joined {
+                Ok(joined) => joined,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            };
+            answers[order] = Some(answer?);
+        }
+        Ok(answers.into_iter().flatten().collect())
+    }
+
+    /// Every shard's documents of `collection`, in shard order.
+    async fn scan(&self, collection: &str) -> Result<Vec<Vec<(String, Document)>>, DatabaseError> {
+        let shards = (0..self.shards.len()).collect();
+        self.scatter(shards, |shard| {
+            let collection = collection.to_string();
+            async move { shard.find_with_ids(collection, Document::new()).await }
+        })
+        .await
+    }
+
+    /// Moves every sharded collection's documents in `chunk` to shard `to`,
+    /// answering how many moved. Reads and writes wait meanwhile.
+    pub async fn move_chunk(&self, chunk: u32, to: usize) -> Result<u64, DatabaseError> {
+        if chunk >= CHUNKS || to >= self.shards.len() {
+            return Err(DatabaseError::InvalidConfig(format!(
+                "no chunk {} or shard {}",
+                chunk, to
+            )));
+        }
+        let _migration = self.migration.write().await;
+
+        let mut copied = Vec::new();
+        for collection in self.options.shard_keys.keys() {
+            for (shard, docs) in self.scan(collection).await?.into_iter().enumerate() {
+                if shard == to {
+                    continue;
+                }
+                for (id, doc) in docs {
+                    if self.chunk_for(collection, &doc) == Some(chunk) {
+                        self.shards[to]
+                            .put(collection.clone(), id.clone(), doc)
+                            .await?;
+                        copied.push((shard, collection.clone(), id));
+                    }
+                }
+            }
+        }
+
+        // Recorded before the originals go, so a move cut short leaves
+        // strays rather than losing documents.
+        let owner = bson::doc! { "shard": to as i64 };
+        self.shards[0]
+            .put(CHUNKS_COLLECTION.to_string(), chunk.to_string(), owner)
+            .await?;
+        self.moved.write().unwrap().insert(chunk, to);
+        for (shard, collection, id) in &copied {
+            self.shards[*shard]
+                .delete_one(collection.clone(), id.clone())
+                .await?;
+        }
+        Ok(copied.len() as u64)
+    }
+
+    /// Each shard's load, how much of it each chunk makes up, and the
+    /// documents found on a shard not owning them.
+    async fn measure(&self, by: BalanceBy) -> Result<Measurement, DatabaseError> {
+        let shards = self.shards.len();
+        let mut measured = Measurement {
+            loads: vec![0; shards],
+            chunks: HashMap::new(),
+            strays: Vec::new(),
+        };
+        for collection in self.options.shard_keys.keys() {
+            for (shard, docs) in self.scan(collection).await?.into_iter().enumerate() {
+                for (id, doc) in docs {
+                    if self.shard_for(collection, &doc) != shard {
+                        measured.strays.push((shard, collection.clone(), id, doc));
+                        continue;
+                    }
+                    let weight = match by {
+                        BalanceBy::Documents => 1,
+                        BalanceBy::Bytes => {
+                            bson::to_vec(&doc).map_or(0, |bytes| bytes.len()) as u64
+                        }
+                    };
+                    let chunk = self.chunk_for(collection, &doc).unwrap_or_default();
+                    measured.loads[shard] += weight;
+                    measured
+                        .chunks
+                        .entry(chunk)
+                        .or_insert_with(|| vec![0; shards])[shard] += weight;
+                }
+            }
+        }
+        Ok(measured)
+    }
+
+    /// Hands strays to the shards owning them, unless they hold them
+    /// already.
+    async fn settle(&self, strays: Vec<Stray>) -> Result<(), DatabaseError> {
+        if strays.is_empty() {
+            return Ok(());
+        }
+        let _migration = self.migration.write().await;
+        info!(
+            "Settling {} documents left by an unfinished chunk move",
+            strays.len()
+        );
+        for (shard, collection, id, doc) in strays {
+            let owner = &self.shards[self.shard_for(&collection, &doc)];
+            if owner
+                .find_one(collection.clone(), id.clone())
+                .await?
+                .is_none()
+            {
+                owner.put(collection.clone(), id.clone(), doc).await?;
+            }
+            self.shards[shard].delete_one(collection, id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A document on a shard not owning it: the shard, collection, ID and
+/// document.
+type Stray = (usize, String, String, Document);
+
+struct Measurement {
+    loads: Vec<u64>,
+    /// Per chunk, how much of each shard's load it makes up.
+    chunks: HashMap<u32, Vec<u64>>,
+    strays: Vec<Stray>,
+}
+
+/// What shards are evened out by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BalanceBy {
+    #[default]
+    Documents,
+    /// Their documents' size in BSON.
+    Bytes,
+}
+
+#[derive(Debug, Clone)]
+pub struct BalancerOptions {
+    pub balance_by: BalanceBy,
+    /// How far the fullest shard may get ahead of the emptiest before
+    /// chunks move, as a fraction of the mean shard.
+    pub threshold: f64,
+    /// How often shards are measured, which reads every sharded document.
+    pub interval: Duration,
+    /// How long to wait after moving a chunk, leaving room for other
+    /// traffic.
+    pub pause: Duration,
+}
+
+impl Default for BalancerOptions {
+    fn default() -> Self {
+        Self {
+            balance_by: BalanceBy::Documents,
+            threshold: 0.2,
+            interval: Duration::from_secs(60),
+            pause: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BalancerProgress {
+    /// Whether chunks are being moved now.
+    pub running: bool,
+    /// How many times shards were measured.
+    pub rounds: u64,
+    /// Each shard's load, in `BalanceBy` units, as of the last move.
+    pub loads: Vec<u64>,
+    /// The chunks the current or last round set out to move, and has moved.
+    pub chunks_planned: usize,
+    pub chunks_moved: usize,
+    pub documents_moved: u64,
+    /// Why the last round failed, until one succeeds.
+    pub last_error: Option<String>,
+}
+
+/// Handle to a balancer evening out a sharded database's shards in the
+/// background. Dropping it stops it, once it's done with the chunk it's
+/// moving.
+pub struct Balancer {
+    shutdown: watch::Sender<bool>,
+    progress: watch::Receiver<BalancerProgress>,
+    task: JoinHandle<()>,
+}
+
+impl Balancer {
+    pub fn start(db: ShardedDatabase, options: BalancerOptions) -> Self {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (progress_tx, progress) = watch::channel(BalancerProgress::default());
+        let task = tokio::spawn(balance(db, options, progress_tx, shutdown_rx));
+        Self {
+            shutdown,
+            progress,
+            task,
+        }
+    }
+
+    pub fn progress(&self) -> BalancerProgress {
+        self.progress.borrow().clone()
+    }
+
+    /// Watches the progress as it's made.
+    pub fn subscribe(&self) -> watch::Receiver<BalancerProgress> {
+        self.progress.clone()
+    }
+
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Balancer task failed: {}", e);
+        }
+    }
+}
+
+async fn balance(
+    db: ShardedDatabase,
+    options: BalancerOptions,
+    progress: watch::Sender<BalancerProgress>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        if let Err(e) = balance_round(&db, &options, &progress, &mut shutdown).await {
+            error!("Failed to balance shards: {:?}", e);
+            progress.send_modify(|progress| {
+                progress.running = false;
+                progress.last_error = Some(format!("{:?}", e));
+            });
+        }
+        let wait = tokio::time::sleep(options.interval);
+        if or_stopped(&mut shutdown, wait).await.is_none() {
+            return;
+        }
+    }
+}
+
+async fn balance_round(
+    db: &ShardedDatabase,
+    options: &BalancerOptions,
+    progress: &watch::Sender<BalancerProgress>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<(), DatabaseError> {
+    let measured = db.measure(options.balance_by).await?;
+    db.settle(measured.strays).await?;
+    let mut loads = measured.loads;
+    let moves = plan(&loads, &measured.chunks, options.threshold);
+    progress.send_modify(|progress| {
+        progress.running = !moves.is_empty();
+        progress.rounds += 1;
+        progress.loads = loads.clone();
+        progress.chunks_planned = moves.len();
+        progress.chunks_moved = 0;
+        progress.documents_moved = 0;
+    });
+    if !moves.is_empty() {
+        info!(
+            "Moving {} chunks to even out shards {:?}",
+            moves.len(),
+            loads
+        );
+    }
+
+    for (chunk, to) in moves {
+        if *shutdown.borrow() {
+            break;
+        }
+        let moved = db.move_chunk(chunk, to).await?;
+        loads = moved_to(&loads, &measured.chunks[&chunk], to);
+        progress.send_modify(|progress| {
+            progress.loads = loads.clone();
+            progress.chunks_moved += 1;
+            progress.documents_moved += moved;
+        });
+        let pause = tokio::time::sleep(options.pause);
+        if or_stopped(shutdown, pause).await.is_none() {
+            break;
+        }
+    }
+
+    progress.send_modify(|progress| {
+        progress.running = false;
+        progress.last_error = None;
+    });
+    Ok(())
+}
+
+/// The chunks to move, in order, and where to, for `loads` to even out:
+/// each move takes a chunk of the fullest shard to the emptiest, picking
+/// the one leaving them closest.
+fn plan(loads: &[u64], chunks: &HashMap<u32, Vec<u64>>, threshold: f64) -> Vec<(u32, usize)> {
+    let spread = |loads: &[u64]| {
+        loads.iter().max().copied().unwrap_or(0) - loads.iter().min().copied().unwrap_or(0)
+    };
+    let mean = loads.iter().sum::<u64>() as f64 / loads.len() as f64;
+    let allowed = (mean * threshold).max(1.0);
+
+    let mut candidates: Vec<u32> = chunks.keys().copied().collect();
+    candidates.sort_unstable();
+    let mut loads = loads.to_vec();
+    let mut moves = Vec::new();
+    while spread(&loads) as f64 > allowed {
+        let fullest = (0..loads.len()).max_by_key(|shard| loads[*shard]).unwrap();
+        let emptiest = (0..loads.len()).min_by_key(|shard| loads[*shard]).unwrap();
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunks[chunk][fullest] > 0)
+            .map(|(at, chunk)| (at, moved_to(&loads, &chunks[chunk], emptiest)))
+            .filter(|(_, moved)| spread(moved) < spread(&loads))
+            .min_by_key(|(_, moved)| spread(moved));
+        let Some((at, moved)) = best else {
+            break;
+        };
+        moves.push((candidates.remove(at), emptiest));
+        loads = moved;
+    }
+    moves
+}
+
+/// `loads` once a chunk holding `chunk` of each moves to shard `to`.
+fn moved_to(loads: &[u64], chunk: &[u64], to: usize) -> Vec<u64> {
+    let total: u64 = chunk.iter().sum();
+    loads
+        .iter()
+        .zip(chunk)
+        .enumerate()
+        .map(|(shard, (load, held))| match shard == to {
+            true => load + total - held,
+            false => load - held,
+        })
+        .collect()
+}
+
+/// Waits for `future`, or gives `None` if the balancer is stopped first.
+async fn or_stopped<T>(
+    shutdown: &mut watch::Receiver<bool>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = shutdown.wait_for(|stopped| *stopped) => None,
+    }
+}
+
+fn hash(value: &Bson) -> u32 {
+    let mut bytes = Vec::new();
+    // A single-value document always serializes.
+    let _ = bson::doc! { "": value.clone() }.to_writer(&mut bytes);
+    crc32fast::hash(&bytes)
+}
+
+/// Whether `update`, applied to `doc`, may change its `field`.
+fn changes_field(update: &Document, field: &str, doc: &Document) -> bool {
+    let is_operator = update.keys().any(|key| key.starts_with('$'));
+    if !is_operator {
+        return update.get(field) != doc.get(field);
+    }
+
+    let nested = format!("{}.", field);
+    update.values().any(|fields| match fields {
+        Bson::Document(fields) => fields
+            .keys()
+            .any(|path| path == field || path.starts_with(&nested)),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sharded_database() {
+        let mut dbs = Vec::new();
+        for n in 0..3 {
+            let folder_path = format!("data_tests/test_sharding_{}", n);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            dbs.push(Database::init(folder_path).await.unwrap());
+        }
+        let shards = dbs.iter().cloned().map(Shard::Local).collect();
+        let options = ShardingOptions {
+            shard_keys: HashMap::from([("orders".to_string(), "customer".to_string())]),
+        };
+        let sharded = ShardedDatabase::new(shards, options);
+
+        let mut ids = Vec::new();
+        for n in 0..30 {
+            let doc = bson::doc! { "customer": n % 10, "n": n };
+            ids.push(sharded.insert_one("orders".to_string(), doc).await.unwrap());
+        }
+        sharded
+            .insert_one("settings".to_string(), bson::doc! { "theme": "dark" })
+            .await
+            .unwrap();
+
+        // Every shard got some orders, and only the first the settings.
+        for (n, db) in dbs.iter().enumerate() {
+            let orders = db.find("orders".to_string(), bson::doc! {}).await.unwrap();
+            assert!(!orders.is_empty() && orders.len() < 30);
+            for order in orders {
+                assert_eq!(sharded.shard_for("orders", &order), n);
+            }
+            assert_eq!(
+                db.find("settings".to_string(), bson::doc! {})
+                    .await
+                    .map(|settings| settings.len())
+                    .unwrap_or(0),
+                usize::from(n == 0)
+            );
+        }
+
+        assert_eq!(
+            sharded
+                .find("orders".to_string(), bson::doc! {})
+                .await
+                .unwrap()
+                .len(),
+            30
+        );
+        let targeted = sharded
+            .find("orders".to_string(), bson::doc! { "customer": 4 })
+            .await
+            .unwrap();
+        assert_eq!(targeted.len(), 3);
+        // A range over the shard key can't be routed to one shard.
+        let ranged = sharded
+            .find(
+                "orders".to_string(),
+                bson::doc! { "customer": { "$gte": 8 } },
+            )
+            .await
+            .unwrap();
+        assert_eq!(ranged.len(), 6);
+        assert_eq!(
+            sharded
+                .find("orders".to_string(), bson::doc! { "n": 17 })
+                .await
+                .unwrap(),
+            vec![bson::doc! { "customer": 7, "n": 17 }]
+        );
+
+        assert!(sharded
+            .update_one(
+                "orders".to_string(),
+                ids[0].clone(),
+                bson::doc! { "$set": { "paid": true } },
+            )
+            .await
+            .unwrap());
+        assert_eq!(
+            sharded
+                .find_one("orders".to_string(), ids[0].clone())
+                .await
+                .unwrap(),
+            Some(bson::doc! { "customer": 0, "n": 0, "paid": true })
+        );
+        assert!(matches!(
+            sharded
+                .update_one(
+                    "orders".to_string(),
+                    ids[0].clone(),
+                    bson::doc! { "$set": { "customer": 5 } },
+                )
+                .await,
+            Err(DatabaseError::InvalidUpdate(_))
+        ));
+
+        sharded
+            .delete_one("orders".to_string(), ids[0].clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            sharded
+                .find_one("orders".to_string(), ids[0].clone())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_balancer() {
+        let mut dbs = Vec::new();
+        for n in 0..3 {
+            let folder_path = format!("data_tests/test_balancer_{}", n);
+            let _ = tokio::fs::remove_dir_all(&folder_path).await;
+            dbs.push(Database::init(folder_path).await.unwrap());
+        }
+        let shards: Vec<_> = dbs.iter().cloned().map(Shard::Local).collect();
+        let options = ShardingOptions {
+            shard_keys: HashMap::from([("orders".to_string(), "customer".to_string())]),
+        };
+        let sharded = ShardedDatabase::new(shards.clone(), options.clone());
+
+        // The customers the first shard owns order ten times as much.
+        let mut orders = 0;
+        for customer in 0..30 {
+            let heavy = sharded.shard_for("orders", &bson::doc! { "customer": customer }) == 0;
+            for _ in 0..if heavy { 10 } else { 1 } {
+                let doc = bson::doc! { "customer": customer };
+                sharded.insert_one("orders".to_string(), doc).await.unwrap();
+                orders += 1;
+            }
+        }
+        let count = |db: &Database| {
+            let db = db.clone();
+            async move {
+                db.find("orders".to_string(), bson::doc! {})
+                    .await
+                    .map_or(0, |found| found.len())
+            }
+        };
+        let mut before = Vec::new();
+        for db in &dbs {
+            before.push(count(db).await as u64);
+        }
+
+        let balancer = Balancer::start(
+            sharded.clone(),
+            BalancerOptions {
+                interval: Duration::from_secs(60),
+                pause: Duration::ZERO,
+                ..BalancerOptions::default()
+            },
+        );
+        let mut progress = balancer.subscribe();
+        let done = progress
+            .wait_for(|progress| progress.rounds == 1 && !progress.running)
+            .await
+            .unwrap()
+            .clone();
+        balancer.stop().await;
+        assert!(done.chunks_moved > 0 && done.chunks_moved == done.chunks_planned);
+        assert!(done.last_error.is_none());
+        let spread = |loads: &[u64]| loads.iter().max().unwrap() - loads.iter().min().unwrap();
+        assert_eq!(done.loads.iter().sum::<u64>(), orders);
+        assert!(spread(&done.loads) < spread(&before));
+        assert!((count(&dbs[0]).await as u64) < before[0]);
+
+        // Every order is found where the moved chunks say, also once
+        // reopened.
+        let reopened = ShardedDatabase::open(shards, options).await.unwrap();
+        for sharded in [&sharded, &reopened] {
+            let found = sharded
+                .find_with_ids("orders".to_string(), bson::doc! {})
+                .await
+                .unwrap();
+            assert_eq!(found.len() as u64, orders);
+            for (id, order) in found {
+                let shard = sharded.shard_for("orders", &order);
+                assert_eq!(
+                    dbs[shard]
+                        .find_one("orders".to_string(), id.clone())
+                        .await
+                        .unwrap(),
+                    Some(order.clone())
+                );
+                let customer = bson::doc! { "customer": order.get("customer").unwrap() };
+                assert!(!sharded
+                    .find("orders".to_string(), customer)
+                    .await
+                    .unwrap()
+                    .is_empty());
+            }
+        }
+    }
+}